@@ -1,12 +1,56 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{net::SocketAddr, str::FromStr, time::Duration};
 use structopt::StructOpt;
-use wayk_proto::message::{AuthType, ChannelName, NowCapset};
+use wayk_proto::{
+    error::ProtoError,
+    local::LocalAddr,
+    message::{AuthType, ChannelName, NowCapset},
+    proxy::{ProxyConfig, TargetAddr},
+    tcp::TcpConfig,
+};
 
 #[derive(StructOpt, Debug)]
 #[structopt(author, about)]
 
 pub struct Cli {
-    pub addr: SocketAddr,
+    /// Server address, `<host>:<port>` (or gateway address with `--gateway-association`)
+    ///
+    /// A local agent is reached with `unix:<path>` (Linux, macOS) or `pipe:<name>` (Windows).
+    pub addr: ServerAddr,
+
+    #[structopt(long, env = "WAYK_CLI_PROXY")]
+    /// Proxy to connect through. `socks5://[<username>:<password>@]<host>[:<port>]` or
+    /// `http://[<username>:<password>@]<host>[:<port>]`
+    ///
+    /// The server host name is resolved by the proxy.
+    pub proxy: Option<ProxyConfig>,
+
+    #[structopt(long)]
+    /// Association to join on the Devolutions Gateway at `addr`, which relays the connection to the server
+    pub gateway_association: Option<String>,
+
+    #[structopt(long, env = "WAYK_CLI_GATEWAY_TOKEN")]
+    /// Association token (JWT) presented to the gateway
+    pub gateway_token: Option<String>,
+
+    #[structopt(long)]
+    /// Keep Nagle's algorithm enabled (TCP_NODELAY is set by default)
+    pub tcp_delay: bool,
+
+    #[structopt(long)]
+    /// Enable TCP keep-alive probes after the given idle time in seconds
+    pub tcp_keepalive: Option<u64>,
+
+    #[structopt(long)]
+    /// Size in bytes of the socket send buffer
+    pub tcp_send_buffer: Option<usize>,
+
+    #[structopt(long)]
+    /// Size in bytes of the socket receive buffer
+    pub tcp_recv_buffer: Option<usize>,
+
+    #[structopt(long)]
+    /// Local address to connect from, `<ip>:<port>` (port 0 lets the system pick one)
+    pub bind: Option<SocketAddr>,
 
     #[structopt(short, long)]
     /// Enable verbose logging for debug purpose
@@ -33,6 +77,51 @@ pub struct Cli {
     #[structopt(long)]
     /// Text to put into server clipboard
     pub on_clipboard_ready: Option<String>,
+
+    #[structopt(long)]
+    /// Interval in seconds between keep-alive messages sent once connected (disabled by default)
+    pub keep_alive: Option<u64>,
+
+    #[structopt(long, default_value = "30")]
+    /// Seconds without anything received from the server before the connection is closed
+    pub keep_alive_timeout: u64,
+}
+
+impl Cli {
+    pub fn tcp_config(&self) -> TcpConfig {
+        let mut tcp = TcpConfig::new().nodelay(!self.tcp_delay);
+        if let Some(secs) = self.tcp_keepalive {
+            tcp = tcp.keepalive(Duration::from_secs(secs));
+        }
+        if let Some(size) = self.tcp_send_buffer {
+            tcp = tcp.send_buffer_size(size);
+        }
+        if let Some(size) = self.tcp_recv_buffer {
+            tcp = tcp.recv_buffer_size(size);
+        }
+        if let Some(addr) = self.bind {
+            tcp = tcp.bind_addr(addr);
+        }
+        tcp
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ServerAddr {
+    Remote(TargetAddr),
+    Local(LocalAddr),
+}
+
+impl FromStr for ServerAddr {
+    type Err = ProtoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if LocalAddr::is_local_addr(s) {
+            LocalAddr::from_str(s).map(Self::Local)
+        } else {
+            TargetAddr::from_str(s).map(Self::Remote)
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -5,30 +5,33 @@ use crate::{
     authentication::AuthenticateSM,
     config::{configure_available_auth_types, configure_capabilities, configure_channels_to_open},
 };
-use config::Cli;
+use config::{Cli, ServerAddr};
 use std::{
     convert::TryFrom,
-    io::{Read, Write},
+    io::{self, ErrorKind, Read, Write},
     net::{Shutdown, TcpStream},
     rc::Rc,
     str::FromStr,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use structopt::StructOpt;
 use wayk_proto::{
     channels_manager::ChannelsManager,
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    gateway::GatewayConfig,
     header::AbstractNowHeader,
+    local::LocalStream,
     message::{
         ClipboardFormatDef, NowChatTextMsg, NowClipboardControlRspMsg, NowClipboardFormatDataReqMsg,
         NowClipboardFormatDataRspMsgOwned, NowClipboardFormatListReqMsg, NowMessage, NowString256, NowString65535,
     },
     packet::{NowPacket, NowPacketAccumulator},
     serialization::Encode,
-    sharee::{Sharee, ShareeCallbackTrait, ShareeResult},
+    sharee::{Sharee, ShareeCallbackTrait, ShareeResult, ShareeState},
     sm::{
         ChatChannelCallbackTrait, ChatChannelSM, ChatData, ChatDataRc, ClientConnectionSeqSM,
         ClipboardChannelCallbackTrait, ClipboardChannelSM, ClipboardData, ClipboardDataRc, DummyConnectionSeqCallback,
-        VirtChannelSMResult,
+        Heartbeat, HeartbeatEvent, VirtChannelSMResult,
     },
 };
 
@@ -40,20 +43,33 @@ fn main() {
 
     log::trace!("{:?}", args);
 
-    match TcpStream::connect(args.addr) {
+    match connect(&args) {
         Ok(mut stream) => {
-            log::info!("Connected to server at {}", stream.peer_addr().unwrap());
-
             let mut sharee = build_sharee(&args);
             let mut acc = NowPacketAccumulator::new();
             let mut buf = [0; 512];
+            let mut heartbeat = args.keep_alive.map(|interval| {
+                Heartbeat::new(
+                    Duration::from_secs(interval),
+                    Duration::from_secs(args.keep_alive_timeout),
+                    Instant::now(),
+                )
+            });
             'main: loop {
-                while sharee.waiting_for_packet() {
+                while sharee.waiting_for_packet(Instant::now()) {
                     if let Some(packet) = acc.next_packet(&sharee.get_channels_ctx()) {
                         match packet {
                             Ok(packet) => {
                                 log::debug!("Received {:?} packet.", packet.header.body_type());
-                                handle_update_result(&mut stream, sharee.update_with_body(&packet.body));
+                                if let Some(heartbeat) = heartbeat.as_mut() {
+                                    if let Some(answer) = heartbeat.on_body(&packet.body, Instant::now()) {
+                                        send_packet(&mut stream, NowPacket::from_message(answer));
+                                    }
+                                }
+                                handle_update_result(
+                                    &mut stream,
+                                    sharee.update_with_body(&packet.body, Instant::now()),
+                                );
                             }
                             Err(err) => log::error!("Invalid packet: {}", err),
                         }
@@ -63,13 +79,30 @@ fn main() {
                             break 'main;
                         }
                     } else {
-                        let n = stream.read(&mut buf).unwrap();
-                        acc.accumulate(&buf[..n]);
+                        if let Some(heartbeat) = heartbeat.as_mut() {
+                            if sharee.get_state() == ShareeState::Active {
+                                if !poll_heartbeat(&mut stream, heartbeat) {
+                                    break 'main;
+                                }
+
+                                // wake up for the next keep-alive even if nothing is received
+                                let timeout = heartbeat.next_deadline().saturating_duration_since(Instant::now());
+                                if let Err(err) = stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1)))) {
+                                    log::debug!("Keep-alive only sent once something is received: {}", err);
+                                }
+                            }
+                        }
+
+                        match stream.read(&mut buf) {
+                            Ok(n) => acc.accumulate(&buf[..n]),
+                            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                            Err(err) => panic!("Couldn't read from server: {}", err),
+                        }
                     }
                 }
 
-                while !sharee.waiting_for_packet() {
-                    handle_update_result(&mut stream, sharee.update_without_body());
+                while !sharee.waiting_for_packet(Instant::now()) {
+                    handle_update_result(&mut stream, sharee.update_without_body(Instant::now()));
 
                     if sharee.is_terminated() {
                         break 'main;
@@ -85,6 +118,99 @@ fn main() {
     }
 }
 
+enum Connection {
+    Tcp(TcpStream),
+    Local(LocalStream),
+}
+
+impl Connection {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+            Connection::Local(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(how),
+            Connection::Local(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Local(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Local(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Local(stream) => stream.flush(),
+        }
+    }
+}
+
+fn connect(args: &Cli) -> Result<Connection, ProtoError> {
+    let addr = match &args.addr {
+        ServerAddr::Remote(addr) => addr,
+        ServerAddr::Local(addr) => {
+            if args.gateway_association.is_some() || args.proxy.is_some() {
+                return ProtoError::new(ProtoErrorKind::LocalConnection)
+                    .or_desc("local agents can't be reached through a gateway or a proxy");
+            }
+            let stream = addr.connect()?;
+            log::info!("Connected to local agent at {}", addr);
+            return Ok(Connection::Local(stream));
+        }
+    };
+
+    let tcp = args.tcp_config();
+    let stream = match (&args.gateway_association, &args.proxy) {
+        (Some(association), _) => {
+            let mut gateway = GatewayConfig::new(addr.clone(), association.clone()).tcp(tcp.clone());
+            if let Some(token) = &args.gateway_token {
+                gateway = gateway.token(token.clone());
+            }
+            if let Some(proxy) = &args.proxy {
+                gateway = gateway.proxy(proxy.clone().tcp(tcp));
+            }
+            let stream = gateway.connect()?;
+            log::info!(
+                "Connected to server through gateway {} (association {})",
+                addr,
+                association
+            );
+            stream
+        }
+        (None, Some(proxy)) => {
+            let stream = proxy.clone().tcp(tcp).connect(addr)?;
+            log::info!("Connected to server at {} through proxy {}", addr, proxy.addr);
+            stream
+        }
+        (None, None) => {
+            let stream = tcp.connect(addr)?;
+            log::info!("Connected to server at {}", stream.peer_addr()?);
+            stream
+        }
+    };
+
+    Ok(Connection::Tcp(stream))
+}
+
 fn configure_logger(args: &Cli) {
     use simplelog::*;
     use std::fs::File;
@@ -170,6 +296,21 @@ fn send_packet<'packet, W: Write>(writer: &mut W, packet: NowPacket<'packet>) {
     log::debug!("Sent {:?} packet.", packet.header.body_type());
 }
 
+/// Sends the keep-alive messages due. Returns `false` if the server went silent.
+fn poll_heartbeat<W: Write>(writer: &mut W, heartbeat: &mut Heartbeat) -> bool {
+    while let Some(event) = heartbeat.poll(Instant::now()) {
+        match event {
+            HeartbeatEvent::Send(msg) => send_packet(writer, NowPacket::from_message(msg)),
+            HeartbeatEvent::PeerSilent(silence) => {
+                log::warn!("Nothing received from server for {:?}, closing connection.", silence);
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
 fn handle_update_result<'packet, W: Write>(writer: &mut W, update_result: ShareeResult<'packet>) {
     match update_result {
         Ok(response) => {
@@ -62,4 +62,7 @@ fuzz_target!(|data: &[u8]| {
 
     let _ = NowMessage::decode_from(MessageType::Sharing, &mut cursor);
     cursor.set_position(0);
+
+    let _ = NowMessage::decode_from(MessageType::ChannelWindow, &mut cursor);
+    cursor.set_position(0);
 });
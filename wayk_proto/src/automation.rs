@@ -0,0 +1,491 @@
+//! Headless automation of a remote session.
+//!
+//! [`NowAutomation`](struct.NowAutomation.html) drives a [`NowClient`](../client/struct.NowClient.html)
+//! without GUI, for RPA and testing: graphics updates are decoded into an in-memory copy of the remote
+//! desktop, so that scripts can wait for an image to show up, click, type and capture screenshots.
+//!
+//! Codecs aren't decoded by this crate: a [`FrameDecoder`](trait.FrameDecoder.html) matching the codecs
+//! advertised by the client configuration is to be provided.
+
+use crate::{
+    client::{NowClient, NowClientConfig},
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        EventMouseFlags, InputEvent, NowBody, NowInputEventUnicode, NowMessage, NowUpdateGraphicsMsg, NowUpdateMsg,
+        SizeRect,
+    },
+    server::NowSessionPacket,
+    transport::{StreamTransport, Transport},
+};
+use alloc::collections::VecDeque;
+use core::convert::TryFrom;
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Pixels of a rectangular area, one `u32` per pixel in the layout produced by the frame decoder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    width: u16,
+    height: u16,
+    pixels: Vec<u32>,
+}
+
+impl Framebuffer {
+    /// Black framebuffer.
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; usize::from(width) * usize::from(height)],
+        }
+    }
+
+    /// `pixels` are given row by row, top row first.
+    pub fn from_pixels(width: u16, height: u16, pixels: Vec<u32>) -> Result<Self, ProtoError> {
+        if pixels.len() != usize::from(width) * usize::from(height) {
+            return ProtoError::new(ProtoErrorKind::Decoding("Framebuffer"))
+                .or_else_desc(|| format!("{} pixels given for a {}x{} framebuffer", pixels.len(), width, height));
+        }
+        Ok(Self { width, height, pixels })
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    pub fn pixel(&self, x: u16, y: u16) -> Option<u32> {
+        if x < self.width && y < self.height {
+            Some(self.pixels[self.__index(x, y)])
+        } else {
+            None
+        }
+    }
+
+    /// Copy of the area covered by `rect`, `None` if it doesn't fit in the framebuffer.
+    pub fn crop(&self, rect: &SizeRect) -> Option<Framebuffer> {
+        let (x, y) = (u16::try_from(rect.x).ok()?, u16::try_from(rect.y).ok()?);
+        if u32::from(x) + u32::from(rect.width) > u32::from(self.width)
+            || u32::from(y) + u32::from(rect.height) > u32::from(self.height)
+        {
+            return None;
+        }
+
+        let mut pixels = Vec::with_capacity(usize::from(rect.width) * usize::from(rect.height));
+        for row in y..y + rect.height {
+            let start = self.__index(x, row);
+            pixels.extend_from_slice(&self.pixels[start..start + usize::from(rect.width)]);
+        }
+        Some(Framebuffer {
+            width: rect.width,
+            height: rect.height,
+            pixels,
+        })
+    }
+
+    /// Draws `source` with its top left corner at `(x, y)`. Pixels falling outside are dropped.
+    pub fn blit(&mut self, x: i16, y: i16, source: &Framebuffer) {
+        for source_y in 0..source.height {
+            let row = i32::from(y) + i32::from(source_y);
+            if row < 0 || row >= i32::from(self.height) {
+                continue;
+            }
+            for source_x in 0..source.width {
+                let column = i32::from(x) + i32::from(source_x);
+                if column < 0 || column >= i32::from(self.width) {
+                    continue;
+                }
+                let index = self.__index(column as u16, row as u16);
+                self.pixels[index] = source.pixels[source.__index(source_x, source_y)];
+            }
+        }
+    }
+
+    /// Resizes the framebuffer, keeping the pixels of the area still covered.
+    pub fn resize(&mut self, width: u16, height: u16) {
+        if (width, height) != (self.width, self.height) {
+            let mut resized = Framebuffer::new(width, height);
+            resized.blit(0, 0, self);
+            *self = resized;
+        }
+    }
+
+    /// `true` if `needle` is found at `(x, y)`, each byte of its pixels differing by at most `tolerance`
+    /// (lossy codecs rarely restore the exact pixels).
+    pub fn matches_at(&self, x: u16, y: u16, needle: &Framebuffer, tolerance: u8) -> bool {
+        if u32::from(x) + u32::from(needle.width) > u32::from(self.width)
+            || u32::from(y) + u32::from(needle.height) > u32::from(self.height)
+        {
+            return false;
+        }
+
+        (0..needle.height).all(|needle_y| {
+            (0..needle.width).all(|needle_x| {
+                let pixel = self.pixels[self.__index(x + needle_x, y + needle_y)];
+                __pixels_match(pixel, needle.pixels[needle.__index(needle_x, needle_y)], tolerance)
+            })
+        })
+    }
+
+    /// Top left corner of the first occurrence of `needle`, scanning rows from the top.
+    pub fn find(&self, needle: &Framebuffer, tolerance: u8) -> Option<(u16, u16)> {
+        if needle.width > self.width || needle.height > self.height {
+            return None;
+        }
+
+        (0..=self.height - needle.height)
+            .flat_map(|y| (0..=self.width - needle.width).map(move |x| (x, y)))
+            .find(|&(x, y)| self.matches_at(x, y, needle, tolerance))
+    }
+
+    fn __index(&self, x: u16, y: u16) -> usize {
+        usize::from(y) * usize::from(self.width) + usize::from(x)
+    }
+}
+
+fn __pixels_match(lhs: u32, rhs: u32, tolerance: u8) -> bool {
+    lhs.to_le_bytes()
+        .iter()
+        .zip(rhs.to_le_bytes().iter())
+        .all(|(lhs, rhs)| lhs.abs_diff(*rhs) <= tolerance)
+}
+
+/// Decodes graphics updates into pixels.
+pub trait FrameDecoder {
+    /// Pixels of `update.update_rect`. `None` if the update doesn't change the image, typically
+    /// while a frame spread over several updates is incomplete.
+    fn decode(&mut self, update: &NowUpdateGraphicsMsg<'_>) -> Result<Option<Framebuffer>, ProtoError>;
+}
+
+sa::assert_obj_safe!(FrameDecoder);
+
+/// Image expected on the remote desktop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImagePattern {
+    pub image: Framebuffer,
+    /// Maximum difference of each pixel byte, see `Framebuffer::matches_at`.
+    pub tolerance: u8,
+}
+
+impl ImagePattern {
+    pub fn new(image: Framebuffer) -> Self {
+        Self { image, tolerance: 0 }
+    }
+
+    pub fn tolerance(self, tolerance: u8) -> Self {
+        Self { tolerance, ..self }
+    }
+}
+
+/// Step of an automation script, see `NowAutomation::run_script`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AutomationStep {
+    /// Processes the received updates for the given duration.
+    Wait(Duration),
+    /// Waits until the pattern shows up anywhere on the desktop.
+    WaitForImage {
+        pattern: ImagePattern,
+        timeout: Duration,
+    },
+    /// Waits until the area covered by `rect` matches the pattern.
+    WaitForRegion {
+        rect: SizeRect,
+        pattern: ImagePattern,
+        timeout: Duration,
+    },
+    /// Presses and releases `button` at `(x, y)`.
+    Click {
+        button: EventMouseFlags,
+        x: i16,
+        y: i16,
+    },
+    MoveTo {
+        x: i16,
+        y: i16,
+    },
+    /// Keyboard event with the given flags and scan code, see `NowInputHandle::key`.
+    Key {
+        flags: u8,
+        code: u16,
+    },
+    /// Types the text as unicode events.
+    Text(String),
+    /// Captures the desktop, see `NowAutomation::run_script`.
+    Screenshot,
+}
+
+/// Client session driven by a script rather than by a user.
+///
+/// Graphics updates are consumed to keep the desktop copy up to date, the other messages returned
+/// by `NowClient::recv` are queued for [`poll_packet`](#method.poll_packet).
+pub struct NowAutomation<T, D> {
+    client: NowClient<T>,
+    decoder: D,
+    desktop: Framebuffer,
+    unprocessed: VecDeque<NowSessionPacket>,
+}
+
+impl<S, D> NowAutomation<StreamTransport<S>, D>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    D: FrameDecoder,
+{
+    /// Connects a client over `stream`, see `NowClient::connect`.
+    pub async fn connect(stream: S, config: NowClientConfig, decoder: D) -> Result<Self, ProtoError> {
+        Ok(Self::new(NowClient::connect(stream, config).await?, decoder))
+    }
+}
+
+impl<T, D> NowAutomation<T, D>
+where
+    T: Transport,
+    D: FrameDecoder,
+{
+    pub fn new(client: NowClient<T>, decoder: D) -> Self {
+        let (width, height) = client.surfaces().desktop_size();
+        Self {
+            client,
+            decoder,
+            desktop: Framebuffer::new(width, height),
+            unprocessed: VecDeque::new(),
+        }
+    }
+
+    pub fn client(&self) -> &NowClient<T> {
+        &self.client
+    }
+
+    pub fn client_mut(&mut self) -> &mut NowClient<T> {
+        &mut self.client
+    }
+
+    pub fn into_client(self) -> NowClient<T> {
+        self.client
+    }
+
+    /// Desktop as last updated.
+    pub fn desktop(&self) -> &Framebuffer {
+        &self.desktop
+    }
+
+    pub fn screenshot(&self) -> Framebuffer {
+        self.desktop.clone()
+    }
+
+    /// Oldest received message that isn't a graphics update.
+    pub fn poll_packet(&mut self) -> Option<NowSessionPacket> {
+        self.unprocessed.pop_front()
+    }
+
+    /// Receives and processes the next message. `false` once the session ended.
+    pub async fn process_next(&mut self) -> Result<bool, ProtoError> {
+        let packet = match self.client.recv().await? {
+            Some(packet) => packet,
+            None => return Ok(false),
+        };
+
+        let decoded = match packet.packet()?.body {
+            NowBody::Message(NowMessage::Update(NowUpdateMsg::UpdateGraphics(update))) => {
+                Some((update.update_rect.clone(), self.decoder.decode(&update)?))
+            }
+            _ => None,
+        };
+        match decoded {
+            Some((rect, Some(pixels))) => self.__draw(&rect, &pixels),
+            Some((_, None)) => {}
+            None => self.unprocessed.push_back(packet),
+        }
+        Ok(true)
+    }
+
+    /// Processes the received messages until `probe` returns a value for the desktop.
+    ///
+    /// Fails if `timeout` is reached first or if the session ends.
+    pub async fn wait_for<F, R>(&mut self, mut probe: F, timeout: Duration) -> Result<R, ProtoError>
+    where
+        F: FnMut(&Framebuffer) -> Option<R>,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(value) = probe(&self.desktop) {
+                return Ok(value);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // receiving is cancel safe: a packet partially read is completed by the next call
+            match tokio::time::timeout(remaining, self.process_next()).await {
+                Ok(Ok(true)) => {}
+                Ok(Ok(false)) => {
+                    return ProtoError::new(ProtoErrorKind::Sharee(self.client.state_report().state))
+                        .or_desc("session ended while waiting for the desktop")
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(_) => {
+                    return Err(ProtoError::from(io::Error::from(io::ErrorKind::TimedOut)))
+                        .or_desc("desktop condition not met in time")
+                }
+            }
+        }
+    }
+
+    /// Waits until `pattern` shows up anywhere on the desktop, returning its top left corner.
+    pub async fn wait_for_image(
+        &mut self,
+        pattern: &ImagePattern,
+        timeout: Duration,
+    ) -> Result<(u16, u16), ProtoError> {
+        self.wait_for(|desktop| desktop.find(&pattern.image, pattern.tolerance), timeout)
+            .await
+    }
+
+    /// Waits until the area covered by `rect` matches `pattern`.
+    pub async fn wait_for_region(
+        &mut self,
+        rect: &SizeRect,
+        pattern: &ImagePattern,
+        timeout: Duration,
+    ) -> Result<(), ProtoError> {
+        self.wait_for(
+            |desktop| {
+                let (x, y) = (u16::try_from(rect.x).ok()?, u16::try_from(rect.y).ok()?);
+                let matching = pattern.image.width() == rect.width
+                    && pattern.image.height() == rect.height
+                    && desktop.matches_at(x, y, &pattern.image, pattern.tolerance);
+                if matching {
+                    Some(())
+                } else {
+                    None
+                }
+            },
+            timeout,
+        )
+        .await
+    }
+
+    /// Presses and releases `button` at `(x, y)`.
+    pub async fn click(&mut self, button: EventMouseFlags, x: i16, y: i16) -> Result<(), ProtoError> {
+        let mut input = self.client.input();
+        input.mouse(button, x, y).await?;
+        input.mouse(EventMouseFlags::None, x, y).await
+    }
+
+    pub async fn move_to(&mut self, x: i16, y: i16) -> Result<(), ProtoError> {
+        self.client.input().mouse(EventMouseFlags::None, x, y).await
+    }
+
+    pub async fn key(&mut self, flags: u8, code: u16) -> Result<(), ProtoError> {
+        self.client.input().key(flags, code).await
+    }
+
+    /// Types `text` as unicode events, sent in a single message.
+    pub async fn type_text(&mut self, text: &str) -> Result<(), ProtoError> {
+        let events = text
+            .char_indices()
+            .map(|(i, c)| InputEvent::Unicode(NowInputEventUnicode::new(&text.as_bytes()[i..i + c.len_utf8()])))
+            .collect();
+        self.client.input().send_events(events).await
+    }
+
+    /// Runs `steps` in order, stopping at the first failure. Screenshots are returned in the order taken.
+    pub async fn run_script(&mut self, steps: &[AutomationStep]) -> Result<Vec<Framebuffer>, ProtoError> {
+        let mut screenshots = Vec::new();
+        for (index, step) in steps.iter().enumerate() {
+            log::debug!("automation step {}: {:?}", index, step);
+            self.__run_step(step, &mut screenshots)
+                .await
+                .or_else_desc(|| format!("automation step {} failed", index))?;
+        }
+        Ok(screenshots)
+    }
+
+    async fn __run_step(
+        &mut self,
+        step: &AutomationStep,
+        screenshots: &mut Vec<Framebuffer>,
+    ) -> Result<(), ProtoError> {
+        match step {
+            AutomationStep::Wait(duration) => {
+                let deadline = Instant::now() + *duration;
+                loop {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    match tokio::time::timeout(remaining, self.process_next()).await {
+                        Ok(Ok(true)) => {}
+                        Ok(Ok(false)) | Err(_) => return Ok(()),
+                        Ok(Err(err)) => return Err(err),
+                    }
+                }
+            }
+            AutomationStep::WaitForImage { pattern, timeout } => {
+                self.wait_for_image(pattern, *timeout).await.map(|_| ())
+            }
+            AutomationStep::WaitForRegion { rect, pattern, timeout } => {
+                self.wait_for_region(rect, pattern, *timeout).await
+            }
+            AutomationStep::Click { button, x, y } => self.click(*button, *x, *y).await,
+            AutomationStep::MoveTo { x, y } => self.move_to(*x, *y).await,
+            AutomationStep::Key { flags, code } => self.key(*flags, *code).await,
+            AutomationStep::Text(text) => self.type_text(text).await,
+            AutomationStep::Screenshot => {
+                screenshots.push(self.screenshot());
+                Ok(())
+            }
+        }
+    }
+
+    /// Draws decoded pixels, growing the desktop to the size advertised by the surface list
+    /// or to the extent of the update if larger.
+    fn __draw(&mut self, rect: &SizeRect, pixels: &Framebuffer) {
+        let (desktop_width, desktop_height) = self.client.surfaces().desktop_size();
+        let right = (i32::from(rect.x) + i32::from(pixels.width())).clamp(0, i32::from(u16::MAX)) as u16;
+        let bottom = (i32::from(rect.y) + i32::from(pixels.height())).clamp(0, i32::from(u16::MAX)) as u16;
+        self.desktop.resize(
+            desktop_width.max(self.desktop.width()).max(right),
+            desktop_height.max(self.desktop.height()).max(bottom),
+        );
+        self.desktop.blit(rect.x, rect.y, pixels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i16, y: i16, width: u16, height: u16) -> SizeRect {
+        SizeRect { x, y, width, height }
+    }
+
+    #[test]
+    fn image_found_after_blit() {
+        let mut desktop = Framebuffer::new(8, 6);
+        let button = Framebuffer::from_pixels(2, 2, vec![0x00ff_0000, 0x00ff_0000, 0x00ff_0010, 0x00ff_0000]).unwrap();
+        assert_eq!(desktop.find(&button, 0), None);
+
+        desktop.blit(5, 3, &button);
+        assert_eq!(desktop.pixel(5, 4), Some(0x00ff_0010));
+        assert_eq!(desktop.find(&button, 0), Some((5, 3)));
+        assert_eq!(desktop.crop(&rect(5, 3, 2, 2)), Some(button.clone()));
+        assert_eq!(desktop.crop(&rect(7, 3, 2, 2)), None);
+
+        // lossy codec
+        let expected = Framebuffer::from_pixels(2, 2, vec![0x00fe_0000; 4]).unwrap();
+        assert_eq!(desktop.find(&expected, 0), None);
+        assert_eq!(desktop.find(&expected, 0x10), Some((5, 3)));
+
+        // clipped on the right
+        desktop.blit(7, 0, &button);
+        assert_eq!(desktop.pixel(7, 1), Some(0x00ff_0010));
+        desktop.resize(4, 4);
+        assert_eq!(desktop.find(&button, 0), None);
+        assert!(Framebuffer::from_pixels(2, 2, vec![0; 3]).is_err());
+    }
+}
@@ -1,14 +1,175 @@
 use crate::{
     error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
-    message::{ChannelName, NowVirtualChannel},
-    sm::VirtualChannelSM,
+    message::{ChannelMessage, ChannelName, NowCapset, NowVirtualChannel},
+    serialization::Encode,
+    sm::{ChannelFlowControl, ChannelLiveness, TrafficCounters, VirtualChannelSM},
+    state_report::ChannelStatus,
 };
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, VecDeque};
+use std::{io::Cursor, time::Instant};
 
 pub type ChannelsManagerResult<'a> = Result<Option<(ChannelName, NowVirtualChannel<'a>)>, ProtoError>;
 
+/// Handler of a virtual channel, given messages already decoded for its channel.
+///
+/// Outgoing messages are polled whenever the channels manager is updated and after the channel is opened.
+///
+/// Each handler has its own queue: while a handler is not ready, messages of its channel are kept
+/// in order until it is, the other channels being processed meanwhile.
+pub trait ChannelHandler {
+    /// Message enum of the channel, such as `NowChatMsg`.
+    type Message<'a>: ChannelMessage<'a>;
+
+    /// called when the channel is opened, by the connection sequence or later on, again after a reconnection.
+    fn on_open(&mut self) {}
+
+    fn on_message(&mut self, message: &Self::Message<'_>) -> Result<(), ProtoError>;
+
+    /// `false` while the handler can't take another message, typically waiting on the application.
+    fn is_ready(&self) -> bool {
+        true
+    }
+
+    /// called when the channel is closed by either peer or when the session terminates.
+    /// Messages still queued for the handler are dropped.
+    fn on_close(&mut self) {}
+
+    fn poll_outgoing(&mut self) -> Option<Self::Message<'static>>;
+}
+
+trait AnyChannelHandler {
+    fn on_open(&mut self);
+    fn on_message(&mut self, chan_msg: &NowVirtualChannel<'_>) -> Result<(), ProtoError>;
+    fn is_ready(&self) -> bool;
+    fn on_close(&mut self);
+    fn poll_outgoing(&mut self) -> Option<NowVirtualChannel<'static>>;
+}
+
+impl<H> AnyChannelHandler for H
+where
+    H: ChannelHandler,
+{
+    fn on_open(&mut self) {
+        ChannelHandler::on_open(self)
+    }
+
+    fn on_message(&mut self, chan_msg: &NowVirtualChannel<'_>) -> Result<(), ProtoError> {
+        let message = H::Message::from_virt_channel(chan_msg)
+            .chain(ProtoErrorKind::ChannelsManager)
+            .or_else_desc(|| format!("unexpected message for channel {:?}", chan_msg.get_name()))?;
+        ChannelHandler::on_message(self, message)
+    }
+
+    fn is_ready(&self) -> bool {
+        ChannelHandler::is_ready(self)
+    }
+
+    fn on_close(&mut self) {
+        ChannelHandler::on_close(self)
+    }
+
+    fn poll_outgoing(&mut self) -> Option<NowVirtualChannel<'static>> {
+        ChannelHandler::poll_outgoing(self).map(Into::into)
+    }
+}
+
+struct RegisteredHandler {
+    handler: Box<dyn AnyChannelHandler>,
+    opened: bool,
+    /// Encoded messages received while the handler wasn't ready, oldest first.
+    inbox: VecDeque<Vec<u8>>,
+    /// Outgoing message polled ahead, so that `waiting_for_packet` is known.
+    next_outgoing: Option<NowVirtualChannel<'static>>,
+}
+
+impl RegisteredHandler {
+    fn __has_work(&self, name: &ChannelName, flow_control: &ChannelFlowControl) -> bool {
+        let can_send = self
+            .next_outgoing
+            .as_ref()
+            .is_some_and(|outgoing| flow_control.can_send(name, outgoing.encoded_len()));
+        can_send || (!self.inbox.is_empty() && self.handler.is_ready())
+    }
+
+    /// Processes the oldest queued message if the handler is ready, then takes the next outgoing message.
+    fn __process_queued(
+        &mut self,
+        name: &ChannelName,
+        flow_control: &mut ChannelFlowControl,
+    ) -> Result<Option<NowVirtualChannel<'static>>, ProtoError> {
+        if self.handler.is_ready() {
+            if let Some(encoded) = self.inbox.pop_front() {
+                let chan_msg = NowVirtualChannel::decode_from(name, &mut Cursor::new(&encoded[..]))?;
+                self.handler.on_message(&chan_msg)?;
+                flow_control.on_consumed(name, encoded.len());
+            }
+        }
+        Ok(self.__take_outgoing(name, flow_control))
+    }
+
+    fn __close(&mut self) {
+        if self.opened {
+            self.opened = false;
+            self.inbox.clear();
+            self.next_outgoing = None;
+            self.handler.on_close();
+        }
+    }
+
+    fn __poll_ahead(&mut self) {
+        if self.opened && self.next_outgoing.is_none() {
+            self.next_outgoing = self.handler.poll_outgoing();
+        }
+    }
+
+    /// Outgoing messages are kept until the peer grants enough credits.
+    fn __take_outgoing(
+        &mut self,
+        name: &ChannelName,
+        flow_control: &mut ChannelFlowControl,
+    ) -> Option<NowVirtualChannel<'static>> {
+        self.__poll_ahead();
+        let len = self.next_outgoing.as_ref()?.encoded_len();
+        if !flow_control.can_send(name, len) {
+            return None;
+        }
+        flow_control.on_sent(name, len);
+        let outgoing = self.next_outgoing.take();
+        self.__poll_ahead();
+        outgoing
+    }
+}
+
+const DEFAULT_INBOX_CAPACITY: usize = 256;
+
+/// Usage of a channel, counted from the encoded messages of the channel (framing excluded).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelStats {
+    pub traffic: TrafficCounters,
+    /// Messages received waiting to be processed, or to be sent waiting for credits.
+    pub queue_depth: usize,
+    /// Last message received or sent.
+    pub last_activity: Option<Instant>,
+}
+
+/// Dispatches virtual channel messages to the state machine or handler of their channel.
+///
+/// Messages of a channel are processed in the order they are received. Channels ready to be
+/// updated without message are served in turn, so that a busy channel doesn't starve the others.
+///
+/// Messages sent without being asked for are subject to the credits granted by the peer, see
+/// [`ChannelFlowControl`](../sm/struct.ChannelFlowControl.html). Answers to received messages are always sent.
 pub struct ChannelsManager {
     state_machines: BTreeMap<ChannelName, Box<dyn VirtualChannelSM>>,
+    handlers: BTreeMap<ChannelName, RegisteredHandler>,
+    /// Messages of state machines waiting for credits, the state machine isn't updated meanwhile.
+    held: BTreeMap<ChannelName, NowVirtualChannel<'static>>,
+    flow_control: ChannelFlowControl,
+    liveness: Option<ChannelLiveness>,
+    /// Traffic of each channel having received or sent a message.
+    activity: BTreeMap<ChannelName, ChannelStats>,
+    inbox_capacity: usize,
+    last_served: Option<ChannelName>,
 }
 
 impl Default for ChannelsManager {
@@ -21,9 +182,49 @@ impl ChannelsManager {
     pub fn new() -> Self {
         Self {
             state_machines: BTreeMap::new(),
+            handlers: BTreeMap::new(),
+            held: BTreeMap::new(),
+            flow_control: ChannelFlowControl::new(),
+            liveness: None,
+            activity: BTreeMap::new(),
+            inbox_capacity: DEFAULT_INBOX_CAPACITY,
+            last_served: None,
+        }
+    }
+
+    /// Maximum number of messages queued for a handler not ready. Receiving more is an error. Default is 256.
+    pub fn inbox_capacity(self, inbox_capacity: usize) -> Self {
+        Self { inbox_capacity, ..self }
+    }
+
+    pub fn flow_control(self, flow_control: ChannelFlowControl) -> Self {
+        Self { flow_control, ..self }
+    }
+
+    pub fn get_flow_control(&self) -> &ChannelFlowControl {
+        &self.flow_control
+    }
+
+    pub fn get_flow_control_mut(&mut self) -> &mut ChannelFlowControl {
+        &mut self.flow_control
+    }
+
+    /// Detects open channels whose peer stops answering. Stalls are reported by the sharee as channel events.
+    pub fn liveness(self, liveness: ChannelLiveness) -> Self {
+        Self {
+            liveness: Some(liveness),
+            ..self
         }
     }
 
+    pub fn get_liveness(&self) -> Option<&ChannelLiveness> {
+        self.liveness.as_ref()
+    }
+
+    pub fn get_liveness_mut(&mut self) -> Option<&mut ChannelLiveness> {
+        self.liveness.as_mut()
+    }
+
     pub fn with_sm<VirtChanSM>(mut self, state_machine: VirtChanSM) -> Self
     where
         VirtChanSM: VirtualChannelSM + 'static,
@@ -36,41 +237,505 @@ impl ChannelsManager {
     where
         VirtChanSM: VirtualChannelSM + 'static,
     {
+        self.handlers.remove(&state_machine.get_channel_name());
         self.state_machines
             .insert(state_machine.get_channel_name(), Box::new(state_machine))
     }
 
+    /// Registers `handler` for the channel `name`, replacing the state machine or handler registered
+    /// for this channel if any.
+    pub fn register<H>(&mut self, name: ChannelName, handler: H)
+    where
+        H: ChannelHandler + 'static,
+    {
+        self.state_machines.remove(&name);
+        self.handlers.insert(
+            name,
+            RegisteredHandler {
+                handler: Box::new(handler),
+                opened: false,
+                inbox: VecDeque::new(),
+                next_outgoing: None,
+            },
+        );
+    }
+
+    /// Notifies the state machine or handler of an opened channel.
+    pub fn on_channel_opened(&mut self, name: &ChannelName) {
+        self.flow_control.on_channel_opened(name);
+        if let Some(liveness) = &mut self.liveness {
+            liveness.on_channel_opened(name);
+        }
+        if let Some(registered) = self.handlers.get_mut(name) {
+            registered.opened = true;
+            registered.handler.on_open();
+            registered.__poll_ahead();
+        } else if let Some(sm) = self.state_machines.get_mut(name) {
+            sm.on_open();
+        }
+    }
+
+    /// Notifies the state machine or handler of a closed channel, dropping the messages queued for it.
+    pub fn on_channel_closed(&mut self, name: &ChannelName) {
+        self.flow_control.on_channel_closed(name);
+        if let Some(liveness) = &mut self.liveness {
+            liveness.on_channel_closed(name);
+        }
+        self.held.remove(name);
+        if let Some(registered) = self.handlers.get_mut(name) {
+            registered.__close();
+        } else if let Some(sm) = self.state_machines.get_mut(name) {
+            sm.on_close();
+        }
+    }
+
+    /// Notifies every state machine and handler the session terminated.
+    pub fn on_channels_closed(&mut self) {
+        self.flow_control.on_channels_closed();
+        if let Some(liveness) = &mut self.liveness {
+            liveness.on_channels_closed();
+        }
+        self.held.clear();
+        for registered in self.handlers.values_mut() {
+            registered.__close();
+        }
+        for sm in self.state_machines.values_mut() {
+            sm.on_close();
+        }
+    }
+
+    /// Updates the channel of a received message, its activity being recorded at `now`.
     pub fn update_with_virt_msg<'msg: 'a, 'a>(
         &mut self,
         chan_msg: &'a NowVirtualChannel<'msg>,
+        now: Instant,
     ) -> ChannelsManagerResult<'msg> {
-        if let Some(sm) = self.state_machines.get_mut(chan_msg.get_name()) {
-            sm.update_with_chan_msg(chan_msg)
-                .map(|o| o.map(|chan| (sm.get_channel_name(), chan)))
+        let name = chan_msg.get_name();
+        let len = chan_msg.encoded_len();
+        self.flow_control.on_received(name, len);
+        if let Some(liveness) = &mut self.liveness {
+            liveness.on_acknowledged(name);
+        }
+        self.__record_activity(name, now).traffic.record_in(len);
+        let result = self.__update_with_virt_msg(chan_msg, len);
+        self.__record_outgoing(&result, now);
+        result
+    }
+
+    fn __update_with_virt_msg<'msg: 'a, 'a>(
+        &mut self,
+        chan_msg: &'a NowVirtualChannel<'msg>,
+        len: usize,
+    ) -> ChannelsManagerResult<'msg> {
+        let name = chan_msg.get_name();
+        if let Some(registered) = self.handlers.get_mut(name) {
+            if registered.inbox.is_empty() && registered.handler.is_ready() {
+                registered.handler.on_message(chan_msg)?;
+                self.flow_control.on_consumed(name, len);
+            } else if registered.inbox.len() < self.inbox_capacity {
+                registered.inbox.push_back(chan_msg.encode()?);
+            } else {
+                return ProtoError::new(ProtoErrorKind::ChannelsManager).or_desc(format!(
+                    "too many messages queued for channel {:?}",
+                    chan_msg.get_name()
+                ));
+            }
+            Ok(registered
+                .__take_outgoing(name, &mut self.flow_control)
+                .map(|chan| (name.clone(), chan)))
+        } else if let Some(sm) = self.state_machines.get_mut(name) {
+            let answer = sm.update_with_chan_msg(chan_msg)?;
+            self.flow_control.on_consumed(name, len);
+            if let Some(answer) = &answer {
+                self.flow_control.on_sent(name, answer.encoded_len());
+            }
+            Ok(answer.map(|chan| (sm.get_channel_name(), chan)))
         } else {
             ProtoError::new(ProtoErrorKind::ChannelsManager)
                 .or_desc(format!("state machine for channel {:?} not found", chan_msg.get_name()))
         }
     }
 
-    pub fn update_without_virt_msg<'msg>(&mut self) -> ChannelsManagerResult<'msg> {
-        for sm in self.state_machines.values_mut() {
-            if !sm.waiting_for_packet() {
-                return sm
-                    .update_without_chan_msg()
-                    .map(|o| o.map(|chan| (sm.get_channel_name(), chan)));
+    /// Updates the next channel ready to send, a message sent being recorded at `now`.
+    pub fn update_without_virt_msg<'msg>(&mut self, now: Instant) -> ChannelsManagerResult<'msg> {
+        let result = self.__update_without_virt_msg();
+        self.__record_outgoing(&result, now);
+        result
+    }
+
+    fn __update_without_virt_msg<'msg>(&mut self) -> ChannelsManagerResult<'msg> {
+        let name = match self.__next_channel_to_serve() {
+            Some(name) => name,
+            None => {
+                return ProtoError::new(ProtoErrorKind::ChannelsManager)
+                    .or_desc("no channel state machine is ready to update without message")
+            }
+        };
+        self.last_served = Some(name.clone());
+
+        if let Some(sm) = self.state_machines.get_mut(&name) {
+            let outgoing: Option<NowVirtualChannel<'static>> = match self.held.remove(&name) {
+                Some(held) => Some(held),
+                None => sm.update_without_chan_msg()?,
+            };
+            match outgoing {
+                Some(chan) if !self.flow_control.can_send(&name, chan.encoded_len()) => {
+                    log::trace!("message of channel {:?} held until credits are granted", name);
+                    self.held.insert(name, chan);
+                    Ok(None)
+                }
+                Some(chan) => {
+                    self.flow_control.on_sent(&name, chan.encoded_len());
+                    Ok(Some((name, chan)))
+                }
+                None => Ok(None),
+            }
+        } else {
+            let registered = self.handlers.get_mut(&name).expect("channel to serve is registered");
+            registered
+                .__process_queued(&name, &mut self.flow_control)
+                .map(|o| o.map(|chan| (name.clone(), chan)))
+        }
+    }
+
+    pub fn channel_status(&self, name: &ChannelName) -> ChannelStatus {
+        if let Some(sm) = self.state_machines.get(name) {
+            if sm.is_terminated() {
+                ChannelStatus::Terminated
+            } else if !self.__sm_ready(name, sm.as_ref()) {
+                ChannelStatus::Waiting
+            } else {
+                ChannelStatus::Ready
+            }
+        } else if let Some(registered) = self.handlers.get(name) {
+            ChannelStatus::Handler {
+                opened: registered.opened,
+                queued: registered.inbox.len(),
+            }
+        } else {
+            ChannelStatus::Unhandled
+        }
+    }
+
+    /// Channels having a state machine or a handler.
+    pub fn channel_names(&self) -> Vec<ChannelName> {
+        let mut names: Vec<ChannelName> = self
+            .state_machines
+            .keys()
+            .chain(self.handlers.keys())
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Usage of `name` since the channels manager was created, reopening the channel included.
+    pub fn channel_stats(&self, name: &ChannelName) -> ChannelStats {
+        let queued_in = self.handlers.get(name).map_or(0, |registered| registered.inbox.len());
+        let queued_out = usize::from(self.held.contains_key(name))
+            + self
+                .handlers
+                .get(name)
+                .map_or(0, |registered| usize::from(registered.next_outgoing.is_some()));
+        ChannelStats {
+            queue_depth: queued_in + queued_out,
+            ..self.activity.get(name).copied().unwrap_or_default()
+        }
+    }
+
+    /// Usage of every channel having a state machine or a handler.
+    pub fn stats(&self) -> BTreeMap<ChannelName, ChannelStats> {
+        self.channel_names()
+            .into_iter()
+            .map(|name| {
+                let stats = self.channel_stats(&name);
+                (name, stats)
+            })
+            .collect()
+    }
+
+    /// Number of messages queued for the handler of `name`.
+    pub fn queued_messages(&self, name: &ChannelName) -> usize {
+        self.handlers.get(name).map_or(0, |registered| registered.inbox.len())
+    }
+
+    fn __record_activity(&mut self, name: &ChannelName, now: Instant) -> &mut ChannelStats {
+        let stats = self.activity.entry(name.clone()).or_default();
+        stats.last_activity = Some(now);
+        stats
+    }
+
+    fn __record_outgoing(&mut self, result: &ChannelsManagerResult<'_>, now: Instant) {
+        if let Ok(Some((name, chan))) = result {
+            let len = chan.encoded_len();
+            self.__record_activity(name, now).traffic.record_out(len);
+            if let Some(liveness) = &mut self.liveness {
+                liveness.on_sent(name, now);
             }
         }
-        ProtoError::new(ProtoErrorKind::ChannelsManager)
-            .or_desc("no channel state machine is ready to update without message")
+    }
+
+    /// A state machine holding a message is ready once the message can be sent.
+    fn __sm_ready(&self, name: &ChannelName, sm: &dyn VirtualChannelSM) -> bool {
+        match self.held.get(name) {
+            Some(held) => self.flow_control.can_send(name, held.encoded_len()),
+            None => !sm.waiting_for_packet(),
+        }
+    }
+
+    /// First channel ready after the last one served, wrapping around.
+    fn __next_channel_to_serve(&self) -> Option<ChannelName> {
+        let ready: Vec<&ChannelName> = self
+            .state_machines
+            .iter()
+            .filter(|(name, sm)| self.__sm_ready(name, sm.as_ref()))
+            .map(|(name, _)| name)
+            .chain(
+                self.handlers
+                    .iter()
+                    .filter(|(name, registered)| registered.__has_work(name, &self.flow_control))
+                    .map(|(name, _)| name),
+            )
+            .collect();
+        let last_served = self.last_served.as_ref();
+        ready
+            .iter()
+            .filter(|name| Some(**name) > last_served)
+            .min()
+            .or_else(|| ready.iter().min())
+            .map(|name| (*name).clone())
+    }
+
+    /// Notifies every channel state machine of the updated peer capabilities.
+    pub fn on_capabilities_updated(&mut self, peer_capabilities: &[NowCapset<'static>]) {
+        for sm in self.state_machines.values_mut() {
+            sm.on_capabilities_updated(peer_capabilities);
+        }
     }
 
     pub fn waiting_for_packet(&self) -> bool {
-        for sm in self.state_machines.values() {
-            if !sm.waiting_for_packet() {
+        for (name, sm) in &self.state_machines {
+            if self.__sm_ready(name, sm.as_ref()) {
                 return false;
             }
         }
-        true
+        !self
+            .handlers
+            .iter()
+            .any(|(name, registered)| registered.__has_work(name, &self.flow_control))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        NowChatMsg, NowChatPokeMsg, NowChatTextMsg, NowClipboardFormatDataReqMsg, NowClipboardMsg, NowString65535,
+    };
+    use std::{cell::RefCell, rc::Rc, str::FromStr, time::Duration};
+
+    #[derive(Default)]
+    struct EchoChat {
+        received: Rc<RefCell<Vec<NowChatMsg>>>,
+        outgoing: Vec<NowChatMsg>,
+        closed: Rc<RefCell<bool>>,
+    }
+
+    impl ChannelHandler for EchoChat {
+        type Message<'a> = NowChatMsg;
+
+        fn on_open(&mut self) {
+            self.outgoing.push(NowChatPokeMsg::new(0).into());
+        }
+
+        fn on_message(&mut self, message: &NowChatMsg) -> Result<(), ProtoError> {
+            self.received.borrow_mut().push(message.clone());
+            self.outgoing.push(message.clone());
+            Ok(())
+        }
+
+        fn on_close(&mut self) {
+            *self.closed.borrow_mut() = true;
+        }
+
+        fn poll_outgoing(&mut self) -> Option<NowChatMsg> {
+            self.outgoing.pop()
+        }
+    }
+
+    #[test]
+    fn handler_receives_typed_messages() {
+        let handler = EchoChat::default();
+        let received = Rc::clone(&handler.received);
+        let closed = Rc::clone(&handler.closed);
+        let mut manager = ChannelsManager::new();
+        manager.register(ChannelName::Chat, handler);
+
+        // nothing is sent before the channel is opened
+        assert!(manager.waiting_for_packet());
+        manager.on_channel_opened(&ChannelName::Chat);
+        assert!(!manager.waiting_for_packet());
+        let (name, poke) = manager.update_without_virt_msg(Instant::now()).unwrap().unwrap();
+        assert_eq!(name, ChannelName::Chat);
+        assert!(matches!(poke, NowVirtualChannel::Chat(NowChatMsg::Poke(_))));
+        assert!(manager.waiting_for_packet());
+
+        let text = NowVirtualChannel::from(NowChatTextMsg::new(0, 1, NowString65535::from_str("hello").unwrap()));
+        let (_, echo) = manager.update_with_virt_msg(&text, Instant::now()).unwrap().unwrap();
+        assert!(matches!(echo, NowVirtualChannel::Chat(NowChatMsg::Text(_))));
+        assert_eq!(received.borrow().len(), 1);
+
+        let mismatched =
+            NowVirtualChannel::Custom(crate::message::CustomVirtualChannel::new(ChannelName::Chat, &[][..]));
+        assert!(manager.update_with_virt_msg(&mismatched, Instant::now()).is_err());
+
+        manager.on_channels_closed();
+        assert!(*closed.borrow());
+    }
+
+    #[test]
+    fn liveness_follows_the_given_instant() {
+        let mut manager = ChannelsManager::new().liveness(ChannelLiveness::new(Duration::from_secs(10)));
+        manager.register(ChannelName::Chat, EchoChat::default());
+        manager.on_channel_opened(&ChannelName::Chat);
+
+        // far from the clock of the machine
+        let t0 = Instant::now() + Duration::from_secs(24 * 3600);
+        manager.update_without_virt_msg(t0).unwrap().unwrap();
+        assert_eq!(manager.channel_stats(&ChannelName::Chat).last_activity, Some(t0));
+
+        let liveness = manager.get_liveness_mut().unwrap();
+        assert_eq!(liveness.next_deadline(), Some(t0 + Duration::from_secs(10)));
+        assert_eq!(liveness.poll(t0 + Duration::from_secs(9)), None);
+        let stalled = liveness.poll(t0 + Duration::from_secs(10)).unwrap();
+        assert_eq!(stalled.name, ChannelName::Chat);
+    }
+
+    struct StalledClipboard {
+        ready: Rc<RefCell<bool>>,
+        sequence_ids: Rc<RefCell<Vec<u16>>>,
+    }
+
+    impl ChannelHandler for StalledClipboard {
+        type Message<'a> = NowClipboardMsg<'a>;
+
+        fn on_message(&mut self, message: &NowClipboardMsg<'_>) -> Result<(), ProtoError> {
+            if let NowClipboardMsg::FormatDataReq(req) = message {
+                self.sequence_ids.borrow_mut().push(req.sequence_id);
+            }
+            Ok(())
+        }
+
+        fn is_ready(&self) -> bool {
+            *self.ready.borrow()
+        }
+
+        fn poll_outgoing(&mut self) -> Option<NowClipboardMsg<'static>> {
+            None
+        }
+    }
+
+    #[test]
+    fn stalled_channel_does_not_delay_others() {
+        let ready = Rc::new(RefCell::new(false));
+        let sequence_ids = Rc::new(RefCell::new(Vec::new()));
+        let chat = EchoChat::default();
+        let chat_received = Rc::clone(&chat.received);
+        let mut manager = ChannelsManager::new().inbox_capacity(3);
+        manager.register(
+            ChannelName::Clipboard,
+            StalledClipboard {
+                ready: Rc::clone(&ready),
+                sequence_ids: Rc::clone(&sequence_ids),
+            },
+        );
+        manager.register(ChannelName::Chat, chat);
+        manager.on_channel_opened(&ChannelName::Clipboard);
+
+        for sequence_id in 0..3 {
+            let req = NowVirtualChannel::from(NowClipboardFormatDataReqMsg::new(sequence_id, 1));
+            assert!(manager.update_with_virt_msg(&req, Instant::now()).unwrap().is_none());
+        }
+        assert_eq!(manager.queued_messages(&ChannelName::Clipboard), 3);
+        let overflow = NowVirtualChannel::from(NowClipboardFormatDataReqMsg::new(3, 1));
+        assert!(manager.update_with_virt_msg(&overflow, Instant::now()).is_err());
+
+        // chat goes on while the clipboard is stalled
+        let text = NowVirtualChannel::from(NowChatTextMsg::new(0, 1, NowString65535::from_str("hi").unwrap()));
+        manager.update_with_virt_msg(&text, Instant::now()).unwrap();
+        assert_eq!(chat_received.borrow().len(), 1);
+        assert!(manager.waiting_for_packet());
+
+        // queued messages are processed in order once ready
+        *ready.borrow_mut() = true;
+        while !manager.waiting_for_packet() {
+            manager.update_without_virt_msg(Instant::now()).unwrap();
+        }
+        assert_eq!(*sequence_ids.borrow(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn ready_channels_served_in_turn() {
+        let mut manager = ChannelsManager::new();
+        for name in [ChannelName::Chat, ChannelName::Unknown("Echo".into())] {
+            // along with the poke sent on open
+            let handler = EchoChat {
+                outgoing: vec![NowChatPokeMsg::new(0).into()],
+                ..EchoChat::default()
+            };
+            manager.register(name.clone(), handler);
+            manager.on_channel_opened(&name);
+        }
+
+        let mut served = Vec::new();
+        while !manager.waiting_for_packet() {
+            served.push(manager.update_without_virt_msg(Instant::now()).unwrap().unwrap().0);
+        }
+        assert_eq!(
+            served,
+            vec![
+                ChannelName::Unknown("Echo".into()),
+                ChannelName::Chat,
+                ChannelName::Unknown("Echo".into()),
+                ChannelName::Chat,
+            ]
+        );
+    }
+
+    #[test]
+    fn stats_count_traffic_and_queue_depth() {
+        let ready = Rc::new(RefCell::new(false));
+        let mut manager = ChannelsManager::new();
+        manager.register(ChannelName::Chat, EchoChat::default());
+        manager.register(
+            ChannelName::Clipboard,
+            StalledClipboard {
+                ready: Rc::clone(&ready),
+                sequence_ids: Rc::new(RefCell::new(Vec::new())),
+            },
+        );
+        manager.on_channel_opened(&ChannelName::Chat);
+        manager.on_channel_opened(&ChannelName::Clipboard);
+        assert_eq!(manager.channel_stats(&ChannelName::Chat).last_activity, None);
+
+        let now = Instant::now();
+        let text = NowVirtualChannel::from(NowChatTextMsg::new(0, 1, NowString65535::from_str("hello").unwrap()));
+        manager.update_with_virt_msg(&text, now).unwrap();
+        let req = NowVirtualChannel::from(NowClipboardFormatDataReqMsg::new(0, 1));
+        manager.update_with_virt_msg(&req, now).unwrap();
+        manager.update_with_virt_msg(&req, now).unwrap();
+
+        let chat = manager.channel_stats(&ChannelName::Chat);
+        assert_eq!(chat.traffic.messages_in, 1);
+        assert_eq!(chat.traffic.bytes_in, text.encoded_len() as u64);
+        assert_eq!(chat.traffic.messages_out, 1);
+        // poke sent on open polled ahead
+        assert_eq!(chat.queue_depth, 1);
+        assert_eq!(chat.last_activity, Some(now));
+
+        let clipboard = manager.stats()[&ChannelName::Clipboard];
+        assert_eq!(clipboard.traffic.messages_in, 2);
+        assert_eq!(clipboard.traffic.messages_out, 0);
+        assert_eq!(clipboard.queue_depth, 2);
     }
 }
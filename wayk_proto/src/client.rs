@@ -0,0 +1,724 @@
+//! Async client session.
+//!
+//! [`NowClient`](struct.NowClient.html) runs the whole connection sequence (handshake, negotiation,
+//! authentication, association, capabilities and channels) in a single `connect` call, then answers
+//! the protocol messages on its own: only the messages left to the application are returned by
+//! [`recv`](struct.NowClient.html#method.recv). Input and virtual channel messages are sent through
+//! typed handles.
+//!
+//! Like the rest of the crate, the client is not `Send`: it is driven from a single task.
+
+use crate::{
+    channels_manager::{ChannelHandler, ChannelStats, ChannelsManager},
+    container::Vec8,
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        AuthType, ChannelName, DisconnectStatusCode, EventMouseFlags, InputEvent, NowBody, NowCapset, NowChannelDef,
+        NowCodecDef, NowInputEventKeyboard, NowInputEventMouse, NowInputEventScroll, NowInputMsg, NowMessage,
+        NowTerminateMsg, NowVirtualChannel, UpdateCapset, VirtChannelsCtx,
+    },
+    middleware::{MessageHook, MessageHooks},
+    packet::NowPacket,
+    serialization::Encode,
+    server::NowSessionPacket,
+    sharee::{merge_capabilities, Sharee, ShareeCallbackTrait, ShareeResult},
+    sm::{
+        AutoResponder, ChannelCloseReason, ChannelLifecycleEvent, ChannelLifecycleState, ClientConnectionSeqSM,
+        ConnectionSM, DummyConnectionSM, DummyConnectionSeqCallback, Heartbeat, LatencySnapshot, StepTimeouts,
+        SurfaceEventQueue, SurfaceManager,
+    },
+    state_report::SessionStateReport,
+    transport::{NowTransport, ShutdownOutcome, StreamTransport, Transport, TransportEvent, TransportTimeouts},
+    version::{NowProtocolVersion, NowProtocolVersionRange},
+};
+use alloc::collections::{BTreeMap, VecDeque};
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Configuration of a client session.
+///
+/// Everything the connection sequence advertises is derived from this configuration: authentication
+/// types, capability sets (codecs included) and channels to open, the channels handled by the channels
+/// manager being opened as well.
+pub struct NowClientConfig {
+    available_auth_types: Vec<AuthType>,
+    authenticate_sm: Box<dyn ConnectionSM>,
+    capabilities: Vec<NowCapset<'static>>,
+    channels_to_open: Vec<ChannelName>,
+    channels_manager: ChannelsManager,
+    timeouts: TransportTimeouts,
+    versions: NowProtocolVersionRange,
+    hooks: MessageHooks,
+    auto_responder: AutoResponder,
+    heartbeat: Option<(Duration, Duration)>,
+}
+
+impl Default for NowClientConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NowClientConfig {
+    pub fn new() -> Self {
+        Self {
+            available_auth_types: Vec::new(),
+            authenticate_sm: Box::new(DummyConnectionSM),
+            capabilities: Vec::new(),
+            channels_to_open: Vec::new(),
+            channels_manager: ChannelsManager::new(),
+            timeouts: TransportTimeouts::default(),
+            versions: NowProtocolVersionRange::default(),
+            hooks: MessageHooks::new(),
+            auto_responder: AutoResponder::default(),
+            heartbeat: None,
+        }
+    }
+
+    /// Authentication types, in order of preference.
+    pub fn available_auth_process(self, available_auth_types: Vec<AuthType>) -> Self {
+        Self {
+            available_auth_types,
+            ..self
+        }
+    }
+
+    /// Authentication state machine run against the server.
+    pub fn authenticate_sm<P: ConnectionSM + 'static>(self, sm: P) -> Self {
+        Self {
+            authenticate_sm: Box::new(sm),
+            ..self
+        }
+    }
+
+    pub fn capabilities(self, capabilities: Vec<NowCapset<'static>>) -> Self {
+        Self { capabilities, ..self }
+    }
+
+    /// Adds a capability set, replacing the one of the same kind if any.
+    pub fn capability(mut self, capset: NowCapset<'static>) -> Self {
+        merge_capabilities(&mut self.capabilities, &[capset]);
+        self
+    }
+
+    /// Codecs supported for graphics updates, in order of preference, advertised by the update capability set.
+    pub fn codecs(mut self, codecs: Vec<NowCodecDef>) -> Self {
+        let update_capset = self.capabilities.iter_mut().find_map(|capset| match capset {
+            NowCapset::Update(capset) => Some(capset),
+            _ => None,
+        });
+        match update_capset {
+            Some(capset) => capset.codecs = Vec8(codecs),
+            None => self
+                .capabilities
+                .push(NowCapset::Update(UpdateCapset::new_with_supported_codecs(codecs))),
+        }
+        self
+    }
+
+    /// Protocol versions supported. Channels unavailable with the version selected by the server aren't opened.
+    pub fn versions(self, versions: NowProtocolVersionRange) -> Self {
+        Self { versions, ..self }
+    }
+
+    /// Adds a hook observing or rewriting the messages of the session, see `middleware`.
+    pub fn hook<H: MessageHook + 'static>(mut self, hook: H) -> Self {
+        self.hooks.add(hook);
+        self
+    }
+
+    /// Routine messages (keep-alives, probes and their acknowledgments) answered by the session
+    /// instead of being left to the application.
+    pub fn auto_responder(self, auto_responder: AutoResponder) -> Self {
+        Self { auto_responder, ..self }
+    }
+
+    /// Sends keep-alive requests on `interval` once connected, from which the latency and the clock
+    /// of the server are estimated (see `NowClient::latency`). A server silent for `timeout` is logged.
+    ///
+    /// Keep-alive requests of the server are then answered by the heartbeat: the keep-alive family
+    /// of the auto-responder is to be left disabled.
+    pub fn heartbeat(self, interval: Duration, timeout: Duration) -> Self {
+        Self {
+            heartbeat: Some((interval, timeout)),
+            ..self
+        }
+    }
+
+    pub fn channels_to_open(self, channels_to_open: Vec<ChannelName>) -> Self {
+        Self {
+            channels_to_open,
+            ..self
+        }
+    }
+
+    /// Adds a channel to open.
+    pub fn channel(mut self, channel: ChannelName) -> Self {
+        if !self.channels_to_open.contains(&channel) {
+            self.channels_to_open.push(channel);
+        }
+        self
+    }
+
+    /// Handlers for the channels to open.
+    pub fn channels_manager(self, channels_manager: ChannelsManager) -> Self {
+        Self {
+            channels_manager,
+            ..self
+        }
+    }
+
+    pub fn timeouts(self, timeouts: TransportTimeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    /// See `TransportTimeouts::connect`.
+    pub fn connect_timeout(self, connect: Duration) -> Self {
+        Self {
+            timeouts: self.timeouts.connect(connect),
+            ..self
+        }
+    }
+
+    /// See `TransportTimeouts::read`.
+    pub fn read_timeout(self, read: Duration) -> Self {
+        Self {
+            timeouts: self.timeouts.read(read),
+            ..self
+        }
+    }
+
+    /// See `TransportTimeouts::idle`.
+    pub fn idle_timeout(self, idle: Duration) -> Self {
+        Self {
+            timeouts: self.timeouts.idle(idle),
+            ..self
+        }
+    }
+
+    /// See `TransportTimeouts::steps`.
+    pub fn step_timeouts(self, steps: StepTimeouts) -> Self {
+        Self {
+            timeouts: self.timeouts.steps(steps),
+            ..self
+        }
+    }
+
+    /// Channels opened by the connection sequence: the declared ones, then those handled by the channels manager.
+    pub fn get_channels_to_open(&self) -> Vec<ChannelName> {
+        let mut channels = self.channels_to_open.clone();
+        for name in self.channels_manager.channel_names() {
+            if !channels.contains(&name) {
+                channels.push(name);
+            }
+        }
+        channels
+    }
+
+    pub fn get_capabilities(&self) -> &[NowCapset<'static>] {
+        &self.capabilities
+    }
+
+    pub fn get_timeouts(&self) -> TransportTimeouts {
+        self.timeouts
+    }
+
+    fn __split(
+        self,
+    ) -> (
+        ClientConnectionSeqSM<DummyConnectionSeqCallback>,
+        ChannelsManager,
+        TransportTimeouts,
+        MessageHooks,
+    ) {
+        let channels_to_open = self.get_channels_to_open();
+        let mut connection_seq = ClientConnectionSeqSM::new(
+            DummyConnectionSeqCallback,
+            self.available_auth_types,
+            self.authenticate_sm,
+            self.capabilities,
+            channels_to_open.into_iter().map(NowChannelDef::new).collect(),
+        );
+        connection_seq.set_versions(self.versions);
+        (connection_seq, self.channels_manager, self.timeouts, self.hooks)
+    }
+}
+
+/// Queues the messages unprocessed by the sharee for the application.
+struct QueueCallback {
+    unprocessed: Rc<RefCell<VecDeque<Vec<u8>>>>,
+}
+
+impl ShareeCallbackTrait for QueueCallback {
+    fn on_unprocessed_message<'msg: 'a, 'a>(&mut self, message: &'a NowMessage<'msg>) -> ShareeResult<'msg> {
+        let bytes = NowPacket::from_message(message.clone()).encode()?;
+        self.unprocessed.borrow_mut().push_back(bytes);
+        Ok(None)
+    }
+}
+
+type ClientSharee = Sharee<ClientConnectionSeqSM<DummyConnectionSeqCallback>, QueueCallback>;
+
+/// Sends `packet` unless dropped by the outgoing hooks of the sharee.
+async fn send_intercepted<T: Transport>(
+    transport: &mut NowTransport<T>,
+    sharee: &mut ClientSharee,
+    packet: NowPacket<'_>,
+) -> Result<(), ProtoError> {
+    match sharee.intercept_outgoing(packet, Instant::now()) {
+        Some(packet) => transport.send(packet).await,
+        None => Ok(()),
+    }
+}
+
+/// Established client session.
+pub struct NowClient<T> {
+    transport: NowTransport<T>,
+    sharee: ClientSharee,
+    unprocessed: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    heartbeat: Option<Heartbeat>,
+}
+
+impl<S> NowClient<StreamTransport<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Runs the connection sequence over `stream`, typically a TCP stream connected to the server.
+    pub async fn connect(stream: S, config: NowClientConfig) -> Result<Self, ProtoError> {
+        Self::connect_transport(StreamTransport::new(stream), config).await
+    }
+}
+
+impl<T> NowClient<T>
+where
+    T: Transport,
+{
+    /// Runs the connection sequence over `transport`.
+    ///
+    /// Fails if the server refuses the connection or if the connect timeout of the configuration is reached.
+    pub async fn connect_transport(transport: T, config: NowClientConfig) -> Result<Self, ProtoError> {
+        let auto_responder = config.auto_responder;
+        let heartbeat = config.heartbeat;
+        let (connection_seq, channels_manager, timeouts, hooks) = config.__split();
+
+        let unprocessed = Rc::new(RefCell::new(VecDeque::new()));
+        let mut sharee = Sharee::new(
+            connection_seq,
+            channels_manager,
+            QueueCallback {
+                unprocessed: Rc::clone(&unprocessed),
+            },
+        );
+        sharee.set_auto_responder(auto_responder);
+        sharee.set_hooks(hooks);
+
+        let mut transport = NowTransport::with_transport(transport);
+        transport.set_timeouts(timeouts);
+        transport.connect(&mut sharee).await?;
+
+        Ok(Self {
+            transport,
+            sharee,
+            unprocessed,
+            heartbeat: heartbeat.map(|(interval, timeout)| Heartbeat::new(interval, timeout, Instant::now())),
+        })
+    }
+
+    /// Re-attaches the session to `transport`, typically a new connection once the previous one was lost.
+    ///
+    /// The connection sequence is run again, associating with the previous session if the server
+    /// provided one. Virtual channel state machines, surfaces and the messages not received yet are
+    /// kept: the channels manager, the hooks, the auto-responder and the heartbeat of `config` are not used.
+    /// Latency is estimated again over the new link.
+    pub async fn reattach(&mut self, transport: T, config: NowClientConfig) -> Result<(), ProtoError> {
+        let session_id = self.sharee.get_session_id();
+        let (connection_seq, _, timeouts, _) = config.__split();
+        if let Some(shared_data) = connection_seq.get_shared_data() {
+            shared_data.borrow_mut().session_id = session_id;
+        }
+        self.sharee.reconnect(connection_seq);
+
+        let mut transport = NowTransport::with_transport(transport);
+        transport.set_timeouts(timeouts);
+        transport.connect(&mut self.sharee).await?;
+        log::debug!("re-attached to session {:?}", self.sharee.get_session_id());
+
+        // the previous link is dead: pending frames are dropped along with it
+        self.transport = transport;
+        if let Some(heartbeat) = &mut self.heartbeat {
+            *heartbeat = Heartbeat::new(heartbeat.keep_alive().interval(), heartbeat.timeout(), Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Session the connection is associated with, if the server provided one.
+    pub fn session_id(&self) -> Option<u32> {
+        self.sharee.get_session_id()
+    }
+
+    /// Protocol version selected by the server, from which the features enabled for the session are derived.
+    pub fn negotiated_version(&self) -> Option<NowProtocolVersion> {
+        self.sharee.negotiated_version()
+    }
+
+    /// Round trip time, jitter and clock of the server estimated so far, `None` without heartbeat
+    /// (see `NowClientConfig::heartbeat`).
+    pub fn latency(&self) -> Option<LatencySnapshot> {
+        self.heartbeat.as_ref().map(|heartbeat| heartbeat.latency().snapshot())
+    }
+
+    /// Snapshot of the session state, see `Sharee::state_report`.
+    pub fn state_report(&self) -> SessionStateReport {
+        self.sharee.state_report()
+    }
+
+    /// Channels currently opened.
+    pub fn get_channels_ctx(&self) -> &VirtChannelsCtx {
+        self.sharee.get_channels_ctx()
+    }
+
+    /// Usage of each channel having a handler, see `ChannelsManager::stats`.
+    pub fn channel_stats(&self) -> BTreeMap<ChannelName, ChannelStats> {
+        self.sharee.get_channels_manager().stats()
+    }
+
+    /// Oldest channel opened or closed not polled yet, see `Sharee::poll_channel_event`.
+    pub fn poll_channel_event(&mut self) -> Option<ChannelLifecycleEvent> {
+        self.sharee.poll_channel_event()
+    }
+
+    /// Remote monitor topology as known from the surface list updates received so far.
+    pub fn surfaces(&self) -> &SurfaceManager<SurfaceEventQueue> {
+        self.sharee.get_surfaces()
+    }
+
+    pub fn get_transport(&self) -> &NowTransport<T> {
+        &self.transport
+    }
+
+    pub fn get_transport_mut(&mut self) -> &mut NowTransport<T> {
+        &mut self.transport
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.sharee.is_terminated()
+    }
+
+    /// `true` once the link failed, see `Sharee::on_error`.
+    pub fn is_interrupted(&self) -> bool {
+        self.sharee.is_interrupted()
+    }
+
+    pub fn input(&mut self) -> NowInputHandle<'_, T> {
+        NowInputHandle {
+            transport: &mut self.transport,
+            sharee: &mut self.sharee,
+        }
+    }
+
+    /// Handle on `channel`, if opened.
+    pub fn channel(&mut self, channel: &ChannelName) -> Option<NowChannelHandle<'_, T>> {
+        let id = self.sharee.get_channels_ctx().get_id_by_channel(channel)?;
+        Some(NowChannelHandle {
+            id,
+            transport: &mut self.transport,
+            sharee: &mut self.sharee,
+        })
+    }
+
+    pub fn clipboard(&mut self) -> Option<NowChannelHandle<'_, T>> {
+        self.channel(&ChannelName::Clipboard)
+    }
+
+    pub fn chat(&mut self) -> Option<NowChannelHandle<'_, T>> {
+        self.channel(&ChannelName::Chat)
+    }
+
+    /// Registers `handler` for `channel` once connected, so that the channel can be opened when
+    /// first needed instead of at connect time.
+    pub fn register_channel<H>(&mut self, channel: ChannelName, handler: H) -> Result<(), ProtoError>
+    where
+        H: ChannelHandler + 'static,
+    {
+        self.sharee.register_channel(channel, handler)
+    }
+
+    /// Channels currently available on the server, resolving when the server answers.
+    pub async fn available_channels(&mut self) -> Result<Vec<ChannelName>, ProtoError> {
+        let request = self.sharee.request_channel_list()?;
+        send_intercepted(&mut self.transport, &mut self.sharee, request).await?;
+
+        loop {
+            if let Some(channels) = self.sharee.peer_channels() {
+                return Ok(channels.to_vec());
+            }
+
+            if !self.__step().await? {
+                return ProtoError::new(ProtoErrorKind::Sharee(self.sharee.get_state()))
+                    .or_desc("session ended before the channel list was received");
+            }
+        }
+    }
+
+    /// Opens `channel` once connected, resolving when the server acknowledges it.
+    ///
+    /// Messages received meanwhile are processed as usual, the ones left to the application being
+    /// kept for `recv`. Fails with the close reason if the server refuses the channel or if the
+    /// session ends first.
+    pub async fn open_channel(&mut self, channel: ChannelName) -> Result<NowChannelHandle<'_, T>, ProtoError> {
+        let request = self.sharee.open_channel(channel.clone())?;
+        send_intercepted(&mut self.transport, &mut self.sharee, request).await?;
+
+        let id = loop {
+            match self.sharee.channel_state(&channel) {
+                Some(ChannelLifecycleState::Open { id }) => break id,
+                Some(ChannelLifecycleState::Closed(reason)) => {
+                    return ProtoError::new(ProtoErrorKind::VirtualChannel(channel))
+                        .or_else_desc(|| format!("channel not opened: {}", reason))
+                }
+                _ => {}
+            }
+
+            if !self.__step().await? {
+                return ProtoError::new(ProtoErrorKind::VirtualChannel(channel))
+                    .or_else_desc(|| format!("channel not opened: {}", ChannelCloseReason::SessionTerminated));
+            }
+        };
+
+        Ok(NowChannelHandle {
+            id,
+            transport: &mut self.transport,
+            sharee: &mut self.sharee,
+        })
+    }
+
+    /// Closes `channel`, resolving when the server acknowledges it or when the channel is closed otherwise.
+    pub async fn close_channel(&mut self, channel: ChannelName) -> Result<ChannelCloseReason, ProtoError> {
+        let request = self.sharee.close_channel(channel.clone())?;
+        send_intercepted(&mut self.transport, &mut self.sharee, request).await?;
+
+        loop {
+            if let Some(ChannelLifecycleState::Closed(reason)) = self.sharee.channel_state(&channel) {
+                return Ok(reason);
+            }
+
+            if !self.__step().await? {
+                return Ok(ChannelCloseReason::SessionTerminated);
+            }
+        }
+    }
+
+    /// Closes then opens again `channel`, such as when it stalled, resolving when the server acknowledges
+    /// both requests. See `Sharee::reset_channel`.
+    pub async fn reset_channel(&mut self, channel: ChannelName) -> Result<NowChannelHandle<'_, T>, ProtoError> {
+        let request = self.sharee.reset_channel(channel.clone())?;
+        send_intercepted(&mut self.transport, &mut self.sharee, request).await?;
+
+        let id = loop {
+            match self.sharee.channel_state(&channel) {
+                Some(ChannelLifecycleState::Open { id }) => break id,
+                Some(ChannelLifecycleState::Closed(reason)) if reason != ChannelCloseReason::Local => {
+                    return ProtoError::new(ProtoErrorKind::VirtualChannel(channel))
+                        .or_else_desc(|| format!("channel not reset: {}", reason))
+                }
+                _ => {}
+            }
+
+            if !self.__step().await? {
+                return ProtoError::new(ProtoErrorKind::VirtualChannel(channel))
+                    .or_else_desc(|| format!("channel not reset: {}", ChannelCloseReason::SessionTerminated));
+            }
+        };
+
+        Ok(NowChannelHandle {
+            id,
+            transport: &mut self.transport,
+            sharee: &mut self.sharee,
+        })
+    }
+
+    pub async fn send<'a, P: Into<NowPacket<'a>>>(&mut self, packet: P) -> Result<(), ProtoError> {
+        send_intercepted(&mut self.transport, &mut self.sharee, packet.into()).await
+    }
+
+    /// Next message left to the application.
+    ///
+    /// Protocol and virtual channel messages handled by the session are answered meanwhile.
+    /// Returns `None` once the link is closed or the server terminated the session.
+    pub async fn recv(&mut self) -> Result<Option<NowSessionPacket>, ProtoError> {
+        loop {
+            if let Some(bytes) = self.unprocessed.borrow_mut().pop_front() {
+                return Ok(Some(NowSessionPacket::new(
+                    bytes,
+                    self.sharee.get_channels_ctx().clone(),
+                )));
+            }
+
+            if !self.__step().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Updates the sharee once, with the next packet received if it waits for one.
+    /// `false` once the link is closed or the session terminated or interrupted.
+    ///
+    /// Errors are recorded by the sharee, interrupting the session on transport failures.
+    async fn __step(&mut self) -> Result<bool, ProtoError> {
+        let result = self.__update().await;
+        if let Err(err) = &result {
+            self.sharee.on_error(err);
+        }
+        result
+    }
+
+    async fn __update(&mut self) -> Result<bool, ProtoError> {
+        if !self.sharee.is_running() {
+            return Ok(false);
+        }
+
+        let now = Instant::now();
+        if !self.sharee.waiting_for_packet(now) {
+            if let Some(answer) = self.sharee.update_without_body(now)? {
+                self.transport.send(answer).await?;
+            }
+            return Ok(true);
+        }
+
+        // the answer may borrow the received packet: it is encoded before writing
+        let received = match &mut self.heartbeat {
+            Some(heartbeat) => match self.transport.recv_with_heartbeat(heartbeat).await? {
+                TransportEvent::Packet(packet) => Some(packet),
+                TransportEvent::PeerSilent(silence) => {
+                    log::warn!("server silent for {:?}", silence);
+                    return Ok(true);
+                }
+                TransportEvent::Closed => None,
+            },
+            None => self.transport.recv().await?,
+        };
+        let (answer, channels_updated) = match received {
+            Some(packet) => {
+                let channels_updated = matches!(packet.body, NowBody::Message(NowMessage::Channel(_)));
+                let answer = match self.sharee.update_with_body(&packet.body, Instant::now())? {
+                    Some(answer) => Some(answer.encode()?),
+                    None => None,
+                };
+                (answer, channels_updated)
+            }
+            None => return Ok(false),
+        };
+
+        if channels_updated {
+            // packets of channels opened since are to be decoded as well
+            self.transport.set_channels_ctx(self.sharee.get_channels_ctx().clone());
+        }
+
+        match answer {
+            Some(answer) => self.transport.write_frame(&answer).await?,
+            None if self.sharee.is_terminated() => {
+                // the server may close the link without waiting for the acknowledgement
+                if let Err(err) = self.transport.send(NowTerminateMsg::default()).await {
+                    log::debug!("couldn't acknowledge termination: {}", err);
+                }
+                return Ok(false);
+            }
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Terminates the session and closes the link.
+    pub async fn close(mut self) -> Result<(), ProtoError> {
+        if self.sharee.is_running() {
+            self.transport.send(NowTerminateMsg::default()).await?;
+        }
+        self.transport.close().await
+    }
+
+    /// Terminates the session with `reason`, waiting up to `ack_timeout` for the server to acknowledge
+    /// it before closing the link. See [`NowTransport::shutdown`](../transport/struct.NowTransport.html#method.shutdown).
+    pub async fn shutdown(
+        mut self,
+        reason: DisconnectStatusCode,
+        ack_timeout: Duration,
+    ) -> Result<ShutdownOutcome, ProtoError> {
+        if !self.sharee.is_running() {
+            self.transport.close().await?;
+            return Ok(ShutdownOutcome::Closed);
+        }
+        self.transport.shutdown(reason, ack_timeout).await
+    }
+}
+
+/// Sends input events to the server.
+pub struct NowInputHandle<'a, T> {
+    transport: &'a mut NowTransport<T>,
+    sharee: &'a mut ClientSharee,
+}
+
+impl<T> NowInputHandle<'_, T>
+where
+    T: Transport,
+{
+    /// Sends the events in a single message.
+    pub async fn send_events(&mut self, events: Vec<InputEvent<'_>>) -> Result<(), ProtoError> {
+        send_intercepted(self.transport, self.sharee, NowInputMsg::new_with_events(events).into()).await
+    }
+
+    /// Moves the mouse to `(x, y)` with the `buttons` pressed.
+    pub async fn mouse(&mut self, buttons: EventMouseFlags, x: i16, y: i16) -> Result<(), ProtoError> {
+        self.send_events(vec![InputEvent::Mouse(
+            NowInputEventMouse::new_with_flags_and_position(buttons, x, y),
+        )])
+        .await
+    }
+
+    pub async fn scroll(&mut self, x: i16, y: i16) -> Result<(), ProtoError> {
+        self.send_events(vec![InputEvent::Scroll(NowInputEventScroll::new_with_position(x, y))])
+            .await
+    }
+
+    pub async fn key(&mut self, flags: u8, code: u16) -> Result<(), ProtoError> {
+        self.send_events(vec![InputEvent::Keyboard(
+            NowInputEventKeyboard::new_with_flags_and_code(flags, code),
+        )])
+        .await
+    }
+}
+
+/// Sends messages over an opened virtual channel.
+pub struct NowChannelHandle<'a, T> {
+    id: u8,
+    transport: &'a mut NowTransport<T>,
+    sharee: &'a mut ClientSharee,
+}
+
+impl<T> NowChannelHandle<'_, T>
+where
+    T: Transport,
+{
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub async fn send<'msg, C: Into<NowVirtualChannel<'msg>>>(&mut self, message: C) -> Result<(), ProtoError> {
+        let message = message.into();
+        let expected = self.transport.get_channels_ctx().get_channel_by_id(self.id);
+        if expected != Some(message.get_name()) {
+            return ProtoError::new(ProtoErrorKind::ChannelsManager)
+                .or_else_desc(|| format!("{:?} message sent over channel {:?}", message.get_name(), expected));
+        }
+
+        send_intercepted(
+            self.transport,
+            self.sharee,
+            NowPacket::from_virt_channel(message, self.id),
+        )
+        .await
+    }
+}
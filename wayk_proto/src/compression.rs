@@ -0,0 +1,616 @@
+//! Transparent compression of packet bodies.
+//!
+//! Compression is negotiated with the `COMPRESSION` flag of the transport capset: once both
+//! peers advertised it, bodies above a size threshold may be compressed. A compressed packet
+//! has the compressed flag set in its header and its body is the uncompressed size (`u32`)
+//! followed by a zstd frame.
+//!
+//! Compressed packets are recognized by their header, so decompression doesn't depend on negotiation.
+//!
+//! Independently, messages of individual virtual channels may be compressed with [`ChannelCompression`],
+//! negotiated with the channel compression capset. Codecs are pluggable, zstd and LZ4 being built in.
+
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    header::{AbstractNowHeader, NowHeader},
+    message::{BodyType, ChannelCodecsDef, ChannelCompressionCapset, ChannelName, NowCapset, VirtChannelsCtx},
+    serialization::{Decode, DecodeLimits, Encode},
+};
+use alloc::{borrow::Cow, collections::BTreeMap, rc::Rc};
+use byteorder::{ByteOrder, LittleEndian};
+use core::convert::TryFrom;
+
+/// Whether both capability sets advertise compression.
+pub fn is_negotiated(capabilities: &[NowCapset<'_>], peer_capabilities: &[NowCapset<'_>]) -> bool {
+    fn advertised(capabilities: &[NowCapset<'_>]) -> bool {
+        capabilities.iter().any(|capset| match capset {
+            NowCapset::Transport(capset) => capset.flags.compression(),
+            _ => false,
+        })
+    }
+
+    advertised(capabilities) && advertised(peer_capabilities)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCompression {
+    threshold: usize,
+}
+
+impl Default for FrameCompression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameCompression {
+    pub const DEFAULT_THRESHOLD: usize = 256;
+
+    const UNCOMPRESSED_SIZE_LEN: usize = 4;
+    const ZSTD_LEVEL: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+
+    pub fn new() -> Self {
+        Self {
+            threshold: Self::DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// Bodies smaller than `threshold` bytes are sent as is.
+    pub fn threshold(self, threshold: usize) -> Self {
+        Self { threshold }
+    }
+
+    /// Compresses the body of an encoded packet if it is above the threshold and compression pays off.
+    pub fn compress_frame<'a>(&self, frame: &'a [u8]) -> Result<Cow<'a, [u8]>, ProtoError> {
+        let header = NowHeader::decode(frame)?;
+        if header.is_compressed() || header.body_len() < self.threshold {
+            return Ok(Cow::Borrowed(frame));
+        }
+
+        let body = frame
+            .get(header.len()..header.packet_len())
+            .chain(ProtoErrorKind::Encoding("compressed packet"))
+            .or_else_desc(|| format!("frame shorter ({}) than its packet", frame.len()))?;
+        let block = zstd::bulk::compress(body, Self::ZSTD_LEVEL)
+            .map_err(ProtoError::from)
+            .chain(ProtoErrorKind::Encoding("compressed packet"))?;
+        let compressed_len = Self::UNCOMPRESSED_SIZE_LEN + block.len();
+        if compressed_len >= body.len() {
+            return Ok(Cow::Borrowed(frame));
+        }
+
+        let compressed_header = NowHeader::new(header.body_type(), compressed_len as u32).compressed();
+        let mut compressed = compressed_header.encode()?;
+        compressed.reserve(compressed_len);
+        compressed.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        compressed.extend_from_slice(&block);
+        Ok(Cow::Owned(compressed))
+    }
+
+    /// Decompresses the body of a received packet if its header is flagged as compressed.
+    ///
+    /// The uncompressed size is checked against `limits` before anything is allocated.
+    pub fn decompress_frame<'a>(frame: &'a [u8], limits: DecodeLimits) -> Result<Cow<'a, [u8]>, ProtoError> {
+        let header = NowHeader::decode(frame)?;
+        if !header.is_compressed() {
+            return Ok(Cow::Borrowed(frame));
+        }
+
+        let body = frame
+            .get(header.len()..header.packet_len())
+            .filter(|body| body.len() >= Self::UNCOMPRESSED_SIZE_LEN)
+            .chain(ProtoErrorKind::Decoding("compressed packet"))
+            .or_else_desc(|| format!("frame too short ({}) for a compressed packet", frame.len()))?;
+
+        let uncompressed_len = LittleEndian::read_u32(body) as usize;
+        if uncompressed_len > limits.max_message_size {
+            return ProtoError::new(ProtoErrorKind::LimitExceeded("compressed packet")).or_else_desc(|| {
+                format!(
+                    "uncompressed size ({}) greater than message size limit ({})",
+                    uncompressed_len, limits.max_message_size
+                )
+            });
+        }
+
+        let uncompressed_body = zstd_decompress(&body[Self::UNCOMPRESSED_SIZE_LEN..], uncompressed_len)
+            .chain(ProtoErrorKind::Decoding("compressed packet"))?;
+        let body_len = u32::try_from(uncompressed_len)?;
+        let mut uncompressed = NowHeader::new(header.body_type(), body_len).encode()?;
+        uncompressed.extend_from_slice(&uncompressed_body);
+        Ok(Cow::Owned(uncompressed))
+    }
+}
+
+/// Compression algorithm of channel messages, identified by the same `id` on both peers.
+pub trait ChannelCodec: core::fmt::Debug {
+    fn id(&self) -> u8;
+
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>, ProtoError>;
+
+    /// `uncompressed_len` is checked against the decode limits beforehand.
+    fn decompress(&self, input: &[u8], uncompressed_len: usize) -> Result<Vec<u8>, ProtoError>;
+}
+
+/// Built-in codec, preferred when no other is registered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZstdCodec;
+
+impl ChannelCodec for ZstdCodec {
+    fn id(&self) -> u8 {
+        ChannelCompression::ZSTD
+    }
+
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>, ProtoError> {
+        zstd::bulk::compress(input, FrameCompression::ZSTD_LEVEL)
+            .map_err(ProtoError::from)
+            .chain(ProtoErrorKind::Encoding("zstd frame"))
+    }
+
+    fn decompress(&self, input: &[u8], uncompressed_len: usize) -> Result<Vec<u8>, ProtoError> {
+        zstd_decompress(input, uncompressed_len)
+    }
+}
+
+/// Built-in codec, used when no other is registered and the peer doesn't support zstd.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4Codec;
+
+impl ChannelCodec for Lz4Codec {
+    fn id(&self) -> u8 {
+        ChannelCompression::LZ4
+    }
+
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>, ProtoError> {
+        Ok(lz4_flex::block::compress(input))
+    }
+
+    fn decompress(&self, input: &[u8], uncompressed_len: usize) -> Result<Vec<u8>, ProtoError> {
+        lz4_flex::block::decompress(input, uncompressed_len)
+            .or_else(|err| ProtoError::new(ProtoErrorKind::Decoding("LZ4 block")).or_desc(err.to_string()))
+    }
+}
+
+/// Compression of the messages of individual virtual channels, such as clipboard and file transfer.
+///
+/// Each peer advertises the channels it compresses along with its codecs, by order of preference
+/// (see [`capset`](#method.capset)). A channel is compressed once both peers advertised it with a common
+/// codec: its messages are then prefixed with the codec id, `NONE` for messages sent as is. Compressed
+/// messages follow with their uncompressed size (`u32`) and the compressed block.
+#[derive(Debug, Clone)]
+pub struct ChannelCompression {
+    codecs: Vec<Rc<dyn ChannelCodec>>,
+    channels: Vec<ChannelName>,
+    negotiated: BTreeMap<ChannelName, u8>,
+    threshold: usize,
+}
+
+impl Default for ChannelCompression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChannelCompression {
+    pub const NONE: u8 = 0x00;
+    pub const LZ4: u8 = 0x01;
+    pub const ZSTD: u8 = 0x02;
+
+    const HEADER_LEN: usize = 1 + FrameCompression::UNCOMPRESSED_SIZE_LEN;
+
+    pub fn new() -> Self {
+        Self {
+            codecs: Vec::new(),
+            channels: Vec::new(),
+            negotiated: BTreeMap::new(),
+            threshold: FrameCompression::DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// Registers a codec, preferred over the ones registered after it.
+    /// [`ZstdCodec`](struct.ZstdCodec.html) then [`Lz4Codec`](struct.Lz4Codec.html) are used when none is registered.
+    pub fn codec(mut self, codec: Rc<dyn ChannelCodec>) -> Self {
+        self.codecs.retain(|registered| registered.id() != codec.id());
+        self.codecs.push(codec);
+        self
+    }
+
+    /// Compresses the messages of `name`.
+    pub fn channel(mut self, name: ChannelName) -> Self {
+        if !self.channels.contains(&name) {
+            self.channels.push(name);
+        }
+        self
+    }
+
+    /// Messages smaller than `threshold` bytes are sent as is.
+    pub fn threshold(self, threshold: usize) -> Self {
+        Self { threshold, ..self }
+    }
+
+    fn codec_ids(&self) -> Vec<u8> {
+        if self.codecs.is_empty() {
+            vec![Self::ZSTD, Self::LZ4]
+        } else {
+            self.codecs.iter().map(|codec| codec.id()).collect()
+        }
+    }
+
+    fn get_codec(&self, id: u8) -> Option<Rc<dyn ChannelCodec>> {
+        if self.codecs.is_empty() {
+            return match id {
+                Self::ZSTD => Some(Rc::new(ZstdCodec)),
+                Self::LZ4 => Some(Rc::new(Lz4Codec)),
+                _ => None,
+            };
+        }
+        self.codecs.iter().find(|codec| codec.id() == id).cloned()
+    }
+
+    /// Capset advertising the compressed channels, to add to the capabilities of the connection sequence.
+    pub fn capset(&self) -> NowCapset<'static> {
+        let codecs = self.codec_ids();
+        NowCapset::ChannelCompression(ChannelCompressionCapset::new(
+            self.channels
+                .iter()
+                .map(|name| ChannelCodecsDef::new(name.clone(), codecs.clone()))
+                .collect(),
+        ))
+    }
+
+    /// Selects the channels compressed with the peer, and the codec each one is sent with.
+    pub fn negotiate(&mut self, capabilities: &[NowCapset<'_>], peer_capabilities: &[NowCapset<'_>]) {
+        fn find<'a>(capabilities: &'a [NowCapset<'_>]) -> Option<&'a ChannelCompressionCapset> {
+            capabilities.iter().find_map(|capset| match capset {
+                NowCapset::ChannelCompression(capset) => Some(capset),
+                _ => None,
+            })
+        }
+
+        self.negotiated.clear();
+        let (own, peer) = match (find(capabilities), find(peer_capabilities)) {
+            (Some(own), Some(peer)) => (own, peer),
+            _ => return,
+        };
+
+        for def in own.channels.0.iter() {
+            let peer_codecs = match peer.codecs(&def.name) {
+                Some(peer_codecs) => peer_codecs,
+                None => continue,
+            };
+            let common = def
+                .codecs
+                .0
+                .iter()
+                .find(|id| peer_codecs.contains(id) && self.get_codec(**id).is_some());
+            if let Some(id) = common {
+                log::debug!("channel {:?} compressed with codec {:#04x}", def.name, id);
+                self.negotiated.insert(def.name.clone(), *id);
+            }
+        }
+    }
+
+    pub fn is_negotiated(&self, name: &ChannelName) -> bool {
+        self.negotiated.contains_key(name)
+    }
+
+    /// Channel of a packet not compressed as a whole, if any.
+    fn frame_channel<'a>(header: &NowHeader, channels_ctx: &'a VirtChannelsCtx) -> Option<&'a ChannelName> {
+        if header.is_compressed() {
+            return None;
+        }
+        match header.body_type() {
+            BodyType::VirtualChannel(id) => channels_ctx.get_channel_by_id(id),
+            BodyType::Message(_) => None,
+        }
+    }
+
+    /// Prefixes the message of a compressed channel, compressing it if it is above the threshold
+    /// and compression pays off. Other packets are returned as is.
+    pub fn compress_frame<'a>(
+        &self,
+        frame: &'a [u8],
+        channels_ctx: &VirtChannelsCtx,
+    ) -> Result<Cow<'a, [u8]>, ProtoError> {
+        let header = NowHeader::decode(frame)?;
+        let codec_id = match Self::frame_channel(&header, channels_ctx).and_then(|name| self.negotiated.get(name)) {
+            Some(codec_id) => *codec_id,
+            None => return Ok(Cow::Borrowed(frame)),
+        };
+
+        let body = frame
+            .get(header.len()..header.packet_len())
+            .chain(ProtoErrorKind::Encoding("compressed channel message"))
+            .or_else_desc(|| format!("frame shorter ({}) than its packet", frame.len()))?;
+
+        let mut block = None;
+        if body.len() >= self.threshold {
+            let codec = self
+                .get_codec(codec_id)
+                .chain(ProtoErrorKind::Encoding("compressed channel message"))
+                .or_else_desc(|| format!("codec {:#04x} not registered", codec_id))?;
+            block = Some(codec.compress(body)?).filter(|block| Self::HEADER_LEN + block.len() < 1 + body.len());
+        }
+
+        let body_len = match &block {
+            Some(block) => Self::HEADER_LEN + block.len(),
+            None => 1 + body.len(),
+        };
+        let mut prefixed = NowHeader::new(header.body_type(), u32::try_from(body_len)?).encode()?;
+        prefixed.reserve(body_len);
+        match block {
+            Some(block) => {
+                prefixed.push(codec_id);
+                prefixed.extend_from_slice(&(body.len() as u32).to_le_bytes());
+                prefixed.extend_from_slice(&block);
+            }
+            None => {
+                prefixed.push(Self::NONE);
+                prefixed.extend_from_slice(body);
+            }
+        }
+        Ok(Cow::Owned(prefixed))
+    }
+
+    /// Strips the prefix of a message received over a compressed channel, decompressing it if needed.
+    ///
+    /// The uncompressed size is checked against `limits` before anything is allocated.
+    pub fn decompress_frame<'a>(
+        &self,
+        frame: &'a [u8],
+        channels_ctx: &VirtChannelsCtx,
+        limits: DecodeLimits,
+    ) -> Result<Cow<'a, [u8]>, ProtoError> {
+        let header = NowHeader::decode(frame)?;
+        let name = match Self::frame_channel(&header, channels_ctx).filter(|name| self.is_negotiated(name)) {
+            Some(name) => name,
+            None => return Ok(Cow::Borrowed(frame)),
+        };
+
+        let (codec_id, body) = frame
+            .get(header.len()..header.packet_len())
+            .and_then(|body| body.split_first())
+            .chain(ProtoErrorKind::Decoding("compressed channel message"))
+            .or_else_desc(|| format!("message of compressed channel {:?} too short", name))?;
+
+        let uncompressed_body = if *codec_id == Self::NONE {
+            Cow::Borrowed(body)
+        } else {
+            let codec = self
+                .get_codec(*codec_id)
+                .chain(ProtoErrorKind::Decoding("compressed channel message"))
+                .or_else_desc(|| format!("unknown codec {:#04x} for channel {:?}", codec_id, name))?;
+            if body.len() < FrameCompression::UNCOMPRESSED_SIZE_LEN {
+                return ProtoError::new(ProtoErrorKind::Decoding("compressed channel message"))
+                    .or_else_desc(|| format!("compressed message of channel {:?} too short", name));
+            }
+
+            let uncompressed_len = LittleEndian::read_u32(body) as usize;
+            if uncompressed_len > limits.max_message_size {
+                return ProtoError::new(ProtoErrorKind::LimitExceeded("compressed channel message")).or_else_desc(
+                    || {
+                        format!(
+                            "uncompressed size ({}) greater than message size limit ({})",
+                            uncompressed_len, limits.max_message_size
+                        )
+                    },
+                );
+            }
+            let uncompressed = codec.decompress(&body[FrameCompression::UNCOMPRESSED_SIZE_LEN..], uncompressed_len)?;
+            if uncompressed.len() != uncompressed_len {
+                return ProtoError::new(ProtoErrorKind::Decoding("compressed channel message"))
+                    .or_else_desc(|| format!("codec {:#04x} output doesn't match the uncompressed size", codec_id));
+            }
+            Cow::Owned(uncompressed)
+        };
+
+        let body_len = u32::try_from(uncompressed_body.len())?;
+        let mut uncompressed = NowHeader::new(header.body_type(), body_len).encode()?;
+        uncompressed.extend_from_slice(&uncompressed_body);
+        Ok(Cow::Owned(uncompressed))
+    }
+}
+
+/// Decompresses a zstd frame into at most `uncompressed_len` bytes, the exact size being checked by the caller.
+fn zstd_decompress(input: &[u8], uncompressed_len: usize) -> Result<Vec<u8>, ProtoError> {
+    let output = zstd::bulk::decompress(input, uncompressed_len).map_err(ProtoError::from)?;
+    if output.len() != uncompressed_len {
+        return ProtoError::new(ProtoErrorKind::Decoding("zstd frame")).or_else_desc(|| {
+            format!(
+                "uncompressed size ({}) differs from the announced one ({})",
+                output.len(),
+                uncompressed_len
+            )
+        });
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        container::Bytes32,
+        message::{
+            EdgeRect, NowClipboardFormatDataRspMsg, NowSurfaceDef, NowSurfaceListReqMsg, NowSurfaceMsg,
+            TransportCapset, TransportCapsetFlags,
+        },
+        packet::NowPacket,
+    };
+
+    #[test]
+    fn lz4_codec_round_trip() {
+        let inputs: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"short".to_vec(),
+            vec![0; 70_000],
+            (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect(),
+        ];
+
+        for input in inputs {
+            let block = Lz4Codec.compress(&input).unwrap();
+            assert_eq!(Lz4Codec.decompress(&block, input.len()).unwrap(), input);
+        }
+
+        let block = Lz4Codec.compress(&[0x2a; 1000]).unwrap();
+        assert!(Lz4Codec.decompress(&block, 999).is_err());
+        assert!(Lz4Codec.decompress(&block[..block.len() - 1], 1000).is_err());
+    }
+
+    #[test]
+    fn frame_compressed_above_threshold() {
+        let surfaces = (1..=64).map(|id| NowSurfaceDef::new(id, EdgeRect::default())).collect();
+        let msg = NowSurfaceMsg::ListReq(NowSurfaceListReqMsg::new_with_surfaces(0, 1920, 1080, surfaces));
+        let frame = NowPacket::from_message(msg).encode().unwrap();
+
+        let compression = FrameCompression::new();
+        let compressed = compression.compress_frame(&frame).unwrap();
+        assert!(compressed.len() < frame.len());
+        assert!(NowHeader::decode(&compressed).unwrap().is_compressed());
+
+        let decompressed = FrameCompression::decompress_frame(&compressed, DecodeLimits::default()).unwrap();
+        assert_eq!(decompressed[..], frame[..]);
+        NowPacket::decode_from(
+            NowHeader::decode(&decompressed).unwrap(),
+            &decompressed[NowHeader::decode(&decompressed).unwrap().len()..],
+            &VirtChannelsCtx::new(),
+        )
+        .unwrap();
+
+        // small bodies are sent as is
+        let small = compression.threshold(frame.len()).compress_frame(&frame).unwrap();
+        assert!(matches!(small, Cow::Borrowed(_)));
+
+        let limits = DecodeLimits {
+            max_message_size: 64,
+            ..DecodeLimits::default()
+        };
+        assert!(FrameCompression::decompress_frame(&compressed, limits).is_err());
+
+        // announced size not matching the zstd frame
+        let mut corrupted = compressed.into_owned();
+        let header_len = NowHeader::decode(&corrupted).unwrap().len();
+        corrupted[header_len] ^= 0x01;
+        assert!(FrameCompression::decompress_frame(&corrupted, DecodeLimits::default()).is_err());
+    }
+
+    #[test]
+    fn negotiation() {
+        let plain = [NowCapset::Transport(TransportCapset::default())];
+        let compressing = [NowCapset::Transport(TransportCapset::new(
+            TransportCapsetFlags::new_empty().set_compression(),
+        ))];
+
+        assert!(is_negotiated(&compressing, &compressing));
+        assert!(!is_negotiated(&compressing, &plain));
+        assert!(!is_negotiated(&plain, &compressing));
+    }
+
+    #[derive(Debug)]
+    struct StoreCodec;
+
+    impl ChannelCodec for StoreCodec {
+        fn id(&self) -> u8 {
+            0x80
+        }
+
+        fn compress(&self, input: &[u8]) -> Result<Vec<u8>, ProtoError> {
+            Ok(input.to_vec())
+        }
+
+        fn decompress(&self, input: &[u8], _: usize) -> Result<Vec<u8>, ProtoError> {
+            Ok(input.to_vec())
+        }
+    }
+
+    #[test]
+    fn channel_compression_negotiation() {
+        let default = ChannelCompression::new().channel(ChannelName::Clipboard);
+        let lz4 = ChannelCompression::new()
+            .codec(Rc::new(Lz4Codec))
+            .channel(ChannelName::Clipboard);
+        let custom = ChannelCompression::new()
+            .codec(Rc::new(StoreCodec))
+            .codec(Rc::new(Lz4Codec))
+            .channel(ChannelName::Clipboard)
+            .channel(ChannelName::FileTransfer);
+        let store_only = ChannelCompression::new()
+            .codec(Rc::new(StoreCodec))
+            .channel(ChannelName::Clipboard);
+
+        let mut negotiated = default.clone();
+        negotiated.negotiate(&[default.capset()], &[default.capset()]);
+        assert_eq!(negotiated.negotiated[&ChannelName::Clipboard], ChannelCompression::ZSTD);
+        negotiated.negotiate(&[default.capset()], &[lz4.capset()]);
+        assert_eq!(negotiated.negotiated[&ChannelName::Clipboard], ChannelCompression::LZ4);
+
+        let mut negotiated = custom.clone();
+        negotiated.negotiate(&[custom.capset()], &[default.capset()]);
+        assert!(negotiated.is_negotiated(&ChannelName::Clipboard));
+        assert!(!negotiated.is_negotiated(&ChannelName::FileTransfer));
+        assert_eq!(negotiated.negotiated[&ChannelName::Clipboard], ChannelCompression::LZ4);
+
+        negotiated.negotiate(&[custom.capset()], &[store_only.capset()]);
+        assert_eq!(negotiated.negotiated[&ChannelName::Clipboard], 0x80);
+
+        let mut negotiated = lz4.clone();
+        negotiated.negotiate(&[lz4.capset()], &[store_only.capset()]);
+        assert!(!negotiated.is_negotiated(&ChannelName::Clipboard));
+        negotiated.negotiate(&[lz4.capset()], &[]);
+        assert!(!negotiated.is_negotiated(&ChannelName::Clipboard));
+    }
+
+    #[test]
+    fn channel_message_compressed_once_negotiated() {
+        let mut channels_ctx = VirtChannelsCtx::new();
+        channels_ctx.insert(1, ChannelName::Clipboard);
+        channels_ctx.insert(2, ChannelName::FileTransfer);
+
+        let data = vec![0x2a; 4096];
+        let mut msg = NowClipboardFormatDataRspMsg::new(3, 13);
+        msg.format_data = Bytes32(&data);
+        let frame = NowPacket::from_virt_channel(msg, 1).encode().unwrap();
+
+        let mut compression = ChannelCompression::new().channel(ChannelName::Clipboard);
+        let capset = compression.capset();
+        compression.negotiate(core::slice::from_ref(&capset), core::slice::from_ref(&capset));
+
+        let compressed = compression.compress_frame(&frame, &channels_ctx).unwrap();
+        assert!(compressed.len() < 100);
+        assert!(!NowHeader::decode(&compressed).unwrap().is_compressed());
+        let header = NowHeader::decode(&compressed).unwrap();
+        assert_eq!(compressed[header.len()], ChannelCompression::ZSTD);
+
+        let decompressed = compression
+            .decompress_frame(&compressed, &channels_ctx, DecodeLimits::default())
+            .unwrap();
+        assert_eq!(decompressed[..], frame[..]);
+
+        // small messages are prefixed only
+        let small = compression
+            .clone()
+            .threshold(frame.len())
+            .compress_frame(&frame, &channels_ctx)
+            .unwrap();
+        assert_eq!(small.len(), frame.len() + 1);
+        assert_eq!(small[header.len()], ChannelCompression::NONE);
+        let decompressed = compression
+            .decompress_frame(&small, &channels_ctx, DecodeLimits::default())
+            .unwrap();
+        assert_eq!(decompressed[..], frame[..]);
+
+        // other channels are left untouched
+        let other = NowPacket::from_virt_channel(NowClipboardFormatDataRspMsg::new(3, 13), 2)
+            .encode()
+            .unwrap();
+        let untouched = compression.compress_frame(&other, &channels_ctx).unwrap();
+        assert!(matches!(untouched, Cow::Borrowed(_)));
+
+        let limits = DecodeLimits {
+            max_message_size: 1024,
+            ..DecodeLimits::default()
+        };
+        assert!(compression
+            .decompress_frame(&compressed, &channels_ctx, limits)
+            .is_err());
+    }
+}
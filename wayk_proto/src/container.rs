@@ -1,8 +1,31 @@
 macro_rules! impl_container {
     ($ty:ident as Vec with $size_ty:ident) => {
         #[derive(PartialEq, Debug, Clone)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        #[cfg_attr(feature = "serde", serde(transparent))]
         pub struct $ty<Item>(pub Vec<Item>);
 
+        #[cfg(feature = "fuzzing")]
+        impl<'arb, Item: arbitrary::Arbitrary<'arb>> arbitrary::Arbitrary<'arb> for $ty<Item> {
+            fn arbitrary(u: &mut arbitrary::Unstructured<'arb>) -> arbitrary::Result<Self> {
+                let len = u.arbitrary_len::<Item>()?.min($size_ty::MAX as usize);
+                (0..len)
+                    .map(|_| Item::arbitrary(u))
+                    .collect::<arbitrary::Result<Vec<Item>>>()
+                    .map(Self)
+            }
+        }
+
+        #[cfg(feature = "schema")]
+        impl<Item: crate::schema::WireSchema> crate::schema::WireSchema for $ty<Item> {
+            fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+                crate::schema::TypeSchema::List {
+                    count: Box::new(schema.register::<$size_ty>()),
+                    item: Box::new(schema.register::<Item>()),
+                }
+            }
+        }
+
         impl<Item> core::ops::Deref for $ty<Item> {
             type Target = Vec<Item>;
 
@@ -70,14 +93,26 @@ macro_rules! impl_container {
             Item: crate::serialization::Encode + core::fmt::Debug,
         {
             fn encoded_len(&self) -> usize {
+                self.encoded_len_ctx(&Default::default())
+            }
+
+            fn encoded_len_ctx(&self, ctx: &crate::serialization::EncodeCtx) -> usize {
                 self.iter().fold(core::mem::size_of::<$size_ty>(), |acc, item| {
-                    acc + item.encoded_len()
+                    acc + item.encoded_len_ctx(ctx)
                 })
             }
 
             fn encode_into<W: std::io::Write>(
                 &self,
                 writer: &mut W,
+            ) -> core::result::Result<(), $crate::error::ProtoError> {
+                self.encode_into_ctx(writer, &Default::default())
+            }
+
+            fn encode_into_ctx<W: std::io::Write>(
+                &self,
+                writer: &mut W,
+                ctx: &crate::serialization::EncodeCtx,
             ) -> core::result::Result<(), $crate::error::ProtoError> {
                 use crate::error::*;
                 use core::convert::TryFrom;
@@ -88,7 +123,7 @@ macro_rules! impl_container {
                     .or_desc("couldn't convert losslessly vec size into u8 (count)")?;
                 count.encode_into(writer)?;
                 for item in self {
-                    item.encode_into(writer)
+                    item.encode_into_ctx(writer, ctx)
                         .chain($crate::error::ProtoErrorKind::Encoding(stringify!($ty)))
                         .or_else_desc(|| format!("couldn't encode item {:?}", item))?;
                 }
@@ -101,15 +136,57 @@ macro_rules! impl_container {
             Item: crate::serialization::Decode<'dec>,
         {
             fn decode_from(cursor: &mut std::io::Cursor<&'dec [u8]>) -> Result<Self, $crate::error::ProtoError> {
-                use crate::error::*;
+                Self::decode_with_max_len(cursor, <$size_ty>::MAX as usize)
+            }
+
+            fn decode_from_ctx(
+                cursor: &mut std::io::Cursor<&'dec [u8]>,
+                ctx: &crate::serialization::DecodeCtx,
+            ) -> Result<Self, $crate::error::ProtoError> {
+                Self::decode_with_max_len_ctx(cursor, <$size_ty>::MAX as usize, ctx)
+            }
+        }
+
+        impl<'dec, Item> $ty<Item>
+        where
+            Item: crate::serialization::Decode<'dec>,
+        {
+            /// Fails before decoding any item if the count exceeds `max_len`.
+            /// Use this to guard against hostile count fields.
+            pub fn decode_with_max_len(
+                cursor: &mut std::io::Cursor<&'dec [u8]>,
+                max_len: usize,
+            ) -> Result<Self, $crate::error::ProtoError> {
+                Self::decode_with_max_len_ctx(cursor, max_len, &Default::default())
+            }
+
+            pub fn decode_with_max_len_ctx(
+                cursor: &mut std::io::Cursor<&'dec [u8]>,
+                max_len: usize,
+                ctx: &crate::serialization::DecodeCtx,
+            ) -> Result<Self, $crate::error::ProtoError> {
+                use crate::{error::*, serialization::Decode};
 
                 let count = <$size_ty>::decode_from(cursor)
                     .chain($crate::error::ProtoErrorKind::Decoding(stringify!($ty)))
                     .or_desc("couldn't decode list count")?;
+                if count as u128 > max_len as u128 {
+                    return ProtoError::new(ProtoErrorKind::Decoding(stringify!($ty)))
+                        .or_else_desc(|| format!("list count ({}) greater than maximum ({})", count, max_len));
+                }
+
+                ctx.limits.check_collection_len(count as usize, stringify!($ty))?;
+
+                // items take at least a byte each, except for degenerate ones
+                let remaining = cursor.get_ref().len().saturating_sub(cursor.position() as usize);
                 let mut vec = Vec::new();
+                if let Err(e) = vec.try_reserve((count as usize).min(remaining)) {
+                    return ProtoError::new(ProtoErrorKind::LimitExceeded(stringify!($ty)))
+                        .or_else_desc(|| format!("couldn't allocate list: {}", e));
+                }
                 for i in 0..count {
                     vec.push(
-                        Item::decode_from(cursor)
+                        Item::decode_from_ctx(cursor, ctx)
                             .chain($crate::error::ProtoErrorKind::Decoding(stringify!($ty)))
                             .or_else_desc(|| format!("couldn't decode item n°{}", i))?,
                     );
@@ -120,7 +197,27 @@ macro_rules! impl_container {
     };
     ($ty:ident as &[u8] with $size_ty:ident) => {
         #[derive(PartialEq, Debug, Clone)]
-        pub struct $ty<'a>(pub &'a [u8]);
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        #[cfg_attr(feature = "serde", serde(transparent))]
+        pub struct $ty<'a>(#[cfg_attr(feature = "serde", serde(borrow))] pub &'a [u8]);
+
+        #[cfg(feature = "fuzzing")]
+        impl<'a> arbitrary::Arbitrary<'a> for $ty<'a> {
+            fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+                let len = u.arbitrary_len::<u8>()?.min($size_ty::MAX as usize);
+                u.bytes(len).map(Self)
+            }
+        }
+
+        #[cfg(feature = "schema")]
+        impl crate::schema::WireSchema for $ty<'_> {
+            fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+                crate::schema::TypeSchema::List {
+                    count: Box::new(schema.register::<$size_ty>()),
+                    item: Box::new(schema.register::<u8>()),
+                }
+            }
+        }
 
         impl<'a> core::ops::Deref for $ty<'a> {
             type Target = &'a [u8];
@@ -200,6 +297,7 @@ macro_rules! impl_container {
                     });
                 }
                 let bytes = &slices_to_end[..count as usize];
+                cursor.set_position((start_inclusive + count as usize) as u64);
                 Ok($ty(bytes))
             }
         }
@@ -237,6 +335,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn decode_vec8_max_len() {
+        let mut cursor = std::io::Cursor::new(&U16_VEC8[..]);
+        assert!(Vec8::<u16>::decode_with_max_len(&mut cursor, 2).is_err());
+        let mut cursor = std::io::Cursor::new(&U16_VEC8[..]);
+        assert_eq!(Vec8::<u16>::decode_with_max_len(&mut cursor, 3).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn decode_vec32_hostile_count() {
+        // claims 2^32 - 1 items but provides only one
+        let bytes = [0xff, 0xff, 0xff, 0xff, 0x01, 0x00];
+        let mut cursor = std::io::Cursor::new(&bytes[..]);
+        let err = Vec32::<u16>::decode_with_max_len(&mut cursor, 1024).err().unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "couldn't decode Vec32 [description: list count (4294967295) greater than maximum (1024)]"
+        );
+    }
+
+    #[test]
+    fn decode_vec32_collection_limit() {
+        let mut ctx = crate::serialization::DecodeCtx::default();
+        ctx.limits.max_collection_len = 2;
+
+        let mut cursor = std::io::Cursor::new(&U16_VEC32[..]);
+        let err = Vec32::<u16>::decode_from_ctx(&mut cursor, &ctx).err().unwrap();
+        assert!(matches!(err.kind, crate::error::ProtoErrorKind::LimitExceeded("Vec32")));
+
+        ctx.limits.max_collection_len = 3;
+        let mut cursor = std::io::Cursor::new(&U16_VEC32[..]);
+        assert_eq!(Vec32::<u16>::decode_from_ctx(&mut cursor, &ctx).unwrap().len(), 3);
+    }
+
     const U16_VEC32: [u8; 10] = [0x03, 0x00, 0x00, 0x00, 0x50, 0x10, 0x0a, 0x09, 0x57, 0x0b];
 
     #[test]
@@ -274,6 +406,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn decode_bytes8_advances_cursor() {
+        let mut cursor = std::io::Cursor::new(&ENCODED_MSG_WITH_BYTES8[3..]);
+        let bytes = Bytes8::decode_from(&mut cursor).unwrap();
+        assert_eq!(bytes, &ENCODED_MSG_WITH_BYTES8[4..=9]);
+        assert_eq!(u8::decode_from(&mut cursor).unwrap(), 0xc3);
+    }
+
     const ENCODED_MSG_WITH_BYTES32: [u8; 16] = [
         0x38, 0xae, 0xf3, // things
         0x06, 0x00, 0x00, 0x00, // count
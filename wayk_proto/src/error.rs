@@ -1,7 +1,7 @@
 use crate::{
-    message::{ChannelName, MessageType},
+    message::{AccessControlCode, ChannelName, MessageType, NowStatusCode},
     sharee::ShareeState,
-    sm::ConnectionState,
+    sm::{ConnectionState, SurfaceRequestKind},
 };
 use core::{fmt, num::TryFromIntError};
 
@@ -11,6 +11,7 @@ macro_rules! error_chain {
         pub struct $error_ty {
             pub kind: $error_kind_ty,
             pub description: Option<std::borrow::Cow<'static, str>>,
+            pub decode_context: Option<Box<DecodeContext>>,
             pub source: Option<Box<$error_ty>>,
         }
 
@@ -36,6 +37,10 @@ macro_rules! error_chain {
                     print!(" [description: {}]", desc);
                 }
 
+                if let Some(context) = &self.decode_context {
+                    print!(" [{}]\n{}", context, context.hexdump());
+                }
+
                 if let Some(source) = &self.source {
                     print!("\n\t↳ source: ");
                     source.__print_trace();
@@ -51,6 +56,10 @@ macro_rules! error_chain {
                     write!(f, " [description: {}]", desc)?;
                 }
 
+                if let Some(context) = &self.decode_context {
+                    write!(f, " [{}]", context)?;
+                }
+
                 if let Some(source) = &self.source {
                     write!(f, " [source: {}]", source)?;
                 }
@@ -127,9 +136,11 @@ macro_rules! error_chain {
                 }
 
                 fn chain(self, kind: $error_kind_ty) -> core::result::Result<T, $error_ty> {
-                    self.map_err(|err| $error_ty {
+                    self.map_err(|mut err| $error_ty {
                         kind,
                         description: None,
+                        // context is kept on the outermost error
+                        decode_context: err.decode_context.take(),
                         source: Some(Box::new(err)),
                     })
                 }
@@ -147,6 +158,7 @@ macro_rules! error_chain {
                 Self {
                     kind,
                     description: None,
+                    decode_context: None,
                     source: None,
                 }
             }
@@ -160,6 +172,111 @@ error_chain! { ProtoError, ProtoErrorKind }
 
 sa::assert_impl_all!(ProtoError: Sync, Send);
 
+impl ProtoError {
+    /// Records that the error happened while decoding `field`, starting at `offset` in `buffer`.
+    ///
+    /// Innermost field is recorded first: its offset and the bytes around are kept.
+    pub fn in_field(mut self, field: &'static str, buffer: &[u8], offset: u64) -> Self {
+        let context = self
+            .decode_context
+            .get_or_insert_with(|| Box::new(DecodeContext::new(buffer, offset)));
+        context.path.insert(0, field);
+        self
+    }
+}
+
+impl ProtoError {
+    /// Class of the failure, deciding how a session recovers from it.
+    ///
+    /// Kinds only locating the failure (connection sequence step, channel, sharee) are classified
+    /// after their source when they have one.
+    pub fn class(&self) -> ErrorClass {
+        match (self.kind.class(), &self.source) {
+            (Some(class), _) => class,
+            (None, Some(source)) => source.class(),
+            (None, None) => match self.kind {
+                ProtoErrorKind::ConnectionSequence(ConnectionState::Authenticate)
+                | ProtoErrorKind::ConnectionSequence(ConnectionState::Associate) => ErrorClass::AuthRejected,
+                ProtoErrorKind::Sharee(_) => ErrorClass::Other,
+                _ => ErrorClass::ProtocolViolation,
+            },
+        }
+    }
+
+    /// `true` if the session may be resumed over a new link, see `ErrorClass::Transport`.
+    pub fn is_recoverable(&self) -> bool {
+        self.class().is_recoverable()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The link failed or timed out. The session may be re-attached over a new link.
+    Transport,
+    /// The peer sent a malformed or unexpected message.
+    ProtocolViolation,
+    /// Authentication, association or access control was refused.
+    AuthRejected,
+    /// The peer terminated the session.
+    PeerTerminated,
+    /// Failure leaving the session as is: cancelled operation, failed request or local error.
+    Other,
+}
+
+impl ErrorClass {
+    pub fn is_recoverable(self) -> bool {
+        self == ErrorClass::Transport
+    }
+}
+
+/// Location of a decoding failure.
+#[derive(Debug, Clone)]
+pub struct DecodeContext {
+    /// Offset, in the decoded buffer, of the innermost field that couldn't be decoded.
+    pub offset: u64,
+    /// Fields being decoded, outermost first.
+    pub path: Vec<&'static str>,
+    window_start: u64,
+    window: Vec<u8>,
+}
+
+impl DecodeContext {
+    const WINDOW_RADIUS: u64 = 16;
+
+    fn new(buffer: &[u8], offset: u64) -> Self {
+        let window_start = offset.saturating_sub(Self::WINDOW_RADIUS).min(buffer.len() as u64);
+        let window_end = (offset + Self::WINDOW_RADIUS).min(buffer.len() as u64);
+
+        Self {
+            offset,
+            path: Vec::new(),
+            window_start,
+            window: buffer[window_start as usize..window_end as usize].to_vec(),
+        }
+    }
+
+    /// Bytes around the failure, 16 per line. The byte at the failure offset is marked with `>`.
+    pub fn hexdump(&self) -> String {
+        let mut dump = String::new();
+        for (line_idx, line) in self.window.chunks(16).enumerate() {
+            let line_start = self.window_start + line_idx as u64 * 16;
+            dump.push_str(&format!("{:08x}:", line_start));
+            for (i, byte) in line.iter().enumerate() {
+                let marker = if line_start + i as u64 == self.offset { '>' } else { ' ' };
+                dump.push_str(&format!("{}{:02x}", marker, byte));
+            }
+            dump.push('\n');
+        }
+        dump
+    }
+}
+
+impl fmt::Display for DecodeContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at offset {} in {}", self.offset, self.path.join(" > "))
+    }
+}
+
 impl From<std::io::Error> for ProtoError {
     fn from(e: std::io::Error) -> Self {
         Self::from(ProtoErrorKind::Io(e))
@@ -182,29 +299,119 @@ impl From<std::num::TryFromIntError> for ProtoError {
 pub enum ProtoErrorKind {
     Decoding(&'static str),
     Encoding(&'static str),
+    LimitExceeded(&'static str),
+    OutOfRange(&'static str),
     ConnectionSequence(ConnectionState),
     VirtualChannel(ChannelName),
     ChannelsManager,
     UnexpectedMessage(MessageType),
     Sharee(ShareeState),
+    CapabilityNotNegotiated(&'static str),
+    AccessDenied(AccessControlCode),
+    Server,
+    Wake,
+    Proxy,
+    Gateway,
+    LocalConnection,
+    PortForwarding,
+    Cancelled,
+    SurfaceLayout,
+    SurfaceRequestFailed(SurfaceRequestKind, NowStatusCode),
+    SurfaceRequestTimedOut(SurfaceRequestKind),
     Io(std::io::Error),
     FromUtf8(std::string::FromUtf8Error),
     IntConversion(TryFromIntError),
 }
 
+impl ProtoErrorKind {
+    /// `None` for the kinds locating a failure rather than describing it.
+    fn class(&self) -> Option<ErrorClass> {
+        match self {
+            ProtoErrorKind::ConnectionSequence(_)
+            | ProtoErrorKind::VirtualChannel(_)
+            | ProtoErrorKind::ChannelsManager
+            | ProtoErrorKind::Sharee(_) => None,
+            ProtoErrorKind::UnexpectedMessage(MessageType::Terminate) => Some(ErrorClass::PeerTerminated),
+            ProtoErrorKind::Decoding(_)
+            | ProtoErrorKind::LimitExceeded(_)
+            | ProtoErrorKind::OutOfRange(_)
+            | ProtoErrorKind::UnexpectedMessage(_)
+            | ProtoErrorKind::CapabilityNotNegotiated(_)
+            | ProtoErrorKind::SurfaceLayout
+            | ProtoErrorKind::FromUtf8(_)
+            | ProtoErrorKind::IntConversion(_) => Some(ErrorClass::ProtocolViolation),
+            ProtoErrorKind::AccessDenied(_) => Some(ErrorClass::AuthRejected),
+            ProtoErrorKind::Io(e) if e.kind() == std::io::ErrorKind::InvalidData => Some(ErrorClass::ProtocolViolation),
+            ProtoErrorKind::Io(_)
+            | ProtoErrorKind::Server
+            | ProtoErrorKind::Wake
+            | ProtoErrorKind::Proxy
+            | ProtoErrorKind::Gateway
+            | ProtoErrorKind::LocalConnection => Some(ErrorClass::Transport),
+            ProtoErrorKind::Encoding(_)
+            | ProtoErrorKind::PortForwarding
+            | ProtoErrorKind::Cancelled
+            | ProtoErrorKind::SurfaceRequestFailed(..)
+            | ProtoErrorKind::SurfaceRequestTimedOut(_) => Some(ErrorClass::Other),
+        }
+    }
+}
+
 impl fmt::Display for ProtoErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ProtoErrorKind::Decoding(desc) => write!(f, "couldn't decode {}", desc),
             ProtoErrorKind::Encoding(desc) => write!(f, "couldn't encode {}", desc),
+            ProtoErrorKind::LimitExceeded(desc) => write!(f, "decode limit exceeded for {}", desc),
+            ProtoErrorKind::OutOfRange(desc) => write!(f, "{} is out of range", desc),
             ProtoErrorKind::ConnectionSequence(state) => write!(f, "connection sequence failed at state {:?}", state),
             ProtoErrorKind::VirtualChannel(name) => write!(f, "virtual channel {:?} failed", name),
             ProtoErrorKind::ChannelsManager => write!(f, "virtual channels manager failed"),
             ProtoErrorKind::UnexpectedMessage(packet) => write!(f, "unexpected {:?} message", packet),
             ProtoErrorKind::Sharee(state) => write!(f, "sharee error in state {:?}", state),
+            ProtoErrorKind::CapabilityNotNegotiated(name) => write!(f, "capability {} not negotiated", name),
+            ProtoErrorKind::AccessDenied(code) => write!(f, "access denied for {:?}", code),
+            ProtoErrorKind::Server => write!(f, "server failed"),
+            ProtoErrorKind::Wake => write!(f, "remote host wake failed"),
+            ProtoErrorKind::Proxy => write!(f, "proxy connection failed"),
+            ProtoErrorKind::Gateway => write!(f, "gateway relay failed"),
+            ProtoErrorKind::LocalConnection => write!(f, "local agent connection failed"),
+            ProtoErrorKind::PortForwarding => write!(f, "port forwarding failed"),
+            ProtoErrorKind::Cancelled => write!(f, "operation cancelled"),
+            ProtoErrorKind::SurfaceLayout => write!(f, "invalid surface layout"),
+            ProtoErrorKind::SurfaceRequestFailed(kind, status) => {
+                write!(f, "surface {:?} request failed: {}", kind, status)
+            }
+            ProtoErrorKind::SurfaceRequestTimedOut(kind) => write!(f, "surface {:?} request timed out", kind),
             ProtoErrorKind::Io(e) => write!(f, "io error: {}", e),
             ProtoErrorKind::FromUtf8(e) => write!(f, "couldn't parse utf8 string: {}", e),
             ProtoErrorKind::IntConversion(e) => write!(f, "integer conversion failed: {}", e),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classified_after_source() {
+        let err = ProtoError::new::<()>(ProtoErrorKind::UnexpectedMessage(MessageType::Terminate))
+            .chain(ProtoErrorKind::ConnectionSequence(ConnectionState::Capabilities))
+            .unwrap_err();
+        assert_eq!(err.class(), ErrorClass::PeerTerminated);
+
+        let err = ProtoError::new::<()>(ProtoErrorKind::ConnectionSequence(ConnectionState::Authenticate))
+            .or_desc("invalid credentials")
+            .unwrap_err();
+        assert_eq!(err.class(), ErrorClass::AuthRejected);
+
+        let err = ProtoError::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+        assert!(err.is_recoverable());
+        let err = ProtoError::from(std::io::Error::from(std::io::ErrorKind::InvalidData));
+        assert_eq!(err.class(), ErrorClass::ProtocolViolation);
+
+        let err = ProtoError::new::<()>(ProtoErrorKind::ConnectionSequence(ConnectionState::Channels)).unwrap_err();
+        assert_eq!(err.class(), ErrorClass::ProtocolViolation);
+    }
+}
@@ -0,0 +1,356 @@
+//! Relayed connections through a Devolutions Gateway.
+//!
+//! Both peers connect to the gateway with the same association: the gateway then relays the
+//! stream between them, so hosts behind NAT can be reached. Once the JET preamble is exchanged,
+//! the stream carries the NOW protocol as a direct connection would.
+
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    proxy::{drive_handshake, ProxyConfig, ProxyStep, TargetAddr},
+    tcp::TcpConfig,
+};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use core::convert::TryFrom;
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+#[derive(PartialEq, Eq, Clone)]
+pub struct GatewayConfig {
+    /// `<host>:<port>` of the gateway.
+    pub addr: TargetAddr,
+    /// Association (session) identifier shared by both peers.
+    pub association: String,
+    /// Association token (JWT) authorizing the connection, if required by the gateway.
+    pub token: Option<String>,
+    /// Proxy used to reach the gateway.
+    pub proxy: Option<ProxyConfig>,
+    /// Options of the direct connection to the gateway. Connections through a proxy use those of the proxy.
+    pub tcp: TcpConfig,
+}
+
+impl core::fmt::Debug for GatewayConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("GatewayConfig")
+            .field("addr", &self.addr)
+            .field("association", &self.association)
+            .field("token", &self.token.as_ref().map(|_| "<hidden>"))
+            .field("proxy", &self.proxy)
+            .field("tcp", &self.tcp)
+            .finish()
+    }
+}
+
+impl GatewayConfig {
+    pub fn new(addr: TargetAddr, association: impl Into<String>) -> Self {
+        Self {
+            addr,
+            association: association.into(),
+            token: None,
+            proxy: None,
+            tcp: TcpConfig::default(),
+        }
+    }
+
+    pub fn token(self, token: impl Into<String>) -> Self {
+        Self {
+            token: Some(token.into()),
+            ..self
+        }
+    }
+
+    pub fn proxy(self, proxy: ProxyConfig) -> Self {
+        Self {
+            proxy: Some(proxy),
+            ..self
+        }
+    }
+
+    pub fn tcp(self, tcp: TcpConfig) -> Self {
+        Self { tcp, ..self }
+    }
+
+    /// Connects to the gateway (through the proxy if any) and joins the association.
+    ///
+    /// The returned stream is relayed to the peer.
+    pub fn connect(&self) -> Result<TcpStream, ProtoError> {
+        let mut stream = match &self.proxy {
+            Some(proxy) => proxy.connect(&self.addr)?,
+            None => self
+                .tcp
+                .connect(&self.addr)
+                .or_else_desc(|| format!("couldn't connect to gateway {}", self.addr))?,
+        };
+        self.handshake(&mut stream)?;
+        Ok(stream)
+    }
+
+    /// Sends the JET preamble over `stream`, connected to the gateway, and waits for the gateway to accept it.
+    pub fn handshake<S: Read + Write>(&self, stream: &mut S) -> Result<(), ProtoError> {
+        let mut handshake = JetHandshake::new(self);
+        drive_handshake(stream, |received| handshake.step(received))
+    }
+
+    #[cfg(feature = "async")]
+    /// Same as [`handshake`](#method.handshake) over an async stream.
+    pub async fn handshake_async<S>(&self, stream: &mut S) -> Result<(), ProtoError>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let mut handshake = JetHandshake::new(self);
+        crate::proxy::drive_handshake_async(stream, |received| handshake.step(received)).await
+    }
+}
+
+/// Fields of the preamble are CRLF-delimited: controls or whitespace would forge headers.
+fn check_field(name: &str, value: &str) -> Result<(), ProtoError> {
+    match value.chars().find(|c| c.is_control() || c.is_whitespace()) {
+        Some(c) => ProtoError::new(ProtoErrorKind::Gateway)
+            .or_else_desc(|| format!("invalid character {:?} in JET {} {:?}", c, name, value)),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum JetState {
+    Request,
+    ResponseHeader,
+    ResponseBody,
+    Done,
+}
+
+/// JET connect preamble, independent of I/O.
+///
+/// Each JET message is an 8 bytes header (signature, size, flags and mask)
+/// followed by an HTTP-like request or response.
+pub struct JetHandshake {
+    host: String,
+    association: String,
+    token: Option<String>,
+    state: JetState,
+}
+
+impl JetHandshake {
+    pub const SIGNATURE: u32 = 0x0054_454A; // "JET\0"
+    pub const HEADER_SIZE: usize = 8;
+    pub const VERSION: u32 = 2;
+
+    pub fn new(config: &GatewayConfig) -> Self {
+        let host = match &config.addr {
+            TargetAddr::Ip(addr) => addr.ip().to_string(),
+            TargetAddr::Domain(host, _) => host.clone(),
+        };
+
+        Self {
+            host,
+            association: config.association.clone(),
+            token: config.token.clone(),
+            state: JetState::Request,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == JetState::Done
+    }
+
+    /// Next step of the preamble. `received` holds the bytes read for the previous
+    /// [`Read`](../proxy/enum.ProxyStep.html#variant.Read) step, and is ignored otherwise.
+    pub fn step(&mut self, received: &[u8]) -> Result<ProxyStep, ProtoError> {
+        match self.state {
+            JetState::Request => {
+                let request = self.request()?;
+                self.state = JetState::ResponseHeader;
+                Ok(ProxyStep::Write(request))
+            }
+            JetState::ResponseHeader if received.is_empty() => Ok(ProxyStep::Read(Self::HEADER_SIZE)),
+            JetState::ResponseHeader => {
+                if received.len() != Self::HEADER_SIZE {
+                    return ProtoError::new(ProtoErrorKind::Gateway)
+                        .or_else_desc(|| format!("truncated JET message header: {:?}", received));
+                }
+
+                let signature = LittleEndian::read_u32(&received[0..4]);
+                if signature != Self::SIGNATURE {
+                    return ProtoError::new(ProtoErrorKind::Gateway)
+                        .or_else_desc(|| format!("invalid JET message signature: {:#010x}", signature));
+                }
+
+                let size = usize::from(BigEndian::read_u16(&received[4..6]));
+                let body_size = size
+                    .checked_sub(Self::HEADER_SIZE)
+                    .filter(|body_size| *body_size > 0)
+                    .chain(ProtoErrorKind::Gateway)
+                    .or_else_desc(|| format!("invalid JET message size: {}", size))?;
+
+                self.state = JetState::ResponseBody;
+                Ok(ProxyStep::Read(body_size))
+            }
+            JetState::ResponseBody => {
+                self.check_response(received)?;
+                self.state = JetState::Done;
+                Ok(ProxyStep::Done)
+            }
+            JetState::Done => Ok(ProxyStep::Done),
+        }
+    }
+
+    fn request(&self) -> Result<Vec<u8>, ProtoError> {
+        check_field("host", &self.host)?;
+        check_field("association", &self.association)?;
+        if let Some(token) = &self.token {
+            check_field("token", token)?;
+        }
+
+        let mut body = format!(
+            "GET / HTTP/1.1\r\nHost: {}\r\nConnection: Keep-Alive\r\nJet-Method: Connect\r\nJet-Version: {}\r\nJet-Association: {}\r\n",
+            self.host,
+            Self::VERSION,
+            self.association
+        );
+        if let Some(token) = &self.token {
+            body.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        body.push_str("\r\n");
+
+        let size = u16::try_from(Self::HEADER_SIZE + body.len())
+            .map_err(ProtoError::from)
+            .chain(ProtoErrorKind::Gateway)
+            .or_desc("JET connect request is too large")?;
+
+        let mut request = vec![0; Self::HEADER_SIZE];
+        LittleEndian::write_u32(&mut request[0..4], Self::SIGNATURE);
+        BigEndian::write_u16(&mut request[4..6], size);
+        // flags and mask are unused
+        request.extend_from_slice(body.as_bytes());
+        Ok(request)
+    }
+
+    fn check_response(&self, body: &[u8]) -> Result<(), ProtoError> {
+        let response = String::from_utf8_lossy(body);
+        let status_line = response.lines().next().unwrap_or_default();
+
+        let status = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok());
+        match status {
+            Some(200) => Ok(()),
+            Some(401) | Some(403) => ProtoError::new(ProtoErrorKind::Gateway)
+                .or_else_desc(|| format!("gateway rejected association token: {}", status_line)),
+            Some(404) => ProtoError::new(ProtoErrorKind::Gateway)
+                .or_else_desc(|| format!("unknown association {}: {}", self.association, status_line)),
+            _ => ProtoError::new(ProtoErrorKind::Gateway)
+                .or_else_desc(|| format!("gateway refused connection: {}", status_line)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASSOCIATION: &str = "a9d3c5e3-7f2a-4c6b-9a1e-3b8e2f6d4c10";
+
+    fn jet_message(body: &[u8]) -> Vec<u8> {
+        let mut message = vec![0x4A, 0x45, 0x54, 0x00];
+        message.extend_from_slice(&((body.len() + 8) as u16).to_be_bytes());
+        message.extend_from_slice(&[0, 0]);
+        message.extend_from_slice(body);
+        message
+    }
+
+    #[test]
+    fn connect_request_with_token() {
+        let config = GatewayConfig::new(TargetAddr::Domain("gateway.corp".to_owned(), 8080), ASSOCIATION).token("eyJ0");
+        let mut handshake = JetHandshake::new(&config);
+
+        let body = format!(
+            "GET / HTTP/1.1\r\nHost: gateway.corp\r\nConnection: Keep-Alive\r\nJet-Method: Connect\r\n\
+             Jet-Version: 2\r\nJet-Association: {}\r\nAuthorization: Bearer eyJ0\r\n\r\n",
+            ASSOCIATION
+        );
+        assert_eq!(
+            handshake.step(&[]).unwrap(),
+            ProxyStep::Write(jet_message(body.as_bytes()))
+        );
+        assert_eq!(handshake.step(&[]).unwrap(), ProxyStep::Read(8));
+
+        let response = jet_message(b"HTTP/1.1 200 OK\r\nJet-Version: 2\r\n\r\n");
+        assert_eq!(
+            handshake.step(&response[..8]).unwrap(),
+            ProxyStep::Read(response.len() - 8)
+        );
+        assert_eq!(handshake.step(&response[8..]).unwrap(), ProxyStep::Done);
+        assert!(handshake.is_done());
+    }
+
+    #[test]
+    fn forged_fields_rejected() {
+        let forged = "x\r\nX-Forged: 1";
+        let configs = vec![
+            GatewayConfig::new(TargetAddr::Domain(format!("gateway.corp{}", forged), 8080), ASSOCIATION),
+            GatewayConfig::new(TargetAddr::Domain("gateway.corp".to_owned(), 8080), forged),
+            GatewayConfig::new(TargetAddr::Domain("gateway.corp".to_owned(), 8080), ASSOCIATION).token(forged),
+            GatewayConfig::new(TargetAddr::Domain("gateway.corp".to_owned(), 8080), ASSOCIATION).token("eyJ0 eyJ1"),
+        ];
+
+        for config in &configs {
+            let mut handshake = JetHandshake::new(config);
+            assert!(matches!(
+                handshake.step(&[]).err().unwrap().kind,
+                ProtoErrorKind::Gateway
+            ));
+            assert!(!handshake.is_done());
+        }
+    }
+
+    #[test]
+    fn relays_stream_once_accepted() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gateway = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0; 8];
+            stream.read_exact(&mut header).unwrap();
+            let mut body = vec![0; usize::from(u16::from_be_bytes([header[4], header[5]])) - 8];
+            stream.read_exact(&mut body).unwrap();
+            assert!(String::from_utf8(body).unwrap().contains(ASSOCIATION));
+
+            // response and first relayed bytes are received together
+            let mut answer = jet_message(b"HTTP/1.1 200 OK\r\n\r\n");
+            answer.extend_from_slice(b"NOW");
+            stream.write_all(&answer).unwrap();
+        });
+
+        let mut stream = GatewayConfig::new(addr.into(), ASSOCIATION).connect().unwrap();
+        let mut relayed = [0; 3];
+        stream.read_exact(&mut relayed).unwrap();
+        assert_eq!(&relayed, b"NOW");
+        gateway.join().unwrap();
+    }
+
+    #[test]
+    fn unknown_association() {
+        let mut handshake = JetHandshake::new(&GatewayConfig::new("127.0.0.1:8080".parse().unwrap(), ASSOCIATION));
+        handshake.step(&[]).unwrap();
+        handshake.step(&[]).unwrap();
+
+        let response = jet_message(b"HTTP/1.1 404 Not Found\r\n\r\n");
+        handshake.step(&response[..8]).unwrap();
+        let err = handshake.step(&response[8..]).err().unwrap();
+        assert!(matches!(err.kind, ProtoErrorKind::Gateway));
+    }
+
+    #[test]
+    fn truncated_response_header() {
+        let mut handshake = JetHandshake::new(&GatewayConfig::new("127.0.0.1:8080".parse().unwrap(), ASSOCIATION));
+        handshake.step(&[]).unwrap();
+        handshake.step(&[]).unwrap();
+
+        let response = jet_message(b"HTTP/1.1 200 OK\r\n\r\n");
+        let err = handshake.step(&response[..5]).err().unwrap();
+        assert!(matches!(err.kind, ProtoErrorKind::Gateway));
+        assert!(!handshake.is_done());
+    }
+}
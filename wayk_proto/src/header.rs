@@ -6,6 +6,7 @@ use crate::{
 use std::io::{Cursor, Read, Write};
 
 const HEADER_VIRTUAL_CHANNEL_FLAG: u8 = 0x01;
+const HEADER_COMPRESSED_FLAG: u8 = 0x02;
 
 #[allow(clippy::len_without_is_empty)] // it doesn't make sense in our case
 pub trait AbstractNowHeader {
@@ -15,6 +16,11 @@ pub trait AbstractNowHeader {
     fn body_type(&self) -> BodyType;
     fn body_len(&self) -> usize;
     fn packet_len(&self) -> usize;
+
+    /// Whether the body is compressed (see the `compression` module).
+    fn is_compressed(&self) -> bool {
+        self.flags() & HEADER_COMPRESSED_FLAG != 0
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -93,6 +99,47 @@ impl NowHeader {
         }
     }
 
+    #[cfg(feature = "async")]
+    pub async fn read_from_async<R: tokio::io::AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        use tokio::io::AsyncReadExt;
+
+        let mut buffer = [0u8; NowLongHeader::SIZE];
+        reader
+            .read_exact(&mut buffer[..NowShortHeader::SIZE])
+            .await
+            .map_err(ProtoError::from)
+            .chain(ProtoErrorKind::Decoding(stringify!(NowHeader)))
+            .or_desc("couldn't read short bit (no enough bytes provided")?;
+
+        if buffer[3] > 7 {
+            Ok(NowHeader::Short(NowShortHeader::decode(
+                &buffer[..NowShortHeader::SIZE],
+            )?))
+        } else {
+            reader
+                .read_exact(&mut buffer[NowShortHeader::SIZE..])
+                .await
+                .map_err(ProtoError::from)
+                .chain(ProtoErrorKind::Decoding(stringify!(NowHeader)))
+                .or_desc("not enough bytes provided to parse long header")?;
+            Ok(NowHeader::Long(NowLongHeader::decode(&buffer)?))
+        }
+    }
+
+    /// Same header, flagged as having a compressed body.
+    pub fn compressed(self) -> Self {
+        match self {
+            NowHeader::Short(mut header) => {
+                header.flags |= HEADER_COMPRESSED_FLAG;
+                NowHeader::Short(header)
+            }
+            NowHeader::Long(mut header) => {
+                header.flags |= HEADER_COMPRESSED_FLAG;
+                NowHeader::Long(header)
+            }
+        }
+    }
+
     pub fn borrow_short(&self) -> Option<&NowShortHeader> {
         match self {
             NowHeader::Short(header) => Some(header),
@@ -11,16 +11,37 @@ extern crate wayk_proto_derive;
 #[doc(hidden)]
 pub mod macros;
 pub mod auth;
+#[cfg(feature = "async")]
+pub mod automation;
 pub mod channels_manager;
+#[cfg(feature = "async")]
+pub mod client;
+#[cfg(feature = "compression")]
+pub mod compression;
 pub mod container;
 pub mod error;
+pub mod gateway;
 pub mod header;
+pub mod local;
 pub mod message;
+pub mod middleware;
 pub mod packet;
+pub mod proxy;
+#[cfg(feature = "schema")]
+pub mod schema;
+pub mod send_queue;
 pub mod serialization;
+#[cfg(feature = "async")]
+pub mod server;
+pub mod session_event;
 pub mod sharee;
 pub mod sm;
+pub mod state_report;
+pub mod tcp;
+#[cfg(feature = "async")]
+pub mod transport;
 pub mod version;
+pub mod wake;
 
 ////////////////////////////////////////////////////////////////////////////////
 
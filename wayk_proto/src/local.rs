@@ -0,0 +1,243 @@
+//! Connections to a Wayk agent running on the same host.
+//!
+//! The agent listens on a Unix domain socket (Linux and macOS) or on a named pipe (Windows).
+//! Packets are framed exactly as over TCP: a [`LocalStream`](struct.LocalStream.html) can be used
+//! wherever a `TcpStream` is. For async connections, a `tokio::net::UnixStream` or a tokio named pipe
+//! can be given to `transport::StreamTransport` as is.
+
+use crate::error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt};
+use core::{fmt, str::FromStr};
+use std::{
+    io::{self, Read, Write},
+    net::Shutdown,
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::PathBuf};
+
+#[cfg(windows)]
+use std::fs::File;
+
+/// Address of a local agent.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LocalAddr {
+    #[cfg(unix)]
+    UnixSocket(PathBuf),
+    /// Pipe name, without the `\\.\pipe\` prefix.
+    #[cfg(windows)]
+    NamedPipe(String),
+}
+
+impl LocalAddr {
+    pub const UNIX_SCHEME: &'static str = "unix:";
+    pub const PIPE_SCHEME: &'static str = "pipe:";
+
+    /// Returns `true` if `s` is a local address, even if not supported on this platform.
+    pub fn is_local_addr(s: &str) -> bool {
+        let s = s.trim();
+        s.starts_with(Self::UNIX_SCHEME) || s.starts_with(Self::PIPE_SCHEME)
+    }
+
+    pub fn connect(&self) -> Result<LocalStream, ProtoError> {
+        match self {
+            #[cfg(unix)]
+            LocalAddr::UnixSocket(path) => UnixStream::connect(path)
+                .map(|stream| LocalStream(LocalStreamInner::UnixSocket(stream)))
+                .map_err(ProtoError::from)
+                .chain(ProtoErrorKind::LocalConnection)
+                .or_else_desc(|| format!("couldn't connect to unix socket {}", path.display())),
+            #[cfg(windows)]
+            LocalAddr::NamedPipe(name) => {
+                let path = format!(r"\\.\pipe\{}", name);
+                std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&path)
+                    .map(|pipe| LocalStream(LocalStreamInner::NamedPipe(pipe)))
+                    .map_err(ProtoError::from)
+                    .chain(ProtoErrorKind::LocalConnection)
+                    .or_else_desc(|| format!("couldn't open named pipe {}", path))
+            }
+        }
+    }
+}
+
+impl FromStr for LocalAddr {
+    type Err = ProtoError;
+
+    /// Parses `unix:<path>` or `pipe:<name>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Some(path) = s.strip_prefix(Self::UNIX_SCHEME) {
+            #[cfg(unix)]
+            {
+                if !path.is_empty() {
+                    return Ok(LocalAddr::UnixSocket(PathBuf::from(path)));
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = path;
+                return ProtoError::new(ProtoErrorKind::LocalConnection)
+                    .or_desc("unix sockets are not supported on this platform");
+            }
+        } else if let Some(name) = s.strip_prefix(Self::PIPE_SCHEME) {
+            #[cfg(windows)]
+            {
+                let name = name.trim_start_matches(r"\\.\pipe\");
+                if !name.is_empty() {
+                    return Ok(LocalAddr::NamedPipe(name.to_owned()));
+                }
+            }
+            #[cfg(not(windows))]
+            {
+                let _ = name;
+                return ProtoError::new(ProtoErrorKind::LocalConnection)
+                    .or_desc("named pipes are not supported on this platform");
+            }
+        }
+
+        ProtoError::new(ProtoErrorKind::LocalConnection)
+            .or_else_desc(|| format!("invalid local address (expected unix:<path> or pipe:<name>): {}", s))
+    }
+}
+
+impl fmt::Display for LocalAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            #[cfg(unix)]
+            LocalAddr::UnixSocket(ref path) => write!(f, "{}{}", Self::UNIX_SCHEME, path.display()),
+            #[cfg(windows)]
+            LocalAddr::NamedPipe(ref name) => write!(f, "{}{}", Self::PIPE_SCHEME, name),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum LocalStreamInner {
+    #[cfg(unix)]
+    UnixSocket(UnixStream),
+    #[cfg(windows)]
+    NamedPipe(File),
+}
+
+/// Stream connected to a local agent.
+#[derive(Debug)]
+pub struct LocalStream(LocalStreamInner);
+
+impl LocalStream {
+    /// Same as `TcpStream::set_read_timeout`. Not supported on named pipes, whose reads block until data is received.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.0 {
+            #[cfg(unix)]
+            LocalStreamInner::UnixSocket(stream) => stream.set_read_timeout(timeout),
+            #[cfg(windows)]
+            LocalStreamInner::NamedPipe(_) => {
+                let _ = timeout;
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "read timeouts are not supported on named pipes",
+                ))
+            }
+        }
+    }
+
+    /// Same as `TcpStream::shutdown`. Named pipes are only closed once dropped.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match &self.0 {
+            #[cfg(unix)]
+            LocalStreamInner::UnixSocket(stream) => stream.shutdown(how),
+            #[cfg(windows)]
+            LocalStreamInner::NamedPipe(_) => {
+                let _ = how;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Read for LocalStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            #[cfg(unix)]
+            LocalStreamInner::UnixSocket(stream) => stream.read(buf),
+            #[cfg(windows)]
+            LocalStreamInner::NamedPipe(pipe) => pipe.read(buf),
+        }
+    }
+}
+
+impl Write for LocalStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            #[cfg(unix)]
+            LocalStreamInner::UnixSocket(stream) => stream.write(buf),
+            #[cfg(windows)]
+            LocalStreamInner::NamedPipe(pipe) => pipe.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.0 {
+            #[cfg(unix)]
+            LocalStreamInner::UnixSocket(stream) => stream.flush(),
+            #[cfg(windows)]
+            LocalStreamInner::NamedPipe(pipe) => pipe.flush(),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{NowBody, NowMessage, NowNetworkKeepAliveReqMsg, NowNetworkMsg, VirtChannelsCtx},
+        packet::{NowPacket, NowPacketAccumulator},
+        serialization::Encode,
+    };
+
+    #[test]
+    fn parse_local_addr() {
+        assert_eq!(
+            "unix:/run/wayk/agent.sock".parse::<LocalAddr>().unwrap(),
+            LocalAddr::UnixSocket(PathBuf::from("/run/wayk/agent.sock"))
+        );
+        assert!(LocalAddr::is_local_addr("pipe:wayk-agent"));
+        assert!("pipe:wayk-agent".parse::<LocalAddr>().is_err());
+        assert!("unix:".parse::<LocalAddr>().is_err());
+        assert!("127.0.0.1:4489".parse::<LocalAddr>().is_err());
+    }
+
+    #[test]
+    fn packets_framed_over_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("wayk-proto-local-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("agent.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let agent = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let packet = NowPacket::from_message(NowNetworkMsg::from(NowNetworkKeepAliveReqMsg::new(3, 0)));
+            stream.write_all(&packet.encode().unwrap()).unwrap();
+        });
+
+        let addr = format!("unix:{}", path.display()).parse::<LocalAddr>().unwrap();
+        let mut stream = addr.connect().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        agent.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut acc = NowPacketAccumulator::new();
+        acc.accumulate(&received);
+        match acc.next_packet(&VirtChannelsCtx::new()).unwrap().unwrap().body {
+            NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveReq(req))) => {
+                assert_eq!(req.sequence_id, 3)
+            }
+            other => panic!("expected a keep-alive request, got {:?}", other),
+        }
+    }
+}
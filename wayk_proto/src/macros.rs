@@ -8,11 +8,34 @@
 #[macro_export]
 macro_rules! __flags_struct {
     ($flags_type:ident : $underlying_type:ident) => {
+        $crate::__flags_struct!{ @struct $flags_type : $underlying_type => [] }
+    };
+    (@struct $flags_type:ident : $underlying_type:ident => [ $( $UPPERCASE:ident ),* ]) => {
         #[derive(wayk_proto_derive::Encode, wayk_proto_derive::Decode, Debug, PartialEq, Clone, Copy)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        #[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+        #[fixed_size]
+        #[manual_schema]
         pub struct $flags_type {
             pub value: $underlying_type,
         }
 
+        #[cfg(feature = "schema")]
+        impl $crate::schema::WireSchema for $flags_type {
+            fn describe(schema: &mut $crate::schema::Schema) -> $crate::schema::TypeSchema {
+                schema.define(stringify!($flags_type), |_| $crate::schema::TypeSchema::Flags {
+                    repr: stringify!($underlying_type),
+                    size: core::mem::size_of::<$underlying_type>(),
+                    flags: vec![$(
+                        $crate::schema::NamedValue {
+                            name: stringify!($UPPERCASE),
+                            value: Self::$UPPERCASE as u64,
+                        }
+                    ),*],
+                })
+            }
+        }
+
         impl From<$underlying_type> for $flags_type {
             fn from(value: $underlying_type) -> Self {
                 Self { value }
@@ -42,7 +65,7 @@ macro_rules! __flags_struct {
             $( $lowercase:ident = $UPPERCASE:ident = $const_value:expr , )+
         }
     ) => {
-        $crate::__flags_struct!{ $flags_type : $underlying_type }
+        $crate::__flags_struct!{ @struct $flags_type : $underlying_type => [ $( $UPPERCASE ),+ ] }
 
         impl $flags_type {
             $(
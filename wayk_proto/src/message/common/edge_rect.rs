@@ -1,8 +1,12 @@
 // NOW_EDGE_RECT
 
-use core::mem;
+use crate::serialization::FixedSize;
+use core::convert::TryFrom;
 
-#[derive(Encode, Decode, Debug, Clone, Default)]
+#[derive(Encode, Decode, Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[fixed_size]
 pub struct EdgeRect {
     pub left: i16,
     pub top: i16,
@@ -11,7 +15,120 @@ pub struct EdgeRect {
 }
 
 impl EdgeRect {
-    pub const REQUIRED_SIZE: usize = mem::size_of::<Self>();
+    pub const REQUIRED_SIZE: usize = Self::ENCODED_SIZE;
+
+    /// Builds a rectangle from its origin and size. Returns `None` if an edge overflows.
+    pub fn from_xywh(x: i16, y: i16, width: u16, height: u16) -> Option<Self> {
+        let right = i16::try_from(i32::from(x) + i32::from(width)).ok()?;
+        let bottom = i16::try_from(i32::from(y) + i32::from(height)).ok()?;
+        Some(Self {
+            left: x,
+            top: y,
+            right,
+            bottom,
+        })
+    }
+
+    /// Origin and size. An empty rectangle has a null size.
+    pub fn to_xywh(&self) -> (i16, i16, u16, u16) {
+        let width = self.width().max(0) as u16;
+        let height = self.height().max(0) as u16;
+        (self.left, self.top, width, height)
+    }
+
+    pub fn width(&self) -> i32 {
+        i32::from(self.right) - i32::from(self.left)
+    }
+
+    pub fn height(&self) -> i32 {
+        i32::from(self.bottom) - i32::from(self.top)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width() <= 0 || self.height() <= 0
+    }
+
+    /// Whether the point is inside the rectangle (right and bottom edges excluded).
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        i32::from(self.left) <= x && x < i32::from(self.right) && i32::from(self.top) <= y && y < i32::from(self.bottom)
+    }
+
+    /// Whether the other rectangle is entirely inside this one.
+    pub fn contains_rect(&self, other: &EdgeRect) -> bool {
+        self.left <= other.left && self.top <= other.top && other.right <= self.right && other.bottom <= self.bottom
+    }
+
+    /// Area shared by both rectangles, if any.
+    pub fn intersection(&self, other: &EdgeRect) -> Option<EdgeRect> {
+        let rect = EdgeRect {
+            left: self.left.max(other.left),
+            top: self.top.max(other.top),
+            right: self.right.min(other.right),
+            bottom: self.bottom.min(other.bottom),
+        };
+
+        if rect.is_empty() {
+            None
+        } else {
+            Some(rect)
+        }
+    }
+
+    /// Smallest rectangle containing both rectangles. Empty rectangles are ignored.
+    pub fn union(&self, other: &EdgeRect) -> EdgeRect {
+        if other.is_empty() {
+            return self.clone();
+        }
+        if self.is_empty() {
+            return other.clone();
+        }
+
+        EdgeRect {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+
+    /// Moves the rectangle. Returns `None` if an edge overflows.
+    pub fn translate(&self, dx: i16, dy: i16) -> Option<EdgeRect> {
+        Some(EdgeRect {
+            left: self.left.checked_add(dx)?,
+            top: self.top.checked_add(dy)?,
+            right: self.right.checked_add(dx)?,
+            bottom: self.bottom.checked_add(dy)?,
+        })
+    }
+
+    /// Scales all edges by `numerator / denominator` (rounded towards zero).
+    /// Returns `None` if the denominator is zero or an edge overflows.
+    pub fn scale(&self, numerator: u16, denominator: u16) -> Option<EdgeRect> {
+        if denominator == 0 {
+            return None;
+        }
+
+        let scale = |value: i16| i16::try_from(i32::from(value) * i32::from(numerator) / i32::from(denominator)).ok();
+        Some(EdgeRect {
+            left: scale(self.left)?,
+            top: scale(self.top)?,
+            right: scale(self.right)?,
+            bottom: scale(self.bottom)?,
+        })
+    }
+
+    /// Whether both rectangles share a non-empty area.
+    pub fn intersects(&self, other: &EdgeRect) -> bool {
+        self.left < other.right && other.left < self.right && self.top < other.bottom && other.top < self.bottom
+    }
+
+    /// Whether both rectangles share an edge segment without overlapping.
+    pub fn is_adjacent_to(&self, other: &EdgeRect) -> bool {
+        let vertical_overlap = self.top < other.bottom && other.top < self.bottom;
+        let horizontal_overlap = self.left < other.right && other.left < self.right;
+        ((self.right == other.left || other.right == self.left) && vertical_overlap)
+            || ((self.bottom == other.top || other.bottom == self.top) && horizontal_overlap)
+    }
 }
 
 #[cfg(test)]
@@ -46,4 +163,44 @@ mod tests {
         };
         assert_eq!(rect.encode().unwrap(), NOW_EDGE_RECT.to_vec());
     }
+
+    fn rect(left: i16, top: i16, right: i16, bottom: i16) -> EdgeRect {
+        EdgeRect {
+            left,
+            top,
+            right,
+            bottom,
+        }
+    }
+
+    #[test]
+    fn xywh_conversion() {
+        let r = EdgeRect::from_xywh(-1280, 0, 1280, 1024).unwrap();
+        assert_eq!(r, rect(-1280, 0, 0, 1024));
+        assert_eq!(r.to_xywh(), (-1280, 0, 1280, 1024));
+        assert!(EdgeRect::from_xywh(i16::MAX, 0, 1, 1).is_none());
+        assert_eq!(rect(10, 10, 0, 0).to_xywh(), (10, 10, 0, 0));
+    }
+
+    #[test]
+    fn intersection_and_union() {
+        let a = rect(0, 0, 1920, 1080);
+        let b = rect(1000, 500, 2920, 1580);
+        assert_eq!(a.intersection(&b), Some(rect(1000, 500, 1920, 1080)));
+        assert_eq!(a.intersection(&rect(1920, 0, 3840, 1080)), None);
+        assert_eq!(a.union(&b), rect(0, 0, 2920, 1580));
+        assert_eq!(a.union(&EdgeRect::default()), a);
+        assert!(a.contains_rect(&rect(10, 10, 1920, 1080)));
+        assert!(!a.contains_rect(&b));
+    }
+
+    #[test]
+    fn translate_and_scale() {
+        let r = rect(0, 0, 1920, 1080);
+        assert_eq!(r.translate(-1920, 10), Some(rect(-1920, 10, 0, 1090)));
+        assert!(r.translate(i16::MAX, 0).is_none());
+        assert_eq!(r.scale(1, 2), Some(rect(0, 0, 960, 540)));
+        assert!(r.scale(20, 1).is_none());
+        assert!(r.scale(1, 0).is_none());
+    }
 }
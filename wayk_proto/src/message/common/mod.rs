@@ -1,10 +1,14 @@
 // ****** Common Structures ****** //
 
 pub mod edge_rect;
+pub mod now_cstr;
 pub mod now_string;
+pub mod now_string_utf16;
 pub mod size_rect;
 
 // re-export
 pub use edge_rect::*;
+pub use now_cstr::*;
 pub use now_string::*;
+pub use now_string_utf16::*;
 pub use size_rect::*;
@@ -0,0 +1,173 @@
+// NOW_CSTR
+
+use crate::{
+    error::*,
+    serialization::{Decode, Encode},
+};
+use byteorder::WriteBytesExt;
+use std::{
+    borrow::Cow,
+    io::{Cursor, Write},
+};
+
+/// Null-terminated UTF-8 string without size prefix.
+///
+/// Decoding borrows from the input buffer unless invalid sequences are replaced by `decode_lossy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NowCStr<'a> {
+    str: Cow<'a, str>,
+}
+
+impl<'a> NowCStr<'a> {
+    pub fn new<S: Into<Cow<'a, str>>>(s: S) -> Result<Self> {
+        let str = s.into();
+        if str.contains('\0') {
+            return ProtoError::new(ProtoErrorKind::Encoding("NowCStr"))
+                .or_desc("provided string contains a null byte");
+        }
+
+        Ok(Self { str })
+    }
+
+    /// Decodes the string, replacing invalid utf8 sequences with `U+FFFD` instead of failing.
+    pub fn decode_lossy(cursor: &mut Cursor<&'a [u8]>) -> Result<Self> {
+        let bytes = Self::decode_bytes(cursor)?;
+        Ok(Self {
+            str: String::from_utf8_lossy(bytes),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.str.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.str.is_empty()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.str
+    }
+
+    pub fn into_owned(self) -> NowCStr<'static> {
+        NowCStr {
+            str: Cow::Owned(self.str.into_owned()),
+        }
+    }
+
+    fn decode_bytes<'dec>(cursor: &mut Cursor<&'dec [u8]>) -> Result<&'dec [u8]> {
+        let start_inclusive = cursor.position() as usize;
+        let bytes: &'dec [u8] = cursor.get_ref();
+        let remaining = bytes.get(start_inclusive..).unwrap_or(&[]);
+
+        let len = match remaining.iter().position(|&b| b == 0) {
+            Some(len) => len,
+            None => {
+                return ProtoError::new(ProtoErrorKind::Decoding(stringify!(NowCStr)))
+                    .or_desc("no null terminator found")
+            }
+        };
+        cursor.set_position((start_inclusive + len + 1) as u64);
+
+        Ok(&remaining[..len])
+    }
+}
+
+impl<'dec: 'a, 'a> Decode<'dec> for NowCStr<'a> {
+    fn decode_from(cursor: &mut Cursor<&'dec [u8]>) -> Result<Self> {
+        let bytes = Self::decode_bytes(cursor)?;
+        match core::str::from_utf8(bytes) {
+            Ok(str) => Ok(Self {
+                str: Cow::Borrowed(str),
+            }),
+            Err(e) => ProtoError::new(ProtoErrorKind::Decoding(stringify!(NowCStr)))
+                .or_else_desc(|| format!("invalid utf8 string: {}", e)),
+        }
+    }
+}
+
+impl Encode for NowCStr<'_> {
+    fn encoded_len(&self) -> usize {
+        self.str.len() + std::mem::size_of::<u8>()
+    }
+
+    fn encode_into<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(self.str.as_bytes())?;
+        writer.write_u8(0u8)?; // null terminator
+        Ok(())
+    }
+}
+
+impl PartialEq<&str> for NowCStr<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        self.str == *other
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for NowCStr<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.str)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for NowCStr<'_> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        Self::new(string).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for NowCStr<'a> {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let s = <&str>::arbitrary(u)?;
+        Ok(Self {
+            str: s.replace('\0', "").into(),
+        })
+    }
+}
+
+#[cfg(feature = "schema")]
+impl crate::schema::WireSchema for NowCStr<'_> {
+    fn describe(_: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+        crate::schema::TypeSchema::String {
+            encoding: "utf8",
+            length: None,
+            max_len: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn now_cstr_round_trip() {
+        let bytes = [b'w', b'a', b'y', b'k', 0x00, 0x2a];
+        let mut cursor = Cursor::new(&bytes[..]);
+        let cstr = NowCStr::decode_from(&mut cursor).unwrap();
+        assert_eq!(cstr, "wayk");
+        assert_eq!(cstr.as_str().as_ptr(), bytes.as_ptr());
+        assert_eq!(u8::decode_from(&mut cursor).unwrap(), 0x2a);
+
+        assert_eq!(cstr.encoded_len(), 5);
+        assert_eq!(cstr.encode().unwrap(), bytes[..5].to_vec());
+    }
+
+    #[test]
+    fn now_cstr_errors() {
+        assert!(NowCStr::decode(b"wa").is_err());
+        assert!(NowCStr::decode(&[0xff, 0x00]).is_err());
+        assert!(NowCStr::new("wa\0yk").is_err());
+    }
+
+    #[test]
+    fn now_cstr_lossy() {
+        let bytes = [b'w', 0xff, 0x00];
+        let cstr = NowCStr::decode_lossy(&mut Cursor::new(&bytes[..])).unwrap();
+        assert_eq!(cstr, "w\u{fffd}");
+    }
+}
@@ -17,7 +17,7 @@ pub trait NowStringSize {
 }
 
 macro_rules! now_string_size {
-    ( $string_size_name:ident, $string_size_type:ident, $now_string_name:ident, $size:literal ) => {
+    ( $string_size_name:ident, $string_size_type:ident, $now_string_name:ident, $now_str_name:ident, $size:literal ) => {
         #[derive(Debug, Clone, Copy)]
         pub struct $string_size_name;
 
@@ -26,15 +26,16 @@ macro_rules! now_string_size {
         }
 
         pub type $now_string_name = NowString<$string_size_name, $string_size_type>;
+        pub type $now_str_name<'a> = NowStr<'a, $string_size_name, $string_size_type>;
     };
 }
 
-now_string_size! { StringSize16,    u8,  NowString16,    16    }
-now_string_size! { StringSize32,    u8,  NowString32,    32    }
-now_string_size! { StringSize64,    u8,  NowString64,    64    }
-now_string_size! { StringSize128,   u8,  NowString128,   128   }
-now_string_size! { StringSize256,   u8,  NowString256,   256   }
-now_string_size! { StringSize65535, u16, NowString65535, 65535 }
+now_string_size! { StringSize16,    u8,  NowString16,    NowStr16,    16    }
+now_string_size! { StringSize32,    u8,  NowString32,    NowStr32,    32    }
+now_string_size! { StringSize64,    u8,  NowString64,    NowStr64,    64    }
+now_string_size! { StringSize128,   u8,  NowString128,   NowStr128,   128   }
+now_string_size! { StringSize256,   u8,  NowString256,   NowStr256,   256   }
+now_string_size! { StringSize65535, u16, NowString65535, NowStr65535, 65535 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NowString<Size, SizeType> {
@@ -230,6 +231,218 @@ impl<Size, SizeType> PartialEq<String> for NowString<Size, SizeType> {
     }
 }
 
+/// Borrowing counterpart of `NowString`: decoding doesn't allocate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NowStr<'a, Size, SizeType> {
+    str: &'a str,
+    _pd: PhantomData<(Size, SizeType)>,
+}
+
+impl<'dec: 'a, 'a, Size, SizeType> Decode<'dec> for NowStr<'a, Size, SizeType>
+where
+    Size: NowStringSize,
+    SizeType: Decode<'dec> + ToPrimitive,
+{
+    fn decode_from(cursor: &mut Cursor<&'dec [u8]>) -> Result<Self> {
+        let expected_size = SizeType::decode_from(cursor)?.to_usize().unwrap(); // should never panic by construction
+
+        if expected_size > Size::SIZE {
+            return ProtoError::new(ProtoErrorKind::Decoding(stringify!(NowStr))).or_else_desc(|| {
+                format!(
+                    "attempted to parse a string greater (len: {}) than the NowStr{} size limit",
+                    expected_size,
+                    Size::SIZE
+                )
+            });
+        }
+
+        let start_inclusive = cursor.position() as usize;
+        let bytes: &'dec [u8] = cursor.get_ref();
+        // string followed by its null terminator
+        if bytes.len() < start_inclusive + expected_size + 1 {
+            return ProtoError::new(ProtoErrorKind::Decoding(stringify!(NowStr))).or_else_desc(|| {
+                format!(
+                    "no enough bytes to parse the NowStr{} (expected {})",
+                    Size::SIZE,
+                    expected_size
+                )
+            });
+        }
+
+        let str = match core::str::from_utf8(&bytes[start_inclusive..start_inclusive + expected_size]) {
+            Ok(str) => str,
+            Err(e) => {
+                return ProtoError::new(ProtoErrorKind::Decoding(stringify!(NowStr)))
+                    .or_else_desc(|| format!("invalid utf8 string: {}", e))
+            }
+        };
+        cursor.set_position((start_inclusive + expected_size + 1) as u64);
+
+        Ok(NowStr { str, _pd: PhantomData })
+    }
+}
+
+impl<Size, SizeType> Encode for NowStr<'_, Size, SizeType>
+where
+    Size: NowStringSize,
+    SizeType: Encode + FromPrimitive,
+{
+    fn encoded_len(&self) -> usize {
+        self.str.len() + std::mem::size_of::<u8>() + std::mem::size_of::<SizeType>()
+    }
+
+    fn encode_into<W: Write>(&self, writer: &mut W) -> Result<()> {
+        NowString::<Size, SizeType>::helper_write_into(writer, self.str)
+    }
+}
+
+impl<'a, Size, SizeType> NowStr<'a, Size, SizeType>
+where
+    Size: NowStringSize,
+{
+    pub fn new(s: &'a str) -> Result<Self> {
+        if s.len() > Size::SIZE {
+            return ProtoError::new(ProtoErrorKind::Encoding("NowStr")).or_else_desc(|| {
+                format!(
+                    "provided string greater (len: {}) than NowStr{} size limit",
+                    s.len(),
+                    Size::SIZE
+                )
+            });
+        }
+
+        Ok(Self {
+            str: s,
+            _pd: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.str.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.str.is_empty()
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.str
+    }
+
+    pub fn to_now_string(&self) -> NowString<Size, SizeType> {
+        NowString {
+            str: self.str.to_owned(),
+            _pd: PhantomData,
+        }
+    }
+}
+
+impl<Size, SizeType> PartialEq<&str> for NowStr<'_, Size, SizeType> {
+    fn eq(&self, other: &&str) -> bool {
+        self.str == *other
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<Size, SizeType> serde::Serialize for NowString<Size, SizeType> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.str)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, Size, SizeType> serde::Deserialize<'de> for NowString<Size, SizeType>
+where
+    Size: NowStringSize,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        Self::try_from(string).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<Size, SizeType> serde::Serialize for NowStr<'_, Size, SizeType> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.str)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de: 'a, 'a, Size, SizeType> serde::Deserialize<'de> for NowStr<'a, Size, SizeType>
+where
+    Size: NowStringSize,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+        let str = <&'de str>::deserialize(deserializer)?;
+        Self::new(str).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Longest prefix of `s` not exceeding `max_len` bytes.
+#[cfg(feature = "fuzzing")]
+fn truncate_str(s: &str, max_len: usize) -> &str {
+    let mut end = s.len().min(max_len);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'arb, Size, SizeType> arbitrary::Arbitrary<'arb> for NowString<Size, SizeType>
+where
+    Size: NowStringSize,
+{
+    fn arbitrary(u: &mut arbitrary::Unstructured<'arb>) -> arbitrary::Result<Self> {
+        let s = <&str>::arbitrary(u)?;
+        Ok(Self {
+            str: truncate_str(s, Size::SIZE).to_owned(),
+            _pd: PhantomData,
+        })
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a, Size, SizeType> arbitrary::Arbitrary<'a> for NowStr<'a, Size, SizeType>
+where
+    Size: NowStringSize,
+{
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let s = <&str>::arbitrary(u)?;
+        Ok(Self {
+            str: truncate_str(s, Size::SIZE),
+            _pd: PhantomData,
+        })
+    }
+}
+
+#[cfg(feature = "schema")]
+impl<Size, SizeType> crate::schema::WireSchema for NowString<Size, SizeType>
+where
+    Size: NowStringSize,
+    SizeType: crate::schema::WireSchema,
+{
+    fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+        crate::schema::TypeSchema::String {
+            encoding: "utf8",
+            length: Some(Box::new(schema.register::<SizeType>())),
+            max_len: Some(Size::SIZE),
+        }
+    }
+}
+
+#[cfg(feature = "schema")]
+impl<Size, SizeType> crate::schema::WireSchema for NowStr<'_, Size, SizeType>
+where
+    Size: NowStringSize,
+    SizeType: crate::schema::WireSchema,
+{
+    fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+        schema.register::<NowString<Size, SizeType>>()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -287,6 +500,30 @@ mod tests {
         assert_eq!(encoded_now_string, NOW_STRING_CHINESE);
     }
 
+    #[test]
+    fn decode_now_str_64_borrows() {
+        let mut bytes = NOW_STRING_CHINESE.to_vec();
+        bytes.push(0x2a);
+        let mut cursor = Cursor::new(bytes.as_slice());
+        let nstr = NowStr64::decode_from(&mut cursor).unwrap();
+        assert_eq!(nstr, STRING_CHINESE);
+        assert_eq!(nstr.as_str().as_ptr(), bytes[1..].as_ptr());
+        assert_eq!(u8::decode_from(&mut cursor).unwrap(), 0x2a);
+
+        assert_eq!(nstr.encode().unwrap(), NOW_STRING_CHINESE.to_vec());
+        assert_eq!(nstr.to_now_string(), STRING_CHINESE);
+    }
+
+    #[test]
+    fn decode_now_str_64_errors() {
+        assert!(NowStr64::decode(&NOW_STRING_CHINESE[..7]).is_err());
+        assert!(NowStr64::decode(&[0x02, 0xff, 0xfe, 0x00]).is_err());
+
+        let mut bytes = [0; 66];
+        bytes[0] = 65;
+        assert!(NowStr64::decode(&bytes).is_err());
+    }
+
     #[rustfmt::skip]
     const NOW_STRING_65535_CHINESE: [u8; 9] = [
         0x06, 0x00, // size
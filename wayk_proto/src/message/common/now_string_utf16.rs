@@ -0,0 +1,277 @@
+// NOW_STRING (UTF-16)
+
+use crate::{
+    error::*,
+    message::NowStringSize,
+    serialization::{Decode, Encode},
+};
+use core::{convert::TryFrom, marker::PhantomData, str::FromStr};
+use num_traits::{FromPrimitive, ToPrimitive};
+use std::io::{Cursor, Write};
+
+/// UTF-16LE counterpart of `NowString`.
+///
+/// The size prefix and the `Size` limit count UTF-16 code units, not bytes.
+/// The string is followed by a null code unit on the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NowStringUtf16<Size, SizeType> {
+    str: String,
+    _pd: PhantomData<(Size, SizeType)>,
+}
+
+impl<Size, SizeType> NowStringUtf16<Size, SizeType>
+where
+    Size: NowStringSize,
+{
+    pub fn new_empty() -> Self {
+        Self {
+            str: String::new(),
+            _pd: PhantomData,
+        }
+    }
+
+    pub fn from_string(string: String) -> Result<Self> {
+        Self::try_from(string)
+    }
+
+    /// Length in UTF-16 code units.
+    pub fn len(&self) -> usize {
+        self.str.encode_utf16().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.str.is_empty()
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.str.as_str()
+    }
+}
+
+impl<Size, SizeType> NowStringUtf16<Size, SizeType>
+where
+    Size: NowStringSize,
+    SizeType: for<'dec> Decode<'dec> + ToPrimitive,
+{
+    /// Decodes the string, replacing invalid code units with `U+FFFD` instead of failing.
+    pub fn decode_lossy(cursor: &mut Cursor<&[u8]>) -> Result<Self> {
+        let units = Self::decode_code_units(cursor)?;
+        Ok(Self {
+            str: String::from_utf16_lossy(&units),
+            _pd: PhantomData,
+        })
+    }
+
+    fn decode_code_units(cursor: &mut Cursor<&[u8]>) -> Result<Vec<u16>> {
+        let expected_size = SizeType::decode_from(cursor)?.to_usize().unwrap(); // should never panic by construction
+
+        if expected_size > Size::SIZE {
+            return ProtoError::new(ProtoErrorKind::Decoding(stringify!(NowStringUtf16))).or_else_desc(|| {
+                format!(
+                    "attempted to parse a string greater (len: {}) than the NowStringUtf16{} size limit",
+                    expected_size,
+                    Size::SIZE
+                )
+            });
+        }
+
+        let mut units = Vec::with_capacity(expected_size);
+        for _ in 0..expected_size {
+            units.push(
+                u16::decode_from(cursor)
+                    .chain(ProtoErrorKind::Decoding(stringify!(NowStringUtf16)))
+                    .or_else_desc(|| {
+                        format!(
+                            "no enough bytes to parse the NowStringUtf16{} (expected {} code units)",
+                            Size::SIZE,
+                            expected_size
+                        )
+                    })?,
+            );
+        }
+        u16::decode_from(cursor)
+            .chain(ProtoErrorKind::Decoding(stringify!(NowStringUtf16)))
+            .or_desc("missing null terminator")?;
+
+        Ok(units)
+    }
+}
+
+impl<'dec, Size, SizeType> Decode<'dec> for NowStringUtf16<Size, SizeType>
+where
+    Size: NowStringSize,
+    SizeType: for<'a> Decode<'a> + ToPrimitive,
+{
+    fn decode_from(cursor: &mut Cursor<&'dec [u8]>) -> Result<Self> {
+        let units = Self::decode_code_units(cursor)?;
+        let str = match String::from_utf16(&units) {
+            Ok(str) => str,
+            Err(e) => {
+                return ProtoError::new(ProtoErrorKind::Decoding(stringify!(NowStringUtf16)))
+                    .or_else_desc(|| format!("invalid utf16 string: {}", e))
+            }
+        };
+
+        Ok(Self { str, _pd: PhantomData })
+    }
+}
+
+impl<Size, SizeType> Encode for NowStringUtf16<Size, SizeType>
+where
+    SizeType: Encode + FromPrimitive,
+{
+    fn encoded_len(&self) -> usize {
+        (self.str.encode_utf16().count() + 1) * std::mem::size_of::<u16>() + std::mem::size_of::<SizeType>()
+    }
+
+    fn encode_into<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let len = self.str.encode_utf16().count();
+        let len = <SizeType as FromPrimitive>::from_usize(len).unwrap(); // should never panic by construction
+        len.encode_into(writer)?;
+        for unit in self.str.encode_utf16() {
+            unit.encode_into(writer)?;
+        }
+        0u16.encode_into(writer)?; // null terminator
+        Ok(())
+    }
+}
+
+impl<Size, SizeType> From<NowStringUtf16<Size, SizeType>> for String {
+    fn from(s: NowStringUtf16<Size, SizeType>) -> Self {
+        s.str
+    }
+}
+
+impl<Size, SizeType> TryFrom<String> for NowStringUtf16<Size, SizeType>
+where
+    Size: NowStringSize,
+{
+    type Error = ProtoError;
+
+    fn try_from(string: String) -> Result<Self> {
+        let len = string.encode_utf16().count();
+        if len > Size::SIZE {
+            return ProtoError::new(ProtoErrorKind::Encoding("NowStringUtf16")).or_else_desc(|| {
+                format!(
+                    "provided string greater (len: {}) than NowStringUtf16{} size limit",
+                    len,
+                    Size::SIZE
+                )
+            });
+        }
+
+        Ok(Self {
+            str: string,
+            _pd: PhantomData,
+        })
+    }
+}
+
+impl<Size, SizeType> FromStr for NowStringUtf16<Size, SizeType>
+where
+    Size: NowStringSize,
+{
+    type Err = ProtoError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::try_from(s.to_string())
+    }
+}
+
+impl<Size, SizeType> PartialEq<&str> for NowStringUtf16<Size, SizeType> {
+    fn eq(&self, other: &&str) -> bool {
+        &self.str == other
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<Size, SizeType> serde::Serialize for NowStringUtf16<Size, SizeType> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.str)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, Size, SizeType> serde::Deserialize<'de> for NowStringUtf16<Size, SizeType>
+where
+    Size: NowStringSize,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        Self::try_from(string).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'arb, Size, SizeType> arbitrary::Arbitrary<'arb> for NowStringUtf16<Size, SizeType>
+where
+    Size: NowStringSize,
+{
+    fn arbitrary(u: &mut arbitrary::Unstructured<'arb>) -> arbitrary::Result<Self> {
+        let mut len = 0;
+        let str = <&str>::arbitrary(u)?
+            .chars()
+            .take_while(|c| {
+                len += c.len_utf16();
+                len <= Size::SIZE
+            })
+            .collect();
+        Ok(Self { str, _pd: PhantomData })
+    }
+}
+
+#[cfg(feature = "schema")]
+impl<Size, SizeType> crate::schema::WireSchema for NowStringUtf16<Size, SizeType>
+where
+    Size: NowStringSize,
+    SizeType: crate::schema::WireSchema,
+{
+    fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+        crate::schema::TypeSchema::String {
+            encoding: "utf16le",
+            length: Some(Box::new(schema.register::<SizeType>())),
+            max_len: Some(Size::SIZE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{StringSize16, StringSize64};
+
+    type NowStringUtf16_64 = NowStringUtf16<StringSize64, u16>;
+
+    #[rustfmt::skip]
+    const NOW_STRING_UTF16_CHINESE: [u8; 8] = [
+        0x02, 0x00, // size (code units)
+        0x80, 0x7b, 0xcb, 0x4e, // actual UTF16 string
+        0x00, 0x00, // null terminator
+    ];
+
+    #[test]
+    fn now_string_utf16_round_trip() {
+        let nstr = NowStringUtf16_64::decode(&NOW_STRING_UTF16_CHINESE).unwrap();
+        assert_eq!(nstr, "简介");
+        assert_eq!(nstr.len(), 2);
+        assert_eq!(nstr.encoded_len(), NOW_STRING_UTF16_CHINESE.len());
+        assert_eq!(nstr.encode().unwrap(), NOW_STRING_UTF16_CHINESE.to_vec());
+    }
+
+    #[test]
+    fn now_string_utf16_lossy() {
+        // lone high surrogate
+        let bytes = [0x01, 0x00, 0x00, 0xd8, 0x00, 0x00];
+        assert!(NowStringUtf16_64::decode(&bytes).is_err());
+
+        let nstr = NowStringUtf16_64::decode_lossy(&mut Cursor::new(&bytes[..])).unwrap();
+        assert_eq!(nstr, "\u{fffd}");
+    }
+
+    #[test]
+    fn now_string_utf16_size_limit() {
+        assert!(NowStringUtf16::<StringSize16, u8>::from_str("0123456789abcdef").is_ok());
+        assert!(NowStringUtf16::<StringSize16, u8>::from_str("0123456789abcdefg").is_err());
+        assert!(NowStringUtf16::<StringSize16, u8>::decode(&[0x11; 40]).is_err());
+    }
+}
@@ -1,8 +1,11 @@
 // NOW_SIZE_RECT
 
-use core::mem;
+use crate::serialization::FixedSize;
 
-#[derive(Decode, Encode, Debug, Clone, Default)]
+#[derive(Decode, Encode, Debug, PartialEq, Eq, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[fixed_size]
 pub struct SizeRect {
     pub x: i16,
     pub y: i16,
@@ -11,7 +14,7 @@ pub struct SizeRect {
 }
 
 impl SizeRect {
-    pub const REQUIRED_SIZE: usize = mem::size_of::<Self>();
+    pub const REQUIRED_SIZE: usize = Self::ENCODED_SIZE;
 }
 
 #[cfg(test)]
@@ -1,4 +1,6 @@
 #[derive(Encode, Decode, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowActivateMsg {
     flags: u32,
 }
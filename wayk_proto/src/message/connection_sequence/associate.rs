@@ -1,9 +1,14 @@
 // NOW_ASSOCIATE_MSG
 
-use crate::message::status::{AssociateStatusCode, NowStatus};
+use crate::message::{
+    status::{AssociateStatusCode, NowStatus},
+    HandoffToken,
+};
 use num_derive::FromPrimitive;
 
 #[derive(Decode, Encode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum AssociateMessageType {
     Info = 0x01,
@@ -12,6 +17,8 @@ pub enum AssociateMessageType {
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "AssociateMessageType"]
 pub enum NowAssociateMsg {
     Info(NowAssociateInfoMsg),
@@ -68,7 +75,10 @@ __flags_struct! {
 }
 
 #[derive(Decode, Encode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowAssociateInfoMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: AssociateMessageType,
     reserved: u8,
     pub flags: AssociateInfoFlags,
@@ -101,16 +111,21 @@ impl NowAssociateInfoMsg {
 __flags_struct! {
     AssociateRequestFlags: u16 => {
         force = FORCE = 0x0001,
+        handoff = HANDOFF = 0x0002,
         failure = FAILURE = 0x8000,
     }
 }
 
+/// When `HANDOFF` is set, the handoff token issued for `session_id` follows.
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NowAssociateRequestMsg {
     subtype: AssociateMessageType,
     reserved: u8,
     pub flags: AssociateRequestFlags,
     pub session_id: u32,
+    #[decode_if(flags, AssociateRequestFlags::HANDOFF)]
+    pub handoff_token: Option<HandoffToken>,
 }
 
 impl Default for NowAssociateRequestMsg {
@@ -132,10 +147,37 @@ impl NowAssociateRequestMsg {
             reserved: 0x00,
             flags,
             session_id,
+            handoff_token: None,
+        }
+    }
+
+    pub fn new_handoff(session_id: u32, token: HandoffToken) -> Self {
+        Self {
+            handoff_token: Some(token),
+            ..Self::new_with_session_id(AssociateRequestFlags::new_empty().set_handoff(), session_id)
         }
     }
 }
 
+#[cfg(feature = "fuzzing")]
+impl<'arb> arbitrary::Arbitrary<'arb> for NowAssociateRequestMsg {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'arb>) -> arbitrary::Result<Self> {
+        let flags = AssociateRequestFlags::arbitrary(u)?;
+        let session_id = u32::arbitrary(u)?;
+        let handoff_token = if flags.handoff() {
+            Some(HandoffToken::arbitrary(u)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            reserved: u8::arbitrary(u)?,
+            handoff_token,
+            ..Self::new_with_session_id(flags, session_id)
+        })
+    }
+}
+
 __flags_struct! {
     AssociateResponseFlags: u16 => {
         failure = FAILURE = 0x8000,
@@ -143,7 +185,10 @@ __flags_struct! {
 }
 
 #[derive(Decode, Encode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowAssociateResponseMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: AssociateMessageType,
     reserved: u8,
     pub flags: AssociateResponseFlags,
@@ -231,6 +276,34 @@ mod tests {
         assert_eq!(ASSOCIATE_MSG_REQUEST, request.encode().unwrap()[0..]);
     }
 
+    #[rustfmt::skip]
+    const ASSOCIATE_MSG_HANDOFF_REQUEST: [u8; 24] = [
+        0x02, // subtype
+        0x00, // reserved
+        0x02, 0x00, // flags
+        0x2a, 0x00, 0x00, 0x00, // session id
+        // handoff token
+        0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn handoff_request_decoding() {
+        let msg = NowAssociateMsg::decode(&ASSOCIATE_MSG_HANDOFF_REQUEST).unwrap();
+        if let NowAssociateMsg::Request(msg) = msg {
+            assert!(msg.flags.handoff());
+            assert_eq!(msg.session_id, 42);
+            assert_eq!(msg.handoff_token, Some([1, 2, 3, 4]));
+        } else {
+            panic!("Expected a request message, found {:?}", msg);
+        }
+    }
+
+    #[test]
+    fn handoff_request_encoding() {
+        let request = NowAssociateMsg::from(NowAssociateRequestMsg::new_handoff(42, [1, 2, 3, 4]));
+        assert_eq!(ASSOCIATE_MSG_HANDOFF_REQUEST, request.encode().unwrap()[0..]);
+    }
+
     const ASSOCIATE_MSG_RESPONSE: [u8; 12] = [0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
 
     #[test]
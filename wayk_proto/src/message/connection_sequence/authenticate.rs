@@ -7,6 +7,8 @@ use num_derive::FromPrimitive;
 // TODO: check usage of this enum...
 // SRP message types
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum SRPMessageType {
     SRPInitiate = 0x01,
@@ -16,6 +18,8 @@ pub enum SRPMessageType {
 }
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum AuthenticateMessageType {
     Token = 0x01,
@@ -24,6 +28,8 @@ pub enum AuthenticateMessageType {
 }
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum AuthType {
     None = 0x00,
@@ -46,8 +52,11 @@ __flags_struct! {
 // NOW_AUTHENTICATE_MSG
 
 #[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "AuthenticateMessageType"]
 pub enum NowAuthenticateMsg<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
     Token(NowAuthenticateTokenMsg<'a>),
     Success(NowAuthenticateSuccessMsg),
     Failure(NowAuthenticateFailureMsg),
@@ -83,11 +92,15 @@ impl From<NowAuthenticateFailureMsg> for NowAuthenticateMsg<'_> {
 // subtypes
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowAuthenticateTokenMsg<'a> {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: AuthenticateMessageType,
     flags: u8,
     pub auth_type: AuthType,
     auth_flags: u8,
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub token_data: Bytes16<'a>,
 }
 
@@ -107,7 +120,10 @@ impl<'a> NowAuthenticateTokenMsg<'a> {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowAuthenticateTokenMsgOwned {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: AuthenticateMessageType,
     flags: u8,
     pub auth_type: AuthType,
@@ -130,7 +146,10 @@ impl NowAuthenticateTokenMsgOwned {
 }
 
 #[derive(Decode, Encode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowAuthenticateSuccessMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: AuthenticateMessageType,
     flags: u8,
     reserved: u16,
@@ -160,7 +179,10 @@ impl Default for NowAuthenticateSuccessMsg {
 }
 
 #[derive(Decode, Encode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowAuthenticateFailureMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: AuthenticateMessageType,
     pub flags: AuthentificationFailureFlags,
     reserved: u16,
@@ -1,8 +1,8 @@
 use crate::{
     container::Vec8,
     error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt, Result},
-    message::{MouseMode, NowString, NowString64, NowSurfaceListReqMsg, NowSystemOsInfo},
-    serialization::{Decode, Encode},
+    message::{ChannelName, MouseMode, NowString, NowString64, NowSurfaceListReqMsg, NowSystemOsInfo},
+    serialization::{Decode, Encode, FixedSize},
 };
 use byteorder::{LittleEndian, ReadBytesExt};
 use core::{convert::TryFrom, mem};
@@ -16,10 +16,13 @@ __flags_struct! {
         list_req = LIST_REQ = 0x0000_0001,
         select = SELECT = 0x0000_0002,
         multi = MULTI = 0x0000_0004,
+        extended_status = EXTENDED_STATUS = 0x0000_0008,
     }
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct SurfaceCapset {
     pub flags: SurfaceCapsetFlags,
     pub list_req: NowSurfaceListReqMsg,
@@ -36,6 +39,8 @@ impl SurfaceCapset {
 // NOW_UPDATE_CAPSET
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum Codec {
     Unspecified = 0x0000,
@@ -45,6 +50,8 @@ pub enum Codec {
 }
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum QualityMode {
     Unspecified = 0x00,
@@ -54,7 +61,13 @@ pub enum QualityMode {
 }
 
 #[derive(Debug, Clone, Decode, Encode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[fixed_size]
 pub struct NowCodecDef {
+    #[size_field]
+    #[range(min = "Self::ENCODED_SIZE")]
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::ENCODED_SIZE as u16))]
     size: u16,
     pub id: Codec,
     pub flags: u32,
@@ -67,7 +80,7 @@ impl NowCodecDef {
 
     pub fn new_with_flags(codec_id: Codec, flags: u32) -> Self {
         Self {
-            size: mem::size_of::<NowCodecDef>() as u16,
+            size: Self::ENCODED_SIZE as u16,
             id: codec_id,
             flags,
         }
@@ -75,6 +88,8 @@ impl NowCodecDef {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct UpdateCapset {
     flags: u32,
     pub quality_mode: QualityMode,
@@ -113,6 +128,8 @@ impl UpdateCapset {
 // NOW_INPUT_CAPSET
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum InputActionCode {
     SAS = 0x0001,
@@ -133,6 +150,8 @@ __flags_struct! {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowInputActionDef {
     pub code: InputActionCode,
     pub flags: InputActionFlags,
@@ -155,6 +174,8 @@ impl NowInputActionDef {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct InputCapset {
     flags: u32,
     reserved: u32,
@@ -182,6 +203,8 @@ __flags_struct! {
 }
 
 #[derive(Decode, Encode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct MouseCapset {
     pub flags: MouseCapsetFlags,
     pub mode: MouseMode,
@@ -205,6 +228,8 @@ impl MouseCapset {
 // NOW_ACCESS_CAPSET
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum AccessControlCode {
     Viewing = 0x0001,
@@ -224,6 +249,8 @@ __flags_struct! {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct AccessControlDef {
     pub code: AccessControlCode,
     pub flags: AccessFlags,
@@ -248,6 +275,8 @@ impl AccessControlDef {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct AccessCapset {
     flags: u32,
     reserved: u32,
@@ -277,6 +306,8 @@ __flags_struct! {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct LicenseCapset {
     pub flags: LicenseCapsetFlags,
 }
@@ -287,19 +318,132 @@ impl LicenseCapset {
 
 // NOW_TRANSPORT_CAPSET
 
+__flags_struct! {
+    TransportCapsetFlags: u32 => {
+        compression = COMPRESSION = 0x0000_0001,
+    }
+}
+
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct TransportCapset {
-    flags: u32,
+    pub flags: TransportCapsetFlags,
 }
 
 impl Default for TransportCapset {
     fn default() -> Self {
-        TransportCapset { flags: 0 }
+        TransportCapset {
+            flags: TransportCapsetFlags::new_empty(),
+        }
     }
 }
 
 impl TransportCapset {
     const NAME: &'static str = "NowTransport";
+
+    pub fn new(flags: TransportCapsetFlags) -> Self {
+        Self { flags }
+    }
+}
+
+// NOW_NETWORK_CAPSET
+
+__flags_struct! {
+    NetworkCapsetFlags: u32 => {
+        keep_alive = KEEP_ALIVE = 0x0000_0001,
+        probe = PROBE = 0x0000_0002,
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NetworkCapset {
+    pub flags: NetworkCapsetFlags,
+    reserved: u32,
+}
+
+impl NetworkCapset {
+    const NAME: &'static str = "NowNetwork";
+
+    pub fn new(flags: NetworkCapsetFlags) -> Self {
+        Self { flags, reserved: 0 }
+    }
+}
+
+// NOW_DESKTOP_CAPSET
+
+__flags_struct! {
+    DesktopCapsetFlags: u32 => {
+        curtain = CURTAIN = 0x0000_0001,
+        display_power = DISPLAY_POWER = 0x0000_0002,
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct DesktopCapset {
+    pub flags: DesktopCapsetFlags,
+    reserved: u32,
+}
+
+impl DesktopCapset {
+    const NAME: &'static str = "NowDesktop";
+
+    pub fn new(flags: DesktopCapsetFlags) -> Self {
+        Self { flags, reserved: 0 }
+    }
+}
+
+// NOW_CHANNEL_COMPRESSION_CAPSET
+
+/// Compression codecs supported for the messages of a channel, by order of preference.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct ChannelCodecsDef {
+    pub name: ChannelName,
+    pub codecs: Vec8<u8>,
+}
+
+impl ChannelCodecsDef {
+    pub fn new(name: ChannelName, codecs: Vec<u8>) -> Self {
+        Self {
+            name,
+            codecs: Vec8(codecs),
+        }
+    }
+}
+
+/// Channels whose messages may be compressed, independently of the transport compression.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct ChannelCompressionCapset {
+    flags: u32,
+    pub channels: Vec8<ChannelCodecsDef>,
+}
+
+impl ChannelCompressionCapset {
+    const NAME: &'static str = "NowChannelCompression";
+
+    pub fn new(channels: Vec<ChannelCodecsDef>) -> Self {
+        Self {
+            flags: 0,
+            channels: Vec8(channels),
+        }
+    }
+
+    /// Codecs supported for `name`, `None` if its messages aren't to be compressed.
+    pub fn codecs(&self, name: &ChannelName) -> Option<&[u8]> {
+        self.channels
+            .0
+            .iter()
+            .find(|def| &def.name == name)
+            .map(|def| &def.codecs.0[..])
+    }
 }
 
 // NOW_SYSTEM_CAPSET
@@ -311,6 +455,8 @@ __flags_struct! {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct SystemCapset {
     pub flags: SystemCapsetFlags,
     pub os_info: Option<NowSystemOsInfo>,
@@ -347,6 +493,22 @@ impl Encode for SystemCapset {
     }
 }
 
+#[cfg(feature = "schema")]
+impl crate::schema::WireSchema for SystemCapset {
+    fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+        use crate::schema::{FieldSchema, TypeSchema};
+
+        schema.define(stringify!(SystemCapset), |schema| TypeSchema::Struct {
+            size: None,
+            fields: vec![
+                FieldSchema::new("flags", schema.register::<SystemCapsetFlags>()),
+                FieldSchema::new("os_info", schema.register::<NowSystemOsInfo>())
+                    .present_if("flags & SystemCapsetFlags::OS_INFO"),
+            ],
+        })
+    }
+}
+
 impl<'dec: 'a, 'a> Decode<'dec> for SystemCapset {
     fn decode_from(cursor: &mut Cursor<&'dec [u8]>) -> Result<Self> {
         let flags = SystemCapsetFlags::decode_from(cursor)?;
@@ -362,6 +524,8 @@ impl<'dec: 'a, 'a> Decode<'dec> for SystemCapset {
 // unknown capset (not specified)
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct UnknownCapset<'a> {
     // capset struct full size (including size bits and name)
     pub size: u16,
@@ -407,7 +571,10 @@ impl<'a> UnknownCapset<'a> {
 // NOW_CAPABILITIES_MSG
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub enum NowCapset<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
     Unknown(UnknownCapset<'a>),
     Transport(TransportCapset),
     Surface(SurfaceCapset),
@@ -416,8 +583,10 @@ pub enum NowCapset<'a> {
     Update(UpdateCapset),
     Input(InputCapset),
     Mouse(MouseCapset),
-    //TODO: Network(NetworkCapset),
+    Network(NetworkCapset),
+    Desktop(DesktopCapset),
     System(Box<SystemCapset>), // size difference is large...
+    ChannelCompression(ChannelCompressionCapset),
 }
 
 impl NowCapset<'_> {
@@ -431,7 +600,10 @@ impl NowCapset<'_> {
             NowCapset::Update(_) => UpdateCapset::NAME,
             NowCapset::Input(_) => InputCapset::NAME,
             NowCapset::Mouse(_) => MouseCapset::NAME,
+            NowCapset::Network(_) => NetworkCapset::NAME,
+            NowCapset::Desktop(_) => DesktopCapset::NAME,
             NowCapset::System(_) => SystemCapset::NAME,
+            NowCapset::ChannelCompression(_) => ChannelCompressionCapset::NAME,
         }
     }
 }
@@ -468,7 +640,10 @@ impl<'a> Encode for NowCapset<'a> {
             NowCapset::Update(capset) => encoded_len_capset_variant!(capset, UpdateCapset),
             NowCapset::Input(capset) => encoded_len_capset_variant!(capset, InputCapset),
             NowCapset::Mouse(capset) => encoded_len_capset_variant!(capset, MouseCapset),
+            NowCapset::Network(capset) => encoded_len_capset_variant!(capset, NetworkCapset),
+            NowCapset::Desktop(capset) => encoded_len_capset_variant!(capset, DesktopCapset),
             NowCapset::System(capset) => encoded_len_capset_variant!(capset, SystemCapset),
+            NowCapset::ChannelCompression(capset) => encoded_len_capset_variant!(capset, ChannelCompressionCapset),
         }
     }
 
@@ -496,15 +671,71 @@ impl<'a> Encode for NowCapset<'a> {
             NowCapset::Mouse(capset) => {
                 encode_capset_variant! {capset, MouseCapset, writer}
             }
+            NowCapset::Network(capset) => {
+                encode_capset_variant! {capset, NetworkCapset, writer}
+            }
+            NowCapset::Desktop(capset) => {
+                encode_capset_variant! {capset, DesktopCapset, writer}
+            }
             NowCapset::System(capset) => {
                 encode_capset_variant! {capset, SystemCapset, writer}
             }
+            NowCapset::ChannelCompression(capset) => {
+                encode_capset_variant! {capset, ChannelCompressionCapset, writer}
+            }
         }
 
         Ok(())
     }
 }
 
+#[cfg(feature = "schema")]
+impl crate::schema::WireSchema for NowCapset<'_> {
+    fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+        use crate::schema::{FieldSchema, MessageVariantSchema, TypeSchema};
+
+        fn variant<T: crate::schema::WireSchema>(
+            schema: &mut crate::schema::Schema,
+            name: &'static str,
+        ) -> MessageVariantSchema {
+            MessageVariantSchema {
+                name,
+                tag: None,
+                ty: schema.register::<T>(),
+            }
+        }
+
+        schema.define(stringify!(NowCapset), |schema| {
+            // capsets are dispatched on their name rather than on an integer tag
+            let capset = TypeSchema::Message {
+                tag: None,
+                variants: vec![
+                    variant::<TransportCapset>(schema, TransportCapset::NAME),
+                    variant::<SurfaceCapset>(schema, SurfaceCapset::NAME),
+                    variant::<LicenseCapset>(schema, LicenseCapset::NAME),
+                    variant::<AccessCapset>(schema, AccessCapset::NAME),
+                    variant::<UpdateCapset>(schema, UpdateCapset::NAME),
+                    variant::<InputCapset>(schema, InputCapset::NAME),
+                    variant::<MouseCapset>(schema, MouseCapset::NAME),
+                    variant::<NetworkCapset>(schema, NetworkCapset::NAME),
+                    variant::<DesktopCapset>(schema, DesktopCapset::NAME),
+                    variant::<SystemCapset>(schema, SystemCapset::NAME),
+                    variant::<ChannelCompressionCapset>(schema, ChannelCompressionCapset::NAME),
+                ],
+            };
+
+            TypeSchema::Struct {
+                size: None,
+                fields: vec![
+                    FieldSchema::new("size", schema.register::<u16>()).size_field(),
+                    FieldSchema::new("name", schema.register::<NowString64>()),
+                    FieldSchema::new("capset", capset),
+                ],
+            }
+        })
+    }
+}
+
 impl<'dec: 'a, 'a> Decode<'dec> for NowCapset<'a> {
     fn decode_from(cursor: &mut Cursor<&'dec [u8]>) -> Result<Self> {
         let size = u16::decode_from(cursor)?;
@@ -517,7 +748,12 @@ impl<'dec: 'a, 'a> Decode<'dec> for NowCapset<'a> {
             UpdateCapset::NAME => Ok(Self::Update(UpdateCapset::decode_from(cursor)?)),
             InputCapset::NAME => Ok(Self::Input(InputCapset::decode_from(cursor)?)),
             MouseCapset::NAME => Ok(Self::Mouse(MouseCapset::decode_from(cursor)?)),
+            NetworkCapset::NAME => Ok(Self::Network(NetworkCapset::decode_from(cursor)?)),
+            DesktopCapset::NAME => Ok(Self::Desktop(DesktopCapset::decode_from(cursor)?)),
             SystemCapset::NAME => Ok(Self::System(Box::new(SystemCapset::decode_from(cursor)?))),
+            ChannelCompressionCapset::NAME => {
+                Ok(Self::ChannelCompression(ChannelCompressionCapset::decode_from(cursor)?))
+            }
             _ => Ok(Self::Unknown(UnknownCapset {
                 size,
                 name,
@@ -528,8 +764,11 @@ impl<'dec: 'a, 'a> Decode<'dec> for NowCapset<'a> {
 }
 
 #[derive(Encode, Decode, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowCapabilitiesMsg<'a> {
     flags: u32,
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub capabilities: Vec8<NowCapset<'a>>,
 }
 
@@ -12,6 +12,8 @@ use wayk_proto::{
 };
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum ChannelMessageType {
     ChannelListRequest = 0x01,
@@ -43,6 +45,8 @@ __flags_struct! {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowChannelDef {
     pub flags: ChannelDefFlags,
     pub name: ChannelName,
@@ -59,6 +63,7 @@ impl NowChannelDef {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ChannelName {
     Unknown(Cow<'static, str>),
     Clipboard,
@@ -66,6 +71,9 @@ pub enum ChannelName {
     Exec,
     Chat,
     Tunnel,
+    Device,
+    Printer,
+    Drive,
 }
 
 impl Encode for ChannelName {
@@ -77,6 +85,9 @@ impl Encode for ChannelName {
             ChannelName::Exec => Self::EXEC_STR,
             ChannelName::Chat => Self::CHAT_STR,
             ChannelName::Tunnel => Self::TUNNEL_STR,
+            ChannelName::Device => Self::DEVICE_STR,
+            ChannelName::Printer => Self::PRINTER_STR,
+            ChannelName::Drive => Self::DRIVE_STR,
         };
         name.len() + 2
     }
@@ -97,6 +108,9 @@ impl<'dec: 'a, 'a> Decode<'dec> for ChannelName {
             Self::EXEC_STR => Ok(Self::Exec),
             Self::CHAT_STR => Ok(Self::Chat),
             Self::TUNNEL_STR => Ok(Self::Tunnel),
+            Self::DEVICE_STR => Ok(Self::Device),
+            Self::PRINTER_STR => Ok(Self::Printer),
+            Self::DRIVE_STR => Ok(Self::Drive),
             _ => Ok(Self::Unknown(name.into())),
         }
     }
@@ -108,6 +122,31 @@ impl ChannelName {
     pub const EXEC_STR: &'static str = "NowExec";
     pub const CHAT_STR: &'static str = "NowChat";
     pub const TUNNEL_STR: &'static str = "NowTunnel";
+    pub const DEVICE_STR: &'static str = "NowDevice";
+    pub const PRINTER_STR: &'static str = "NowPrinter";
+    pub const DRIVE_STR: &'static str = "NowDrive";
+
+    /// Channel defined by the application, negotiated by name like the standard ones.
+    /// Names of standard channels give the standard channel.
+    pub fn custom<S: Into<Cow<'static, str>>>(name: S) -> Self {
+        let name = name.into();
+        match name.as_ref() {
+            Self::CLIPBOARD_STR => Self::Clipboard,
+            Self::FILE_TRANSFER_STR => Self::FileTransfer,
+            Self::EXEC_STR => Self::Exec,
+            Self::CHAT_STR => Self::Chat,
+            Self::TUNNEL_STR => Self::Tunnel,
+            Self::DEVICE_STR => Self::Device,
+            Self::PRINTER_STR => Self::Printer,
+            Self::DRIVE_STR => Self::Drive,
+            _ => Self::Unknown(name),
+        }
+    }
+
+    /// `true` for channels defined by the application.
+    pub fn is_custom(&self) -> bool {
+        matches!(self, Self::Unknown(_))
+    }
 
     pub fn as_str(&self) -> &str {
         match self {
@@ -117,11 +156,40 @@ impl ChannelName {
             Self::Exec => Self::EXEC_STR,
             Self::Chat => Self::CHAT_STR,
             Self::Tunnel => Self::TUNNEL_STR,
+            Self::Device => Self::DEVICE_STR,
+            Self::Printer => Self::PRINTER_STR,
+            Self::Drive => Self::DRIVE_STR,
         }
     }
 }
 
+#[cfg(feature = "fuzzing")]
+impl<'arb> arbitrary::Arbitrary<'arb> for ChannelName {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'arb>) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=8)? {
+            0 => Self::Unknown(NowString64::arbitrary(u)?.as_str().to_owned().into()),
+            1 => Self::Clipboard,
+            2 => Self::FileTransfer,
+            3 => Self::Exec,
+            4 => Self::Chat,
+            5 => Self::Tunnel,
+            6 => Self::Device,
+            7 => Self::Printer,
+            _ => Self::Drive,
+        })
+    }
+}
+
+#[cfg(feature = "schema")]
+impl wayk_proto::schema::WireSchema for ChannelName {
+    fn describe(schema: &mut wayk_proto::schema::Schema) -> wayk_proto::schema::TypeSchema {
+        schema.register::<NowString64>()
+    }
+}
+
 #[derive(Encode, Decode, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowChannelMsg {
     pub subtype: ChannelMessageType,
     flags: u8,
@@ -2,7 +2,7 @@
 
 use crate::{
     message::status::{HandshakeStatusCode, NowStatus},
-    version::{WAYK_NOW_VERSION_MAJOR, WAYK_NOW_VERSION_MINOR, WAYK_NOW_VERSION_PATCH},
+    version::{NowProtocolVersion, WAYK_NOW_VERSION_MAJOR, WAYK_NOW_VERSION_MINOR, WAYK_NOW_VERSION_PATCH},
 };
 
 __flags_struct! {
@@ -14,6 +14,8 @@ __flags_struct! {
 }
 
 #[derive(Decode, Encode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowHandshakeMsg {
     pub version_major: u8,
     pub version_minor: u8,
@@ -39,6 +41,20 @@ impl NowHandshakeMsg {
         Self::default()
     }
 
+    /// Successful handshake advertising `version`.
+    pub fn new_with_version(version: NowProtocolVersion) -> Self {
+        Self {
+            version_major: version.major,
+            version_minor: version.minor,
+            version_patch: version.patch,
+            ..Self::default()
+        }
+    }
+
+    pub fn version(&self) -> NowProtocolVersion {
+        NowProtocolVersion::new(self.version_major, self.version_minor, self.version_patch)
+    }
+
     pub fn configure_failure(&mut self, status: NowStatus<HandshakeStatusCode>) {
         self.flags.set_failure();
         self.status = status;
@@ -5,10 +5,14 @@ use crate::{container::Vec8, message::AuthType};
 __flags_struct! {
     NegotiateFlags: u32 => {
         srp_extended = SRP_EXTENDED = 0x0000_0001,
+        handoff = HANDOFF = 0x0000_0002,
+        tls = TLS = 0x0000_0004,
     }
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowNegotiateMsg {
     pub flags: NegotiateFlags,
     pub auth_list: Vec8<AuthType>,
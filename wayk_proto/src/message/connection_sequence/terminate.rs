@@ -1,6 +1,8 @@
-use crate::message::status::{DisconnectStatusCode, NowStatus};
+use crate::message::status::{DisconnectStatusCode, NowStatus, SeverityLevel, StatusType};
 
 #[derive(Encode, Decode, Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowTerminateMsg {
     flags: u32,
     pub status: NowStatus<DisconnectStatusCode>,
@@ -16,6 +18,16 @@ impl NowTerminateMsg {
     pub fn new(status: NowStatus<DisconnectStatusCode>) -> Self {
         Self { flags: 0, status }
     }
+
+    /// Disconnect status with `reason` as code.
+    pub fn with_reason(reason: DisconnectStatusCode) -> Self {
+        Self::new(
+            NowStatus::builder(reason)
+                .severity(SeverityLevel::Info)
+                .status_type(StatusType::Disconnect)
+                .build(),
+        )
+    }
 }
 
 #[cfg(test)]
@@ -11,14 +11,20 @@ pub use now_messages::*;
 pub use status::*;
 pub use virtual_channels::*;
 
-use crate::{error::*, serialization::Decode};
-use alloc::collections::BTreeMap;
+use crate::{
+    error::*,
+    serialization::{Decode, DecodeCtx, DecodeLimits, Encode},
+    version::NowProtocolVersion,
+};
+use alloc::{borrow::Cow, collections::BTreeMap};
 use num_derive::FromPrimitive;
 use std::io::Cursor;
 
 // == MESSAGE TYPE == //
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum MessageType {
     Status = 0x00,
@@ -40,13 +46,29 @@ pub enum MessageType {
     System = 0x48,
     Session = 0x49,
     Sharing = 0x50,
+    Batch = 0x51,
+    ChannelWindow = 0x52,
 }
 
 // == VIRTUAL CHANNELS CONTEXT ==
 
+/// How subtypes unknown to this implementation are handled when decoding.
+///
+/// In permissive mode, unknown surface messages are kept as `NowSurfaceMsg::Unknown`
+/// so that newer peers don't break older implementations.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum DecodeMode {
+    #[default]
+    Strict,
+    Permissive,
+}
+
 #[derive(Debug, Clone)]
 pub struct VirtChannelsCtx {
     entries: BTreeMap<u8, ChannelName>,
+    decode_mode: DecodeMode,
+    decode_limits: DecodeLimits,
+    version: NowProtocolVersion,
 }
 
 impl Default for VirtChannelsCtx {
@@ -59,13 +81,58 @@ impl VirtChannelsCtx {
     pub fn new() -> Self {
         Self {
             entries: Default::default(),
+            decode_mode: DecodeMode::default(),
+            decode_limits: DecodeLimits::default(),
+            version: NowProtocolVersion::CURRENT,
         }
     }
 
+    pub fn decode_mode(&self) -> DecodeMode {
+        self.decode_mode
+    }
+
+    pub fn set_decode_mode(&mut self, mode: DecodeMode) {
+        self.decode_mode = mode;
+    }
+
+    pub fn decode_limits(&self) -> DecodeLimits {
+        self.decode_limits
+    }
+
+    pub fn set_decode_limits(&mut self, limits: DecodeLimits) {
+        self.decode_limits = limits;
+    }
+
+    /// Protocol version negotiated for the session.
+    pub fn version(&self) -> NowProtocolVersion {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: NowProtocolVersion) {
+        self.version = version;
+    }
+
+    /// Decoding context for packets received on these channels.
+    pub fn decode_ctx(&self) -> DecodeCtx {
+        let mut ctx = DecodeCtx::new(self.version);
+        ctx.mode = self.decode_mode;
+        ctx.limits = self.decode_limits;
+        ctx
+    }
+
     pub fn insert(&mut self, id: u8, name: ChannelName) -> Option<ChannelName> {
         self.entries.insert(id, name)
     }
 
+    pub fn remove(&mut self, id: u8) -> Option<ChannelName> {
+        self.entries.remove(&id)
+    }
+
+    /// Forgets the opened channels, keeping the decoding settings.
+    pub fn clear_channels(&mut self) {
+        self.entries.clear();
+    }
+
     pub fn get_channel_by_id(&self, id: u8) -> Option<&ChannelName> {
         self.entries.get(&id)
     }
@@ -73,11 +140,18 @@ impl VirtChannelsCtx {
     pub fn get_id_by_channel(&self, name: &ChannelName) -> Option<u8> {
         self.entries.iter().find(|pair| pair.1 == name).map(|pair| *pair.0)
     }
+
+    /// Opened channels, by id.
+    pub fn channels(&self) -> impl Iterator<Item = (u8, &ChannelName)> {
+        self.entries.iter().map(|(id, name)| (*id, name))
+    }
 }
 
 // == BODY TYPE == //
 
 #[derive(Debug, Clone, PartialEq, Copy, Eq, Encode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "None"]
 pub enum BodyType {
     Message(MessageType),
@@ -99,9 +173,13 @@ impl From<u8> for BodyType {
 // == NOW BODY == //
 
 #[derive(Debug, Clone, Encode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "None"]
 pub enum NowBody<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
     Message(NowMessage<'a>),
+    #[cfg_attr(feature = "serde", serde(borrow))]
     VirtualChannel(NowVirtualChannel<'a>),
 }
 
@@ -119,20 +197,77 @@ impl<'a> From<NowVirtualChannel<'a>> for NowBody<'a> {
 
 // == NOW VIRTUAL CHANNEL == //
 
-#[derive(Debug, Clone, Encode)]
+/// Message of a channel defined by the application, such as a proprietary channel registered with
+/// `ChannelName::custom`. The payload is opaque to the protocol: only its bytes go on the wire.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct CustomVirtualChannel<'a> {
     pub name: ChannelName,
-    pub payload: &'a [u8],
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub payload: Cow<'a, [u8]>,
+}
+
+impl<'a> CustomVirtualChannel<'a> {
+    pub fn new<P: Into<Cow<'a, [u8]>>>(name: ChannelName, payload: P) -> Self {
+        Self {
+            name,
+            payload: payload.into(),
+        }
+    }
+
+    pub fn into_owned(self) -> CustomVirtualChannel<'static> {
+        CustomVirtualChannel {
+            name: self.name,
+            payload: Cow::Owned(self.payload.into_owned()),
+        }
+    }
+}
+
+impl Encode for CustomVirtualChannel<'_> {
+    fn encoded_len(&self) -> usize {
+        self.payload.len()
+    }
+
+    fn encode_into<W: std::io::Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.payload)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "schema")]
+impl crate::schema::WireSchema for CustomVirtualChannel<'_> {
+    fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+        use crate::schema::{FieldSchema, TypeSchema};
+
+        schema.define(stringify!(CustomVirtualChannel), |_| TypeSchema::Struct {
+            size: None,
+            fields: vec![FieldSchema::new(
+                "payload",
+                TypeSchema::Opaque {
+                    description: "application defined, up to the end of the packet",
+                },
+            )],
+        })
+    }
 }
 
 #[derive(Debug, Clone, Encode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "None"]
 pub enum NowVirtualChannel<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
     Clipboard(NowClipboardMsg<'a>),
     Chat(NowChatMsg),
     // TODO: Exec(NowExecMsg),
-    // TODO: FileTransfer(NowFileTransferMsg),
-    // TODO: Tunnel(NowTunnelMsg),
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    FileTransfer(NowFileTransferMsg<'a>),
+    Tunnel(NowTunnelMsg),
+    Device(NowDeviceMsg),
+    Printer(NowPrinterMsg),
+    Drive(NowDriveMsg),
+    #[cfg_attr(feature = "serde", serde(borrow))]
     Custom(CustomVirtualChannel<'a>),
 }
 
@@ -141,9 +276,14 @@ impl<'a> NowVirtualChannel<'a> {
         Ok(match channel {
             ChannelName::Clipboard => Self::Clipboard(NowClipboardMsg::decode_from(cursor)?),
             ChannelName::Chat => Self::Chat(NowChatMsg::decode_from(cursor)?),
+            ChannelName::FileTransfer => Self::FileTransfer(NowFileTransferMsg::decode_from(cursor)?),
+            ChannelName::Tunnel => Self::Tunnel(NowTunnelMsg::decode_from(cursor)?),
+            ChannelName::Device => Self::Device(NowDeviceMsg::decode_from(cursor)?),
+            ChannelName::Printer => Self::Printer(NowPrinterMsg::decode_from(cursor)?),
+            ChannelName::Drive => Self::Drive(NowDriveMsg::decode_from(cursor)?),
             _ => Self::Custom(CustomVirtualChannel {
                 name: channel.clone(),
-                payload: &cursor.get_ref()[cursor.position() as usize..],
+                payload: Cow::Borrowed(&cursor.get_ref()[cursor.position() as usize..]),
             }),
         })
     }
@@ -152,6 +292,11 @@ impl<'a> NowVirtualChannel<'a> {
         match self {
             NowVirtualChannel::Clipboard(_) => &ChannelName::Clipboard,
             NowVirtualChannel::Chat(_) => &ChannelName::Chat,
+            NowVirtualChannel::FileTransfer(_) => &ChannelName::FileTransfer,
+            NowVirtualChannel::Tunnel(_) => &ChannelName::Tunnel,
+            NowVirtualChannel::Device(_) => &ChannelName::Device,
+            NowVirtualChannel::Printer(_) => &ChannelName::Printer,
+            NowVirtualChannel::Drive(_) => &ChannelName::Drive,
             NowVirtualChannel::Custom(msg) => &msg.name,
         }
     }
@@ -289,61 +434,360 @@ impl From<NowChatPokeMsg> for NowVirtualChannel<'_> {
     }
 }
 
+impl<'a> From<NowFileTransferMsg<'a>> for NowVirtualChannel<'a> {
+    fn from(msg: NowFileTransferMsg<'a>) -> Self {
+        Self::FileTransfer(msg)
+    }
+}
+
+impl From<NowFileTransferPolicyMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowFileTransferPolicyMsg) -> Self {
+        Self::FileTransfer(NowFileTransferMsg::Policy(msg))
+    }
+}
+
+impl From<NowFileTransferDownloadReqMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowFileTransferDownloadReqMsg) -> Self {
+        Self::FileTransfer(NowFileTransferMsg::DownloadReq(msg))
+    }
+}
+
+impl From<NowFileTransferDownloadRspMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowFileTransferDownloadRspMsg) -> Self {
+        Self::FileTransfer(NowFileTransferMsg::DownloadRsp(msg))
+    }
+}
+
+impl<'a> From<NowFileTransferDataMsg<'a>> for NowVirtualChannel<'a> {
+    fn from(msg: NowFileTransferDataMsg<'a>) -> Self {
+        Self::FileTransfer(NowFileTransferMsg::Data(msg))
+    }
+}
+
+impl From<NowFileTransferAbortMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowFileTransferAbortMsg) -> Self {
+        Self::FileTransfer(NowFileTransferMsg::Abort(msg))
+    }
+}
+
+impl From<NowTunnelMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowTunnelMsg) -> Self {
+        Self::Tunnel(msg)
+    }
+}
+
+impl From<NowTunnelOpenMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowTunnelOpenMsg) -> Self {
+        Self::Tunnel(NowTunnelMsg::Open(msg))
+    }
+}
+
+impl From<NowTunnelDataMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowTunnelDataMsg) -> Self {
+        Self::Tunnel(NowTunnelMsg::Data(msg))
+    }
+}
+
+impl From<NowTunnelCloseMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowTunnelCloseMsg) -> Self {
+        Self::Tunnel(NowTunnelMsg::Close(msg))
+    }
+}
+
+impl From<NowTunnelListenMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowTunnelListenMsg) -> Self {
+        Self::Tunnel(NowTunnelMsg::Listen(msg))
+    }
+}
+
+impl From<NowTunnelListenRspMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowTunnelListenRspMsg) -> Self {
+        Self::Tunnel(NowTunnelMsg::ListenRsp(msg))
+    }
+}
+
+impl From<NowTunnelWindowMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowTunnelWindowMsg) -> Self {
+        Self::Tunnel(NowTunnelMsg::Window(msg))
+    }
+}
+
+impl From<NowDeviceMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowDeviceMsg) -> Self {
+        Self::Device(msg)
+    }
+}
+
+impl From<NowDeviceAnnounceMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowDeviceAnnounceMsg) -> Self {
+        Self::Device(NowDeviceMsg::Announce(msg))
+    }
+}
+
+impl From<NowDeviceAnnounceRspMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowDeviceAnnounceRspMsg) -> Self {
+        Self::Device(NowDeviceMsg::AnnounceRsp(msg))
+    }
+}
+
+impl From<NowDeviceRemoveMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowDeviceRemoveMsg) -> Self {
+        Self::Device(NowDeviceMsg::Remove(msg))
+    }
+}
+
+impl From<NowDeviceIoRequestMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowDeviceIoRequestMsg) -> Self {
+        Self::Device(NowDeviceMsg::IoRequest(msg))
+    }
+}
+
+impl From<NowDeviceIoCompletionMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowDeviceIoCompletionMsg) -> Self {
+        Self::Device(NowDeviceMsg::IoCompletion(msg))
+    }
+}
+
+impl From<NowPrinterMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowPrinterMsg) -> Self {
+        Self::Printer(msg)
+    }
+}
+
+impl From<NowPrinterAnnounceMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowPrinterAnnounceMsg) -> Self {
+        Self::Printer(NowPrinterMsg::Announce(msg))
+    }
+}
+
+impl From<NowPrinterRemoveMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowPrinterRemoveMsg) -> Self {
+        Self::Printer(NowPrinterMsg::Remove(msg))
+    }
+}
+
+impl From<NowPrintJobStartMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowPrintJobStartMsg) -> Self {
+        Self::Printer(NowPrinterMsg::JobStart(msg))
+    }
+}
+
+impl From<NowPrintJobDataMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowPrintJobDataMsg) -> Self {
+        Self::Printer(NowPrinterMsg::JobData(msg))
+    }
+}
+
+impl From<NowPrintJobEndMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowPrintJobEndMsg) -> Self {
+        Self::Printer(NowPrinterMsg::JobEnd(msg))
+    }
+}
+
+impl From<NowPrintJobCancelMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowPrintJobCancelMsg) -> Self {
+        Self::Printer(NowPrinterMsg::JobCancel(msg))
+    }
+}
+
+impl From<NowPrintJobStatusMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowPrintJobStatusMsg) -> Self {
+        Self::Printer(NowPrinterMsg::JobStatus(msg))
+    }
+}
+
+impl From<NowDriveMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowDriveMsg) -> Self {
+        Self::Drive(msg)
+    }
+}
+
+impl From<NowDriveAnnounceMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowDriveAnnounceMsg) -> Self {
+        Self::Drive(NowDriveMsg::Announce(msg))
+    }
+}
+
+impl From<NowDriveRemoveMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowDriveRemoveMsg) -> Self {
+        Self::Drive(NowDriveMsg::Remove(msg))
+    }
+}
+
+impl From<NowDriveRequestMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowDriveRequestMsg) -> Self {
+        Self::Drive(NowDriveMsg::Request(msg))
+    }
+}
+
+impl From<NowDriveResponseMsg> for NowVirtualChannel<'_> {
+    fn from(msg: NowDriveResponseMsg) -> Self {
+        Self::Drive(NowDriveMsg::Response(msg))
+    }
+}
+
 impl<'a> From<CustomVirtualChannel<'a>> for NowVirtualChannel<'a> {
     fn from(msg: CustomVirtualChannel<'a>) -> Self {
         Self::Custom(msg)
     }
 }
 
+/// Message enum of a given virtual channel.
+pub trait ChannelMessage<'a>: Into<NowVirtualChannel<'a>> {
+    /// Channel specific message, `None` if `chan_msg` belongs to another kind of channel.
+    fn from_virt_channel<'b>(chan_msg: &'b NowVirtualChannel<'a>) -> Option<&'b Self>;
+}
+
+impl<'a> ChannelMessage<'a> for NowClipboardMsg<'a> {
+    fn from_virt_channel<'b>(chan_msg: &'b NowVirtualChannel<'a>) -> Option<&'b Self> {
+        match chan_msg {
+            NowVirtualChannel::Clipboard(msg) => Some(msg),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> ChannelMessage<'a> for NowChatMsg {
+    fn from_virt_channel<'b>(chan_msg: &'b NowVirtualChannel<'a>) -> Option<&'b Self> {
+        match chan_msg {
+            NowVirtualChannel::Chat(msg) => Some(msg),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> ChannelMessage<'a> for NowFileTransferMsg<'a> {
+    fn from_virt_channel<'b>(chan_msg: &'b NowVirtualChannel<'a>) -> Option<&'b Self> {
+        match chan_msg {
+            NowVirtualChannel::FileTransfer(msg) => Some(msg),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> ChannelMessage<'a> for NowTunnelMsg {
+    fn from_virt_channel<'b>(chan_msg: &'b NowVirtualChannel<'a>) -> Option<&'b Self> {
+        match chan_msg {
+            NowVirtualChannel::Tunnel(msg) => Some(msg),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> ChannelMessage<'a> for NowDeviceMsg {
+    fn from_virt_channel<'b>(chan_msg: &'b NowVirtualChannel<'a>) -> Option<&'b Self> {
+        match chan_msg {
+            NowVirtualChannel::Device(msg) => Some(msg),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> ChannelMessage<'a> for NowPrinterMsg {
+    fn from_virt_channel<'b>(chan_msg: &'b NowVirtualChannel<'a>) -> Option<&'b Self> {
+        match chan_msg {
+            NowVirtualChannel::Printer(msg) => Some(msg),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> ChannelMessage<'a> for NowDriveMsg {
+    fn from_virt_channel<'b>(chan_msg: &'b NowVirtualChannel<'a>) -> Option<&'b Self> {
+        match chan_msg {
+            NowVirtualChannel::Drive(msg) => Some(msg),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> ChannelMessage<'a> for CustomVirtualChannel<'a> {
+    fn from_virt_channel<'b>(chan_msg: &'b NowVirtualChannel<'a>) -> Option<&'b Self> {
+        match chan_msg {
+            NowVirtualChannel::Custom(msg) => Some(msg),
+            _ => None,
+        }
+    }
+}
+
 // == NOW MESSAGE == //
 
 #[derive(Debug, Clone, Encode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "None"]
 pub enum NowMessage<'a> {
     Handshake(NowHandshakeMsg),
     Negotiate(NowNegotiateMsg),
+    #[cfg_attr(feature = "serde", serde(borrow))]
     Authenticate(NowAuthenticateMsg<'a>),
     Associate(NowAssociateMsg),
+    #[cfg_attr(feature = "serde", serde(borrow))]
     Capabilities(NowCapabilitiesMsg<'a>),
     Channel(NowChannelMsg),
     Activate(NowActivateMsg),
     Terminate(NowTerminateMsg),
-    Input(NowInputMsg),
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    Input(NowInputMsg<'a>),
     Surface(NowSurfaceMsg),
+    #[cfg_attr(feature = "serde", serde(borrow))]
     Update(NowUpdateMsg<'a>),
     System(NowSystemMsg),
     Sharing(NowSharingMsg),
+    Network(NowNetworkMsg),
+    Desktop(NowDesktopMsg),
+    Session(NowSessionMsg),
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    Batch(NowBatchMsg<'a>),
+    ChannelWindow(NowChannelWindowMsg),
 }
 
 impl<'a> NowMessage<'a> {
     pub fn decode_from<'dec: 'a>(msg_type: MessageType, cursor: &mut Cursor<&'dec [u8]>) -> Result<Self> {
+        Self::decode_with_mode(msg_type, cursor, DecodeMode::Strict)
+    }
+
+    pub fn decode_with_mode<'dec: 'a>(
+        msg_type: MessageType,
+        cursor: &mut Cursor<&'dec [u8]>,
+        mode: DecodeMode,
+    ) -> Result<Self> {
+        Self::decode_with_ctx(msg_type, cursor, &DecodeCtx::with_mode(mode))
+    }
+
+    pub fn decode_with_ctx<'dec: 'a>(
+        msg_type: MessageType,
+        cursor: &mut Cursor<&'dec [u8]>,
+        ctx: &DecodeCtx,
+    ) -> Result<Self> {
+        ctx.limits.check_message_size(cursor, "NowMessage")?;
+
         Ok(match msg_type {
-            MessageType::Handshake => Self::Handshake(NowHandshakeMsg::decode_from(cursor)?),
-            MessageType::Negotiate => Self::Negotiate(NowNegotiateMsg::decode_from(cursor)?),
-            MessageType::Authenticate => Self::Authenticate(NowAuthenticateMsg::decode_from(cursor)?),
-            MessageType::Associate => Self::Associate(NowAssociateMsg::decode_from(cursor)?),
-            MessageType::Capabilities => Self::Capabilities(NowCapabilitiesMsg::decode_from(cursor)?),
-            MessageType::Channel => Self::Channel(NowChannelMsg::decode_from(cursor)?),
-            MessageType::Activate => Self::Activate(NowActivateMsg::decode_from(cursor)?),
-            MessageType::Terminate => Self::Terminate(NowTerminateMsg::decode_from(cursor)?),
-            MessageType::Surface => Self::Surface(NowSurfaceMsg::decode_from(cursor)?),
-            MessageType::Update => Self::Update(NowUpdateMsg::decode_from(cursor)?),
-            MessageType::System => Self::System(NowSystemMsg::decode_from(cursor)?),
-            MessageType::Input => Self::Input(NowInputMsg::decode_from(cursor)?),
-            MessageType::Sharing => Self::Sharing(NowSharingMsg::decode_from(cursor)?),
+            MessageType::Handshake => Self::Handshake(NowHandshakeMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Negotiate => Self::Negotiate(NowNegotiateMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Authenticate => Self::Authenticate(NowAuthenticateMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Associate => Self::Associate(NowAssociateMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Capabilities => Self::Capabilities(NowCapabilitiesMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Channel => Self::Channel(NowChannelMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Activate => Self::Activate(NowActivateMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Terminate => Self::Terminate(NowTerminateMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Surface => Self::Surface(NowSurfaceMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Update => Self::Update(NowUpdateMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::System => Self::System(NowSystemMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Input => Self::Input(NowInputMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Sharing => Self::Sharing(NowSharingMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Network => Self::Network(NowNetworkMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Desktop => Self::Desktop(NowDesktopMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Session => Self::Session(NowSessionMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::Batch => Self::Batch(NowBatchMsg::decode_from_ctx(cursor, ctx)?),
+            MessageType::ChannelWindow => Self::ChannelWindow(NowChannelWindowMsg::decode_from_ctx(cursor, ctx)?),
 
             MessageType::Status => ProtoError::new(ProtoErrorKind::Decoding("NowMessage"))
                 .or_desc("Status message type not yet supported")?,
             MessageType::Mouse => ProtoError::new(ProtoErrorKind::Decoding("NowMessage"))
                 .or_desc("Mouse message type not yet supported")?,
-            MessageType::Network => ProtoError::new(ProtoErrorKind::Decoding("NowMessage"))
-                .or_desc("Network message type not yet supported")?,
             MessageType::Access => ProtoError::new(ProtoErrorKind::Decoding("NowMessage"))
                 .or_desc("Access message type not yet supported")?,
-            MessageType::Desktop => ProtoError::new(ProtoErrorKind::Decoding("NowMessage"))
-                .or_desc("Desktop message type not yet supported")?,
-            MessageType::Session => ProtoError::new(ProtoErrorKind::Decoding("NowMessage"))
-                .or_desc("Session message type not yet supported")?,
         })
     }
 
@@ -362,6 +806,11 @@ impl<'a> NowMessage<'a> {
             NowMessage::Update(_) => MessageType::Update,
             NowMessage::System(_) => MessageType::System,
             NowMessage::Sharing(_) => MessageType::Sharing,
+            NowMessage::Network(_) => MessageType::Network,
+            NowMessage::Desktop(_) => MessageType::Desktop,
+            NowMessage::Session(_) => MessageType::Session,
+            NowMessage::Batch(_) => MessageType::Batch,
+            NowMessage::ChannelWindow(_) => MessageType::ChannelWindow,
         }
     }
 }
@@ -414,8 +863,8 @@ impl From<NowTerminateMsg> for NowMessage<'_> {
     }
 }
 
-impl From<NowInputMsg> for NowMessage<'_> {
-    fn from(msg: NowInputMsg) -> Self {
+impl<'a> From<NowInputMsg<'a>> for NowMessage<'a> {
+    fn from(msg: NowInputMsg<'a>) -> Self {
         Self::Input(msg)
     }
 }
@@ -443,3 +892,33 @@ impl From<NowSharingMsg> for NowMessage<'_> {
         Self::Sharing(msg)
     }
 }
+
+impl From<NowNetworkMsg> for NowMessage<'_> {
+    fn from(msg: NowNetworkMsg) -> Self {
+        Self::Network(msg)
+    }
+}
+
+impl From<NowDesktopMsg> for NowMessage<'_> {
+    fn from(msg: NowDesktopMsg) -> Self {
+        Self::Desktop(msg)
+    }
+}
+
+impl From<NowSessionMsg> for NowMessage<'_> {
+    fn from(msg: NowSessionMsg) -> Self {
+        Self::Session(msg)
+    }
+}
+
+impl From<NowChannelWindowMsg> for NowMessage<'_> {
+    fn from(msg: NowChannelWindowMsg) -> Self {
+        Self::ChannelWindow(msg)
+    }
+}
+
+impl<'a> From<NowBatchMsg<'a>> for NowMessage<'a> {
+    fn from(msg: NowBatchMsg<'a>) -> Self {
+        Self::Batch(msg)
+    }
+}
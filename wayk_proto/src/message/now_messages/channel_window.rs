@@ -0,0 +1,46 @@
+// NOW_CHANNEL_WINDOW_MSG
+
+use crate::message::ChannelName;
+
+/// Credits granted to the peer for sending over a channel under flow control (see `ChannelFlowControl`).
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowChannelWindowMsg {
+    pub name: ChannelName,
+    /// Bytes the peer may send in addition to the credits granted before.
+    pub credits: u32,
+}
+
+impl NowChannelWindowMsg {
+    pub const REQUIRED_SIZE: usize = 6;
+
+    pub fn new(name: ChannelName, credits: u32) -> Self {
+        Self { name, credits }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Decode, Encode};
+
+    #[rustfmt::skip]
+    const CHANNEL_WINDOW_MSG: [u8; 13] = [
+        0x07, 0x4e, 0x6f, 0x77, 0x43, 0x68, 0x61, 0x74, 0x00, // name
+        0x00, 0x10, 0x00, 0x00, // credits
+    ];
+
+    #[test]
+    fn decoding() {
+        let msg = NowChannelWindowMsg::decode(&CHANNEL_WINDOW_MSG).unwrap();
+        assert_eq!(msg.name, ChannelName::Chat);
+        assert_eq!(msg.credits, 4096);
+    }
+
+    #[test]
+    fn encoding() {
+        let msg = NowChannelWindowMsg::new(ChannelName::Chat, 4096);
+        assert_eq!(msg.encode().unwrap(), CHANNEL_WINDOW_MSG.to_vec());
+    }
+}
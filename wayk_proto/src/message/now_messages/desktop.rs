@@ -0,0 +1,314 @@
+// NOW_DESKTOP_MSG
+
+use num_derive::FromPrimitive;
+
+#[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum DesktopMessageType {
+    CurtainReq = 0x01,
+    CurtainRsp = 0x02,
+    DisplayPowerReq = 0x03,
+    DisplayPowerRsp = 0x04,
+}
+
+#[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[meta_enum = "DesktopMessageType"]
+pub enum NowDesktopMsg {
+    CurtainReq(NowDesktopCurtainReqMsg),
+    CurtainRsp(NowDesktopCurtainRspMsg),
+    DisplayPowerReq(NowDesktopDisplayPowerReqMsg),
+    DisplayPowerRsp(NowDesktopDisplayPowerRspMsg),
+}
+
+impl From<NowDesktopCurtainReqMsg> for NowDesktopMsg {
+    fn from(msg: NowDesktopCurtainReqMsg) -> Self {
+        Self::CurtainReq(msg)
+    }
+}
+
+impl From<NowDesktopCurtainRspMsg> for NowDesktopMsg {
+    fn from(msg: NowDesktopCurtainRspMsg) -> Self {
+        Self::CurtainRsp(msg)
+    }
+}
+
+impl From<NowDesktopDisplayPowerReqMsg> for NowDesktopMsg {
+    fn from(msg: NowDesktopDisplayPowerReqMsg) -> Self {
+        Self::DisplayPowerReq(msg)
+    }
+}
+
+impl From<NowDesktopDisplayPowerRspMsg> for NowDesktopMsg {
+    fn from(msg: NowDesktopDisplayPowerRspMsg) -> Self {
+        Self::DisplayPowerRsp(msg)
+    }
+}
+
+// subtypes
+
+__flags_struct! {
+    CurtainFlags: u8 => {
+        block_input = BLOCK_INPUT = 0x01,
+        blank_display = BLANK_DISPLAY = 0x02,
+    }
+}
+
+impl CurtainFlags {
+    pub fn is_empty(self) -> bool {
+        self.value == 0
+    }
+
+    pub fn contains(self, other: CurtainFlags) -> bool {
+        self.value & other.value == other.value
+    }
+
+    pub fn union(self, other: CurtainFlags) -> Self {
+        Self::from(self.value | other.value)
+    }
+
+    pub fn difference(self, other: CurtainFlags) -> Self {
+        Self::from(self.value & !other.value)
+    }
+}
+
+/// Request to block the physical input devices and/or to blank the physical display of the host.
+///
+/// A request with no flag set releases everything.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDesktopCurtainReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DesktopMessageType,
+    pub flags: CurtainFlags,
+}
+
+impl NowDesktopCurtainReqMsg {
+    pub const SUBTYPE: DesktopMessageType = DesktopMessageType::CurtainReq;
+    pub const REQUIRED_SIZE: usize = 2;
+
+    pub fn new(flags: CurtainFlags) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags,
+        }
+    }
+
+    pub fn block_input() -> Self {
+        Self::new(CurtainFlags::new_empty().set_block_input())
+    }
+
+    pub fn blank_display() -> Self {
+        Self::new(CurtainFlags::new_empty().set_blank_display())
+    }
+
+    pub fn block_input_and_blank_display() -> Self {
+        Self::new(CurtainFlags::new_empty().set_block_input().set_blank_display())
+    }
+
+    pub fn release() -> Self {
+        Self::new(CurtainFlags::new_empty())
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDesktopCurtainRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DesktopMessageType,
+    /// Flags of the request being answered.
+    pub flags: CurtainFlags,
+    /// Items currently applied by the host.
+    pub applied: CurtainFlags,
+    reserved: u8,
+}
+
+impl NowDesktopCurtainRspMsg {
+    pub const SUBTYPE: DesktopMessageType = DesktopMessageType::CurtainRsp;
+    pub const REQUIRED_SIZE: usize = 4;
+
+    pub fn new(flags: CurtainFlags, applied: CurtainFlags) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags,
+            applied,
+            reserved: 0,
+        }
+    }
+
+    /// Requested items the host refused to apply.
+    pub fn refused(&self) -> CurtainFlags {
+        self.flags.difference(self.applied)
+    }
+}
+
+__flags_struct! {
+    DisplayPowerFlags: u8 => {
+        wake_display = WAKE_DISPLAY = 0x01,
+        inhibit_sleep = INHIBIT_SLEEP = 0x02,
+        inhibit_screensaver = INHIBIT_SCREENSAVER = 0x04,
+    }
+}
+
+impl DisplayPowerFlags {
+    /// Items staying in effect until explicitly allowed again (waking the display is a one-shot action).
+    pub const INHIBIT_MASK: u8 = Self::INHIBIT_SLEEP | Self::INHIBIT_SCREENSAVER;
+
+    pub fn is_empty(self) -> bool {
+        self.value == 0
+    }
+
+    pub fn inhibitions(self) -> Self {
+        Self::from(self.value & Self::INHIBIT_MASK)
+    }
+
+    pub fn union(self, other: DisplayPowerFlags) -> Self {
+        Self::from(self.value | other.value)
+    }
+
+    pub fn intersection(self, other: DisplayPowerFlags) -> Self {
+        Self::from(self.value & other.value)
+    }
+
+    pub fn difference(self, other: DisplayPowerFlags) -> Self {
+        Self::from(self.value & !other.value)
+    }
+}
+
+/// Request to wake the physical display of the host and/or to inhibit sleep and screensaver.
+///
+/// Inhibitions not present in the request are allowed again.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDesktopDisplayPowerReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DesktopMessageType,
+    pub flags: DisplayPowerFlags,
+}
+
+impl NowDesktopDisplayPowerReqMsg {
+    pub const SUBTYPE: DesktopMessageType = DesktopMessageType::DisplayPowerReq;
+    pub const REQUIRED_SIZE: usize = 2;
+
+    pub fn new(flags: DisplayPowerFlags) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags,
+        }
+    }
+
+    pub fn wake_display() -> Self {
+        Self::new(DisplayPowerFlags::new_empty().set_wake_display())
+    }
+
+    /// Inhibits both sleep and screensaver for the session duration.
+    pub fn inhibit() -> Self {
+        Self::new(DisplayPowerFlags::from(DisplayPowerFlags::INHIBIT_MASK))
+    }
+
+    pub fn allow() -> Self {
+        Self::new(DisplayPowerFlags::new_empty())
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDesktopDisplayPowerRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DesktopMessageType,
+    /// Flags of the request being answered.
+    pub flags: DisplayPowerFlags,
+    /// What the host actually did: display woken and inhibitions currently in effect.
+    pub applied: DisplayPowerFlags,
+    reserved: u8,
+}
+
+impl NowDesktopDisplayPowerRspMsg {
+    pub const SUBTYPE: DesktopMessageType = DesktopMessageType::DisplayPowerRsp;
+    pub const REQUIRED_SIZE: usize = 4;
+
+    pub fn new(flags: DisplayPowerFlags, applied: DisplayPowerFlags) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags,
+            applied,
+            reserved: 0,
+        }
+    }
+
+    /// Requested items the host didn't apply.
+    pub fn refused(&self) -> DisplayPowerFlags {
+        self.flags.difference(self.applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Decode, Encode};
+
+    #[rustfmt::skip]
+    const CURTAIN_RSP_MSG: [u8; 4] = [
+        0x02, // subtype
+        0x03, // flags
+        0x01, // applied
+        0x00, // reserved
+    ];
+
+    #[test]
+    fn curtain_rsp_decoding() {
+        let msg = NowDesktopMsg::decode(&CURTAIN_RSP_MSG).unwrap();
+        if let NowDesktopMsg::CurtainRsp(msg) = msg {
+            assert!(msg.applied.block_input());
+            assert!(!msg.applied.blank_display());
+            assert_eq!(msg.refused(), CurtainFlags::BLANK_DISPLAY);
+        } else {
+            panic!("expected a curtain rsp message and got {:?}", msg);
+        }
+    }
+
+    #[test]
+    fn curtain_rsp_encoding() {
+        let msg = NowDesktopMsg::from(NowDesktopCurtainRspMsg::new(
+            CurtainFlags::new_empty().set_block_input().set_blank_display(),
+            CurtainFlags::new_empty().set_block_input(),
+        ));
+        assert_eq!(msg.encode().unwrap(), CURTAIN_RSP_MSG.to_vec());
+    }
+
+    #[rustfmt::skip]
+    const DISPLAY_POWER_REQ_MSG: [u8; 2] = [
+        0x03, // subtype
+        0x07, // flags
+    ];
+
+    #[test]
+    fn display_power_req_decoding() {
+        let msg = NowDesktopMsg::decode(&DISPLAY_POWER_REQ_MSG).unwrap();
+        if let NowDesktopMsg::DisplayPowerReq(msg) = msg {
+            assert!(msg.flags.wake_display());
+            assert!(msg.flags.inhibit_sleep());
+            assert!(msg.flags.inhibit_screensaver());
+        } else {
+            panic!("expected a display power req message and got {:?}", msg);
+        }
+    }
+
+    #[test]
+    fn display_power_req_encoding() {
+        let msg = NowDesktopMsg::from(NowDesktopDisplayPowerReqMsg::new(
+            DisplayPowerFlags::new_empty()
+                .set_wake_display()
+                .set_inhibit_sleep()
+                .set_inhibit_screensaver(),
+        ));
+        assert_eq!(msg.encode().unwrap(), DISPLAY_POWER_REQ_MSG.to_vec());
+    }
+}
@@ -10,6 +10,8 @@ use wayk_proto::{
 };
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum InputMessageType {
     Mouse = 0x01,
@@ -21,6 +23,8 @@ pub enum InputMessageType {
 }
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum EventMouseFlags {
     None = 0x0,
@@ -32,7 +36,10 @@ pub enum EventMouseFlags {
 }
 
 #[derive(Encode, Decode, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowInputEventMouse {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = InputMessageType::Mouse))]
     subtype: InputMessageType,
     pub flags: EventMouseFlags,
     pub x: i16,
@@ -51,7 +58,10 @@ impl NowInputEventMouse {
 }
 
 #[derive(Encode, Decode, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowInputEventScroll {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = InputMessageType::Scroll))]
     subtype: InputMessageType,
     flags: u8,
     pub x: i16,
@@ -70,7 +80,10 @@ impl NowInputEventScroll {
 }
 
 #[derive(Encode, Decode, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowInputEventKeyboard {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = InputMessageType::Keyboard))]
     subtype: InputMessageType,
     pub flags: u8,
     pub code: u16,
@@ -87,12 +100,13 @@ impl NowInputEventKeyboard {
 }
 
 #[derive(Clone, Debug)]
-pub struct NowInputEventUnicode {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NowInputEventUnicode<'a> {
     subtype: InputMessageType,
-    pub code: Vec<u8>,
+    pub code: &'a [u8],
 }
 
-impl<'a> Encode for NowInputEventUnicode {
+impl Encode for NowInputEventUnicode<'_> {
     fn encoded_len(&self) -> usize {
         mem::size_of::<u8>() + mem::size_of::<u8>() + self.code.len()
     }
@@ -101,12 +115,12 @@ impl<'a> Encode for NowInputEventUnicode {
         self.subtype.encode_into(writer)?;
         let flags = (self.code.len() as u8 - 1) << 6;
         flags.encode_into(writer)?;
-        writer.write_all(&self.code)?;
+        writer.write_all(self.code)?;
         Ok(())
     }
 }
 
-impl<'dec: 'a, 'a> Decode<'dec> for NowInputEventUnicode {
+impl<'dec: 'a, 'a> Decode<'dec> for NowInputEventUnicode<'a> {
     fn decode_from(cursor: &mut Cursor<&'dec [u8]>) -> Result<Self> {
         let _subtype = cursor.read_u8()?;
         let flags = cursor.read_u8()?;
@@ -116,16 +130,18 @@ impl<'dec: 'a, 'a> Decode<'dec> for NowInputEventUnicode {
         let code_size = (flags >> 6) + 1;
         let end_exclusive = start_inclusive + code_size as usize;
 
-        const SUBTYPE_FLAGS_BYTES: usize = 2;
-        let bytes_left = (cursor.get_ref().len() - start_inclusive + SUBTYPE_FLAGS_BYTES) as usize;
-
-        let code = if bytes_left == end_exclusive {
-            cursor.get_ref()[start_inclusive..end_exclusive].to_vec()
-        } else {
-            return ProtoError::new(ProtoErrorKind::Decoding(
-                "NowInputEventUnicode: bytes_left != end_exclusive",
-            ));
-        };
+        let bytes: &'dec [u8] = cursor.get_ref();
+        if bytes.len() < end_exclusive {
+            return ProtoError::new(ProtoErrorKind::Decoding(stringify!(NowInputEventUnicode))).or_else_desc(|| {
+                format!(
+                    "code size ({}) greater than available bytes ({})",
+                    code_size,
+                    bytes.len() - start_inclusive
+                )
+            });
+        }
+        let code = &bytes[start_inclusive..end_exclusive];
+        cursor.set_position(end_exclusive as u64);
 
         Ok(NowInputEventUnicode {
             subtype: InputMessageType::Unicode,
@@ -134,8 +150,8 @@ impl<'dec: 'a, 'a> Decode<'dec> for NowInputEventUnicode {
     }
 }
 
-impl NowInputEventUnicode {
-    pub fn new(code: Vec<u8>) -> Self {
+impl<'a> NowInputEventUnicode<'a> {
+    pub fn new(code: &'a [u8]) -> Self {
         Self {
             subtype: InputMessageType::Unicode,
             code,
@@ -143,7 +159,38 @@ impl NowInputEventUnicode {
     }
 }
 
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for NowInputEventUnicode<'a> {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let len = u.int_in_range(1..=4)?;
+        Ok(Self::new(u.bytes(len)?))
+    }
+}
+
+#[cfg(feature = "schema")]
+impl crate::schema::WireSchema for NowInputEventUnicode<'_> {
+    fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+        use crate::schema::{FieldSchema, TypeSchema};
+
+        schema.define(stringify!(NowInputEventUnicode), |schema| TypeSchema::Struct {
+            size: None,
+            fields: vec![
+                FieldSchema::new("subtype", schema.register::<InputMessageType>()),
+                FieldSchema::new("flags", schema.register::<u8>()),
+                FieldSchema::new(
+                    "code",
+                    TypeSchema::Opaque {
+                        description: "utf8 code point, (flags >> 6) + 1 bytes",
+                    },
+                ),
+            ],
+        })
+    }
+}
+
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum ToggleEventKeys {
     ScrollLock = 0x0001,
@@ -153,7 +200,10 @@ pub enum ToggleEventKeys {
 }
 
 #[derive(Encode, Decode, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowInputEventToggle {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = InputMessageType::Toggle))]
     subtype: InputMessageType,
     flags: u8,
     pub code: u16,
@@ -170,7 +220,10 @@ impl NowInputEventToggle {
 }
 
 #[derive(Encode, Decode, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowInputEventAction {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = InputMessageType::Action))]
     subtype: InputMessageType,
     flags: u8,
     pub code: InputActionCode,
@@ -187,27 +240,37 @@ impl NowInputEventAction {
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "InputMessageType"]
-pub enum InputEvent {
+pub enum InputEvent<'a> {
     Mouse(NowInputEventMouse),
     Scroll(NowInputEventScroll),
     Keyboard(NowInputEventKeyboard),
-    Unicode(NowInputEventUnicode),
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    Unicode(NowInputEventUnicode<'a>),
     Toggle(NowInputEventToggle),
     Action(NowInputEventAction),
 }
 
 #[derive(Encode, Decode, Clone, Debug)]
-pub struct NowInputMsg {
-    input_event: Vec16<InputEvent>,
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowInputMsg<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    input_event: Vec16<InputEvent<'a>>,
 }
 
-impl NowInputMsg {
-    pub fn new_with_events(input_event: Vec<InputEvent>) -> Self {
+impl<'a> NowInputMsg<'a> {
+    pub fn new_with_events(input_event: Vec<InputEvent<'a>>) -> Self {
         Self {
             input_event: Vec16(input_event),
         }
     }
+
+    pub fn events(&self) -> &[InputEvent<'a>] {
+        &self.input_event.0
+    }
 }
 
 #[cfg(test)]
@@ -329,7 +392,7 @@ mod tests {
 
     #[test]
     fn input_event_unicode_encode() {
-        let unicode_events = vec![InputEvent::Unicode(NowInputEventUnicode::new(vec![
+        let unicode_events = vec![InputEvent::Unicode(NowInputEventUnicode::new(&[
             0xe4, 0x05, 0x77, 0x02,
         ]))];
 
@@ -342,9 +405,29 @@ mod tests {
         let unicode_event = InputEvent::decode(&UNICODE_EVENT_FULL_PACKET[6..]).unwrap();
         if let InputEvent::Unicode(unicode_event) = unicode_event {
             assert_eq!(unicode_event.subtype, InputMessageType::Unicode);
-            assert_eq!(unicode_event.code, vec![0xe4, 0x05, 0x77, 0x02]);
+            assert_eq!(unicode_event.code, &[0xe4, 0x05, 0x77, 0x02]);
         } else {
             panic!("didnt decode unicode message")
         }
     }
+
+    #[test]
+    fn input_event_unicode_followed_by_event_decode() {
+        let events = vec![
+            InputEvent::Unicode(NowInputEventUnicode::new("é".as_bytes())),
+            InputEvent::Toggle(NowInputEventToggle::new_with_code(ToggleEventKeys::CapsLock as u16)),
+        ];
+        let encoded = NowInputMsg::new_with_events(events).encode().unwrap();
+
+        let msg = NowInputMsg::decode(&encoded).unwrap();
+        match msg.events() {
+            [InputEvent::Unicode(unicode_event), InputEvent::Toggle(toggle_event)] => {
+                // borrowed from the received bytes
+                assert!(encoded.as_ptr_range().contains(&unicode_event.code.as_ptr()));
+                assert_eq!(unicode_event.code, "é".as_bytes());
+                assert_eq!(toggle_event.code, ToggleEventKeys::CapsLock as u16);
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+    }
 }
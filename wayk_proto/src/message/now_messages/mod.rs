@@ -1,15 +1,25 @@
 // ****** Now Messages ****** //
 
+pub mod batch;
+pub mod channel_window;
+pub mod desktop;
 pub mod input;
 pub mod mouse;
+pub mod network;
+pub mod session;
 pub mod sharing;
 pub mod surface;
 pub mod system;
 pub mod update;
 
 // re-export
+pub use batch::*;
+pub use channel_window::*;
+pub use desktop::*;
 pub use input::*;
 pub use mouse::*;
+pub use network::*;
+pub use session::*;
 pub use sharing::*;
 pub use surface::*;
 pub use system::*;
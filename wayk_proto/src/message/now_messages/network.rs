@@ -0,0 +1,194 @@
+// NOW_NETWORK_MSG
+
+use num_derive::FromPrimitive;
+
+#[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum NetworkMessageType {
+    KeepAliveReq = 0x01,
+    KeepAliveRsp = 0x02,
+    ProbeReq = 0x03,
+    ProbeRsp = 0x04,
+}
+
+#[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[meta_enum = "NetworkMessageType"]
+pub enum NowNetworkMsg {
+    KeepAliveReq(NowNetworkKeepAliveReqMsg),
+    KeepAliveRsp(NowNetworkKeepAliveRspMsg),
+    ProbeReq(NowNetworkProbeReqMsg),
+    ProbeRsp(NowNetworkProbeRspMsg),
+}
+
+impl From<NowNetworkKeepAliveReqMsg> for NowNetworkMsg {
+    fn from(msg: NowNetworkKeepAliveReqMsg) -> Self {
+        Self::KeepAliveReq(msg)
+    }
+}
+
+impl From<NowNetworkKeepAliveRspMsg> for NowNetworkMsg {
+    fn from(msg: NowNetworkKeepAliveRspMsg) -> Self {
+        Self::KeepAliveRsp(msg)
+    }
+}
+
+impl From<NowNetworkProbeReqMsg> for NowNetworkMsg {
+    fn from(msg: NowNetworkProbeReqMsg) -> Self {
+        Self::ProbeReq(msg)
+    }
+}
+
+impl From<NowNetworkProbeRspMsg> for NowNetworkMsg {
+    fn from(msg: NowNetworkProbeRspMsg) -> Self {
+        Self::ProbeRsp(msg)
+    }
+}
+
+// subtypes
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowNetworkKeepAliveReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: NetworkMessageType,
+    flags: u8,
+    pub sequence_id: u16,
+    pub timestamp: u32,
+}
+
+impl NowNetworkKeepAliveReqMsg {
+    pub const SUBTYPE: NetworkMessageType = NetworkMessageType::KeepAliveReq;
+    pub const REQUIRED_SIZE: usize = 8;
+
+    pub fn new(sequence_id: u16, timestamp: u32) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            sequence_id,
+            timestamp,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowNetworkKeepAliveRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: NetworkMessageType,
+    flags: u8,
+    pub sequence_id: u16,
+    /// Timestamp echoed back from the request.
+    pub timestamp: u32,
+}
+
+impl NowNetworkKeepAliveRspMsg {
+    pub const SUBTYPE: NetworkMessageType = NetworkMessageType::KeepAliveRsp;
+    pub const REQUIRED_SIZE: usize = 8;
+
+    pub fn new(sequence_id: u16, timestamp: u32) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            sequence_id,
+            timestamp,
+        }
+    }
+
+    pub fn answer(req: &NowNetworkKeepAliveReqMsg) -> Self {
+        Self::new(req.sequence_id, req.timestamp)
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowNetworkProbeReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: NetworkMessageType,
+    flags: u8,
+    pub sequence_id: u16,
+    pub timestamp: u32,
+}
+
+impl NowNetworkProbeReqMsg {
+    pub const SUBTYPE: NetworkMessageType = NetworkMessageType::ProbeReq;
+    pub const REQUIRED_SIZE: usize = 8;
+
+    pub fn new(sequence_id: u16, timestamp: u32) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            sequence_id,
+            timestamp,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowNetworkProbeRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: NetworkMessageType,
+    flags: u8,
+    pub sequence_id: u16,
+    /// Timestamp echoed back from the request.
+    pub timestamp: u32,
+}
+
+impl NowNetworkProbeRspMsg {
+    pub const SUBTYPE: NetworkMessageType = NetworkMessageType::ProbeRsp;
+    pub const REQUIRED_SIZE: usize = 8;
+
+    pub fn new(sequence_id: u16, timestamp: u32) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            sequence_id,
+            timestamp,
+        }
+    }
+
+    pub fn answer(req: &NowNetworkProbeReqMsg) -> Self {
+        Self::new(req.sequence_id, req.timestamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Decode, Encode};
+
+    #[rustfmt::skip]
+    const KEEP_ALIVE_RSP_MSG: [u8; 8] = [
+        0x02, // subtype
+        0x00, // flags
+        0x2a, 0x00, // sequence id
+        0xe8, 0x03, 0x00, 0x00, // timestamp
+    ];
+
+    #[test]
+    fn keep_alive_rsp_decoding() {
+        let msg = NowNetworkMsg::decode(&KEEP_ALIVE_RSP_MSG).unwrap();
+        if let NowNetworkMsg::KeepAliveRsp(msg) = msg {
+            assert_eq!(msg.subtype, NetworkMessageType::KeepAliveRsp);
+            assert_eq!(msg.sequence_id, 42);
+            assert_eq!(msg.timestamp, 1000);
+        } else {
+            panic!("expected a keep alive rsp message and got {:?}", msg);
+        }
+    }
+
+    #[test]
+    fn keep_alive_rsp_encoding() {
+        let req = NowNetworkKeepAliveReqMsg::new(42, 1000);
+        let msg = NowNetworkMsg::from(NowNetworkKeepAliveRspMsg::answer(&req));
+        assert_eq!(msg.encode().unwrap(), KEEP_ALIVE_RSP_MSG.to_vec());
+    }
+}
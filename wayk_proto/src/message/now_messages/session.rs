@@ -0,0 +1,158 @@
+// NOW_SESSION_MSG
+
+use crate::message::NowStatusCode;
+use num_derive::FromPrimitive;
+
+/// Single-use token allowing another viewer to take over a session.
+pub type HandoffToken = [u32; 4];
+
+#[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum SessionMessageType {
+    HandoffTokenReq = 0x01,
+    HandoffTokenRsp = 0x02,
+}
+
+#[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[meta_enum = "SessionMessageType"]
+pub enum NowSessionMsg {
+    HandoffTokenReq(NowSessionHandoffTokenReqMsg),
+    HandoffTokenRsp(NowSessionHandoffTokenRspMsg),
+}
+
+impl From<NowSessionHandoffTokenReqMsg> for NowSessionMsg {
+    fn from(msg: NowSessionHandoffTokenReqMsg) -> Self {
+        Self::HandoffTokenReq(msg)
+    }
+}
+
+impl From<NowSessionHandoffTokenRspMsg> for NowSessionMsg {
+    fn from(msg: NowSessionHandoffTokenRspMsg) -> Self {
+        Self::HandoffTokenRsp(msg)
+    }
+}
+
+// subtypes
+
+/// Sent by the current viewer to get a token for another viewer.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowSessionHandoffTokenReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: SessionMessageType,
+    flags: u8,
+    reserved: u16,
+}
+
+impl Default for NowSessionHandoffTokenReqMsg {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NowSessionHandoffTokenReqMsg {
+    pub const SUBTYPE: SessionMessageType = SessionMessageType::HandoffTokenReq;
+
+    pub fn new() -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            reserved: 0,
+        }
+    }
+}
+
+__flags_struct! {
+    HandoffFlags: u8 => {
+        carry_over_access = CARRY_OVER_ACCESS = 0x01,
+    }
+}
+
+/// Token bound to the session, valid once and for `lifetime` seconds.
+///
+/// Unless `CARRY_OVER_ACCESS` is set, access rights granted to the current
+/// viewer are prompted again to the end user for the new viewer.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowSessionHandoffTokenRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: SessionMessageType,
+    pub flags: HandoffFlags,
+    reserved: u16,
+    pub status: NowStatusCode,
+    pub session_id: u32,
+    pub lifetime: u32,
+    pub token: HandoffToken,
+}
+
+impl NowSessionHandoffTokenRspMsg {
+    pub const SUBTYPE: SessionMessageType = SessionMessageType::HandoffTokenRsp;
+
+    pub fn new(flags: HandoffFlags, session_id: u32, lifetime: u32, token: HandoffToken) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags,
+            reserved: 0,
+            status: NowStatusCode::Success,
+            session_id,
+            lifetime,
+            token,
+        }
+    }
+
+    pub fn new_with_status(status: NowStatusCode) -> Self {
+        Self {
+            status,
+            ..Self::new(HandoffFlags::new_empty(), 0, 0, [0; 4])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Decode, Encode};
+
+    #[rustfmt::skip]
+    const HANDOFF_TOKEN_RSP_MSG: [u8; 30] = [
+        0x02, // subtype
+        0x01, // flags
+        0x00, 0x00, // reserved
+        0x00, 0x00, // status
+        0x2a, 0x00, 0x00, 0x00, // session id
+        0x3c, 0x00, 0x00, 0x00, // lifetime
+        // token
+        0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn handoff_token_rsp_decoding() {
+        let msg = NowSessionMsg::decode(&HANDOFF_TOKEN_RSP_MSG).unwrap();
+        if let NowSessionMsg::HandoffTokenRsp(msg) = msg {
+            assert!(msg.flags.carry_over_access());
+            assert_eq!(msg.status, NowStatusCode::Success);
+            assert_eq!(msg.session_id, 42);
+            assert_eq!(msg.lifetime, 60);
+            assert_eq!(msg.token, [1, 2, 3, 4]);
+        } else {
+            panic!("expected a handoff token response and got {:?}", msg);
+        }
+    }
+
+    #[test]
+    fn handoff_token_rsp_encoding() {
+        let msg = NowSessionMsg::from(NowSessionHandoffTokenRspMsg::new(
+            HandoffFlags::new_empty().set_carry_over_access(),
+            42,
+            60,
+            [1, 2, 3, 4],
+        ));
+        assert_eq!(msg.encode().unwrap(), HANDOFF_TOKEN_RSP_MSG.to_vec());
+    }
+}
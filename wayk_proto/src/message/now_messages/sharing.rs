@@ -3,6 +3,8 @@ use num_derive::FromPrimitive;
 use std::str::FromStr;
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum SharingMessageType {
     Suspend = 0x01,
@@ -10,7 +12,10 @@ pub enum SharingMessageType {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowSharingSuspendMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = SharingMessageType::Suspend))]
     subtype: SharingMessageType,
     flags: u8,
     reserved: u16,
@@ -42,7 +47,10 @@ impl NowSharingSuspendMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowSharingResumeMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = SharingMessageType::Resume))]
     subtype: SharingMessageType,
     flags: u8,
     reserved: u16,
@@ -51,6 +59,8 @@ pub struct NowSharingResumeMsg {
 // NOW_SHARING_MSG
 
 #[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "SharingMessageType"]
 pub enum NowSharingMsg {
     Suspend(NowSharingSuspendMsg),
@@ -1,21 +1,192 @@
 use crate::{
     container::Vec8,
-    error::{ProtoErrorKind, ProtoErrorResultExt, Result},
-    message::EdgeRect,
-    serialization::{Decode, Encode},
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt, Result},
+    message::{EdgeRect, NowCapset, NowStatusCode},
+    serialization::{Decode, Encode, EncodeCtx, FixedSize},
+    version::ProtocolFeature,
 };
-use byteorder::ReadBytesExt;
-use core::mem;
+use core::convert::TryFrom;
 use num_derive::FromPrimitive;
-use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::io::{Cursor, Write};
 
 __flags_struct! {
     SurfaceResponseFlags: u8 => {
+        extended_status = EXTENDED_STATUS = 0x01,
         failure = FAILURE = 0x80,
     }
 }
 
+fn extended_status_negotiated(ctx: &EncodeCtx) -> bool {
+    if !ctx.version.supports(ProtocolFeature::ExtendedSurfaceStatus) {
+        return false;
+    }
+
+    match ctx.capabilities() {
+        Some(capabilities) => capabilities.iter().any(|capset| match capset {
+            NowCapset::Surface(capset) => capset.flags.extended_status(),
+            _ => false,
+        }),
+        None => true,
+    }
+}
+
+/// Implements codec and status accessors for surface response messages.
+///
+/// When the `EXTENDED_STATUS` flag is set, a status code follows the sequence id.
+/// Peers only send it if the `EXTENDED_STATUS` surface capability was negotiated, with a protocol version
+/// supporting it: when encoding with a context where it wasn't, the status is reduced to the failure flag.
+macro_rules! surface_rsp_msg {
+    ($rsp_ty:ident) => {
+        impl $rsp_ty {
+            pub fn new(flags: SurfaceResponseFlags, sequence_id: u16) -> Self {
+                Self {
+                    subtype: Self::SUBTYPE,
+                    flags,
+                    sequence_id,
+                    extended_status: None,
+                }
+            }
+
+            /// Builds a response with an explicit status code (requires the extended status capability).
+            pub fn new_with_status(sequence_id: u16, status: NowStatusCode) -> Self {
+                let mut flags = SurfaceResponseFlags::new_empty().set_extended_status();
+                if !status.is_success() {
+                    flags.set_failure();
+                }
+
+                Self {
+                    subtype: Self::SUBTYPE,
+                    flags,
+                    sequence_id,
+                    extended_status: Some(status),
+                }
+            }
+
+            /// Status of the response. Falls back on the failure flag if no extended status is provided.
+            pub fn status(&self) -> NowStatusCode {
+                match self.extended_status {
+                    Some(status) => status,
+                    None if self.flags.failure() => NowStatusCode::Failure,
+                    None => NowStatusCode::Success,
+                }
+            }
+
+            fn without_extended_status(&self, ctx: &EncodeCtx) -> Option<Self> {
+                if self.extended_status.is_none() || extended_status_negotiated(ctx) {
+                    return None;
+                }
+
+                let mut flags = self.flags;
+                flags.unset_extended_status();
+                Some(Self {
+                    flags,
+                    extended_status: None,
+                    ..self.clone()
+                })
+            }
+        }
+
+        impl Encode for $rsp_ty {
+            fn encoded_len(&self) -> usize {
+                self.subtype.encoded_len()
+                    + self.flags.encoded_len()
+                    + self.sequence_id.encoded_len()
+                    + self
+                        .extended_status
+                        .map(|status| status.encoded_len())
+                        .unwrap_or(0)
+            }
+
+            fn encode_into<W: Write>(&self, writer: &mut W) -> Result<()> {
+                self.subtype.encode_into(writer)?;
+                self.flags.encode_into(writer)?;
+                self.sequence_id.encode_into(writer)?;
+                if let Some(status) = &self.extended_status {
+                    status.encode_into(writer)?;
+                }
+                Ok(())
+            }
+
+            fn encoded_len_ctx(&self, ctx: &EncodeCtx) -> usize {
+                match self.without_extended_status(ctx) {
+                    Some(rsp) => rsp.encoded_len(),
+                    None => self.encoded_len(),
+                }
+            }
+
+            fn encode_into_ctx<W: Write>(&self, writer: &mut W, ctx: &EncodeCtx) -> Result<()> {
+                match self.without_extended_status(ctx) {
+                    Some(rsp) => rsp.encode_into(writer),
+                    None => self.encode_into(writer),
+                }
+            }
+        }
+
+        impl Decode<'_> for $rsp_ty {
+            fn decode_from(cursor: &mut Cursor<&[u8]>) -> Result<Self> {
+                let subtype = SurfaceMessageType::decode_from(cursor)?;
+                let flags = SurfaceResponseFlags::decode_from(cursor)?;
+                let sequence_id = u16::decode_from(cursor)?;
+                let extended_status = if flags.extended_status() {
+                    Some(
+                        NowStatusCode::decode_from(cursor)
+                            .chain(ProtoErrorKind::Decoding(stringify!($rsp_ty)))
+                            .or_desc("couldn't decode extended status")?,
+                    )
+                } else {
+                    None
+                };
+
+                Ok(Self {
+                    subtype,
+                    flags,
+                    sequence_id,
+                    extended_status,
+                })
+            }
+        }
+
+        #[cfg(feature = "fuzzing")]
+        impl<'arb> arbitrary::Arbitrary<'arb> for $rsp_ty {
+            fn arbitrary(u: &mut arbitrary::Unstructured<'arb>) -> arbitrary::Result<Self> {
+                let flags = SurfaceResponseFlags::arbitrary(u)?;
+                let sequence_id = u16::arbitrary(u)?;
+                let extended_status = if flags.extended_status() {
+                    Some(NowStatusCode::arbitrary(u)?)
+                } else {
+                    None
+                };
+
+                Ok(Self {
+                    extended_status,
+                    ..Self::new(flags, sequence_id)
+                })
+            }
+        }
+
+        #[cfg(feature = "schema")]
+        impl crate::schema::WireSchema for $rsp_ty {
+            fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+                use crate::schema::{FieldSchema, TypeSchema};
+
+                schema.define(stringify!($rsp_ty), |schema| TypeSchema::Struct {
+                    size: None,
+                    fields: vec![
+                        FieldSchema::new("subtype", schema.register::<SurfaceMessageType>()),
+                        FieldSchema::new("flags", schema.register::<SurfaceResponseFlags>()),
+                        FieldSchema::new("sequence_id", schema.register::<u16>()),
+                        FieldSchema::new("extended_status", schema.register::<NowStatusCode>())
+                            .present_if("flags & SurfaceResponseFlags::EXTENDED_STATUS"),
+                    ],
+                })
+            }
+        }
+    };
+}
+
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum SurfaceMessageType {
     ListReq = 0x01,
@@ -24,6 +195,7 @@ pub enum SurfaceMessageType {
     MapRsp = 0x04,
     SelectReq = 0x05,
     SelectRsp = 0x06,
+    MultiSelectReq = 0x07,
 }
 
 // NOW_SURFACE_DEF
@@ -45,42 +217,189 @@ impl Default for SurfacePropertiesFlags {
     }
 }
 
-#[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+/// Clockwise rotation applied to the surface framebuffer to display it on the desktop.
+///
+/// Surface-local coordinates are expressed in the framebuffer (native, unrotated) frame,
+/// whereas the surface rect on the desktop is expressed in the rotated frame.
+///
+/// Orientations unknown to this implementation are kept as is in `Other` and handled as `Landscape`.
+#[derive(Encode, Decode, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum SurfaceOrientation {
     Landscape = 0,
     Portrait = 90,
     LandscapeFlipped = 180,
     PortraitFlipped = 270,
+    #[decode_other]
+    Other(u16),
 }
 
+impl SurfaceOrientation {
+    pub fn degrees(self) -> u16 {
+        self.to_primitive()
+    }
+
+    pub fn is_portrait(self) -> bool {
+        match self {
+            Self::Portrait | Self::PortraitFlipped => true,
+            Self::Landscape | Self::LandscapeFlipped | Self::Other(_) => false,
+        }
+    }
+
+    /// Size in the rotated frame of a framebuffer of the given size (and conversely).
+    pub fn rotate_size(self, width: i32, height: i32) -> (i32, i32) {
+        if self.is_portrait() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// Rotates a rect of a framebuffer of the given size into the rotated frame.
+    /// Returns `None` if an edge overflows.
+    pub fn rotate_rect(self, rect: &EdgeRect, native_width: i16, native_height: i16) -> Option<EdgeRect> {
+        let from_end = |size: i16, edge: i16| i16::try_from(i32::from(size) - i32::from(edge)).ok();
+        match self {
+            Self::Landscape | Self::Other(_) => Some(rect.clone()),
+            Self::Portrait => Some(EdgeRect {
+                left: from_end(native_height, rect.bottom)?,
+                top: rect.left,
+                right: from_end(native_height, rect.top)?,
+                bottom: rect.right,
+            }),
+            Self::LandscapeFlipped => Some(EdgeRect {
+                left: from_end(native_width, rect.right)?,
+                top: from_end(native_height, rect.bottom)?,
+                right: from_end(native_width, rect.left)?,
+                bottom: from_end(native_height, rect.top)?,
+            }),
+            Self::PortraitFlipped => Some(EdgeRect {
+                left: rect.top,
+                top: from_end(native_width, rect.right)?,
+                right: rect.bottom,
+                bottom: from_end(native_width, rect.left)?,
+            }),
+        }
+    }
+
+    /// Transforms a framebuffer pixel of the surface displayed at `surface_rect` into desktop coordinates.
+    pub fn local_to_desktop(self, surface_rect: &EdgeRect, x: i32, y: i32) -> (i32, i32) {
+        let (native_width, native_height) = self.rotate_size(surface_rect.width(), surface_rect.height());
+        let (dx, dy) = match self {
+            Self::Landscape | Self::Other(_) => (x, y),
+            Self::Portrait => (native_height - 1 - y, x),
+            Self::LandscapeFlipped => (native_width - 1 - x, native_height - 1 - y),
+            Self::PortraitFlipped => (y, native_width - 1 - x),
+        };
+        (i32::from(surface_rect.left) + dx, i32::from(surface_rect.top) + dy)
+    }
+
+    /// Transforms a desktop pixel into the framebuffer coordinates of the surface displayed at `surface_rect`.
+    /// Returns `None` if the point is outside of the surface.
+    pub fn desktop_to_local(self, surface_rect: &EdgeRect, x: i32, y: i32) -> Option<(i32, i32)> {
+        if !surface_rect.contains(x, y) {
+            return None;
+        }
+
+        let (native_width, native_height) = self.rotate_size(surface_rect.width(), surface_rect.height());
+        let dx = x - i32::from(surface_rect.left);
+        let dy = y - i32::from(surface_rect.top);
+        Some(match self {
+            Self::Landscape | Self::Other(_) => (dx, dy),
+            Self::Portrait => (dy, native_height - 1 - dx),
+            Self::LandscapeFlipped => (native_width - 1 - dx, native_height - 1 - dy),
+            Self::PortraitFlipped => (native_width - 1 - dy, dx),
+        })
+    }
+}
+
+/// Scaling information of a surface, used by high-DPI setups.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct SurfaceScaling {
+    #[range(min = 1)]
+    #[cfg_attr(feature = "fuzzing", arbitrary(with = arbitrary_non_zero))]
+    pub dpi_x: u16,
+    #[range(min = 1)]
+    #[cfg_attr(feature = "fuzzing", arbitrary(with = arbitrary_non_zero))]
+    pub dpi_y: u16,
+    #[range(min = 1)]
+    #[cfg_attr(feature = "fuzzing", arbitrary(with = arbitrary_non_zero))]
+    pub pct_scale_x: u16,
+    #[range(min = 1)]
+    #[cfg_attr(feature = "fuzzing", arbitrary(with = arbitrary_non_zero))]
+    pub pct_scale_y: u16,
+    pub native_rect: EdgeRect,
+}
+
+#[cfg(feature = "fuzzing")]
+fn arbitrary_non_zero(u: &mut arbitrary::Unstructured) -> arbitrary::Result<u16> {
+    u.int_in_range(1..=u16::MAX)
+}
+
+/// Surface definition.
+///
+/// Scaling information follows the base fields when the declared `size` is large enough.
+/// Unknown trailing fields are skipped.
 #[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "SurfaceDefFields"))]
 pub struct NowSurfaceDef {
+    #[size_field]
+    #[range(min = "Self::REQUIRED_SIZE")]
+    #[cfg_attr(feature = "serde", serde(skip))]
     size: u16,
     pub flags: SurfacePropertiesFlags,
     pub surface_id: u16,
     pub orientation: SurfaceOrientation,
     pub rect: EdgeRect,
-    // unused fields
-    #[decode_ignore]
-    #[encode_ignore]
-    dpi_x: u16,
-    #[decode_ignore]
-    #[encode_ignore]
-    dpi_y: u16,
-    #[decode_ignore]
-    #[encode_ignore]
-    pct_scale_x: u16,
-    #[decode_ignore]
-    #[encode_ignore]
-    pct_scale_y: u16,
-    #[decode_ignore]
-    #[encode_ignore]
-    native_rect: EdgeRect,
+    #[decode_if(size >= Self::EXTENDED_SIZE)]
+    scaling: Option<SurfaceScaling>,
+}
+
+/// Fields of a [`NowSurfaceDef`](struct.NowSurfaceDef.html) deserialized by serde: the size is derived
+/// from them rather than trusted, so that it always matches the encoded fields.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct SurfaceDefFields {
+    flags: SurfacePropertiesFlags,
+    surface_id: u16,
+    orientation: SurfaceOrientation,
+    rect: EdgeRect,
+    scaling: Option<SurfaceScaling>,
+}
+
+#[cfg(feature = "serde")]
+impl From<SurfaceDefFields> for NowSurfaceDef {
+    fn from(fields: SurfaceDefFields) -> Self {
+        Self {
+            size: Self::size_for(&fields.scaling),
+            flags: fields.flags,
+            surface_id: fields.surface_id,
+            orientation: fields.orientation,
+            rect: fields.rect,
+            scaling: fields.scaling,
+        }
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'arb> arbitrary::Arbitrary<'arb> for NowSurfaceDef {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'arb>) -> arbitrary::Result<Self> {
+        let mut def = Self::new(u16::arbitrary(u)?, EdgeRect::arbitrary(u)?)
+            .flags(SurfacePropertiesFlags::arbitrary(u)?)
+            .orientation(SurfaceOrientation::arbitrary(u)?);
+        def.set_scaling(Option::arbitrary(u)?);
+        Ok(def)
+    }
 }
 
 impl NowSurfaceDef {
     pub const REQUIRED_SIZE: usize = 16;
+    pub const EXTENDED_SIZE: usize = 32;
 
     pub fn new(surface_id: u16, rect: EdgeRect) -> Self {
         Self {
@@ -89,11 +408,7 @@ impl NowSurfaceDef {
             surface_id,
             orientation: SurfaceOrientation::Landscape,
             rect,
-            dpi_x: 0,
-            dpi_y: 0,
-            pct_scale_x: 0,
-            pct_scale_y: 0,
-            native_rect: EdgeRect::default(),
+            scaling: None,
         }
     }
 
@@ -110,36 +425,96 @@ impl NowSurfaceDef {
             ..self
         }
     }
+
+    pub fn scaling(&self) -> Option<&SurfaceScaling> {
+        self.scaling.as_ref()
+    }
+
+    pub fn set_scaling(&mut self, scaling: Option<SurfaceScaling>) {
+        self.size = Self::size_for(&scaling);
+        self.scaling = scaling;
+    }
+
+    pub fn dpi_x(&self) -> Option<u16> {
+        self.scaling.as_ref().map(|scaling| scaling.dpi_x)
+    }
+
+    pub fn dpi_y(&self) -> Option<u16> {
+        self.scaling.as_ref().map(|scaling| scaling.dpi_y)
+    }
+
+    pub fn pct_scale_x(&self) -> Option<u16> {
+        self.scaling.as_ref().map(|scaling| scaling.pct_scale_x)
+    }
+
+    pub fn pct_scale_y(&self) -> Option<u16> {
+        self.scaling.as_ref().map(|scaling| scaling.pct_scale_y)
+    }
+
+    pub fn native_rect(&self) -> Option<&EdgeRect> {
+        self.scaling.as_ref().map(|scaling| &scaling.native_rect)
+    }
+
+    fn size_for(scaling: &Option<SurfaceScaling>) -> u16 {
+        if scaling.is_some() {
+            Self::EXTENDED_SIZE as u16
+        } else {
+            Self::REQUIRED_SIZE as u16
+        }
+    }
 }
 
 // NOW_SURFACE_MAP
 
-#[derive(Debug, Clone, Encode, Decode)]
+__flags_struct! {
+    SurfaceMapFlags: u16 => {
+        disabled = DISABLED = 0x0001,
+        scaled = SCALED = 0x0002,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[fixed_size]
 pub struct NowSurfaceMap {
+    #[size_field]
+    #[range(min = "Self::REQUIRED_SIZE")]
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::ENCODED_SIZE as u16))]
     size: u16,
-    flags: u16,
+    pub flags: SurfaceMapFlags,
     pub surface_id: u16,
     pub output_id: u16,
     pub output_rect: EdgeRect,
 }
 
 impl NowSurfaceMap {
-    pub const REQUIRED_SIZE: usize = mem::size_of::<Self>();
+    pub const REQUIRED_SIZE: usize = Self::ENCODED_SIZE;
 
     pub fn new(surface_id: u16, output_id: u16, output_rect: EdgeRect) -> Self {
         Self {
             size: Self::REQUIRED_SIZE as u16,
-            flags: 0,
+            flags: SurfaceMapFlags::new_empty(),
             surface_id,
             output_id,
             output_rect,
         }
     }
+
+    pub fn flags<F: Into<SurfaceMapFlags>>(self, flags: F) -> Self {
+        Self {
+            flags: flags.into(),
+            ..self
+        }
+    }
 }
 
 // NOW_SURFACE_MSG
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[message_enum(tag = u8, subtype = SurfaceMessageType)]
 pub enum NowSurfaceMsg {
     ListReq(NowSurfaceListReqMsg),
     ListRsp(NowSurfaceListRspMsg),
@@ -147,84 +522,13 @@ pub enum NowSurfaceMsg {
     MapRsp(NowSurfaceMapRspMsg),
     SelectReq(NowSurfaceSelectReqMsg),
     SelectRsp(NowSurfaceSelectRspMsg),
-}
-
-impl Encode for NowSurfaceMsg {
-    fn encoded_len(&self) -> usize {
-        match self {
-            NowSurfaceMsg::ListReq(msg) => msg.encoded_len(),
-            NowSurfaceMsg::ListRsp(msg) => msg.encoded_len(),
-            NowSurfaceMsg::MapReq(msg) => msg.encoded_len(),
-            NowSurfaceMsg::MapRsp(msg) => msg.encoded_len(),
-            NowSurfaceMsg::SelectReq(msg) => msg.encoded_len(),
-            NowSurfaceMsg::SelectRsp(msg) => msg.encoded_len(),
-        }
-    }
-
-    fn encode_into<W: Write>(&self, writer: &mut W) -> Result<()> {
-        match self {
-            NowSurfaceMsg::ListReq(msg) => msg
-                .encode_into(writer)
-                .chain(ProtoErrorKind::Encoding(stringify!(NowSurfaceMsg)))
-                .or_desc("couldn't encode list request message"),
-            NowSurfaceMsg::ListRsp(msg) => msg
-                .encode_into(writer)
-                .chain(ProtoErrorKind::Encoding(stringify!(NowSurfaceMsg)))
-                .or_desc("couldn't encode list response message"),
-            NowSurfaceMsg::MapReq(msg) => msg
-                .encode_into(writer)
-                .chain(ProtoErrorKind::Encoding(stringify!(NowSurfaceMsg)))
-                .or_desc("couldn't encode map request message"),
-            NowSurfaceMsg::MapRsp(msg) => msg
-                .encode_into(writer)
-                .chain(ProtoErrorKind::Encoding(stringify!(NowSurfaceMsg)))
-                .or_desc("couldn't encode map response message"),
-            NowSurfaceMsg::SelectReq(msg) => msg
-                .encode_into(writer)
-                .chain(ProtoErrorKind::Encoding(stringify!(NowSurfaceMsg)))
-                .or_desc("couldn't encode select request message"),
-            NowSurfaceMsg::SelectRsp(msg) => msg
-                .encode_into(writer)
-                .chain(ProtoErrorKind::Encoding(stringify!(NowSurfaceMsg)))
-                .or_desc("couldn't encode select response message"),
-        }
-    }
-}
-
-impl Decode<'_> for NowSurfaceMsg {
-    fn decode_from(cursor: &mut Cursor<&[u8]>) -> Result<Self> {
-        let subtype = num::FromPrimitive::from_u8(cursor.read_u8()?)
-            .chain(ProtoErrorKind::Decoding(stringify!(NowSurfaceMsg)))
-            .or_desc("invalid subtype")?;
-        cursor.seek(SeekFrom::Current(-1)).unwrap(); // cannot fail
-
-        match subtype {
-            SurfaceMessageType::ListReq => NowSurfaceListReqMsg::decode_from(cursor)
-                .map(Self::ListReq)
-                .chain(ProtoErrorKind::Decoding(stringify!(NowSurfaceMsg)))
-                .or_desc("invalid list request message"),
-            SurfaceMessageType::ListRsp => NowSurfaceListRspMsg::decode_from(cursor)
-                .map(Self::ListRsp)
-                .chain(ProtoErrorKind::Decoding(stringify!(NowSurfaceMsg)))
-                .or_desc("invalid list response message"),
-            SurfaceMessageType::MapReq => NowSurfaceMapReqMsg::decode_from(cursor)
-                .map(Self::MapReq)
-                .chain(ProtoErrorKind::Decoding(stringify!(NowSurfaceMsg)))
-                .or_desc("invalid map request message"),
-            SurfaceMessageType::MapRsp => NowSurfaceMapRspMsg::decode_from(cursor)
-                .map(Self::MapRsp)
-                .chain(ProtoErrorKind::Decoding(stringify!(NowSurfaceMsg)))
-                .or_desc("invalid map response message"),
-            SurfaceMessageType::SelectReq => NowSurfaceSelectReqMsg::decode_from(cursor)
-                .map(Self::SelectReq)
-                .chain(ProtoErrorKind::Decoding(stringify!(NowSurfaceMsg)))
-                .or_desc("invalid select request message"),
-            SurfaceMessageType::SelectRsp => NowSurfaceSelectRspMsg::decode_from(cursor)
-                .map(Self::SelectRsp)
-                .chain(ProtoErrorKind::Decoding(stringify!(NowSurfaceMsg)))
-                .or_desc("invalid select response message"),
-        }
-    }
+    MultiSelectReq(NowSurfaceMultiSelectReqMsg),
+    /// Subtype unknown to this implementation, only decoded in permissive mode.
+    #[decode_other]
+    Unknown {
+        subtype: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl From<NowSurfaceListReqMsg> for NowSurfaceMsg {
@@ -263,10 +567,41 @@ impl From<NowSurfaceSelectRspMsg> for NowSurfaceMsg {
     }
 }
 
+impl From<NowSurfaceMultiSelectReqMsg> for NowSurfaceMsg {
+    fn from(msg: NowSurfaceMultiSelectReqMsg) -> Self {
+        Self::MultiSelectReq(msg)
+    }
+}
+
+/// Allocates sequence ids of surface requests, wrapping around on overflow.
+#[derive(Debug, Clone, Default)]
+pub struct SurfaceSequenceIds {
+    next: u16,
+}
+
+impl SurfaceSequenceIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn starting_at(first: u16) -> Self {
+        Self { next: first }
+    }
+
+    pub fn next_id(&mut self) -> u16 {
+        let id = self.next;
+        self.next = self.next.wrapping_add(1);
+        id
+    }
+}
+
 // subtypes
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowSurfaceListReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: SurfaceMessageType,
     flags: u8,
     pub sequence_id: u16,
@@ -279,6 +614,14 @@ impl NowSurfaceListReqMsg {
     pub const SUBTYPE: SurfaceMessageType = SurfaceMessageType::ListReq;
     pub const REQUIRED_SIZE: usize = 9;
 
+    pub fn builder() -> NowSurfaceListReqMsgBuilder {
+        NowSurfaceListReqMsgBuilder {
+            sequence_id: 0,
+            desktop_size: None,
+            surfaces: Vec::new(),
+        }
+    }
+
     pub fn new(sequence_id: u16, desktop_width: u16, desktop_height: u16) -> Self {
         Self::new_with_surfaces(sequence_id, desktop_width, desktop_height, Vec::new())
     }
@@ -290,7 +633,7 @@ impl NowSurfaceListReqMsg {
         surfaces: Vec<NowSurfaceDef>,
     ) -> Self {
         Self {
-            subtype: SurfaceMessageType::ListReq,
+            subtype: Self::SUBTYPE,
             flags: 0,
             sequence_id,
             desktop_width,
@@ -298,31 +641,168 @@ impl NowSurfaceListReqMsg {
             surfaces: Vec8(surfaces),
         }
     }
+
+    /// Checks the multi-monitor layout: surface ids are unique, rects are non-empty,
+    /// don't overlap, form a single block without gaps and fit in the desktop size.
+    pub fn validate(&self) -> Result<()> {
+        for (i, def) in self.surfaces.iter().enumerate() {
+            let rect = &def.rect;
+            if rect.is_empty() {
+                return ProtoError::new(ProtoErrorKind::SurfaceLayout)
+                    .or_else_desc(|| format!("surface {} has an empty rect {:?}", def.surface_id, rect));
+            }
+
+            for other in &self.surfaces[..i] {
+                if other.surface_id == def.surface_id {
+                    return ProtoError::new(ProtoErrorKind::SurfaceLayout)
+                        .or_else_desc(|| format!("duplicated surface id {}", def.surface_id));
+                }
+
+                if other.rect.intersects(rect) {
+                    return ProtoError::new(ProtoErrorKind::SurfaceLayout)
+                        .or_else_desc(|| format!("surfaces {} and {} overlap", other.surface_id, def.surface_id));
+                }
+            }
+        }
+
+        if let Some(origin_x) = self.surfaces.iter().map(|def| def.rect.left).min() {
+            let origin_y = self.surfaces.iter().map(|def| def.rect.top).min().unwrap_or(0);
+            for def in self.surfaces.iter() {
+                if i32::from(def.rect.right) - i32::from(origin_x) > i32::from(self.desktop_width)
+                    || i32::from(def.rect.bottom) - i32::from(origin_y) > i32::from(self.desktop_height)
+                {
+                    return ProtoError::new(ProtoErrorKind::SurfaceLayout).or_else_desc(|| {
+                        format!(
+                            "surface {} exceeds desktop size {}x{}",
+                            def.surface_id, self.desktop_width, self.desktop_height
+                        )
+                    });
+                }
+            }
+        }
+
+        // every surface must be reachable from the first one through shared edges
+        let mut reached = vec![false; self.surfaces.len()];
+        let mut to_visit = Vec::new();
+        if !self.surfaces.is_empty() {
+            reached[0] = true;
+            to_visit.push(0);
+        }
+        while let Some(current) = to_visit.pop() {
+            for (i, def) in self.surfaces.iter().enumerate() {
+                if !reached[i] && def.rect.is_adjacent_to(&self.surfaces[current].rect) {
+                    reached[i] = true;
+                    to_visit.push(i);
+                }
+            }
+        }
+        if let Some(i) = reached.iter().position(|reached| !reached) {
+            return ProtoError::new(ProtoErrorKind::SurfaceLayout).or_else_desc(|| {
+                format!(
+                    "surface {} is separated from others by a gap",
+                    self.surfaces[i].surface_id
+                )
+            });
+        }
+
+        Ok(())
+    }
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+/// Unless set explicitly, the desktop size is the bounding box of all surfaces.
+///
+/// Exactly one surface ends up primary: the first one flagged as such, or the first
+/// one if none is.
+pub struct NowSurfaceListReqMsgBuilder {
+    sequence_id: u16,
+    desktop_size: Option<(u16, u16)>,
+    surfaces: Vec<NowSurfaceDef>,
+}
+
+impl NowSurfaceListReqMsgBuilder {
+    pub fn sequence_id(self, sequence_id: u16) -> Self {
+        Self { sequence_id, ..self }
+    }
+
+    pub fn next_sequence_id(self, ids: &mut SurfaceSequenceIds) -> Self {
+        self.sequence_id(ids.next_id())
+    }
+
+    pub fn desktop_size(self, width: u16, height: u16) -> Self {
+        Self {
+            desktop_size: Some((width, height)),
+            ..self
+        }
+    }
+
+    pub fn surface(mut self, surface: NowSurfaceDef) -> Self {
+        self.surfaces.push(surface);
+        self
+    }
+
+    pub fn surfaces<I: IntoIterator<Item = NowSurfaceDef>>(mut self, surfaces: I) -> Self {
+        self.surfaces.extend(surfaces);
+        self
+    }
+
+    pub fn build(mut self) -> NowSurfaceListReqMsg {
+        let primary = self.surfaces.iter().position(|def| def.flags.primary()).unwrap_or(0);
+        for (i, def) in self.surfaces.iter_mut().enumerate() {
+            if i == primary {
+                def.flags.set_primary();
+            } else {
+                def.flags.unset_primary();
+            }
+        }
+
+        let (desktop_width, desktop_height) = self
+            .desktop_size
+            .unwrap_or_else(|| __desktop_bounds(self.surfaces.iter().map(|def| &def.rect)));
+
+        NowSurfaceListReqMsg::new_with_surfaces(self.sequence_id, desktop_width, desktop_height, self.surfaces)
+    }
+}
+
+fn __desktop_bounds<'a, I: Iterator<Item = &'a EdgeRect> + Clone>(rects: I) -> (u16, u16) {
+    let left = rects.clone().map(|rect| i32::from(rect.left)).min().unwrap_or(0);
+    let top = rects.clone().map(|rect| i32::from(rect.top)).min().unwrap_or(0);
+    let right = rects.clone().map(|rect| i32::from(rect.right)).max().unwrap_or(0);
+    let bottom = rects.map(|rect| i32::from(rect.bottom)).max().unwrap_or(0);
+    (
+        (right - left).max(0).min(i32::from(u16::MAX)) as u16,
+        (bottom - top).max(0).min(i32::from(u16::MAX)) as u16,
+    )
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NowSurfaceListRspMsg {
     subtype: SurfaceMessageType,
     pub flags: SurfaceResponseFlags,
     pub sequence_id: u16,
+    pub extended_status: Option<NowStatusCode>,
 }
 
 impl NowSurfaceListRspMsg {
     pub const SUBTYPE: SurfaceMessageType = SurfaceMessageType::ListRsp;
+}
 
-    pub fn new(flags: SurfaceResponseFlags, sequence_id: u16) -> Self {
-        Self {
-            subtype: Self::SUBTYPE,
-            flags,
-            sequence_id,
-        }
+surface_rsp_msg!(NowSurfaceListRspMsg);
+
+__flags_struct! {
+    SurfaceMapReqFlags: u8 => {
+        partial = PARTIAL = 0x01,
     }
 }
 
+/// Without the `PARTIAL` flag, surfaces missing from `maps` are unmapped.
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowSurfaceMapReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: SurfaceMessageType,
-    pub flags: u8, // TODO: find flags values
+    pub flags: SurfaceMapReqFlags,
     pub sequence_id: u16,
     pub desktop_width: u16,
     pub desktop_height: u16,
@@ -333,6 +813,15 @@ impl NowSurfaceMapReqMsg {
     pub const SUBTYPE: SurfaceMessageType = SurfaceMessageType::MapReq;
     pub const REQUIRED_SIZE: usize = 9;
 
+    pub fn builder() -> NowSurfaceMapReqMsgBuilder {
+        NowSurfaceMapReqMsgBuilder {
+            flags: SurfaceMapReqFlags::new_empty(),
+            sequence_id: 0,
+            desktop_size: (0, 0),
+            maps: Vec::new(),
+        }
+    }
+
     pub fn new(sequence_id: u16, desktop_width: u16, desktop_height: u16) -> Self {
         Self::new_with_mappings(sequence_id, desktop_width, desktop_height, Vec::new())
     }
@@ -344,73 +833,296 @@ impl NowSurfaceMapReqMsg {
         maps: Vec<NowSurfaceMap>,
     ) -> Self {
         Self {
-            subtype: SurfaceMessageType::ListReq,
-            flags: 0,
+            subtype: Self::SUBTYPE,
+            flags: SurfaceMapReqFlags::new_empty(),
             sequence_id,
             desktop_width,
             desktop_height,
             maps: Vec8(maps),
         }
     }
+
+    pub fn flags<F: Into<SurfaceMapReqFlags>>(self, flags: F) -> Self {
+        Self {
+            flags: flags.into(),
+            ..self
+        }
+    }
+
+    /// Mirrors every listed surface to the same output rect.
+    pub fn mirror_all(sequence_id: u16, list: &NowSurfaceListReqMsg, output_id: u16, output_rect: EdgeRect) -> Self {
+        let maps = list
+            .surfaces
+            .iter()
+            .map(|def| __full_output_map(def, output_id, &output_rect))
+            .collect();
+        Self::new_with_mappings(sequence_id, list.desktop_width, list.desktop_height, maps)
+    }
+
+    /// Maps surfaces 1:1 to outputs, in reading order on the desktop (top to bottom, left to right).
+    /// Each output is given as its id and rect.
+    pub fn span(sequence_id: u16, list: &NowSurfaceListReqMsg, outputs: &[(u16, EdgeRect)]) -> Result<Self> {
+        if outputs.len() < list.surfaces.len() {
+            return ProtoError::new(ProtoErrorKind::SurfaceLayout).or_else_desc(|| {
+                format!(
+                    "{} outputs aren't enough to span {} surfaces",
+                    outputs.len(),
+                    list.surfaces.len()
+                )
+            });
+        }
+
+        let mut surfaces: Vec<&NowSurfaceDef> = list.surfaces.iter().collect();
+        surfaces.sort_by_key(|def| (def.rect.top, def.rect.left));
+
+        let maps = surfaces
+            .into_iter()
+            .zip(outputs)
+            .map(|(def, (output_id, output_rect))| __full_output_map(def, *output_id, output_rect))
+            .collect();
+        Ok(Self::new_with_mappings(
+            sequence_id,
+            list.desktop_width,
+            list.desktop_height,
+            maps,
+        ))
+    }
+
+    /// Scales the whole desktop down (or up) to fit a single output, keeping the aspect ratio.
+    /// The desktop is centered on the output.
+    pub fn scale_to_fit(
+        sequence_id: u16,
+        list: &NowSurfaceListReqMsg,
+        output_id: u16,
+        output_width: u16,
+        output_height: u16,
+    ) -> Self {
+        let desktop_width = i64::from(list.desktop_width.max(1));
+        let desktop_height = i64::from(list.desktop_height.max(1));
+        let (output_width, output_height) = (i64::from(output_width), i64::from(output_height));
+
+        // scale factor as a fraction, bound by the most constrained dimension
+        let (num, den) = if output_width * desktop_height <= output_height * desktop_width {
+            (output_width, desktop_width)
+        } else {
+            (output_height, desktop_height)
+        };
+        let offset_x = (output_width - desktop_width * num / den) / 2;
+        let offset_y = (output_height - desktop_height * num / den) / 2;
+
+        let origin_x = list.surfaces.iter().map(|def| def.rect.left).min().unwrap_or(0);
+        let origin_y = list.surfaces.iter().map(|def| def.rect.top).min().unwrap_or(0);
+        let scale_x = |x: i16| (offset_x + (i64::from(x) - i64::from(origin_x)) * num / den) as i16;
+        let scale_y = |y: i16| (offset_y + (i64::from(y) - i64::from(origin_y)) * num / den) as i16;
+
+        let maps = list
+            .surfaces
+            .iter()
+            .map(|def| {
+                let output_rect = EdgeRect {
+                    left: scale_x(def.rect.left),
+                    top: scale_y(def.rect.top),
+                    right: scale_x(def.rect.right),
+                    bottom: scale_y(def.rect.bottom),
+                };
+                let flags = if num == den {
+                    SurfaceMapFlags::new_empty()
+                } else {
+                    SurfaceMapFlags::new_empty().set_scaled()
+                };
+                NowSurfaceMap::new(def.surface_id, output_id, output_rect).flags(flags)
+            })
+            .collect();
+        Self::new_with_mappings(sequence_id, list.desktop_width, list.desktop_height, maps)
+    }
 }
 
-#[derive(Debug, Clone, Encode, Decode)]
+/// Maps a surface to a whole output, flagged as scaled if sizes differ.
+fn __full_output_map(def: &NowSurfaceDef, output_id: u16, output_rect: &EdgeRect) -> NowSurfaceMap {
+    let flags = if (def.rect.width(), def.rect.height()) == (output_rect.width(), output_rect.height()) {
+        SurfaceMapFlags::new_empty()
+    } else {
+        SurfaceMapFlags::new_empty().set_scaled()
+    };
+    NowSurfaceMap::new(def.surface_id, output_id, output_rect.clone()).flags(flags)
+}
+
+/// Builds a full (non-partial) map request by default.
+pub struct NowSurfaceMapReqMsgBuilder {
+    flags: SurfaceMapReqFlags,
+    sequence_id: u16,
+    desktop_size: (u16, u16),
+    maps: Vec<NowSurfaceMap>,
+}
+
+impl NowSurfaceMapReqMsgBuilder {
+    pub fn sequence_id(self, sequence_id: u16) -> Self {
+        Self { sequence_id, ..self }
+    }
+
+    pub fn next_sequence_id(self, ids: &mut SurfaceSequenceIds) -> Self {
+        self.sequence_id(ids.next_id())
+    }
+
+    pub fn desktop_size(self, width: u16, height: u16) -> Self {
+        Self {
+            desktop_size: (width, height),
+            ..self
+        }
+    }
+
+    /// Uses the desktop size of the given list request.
+    pub fn list(self, list: &NowSurfaceListReqMsg) -> Self {
+        self.desktop_size(list.desktop_width, list.desktop_height)
+    }
+
+    pub fn partial(mut self, partial: bool) -> Self {
+        if partial {
+            self.flags.set_partial();
+        } else {
+            self.flags.unset_partial();
+        }
+        self
+    }
+
+    pub fn map(mut self, map: NowSurfaceMap) -> Self {
+        self.maps.push(map);
+        self
+    }
+
+    pub fn maps<I: IntoIterator<Item = NowSurfaceMap>>(mut self, maps: I) -> Self {
+        self.maps.extend(maps);
+        self
+    }
+
+    pub fn build(self) -> NowSurfaceMapReqMsg {
+        let (desktop_width, desktop_height) = self.desktop_size;
+        NowSurfaceMapReqMsg::new_with_mappings(self.sequence_id, desktop_width, desktop_height, self.maps)
+            .flags(self.flags)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NowSurfaceMapRspMsg {
     subtype: SurfaceMessageType,
     pub flags: SurfaceResponseFlags,
     pub sequence_id: u16,
+    pub extended_status: Option<NowStatusCode>,
 }
 
 impl NowSurfaceMapRspMsg {
     pub const SUBTYPE: SurfaceMessageType = SurfaceMessageType::MapRsp;
-
-    pub fn new(flags: SurfaceResponseFlags, sequence_id: u16) -> Self {
-        Self {
-            subtype: Self::SUBTYPE,
-            flags,
-            sequence_id,
-        }
-    }
 }
 
+surface_rsp_msg!(NowSurfaceMapRspMsg);
+
 #[derive(Debug, Clone, Decode, Encode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowSurfaceSelectReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: SurfaceMessageType,
     pub flags: u8, // TODO: find flags values
+    #[pad_to(4)]
     pub sequence_id: u16,
-    reserved: u16,
     pub surface_id: u16,
 }
 
 impl NowSurfaceSelectReqMsg {
     pub const SUBTYPE: SurfaceMessageType = SurfaceMessageType::SelectReq;
 
+    pub fn builder() -> NowSurfaceSelectReqMsgBuilder {
+        NowSurfaceSelectReqMsgBuilder {
+            sequence_id: 0,
+            surface_ids: Vec::new(),
+        }
+    }
+
     pub fn new(flags: u8, sequence_id: u16, surface_id: u16) -> Self {
         Self {
             subtype: Self::SUBTYPE,
             flags,
             sequence_id,
-            reserved: 0,
             surface_id,
         }
     }
 }
 
-#[derive(Debug, Clone, Decode, Encode)]
+/// Builds a select request, or a multi select request if several surfaces are selected.
+pub struct NowSurfaceSelectReqMsgBuilder {
+    sequence_id: u16,
+    surface_ids: Vec<u16>,
+}
+
+impl NowSurfaceSelectReqMsgBuilder {
+    pub fn sequence_id(self, sequence_id: u16) -> Self {
+        Self { sequence_id, ..self }
+    }
+
+    pub fn next_sequence_id(self, ids: &mut SurfaceSequenceIds) -> Self {
+        self.sequence_id(ids.next_id())
+    }
+
+    pub fn surface(mut self, surface_id: u16) -> Self {
+        if !self.surface_ids.contains(&surface_id) {
+            self.surface_ids.push(surface_id);
+        }
+        self
+    }
+
+    pub fn surfaces<I: IntoIterator<Item = u16>>(self, surface_ids: I) -> Self {
+        surface_ids.into_iter().fold(self, Self::surface)
+    }
+
+    pub fn build(self) -> Result<NowSurfaceMsg> {
+        match self.surface_ids.as_slice() {
+            [] => ProtoError::new(ProtoErrorKind::Encoding(stringify!(NowSurfaceSelectReqMsg)))
+                .or_desc("no surface to select"),
+            [surface_id] => Ok(NowSurfaceSelectReqMsg::new(0, self.sequence_id, *surface_id).into()),
+            _ => Ok(NowSurfaceMultiSelectReqMsg::new(0, self.sequence_id, self.surface_ids).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NowSurfaceSelectRspMsg {
     subtype: SurfaceMessageType,
     pub flags: SurfaceResponseFlags,
     pub sequence_id: u16,
+    pub extended_status: Option<NowStatusCode>,
 }
 
 impl NowSurfaceSelectRspMsg {
     pub const SUBTYPE: SurfaceMessageType = SurfaceMessageType::SelectRsp;
+}
+
+surface_rsp_msg!(NowSurfaceSelectRspMsg);
 
-    pub fn new(flags: SurfaceResponseFlags, sequence_id: u16) -> Self {
+/// Selects several surfaces at once. Answered by a select response.
+///
+/// Only sent if the `MULTI` surface capability was negotiated.
+#[derive(Debug, Clone, Decode, Encode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowSurfaceMultiSelectReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: SurfaceMessageType,
+    pub flags: u8,
+    pub sequence_id: u16,
+    pub surface_ids: Vec8<u16>,
+}
+
+impl NowSurfaceMultiSelectReqMsg {
+    pub const SUBTYPE: SurfaceMessageType = SurfaceMessageType::MultiSelectReq;
+
+    pub fn new(flags: u8, sequence_id: u16, surface_ids: Vec<u16>) -> Self {
         Self {
             subtype: Self::SUBTYPE,
             flags,
             sequence_id,
+            surface_ids: Vec8(surface_ids),
         }
     }
 }
@@ -418,6 +1130,7 @@ impl NowSurfaceSelectRspMsg {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::message::{DecodeMode, SurfaceCapset, SurfaceCapsetFlags};
 
     #[rustfmt::skip]
     const SURFACE_LIST_REQ_MSG: [u8; 25] = [
@@ -472,5 +1185,775 @@ mod tests {
         assert_eq!(msg.encode().unwrap(), SURFACE_LIST_REQ_MSG.to_vec());
     }
 
-    // TODO: test NowSurfaceMapReqMsg
+    #[rustfmt::skip]
+    const SURFACE_SELECT_RSP_ACCESS_DENIED_MSG: [u8; 6] = [
+        0x06, // subtype
+        0x81, // flags
+        0x07, 0x00, // sequence id
+        0x03, 0x00, // extended status
+    ];
+
+    #[test]
+    fn select_rsp_with_extended_status_decoding() {
+        let msg = NowSurfaceMsg::decode(&SURFACE_SELECT_RSP_ACCESS_DENIED_MSG).unwrap();
+        if let NowSurfaceMsg::SelectRsp(msg) = msg {
+            assert!(msg.flags.failure());
+            assert_eq!(msg.sequence_id, 7);
+            assert_eq!(msg.status(), NowStatusCode::AccessDenied);
+        } else {
+            panic!("expected a surface select rsp message and got {:?}", msg);
+        }
+    }
+
+    #[test]
+    fn map_rsp_unknown_status_round_trip() {
+        let bytes = [0x04, 0x81, 0x01, 0x00, 0x34, 0x12];
+        let msg = NowSurfaceMsg::decode(&bytes).unwrap();
+        if let NowSurfaceMsg::MapRsp(msg) = &msg {
+            assert_eq!(msg.status(), NowStatusCode::Other(0x1234));
+        } else {
+            panic!("expected a surface map rsp message and got {:?}", msg);
+        }
+        assert_eq!(msg.encode().unwrap(), bytes.to_vec());
+    }
+
+    #[test]
+    fn rsp_encoding_with_ctx() {
+        let msg = NowSurfaceMsg::from(NowSurfaceSelectRspMsg::new_with_status(7, NowStatusCode::AccessDenied));
+
+        let mut ctx = EncodeCtx::default();
+        assert_eq!(
+            msg.encode_ctx(&ctx).unwrap(),
+            SURFACE_SELECT_RSP_ACCESS_DENIED_MSG.to_vec()
+        );
+
+        let list_req = NowSurfaceListReqMsg::new_with_surfaces(0, 1024, 768, Vec::new());
+        let capset = SurfaceCapset::new(SurfaceCapsetFlags::new_empty().set_select(), list_req.clone());
+        ctx.configure_from_capabilities(&[NowCapset::Surface(capset)]);
+        assert_eq!(msg.encoded_len_ctx(&ctx), 4);
+        assert_eq!(msg.encode_ctx(&ctx).unwrap(), vec![0x06, 0x80, 0x07, 0x00]);
+
+        let capset = SurfaceCapset::new(SurfaceCapsetFlags::new_empty().set_extended_status(), list_req);
+        ctx.configure_from_capabilities(&[NowCapset::Surface(capset)]);
+        assert_eq!(
+            msg.encode_ctx(&ctx).unwrap(),
+            SURFACE_SELECT_RSP_ACCESS_DENIED_MSG.to_vec()
+        );
+
+        // negotiated version predating the extended status
+        ctx.version = crate::version::NowProtocolVersion::new(3, 2, 0);
+        assert_eq!(msg.encode_ctx(&ctx).unwrap(), vec![0x06, 0x80, 0x07, 0x00]);
+    }
+
+    #[test]
+    fn legacy_failure_rsp() {
+        let msg = NowSurfaceListRspMsg::decode(&[0x02, 0x80, 0x01, 0x00]).unwrap();
+        assert_eq!(msg.status(), NowStatusCode::Failure);
+        assert_eq!(msg.encoded_len(), 4);
+    }
+
+    #[rustfmt::skip]
+    const SURFACE_LIST_REQ_EXTENDED_MSG: [u8; 41] = [
+        0x01, // subtype
+        0x00, // flags
+        0x00, 0x00, // sequence id
+        0x00, 0x0f, // desktop width
+        0x70, 0x08, // desktop height
+        0x01, // surface count
+        // surface(s)
+        0x20, 0x00, // size
+        0x09, 0x00, // flags
+        0x00, 0x00, // surface id
+        0x00, 0x00, // orientation
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x70, 0x08, // rect
+        0x90, 0x00, // dpi x
+        0x90, 0x00, // dpi y
+        0x96, 0x00, // pct scale x
+        0x96, 0x00, // pct scale y
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0xa0, 0x05, // native rect
+    ];
+
+    #[test]
+    fn list_req_extended_surface_decoding() {
+        let msg = NowSurfaceListReqMsg::decode(&SURFACE_LIST_REQ_EXTENDED_MSG).unwrap();
+        let surface = &msg.surfaces[0];
+        assert_eq!(surface.dpi_x(), Some(144));
+        assert_eq!(surface.dpi_y(), Some(144));
+        assert_eq!(surface.pct_scale_x(), Some(150));
+        assert_eq!(surface.pct_scale_y(), Some(150));
+        assert_eq!(
+            surface.native_rect(),
+            Some(&EdgeRect {
+                left: 0,
+                top: 0,
+                right: 2560,
+                bottom: 1440,
+            })
+        );
+    }
+
+    #[test]
+    fn list_req_extended_surface_round_trip() {
+        let msg = NowSurfaceListReqMsg::decode(&SURFACE_LIST_REQ_EXTENDED_MSG).unwrap();
+        assert_eq!(msg.encode().unwrap(), SURFACE_LIST_REQ_EXTENDED_MSG.to_vec());
+    }
+
+    #[test]
+    fn surface_def_unknown_trailing_fields_skipped() {
+        let mut bytes = SURFACE_LIST_REQ_EXTENDED_MSG[9..].to_vec();
+        bytes[0] = 0x24;
+        bytes.extend_from_slice(&[0xff; 4]);
+        bytes.extend_from_slice(&SURFACE_LIST_REQ_MSG[9..]);
+
+        let mut cursor = Cursor::new(bytes.as_slice());
+        let extended = NowSurfaceDef::decode_from(&mut cursor).unwrap();
+        let legacy = NowSurfaceDef::decode_from(&mut cursor).unwrap();
+        assert_eq!(extended.pct_scale_x(), Some(150));
+        assert_eq!(extended.encoded_len(), NowSurfaceDef::EXTENDED_SIZE);
+        assert!(legacy.scaling().is_none());
+    }
+
+    #[test]
+    fn surface_def_truncated() {
+        let mut bytes = SURFACE_LIST_REQ_EXTENDED_MSG[9..].to_vec();
+        bytes[0] = 0x24;
+        assert!(NowSurfaceDef::decode(&bytes).is_err());
+
+        let mut msg = SURFACE_LIST_REQ_MSG.to_vec();
+        msg[9] = 0x14;
+        assert!(NowSurfaceListReqMsg::decode(&msg).is_err());
+    }
+
+    #[test]
+    fn surface_def_out_of_range() {
+        let mut bytes = SURFACE_LIST_REQ_EXTENDED_MSG[9..].to_vec();
+        bytes[0] = 0x0c;
+        let err = NowSurfaceDef::decode(&bytes).err().unwrap();
+        assert!(matches!(err.kind, ProtoErrorKind::OutOfRange(_)));
+
+        let mut bytes = SURFACE_LIST_REQ_EXTENDED_MSG[9..].to_vec();
+        bytes[20] = 0x00;
+        let err = NowSurfaceDef::decode(&bytes).err().unwrap();
+        assert!(matches!(err.source.unwrap().kind, ProtoErrorKind::OutOfRange(_)));
+    }
+
+    #[rustfmt::skip]
+    const SURFACE_MAP_WITH_TRAILING_FIELD: [u8; 20] = [
+        0x14, 0x00, // size
+        0x02, 0x00, // flags
+        0x01, 0x00, // surface id
+        0x00, 0x00, // output id
+        0x00, 0x00, 0x00, 0x00, 0x80, 0x07, 0x38, 0x04, // output rect
+        0xff, 0xff, 0xff, 0xff, // unknown trailing field
+    ];
+
+    #[test]
+    fn surface_map_unknown_trailing_fields_skipped() {
+        let mut bytes = SURFACE_MAP_WITH_TRAILING_FIELD.to_vec();
+        bytes.push(0x2a);
+
+        let mut cursor = Cursor::new(bytes.as_slice());
+        let map = NowSurfaceMap::decode_from(&mut cursor).unwrap();
+        assert!(map.flags.scaled());
+        assert_eq!(map.surface_id, 1);
+        assert_eq!(map.output_rect.right, 1920);
+        assert_eq!(u8::decode_from(&mut cursor).unwrap(), 0x2a);
+
+        assert_eq!(map.encoded_len(), NowSurfaceMap::REQUIRED_SIZE);
+        assert_eq!(map.encode().unwrap(), {
+            let mut expected = SURFACE_MAP_WITH_TRAILING_FIELD[..16].to_vec();
+            expected[0] = 0x10;
+            expected
+        });
+    }
+
+    #[test]
+    fn surface_map_invalid_declared_size() {
+        let mut too_small = SURFACE_MAP_WITH_TRAILING_FIELD;
+        too_small[0] = 0x0c;
+        assert!(NowSurfaceMap::decode(&too_small).is_err());
+
+        let mut too_large = SURFACE_MAP_WITH_TRAILING_FIELD;
+        too_large[0] = 0x20;
+        assert!(NowSurfaceMap::decode(&too_large).is_err());
+    }
+
+    #[test]
+    fn unknown_orientation_preserved() {
+        assert_eq!(
+            SurfaceOrientation::decode(&[0x5a, 0x00]).unwrap(),
+            SurfaceOrientation::Portrait
+        );
+
+        let orientation = SurfaceOrientation::decode(&[0x2d, 0x00]).unwrap();
+        assert_eq!(orientation, SurfaceOrientation::Other(45));
+        assert_eq!(orientation.degrees(), 45);
+        assert!(!orientation.is_portrait());
+        assert_eq!(orientation.encode().unwrap(), vec![0x2d, 0x00]);
+    }
+
+    #[test]
+    fn surface_def_set_scaling() {
+        let mut surface = NowSurfaceDef::new(0, EdgeRect::default());
+        surface.set_scaling(Some(SurfaceScaling {
+            dpi_x: 144,
+            dpi_y: 144,
+            pct_scale_x: 150,
+            pct_scale_y: 150,
+            native_rect: EdgeRect::default(),
+        }));
+        assert_eq!(surface.encode().unwrap().len(), NowSurfaceDef::EXTENDED_SIZE);
+        surface.set_scaling(None);
+        assert_eq!(surface.encode().unwrap().len(), NowSurfaceDef::REQUIRED_SIZE);
+    }
+
+    fn layout(desktop_width: u16, desktop_height: u16, rects: &[(u16, [i16; 4])]) -> NowSurfaceListReqMsg {
+        let surfaces = rects
+            .iter()
+            .map(|(id, [left, top, right, bottom])| {
+                NowSurfaceDef::new(
+                    *id,
+                    EdgeRect {
+                        left: *left,
+                        top: *top,
+                        right: *right,
+                        bottom: *bottom,
+                    },
+                )
+            })
+            .collect();
+        NowSurfaceListReqMsg::new_with_surfaces(0, desktop_width, desktop_height, surfaces)
+    }
+
+    #[test]
+    fn valid_layouts() {
+        layout(1024, 768, &[(0, [0, 0, 1024, 768])]).validate().unwrap();
+        layout(3000, 1920, &[(1, [0, 0, 1920, 1080]), (2, [1920, 0, 3000, 1920])])
+            .validate()
+            .unwrap();
+        // secondary monitor on the left of the primary one
+        layout(3840, 1080, &[(1, [0, 0, 1920, 1080]), (2, [-1920, 0, 0, 1080])])
+            .validate()
+            .unwrap();
+        layout(0, 0, &[]).validate().unwrap();
+    }
+
+    #[test]
+    fn overlapping_surfaces_rejected() {
+        let err = layout(3840, 1080, &[(1, [0, 0, 1920, 1080]), (2, [1900, 0, 3820, 1080])])
+            .validate()
+            .unwrap_err();
+        assert!(err.to_string().contains("overlap"), "{}", err);
+    }
+
+    #[test]
+    fn gap_between_surfaces_rejected() {
+        let err = layout(3860, 1080, &[(1, [0, 0, 1920, 1080]), (2, [1940, 0, 3860, 1080])])
+            .validate()
+            .unwrap_err();
+        assert!(err.to_string().contains("gap"), "{}", err);
+    }
+
+    #[test]
+    fn surface_exceeding_desktop_rejected() {
+        let err = layout(1920, 1080, &[(1, [0, 0, 1920, 1080]), (2, [1920, 0, 3840, 1080])])
+            .validate()
+            .unwrap_err();
+        assert!(err.to_string().contains("exceeds"), "{}", err);
+    }
+
+    #[test]
+    fn duplicate_surface_id_rejected() {
+        let err = layout(3840, 1080, &[(1, [0, 0, 1920, 1080]), (1, [1920, 0, 3840, 1080])])
+            .validate()
+            .unwrap_err();
+        assert!(err.to_string().contains("duplicated"), "{}", err);
+    }
+
+    const PORTRAIT_RECT: EdgeRect = EdgeRect {
+        left: 1920,
+        top: 0,
+        right: 3000,
+        bottom: 1920,
+    };
+
+    #[test]
+    fn orientation_local_to_desktop_corners() {
+        // framebuffer is 1920x1080, displayed as 1080x1920
+        let origin = SurfaceOrientation::Portrait.local_to_desktop(&PORTRAIT_RECT, 0, 0);
+        assert_eq!(origin, (2999, 0));
+        let bottom_left = SurfaceOrientation::Portrait.local_to_desktop(&PORTRAIT_RECT, 0, 1079);
+        assert_eq!(bottom_left, (1920, 0));
+
+        let origin = SurfaceOrientation::PortraitFlipped.local_to_desktop(&PORTRAIT_RECT, 0, 0);
+        assert_eq!(origin, (1920, 1919));
+
+        let flipped_rect = EdgeRect {
+            left: 0,
+            top: 0,
+            right: 1920,
+            bottom: 1080,
+        };
+        let origin = SurfaceOrientation::LandscapeFlipped.local_to_desktop(&flipped_rect, 0, 0);
+        assert_eq!(origin, (1919, 1079));
+    }
+
+    #[test]
+    fn orientation_round_trip() {
+        let landscape_rect = EdgeRect {
+            left: -1920,
+            top: 0,
+            right: 0,
+            bottom: 1080,
+        };
+        let cases = [
+            (SurfaceOrientation::Landscape, &landscape_rect),
+            (SurfaceOrientation::Portrait, &PORTRAIT_RECT),
+            (SurfaceOrientation::LandscapeFlipped, &landscape_rect),
+            (SurfaceOrientation::PortraitFlipped, &PORTRAIT_RECT),
+        ];
+
+        for (orientation, rect) in cases.iter() {
+            let (width, height) = orientation.rotate_size(rect.width(), rect.height());
+            for &(x, y) in &[
+                (0, 0),
+                (width - 1, 0),
+                (0, height - 1),
+                (width - 1, height - 1),
+                (100, 42),
+            ] {
+                let (dx, dy) = orientation.local_to_desktop(rect, x, y);
+                assert!(
+                    rect.contains(dx, dy),
+                    "{:?}: ({}, {}) -> ({}, {})",
+                    orientation,
+                    x,
+                    y,
+                    dx,
+                    dy
+                );
+                assert_eq!(
+                    orientation.desktop_to_local(rect, dx, dy),
+                    Some((x, y)),
+                    "{:?}",
+                    orientation
+                );
+            }
+        }
+
+        assert_eq!(
+            SurfaceOrientation::Portrait.desktop_to_local(&PORTRAIT_RECT, 0, 0),
+            None
+        );
+    }
+
+    #[test]
+    fn orientation_rotate_rect() {
+        // top-left 100x50 region of a 1920x1080 framebuffer
+        let region = EdgeRect {
+            left: 0,
+            top: 0,
+            right: 100,
+            bottom: 50,
+        };
+
+        let rotated = SurfaceOrientation::Portrait.rotate_rect(&region, 1920, 1080).unwrap();
+        assert_eq!(
+            rotated,
+            EdgeRect {
+                left: 1030,
+                top: 0,
+                right: 1080,
+                bottom: 100,
+            }
+        );
+
+        let rotated = SurfaceOrientation::LandscapeFlipped
+            .rotate_rect(&region, 1920, 1080)
+            .unwrap();
+        assert_eq!(
+            rotated,
+            EdgeRect {
+                left: 1820,
+                top: 1030,
+                right: 1920,
+                bottom: 1080,
+            }
+        );
+
+        let rotated = SurfaceOrientation::PortraitFlipped
+            .rotate_rect(&region, 1920, 1080)
+            .unwrap();
+        assert_eq!(
+            rotated,
+            EdgeRect {
+                left: 0,
+                top: 1820,
+                right: 50,
+                bottom: 1920,
+            }
+        );
+
+        // rotated rect matches the pixels transformed one by one
+        let (x, y) = SurfaceOrientation::Portrait.local_to_desktop(&PORTRAIT_RECT, 99, 49);
+        assert_eq!((x - 1920, y), (1030, 99));
+    }
+
+    #[test]
+    fn orientation_rotate_rect_overflow() {
+        let extreme = EdgeRect {
+            left: i16::MIN,
+            top: i16::MIN,
+            right: i16::MAX,
+            bottom: i16::MAX,
+        };
+        assert_eq!(SurfaceOrientation::Portrait.rotate_rect(&extreme, 1920, 1080), None);
+        assert_eq!(
+            SurfaceOrientation::LandscapeFlipped.rotate_rect(&extreme, i16::MAX, i16::MAX),
+            None
+        );
+        assert_eq!(
+            SurfaceOrientation::PortraitFlipped.rotate_rect(&extreme, i16::MIN, i16::MIN),
+            None
+        );
+        assert_eq!(
+            SurfaceOrientation::Landscape.rotate_rect(&extreme, i16::MIN, i16::MIN),
+            Some(extreme.clone())
+        );
+
+        // edges reaching the limits without overflowing
+        let region = EdgeRect {
+            left: 0,
+            top: 0,
+            right: i16::MAX,
+            bottom: i16::MAX,
+        };
+        assert_eq!(
+            SurfaceOrientation::LandscapeFlipped.rotate_rect(&region, i16::MAX, i16::MAX),
+            Some(region.clone())
+        );
+    }
+
+    #[test]
+    fn list_req_builder_computes_desktop_bounds() {
+        let mut ids = SurfaceSequenceIds::starting_at(7);
+        let msg = NowSurfaceListReqMsg::builder()
+            .next_sequence_id(&mut ids)
+            .surface(NowSurfaceDef::new(
+                1,
+                EdgeRect {
+                    left: -1280,
+                    top: 0,
+                    right: 0,
+                    bottom: 1024,
+                },
+            ))
+            .surface(NowSurfaceDef::new(
+                2,
+                EdgeRect {
+                    left: 0,
+                    top: 0,
+                    right: 1920,
+                    bottom: 1080,
+                },
+            ))
+            .build();
+
+        assert_eq!(msg.sequence_id, 7);
+        assert_eq!(ids.next_id(), 8);
+        assert_eq!((msg.desktop_width, msg.desktop_height), (3200, 1080));
+        assert!(msg.surfaces[0].flags.primary());
+        assert!(!msg.surfaces[1].flags.primary());
+        msg.validate().unwrap();
+    }
+
+    #[test]
+    fn select_req_builder() {
+        let single = NowSurfaceSelectReqMsg::builder()
+            .sequence_id(1)
+            .surface(2)
+            .build()
+            .unwrap();
+        assert!(matches!(single, NowSurfaceMsg::SelectReq(msg) if msg.surface_id == 2));
+
+        let multi = NowSurfaceSelectReqMsg::builder()
+            .surfaces(vec![2, 3, 2])
+            .build()
+            .unwrap();
+        assert!(matches!(multi, NowSurfaceMsg::MultiSelectReq(msg) if msg.surface_ids.0 == vec![2, 3]));
+
+        assert!(NowSurfaceSelectReqMsg::builder().build().is_err());
+    }
+
+    #[rustfmt::skip]
+    const SURFACE_MULTI_SELECT_REQ_MSG: [u8; 9] = [
+        0x07, // subtype
+        0x00, // flags
+        0x05, 0x00, // sequence id
+        0x02, // surface count
+        0x01, 0x00, 0x03, 0x00, // surface ids
+    ];
+
+    #[test]
+    fn multi_select_req_decoding() {
+        let msg = NowSurfaceMsg::decode(&SURFACE_MULTI_SELECT_REQ_MSG).unwrap();
+        if let NowSurfaceMsg::MultiSelectReq(msg) = msg {
+            assert_eq!(msg.sequence_id, 5);
+            assert_eq!(msg.surface_ids.0, vec![1, 3]);
+        } else {
+            panic!("expected a surface multi select req message and got {:?}", msg);
+        }
+    }
+
+    #[test]
+    fn multi_select_req_encoding() {
+        let msg = NowSurfaceMsg::from(NowSurfaceMultiSelectReqMsg::new(0, 5, vec![1, 3]));
+        assert_eq!(msg.encode().unwrap(), SURFACE_MULTI_SELECT_REQ_MSG.to_vec());
+    }
+
+    #[rustfmt::skip]
+    const SURFACE_MAP_REQ_MSG: [u8; 25] = [
+        0x03, // subtype
+        0x01, // flags
+        0x02, 0x00, // sequence id
+        0x00, 0x0f, // desktop width
+        0x38, 0x04, // desktop height
+        0x01, // map count
+        // map(s)
+        0x10, 0x00, // size
+        0x02, 0x00, // flags
+        0x02, 0x00, // surface id
+        0x01, 0x00, // output id
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xd0, 0x02, // output rect
+    ];
+
+    #[test]
+    fn map_req_decoding() {
+        let msg = NowSurfaceMsg::decode(&SURFACE_MAP_REQ_MSG).unwrap();
+        if let NowSurfaceMsg::MapReq(msg) = msg {
+            assert!(msg.flags.partial());
+            assert_eq!(msg.sequence_id, 2);
+            assert_eq!(msg.desktop_width, 3840);
+            assert_eq!(msg.desktop_height, 1080);
+            let map = &msg.maps[0];
+            assert!(map.flags.scaled());
+            assert!(!map.flags.disabled());
+            assert_eq!(map.surface_id, 2);
+            assert_eq!(map.output_id, 1);
+            assert_eq!(map.output_rect.right, 1280);
+            assert_eq!(map.output_rect.bottom, 720);
+        } else {
+            panic!("expected a surface map req message and got {:?}", msg);
+        }
+    }
+
+    #[test]
+    fn map_req_encoding() {
+        let map = NowSurfaceMap::new(
+            2,
+            1,
+            EdgeRect {
+                left: 0,
+                top: 0,
+                right: 1280,
+                bottom: 720,
+            },
+        )
+        .flags(SurfaceMapFlags::new_empty().set_scaled());
+        let msg = NowSurfaceMapReqMsg::new_with_mappings(2, 3840, 1080, vec![map])
+            .flags(SurfaceMapReqFlags::new_empty().set_partial());
+        assert_eq!(NowSurfaceMsg::from(msg).encode().unwrap(), SURFACE_MAP_REQ_MSG.to_vec());
+    }
+
+    /// Exhaustive on purpose: a new variant has to be given a subtype here.
+    fn expected_subtype(msg: &NowSurfaceMsg) -> u8 {
+        let subtype = match msg {
+            NowSurfaceMsg::ListReq(_) => SurfaceMessageType::ListReq,
+            NowSurfaceMsg::ListRsp(_) => SurfaceMessageType::ListRsp,
+            NowSurfaceMsg::MapReq(_) => SurfaceMessageType::MapReq,
+            NowSurfaceMsg::MapRsp(_) => SurfaceMessageType::MapRsp,
+            NowSurfaceMsg::SelectReq(_) => SurfaceMessageType::SelectReq,
+            NowSurfaceMsg::SelectRsp(_) => SurfaceMessageType::SelectRsp,
+            NowSurfaceMsg::MultiSelectReq(_) => SurfaceMessageType::MultiSelectReq,
+            NowSurfaceMsg::Unknown { subtype, .. } => return *subtype,
+        };
+        subtype as u8
+    }
+
+    fn representative_msgs() -> Vec<NowSurfaceMsg> {
+        let rect = |left, right| EdgeRect {
+            left,
+            top: 0,
+            right,
+            bottom: 1080,
+        };
+        let mut scaled = NowSurfaceDef::new(2, rect(1920, 3840)).orientation(SurfaceOrientation::Portrait);
+        scaled.set_scaling(Some(SurfaceScaling {
+            dpi_x: 144,
+            dpi_y: 144,
+            pct_scale_x: 150,
+            pct_scale_y: 150,
+            native_rect: rect(0, 2880),
+        }));
+
+        vec![
+            NowSurfaceListReqMsg::new_with_surfaces(1, 3840, 1080, vec![NowSurfaceDef::new(1, rect(0, 1920)), scaled])
+                .into(),
+            NowSurfaceListRspMsg::new(SurfaceResponseFlags::new_empty(), 1).into(),
+            NowSurfaceMapReqMsg::new_with_mappings(
+                2,
+                3840,
+                1080,
+                vec![
+                    NowSurfaceMap::new(1, 0, rect(0, 1920)),
+                    NowSurfaceMap::new(2, 1, rect(0, 1280)).flags(SurfaceMapFlags::new_empty().set_scaled()),
+                ],
+            )
+            .into(),
+            NowSurfaceMapRspMsg::new_with_status(2, NowStatusCode::Busy).into(),
+            NowSurfaceSelectReqMsg::new(0, 3, 2).into(),
+            NowSurfaceSelectRspMsg::new(SurfaceResponseFlags::new_empty().set_failure(), 3).into(),
+            NowSurfaceMultiSelectReqMsg::new(0, 4, vec![1, 2]).into(),
+        ]
+    }
+
+    #[test]
+    fn every_variant_round_trip() {
+        for msg in representative_msgs() {
+            let encoded = msg.encode().unwrap();
+            assert_eq!(encoded.len(), msg.encoded_len(), "{:?}", msg);
+            assert_eq!(encoded[0], expected_subtype(&msg), "{:?}", msg);
+
+            let decoded = NowSurfaceMsg::decode(&encoded).unwrap();
+            assert_eq!(expected_subtype(&decoded), expected_subtype(&msg), "{:?}", decoded);
+            assert_eq!(decoded.encode().unwrap(), encoded, "{:?}", msg);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn every_variant_json_round_trip() {
+        for msg in representative_msgs() {
+            let json = serde_json::to_string(&msg).unwrap();
+            let deserialized: NowSurfaceMsg = serde_json::from_str(&json).unwrap();
+            assert_eq!(deserialized.encode().unwrap(), msg.encode().unwrap(), "{}", json);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn surface_def_json_size_derived_from_scaling() {
+        let mut surface = NowSurfaceDef::new(1, EdgeRect::default());
+        surface.set_scaling(Some(SurfaceScaling {
+            dpi_x: 144,
+            dpi_y: 144,
+            pct_scale_x: 150,
+            pct_scale_y: 150,
+            native_rect: EdgeRect::default(),
+        }));
+        let mut json: serde_json::Value = serde_json::to_value(&surface).unwrap();
+        assert!(json.get("size").is_none());
+        json["size"] = serde_json::Value::from(NowSurfaceDef::REQUIRED_SIZE);
+
+        let deserialized: NowSurfaceDef = serde_json::from_value(json).unwrap();
+        assert_eq!(deserialized.encoded_len(), NowSurfaceDef::EXTENDED_SIZE);
+        assert_eq!(deserialized.encode().unwrap(), surface.encode().unwrap());
+    }
+
+    fn preset_list() -> NowSurfaceListReqMsg {
+        layout(3200, 1080, &[(2, [1280, 0, 3200, 1080]), (1, [0, 0, 1280, 1024])])
+    }
+
+    #[test]
+    fn mirror_all_preset() {
+        let output = EdgeRect {
+            left: 0,
+            top: 0,
+            right: 1920,
+            bottom: 1080,
+        };
+        let req = NowSurfaceMapReqMsg::mirror_all(1, &preset_list(), 0, output.clone());
+        assert!(!req.flags.partial());
+        assert_eq!(req.maps.len(), 2);
+        assert!(req
+            .maps
+            .iter()
+            .all(|map| map.output_id == 0 && map.output_rect == output));
+        assert!(!req.maps[0].flags.scaled());
+        assert!(req.maps[1].flags.scaled());
+    }
+
+    #[test]
+    fn span_preset() {
+        let output = |right, bottom| EdgeRect {
+            left: 0,
+            top: 0,
+            right,
+            bottom,
+        };
+        let outputs = [(10, output(1280, 1024)), (11, output(1920, 1080))];
+        let req = NowSurfaceMapReqMsg::span(1, &preset_list(), &outputs).unwrap();
+        let maps: Vec<(u16, u16, bool)> = req
+            .maps
+            .iter()
+            .map(|map| (map.surface_id, map.output_id, map.flags.scaled()))
+            .collect();
+        assert_eq!(maps, vec![(1, 10, false), (2, 11, false)]);
+
+        assert!(NowSurfaceMapReqMsg::span(1, &preset_list(), &outputs[..1]).is_err());
+    }
+
+    #[test]
+    fn scale_to_fit_preset() {
+        // 3200x1080 desktop on a 1600x900 output: half size, vertically centered
+        let req = NowSurfaceMapReqMsg::scale_to_fit(1, &preset_list(), 0, 1600, 900);
+        assert!(req.maps.iter().all(|map| map.flags.scaled()));
+        assert_eq!(
+            req.maps[0].output_rect,
+            EdgeRect {
+                left: 640,
+                top: 180,
+                right: 1600,
+                bottom: 720,
+            }
+        );
+        assert_eq!(
+            req.maps[1].output_rect,
+            EdgeRect {
+                left: 0,
+                top: 180,
+                right: 640,
+                bottom: 692,
+            }
+        );
+
+        let req = NowSurfaceMapReqMsg::scale_to_fit(1, &preset_list(), 0, 3200, 1080);
+        assert!(req.maps.iter().all(|map| !map.flags.scaled()));
+    }
+
+    const UNKNOWN_SURFACE_MSG: [u8; 4] = [0x42, 0x01, 0x02, 0x03];
+
+    #[test]
+    fn unknown_subtype_strict() {
+        assert!(NowSurfaceMsg::decode(&UNKNOWN_SURFACE_MSG).is_err());
+    }
+
+    #[test]
+    fn unknown_subtype_permissive_round_trip() {
+        let msg = NowSurfaceMsg::decode_with_mode(&mut Cursor::new(&UNKNOWN_SURFACE_MSG[..]), DecodeMode::Permissive)
+            .unwrap();
+        match &msg {
+            NowSurfaceMsg::Unknown { subtype, payload } => {
+                assert_eq!(*subtype, 0x42);
+                assert_eq!(payload, &[0x01, 0x02, 0x03]);
+            }
+            other => panic!("expected an unknown surface message and got {:?}", other),
+        }
+        assert_eq!(msg.encode().unwrap(), UNKNOWN_SURFACE_MSG.to_vec());
+
+        // known subtypes are decoded as usual
+        let msg = NowSurfaceMsg::decode_with_mode(&mut Cursor::new(&SURFACE_LIST_REQ_MSG[..]), DecodeMode::Permissive)
+            .unwrap();
+        assert!(matches!(msg, NowSurfaceMsg::ListReq(_)));
+    }
 }
@@ -17,6 +17,8 @@ __flags_struct! {
 }
 
 #[derive(Debug, Decode, Encode, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct OsInfoExtraWindows {
     pub extra_flags: u16,
     pub product_flags: WindowsProductFlags,
@@ -30,6 +32,8 @@ pub struct OsInfoExtraWindows {
 }
 
 #[derive(Debug, Decode, Encode, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct OsInfoExtraMac {
     pub extra_flags: u16,
     reserved: u16,
@@ -45,6 +49,8 @@ impl OsInfoExtraMac {
 }
 
 #[derive(Debug, Encode, Decode, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct OsInfoExtraLinux {
     pub extra_flags: u16,
     reserved: u16,
@@ -60,6 +66,8 @@ impl OsInfoExtraLinux {
 }
 
 #[derive(Debug, Decode, Encode, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct OsInfoExtraIOS {
     pub extra_flags: u16,
     reserved: u16,
@@ -75,6 +83,8 @@ impl OsInfoExtraIOS {
 }
 
 #[derive(Debug, Encode, Decode, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct OsInfoExtraAndroid {
     pub extra_flags: u16,
     reserved: u16,
@@ -90,6 +100,8 @@ impl OsInfoExtraAndroid {
 }
 
 #[derive(Debug, Clone, Encode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "None"]
 pub enum OsInfoExtra {
     Windows(OsInfoExtraWindows),
@@ -100,6 +112,8 @@ pub enum OsInfoExtra {
 }
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum SystemInfoType {
     Os = 0x0001,
@@ -113,6 +127,8 @@ __flags_struct! {
 }
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum OsType {
     Windows = 0x01,
@@ -123,6 +139,8 @@ pub enum OsType {
 }
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum OsArch {
     X86 = 0x01,
@@ -132,6 +150,7 @@ pub enum OsArch {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NowSystemOsInfo {
     subtype: SystemInfoType,
     pub flags: SystemOsInfoFlags,
@@ -284,6 +303,35 @@ impl Decode<'_> for NowSystemOsInfo {
     }
 }
 
+#[cfg(feature = "schema")]
+impl crate::schema::WireSchema for NowSystemOsInfo {
+    fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+        use crate::schema::{FieldSchema, TypeSchema};
+
+        schema.define(stringify!(NowSystemOsInfo), |schema| TypeSchema::Struct {
+            size: None,
+            fields: vec![
+                FieldSchema::new("subtype", schema.register::<SystemInfoType>()),
+                FieldSchema::new("flags", schema.register::<SystemOsInfoFlags>()),
+                FieldSchema::new("os_type", schema.register::<OsType>()),
+                FieldSchema::new("os_arch", schema.register::<OsArch>()),
+                FieldSchema::new("version_major", schema.register::<u16>()),
+                FieldSchema::new("version_minor", schema.register::<u16>()),
+                FieldSchema::new("version_patch", schema.register::<u16>()),
+                FieldSchema::new("os_build", schema.register::<NowString16>()),
+                FieldSchema::new("os_name", schema.register::<NowString64>()),
+                FieldSchema::new("kernel_name", schema.register::<NowString16>()),
+                FieldSchema::new("kernel_arch", schema.register::<NowString16>()),
+                FieldSchema::new("kernel_release", schema.register::<NowString32>()),
+                FieldSchema::new("kernel_version", schema.register::<NowString128>()),
+                // variant selected by `os_type`
+                FieldSchema::new("extra", schema.register::<OsInfoExtra>())
+                    .present_if("flags & SystemOsInfoFlags::EXTRA"),
+            ],
+        })
+    }
+}
+
 impl NowSystemOsInfo {
     const SUBTYPE: SystemInfoType = SystemInfoType::Os;
 
@@ -335,7 +383,45 @@ impl NowSystemOsInfo {
     }
 }
 
+#[cfg(feature = "fuzzing")]
+impl<'arb> arbitrary::Arbitrary<'arb> for NowSystemOsInfo {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'arb>) -> arbitrary::Result<Self> {
+        // kernel infos are always encoded
+        let flags = SystemOsInfoFlags::arbitrary(u)?.set_kernel();
+        let os_type = OsType::arbitrary(u)?;
+        let mut info = Self::new(
+            os_type,
+            OsArch::arbitrary(u)?,
+            u16::arbitrary(u)?,
+            u16::arbitrary(u)?,
+            u16::arbitrary(u)?,
+            NowString16::arbitrary(u)?,
+        );
+        info.os_name = NowString64::arbitrary(u)?;
+        info.flags = flags;
+
+        info.kernel_name = NowString16::arbitrary(u)?;
+        info.kernel_arch = NowString16::arbitrary(u)?;
+        info.kernel_release = NowString32::arbitrary(u)?;
+        info.kernel_version = NowString128::arbitrary(u)?;
+
+        if flags.extra() {
+            info.extra = Some(match os_type {
+                OsType::Windows => OsInfoExtra::Windows(OsInfoExtraWindows::arbitrary(u)?),
+                OsType::Mac => OsInfoExtra::Mac(OsInfoExtraMac::arbitrary(u)?),
+                OsType::Linux => OsInfoExtra::Linux(OsInfoExtraLinux::arbitrary(u)?),
+                OsType::IOS => OsInfoExtra::IOS(OsInfoExtraIOS::arbitrary(u)?),
+                OsType::Android => OsInfoExtra::Android(OsInfoExtraAndroid::arbitrary(u)?),
+            });
+        }
+
+        Ok(info)
+    }
+}
+
 #[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "SystemInfoType"]
 pub enum NowSystemInfo {
     Os(NowSystemOsInfo),
@@ -344,6 +430,8 @@ pub enum NowSystemInfo {
 // NOW_SYSTEM_MSG
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum SystemMessageType {
     InfoReq = 0x01,
@@ -352,7 +440,10 @@ pub enum SystemMessageType {
 }
 
 #[derive(Debug, Decode, Encode, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowSystemInfoReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: SystemMessageType,
     flags: u8,
 
@@ -372,7 +463,10 @@ impl NowSystemInfoReqMsg {
 }
 
 #[derive(Decode, Encode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowSystemInfoRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: SystemMessageType,
     flags: u8,
 
@@ -399,7 +493,10 @@ __flags_struct! {
 }
 
 #[derive(Debug, Encode, Decode, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowSystemShutdownMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: SystemMessageType,
     pub flags: ShutdownFlags,
 
@@ -425,6 +522,8 @@ impl NowSystemShutdownMsg {
 }
 
 #[derive(Debug, Clone, Decode, Encode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "SystemMessageType"]
 pub enum NowSystemMsg {
     InfoReq(NowSystemInfoReqMsg),
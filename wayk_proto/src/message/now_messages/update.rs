@@ -7,6 +7,8 @@ use crate::{
 use num_derive::FromPrimitive;
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum UpdateMessageType {
     UpdateGraphics = 0x01,
@@ -22,6 +24,8 @@ __flags_struct! {
 }
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum UpdateRegionFlag {
     Null = 0x01,
@@ -29,6 +33,8 @@ pub enum UpdateRegionFlag {
 }
 
 #[derive(Decode, Encode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowUpdateRegion {
     pub surface_id: u16,
     pub flags: UpdateRegionFlag,
@@ -36,15 +42,21 @@ pub struct NowUpdateRegion {
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "UpdateMessageType"]
 pub enum NowUpdateMsg<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
     UpdateGraphics(NowUpdateGraphicsMsg<'a>),
     UpdateRefresh(NowUpdateRefreshMsg),
     UpdateSuppress(NowUpdateSuppressMsg),
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowUpdateGraphicsMsg<'a> {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = UpdateMessageType::UpdateGraphics))]
     pub subtype: UpdateMessageType,
     flags: u8,
 
@@ -53,15 +65,39 @@ pub struct NowUpdateGraphicsMsg<'a> {
     pub frame_id: u16,
     pub update_flags: UpdateGraphicsFlags,
     pub update_rect: common::SizeRect,
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub update_data: Bytes32<'a>,
 }
 
 impl<'a> NowUpdateGraphicsMsg<'a> {
     pub const REQUIRED_SIZE: usize = 24;
+
+    pub fn new(
+        codec_id: Codec,
+        surface_id: u16,
+        frame_id: u16,
+        update_flags: UpdateGraphicsFlags,
+        update_rect: common::SizeRect,
+        update_data: &'a [u8],
+    ) -> Self {
+        Self {
+            subtype: UpdateMessageType::UpdateGraphics,
+            flags: 0,
+            codec_id,
+            surface_id,
+            frame_id,
+            update_flags,
+            update_rect,
+            update_data: Bytes32(update_data),
+        }
+    }
 }
 
 #[derive(Decode, Encode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowUpdateRefreshMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = UpdateMessageType::UpdateRefresh))]
     pub subtype: UpdateMessageType,
     flags: u8,
 
@@ -70,8 +106,11 @@ pub struct NowUpdateRefreshMsg {
 }
 
 #[derive(Decode, Encode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(C)]
 pub struct NowUpdateSuppressMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = UpdateMessageType::UpdateSuppress))]
     pub subtype: UpdateMessageType,
     flags: u8,
 
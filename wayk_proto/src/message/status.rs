@@ -48,6 +48,7 @@ where
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NowStatus<CodeType> {
     repr: u32,
 
@@ -99,9 +100,31 @@ where
     }
 }
 
-impl<CodeType> Into<u32> for NowStatus<CodeType> {
-    fn into(self) -> u32 {
-        self.repr
+#[cfg(feature = "fuzzing")]
+impl<'arb, CodeType> arbitrary::Arbitrary<'arb> for NowStatus<CodeType>
+where
+    CodeType: arbitrary::Arbitrary<'arb> + num::ToPrimitive,
+{
+    fn arbitrary(u: &mut arbitrary::Unstructured<'arb>) -> arbitrary::Result<Self> {
+        Ok(NowStatus::builder(CodeType::arbitrary(u)?)
+            .severity(SeverityLevel::arbitrary(u)?)
+            .status_type(StatusType::arbitrary(u)?)
+            .build())
+    }
+}
+
+#[cfg(feature = "schema")]
+impl<CodeType: crate::schema::WireSchema> crate::schema::WireSchema for NowStatus<CodeType> {
+    fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+        crate::schema::TypeSchema::Status {
+            code: Box::new(schema.register::<CodeType>()),
+        }
+    }
+}
+
+impl<CodeType> From<NowStatus<CodeType>> for u32 {
+    fn from(status: NowStatus<CodeType>) -> Self {
+        status.repr
     }
 }
 
@@ -171,6 +194,8 @@ where
 }
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum SeverityLevel {
     Info = 0,
@@ -186,6 +211,8 @@ impl fmt::Display for SeverityLevel {
 }
 
 #[derive(Encode, Decode, FromPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum StatusType {
     None = 0,
@@ -218,9 +245,142 @@ enum StatusCode {
     Failure = 0xFFFF,
 }
 
+// Response status code
+//
+// Reason code carried by response messages supporting the extended status.
+// Unknown values are preserved as is.
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub enum NowStatusCode {
+    Success,
+    InvalidRequest,
+    NotSupported,
+    AccessDenied,
+    Busy,
+    NotFound,
+    PolicyDenied,
+    Failure,
+    Other(u16),
+}
+
+impl NowStatusCode {
+    pub fn from_u16(value: u16) -> Self {
+        match value {
+            0x0000 => Self::Success,
+            0x0001 => Self::InvalidRequest,
+            0x0002 => Self::NotSupported,
+            0x0003 => Self::AccessDenied,
+            0x0004 => Self::Busy,
+            0x0005 => Self::NotFound,
+            0x0006 => Self::PolicyDenied,
+            0xFFFF => Self::Failure,
+            other => Self::Other(other),
+        }
+    }
+
+    pub fn as_u16(self) -> u16 {
+        match self {
+            Self::Success => 0x0000,
+            Self::InvalidRequest => 0x0001,
+            Self::NotSupported => 0x0002,
+            Self::AccessDenied => 0x0003,
+            Self::Busy => 0x0004,
+            Self::NotFound => 0x0005,
+            Self::PolicyDenied => 0x0006,
+            Self::Failure => 0xFFFF,
+            Self::Other(value) => value,
+        }
+    }
+
+    pub fn is_success(self) -> bool {
+        self == Self::Success
+    }
+}
+
+impl From<u16> for NowStatusCode {
+    fn from(value: u16) -> Self {
+        Self::from_u16(value)
+    }
+}
+
+impl From<NowStatusCode> for u16 {
+    fn from(code: NowStatusCode) -> Self {
+        code.as_u16()
+    }
+}
+
+impl Encode for NowStatusCode {
+    fn encoded_len(&self) -> usize {
+        std::mem::size_of::<u16>()
+    }
+
+    fn encode_into<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.as_u16().encode_into(writer)
+    }
+}
+
+impl Decode<'_> for NowStatusCode {
+    fn decode_from(cursor: &mut Cursor<&[u8]>) -> Result<Self> {
+        Ok(Self::from_u16(u16::decode_from(cursor)?))
+    }
+}
+
+#[cfg(feature = "schema")]
+impl crate::schema::WireSchema for NowStatusCode {
+    fn describe(schema: &mut crate::schema::Schema) -> crate::schema::TypeSchema {
+        use crate::schema::{NamedValue, TypeSchema};
+
+        schema.define(stringify!(NowStatusCode), |_| {
+            let variants = [
+                ("Success", Self::Success),
+                ("InvalidRequest", Self::InvalidRequest),
+                ("NotSupported", Self::NotSupported),
+                ("AccessDenied", Self::AccessDenied),
+                ("Busy", Self::Busy),
+                ("NotFound", Self::NotFound),
+                ("PolicyDenied", Self::PolicyDenied),
+                ("Failure", Self::Failure),
+            ];
+
+            TypeSchema::Enum {
+                repr: "u16",
+                size: std::mem::size_of::<u16>(),
+                variants: variants
+                    .iter()
+                    .map(|&(name, code)| NamedValue {
+                        name,
+                        value: u64::from(code.as_u16()),
+                    })
+                    .collect(),
+                open: true,
+            }
+        })
+    }
+}
+
+impl fmt::Display for NowStatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Success => write!(f, "success"),
+            Self::InvalidRequest => write!(f, "invalid request"),
+            Self::NotSupported => write!(f, "not supported"),
+            Self::AccessDenied => write!(f, "access denied"),
+            Self::Busy => write!(f, "busy"),
+            Self::NotFound => write!(f, "not found"),
+            Self::PolicyDenied => write!(f, "denied by policy"),
+            Self::Failure => write!(f, "unknown failure"),
+            Self::Other(value) => write!(f, "unknown status code 0x{:04X}", value),
+        }
+    }
+}
+
 // NSTATUS_DISCONNECT_TYPE
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum DisconnectStatusCode {
     Success = StatusCode::Success as u16,
@@ -239,6 +399,7 @@ pub enum DisconnectStatusCode {
     IdleTimeout = 12,
     AlreadyActive = 13,
     LicenseRequired = 14,
+    HandedOff = 15,
 }
 
 impl fmt::Display for DisconnectStatusCode {
@@ -260,6 +421,7 @@ impl fmt::Display for DisconnectStatusCode {
             Self::IdleTimeout => write!(f, "disconnected because of idle timeout."),
             Self::AlreadyActive => write!(f, "disconnected because another connection is already active."),
             Self::LicenseRequired => write!(f, "disconnected because a license is required."),
+            Self::HandedOff => write!(f, "disconnected because the session was handed off to another viewer."),
         }
     }
 }
@@ -267,6 +429,8 @@ impl fmt::Display for DisconnectStatusCode {
 // NSTATUS_CONNECT_TYPE
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum ConnectStatusCode {
     Success = StatusCode::Success as u16,
@@ -297,6 +461,8 @@ impl fmt::Display for ConnectStatusCode {
 // NSTATUS_SECURITY_TYPE
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum SecurityStatusCode {
     Success = StatusCode::Success as u16,
@@ -321,6 +487,8 @@ impl fmt::Display for SecurityStatusCode {
 // NSTATUS_HANDSHAKE_TYPE
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum HandshakeStatusCode {
     Success = StatusCode::Success as u16,
@@ -341,6 +509,8 @@ impl fmt::Display for HandshakeStatusCode {
 // NSTATUS_NEGOTIATE_TYPE
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum NegotiateStatusCode {
     Success = StatusCode::Success as u16,
@@ -359,6 +529,8 @@ impl fmt::Display for NegotiateStatusCode {
 // NSTATUS_AUTH_TYPE
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum AuthStatusCode {
     Success = StatusCode::Success as u16,
@@ -395,10 +567,16 @@ impl fmt::Display for AuthStatusCode {
 // NSTATUS_ASSOCIATE_TYPE
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum AssociateStatusCode {
     Success = StatusCode::Success as u16,
     Failure = StatusCode::Failure as u16,
+    HandoffTokenInvalid = 1,
+    HandoffTokenExpired = 2,
+    /// The host didn't grant remote control.
+    AccessDenied = 3,
 }
 
 impl fmt::Display for AssociateStatusCode {
@@ -406,6 +584,9 @@ impl fmt::Display for AssociateStatusCode {
         match self {
             Self::Success => write!(f, "association succeeded"),
             Self::Failure => write!(f, "association failed"),
+            Self::HandoffTokenInvalid => write!(f, "association failed: invalid handoff token"),
+            Self::HandoffTokenExpired => write!(f, "association failed: handoff token expired"),
+            Self::AccessDenied => write!(f, "association failed: access denied by the host"),
         }
     }
 }
@@ -413,6 +594,8 @@ impl fmt::Display for AssociateStatusCode {
 // NSTATUS_CAPABILITIES_TYPE
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum CapabilitiesStatusCode {
     Success = StatusCode::Success as u16,
@@ -431,6 +614,8 @@ impl fmt::Display for CapabilitiesStatusCode {
 // NSTATUS_CHANNEL_TYPE
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum ChannelStatusCode {
     Success = StatusCode::Success as u16,
@@ -449,6 +634,8 @@ impl fmt::Display for ChannelStatusCode {
 // NSTATUS_CLIPBOARD_TYPE
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum ClipboardStatusCode {
     Success = StatusCode::Success as u16,
@@ -467,6 +654,8 @@ impl fmt::Display for ClipboardStatusCode {
 // NSTATUS_FILE_TRANSFER_TYPE
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum FileTransferStatusCode {
     Success = StatusCode::Success as u16,
@@ -485,6 +674,8 @@ impl fmt::Display for FileTransferStatusCode {
 // NSTATUS_EXEC_TYPE (Remote Execution)
 
 #[derive(Encode, Decode, FromPrimitive, ToPrimitive, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum ExecStatusCode {
     Success = StatusCode::Success as u16,
@@ -536,4 +727,15 @@ mod tests {
             .build();
         assert_eq!(nstatus.as_u32(), 0x8017_ffff);
     }
+
+    #[test]
+    fn status_code_unknown_value_preserved() {
+        let code = NowStatusCode::decode(&[0x42, 0x01]).unwrap();
+        assert_eq!(code, NowStatusCode::Other(0x0142));
+        assert_eq!(code.encode().unwrap(), vec![0x42, 0x01]);
+        assert_eq!(
+            NowStatusCode::decode(&[0x03, 0x00]).unwrap(),
+            NowStatusCode::AccessDenied
+        );
+    }
 }
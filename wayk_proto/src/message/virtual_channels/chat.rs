@@ -4,6 +4,8 @@ use crate::message::common::now_string::NowString65535;
 use num_derive::FromPrimitive;
 
 #[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum ChatMessageType {
     Sync = 0x00,
@@ -16,6 +18,8 @@ pub enum ChatMessageType {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "ChatMessageType"]
 pub enum NowChatMsg {
     Sync(NowChatSyncMsg),
@@ -72,6 +76,8 @@ impl From<NowChatPokeMsg> for NowChatMsg {
 // subtypes
 
 #[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum ChatPresenceStatus {
     Unknown = 0x00,
@@ -93,7 +99,10 @@ __flags_struct! {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowChatSyncMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ChatMessageType,
     flags: u8,
     reserved: u16,
@@ -137,7 +146,10 @@ __flags_struct! {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowChatTextMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ChatMessageType,
     pub flags: ChatTextFlags,
     reserved: u16,
@@ -169,7 +181,10 @@ impl NowChatTextMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowChatReadMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ChatMessageType,
     flags: u8,
     reserved: u16,
@@ -196,7 +211,10 @@ __flags_struct! {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowChatTypingMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ChatMessageType,
     flags: u8,
     reserved: u16,
@@ -222,7 +240,10 @@ impl NowChatTypingMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowChatNameMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ChatMessageType,
     flags: u8,
     reserved: u16,
@@ -243,7 +264,10 @@ impl NowChatNameMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowChatStatusMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ChatMessageType,
     flags: u8,
     reserved: u16,
@@ -264,7 +288,10 @@ impl NowChatStatusMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowChatPokeMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ChatMessageType,
     flags: u8,
     reserved: u16,
@@ -0,0 +1,356 @@
+// Chunking
+
+use crate::{
+    container::Bytes32,
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{CustomVirtualChannel, NowClipboardFormatDataRspMsg, NowFileTransferDataMsg},
+    serialization::{DecodeLimits, Encode},
+    version::{NowProtocolVersion, ProtocolFeature},
+};
+use alloc::{borrow::Cow, collections::BTreeMap};
+
+/// Channel message carrying a part of a payload, followed by the next parts while flagged with a continuation.
+///
+/// Parts of a payload are sent in order over their channel. Payloads of distinct streams, such as two
+/// file transfers, may be interleaved.
+pub trait ChunkMessage<'a>: Encode + Sized {
+    /// Payloads of distinct streams are reassembled separately.
+    fn stream_id(&self) -> u32 {
+        0
+    }
+
+    fn chunk_data(&self) -> &[u8];
+
+    /// `true` when more parts of the payload follow.
+    fn has_continuation(&self) -> bool;
+
+    /// Same message carrying `data` instead.
+    fn with_chunk(&self, data: &'a [u8], continuation: bool) -> Self;
+
+    /// Feature the peer must support for payloads to be chunked, `None` when always understood.
+    fn required_feature() -> Option<ProtocolFeature> {
+        None
+    }
+}
+
+impl<'a> ChunkMessage<'a> for NowClipboardFormatDataRspMsg<'a> {
+    fn stream_id(&self) -> u32 {
+        u32::from(self.sequence_id)
+    }
+
+    fn chunk_data(&self) -> &[u8] {
+        &self.format_data
+    }
+
+    fn has_continuation(&self) -> bool {
+        self.flags.continuation()
+    }
+
+    fn with_chunk(&self, data: &'a [u8], continuation: bool) -> Self {
+        let mut message = self.clone();
+        if continuation {
+            message.flags.set_continuation();
+        } else {
+            message.flags.unset_continuation();
+        }
+        message.format_data = Bytes32(data);
+        message
+    }
+
+    fn required_feature() -> Option<ProtocolFeature> {
+        Some(ProtocolFeature::ClipboardContinuation)
+    }
+}
+
+impl<'a> ChunkMessage<'a> for NowFileTransferDataMsg<'a> {
+    fn stream_id(&self) -> u32 {
+        u32::from(self.request_id)
+    }
+
+    fn chunk_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn has_continuation(&self) -> bool {
+        !self.flags.last()
+    }
+
+    fn with_chunk(&self, data: &'a [u8], continuation: bool) -> Self {
+        NowFileTransferDataMsg::new(self.request_id, data, !continuation)
+    }
+}
+
+/// Custom payloads are opaque to the protocol: chunks are prefixed with a byte, set to
+/// [`CONTINUATION`](struct.ChunkedWriter.html#associatedconstant.CONTINUATION) when more parts follow.
+/// Both peers of a custom channel are to agree on sending chunks.
+impl<'a> ChunkMessage<'a> for CustomVirtualChannel<'a> {
+    fn chunk_data(&self) -> &[u8] {
+        self.payload.get(1..).unwrap_or(&[])
+    }
+
+    fn has_continuation(&self) -> bool {
+        self.payload.first() == Some(&ChunkedWriter::CONTINUATION)
+    }
+
+    fn with_chunk(&self, data: &'a [u8], continuation: bool) -> Self {
+        let mut payload = Vec::with_capacity(data.len() + 1);
+        payload.push(if continuation { ChunkedWriter::CONTINUATION } else { 0 });
+        payload.extend_from_slice(data);
+        CustomVirtualChannel::new(self.name.clone(), Cow::Owned(payload))
+    }
+}
+
+/// Splits payloads into channel messages not exceeding the maximum message size once encoded.
+///
+/// Payloads of messages whose chunking isn't supported with the negotiated version are sent whole.
+#[derive(Debug, Clone)]
+pub struct ChunkedWriter {
+    max_message_size: usize,
+    version: NowProtocolVersion,
+}
+
+impl ChunkedWriter {
+    /// Largest body carried by a short header.
+    pub const DEFAULT_MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+    /// Leading byte of custom channel chunks followed by others.
+    pub const CONTINUATION: u8 = 0x01;
+
+    pub fn new() -> Self {
+        Self {
+            max_message_size: Self::DEFAULT_MAX_MESSAGE_SIZE,
+            version: NowProtocolVersion::CURRENT,
+        }
+    }
+
+    pub fn max_message_size(self, max_message_size: usize) -> Self {
+        Self {
+            max_message_size,
+            ..self
+        }
+    }
+
+    /// Version negotiated with the peer, typically from `VirtChannelsCtx::version`.
+    pub fn version(self, version: NowProtocolVersion) -> Self {
+        Self { version, ..self }
+    }
+
+    pub fn get_max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Whether payloads of `M` are chunked with the negotiated version.
+    pub fn is_chunking<'a, M: ChunkMessage<'a>>(&self) -> bool {
+        M::required_feature().is_none_or(|feature| self.version.supports(feature))
+    }
+
+    /// Splits `payload` into messages like `template`, the last one without continuation.
+    /// An empty payload, or one whose chunking the peer doesn't support, gives one message.
+    pub fn split<'a, M: ChunkMessage<'a>>(&self, template: &M, payload: &'a [u8]) -> Result<Vec<M>, ProtoError> {
+        if !self.is_chunking::<M>() {
+            return Ok(vec![template.with_chunk(payload, false)]);
+        }
+
+        let overhead = template.with_chunk(&[], false).encoded_len();
+        if overhead >= self.max_message_size {
+            return ProtoError::new(ProtoErrorKind::Encoding(stringify!(ChunkedWriter))).or_else_desc(|| {
+                format!(
+                    "message without data ({} bytes) doesn't fit in the maximum message size ({})",
+                    overhead, self.max_message_size
+                )
+            });
+        }
+
+        if payload.is_empty() {
+            return Ok(vec![template.with_chunk(payload, false)]);
+        }
+
+        let chunk_size = self.max_message_size - overhead;
+        let count = payload.len().div_ceil(chunk_size);
+        let messages: Vec<M> = payload
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(index, data)| template.with_chunk(data, index + 1 < count))
+            .collect();
+
+        debug_assert!(messages
+            .iter()
+            .all(|message| message.encoded_len() <= self.max_message_size));
+
+        Ok(messages)
+    }
+}
+
+impl Default for ChunkedWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rebuilds payloads from the chunks received in order, stream by stream.
+#[derive(Debug)]
+pub struct ChunkedReader {
+    limits: DecodeLimits,
+    partial: BTreeMap<u32, Vec<u8>>,
+}
+
+impl ChunkedReader {
+    pub fn new() -> Self {
+        Self {
+            limits: DecodeLimits::default(),
+            partial: BTreeMap::new(),
+        }
+    }
+
+    /// Bounds the size of a rebuilt payload (`max_message_size`)
+    /// and the number of streams rebuilt at the same time (`max_collection_len`).
+    pub fn limits(self, limits: DecodeLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// Number of streams waiting for their last chunk.
+    pub fn pending_count(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Drops the chunks received for `stream_id`, such as when a transfer is aborted.
+    pub fn discard(&mut self, stream_id: u32) {
+        self.partial.remove(&stream_id);
+    }
+
+    /// Adds a received chunk. Returns the whole payload once its last chunk is received.
+    pub fn push<'a, M: ChunkMessage<'a>>(&mut self, message: &M) -> Result<Option<Vec<u8>>, ProtoError> {
+        let stream_id = message.stream_id();
+        if !self.partial.contains_key(&stream_id) {
+            if !message.has_continuation() {
+                return Ok(Some(message.chunk_data().to_vec()));
+            }
+            self.limits
+                .check_collection_len(self.partial.len() + 1, stringify!(ChunkedReader))
+                .or_desc("too many chunked payloads pending")?;
+        }
+
+        let payload = self.partial.entry(stream_id).or_default();
+        let size = payload.len() + message.chunk_data().len();
+        if size > self.limits.max_message_size {
+            self.partial.remove(&stream_id);
+            return ProtoError::new(ProtoErrorKind::LimitExceeded(stringify!(ChunkedReader))).or_else_desc(|| {
+                format!(
+                    "chunked payload size ({}) greater than message size limit ({})",
+                    size, self.limits.max_message_size
+                )
+            });
+        }
+        payload.extend_from_slice(message.chunk_data());
+
+        if message.has_continuation() {
+            Ok(None)
+        } else {
+            Ok(self.partial.remove(&stream_id))
+        }
+    }
+}
+
+impl Default for ChunkedReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::ChannelName;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn chunks_fit_in_max_message_size() {
+        let payload = payload(1000);
+        let writer = ChunkedWriter::new().max_message_size(112);
+        let template = NowClipboardFormatDataRspMsg::new(7, 13);
+        let chunks = writer.split(&template, &payload).unwrap();
+        assert_eq!(chunks.len(), 10);
+        assert!(chunks.iter().all(|chunk| chunk.encoded_len() <= 112));
+        assert!(chunks[..9].iter().all(ChunkMessage::has_continuation));
+        assert!(!chunks[9].has_continuation());
+        assert!(chunks.iter().all(|chunk| chunk.format_id == 13));
+
+        let mut reader = ChunkedReader::new();
+        for chunk in &chunks[..9] {
+            assert_eq!(reader.push(chunk).unwrap(), None);
+        }
+        assert_eq!(reader.pending_count(), 1);
+        assert_eq!(reader.push(&chunks[9]).unwrap(), Some(payload));
+        assert_eq!(reader.pending_count(), 0);
+
+        let single = writer.split(&template, &[]).unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(reader.push(&single[0]).unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn clipboard_data_sent_whole_without_continuation_support() {
+        let payload = payload(1000);
+        let writer = ChunkedWriter::new()
+            .max_message_size(112)
+            .version(NowProtocolVersion::new(3, 3, 0));
+        assert!(!writer.is_chunking::<NowClipboardFormatDataRspMsg>());
+        assert!(writer.is_chunking::<NowFileTransferDataMsg>());
+
+        let chunks = writer
+            .split(&NowClipboardFormatDataRspMsg::new(7, 13), &payload)
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert!(!chunks[0].has_continuation());
+        assert_eq!(&chunks[0].format_data[..], &payload[..]);
+    }
+
+    #[test]
+    fn streams_rebuilt_separately() {
+        let first = payload(300);
+        let second = payload(150);
+        let writer = ChunkedWriter::new().max_message_size(107);
+        let first_chunks = writer
+            .split(&NowFileTransferDataMsg::new(1, &[], true), &first)
+            .unwrap();
+        let second_chunks = writer
+            .split(&NowFileTransferDataMsg::new(2, &[], true), &second)
+            .unwrap();
+        assert!(first_chunks[first_chunks.len() - 1].flags.last());
+
+        let mut reader = ChunkedReader::new();
+        reader.push(&first_chunks[0]).unwrap();
+        reader.push(&second_chunks[0]).unwrap();
+        assert_eq!(reader.push(&second_chunks[1]).unwrap(), Some(second));
+        for chunk in &first_chunks[1..first_chunks.len() - 1] {
+            assert_eq!(reader.push(chunk).unwrap(), None);
+        }
+        assert_eq!(reader.push(&first_chunks[first_chunks.len() - 1]).unwrap(), Some(first));
+    }
+
+    #[test]
+    fn custom_payloads_prefixed_and_bounded() {
+        let payload = payload(250);
+        let template = CustomVirtualChannel::new(ChannelName::custom("AcmeTelemetry"), &[][..]);
+        let chunks = ChunkedWriter::new()
+            .max_message_size(101)
+            .split(&template, &payload)
+            .unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].payload[0], ChunkedWriter::CONTINUATION);
+        assert_eq!(chunks[2].payload.len(), 51);
+
+        let limits = DecodeLimits {
+            max_message_size: 200,
+            ..DecodeLimits::default()
+        };
+        let mut reader = ChunkedReader::new().limits(limits);
+        reader.push(&chunks[0]).unwrap();
+        reader.push(&chunks[1]).unwrap();
+        let err = reader.push(&chunks[2]).err().unwrap();
+        assert!(matches!(err.kind, ProtoErrorKind::LimitExceeded(_)));
+        assert_eq!(reader.pending_count(), 0);
+    }
+}
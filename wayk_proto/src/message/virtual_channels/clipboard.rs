@@ -7,6 +7,8 @@ use crate::{
 use num_derive::FromPrimitive;
 
 #[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum ClipboardMessageType {
     CapabilitiesReq = 0x01,
@@ -24,6 +26,8 @@ pub enum ClipboardMessageType {
 }
 
 #[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u16)]
 pub enum ClipboardControlState {
     None = 0x0000,
@@ -33,11 +37,14 @@ pub enum ClipboardControlState {
 
 __flags_struct! {
     ClipboardResponseFlags: u8 => {
+        continuation = CONTINUATION = 0x01, // format data continued by the next response (see `ProtocolFeature::ClipboardContinuation`)
         failure = FAILURE = 0x80,
     }
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct ClipboardFormatDef {
     pub id: u32,
     pub name: NowString256,
@@ -50,6 +57,8 @@ impl ClipboardFormatDef {
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[meta_enum = "ClipboardMessageType"]
 pub enum NowClipboardMsg<'a> {
     CapabilitiesReq(NowClipboardCapabilitiesReqMsg),
@@ -63,6 +72,7 @@ pub enum NowClipboardMsg<'a> {
     FormatListReq(NowClipboardFormatListReqMsg),
     FormatListRsp(NowClipboardFormatListRspMsg),
     FormatDataReq(NowClipboardFormatDataReqMsg),
+    #[cfg_attr(feature = "serde", serde(borrow))]
     FormatDataRsp(NowClipboardFormatDataRspMsg<'a>),
 
     #[decode_ignore]
@@ -150,7 +160,10 @@ impl From<NowClipboardFormatDataRspMsgOwned> for NowClipboardMsg<'_> {
 // subtypes
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardCapabilitiesReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     flags: u8,
     capabilities: u16,
@@ -175,7 +188,10 @@ impl NowClipboardCapabilitiesReqMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardCapabilitiesRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     pub flags: ClipboardResponseFlags,
     capabilities: u16,
@@ -200,7 +216,10 @@ impl NowClipboardCapabilitiesRspMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardControlReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     flags: u8,
     pub control_state: ClipboardControlState,
@@ -219,7 +238,10 @@ impl NowClipboardControlReqMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardControlRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     pub flags: ClipboardResponseFlags,
     pub control_state: ClipboardControlState,
@@ -242,7 +264,10 @@ impl NowClipboardControlRspMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardSuspendReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     flags: u8,
     reserved: u16,
@@ -263,7 +288,10 @@ impl NowClipboardSuspendReqMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardSuspendRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     pub flags: ClipboardResponseFlags,
     reserved: u16,
@@ -288,7 +316,10 @@ impl NowClipboardSuspendRspMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardResumeReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     flags: u8,
     reserved: u16,
@@ -309,7 +340,10 @@ impl NowClipboardResumeReqMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardResumeRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     pub flags: ClipboardResponseFlags,
     reserved: u16,
@@ -334,7 +368,10 @@ impl NowClipboardResumeRspMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardFormatListReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     flags: u8,
     pub sequence_id: u16,
@@ -359,7 +396,10 @@ impl NowClipboardFormatListReqMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardFormatListRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     pub flags: ClipboardResponseFlags,
     pub sequence_id: u16,
@@ -382,7 +422,10 @@ impl NowClipboardFormatListRspMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardFormatDataReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     flags: u8,
     pub sequence_id: u16,
@@ -403,11 +446,15 @@ impl NowClipboardFormatDataReqMsg {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardFormatDataRspMsg<'a> {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     pub flags: ClipboardResponseFlags,
     pub sequence_id: u16,
     pub format_id: u32,
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub format_data: Bytes32<'a>,
 }
 
@@ -440,7 +487,10 @@ impl<'a> NowClipboardFormatDataRspMsg<'a> {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub struct NowClipboardFormatDataRspMsgOwned {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
     subtype: ClipboardMessageType,
     pub flags: ClipboardResponseFlags,
     pub sequence_id: u16,
@@ -0,0 +1,326 @@
+// Device redirection
+
+use crate::{
+    container::Vec32,
+    message::{NowStatusCode, NowString64},
+};
+use num_derive::FromPrimitive;
+
+#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum DeviceMessageType {
+    Announce = 0x01,
+    AnnounceRsp = 0x02,
+    Remove = 0x03,
+    IoRequest = 0x04,
+    IoCompletion = 0x05,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[meta_enum = "DeviceMessageType"]
+pub enum NowDeviceMsg {
+    Announce(NowDeviceAnnounceMsg),
+    AnnounceRsp(NowDeviceAnnounceRspMsg),
+    Remove(NowDeviceRemoveMsg),
+    IoRequest(NowDeviceIoRequestMsg),
+    IoCompletion(NowDeviceIoCompletionMsg),
+}
+
+impl From<NowDeviceAnnounceMsg> for NowDeviceMsg {
+    fn from(msg: NowDeviceAnnounceMsg) -> Self {
+        Self::Announce(msg)
+    }
+}
+
+impl From<NowDeviceAnnounceRspMsg> for NowDeviceMsg {
+    fn from(msg: NowDeviceAnnounceRspMsg) -> Self {
+        Self::AnnounceRsp(msg)
+    }
+}
+
+impl From<NowDeviceRemoveMsg> for NowDeviceMsg {
+    fn from(msg: NowDeviceRemoveMsg) -> Self {
+        Self::Remove(msg)
+    }
+}
+
+impl From<NowDeviceIoRequestMsg> for NowDeviceMsg {
+    fn from(msg: NowDeviceIoRequestMsg) -> Self {
+        Self::IoRequest(msg)
+    }
+}
+
+impl From<NowDeviceIoCompletionMsg> for NowDeviceMsg {
+    fn from(msg: NowDeviceIoCompletionMsg) -> Self {
+        Self::IoCompletion(msg)
+    }
+}
+
+// subtypes
+
+#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum DeviceType {
+    /// Device whose requests are only understood by the application on both sides.
+    Generic = 0x00,
+    Smartcard = 0x01,
+    UsbHid = 0x02,
+}
+
+#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum DeviceIoFunction {
+    Open = 0x01,
+    Close = 0x02,
+    Read = 0x03,
+    Write = 0x04,
+    Ioctl = 0x05,
+}
+
+__flags_struct! {
+    DeviceAccessFlags: u32 => {
+        read = READ = 0x0000_0001,
+        write = WRITE = 0x0000_0002,
+        exclusive = EXCLUSIVE = 0x0000_0004,
+    }
+}
+
+/// Makes a device available to the receiver, which accepts or refuses it.
+///
+/// `data` describes the device in the format of its type, such as the report descriptor of a HID device.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDeviceAnnounceMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DeviceMessageType,
+    flags: u8,
+    pub device_id: u32,
+    pub device_type: DeviceType,
+    pub name: NowString64,
+    pub data: Vec32<u8>,
+}
+
+impl NowDeviceAnnounceMsg {
+    pub const SUBTYPE: DeviceMessageType = DeviceMessageType::Announce;
+
+    pub fn new(device_id: u32, device_type: DeviceType, name: NowString64, data: Vec<u8>) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            device_id,
+            device_type,
+            name,
+            data: Vec32(data),
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDeviceAnnounceRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DeviceMessageType,
+    flags: u8,
+    pub device_id: u32,
+    pub status: NowStatusCode,
+}
+
+impl NowDeviceAnnounceRspMsg {
+    pub const SUBTYPE: DeviceMessageType = DeviceMessageType::AnnounceRsp;
+
+    pub fn new(device_id: u32, status: NowStatusCode) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            device_id,
+            status,
+        }
+    }
+}
+
+/// The device is no longer available: its handles are closed and its pending requests dropped.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDeviceRemoveMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DeviceMessageType,
+    flags: u8,
+    pub device_id: u32,
+}
+
+impl NowDeviceRemoveMsg {
+    pub const SUBTYPE: DeviceMessageType = DeviceMessageType::Remove;
+
+    pub fn new(device_id: u32) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            device_id,
+        }
+    }
+}
+
+/// Operation on a device accepted by the sender, answered with a completion carrying the same `request_id`.
+///
+/// Fields are used depending on `function`:
+/// - open: `code` holds the [`DeviceAccessFlags`](struct.DeviceAccessFlags.html), the completion value is the handle
+/// - read: `length` bytes at `offset`, the completion data holds the bytes read
+/// - write: `data` at `offset`, the completion value is the number of bytes written
+/// - ioctl: control `code` with `data` as input, the completion data holding up to `length` bytes of output
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDeviceIoRequestMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DeviceMessageType,
+    flags: u8,
+    pub device_id: u32,
+    pub request_id: u32,
+    pub function: DeviceIoFunction,
+    pub handle: u32,
+    pub offset: u64,
+    pub length: u32,
+    pub code: u32,
+    pub data: Vec32<u8>,
+}
+
+impl NowDeviceIoRequestMsg {
+    pub const SUBTYPE: DeviceMessageType = DeviceMessageType::IoRequest;
+
+    fn __new(device_id: u32, request_id: u32, function: DeviceIoFunction, handle: u32) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            device_id,
+            request_id,
+            function,
+            handle,
+            offset: 0,
+            length: 0,
+            code: 0,
+            data: Vec32(Vec::new()),
+        }
+    }
+
+    pub fn open(device_id: u32, request_id: u32, access: DeviceAccessFlags) -> Self {
+        Self {
+            code: access.value,
+            ..Self::__new(device_id, request_id, DeviceIoFunction::Open, 0)
+        }
+    }
+
+    pub fn close(device_id: u32, request_id: u32, handle: u32) -> Self {
+        Self::__new(device_id, request_id, DeviceIoFunction::Close, handle)
+    }
+
+    pub fn read(device_id: u32, request_id: u32, handle: u32, offset: u64, length: u32) -> Self {
+        Self {
+            offset,
+            length,
+            ..Self::__new(device_id, request_id, DeviceIoFunction::Read, handle)
+        }
+    }
+
+    pub fn write(device_id: u32, request_id: u32, handle: u32, offset: u64, data: Vec<u8>) -> Self {
+        Self {
+            offset,
+            data: Vec32(data),
+            ..Self::__new(device_id, request_id, DeviceIoFunction::Write, handle)
+        }
+    }
+
+    pub fn ioctl(device_id: u32, request_id: u32, handle: u32, code: u32, input: Vec<u8>, max_output_len: u32) -> Self {
+        Self {
+            length: max_output_len,
+            code,
+            data: Vec32(input),
+            ..Self::__new(device_id, request_id, DeviceIoFunction::Ioctl, handle)
+        }
+    }
+
+    pub fn access(&self) -> DeviceAccessFlags {
+        DeviceAccessFlags::from(self.code)
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDeviceIoCompletionMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DeviceMessageType,
+    flags: u8,
+    pub device_id: u32,
+    pub request_id: u32,
+    pub status: NowStatusCode,
+    pub value: u32,
+    pub data: Vec32<u8>,
+}
+
+impl NowDeviceIoCompletionMsg {
+    pub const SUBTYPE: DeviceMessageType = DeviceMessageType::IoCompletion;
+
+    pub fn new(device_id: u32, request_id: u32, status: NowStatusCode, value: u32, data: Vec<u8>) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            device_id,
+            request_id,
+            status,
+            value,
+            data: Vec32(data),
+        }
+    }
+
+    pub fn failure(device_id: u32, request_id: u32, status: NowStatusCode) -> Self {
+        Self::new(device_id, request_id, status, 0, Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Decode, Encode};
+
+    #[test]
+    fn io_request_round_trip() {
+        let msg = NowDeviceMsg::from(NowDeviceIoRequestMsg::ioctl(
+            3,
+            9,
+            1,
+            0x0031_0030,
+            vec![0xca, 0xfe],
+            258,
+        ));
+        let encoded = msg.encode().unwrap();
+        assert_eq!(
+            encoded,
+            [
+                0x04, 0x00, 0x03, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01, 0x00, 0x00, 0x30, 0x00, 0x31, 0x00, 0x02, 0x00, 0x00,
+                0x00, 0xca, 0xfe,
+            ]
+        );
+
+        match NowDeviceMsg::decode(&encoded).unwrap() {
+            NowDeviceMsg::IoRequest(msg) => {
+                assert_eq!(msg.function, DeviceIoFunction::Ioctl);
+                assert_eq!(msg.code, 0x0031_0030);
+                assert_eq!(msg.length, 258);
+                assert_eq!(msg.data.0, vec![0xca, 0xfe]);
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+}
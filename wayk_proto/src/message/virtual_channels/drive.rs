@@ -0,0 +1,336 @@
+// Drive redirection
+
+use crate::{
+    container::{Vec16, Vec32},
+    message::{NowStatusCode, NowString256, NowString64},
+};
+use num_derive::FromPrimitive;
+
+#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum DriveMessageType {
+    Announce = 0x01,
+    Remove = 0x02,
+    Request = 0x03,
+    Response = 0x04,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[meta_enum = "DriveMessageType"]
+pub enum NowDriveMsg {
+    Announce(NowDriveAnnounceMsg),
+    Remove(NowDriveRemoveMsg),
+    Request(NowDriveRequestMsg),
+    Response(NowDriveResponseMsg),
+}
+
+impl From<NowDriveAnnounceMsg> for NowDriveMsg {
+    fn from(msg: NowDriveAnnounceMsg) -> Self {
+        Self::Announce(msg)
+    }
+}
+
+impl From<NowDriveRemoveMsg> for NowDriveMsg {
+    fn from(msg: NowDriveRemoveMsg) -> Self {
+        Self::Remove(msg)
+    }
+}
+
+impl From<NowDriveRequestMsg> for NowDriveMsg {
+    fn from(msg: NowDriveRequestMsg) -> Self {
+        Self::Request(msg)
+    }
+}
+
+impl From<NowDriveResponseMsg> for NowDriveMsg {
+    fn from(msg: NowDriveResponseMsg) -> Self {
+        Self::Response(msg)
+    }
+}
+
+// subtypes
+
+#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum DriveOperation {
+    Open = 0x01,
+    Close = 0x02,
+    Read = 0x03,
+    Write = 0x04,
+    Stat = 0x05,
+    Enumerate = 0x06,
+}
+
+__flags_struct! {
+    DriveFlags: u32 => {
+        read_only = READ_ONLY = 0x0000_0001,
+    }
+}
+
+__flags_struct! {
+    DriveOpenFlags: u32 => {
+        read = READ = 0x0000_0001,
+        write = WRITE = 0x0000_0002,
+        create = CREATE = 0x0000_0004, // file created if it doesn't exist
+        truncate = TRUNCATE = 0x0000_0008,
+    }
+}
+
+__flags_struct! {
+    DriveFileAttributes: u32 => {
+        directory = DIRECTORY = 0x0000_0001,
+        read_only = READ_ONLY = 0x0000_0002,
+    }
+}
+
+/// Exposes a directory of the sender to the receiver.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDriveAnnounceMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DriveMessageType,
+    flags: u8,
+    pub drive_id: u32,
+    pub drive_flags: DriveFlags,
+    pub name: NowString64,
+}
+
+impl NowDriveAnnounceMsg {
+    pub const SUBTYPE: DriveMessageType = DriveMessageType::Announce;
+
+    pub fn new(drive_id: u32, drive_flags: DriveFlags, name: NowString64) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            drive_id,
+            drive_flags,
+            name,
+        }
+    }
+}
+
+/// The drive is no longer exposed: its files are closed and pending requests dropped.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDriveRemoveMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DriveMessageType,
+    flags: u8,
+    pub drive_id: u32,
+}
+
+impl NowDriveRemoveMsg {
+    pub const SUBTYPE: DriveMessageType = DriveMessageType::Remove;
+
+    pub fn new(drive_id: u32) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            drive_id,
+        }
+    }
+}
+
+/// Operation on a drive exposed by the receiver, answered with a response carrying the same `request_id`.
+///
+/// Paths are relative to the root of the drive, components separated by `/`.
+/// Fields are used depending on `operation`:
+/// - open: `path` opened with `open_flags`, the response value is the handle
+/// - read: `length` bytes of `handle` at `offset`, the response data holds the bytes read
+/// - write: `data` to `handle` at `offset`, the response value is the number of bytes written
+/// - stat: the response holds the single entry of `path`
+/// - enumerate: up to `length` entries of the directory at `path` starting from index `offset`,
+///   sorted by name, the response value being the total number of entries
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDriveRequestMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DriveMessageType,
+    flags: u8,
+    pub drive_id: u32,
+    pub request_id: u32,
+    pub operation: DriveOperation,
+    pub handle: u32,
+    pub open_flags: DriveOpenFlags,
+    pub offset: u64,
+    pub length: u32,
+    pub path: NowString256,
+    pub data: Vec32<u8>,
+}
+
+impl NowDriveRequestMsg {
+    pub const SUBTYPE: DriveMessageType = DriveMessageType::Request;
+
+    fn __new(drive_id: u32, request_id: u32, operation: DriveOperation) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            drive_id,
+            request_id,
+            operation,
+            handle: 0,
+            open_flags: DriveOpenFlags::new_empty(),
+            offset: 0,
+            length: 0,
+            path: NowString256::new_empty(),
+            data: Vec32(Vec::new()),
+        }
+    }
+
+    pub fn open(drive_id: u32, request_id: u32, path: NowString256, open_flags: DriveOpenFlags) -> Self {
+        Self {
+            path,
+            open_flags,
+            ..Self::__new(drive_id, request_id, DriveOperation::Open)
+        }
+    }
+
+    pub fn close(drive_id: u32, request_id: u32, handle: u32) -> Self {
+        Self {
+            handle,
+            ..Self::__new(drive_id, request_id, DriveOperation::Close)
+        }
+    }
+
+    pub fn read(drive_id: u32, request_id: u32, handle: u32, offset: u64, length: u32) -> Self {
+        Self {
+            handle,
+            offset,
+            length,
+            ..Self::__new(drive_id, request_id, DriveOperation::Read)
+        }
+    }
+
+    pub fn write(drive_id: u32, request_id: u32, handle: u32, offset: u64, data: Vec<u8>) -> Self {
+        Self {
+            handle,
+            offset,
+            data: Vec32(data),
+            ..Self::__new(drive_id, request_id, DriveOperation::Write)
+        }
+    }
+
+    pub fn stat(drive_id: u32, request_id: u32, path: NowString256) -> Self {
+        Self {
+            path,
+            ..Self::__new(drive_id, request_id, DriveOperation::Stat)
+        }
+    }
+
+    pub fn enumerate(drive_id: u32, request_id: u32, path: NowString256, start: u32, max_entries: u32) -> Self {
+        Self {
+            path,
+            offset: u64::from(start),
+            length: max_entries,
+            ..Self::__new(drive_id, request_id, DriveOperation::Enumerate)
+        }
+    }
+}
+
+/// File or directory of a drive.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDriveFileInfo {
+    pub name: NowString256,
+    pub attributes: DriveFileAttributes,
+    pub size: u64,
+    /// Last modification, in seconds since the Unix epoch.
+    pub modified: u64,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowDriveResponseMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: DriveMessageType,
+    flags: u8,
+    pub request_id: u32,
+    pub status: NowStatusCode,
+    pub value: u32,
+    pub data: Vec32<u8>,
+    pub entries: Vec16<NowDriveFileInfo>,
+}
+
+impl NowDriveResponseMsg {
+    pub const SUBTYPE: DriveMessageType = DriveMessageType::Response;
+
+    pub fn new(request_id: u32, status: NowStatusCode) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            request_id,
+            status,
+            value: 0,
+            data: Vec32(Vec::new()),
+            entries: Vec16(Vec::new()),
+        }
+    }
+
+    pub fn value(self, value: u32) -> Self {
+        Self { value, ..self }
+    }
+
+    pub fn data(self, data: Vec<u8>) -> Self {
+        Self {
+            data: Vec32(data),
+            ..self
+        }
+    }
+
+    pub fn entries(self, entries: Vec<NowDriveFileInfo>) -> Self {
+        Self {
+            entries: Vec16(entries),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Decode, Encode};
+    use std::str::FromStr;
+
+    #[test]
+    fn stat_response_round_trip() {
+        let entry = NowDriveFileInfo {
+            name: NowString256::from_str("a").unwrap(),
+            attributes: DriveFileAttributes::new_empty().set_read_only(),
+            size: 3,
+            modified: 0x5f00_0000,
+        };
+        let msg = NowDriveMsg::from(NowDriveResponseMsg::new(4, NowStatusCode::Success).entries(vec![entry]));
+        let encoded = msg.encode().unwrap();
+        assert_eq!(
+            encoded,
+            [
+                0x04, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+                0x00, 0x01, 0x61, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x5f, 0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+
+        match NowDriveMsg::decode(&encoded).unwrap() {
+            NowDriveMsg::Response(msg) => {
+                assert_eq!(msg.request_id, 4);
+                assert_eq!(msg.status, NowStatusCode::Success);
+                assert_eq!(msg.entries.len(), 1);
+                assert_eq!(msg.entries[0].name.as_str(), "a");
+                assert!(msg.entries[0].attributes.read_only());
+                assert_eq!(msg.entries[0].size, 3);
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+}
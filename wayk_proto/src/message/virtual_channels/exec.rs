@@ -3,6 +3,8 @@
 use num_derive::FromPrimitive;
 
 #[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum ExecMessageType {
     CapsetReq = 0x00,
@@ -1,10 +1,320 @@
 // File Transfer
 
+use crate::{
+    container::{Bytes32, Vec8},
+    message::{NowStatusCode, NowString256, NowString65535},
+};
 use num_derive::FromPrimitive;
 
-#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy)]
+#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 #[repr(u8)]
 pub enum FileTransferMessageType {
     CapsetReq = 0x00,
+    Policy = 0x01,
+    DownloadReq = 0x02,
+    DownloadRsp = 0x03,
+    Data = 0x04,
+    Abort = 0x05,
     // TODO: FileTransferMessageType enum
 }
+
+#[derive(Debug, Clone, Encode, Decode)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[message_enum(tag = u8, subtype = FileTransferMessageType)]
+pub enum NowFileTransferMsg<'a> {
+    Policy(NowFileTransferPolicyMsg),
+    DownloadReq(NowFileTransferDownloadReqMsg),
+    DownloadRsp(NowFileTransferDownloadRspMsg),
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    Data(NowFileTransferDataMsg<'a>),
+    Abort(NowFileTransferAbortMsg),
+}
+
+impl From<NowFileTransferPolicyMsg> for NowFileTransferMsg<'_> {
+    fn from(msg: NowFileTransferPolicyMsg) -> Self {
+        Self::Policy(msg)
+    }
+}
+
+impl From<NowFileTransferDownloadReqMsg> for NowFileTransferMsg<'_> {
+    fn from(msg: NowFileTransferDownloadReqMsg) -> Self {
+        Self::DownloadReq(msg)
+    }
+}
+
+impl From<NowFileTransferDownloadRspMsg> for NowFileTransferMsg<'_> {
+    fn from(msg: NowFileTransferDownloadRspMsg) -> Self {
+        Self::DownloadRsp(msg)
+    }
+}
+
+impl<'a> From<NowFileTransferDataMsg<'a>> for NowFileTransferMsg<'a> {
+    fn from(msg: NowFileTransferDataMsg<'a>) -> Self {
+        Self::Data(msg)
+    }
+}
+
+impl From<NowFileTransferAbortMsg> for NowFileTransferMsg<'_> {
+    fn from(msg: NowFileTransferAbortMsg) -> Self {
+        Self::Abort(msg)
+    }
+}
+
+// subtypes
+
+__flags_struct! {
+    FileTransferPolicyFlags: u8 => {
+        max_file_size = MAX_FILE_SIZE = 0x01, // `max_file_size` field is meaningful
+        max_session_bytes = MAX_SESSION_BYTES = 0x02, // `max_session_bytes` field is meaningful
+        allowed_roots = ALLOWED_ROOTS = 0x04, // transfers are restricted to `allowed_roots`
+    }
+}
+
+/// Transfer policy set by the host. Sent once the file transfer channel is opened
+/// and again whenever the policy changes.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowFileTransferPolicyMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: FileTransferMessageType,
+    pub flags: FileTransferPolicyFlags,
+    reserved: u16,
+    pub max_file_size: u64,
+    pub max_session_bytes: u64,
+    pub allowed_roots: Vec8<NowString256>,
+}
+
+impl NowFileTransferPolicyMsg {
+    pub const SUBTYPE: FileTransferMessageType = FileTransferMessageType::Policy;
+    pub const REQUIRED_SIZE: usize = 21;
+
+    /// Policy without any restriction.
+    pub fn new_unrestricted() -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: FileTransferPolicyFlags::new_empty(),
+            reserved: 0,
+            max_file_size: 0,
+            max_session_bytes: 0,
+            allowed_roots: Vec8(Vec::new()),
+        }
+    }
+
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.flags.set_max_file_size();
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn max_session_bytes(mut self, max_session_bytes: u64) -> Self {
+        self.flags.set_max_session_bytes();
+        self.max_session_bytes = max_session_bytes;
+        self
+    }
+
+    pub fn allowed_roots(mut self, allowed_roots: Vec<NowString256>) -> Self {
+        self.flags.set_allowed_roots();
+        self.allowed_roots = Vec8(allowed_roots);
+        self
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowFileTransferDownloadReqMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: FileTransferMessageType,
+    flags: u8,
+    pub request_id: u16,
+    /// File size as known by the requester.
+    pub file_size: u64,
+    pub path: NowString65535,
+}
+
+impl NowFileTransferDownloadReqMsg {
+    pub const SUBTYPE: FileTransferMessageType = FileTransferMessageType::DownloadReq;
+
+    pub fn new(request_id: u16, file_size: u64, path: NowString65535) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            request_id,
+            file_size,
+            path,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowFileTransferDownloadRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: FileTransferMessageType,
+    flags: u8,
+    pub request_id: u16,
+    pub status: NowStatusCode,
+    /// Actual size of the file to be transferred.
+    pub file_size: u64,
+}
+
+impl NowFileTransferDownloadRspMsg {
+    pub const SUBTYPE: FileTransferMessageType = FileTransferMessageType::DownloadRsp;
+    pub const REQUIRED_SIZE: usize = 14;
+
+    pub fn new(request_id: u16, file_size: u64) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            request_id,
+            status: NowStatusCode::Success,
+            file_size,
+        }
+    }
+
+    pub fn new_with_status(request_id: u16, status: NowStatusCode) -> Self {
+        Self {
+            status,
+            ..Self::new(request_id, 0)
+        }
+    }
+}
+
+__flags_struct! {
+    FileTransferDataFlags: u8 => {
+        last = LAST = 0x01, // last chunk of the file
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowFileTransferDataMsg<'a> {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: FileTransferMessageType,
+    pub flags: FileTransferDataFlags,
+    pub request_id: u16,
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub data: Bytes32<'a>,
+}
+
+impl<'a> NowFileTransferDataMsg<'a> {
+    pub const SUBTYPE: FileTransferMessageType = FileTransferMessageType::Data;
+
+    pub fn new(request_id: u16, data: &'a [u8], last: bool) -> Self {
+        let mut flags = FileTransferDataFlags::new_empty();
+        if last {
+            flags.set_last();
+        }
+
+        Self {
+            subtype: Self::SUBTYPE,
+            flags,
+            request_id,
+            data: Bytes32(data),
+        }
+    }
+}
+
+/// Transfer interrupted by either side.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowFileTransferAbortMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: FileTransferMessageType,
+    flags: u8,
+    pub request_id: u16,
+    pub status: NowStatusCode,
+}
+
+impl NowFileTransferAbortMsg {
+    pub const SUBTYPE: FileTransferMessageType = FileTransferMessageType::Abort;
+    pub const REQUIRED_SIZE: usize = 6;
+
+    pub fn new(request_id: u16, status: NowStatusCode) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            request_id,
+            status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Decode, Encode};
+    use std::str::FromStr;
+
+    #[rustfmt::skip]
+    const POLICY_MSG: [u8; 28] = [
+        0x01, // subtype
+        0x05, // flags
+        0x00, 0x00, // reserved
+        0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, // max file size
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // max session bytes
+        0x01, // allowed roots count
+        0x05, 0x2f, 0x64, 0x61, 0x74, 0x61, 0x00, // "/data"
+    ];
+
+    #[rustfmt::skip]
+    const DOWNLOAD_RSP_MSG: [u8; 14] = [
+        0x03, // subtype
+        0x00, // flags
+        0x02, 0x00, // request id
+        0x06, 0x00, // status
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // file size
+    ];
+
+    #[test]
+    fn policy_decoding() {
+        let msg = NowFileTransferMsg::decode(&POLICY_MSG).unwrap();
+        if let NowFileTransferMsg::Policy(msg) = msg {
+            assert!(msg.flags.max_file_size());
+            assert!(!msg.flags.max_session_bytes());
+            assert!(msg.flags.allowed_roots());
+            assert_eq!(msg.max_file_size, 0x0010_0000);
+            assert_eq!(msg.allowed_roots.len(), 1);
+            assert_eq!(msg.allowed_roots[0], "/data");
+        } else {
+            panic!("expected a policy message and got {:?}", msg);
+        }
+    }
+
+    #[test]
+    fn policy_encoding() {
+        let msg = NowFileTransferMsg::from(
+            NowFileTransferPolicyMsg::new_unrestricted()
+                .max_file_size(0x0010_0000)
+                .allowed_roots(vec![NowString256::from_str("/data").unwrap()]),
+        );
+        assert_eq!(msg.encode().unwrap(), POLICY_MSG.to_vec());
+    }
+
+    #[test]
+    fn download_rsp_decoding() {
+        let msg = NowFileTransferMsg::decode(&DOWNLOAD_RSP_MSG).unwrap();
+        if let NowFileTransferMsg::DownloadRsp(msg) = msg {
+            assert_eq!(msg.request_id, 2);
+            assert_eq!(msg.status, NowStatusCode::PolicyDenied);
+        } else {
+            panic!("expected a download response message and got {:?}", msg);
+        }
+    }
+
+    #[test]
+    fn download_rsp_encoding() {
+        let msg = NowFileTransferMsg::from(NowFileTransferDownloadRspMsg::new_with_status(
+            2,
+            NowStatusCode::PolicyDenied,
+        ));
+        assert_eq!(msg.encode().unwrap(), DOWNLOAD_RSP_MSG.to_vec());
+    }
+}
@@ -1,14 +1,24 @@
 // ****** Virtual Channels ******
 
 pub mod chat;
+pub mod chunking;
 pub mod clipboard;
+pub mod device;
+pub mod drive;
 pub mod exec;
 pub mod file_transfer;
+pub mod printer;
+pub mod smartcard;
 pub mod tunnel;
 
 // re-export
 pub use chat::*;
+pub use chunking::*;
 pub use clipboard::*;
+pub use device::*;
+pub use drive::*;
 pub use exec::*;
 pub use file_transfer::*;
+pub use printer::*;
+pub use smartcard::*;
 pub use tunnel::*;
@@ -0,0 +1,325 @@
+// Printer redirection
+
+use crate::{
+    container::Vec32,
+    message::{NowStatusCode, NowString256, NowString64},
+};
+use num_derive::FromPrimitive;
+
+#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum PrinterMessageType {
+    Announce = 0x01,
+    Remove = 0x02,
+    JobStart = 0x03,
+    JobData = 0x04,
+    JobEnd = 0x05,
+    JobCancel = 0x06,
+    JobStatus = 0x07,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[meta_enum = "PrinterMessageType"]
+pub enum NowPrinterMsg {
+    Announce(NowPrinterAnnounceMsg),
+    Remove(NowPrinterRemoveMsg),
+    JobStart(NowPrintJobStartMsg),
+    JobData(NowPrintJobDataMsg),
+    JobEnd(NowPrintJobEndMsg),
+    JobCancel(NowPrintJobCancelMsg),
+    JobStatus(NowPrintJobStatusMsg),
+}
+
+impl From<NowPrinterAnnounceMsg> for NowPrinterMsg {
+    fn from(msg: NowPrinterAnnounceMsg) -> Self {
+        Self::Announce(msg)
+    }
+}
+
+impl From<NowPrinterRemoveMsg> for NowPrinterMsg {
+    fn from(msg: NowPrinterRemoveMsg) -> Self {
+        Self::Remove(msg)
+    }
+}
+
+impl From<NowPrintJobStartMsg> for NowPrinterMsg {
+    fn from(msg: NowPrintJobStartMsg) -> Self {
+        Self::JobStart(msg)
+    }
+}
+
+impl From<NowPrintJobDataMsg> for NowPrinterMsg {
+    fn from(msg: NowPrintJobDataMsg) -> Self {
+        Self::JobData(msg)
+    }
+}
+
+impl From<NowPrintJobEndMsg> for NowPrinterMsg {
+    fn from(msg: NowPrintJobEndMsg) -> Self {
+        Self::JobEnd(msg)
+    }
+}
+
+impl From<NowPrintJobCancelMsg> for NowPrinterMsg {
+    fn from(msg: NowPrintJobCancelMsg) -> Self {
+        Self::JobCancel(msg)
+    }
+}
+
+impl From<NowPrintJobStatusMsg> for NowPrinterMsg {
+    fn from(msg: NowPrintJobStatusMsg) -> Self {
+        Self::JobStatus(msg)
+    }
+}
+
+// subtypes
+
+/// Format of the spool data of a job.
+#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum PrintDataFormat {
+    /// Printer language of the driver, passed through untouched.
+    Raw = 0x00,
+    Pdf = 0x01,
+    Xps = 0x02,
+    PostScript = 0x03,
+}
+
+__flags_struct! {
+    PrinterFlags: u32 => {
+        default = DEFAULT = 0x0000_0001,
+        color = COLOR = 0x0000_0002,
+        duplex = DUPLEX = 0x0000_0004,
+    }
+}
+
+/// Makes a printer of the sender available to the receiver.
+///
+/// Announcing an id already announced replaces the printer.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowPrinterAnnounceMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: PrinterMessageType,
+    flags: u8,
+    pub printer_id: u32,
+    pub printer_flags: PrinterFlags,
+    /// Format expected for the jobs sent to this printer.
+    pub format: PrintDataFormat,
+    pub name: NowString64,
+    pub driver: NowString64,
+}
+
+impl NowPrinterAnnounceMsg {
+    pub const SUBTYPE: PrinterMessageType = PrinterMessageType::Announce;
+
+    pub fn new(
+        printer_id: u32,
+        printer_flags: PrinterFlags,
+        format: PrintDataFormat,
+        name: NowString64,
+        driver: NowString64,
+    ) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            printer_id,
+            printer_flags,
+            format,
+            name,
+            driver,
+        }
+    }
+}
+
+/// The printer is no longer available: jobs printing on it are aborted.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowPrinterRemoveMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: PrinterMessageType,
+    flags: u8,
+    pub printer_id: u32,
+}
+
+impl NowPrinterRemoveMsg {
+    pub const SUBTYPE: PrinterMessageType = PrinterMessageType::Remove;
+
+    pub fn new(printer_id: u32) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            printer_id,
+        }
+    }
+}
+
+/// Starts a job on a printer announced by the receiver, its spool data following in data messages.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowPrintJobStartMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: PrinterMessageType,
+    flags: u8,
+    pub job_id: u32,
+    pub printer_id: u32,
+    pub format: PrintDataFormat,
+    pub copies: u16,
+    pub document: NowString256,
+}
+
+impl NowPrintJobStartMsg {
+    pub const SUBTYPE: PrinterMessageType = PrinterMessageType::JobStart;
+
+    pub fn new(job_id: u32, printer_id: u32, format: PrintDataFormat, copies: u16, document: NowString256) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            job_id,
+            printer_id,
+            format,
+            copies,
+            document,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowPrintJobDataMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: PrinterMessageType,
+    flags: u8,
+    pub job_id: u32,
+    pub data: Vec32<u8>,
+}
+
+impl NowPrintJobDataMsg {
+    pub const SUBTYPE: PrinterMessageType = PrinterMessageType::JobData;
+
+    pub fn new(job_id: u32, data: Vec<u8>) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            job_id,
+            data: Vec32(data),
+        }
+    }
+}
+
+/// All the spool data of the job was sent, answered with its final status.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowPrintJobEndMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: PrinterMessageType,
+    flags: u8,
+    pub job_id: u32,
+}
+
+impl NowPrintJobEndMsg {
+    pub const SUBTYPE: PrinterMessageType = PrinterMessageType::JobEnd;
+
+    pub fn new(job_id: u32) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            job_id,
+        }
+    }
+}
+
+/// Aborts a job not ended yet. No status is sent back.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowPrintJobCancelMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: PrinterMessageType,
+    flags: u8,
+    pub job_id: u32,
+}
+
+impl NowPrintJobCancelMsg {
+    pub const SUBTYPE: PrinterMessageType = PrinterMessageType::JobCancel;
+
+    pub fn new(job_id: u32) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            job_id,
+        }
+    }
+}
+
+/// Final status of a job, sent once it is ended or as soon as it fails.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowPrintJobStatusMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: PrinterMessageType,
+    flags: u8,
+    pub job_id: u32,
+    pub status: NowStatusCode,
+}
+
+impl NowPrintJobStatusMsg {
+    pub const SUBTYPE: PrinterMessageType = PrinterMessageType::JobStatus;
+
+    pub fn new(job_id: u32, status: NowStatusCode) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            job_id,
+            status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Decode, Encode};
+    use std::str::FromStr;
+
+    #[test]
+    fn job_start_round_trip() {
+        let msg = NowPrinterMsg::from(NowPrintJobStartMsg::new(
+            7,
+            2,
+            PrintDataFormat::Pdf,
+            1,
+            NowString256::from_str("a.pdf").unwrap(),
+        ));
+        let encoded = msg.encode().unwrap();
+        assert_eq!(
+            encoded,
+            [
+                0x03, 0x00, 0x07, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x05, 0x61, 0x2e, 0x70,
+                0x64, 0x66, 0x00,
+            ]
+        );
+
+        match NowPrinterMsg::decode(&encoded).unwrap() {
+            NowPrinterMsg::JobStart(msg) => {
+                assert_eq!(msg.job_id, 7);
+                assert_eq!(msg.printer_id, 2);
+                assert_eq!(msg.format, PrintDataFormat::Pdf);
+                assert_eq!(msg.document.as_str(), "a.pdf");
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+}
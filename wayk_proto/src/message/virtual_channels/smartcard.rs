@@ -0,0 +1,113 @@
+// Smartcard redirection, carried over the device channel
+//
+// Readers are devices of type `DeviceType::Smartcard`: opening one connects to its card, closing it
+// disconnects, and the other PC/SC operations are control codes whose payloads are defined here.
+
+use crate::container::{Vec32, Vec8};
+use num_derive::FromPrimitive;
+
+/// Control codes of smartcard devices.
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SmartcardIoctl {
+    /// Input is the command APDU, output the response APDU.
+    Transmit = 0x0053_0001,
+    /// Output is a [`NowSmartcardStatus`](struct.NowSmartcardStatus.html).
+    Status = 0x0053_0002,
+    BeginTransaction = 0x0053_0003,
+    /// Input is the [`SmartcardDisposition`](enum.SmartcardDisposition.html) of the card.
+    EndTransaction = 0x0053_0004,
+    /// Input is a [`NowSmartcardControl`](struct.NowSmartcardControl.html), output the output of the reader.
+    Control = 0x0053_0005,
+}
+
+/// Action on the card when ending a transaction.
+#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum SmartcardDisposition {
+    Leave = 0x00,
+    Reset = 0x01,
+    Unpower = 0x02,
+    Eject = 0x03,
+}
+
+#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum SmartcardProtocol {
+    Undefined = 0x00,
+    T0 = 0x01,
+    T1 = 0x02,
+    Raw = 0x04,
+}
+
+__flags_struct! {
+    SmartcardStateFlags: u32 => {
+        present = PRESENT = 0x0000_0001,
+        powered = POWERED = 0x0000_0002,
+        exclusive = EXCLUSIVE = 0x0000_0004, // card connected exclusively by another application
+    }
+}
+
+/// Status of the card connected.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowSmartcardStatus {
+    pub state: SmartcardStateFlags,
+    pub protocol: SmartcardProtocol,
+    /// Answer to reset of the card.
+    pub atr: Vec8<u8>,
+}
+
+impl NowSmartcardStatus {
+    /// Encoded size with the largest ATR.
+    pub const MAX_SIZE: u32 = 4 + 1 + 1 + 255;
+
+    pub fn new(state: SmartcardStateFlags, protocol: SmartcardProtocol, atr: Vec<u8>) -> Self {
+        Self {
+            state,
+            protocol,
+            atr: Vec8(atr),
+        }
+    }
+}
+
+/// Control code of the reader itself, forwarded as is.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowSmartcardControl {
+    pub code: u32,
+    pub input: Vec32<u8>,
+}
+
+impl NowSmartcardControl {
+    pub fn new(code: u32, input: Vec<u8>) -> Self {
+        Self {
+            code,
+            input: Vec32(input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Decode, Encode};
+
+    #[test]
+    fn status_round_trip() {
+        let status = NowSmartcardStatus::new(
+            SmartcardStateFlags::new_empty().set_present().set_powered(),
+            SmartcardProtocol::T1,
+            vec![0x3b, 0x8f],
+        );
+        let encoded = status.encode().unwrap();
+        assert_eq!(encoded, [0x03, 0x00, 0x00, 0x00, 0x02, 0x02, 0x3b, 0x8f]);
+        assert_eq!(NowSmartcardStatus::decode(&encoded).unwrap(), status);
+    }
+}
@@ -1 +1,302 @@
 // Tunnel
+
+use crate::{
+    container::Vec32,
+    message::{NowStatusCode, NowString256},
+};
+use num_derive::FromPrimitive;
+
+#[derive(Encode, Decode, FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum TunnelMessageType {
+    Open = 0x01,
+    Data = 0x02,
+    Close = 0x03,
+    Listen = 0x04,
+    ListenRsp = 0x05,
+    Window = 0x06,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[meta_enum = "TunnelMessageType"]
+pub enum NowTunnelMsg {
+    Open(NowTunnelOpenMsg),
+    Data(NowTunnelDataMsg),
+    Close(NowTunnelCloseMsg),
+    Listen(NowTunnelListenMsg),
+    ListenRsp(NowTunnelListenRspMsg),
+    Window(NowTunnelWindowMsg),
+}
+
+impl From<NowTunnelOpenMsg> for NowTunnelMsg {
+    fn from(msg: NowTunnelOpenMsg) -> Self {
+        Self::Open(msg)
+    }
+}
+
+impl From<NowTunnelDataMsg> for NowTunnelMsg {
+    fn from(msg: NowTunnelDataMsg) -> Self {
+        Self::Data(msg)
+    }
+}
+
+impl From<NowTunnelCloseMsg> for NowTunnelMsg {
+    fn from(msg: NowTunnelCloseMsg) -> Self {
+        Self::Close(msg)
+    }
+}
+
+impl From<NowTunnelListenMsg> for NowTunnelMsg {
+    fn from(msg: NowTunnelListenMsg) -> Self {
+        Self::Listen(msg)
+    }
+}
+
+impl From<NowTunnelListenRspMsg> for NowTunnelMsg {
+    fn from(msg: NowTunnelListenRspMsg) -> Self {
+        Self::ListenRsp(msg)
+    }
+}
+
+impl From<NowTunnelWindowMsg> for NowTunnelMsg {
+    fn from(msg: NowTunnelWindowMsg) -> Self {
+        Self::Window(msg)
+    }
+}
+
+// subtypes
+
+__flags_struct! {
+    TunnelFlags: u8 => {
+        initiator = INITIATOR = 0x01, // stream opened by the sender: ids of both peers don't collide
+        target = TARGET = 0x02, // `target` field is meaningful
+    }
+}
+
+impl TunnelFlags {
+    fn from_initiator(initiator: bool) -> Self {
+        let mut flags = Self::new_empty();
+        if initiator {
+            flags.set_initiator();
+        }
+        flags
+    }
+}
+
+/// Opens a stream, identified by the sender with `stream_id`.
+///
+/// With a `target` (`host:port`), the receiver is asked to connect to it and to bridge the connection
+/// with the stream, as done for port forwarding.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowTunnelOpenMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: TunnelMessageType,
+    pub flags: TunnelFlags,
+    pub stream_id: u16,
+    pub target: NowString256,
+}
+
+impl NowTunnelOpenMsg {
+    pub const SUBTYPE: TunnelMessageType = TunnelMessageType::Open;
+
+    pub fn new(stream_id: u16) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: TunnelFlags::from_initiator(true),
+            stream_id,
+            target: NowString256::new_empty(),
+        }
+    }
+
+    pub fn new_with_target(stream_id: u16, target: NowString256) -> Self {
+        let mut flags = TunnelFlags::from_initiator(true);
+        flags.set_target();
+        Self {
+            subtype: Self::SUBTYPE,
+            flags,
+            stream_id,
+            target,
+        }
+    }
+
+    pub fn get_target(&self) -> Option<&str> {
+        if self.flags.target() {
+            Some(self.target.as_str())
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowTunnelDataMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: TunnelMessageType,
+    pub flags: TunnelFlags,
+    pub stream_id: u16,
+    pub data: Vec32<u8>,
+}
+
+impl NowTunnelDataMsg {
+    pub const SUBTYPE: TunnelMessageType = TunnelMessageType::Data;
+
+    pub fn new(stream_id: u16, initiator: bool, data: Vec<u8>) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: TunnelFlags::from_initiator(initiator),
+            stream_id,
+            data: Vec32(data),
+        }
+    }
+}
+
+/// No more data is sent over the stream by the sender.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowTunnelCloseMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: TunnelMessageType,
+    pub flags: TunnelFlags,
+    pub stream_id: u16,
+}
+
+impl NowTunnelCloseMsg {
+    pub const SUBTYPE: TunnelMessageType = TunnelMessageType::Close;
+
+    pub fn new(stream_id: u16, initiator: bool) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: TunnelFlags::from_initiator(initiator),
+            stream_id,
+        }
+    }
+}
+
+/// Asks the receiver to listen on `bind_port` and to open a stream to `target` for each connection
+/// accepted, `target` being reachable from the sender (remote port forwarding).
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowTunnelListenMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: TunnelMessageType,
+    flags: u8,
+    pub forward_id: u16,
+    pub bind_port: u16,
+    pub target: NowString256,
+}
+
+impl NowTunnelListenMsg {
+    pub const SUBTYPE: TunnelMessageType = TunnelMessageType::Listen;
+
+    pub fn new(forward_id: u16, bind_port: u16, target: NowString256) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            forward_id,
+            bind_port,
+            target,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowTunnelListenRspMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: TunnelMessageType,
+    flags: u8,
+    pub forward_id: u16,
+    pub status: NowStatusCode,
+}
+
+impl NowTunnelListenRspMsg {
+    pub const SUBTYPE: TunnelMessageType = TunnelMessageType::ListenRsp;
+
+    pub fn new(forward_id: u16, status: NowStatusCode) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: 0,
+            forward_id,
+            status,
+        }
+    }
+}
+
+/// Grants credits to the sender of the stream, once the data received was read.
+///
+/// Each peer may send `TunnelChannel::RECEIVE_WINDOW` bytes over a stream it opened or accepted, then
+/// only as many bytes as it was granted since.
+#[derive(Encode, Decode, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowTunnelWindowMsg {
+    #[cfg_attr(feature = "fuzzing", arbitrary(value = Self::SUBTYPE))]
+    subtype: TunnelMessageType,
+    pub flags: TunnelFlags,
+    pub stream_id: u16,
+    pub credits: u32,
+}
+
+impl NowTunnelWindowMsg {
+    pub const SUBTYPE: TunnelMessageType = TunnelMessageType::Window;
+
+    pub fn new(stream_id: u16, initiator: bool, credits: u32) -> Self {
+        Self {
+            subtype: Self::SUBTYPE,
+            flags: TunnelFlags::from_initiator(initiator),
+            stream_id,
+            credits,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{Decode, Encode};
+
+    #[test]
+    fn data_round_trip() {
+        let encoded = NowTunnelMsg::from(NowTunnelDataMsg::new(7, false, vec![0xde, 0xad]))
+            .encode()
+            .unwrap();
+        assert_eq!(encoded, [0x02, 0x00, 0x07, 0x00, 0x02, 0x00, 0x00, 0x00, 0xde, 0xad]);
+
+        match NowTunnelMsg::decode(&encoded).unwrap() {
+            NowTunnelMsg::Data(msg) => {
+                assert_eq!(msg.stream_id, 7);
+                assert!(!msg.flags.initiator());
+                assert_eq!(msg.data.0, vec![0xde, 0xad]);
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn window_round_trip() {
+        let encoded = NowTunnelMsg::from(NowTunnelWindowMsg::new(3, true, 0x8000))
+            .encode()
+            .unwrap();
+        assert_eq!(encoded, [0x06, 0x01, 0x03, 0x00, 0x00, 0x80, 0x00, 0x00]);
+
+        match NowTunnelMsg::decode(&encoded).unwrap() {
+            NowTunnelMsg::Window(msg) => {
+                assert_eq!(msg.stream_id, 3);
+                assert!(msg.flags.initiator());
+                assert_eq!(msg.credits, 0x8000);
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Hooks observing or rewriting messages, for policy enforcement, logging or protocol experimentation.
+//!
+//! Hooks registered on a sharee (see `Sharee::add_hook`) are called in registration order, each one
+//! seeing the message as left by the previous ones:
+//! - with every received body, before it is dispatched;
+//! - with every packet about to be encoded: answers of the sharee, and packets sent by the application
+//!   through the connection or the client.
+
+use crate::{
+    header::{AbstractNowHeader, NowHeader},
+    message::{BodyType, NowBody},
+    packet::NowPacket,
+};
+
+#[derive(Debug, Clone)]
+pub enum HookAction {
+    Forward,
+    /// Message is neither dispatched nor sent.
+    Drop,
+    /// Message is replaced by the given body. Outgoing virtual channel messages keep their channel id:
+    /// a now message can't be replaced by a virtual channel message.
+    Replace(NowBody<'static>),
+}
+
+pub trait MessageHook {
+    /// called with each received body, before it is dispatched.
+    fn on_incoming(&mut self, body: &NowBody<'_>) -> HookAction {
+        #![allow(unused_variables)]
+        HookAction::Forward
+    }
+
+    /// called with each body about to be encoded and sent.
+    fn on_outgoing(&mut self, body: &NowBody<'_>) -> HookAction {
+        #![allow(unused_variables)]
+        HookAction::Forward
+    }
+}
+
+sa::assert_obj_safe!(MessageHook);
+
+/// Logs every message at trace level.
+pub struct LoggingHook;
+impl MessageHook for LoggingHook {
+    fn on_incoming(&mut self, body: &NowBody<'_>) -> HookAction {
+        log::trace!("incoming: {:?}", body);
+        HookAction::Forward
+    }
+
+    fn on_outgoing(&mut self, body: &NowBody<'_>) -> HookAction {
+        log::trace!("outgoing: {:?}", body);
+        HookAction::Forward
+    }
+}
+
+/// Chain of hooks, called in registration order.
+#[derive(Default)]
+pub struct MessageHooks {
+    hooks: Vec<Box<dyn MessageHook>>,
+}
+
+impl MessageHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add<H: MessageHook + 'static>(&mut self, hook: H) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Outcome of the whole chain for a received body.
+    pub fn on_incoming(&mut self, body: &NowBody<'_>) -> HookAction {
+        self.__run(body, |hook, body| hook.on_incoming(body))
+    }
+
+    /// Packet to send once the chain ran, `None` if dropped.
+    pub fn on_outgoing<'a>(&mut self, packet: NowPacket<'a>) -> Option<NowPacket<'a>> {
+        match self.__run(&packet.body, |hook, body| hook.on_outgoing(body)) {
+            HookAction::Forward => Some(packet),
+            HookAction::Drop => None,
+            HookAction::Replace(body) => __repack(&packet.header, body),
+        }
+    }
+
+    fn __run<F>(&mut self, body: &NowBody<'_>, mut call: F) -> HookAction
+    where
+        F: FnMut(&mut dyn MessageHook, &NowBody<'_>) -> HookAction,
+    {
+        let mut replaced: Option<NowBody<'static>> = None;
+        for hook in &mut self.hooks {
+            let current = replaced.as_ref().unwrap_or(body);
+            match call(hook.as_mut(), current) {
+                HookAction::Forward => {}
+                HookAction::Drop => return HookAction::Drop,
+                HookAction::Replace(body) => replaced = Some(body),
+            }
+        }
+
+        match replaced {
+            Some(body) => HookAction::Replace(body),
+            None => HookAction::Forward,
+        }
+    }
+}
+
+fn __repack<'a>(header: &NowHeader, body: NowBody<'static>) -> Option<NowPacket<'a>> {
+    match (body, header.body_type()) {
+        (NowBody::Message(msg), _) => Some(NowPacket::from_message(msg)),
+        (NowBody::VirtualChannel(chan_msg), BodyType::VirtualChannel(id)) => {
+            Some(NowPacket::from_virt_channel(chan_msg, id))
+        }
+        (NowBody::VirtualChannel(chan_msg), BodyType::Message(_)) => {
+            log::warn!(
+                "{:?} message replacing a now message dropped: no channel to send it over",
+                chan_msg.get_name()
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{NowChatMsg, NowChatPokeMsg, NowMessage, NowTerminateMsg, NowVirtualChannel};
+
+    struct DropTerminate;
+    impl MessageHook for DropTerminate {
+        fn on_outgoing(&mut self, body: &NowBody<'_>) -> HookAction {
+            match body {
+                NowBody::Message(NowMessage::Terminate(_)) => HookAction::Drop,
+                _ => HookAction::Forward,
+            }
+        }
+    }
+
+    struct RewritePoke;
+    impl MessageHook for RewritePoke {
+        fn on_outgoing(&mut self, body: &NowBody<'_>) -> HookAction {
+            match body {
+                NowBody::VirtualChannel(NowVirtualChannel::Chat(NowChatMsg::Poke(poke))) if poke.timestamp == 0 => {
+                    HookAction::Replace(NowBody::VirtualChannel(NowChatPokeMsg::new(1).into()))
+                }
+                _ => HookAction::Forward,
+            }
+        }
+    }
+
+    #[test]
+    fn chain_rewrites_and_drops() {
+        let mut hooks = MessageHooks::new();
+        hooks.add(LoggingHook);
+        hooks.add(RewritePoke);
+        hooks.add(DropTerminate);
+
+        assert!(hooks.on_outgoing(NowTerminateMsg::default().into()).is_none());
+
+        let packet = hooks
+            .on_outgoing(NowPacket::from_virt_channel(NowChatPokeMsg::new(0), 3))
+            .unwrap();
+        assert_eq!(packet.header.body_type(), BodyType::VirtualChannel(3));
+        match packet.body {
+            NowBody::VirtualChannel(NowVirtualChannel::Chat(NowChatMsg::Poke(poke))) => assert_eq!(poke.timestamp, 1),
+            body => panic!("unexpected body: {:?}", body),
+        }
+    }
+}
@@ -1,8 +1,8 @@
 use crate::{
-    error::{ProtoErrorKind, ProtoErrorResultExt, Result},
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt, Result},
     header::{AbstractNowHeader, NowHeader, NowLongHeader},
     message::{BodyType, MessageType, NowBody, NowMessage, NowVirtualChannel, VirtChannelsCtx},
-    serialization::{Decode, Encode},
+    serialization::{Decode, DecodeLimits, Encode},
 };
 use std::{
     io::{Cursor, Read, Write},
@@ -83,6 +83,13 @@ impl<'a> NowPacket<'a> {
             NowMessage::Update(msg) => NowHeader::new_with_msg_type(MessageType::Update, msg.encoded_len() as u32),
             NowMessage::System(msg) => NowHeader::new_with_msg_type(MessageType::System, msg.encoded_len() as u32),
             NowMessage::Sharing(msg) => NowHeader::new_with_msg_type(MessageType::Sharing, msg.encoded_len() as u32),
+            NowMessage::Network(msg) => NowHeader::new_with_msg_type(MessageType::Network, msg.encoded_len() as u32),
+            NowMessage::Desktop(msg) => NowHeader::new_with_msg_type(MessageType::Desktop, msg.encoded_len() as u32),
+            NowMessage::Session(msg) => NowHeader::new_with_msg_type(MessageType::Session, msg.encoded_len() as u32),
+            NowMessage::Batch(msg) => NowHeader::new_with_msg_type(MessageType::Batch, msg.encoded_len() as u32),
+            NowMessage::ChannelWindow(msg) => {
+                NowHeader::new_with_msg_type(MessageType::ChannelWindow, msg.encoded_len() as u32)
+            }
         };
 
         Self {
@@ -108,6 +115,7 @@ impl<'a> NowPacket<'a> {
     ) -> Result<Self> {
         let header = NowHeader::read_from(reader)?;
         let message_len = header.body_len();
+        check_body_len(message_len, channels_ctx.decode_limits())?;
 
         buffer.clear();
         if buffer.capacity() < message_len {
@@ -125,7 +133,11 @@ impl<'a> NowPacket<'a> {
     ) -> Result<Self> {
         let mut cursor = Cursor::new(buffer);
         let body = match header.body_type() {
-            BodyType::Message(msg_type) => NowBody::Message(NowMessage::decode_from(msg_type, &mut cursor)?),
+            BodyType::Message(msg_type) => NowBody::Message(NowMessage::decode_with_ctx(
+                msg_type,
+                &mut cursor,
+                &channels_ctx.decode_ctx(),
+            )?),
             BodyType::VirtualChannel(id) => {
                 let channel_name = channels_ctx
                     .get_channel_by_id(id)
@@ -192,6 +204,10 @@ impl NowPacketAccumulator<'_> {
             Err(err) => return Some(Err(err)),
         };
 
+        if let Err(err) = check_body_len(header.body_len(), channels_ctx.decode_limits()) {
+            return Some(Err(err));
+        }
+
         let packet_len = header.body_len() + header.len();
         if self.buffer.len() >= self.cursor + packet_len {
             let header_len = header.len();
@@ -208,10 +224,27 @@ impl NowPacketAccumulator<'_> {
     }
 }
 
+/// Rejects a body before buffering it if it exceeds the decode limits.
+pub(crate) fn check_body_len(body_len: usize, limits: DecodeLimits) -> Result<()> {
+    let max_message_size = limits.max_message_size;
+    if body_len > max_message_size {
+        return ProtoError::new(ProtoErrorKind::LimitExceeded("NowPacket")).or_else_desc(|| {
+            format!(
+                "body length ({}) greater than message size limit ({})",
+                body_len, max_message_size
+            )
+        });
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::message::{AuthType, NowBody, VirtChannelsCtx};
+    use crate::{
+        message::{AuthType, DecodeMode, NowBody, NowSurfaceMsg, VirtChannelsCtx},
+        serialization::DecodeLimits,
+    };
 
     #[rustfmt::skip]
     const NEGOTIATE_PACKET: [u8; 11] = [
@@ -265,4 +298,50 @@ mod tests {
         assert_eq!(acc.cursor, 0);
         assert_eq!(acc.buffer.len(), 0);
     }
+
+    #[test]
+    fn body_exceeding_message_size_limit() {
+        let mut chan_ctx = VirtChannelsCtx::new();
+        chan_ctx.set_decode_limits(DecodeLimits {
+            max_message_size: 6,
+            ..DecodeLimits::default()
+        });
+
+        let mut buffer = Vec::new();
+        let err = NowPacket::read_from(&mut &NEGOTIATE_PACKET[..], &mut buffer, &chan_ctx)
+            .err()
+            .unwrap();
+        assert!(matches!(err.kind, ProtoErrorKind::LimitExceeded("NowPacket")));
+        assert!(buffer.is_empty());
+
+        let mut acc = NowPacketAccumulator::new();
+        acc.accumulate(&NEGOTIATE_PACKET);
+        assert!(acc.next_packet(&chan_ctx).unwrap().is_err());
+
+        chan_ctx.set_decode_limits(DecodeLimits::default());
+        assert!(acc.next_packet(&chan_ctx).unwrap().is_ok());
+    }
+
+    #[test]
+    fn unknown_surface_subtype_decoding_mode() {
+        let packet = NowPacket::from_message(NowSurfaceMsg::Unknown {
+            subtype: 0x42,
+            payload: vec![0x01, 0x02],
+        });
+        let bytes = packet.encode().unwrap();
+
+        let mut chan_ctx = VirtChannelsCtx::new();
+        let mut buffer = Vec::new();
+        assert!(NowPacket::read_from(&mut bytes.as_slice(), &mut buffer, &chan_ctx).is_err());
+
+        chan_ctx.set_decode_mode(DecodeMode::Permissive);
+        let packet = NowPacket::read_from(&mut bytes.as_slice(), &mut buffer, &chan_ctx).unwrap();
+        match packet.body {
+            NowBody::Message(NowMessage::Surface(NowSurfaceMsg::Unknown { subtype, payload })) => {
+                assert_eq!(subtype, 0x42);
+                assert_eq!(payload, vec![0x01, 0x02]);
+            }
+            other => panic!("expected an unknown surface message and got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,261 @@
+//! Machine-readable description of the wire layout of messages.
+//!
+//! Layouts of derived types are generated by the `Encode` derive, hand-written codecs describe
+//! theirs manually. Named types are collected once in a [`Schema`] that can be dumped as JSON.
+
+use crate::message::{
+    NowChatMsg, NowClipboardMsg, NowDeviceMsg, NowDriveMsg, NowFileTransferMsg, NowMessage, NowPrinterMsg, NowTunnelMsg,
+};
+use core::num::{NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Wire layout of a type.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TypeSchema {
+    /// Named type defined in the schema.
+    Ref { name: &'static str },
+    /// Little endian integer or array of integers.
+    Primitive { name: &'static str, size: usize },
+    /// Bit flags stored in an integer.
+    Flags {
+        repr: &'static str,
+        size: usize,
+        flags: Vec<NamedValue>,
+    },
+    /// Integer enumeration. Unknown values are preserved if `open`.
+    Enum {
+        repr: &'static str,
+        size: usize,
+        variants: Vec<NamedValue>,
+        open: bool,
+    },
+    Struct {
+        size: Option<usize>,
+        fields: Vec<FieldSchema>,
+    },
+    /// Message family dispatched on a leading subtype (or on the header message type if `tag` is none).
+    Message {
+        tag: Option<Box<TypeSchema>>,
+        variants: Vec<MessageVariantSchema>,
+    },
+    /// Items prefixed by their count.
+    List {
+        count: Box<TypeSchema>,
+        item: Box<TypeSchema>,
+    },
+    /// Null-terminated string, prefixed by its length in code units if any.
+    String {
+        encoding: &'static str,
+        length: Option<Box<TypeSchema>>,
+        max_len: Option<usize>,
+    },
+    /// Status packed in a u32: severity (2 bits), status type (8 bits) and code (16 bits).
+    Status { code: Box<TypeSchema> },
+    /// Layout not expressible in this schema.
+    Opaque { description: &'static str },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NamedValue {
+    pub name: &'static str,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldSchema {
+    pub name: &'static str,
+    pub ty: TypeSchema,
+    /// Condition on a previous flags field for the field to be present on the wire.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub present_if: Option<&'static str>,
+    /// Field holding the full size of the struct.
+    #[serde(skip_serializing_if = "core::ops::Not::not")]
+    pub size_field: bool,
+    /// Zero padding before the field so that it starts at a multiple of this offset in the struct.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub align: Option<usize>,
+    /// Zero padding after the field up to this size.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pad_to: Option<usize>,
+}
+
+impl FieldSchema {
+    pub fn new(name: &'static str, ty: TypeSchema) -> Self {
+        Self {
+            name,
+            ty,
+            present_if: None,
+            size_field: false,
+            align: None,
+            pad_to: None,
+        }
+    }
+
+    pub fn present_if(self, condition: &'static str) -> Self {
+        Self {
+            present_if: Some(condition),
+            ..self
+        }
+    }
+
+    pub fn size_field(self) -> Self {
+        Self {
+            size_field: true,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MessageVariantSchema {
+    pub name: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<u64>,
+    pub ty: TypeSchema,
+}
+
+/// Types with a known wire layout.
+pub trait WireSchema {
+    /// Describes the type, defining named types it depends on in `schema`.
+    fn describe(schema: &mut Schema) -> TypeSchema;
+}
+
+/// Optional fields are described by their inner type (see `FieldSchema::present_if`).
+impl<T: WireSchema> WireSchema for Option<T> {
+    fn describe(schema: &mut Schema) -> TypeSchema {
+        T::describe(schema)
+    }
+}
+
+impl<T: WireSchema> WireSchema for Box<T> {
+    fn describe(schema: &mut Schema) -> TypeSchema {
+        T::describe(schema)
+    }
+}
+
+/// Raw bytes spanning the rest of the message.
+impl WireSchema for &[u8] {
+    fn describe(_: &mut Schema) -> TypeSchema {
+        TypeSchema::Opaque {
+            description: "remaining bytes",
+        }
+    }
+}
+
+macro_rules! impl_primitive_schema {
+    ($($ty:ty),+) => {
+        $(
+            impl WireSchema for $ty {
+                fn describe(_: &mut Schema) -> TypeSchema {
+                    TypeSchema::Primitive {
+                        name: stringify!($ty),
+                        size: std::mem::size_of::<$ty>(),
+                    }
+                }
+            }
+        )+
+    };
+}
+
+impl_primitive_schema!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, [u32; 4]);
+impl_primitive_schema!(NonZeroU8, NonZeroU16, NonZeroU32, NonZeroU64);
+
+/// Named types of the protocol.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct Schema {
+    pub types: BTreeMap<&'static str, TypeSchema>,
+}
+
+impl Schema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `T` along with every type it depends on.
+    pub fn register<T: WireSchema>(&mut self) -> TypeSchema {
+        T::describe(self)
+    }
+
+    /// Defines a named type once and returns a reference to it.
+    ///
+    /// The name is reserved before describing the type so that recursive types terminate.
+    pub fn define<F>(&mut self, name: &'static str, describe: F) -> TypeSchema
+    where
+        F: FnOnce(&mut Self) -> TypeSchema,
+    {
+        if !self.types.contains_key(name) {
+            self.types.insert(name, TypeSchema::Opaque { description: "" });
+            let ty = describe(self);
+            self.types.insert(name, ty);
+        }
+
+        TypeSchema::Ref { name }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TypeSchema> {
+        self.types.get(name)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("schema serialization can't fail")
+    }
+}
+
+/// Schema of every message and virtual channel message carried by a packet.
+pub fn protocol_schema() -> Schema {
+    let mut schema = Schema::new();
+    schema.register::<NowMessage<'_>>();
+    schema.register::<NowClipboardMsg<'_>>();
+    schema.register::<NowChatMsg>();
+    schema.register::<NowFileTransferMsg<'_>>();
+    schema.register::<NowTunnelMsg>();
+    schema.register::<NowDeviceMsg>();
+    schema.register::<NowPrinterMsg>();
+    schema.register::<NowDriveMsg>();
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_schema_layouts() {
+        let schema = protocol_schema();
+
+        match schema.get("NowSurfaceMsg").unwrap() {
+            TypeSchema::Message { tag, variants } => {
+                assert_eq!(**tag.as_ref().unwrap(), TypeSchema::Primitive { name: "u8", size: 1 });
+                let list_req = variants.iter().find(|variant| variant.name == "ListReq").unwrap();
+                assert_eq!(list_req.tag, Some(0x01));
+                assert_eq!(
+                    list_req.ty,
+                    TypeSchema::Ref {
+                        name: "NowSurfaceListReqMsg"
+                    }
+                );
+            }
+            other => panic!("expected a message schema and got {:?}", other),
+        }
+
+        match schema.get("NowAssociateRequestMsg").unwrap() {
+            TypeSchema::Struct { fields, .. } => {
+                let token = fields.iter().find(|field| field.name == "handoff_token").unwrap();
+                assert_eq!(token.present_if, Some("flags & AssociateRequestFlags::HANDOFF"));
+            }
+            other => panic!("expected a struct schema and got {:?}", other),
+        }
+
+        match schema.get("NowSurfaceMapRspMsg").unwrap() {
+            TypeSchema::Struct { fields, .. } => assert_eq!(fields.len(), 4),
+            other => panic!("expected a struct schema and got {:?}", other),
+        }
+
+        let json: serde_json::Value = serde_json::from_str(&schema.to_json()).unwrap();
+        assert_eq!(json["NowHandshakeMsg"]["kind"], "struct");
+        assert_eq!(json["SurfaceResponseFlags"]["flags"][0]["name"], "EXTENDED_STATUS");
+    }
+}
@@ -0,0 +1,628 @@
+use crate::{
+    error::Result,
+    message::{
+        BodyType, ChannelName, MessageType, NowBody, NowFileTransferMsg, NowMessage, NowPrinterMsg, NowTunnelMsg,
+        NowVirtualChannel,
+    },
+    packet::NowPacket,
+    serialization::Encode,
+};
+use alloc::collections::{BTreeMap, VecDeque};
+use core::cmp::Reverse;
+use std::time::{Duration, Instant};
+
+/// Scheduling class of an outgoing packet. Lower classes are sent first.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum TrafficClass {
+    /// Input and network control: latency matters more than anything.
+    Realtime,
+    /// Session control, surface updates and chat.
+    Interactive,
+    /// Large transfers (clipboard data, custom channels payloads...).
+    Bulk,
+}
+
+impl TrafficClass {
+    pub const ALL: [TrafficClass; 3] = [TrafficClass::Realtime, TrafficClass::Interactive, TrafficClass::Bulk];
+
+    pub fn of_packet(packet: &NowPacket<'_>) -> Self {
+        match &packet.body {
+            NowBody::Message(msg) => match msg {
+                NowMessage::Input(_) | NowMessage::Network(_) => TrafficClass::Realtime,
+                _ => TrafficClass::Interactive,
+            },
+            NowBody::VirtualChannel(chan) => match chan {
+                NowVirtualChannel::Chat(_) => TrafficClass::Interactive,
+                NowVirtualChannel::FileTransfer(NowFileTransferMsg::Data(_)) => TrafficClass::Bulk,
+                NowVirtualChannel::FileTransfer(_) => TrafficClass::Interactive,
+                NowVirtualChannel::Tunnel(NowTunnelMsg::Data(_)) => TrafficClass::Bulk,
+                NowVirtualChannel::Tunnel(_) => TrafficClass::Interactive,
+                NowVirtualChannel::Device(_) => TrafficClass::Interactive,
+                NowVirtualChannel::Printer(NowPrinterMsg::JobData(_)) => TrafficClass::Bulk,
+                NowVirtualChannel::Printer(_) => TrafficClass::Interactive,
+                NowVirtualChannel::Drive(_) => TrafficClass::Interactive,
+                NowVirtualChannel::Clipboard(_) | NowVirtualChannel::Custom(_) => TrafficClass::Bulk,
+            },
+        }
+    }
+
+    /// Class of a packet known by its header only. Virtual channels are all `Bulk`.
+    pub fn of_body_type(body_type: BodyType) -> Self {
+        match body_type {
+            BodyType::Message(MessageType::Input) | BodyType::Message(MessageType::Network) => TrafficClass::Realtime,
+            BodyType::Message(_) => TrafficClass::Interactive,
+            BodyType::VirtualChannel(_) => TrafficClass::Bulk,
+        }
+    }
+}
+
+// == RATE LIMITING == //
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RateLimit {
+    pub bytes_per_sec: u64,
+    /// Maximum amount of bytes that can be sent at once after an idle period.
+    pub burst: u64,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64, burst: u64) -> Self {
+        Self { bytes_per_sec, burst }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    pub(crate) fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    pub(crate) fn set_limit(&mut self, limit: RateLimit) {
+        self.limit = limit;
+        self.tokens = self.tokens.min(limit.burst as f64);
+    }
+
+    pub(crate) fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.limit.bytes_per_sec as f64).min(self.limit.burst as f64);
+        self.last_refill = self.last_refill.max(now);
+    }
+
+    /// Messages larger than the burst size are allowed once the bucket is full (tokens go into debt).
+    fn required(&self, len: usize) -> f64 {
+        (len as f64).min(self.limit.burst as f64)
+    }
+
+    pub(crate) fn allows(&self, len: usize) -> bool {
+        self.tokens >= self.required(len)
+    }
+
+    pub(crate) fn consume(&mut self, len: usize) {
+        self.tokens -= len as f64;
+    }
+
+    pub(crate) fn wait_time(&self, len: usize) -> Duration {
+        let missing = self.required(len) - self.tokens;
+        if missing <= 0.0 || self.limit.bytes_per_sec == 0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_nanos((missing * 1e9 / self.limit.bytes_per_sec as f64).ceil() as u64)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimiterConfig {
+    pub global: Option<RateLimit>,
+    pub per_class: BTreeMap<TrafficClass, RateLimit>,
+    /// Classes never delayed by the limiter. They still consume global tokens.
+    pub exempt: Vec<TrafficClass>,
+}
+
+impl Default for RateLimiterConfig {
+    fn default() -> Self {
+        Self {
+            global: None,
+            per_class: BTreeMap::new(),
+            exempt: vec![TrafficClass::Realtime],
+        }
+    }
+}
+
+impl RateLimiterConfig {
+    pub fn global(self, limit: RateLimit) -> Self {
+        Self {
+            global: Some(limit),
+            ..self
+        }
+    }
+
+    pub fn class(mut self, class: TrafficClass, limit: RateLimit) -> Self {
+        self.per_class.insert(class, limit);
+        self
+    }
+
+    pub fn exempt(self, exempt: Vec<TrafficClass>) -> Self {
+        Self { exempt, ..self }
+    }
+}
+
+/// Token bucket rate limiter with a global cap and optional per-class caps.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    global: Option<TokenBucket>,
+    per_class: BTreeMap<TrafficClass, TokenBucket>,
+    exempt: Vec<TrafficClass>,
+    last_tick: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimiterConfig, now: Instant) -> Self {
+        let mut limiter = Self {
+            global: None,
+            per_class: BTreeMap::new(),
+            exempt: Vec::new(),
+            last_tick: now,
+        };
+        limiter.set_config(config);
+        limiter
+    }
+
+    /// Applies a new configuration. Current bucket levels are kept when possible.
+    pub fn set_config(&mut self, config: RateLimiterConfig) {
+        let now = self.last_tick;
+
+        self.global = match (self.global.take(), config.global) {
+            (Some(mut bucket), Some(limit)) => {
+                bucket.set_limit(limit);
+                Some(bucket)
+            }
+            (None, Some(limit)) => Some(TokenBucket::new(limit, now)),
+            (_, None) => None,
+        };
+
+        let mut per_class = BTreeMap::new();
+        for (class, limit) in config.per_class {
+            let bucket = match self.per_class.remove(&class) {
+                Some(mut bucket) => {
+                    bucket.set_limit(limit);
+                    bucket
+                }
+                None => TokenBucket::new(limit, now),
+            };
+            per_class.insert(class, bucket);
+        }
+        self.per_class = per_class;
+
+        self.exempt = config.exempt;
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        if let Some(bucket) = &mut self.global {
+            bucket.refill(now);
+        }
+        for bucket in self.per_class.values_mut() {
+            bucket.refill(now);
+        }
+        self.last_tick = self.last_tick.max(now);
+    }
+
+    pub fn is_exempt(&self, class: TrafficClass) -> bool {
+        self.exempt.contains(&class)
+    }
+
+    /// Time to wait before a message of given class and length may be sent.
+    pub fn wait_time(&self, class: TrafficClass, len: usize) -> Duration {
+        if self.is_exempt(class) {
+            return Duration::from_secs(0);
+        }
+
+        let global = self.global.as_ref().map(|b| b.wait_time(len)).unwrap_or_default();
+        let class = self.per_class.get(&class).map(|b| b.wait_time(len)).unwrap_or_default();
+        global.max(class)
+    }
+
+    pub fn allows(&self, class: TrafficClass, len: usize) -> bool {
+        if self.is_exempt(class) {
+            return true;
+        }
+
+        self.global.as_ref().map(|b| b.allows(len)).unwrap_or(true)
+            && self.per_class.get(&class).map(|b| b.allows(len)).unwrap_or(true)
+    }
+
+    pub fn consume(&mut self, class: TrafficClass, len: usize) {
+        if let Some(bucket) = &mut self.global {
+            bucket.consume(len);
+        }
+        if let Some(bucket) = self.per_class.get_mut(&class) {
+            bucket.consume(len);
+        }
+    }
+}
+
+// == PRIORITIES == //
+
+/// Priorities of the outgoing packets, higher first.
+///
+/// Packets of a channel having a priority get it, the others get the priority of their traffic class.
+/// By default input goes first, then other messages and chat, clipboard and finally file transfers.
+#[derive(Debug, Clone)]
+pub struct PriorityPolicy {
+    classes: BTreeMap<TrafficClass, u8>,
+    channels: BTreeMap<ChannelName, u8>,
+}
+
+impl Default for PriorityPolicy {
+    fn default() -> Self {
+        Self::new()
+            .class(TrafficClass::Realtime, 3)
+            .class(TrafficClass::Interactive, 2)
+            .class(TrafficClass::Bulk, 0)
+            .channel(ChannelName::Clipboard, 1)
+            .channel(ChannelName::FileTransfer, 0)
+    }
+}
+
+impl PriorityPolicy {
+    /// Every packet with the same priority.
+    pub fn new() -> Self {
+        Self {
+            classes: BTreeMap::new(),
+            channels: BTreeMap::new(),
+        }
+    }
+
+    pub fn class(mut self, class: TrafficClass, priority: u8) -> Self {
+        self.classes.insert(class, priority);
+        self
+    }
+
+    pub fn channel(mut self, name: ChannelName, priority: u8) -> Self {
+        self.channels.insert(name, priority);
+        self
+    }
+
+    pub fn class_priority(&self, class: TrafficClass) -> u8 {
+        self.classes.get(&class).copied().unwrap_or(0)
+    }
+
+    pub fn priority_of(&self, packet: &NowPacket<'_>) -> u8 {
+        let channel = match &packet.body {
+            NowBody::VirtualChannel(chan) => self.channels.get(chan.get_name()).copied(),
+            NowBody::Message(_) => None,
+        };
+        channel.unwrap_or_else(|| self.class_priority(TrafficClass::of_packet(packet)))
+    }
+}
+
+// == SEND QUEUE == //
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DrainResult {
+    pub sent_bytes: usize,
+    /// Instant at which `drain` should be called again if messages are still queued.
+    pub next_drain: Option<Instant>,
+}
+
+/// Packets of a channel, or messages when `None`.
+type Lane = Option<ChannelName>;
+
+#[derive(Debug, Default)]
+struct PriorityLevel {
+    lanes: BTreeMap<Lane, VecDeque<(TrafficClass, Vec<u8>)>>,
+    last_served: Option<Lane>,
+}
+
+impl PriorityLevel {
+    /// Lanes after the last one served first, wrapping around.
+    fn __lanes_in_turn(&self) -> Vec<Lane> {
+        let mut lanes: Vec<Lane> = self.lanes.keys().cloned().collect();
+        if let Some(last_served) = &self.last_served {
+            let next = lanes.iter().position(|lane| lane > last_served).unwrap_or(lanes.len());
+            lanes.rotate_left(next);
+        }
+        lanes
+    }
+
+    fn __pop(&mut self, lane: &Lane) -> Option<(TrafficClass, Vec<u8>)> {
+        let queue = self.lanes.get_mut(lane)?;
+        let packet = queue.pop_front();
+        if queue.is_empty() {
+            self.lanes.remove(lane);
+        }
+        self.last_served = Some(lane.clone());
+        packet
+    }
+}
+
+/// Priority queue of encoded packets waiting to be sent.
+///
+/// Packets are sent by priority, see [`PriorityPolicy`](struct.PriorityPolicy.html). Channels
+/// sharing a priority are interleaved, one packet each in turn, packets of a channel being sent in
+/// FIFO order. An optional rate limiter can be layered on top.
+#[derive(Debug, Default)]
+pub struct SendQueue {
+    levels: BTreeMap<Reverse<u8>, PriorityLevel>,
+    policy: PriorityPolicy,
+    rate_limiter: Option<RateLimiter>,
+}
+
+impl SendQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rate_limiter(self, rate_limiter: RateLimiter) -> Self {
+        Self {
+            rate_limiter: Some(rate_limiter),
+            ..self
+        }
+    }
+
+    /// Applies to the packets pushed afterward.
+    pub fn with_priorities(self, policy: PriorityPolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<RateLimiter>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    pub fn rate_limiter_mut(&mut self) -> Option<&mut RateLimiter> {
+        self.rate_limiter.as_mut()
+    }
+
+    /// Applies to the packets pushed afterward.
+    pub fn set_priorities(&mut self, policy: PriorityPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn priorities(&self) -> &PriorityPolicy {
+        &self.policy
+    }
+
+    pub fn push_packet(&mut self, packet: &NowPacket<'_>) -> Result<()> {
+        let class = TrafficClass::of_packet(packet);
+        let priority = self.policy.priority_of(packet);
+        let lane = match &packet.body {
+            NowBody::VirtualChannel(chan) => Some(chan.get_name().clone()),
+            NowBody::Message(_) => None,
+        };
+        self.__push(priority, lane, class, packet.encode()?);
+        Ok(())
+    }
+
+    /// Queues an encoded message with the priority of its class.
+    pub fn push(&mut self, class: TrafficClass, bytes: Vec<u8>) {
+        let priority = self.policy.class_priority(class);
+        self.__push(priority, None, class, bytes);
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels
+            .values()
+            .flat_map(|level| level.lanes.values())
+            .map(VecDeque::len)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    /// Pops next packet regardless of rate limiting.
+    pub fn pop(&mut self) -> Option<(TrafficClass, Vec<u8>)> {
+        let (priority, level) = self.levels.iter_mut().next()?;
+        let priority = *priority;
+        let lane = level.__lanes_in_turn().into_iter().next()?;
+        let packet = level.__pop(&lane);
+        self.__remove_empty_level(priority);
+        packet
+    }
+
+    /// Sends everything the rate limiter allows through `sink`.
+    pub fn drain<F>(&mut self, now: Instant, mut sink: F) -> Result<DrainResult>
+    where
+        F: FnMut(TrafficClass, &[u8]) -> Result<()>,
+    {
+        if let Some(limiter) = &mut self.rate_limiter {
+            limiter.tick(now);
+        }
+
+        let mut sent_bytes = 0;
+        let mut next_drain: Option<Instant> = None;
+
+        while let Some((priority, lane)) = self.__next_allowed(now, &mut next_drain) {
+            let level = self.levels.get_mut(&priority).expect("level of the next packet");
+            let (class, bytes) = level.lanes[&lane].front().expect("lane is not empty");
+            let class = *class;
+            if let Some(limiter) = &mut self.rate_limiter {
+                limiter.consume(class, bytes.len());
+            }
+
+            sink(class, bytes)?;
+            sent_bytes += bytes.len();
+            level.__pop(&lane);
+            self.__remove_empty_level(priority);
+        }
+
+        Ok(DrainResult { sent_bytes, next_drain })
+    }
+
+    fn __push(&mut self, priority: u8, lane: Lane, class: TrafficClass, bytes: Vec<u8>) {
+        self.levels
+            .entry(Reverse(priority))
+            .or_default()
+            .lanes
+            .entry(lane)
+            .or_default()
+            .push_back((class, bytes));
+    }
+
+    fn __remove_empty_level(&mut self, priority: Reverse<u8>) {
+        if self.levels.get(&priority).is_some_and(|level| level.lanes.is_empty()) {
+            self.levels.remove(&priority);
+        }
+    }
+
+    /// Lane of the next packet the rate limiter allows, recording when blocked packets may be sent.
+    fn __next_allowed(&self, now: Instant, next_drain: &mut Option<Instant>) -> Option<(Reverse<u8>, Lane)> {
+        for (priority, level) in &self.levels {
+            for lane in level.__lanes_in_turn() {
+                let (class, bytes) = level.lanes[&lane].front().expect("lane is not empty");
+                match &self.rate_limiter {
+                    Some(limiter) if !limiter.allows(*class, bytes.len()) => {
+                        let deadline = now + limiter.wait_time(*class, bytes.len());
+                        *next_drain = Some(next_drain.map_or(deadline, |next| next.min(deadline)));
+                    }
+                    _ => return Some((*priority, lane)),
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        CustomVirtualChannel, EventMouseFlags, InputEvent, NowClipboardFormatDataRspMsg, NowFileTransferDataMsg,
+        NowInputEventMouse, NowInputMsg,
+    };
+
+    #[test]
+    fn priority_order() {
+        let mut queue = SendQueue::new();
+        queue.push(TrafficClass::Bulk, vec![3]);
+        queue.push(TrafficClass::Interactive, vec![2]);
+        let mouse = NowInputEventMouse::new_with_flags_and_position(EventMouseFlags::None, 0, 0);
+        queue
+            .push_packet(&NowPacket::from_message(NowInputMsg::new_with_events(vec![
+                InputEvent::Mouse(mouse),
+            ])))
+            .unwrap();
+        queue.push(TrafficClass::Bulk, vec![4]);
+
+        assert_eq!(queue.pop().unwrap().0, TrafficClass::Realtime);
+        assert_eq!(queue.pop(), Some((TrafficClass::Interactive, vec![2])));
+        assert_eq!(queue.pop(), Some((TrafficClass::Bulk, vec![3])));
+        assert_eq!(queue.pop(), Some((TrafficClass::Bulk, vec![4])));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn channels_by_priority_and_interleaved() {
+        let telemetry = ChannelName::custom("AcmeTelemetry");
+        let policy = PriorityPolicy::default().channel(telemetry.clone(), 1);
+        let mut queue = SendQueue::new().with_priorities(policy);
+        let packets = vec![
+            NowPacket::from_virt_channel(NowFileTransferDataMsg::new(0, &[0], false), 1),
+            NowPacket::from_virt_channel(NowClipboardFormatDataRspMsg::new_with_format_data(0, 1, &[1]), 0),
+            NowPacket::from_virt_channel(NowClipboardFormatDataRspMsg::new_with_format_data(0, 1, &[2]), 0),
+            NowPacket::from_virt_channel(CustomVirtualChannel::new(telemetry.clone(), &[3][..]), 2),
+            NowPacket::from_virt_channel(CustomVirtualChannel::new(telemetry, &[4][..]), 2),
+        ];
+        for packet in &packets {
+            queue.push_packet(packet).unwrap();
+        }
+        queue.push(TrafficClass::Interactive, vec![5]);
+
+        let mut sent = Vec::new();
+        while let Some((_, bytes)) = queue.pop() {
+            sent.push(bytes);
+        }
+        let expected: Vec<Vec<u8>> = [5, 3, 1, 4, 2, 0]
+            .iter()
+            .map(|index| match index {
+                5 => vec![5],
+                index => packets[*index].encode().unwrap(),
+            })
+            .collect();
+        assert_eq!(sent, expected);
+    }
+
+    #[test]
+    fn global_cap_respected_and_input_never_delayed() {
+        const CAP: u64 = 1_000_000;
+        const CHUNK: usize = 16 * 1024;
+
+        let t0 = Instant::now();
+        let limiter = RateLimiter::new(RateLimiterConfig::default().global(RateLimit::new(CAP, 64 * 1024)), t0);
+        let mut queue = SendQueue::new().with_rate_limiter(limiter);
+
+        // file transfer saturating the queue
+        for _ in 0..(20_000_000 / CHUNK) {
+            queue.push(TrafficClass::Bulk, vec![0; CHUNK]);
+        }
+
+        let end = t0 + Duration::from_secs(10);
+        let input_period = Duration::from_millis(20);
+        let mut next_input = t0;
+        let mut now = t0;
+        let mut bulk_bytes = 0;
+
+        while now < end {
+            let input_pushed = now >= next_input;
+            if input_pushed {
+                queue.push(TrafficClass::Realtime, vec![1; 24]);
+                next_input += input_period;
+            }
+
+            let mut input_sent = false;
+            let result = queue
+                .drain(now, |class, bytes| {
+                    match class {
+                        TrafficClass::Realtime => input_sent = true,
+                        _ => bulk_bytes += bytes.len(),
+                    }
+                    Ok(())
+                })
+                .unwrap();
+
+            assert_eq!(input_pushed, input_sent, "input delayed at {:?}", now - t0);
+
+            let next_drain = result.next_drain.expect("queue shouldn't be empty");
+            now = next_drain.min(next_input);
+        }
+
+        let expected = CAP as f64 * 10.0;
+        let error = (bulk_bytes as f64 - expected).abs() / expected;
+        assert!(error < 0.03, "sent {} bytes in 10 seconds", bulk_bytes);
+    }
+
+    #[test]
+    fn config_adjusted_mid_session() {
+        let t0 = Instant::now();
+        let mut limiter = RateLimiter::new(
+            RateLimiterConfig::default().class(TrafficClass::Bulk, RateLimit::new(1000, 1000)),
+            t0,
+        );
+        limiter.consume(TrafficClass::Bulk, 1000);
+        assert!(!limiter.allows(TrafficClass::Bulk, 500));
+        assert_eq!(limiter.wait_time(TrafficClass::Bulk, 500), Duration::from_millis(500));
+
+        limiter.set_config(RateLimiterConfig::default().class(TrafficClass::Bulk, RateLimit::new(10_000, 1000)));
+        assert_eq!(limiter.wait_time(TrafficClass::Bulk, 500), Duration::from_millis(50));
+
+        limiter.tick(t0 + Duration::from_millis(50));
+        assert!(limiter.allows(TrafficClass::Bulk, 500));
+        assert!(limiter.allows(TrafficClass::Realtime, 100_000));
+    }
+}
@@ -1,4 +1,10 @@
-use crate::error::ProtoError;
+use crate::{
+    error::ProtoError,
+    header::{AbstractNowHeader, NowHeader, NowLongHeader, NowShortHeader},
+    message::{DecodeMode, NowCapset, VirtChannelsCtx},
+    packet::NowPacket,
+    version::NowProtocolVersion,
+};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Cursor, Write};
 
@@ -20,6 +26,55 @@ pub trait Encode {
         self.encode_into(&mut buf)?;
         Ok(buf.into_inner())
     }
+
+    /// Encodes into a caller-provided buffer and returns the number of bytes written.
+    ///
+    /// Nothing is written if `buf` is smaller than `encoded_len`.
+    fn encode_into_slice(&self, buf: &mut [u8]) -> Result<usize, ProtoError>
+    where
+        Self: Sized,
+    {
+        self.encode_into_slice_ctx(buf, &EncodeCtx::default())
+    }
+
+    /// Length of the representation selected by `ctx`. Defaults to `encoded_len`.
+    fn encoded_len_ctx(&self, _ctx: &EncodeCtx) -> usize {
+        self.encoded_len()
+    }
+
+    /// Encodes the representation selected by `ctx`. Defaults to `encode_into`.
+    fn encode_into_ctx<W: Write>(&self, writer: &mut W, _ctx: &EncodeCtx) -> Result<(), ProtoError>
+    where
+        Self: Sized,
+    {
+        self.encode_into(writer)
+    }
+
+    fn encode_ctx(&self, ctx: &EncodeCtx) -> Result<Vec<u8>, ProtoError>
+    where
+        Self: Sized,
+    {
+        let mut buf = Cursor::new(Vec::new());
+        self.encode_into_ctx(&mut buf, ctx)?;
+        Ok(buf.into_inner())
+    }
+
+    fn encode_into_slice_ctx(&self, buf: &mut [u8], ctx: &EncodeCtx) -> Result<usize, ProtoError>
+    where
+        Self: Sized,
+    {
+        use crate::error::{ProtoErrorKind, ProtoErrorResultExt};
+
+        let len = self.encoded_len_ctx(ctx);
+        if buf.len() < len {
+            return ProtoError::new(ProtoErrorKind::Encoding("slice"))
+                .or_else_desc(|| format!("buffer too small (expected {} bytes, got {})", len, buf.len()));
+        }
+
+        let mut cursor = Cursor::new(&mut buf[..len]);
+        self.encode_into_ctx(&mut cursor, ctx)?;
+        Ok(cursor.position() as usize)
+    }
 }
 
 sa::assert_obj_safe!(Encode);
@@ -74,8 +129,239 @@ where
     fn decode(bytes: &'dec [u8]) -> Result<Self, ProtoError> {
         Self::decode_from(&mut Cursor::new(bytes))
     }
+
+    /// Decodes the representation selected by `ctx`. Defaults to `decode_from`.
+    fn decode_from_ctx(cursor: &mut Cursor<&'dec [u8]>, _ctx: &DecodeCtx) -> Result<Self, ProtoError> {
+        Self::decode_from(cursor)
+    }
+
+    fn decode_ctx(bytes: &'dec [u8], ctx: &DecodeCtx) -> Result<Self, ProtoError> {
+        Self::decode_from_ctx(&mut Cursor::new(bytes), ctx)
+    }
+}
+
+// === PEEK ===
+
+/// Reads ahead without advancing the cursor.
+///
+/// Used to dispatch on a leading subtype that the selected message decodes again.
+pub trait Peek<'dec> {
+    fn peek<T: Decode<'dec>>(&self) -> Result<T, ProtoError>;
+
+    fn peek_u8(&self) -> Result<u8, ProtoError> {
+        self.peek()
+    }
+
+    fn peek_u16(&self) -> Result<u16, ProtoError> {
+        self.peek()
+    }
+}
+
+impl<'dec> Peek<'dec> for Cursor<&'dec [u8]> {
+    fn peek<T: Decode<'dec>>(&self) -> Result<T, ProtoError> {
+        T::decode_from(&mut self.clone())
+    }
+}
+
+// === PADDING ===
+
+/// Writes zero padding. Used by the `Encode` derive for `align` and `pad_to` fields.
+#[doc(hidden)]
+pub fn __write_padding<W: Write>(writer: &mut W, len: usize) -> Result<(), ProtoError> {
+    const ZEROES: [u8; 16] = [0; 16];
+
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = remaining.min(ZEROES.len());
+        writer.write_all(&ZEROES[..chunk])?;
+        remaining -= chunk;
+    }
+    Ok(())
+}
+
+/// Skips padding up to `position`. Used by the `Decode` derive for `align` and `pad_to` fields.
+#[doc(hidden)]
+pub fn __skip_padding(cursor: &mut Cursor<&[u8]>, position: u64, ty: &'static str) -> Result<(), ProtoError> {
+    use crate::error::{ProtoErrorKind, ProtoErrorResultExt};
+
+    if position > cursor.get_ref().len() as u64 {
+        return ProtoError::new(ProtoErrorKind::Decoding(ty)).or_else_desc(|| {
+            format!(
+                "not enough bytes for padding (expected {}, got {})",
+                position - cursor.position(),
+                cursor.get_ref().len() as u64 - cursor.position()
+            )
+        });
+    }
+    cursor.set_position(position);
+    Ok(())
+}
+
+// === CONTEXT ===
+
+/// Protocol version and capabilities negotiated with the peer, for encoding.
+///
+/// Messages whose layout depends on the negotiated protocol pick their representation from it.
+/// Until capabilities are configured, every optional part supported by the version is encoded.
+#[derive(Debug, Clone, Default)]
+pub struct EncodeCtx {
+    pub version: NowProtocolVersion,
+    capabilities: Option<Vec<NowCapset<'static>>>,
+}
+
+impl EncodeCtx {
+    pub fn new(version: NowProtocolVersion) -> Self {
+        Self {
+            version,
+            capabilities: None,
+        }
+    }
+
+    /// Capsets unknown to this implementation are not kept.
+    pub fn configure_from_capabilities(&mut self, peer_capabilities: &[NowCapset<'_>]) {
+        self.capabilities = Some(known_capabilities(peer_capabilities));
+    }
+
+    /// Negotiated capabilities, if configured.
+    pub fn capabilities(&self) -> Option<&[NowCapset<'static>]> {
+        self.capabilities.as_deref()
+    }
+}
+
+/// Bounds on what a peer can make the decoder allocate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Maximum number of items in a decoded list.
+    pub max_collection_len: usize,
+    /// Maximum size of a message body.
+    pub max_message_size: usize,
+}
+
+impl DecodeLimits {
+    pub const DEFAULT_MAX_COLLECTION_LEN: usize = 65_536;
+    pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+    pub fn check_collection_len(&self, len: usize, ty: &'static str) -> Result<(), ProtoError> {
+        use crate::error::{ProtoErrorKind, ProtoErrorResultExt};
+
+        if len > self.max_collection_len {
+            return ProtoError::new(ProtoErrorKind::LimitExceeded(ty)).or_else_desc(|| {
+                format!(
+                    "list count ({}) greater than collection limit ({})",
+                    len, self.max_collection_len
+                )
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_message_size(&self, cursor: &Cursor<&[u8]>, ty: &'static str) -> Result<(), ProtoError> {
+        use crate::error::{ProtoErrorKind, ProtoErrorResultExt};
+
+        let size = cursor.get_ref().len().saturating_sub(cursor.position() as usize);
+        if size > self.max_message_size {
+            return ProtoError::new(ProtoErrorKind::LimitExceeded(ty)).or_else_desc(|| {
+                format!(
+                    "message size ({}) greater than message size limit ({})",
+                    size, self.max_message_size
+                )
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_collection_len: Self::DEFAULT_MAX_COLLECTION_LEN,
+            max_message_size: Self::DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+/// Protocol version and capabilities negotiated with the peer, for decoding.
+///
+/// See `EncodeCtx`.
+#[derive(Debug, Clone, Default)]
+pub struct DecodeCtx {
+    pub version: NowProtocolVersion,
+    pub mode: DecodeMode,
+    pub limits: DecodeLimits,
+    capabilities: Option<Vec<NowCapset<'static>>>,
+}
+
+impl DecodeCtx {
+    pub fn new(version: NowProtocolVersion) -> Self {
+        Self {
+            version,
+            mode: DecodeMode::default(),
+            limits: DecodeLimits::default(),
+            capabilities: None,
+        }
+    }
+
+    pub fn with_mode(mode: DecodeMode) -> Self {
+        Self {
+            mode,
+            ..Self::default()
+        }
+    }
+
+    /// Capsets unknown to this implementation are not kept.
+    pub fn configure_from_capabilities(&mut self, peer_capabilities: &[NowCapset<'_>]) {
+        self.capabilities = Some(known_capabilities(peer_capabilities));
+    }
+
+    /// Negotiated capabilities, if configured.
+    pub fn capabilities(&self) -> Option<&[NowCapset<'static>]> {
+        self.capabilities.as_deref()
+    }
 }
 
+pub(crate) fn known_capabilities(capabilities: &[NowCapset<'_>]) -> Vec<NowCapset<'static>> {
+    capabilities
+        .iter()
+        .filter_map(|capset| {
+            Some(match capset {
+                NowCapset::Unknown(_) => return None,
+                NowCapset::Transport(capset) => NowCapset::Transport(capset.clone()),
+                NowCapset::Surface(capset) => NowCapset::Surface(capset.clone()),
+                NowCapset::License(capset) => NowCapset::License(capset.clone()),
+                NowCapset::Access(capset) => NowCapset::Access(capset.clone()),
+                NowCapset::Update(capset) => NowCapset::Update(capset.clone()),
+                NowCapset::Input(capset) => NowCapset::Input(capset.clone()),
+                NowCapset::Mouse(capset) => NowCapset::Mouse(capset.clone()),
+                NowCapset::Network(capset) => NowCapset::Network(capset.clone()),
+                NowCapset::Desktop(capset) => NowCapset::Desktop(capset.clone()),
+                NowCapset::System(capset) => NowCapset::System(capset.clone()),
+                NowCapset::ChannelCompression(capset) => NowCapset::ChannelCompression(capset.clone()),
+            })
+        })
+        .collect()
+}
+
+// === FIXED SIZE ===
+
+/// Types always encoded on the same number of bytes.
+///
+/// Implemented by the `Encode` derive for fieldless enums and structs marked `#[fixed_size]`.
+pub trait FixedSize {
+    const ENCODED_SIZE: usize;
+}
+
+macro_rules! impl_fixed_size {
+    ($($ty:ty),+) => {
+        $(
+            impl FixedSize for $ty {
+                const ENCODED_SIZE: usize = std::mem::size_of::<Self>();
+            }
+        )+
+    };
+}
+
+impl_fixed_size!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, [u32; 4]);
+
 // === implementation for primitive types ===
 
 impl Encode for u8 {
@@ -273,6 +559,40 @@ impl Encode for &[u8] {
     }
 }
 
+/// Zero is rejected on decode.
+macro_rules! impl_non_zero {
+    ($($ty:ident => $repr:ty),+) => {
+        $(
+            impl Encode for core::num::$ty {
+                fn encoded_len(&self) -> usize {
+                    std::mem::size_of::<Self>()
+                }
+
+                fn encode_into<W: Write>(&self, writer: &mut W) -> Result<(), ProtoError> {
+                    self.get().encode_into(writer)
+                }
+            }
+
+            impl Decode<'_> for core::num::$ty {
+                fn decode_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, ProtoError> {
+                    use crate::error::{ProtoErrorKind, ProtoErrorResultExt};
+
+                    match Self::new(<$repr>::decode_from(cursor)?) {
+                        Some(value) => Ok(value),
+                        None => ProtoError::new(ProtoErrorKind::OutOfRange(stringify!($ty))).or_desc("value is zero"),
+                    }
+                }
+            }
+
+            impl FixedSize for core::num::$ty {
+                const ENCODED_SIZE: usize = std::mem::size_of::<Self>();
+            }
+        )+
+    };
+}
+
+impl_non_zero!(NonZeroU8 => u8, NonZeroU16 => u16, NonZeroU32 => u32, NonZeroU64 => u64);
+
 impl<'dec: 'a, 'a, T: 'a> Decode<'dec> for Box<T>
 where
     T: Decode<'dec>,
@@ -280,12 +600,160 @@ where
     fn decode_from(cursor: &mut Cursor<&'dec [u8]>) -> Result<Self, ProtoError> {
         T::decode_from(cursor).map(Box::new)
     }
+
+    fn decode_from_ctx(cursor: &mut Cursor<&'dec [u8]>, ctx: &DecodeCtx) -> Result<Self, ProtoError> {
+        T::decode_from_ctx(cursor, ctx).map(Box::new)
+    }
+}
+
+// === STREAMING ===
+
+/// Reassembles now packets from arbitrary-sized chunks, as read from a socket.
+///
+/// Packets are decoded in order once complete. Bytes of an incomplete packet stay
+/// buffered until enough bytes are fed.
+///
+/// A header that can't be decoded, or announcing a body beyond the decode limits, leaves no
+/// way to find where the next packet starts: the decoder fails for good, dropping its buffer.
+#[derive(Debug, Clone, Default)]
+pub struct StreamingDecoder {
+    buffer: Vec<u8>,
+    cursor: usize,
+    failed: bool,
+}
+
+impl StreamingDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a chunk. Bytes of already decoded packets are discarded, and chunks are ignored once failed.
+    pub fn feed(&mut self, chunk: &[u8]) {
+        if self.failed {
+            return;
+        }
+        if self.cursor > 0 {
+            self.buffer.drain(..self.cursor);
+            self.cursor = 0;
+        }
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// Number of bytes buffered and not decoded yet.
+    pub fn pending_len(&self) -> usize {
+        self.buffer.len() - self.cursor
+    }
+
+    /// `true` once a packet header couldn't be decoded.
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Decodes the next packet if all its bytes are buffered.
+    ///
+    /// A packet whose body couldn't be decoded is skipped and its error returned.
+    /// A header error is returned once, `None` being returned afterwards.
+    pub fn next_packet<'a>(&'a mut self, channels_ctx: &VirtChannelsCtx) -> Option<Result<NowPacket<'a>, ProtoError>> {
+        if self.failed {
+            return None;
+        }
+
+        let pending = &self.buffer[self.cursor..];
+
+        // the short bit lives in the flags byte, right after the short header body length
+        let flags = *pending.get(NowShortHeader::SIZE - 1)?;
+        let header_len = if flags > 7 {
+            NowShortHeader::SIZE
+        } else {
+            NowLongHeader::SIZE
+        };
+        if pending.len() < header_len {
+            return None;
+        }
+
+        let header = NowHeader::decode(&pending[..header_len]).and_then(|header| {
+            crate::packet::check_body_len(header.body_len(), channels_ctx.decode_limits())?;
+            Ok(header)
+        });
+        let header = match header {
+            Ok(header) => header,
+            Err(e) => {
+                self.failed = true;
+                self.buffer = Vec::new();
+                self.cursor = 0;
+                return Some(Err(e));
+            }
+        };
+
+        let packet_len = header.len() + header.body_len();
+        if pending.len() < packet_len {
+            return None;
+        }
+
+        let start = self.cursor;
+        self.cursor += packet_len;
+        Some(NowPacket::decode_from(
+            header,
+            &self.buffer[start + header_len..start + packet_len],
+            channels_ctx,
+        ))
+    }
+}
+
+// === ASYNC ===
+
+/// Encodes a value and writes it on an async stream.
+///
+/// The value is encoded at once in a buffer of its exact encoded length.
+#[cfg(feature = "async")]
+pub async fn encode_into_async<T, W>(value: &T, writer: &mut W) -> Result<(), ProtoError>
+where
+    T: Encode,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+
+    let mut buffer = Vec::with_capacity(value.encoded_len());
+    value.encode_into(&mut buffer)?;
+    writer.write_all(&buffer).await?;
+    Ok(())
+}
+
+/// Reads a single packet from an async stream and decodes it.
+///
+/// Only the packet bytes are read: the header first, then exactly the body length announced by the header.
+/// `buffer` holds the body the decoded packet borrows from.
+#[cfg(feature = "async")]
+pub async fn decode_from_async<'dec, R>(
+    reader: &mut R,
+    buffer: &'dec mut Vec<u8>,
+    channels_ctx: &VirtChannelsCtx,
+) -> Result<NowPacket<'dec>, ProtoError>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    let header = NowHeader::read_from_async(reader).await?;
+    crate::packet::check_body_len(header.body_len(), channels_ctx.decode_limits())?;
+    buffer.clear();
+    buffer.resize(header.body_len(), 0);
+    reader.read_exact(buffer).await?;
+
+    NowPacket::decode_from(header, buffer, channels_ctx)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::container::Bytes8;
+    use crate::{
+        container::{Bytes8, Vec16, Vec32, Vec8},
+        error::ProtoErrorKind,
+        message::{
+            NowBody, NowCStr, NowMessage, NowString, NowStringUtf16, NowSurfaceMsg, NowSurfaceSelectReqMsg,
+            NowTerminateMsg, StringSize64,
+        },
+    };
 
     #[derive(Encode, Decode)]
     struct StructDerive<'a> {
@@ -316,4 +784,565 @@ mod tests {
         };
         assert_eq!(s.encode().unwrap(), STRUCT_DERIVE_ENCODED.to_vec());
     }
+
+    #[test]
+    fn peek_does_not_advance_cursor() {
+        let mut cursor = Cursor::new(&STRUCT_DERIVE_ENCODED[..]);
+        assert_eq!(cursor.peek_u8().unwrap(), 0x10);
+        assert_eq!(cursor.peek_u16().unwrap(), 0x2010);
+        assert_eq!(cursor.position(), 0);
+
+        cursor.set_position(7);
+        assert_eq!(cursor.peek_u8().unwrap(), 0x03);
+        assert!(cursor.peek_u16().is_err());
+        assert_eq!(cursor.position(), 7);
+    }
+
+    #[test]
+    fn struct_derive_encode_into_slice() {
+        let s = StructDerive {
+            a: 0x10,
+            b: 0x20,
+            c: 0x4030,
+            update_data: Bytes8(&[0x01, 0x02, 0x03]),
+        };
+
+        let mut buf = [0xff; 16];
+        assert_eq!(s.encode_into_slice(&mut buf).unwrap(), STRUCT_DERIVE_ENCODED.len());
+        assert_eq!(buf[..8], STRUCT_DERIVE_ENCODED);
+        assert_eq!(buf[8..], [0xff; 8]);
+
+        let mut buf = [0xff; 7];
+        assert!(s.encode_into_slice(&mut buf).is_err());
+        assert_eq!(buf, [0xff; 7]);
+    }
+
+    #[derive(Encode, Decode, Debug)]
+    struct InnerDerive {
+        pub b: u16,
+        pub c: u32,
+    }
+
+    #[derive(Encode, Decode, Debug)]
+    struct OuterDerive {
+        pub a: u8,
+        pub inner: InnerDerive,
+    }
+
+    #[test]
+    fn derive_decode_error_context() {
+        let err = OuterDerive::decode(&[0x01, 0x02, 0x03, 0x04, 0x05]).unwrap_err();
+        let context = err.decode_context.as_ref().unwrap();
+        assert_eq!(context.offset, 3);
+        assert_eq!(context.path, vec!["OuterDerive::inner", "InnerDerive::c"]);
+        assert_eq!(context.hexdump(), "00000000: 01 02 03>04 05\n");
+        assert!(err
+            .to_string()
+            .contains("[at offset 3 in OuterDerive::inner > InnerDerive::c]"));
+    }
+
+    #[derive(Encode, Decode)]
+    struct LargeListsDerive {
+        pub words: Vec16<u16>,
+        pub dwords: Vec32<u32>,
+    }
+
+    #[test]
+    fn large_lists_derive_round_trip() {
+        let s = LargeListsDerive {
+            words: Vec16(vec![1; 300]),
+            dwords: Vec32(vec![2; 2]),
+        };
+        let encoded = s.encode().unwrap();
+        assert_eq!(encoded.len(), 2 + 300 * 2 + 4 + 2 * 4);
+
+        let decoded = LargeListsDerive::decode(&encoded).unwrap();
+        assert_eq!(decoded.words.len(), 300);
+        assert_eq!(decoded.dwords.0, vec![2, 2]);
+    }
+
+    #[derive(Encode, Decode)]
+    struct StringsDerive<'a> {
+        pub name: NowStringUtf16<StringSize64, u8>,
+        pub path: NowCStr<'a>,
+    }
+
+    #[test]
+    fn strings_derive_round_trip() {
+        let s = StringsDerive {
+            name: "wayk".parse().unwrap(),
+            path: NowCStr::new("C:\\").unwrap(),
+        };
+        let encoded = s.encode().unwrap();
+        assert_eq!(
+            encoded,
+            vec![0x04, b'w', 0x00, b'a', 0x00, b'y', 0x00, b'k', 0x00, 0x00, 0x00, b'C', b':', b'\\', 0x00]
+        );
+
+        let decoded = StringsDerive::decode(&encoded).unwrap();
+        assert_eq!(decoded.name, "wayk");
+        assert_eq!(decoded.path, "C:\\");
+    }
+
+    #[derive(Encode, Decode, Debug)]
+    struct PaddedDerive<'a> {
+        pub a: u8,
+        #[align(4)]
+        pub b: u16,
+        #[pad_to(4)]
+        pub c: u8,
+        #[align(8)]
+        pub name: NowString<StringSize64, u8>,
+        #[pad_to(8)]
+        pub data: Bytes8<'a>,
+    }
+
+    #[derive(Encode, Decode)]
+    #[fixed_size]
+    struct FixedSizePaddedDerive {
+        pub a: u8,
+        #[align(4)]
+        pub b: u32,
+        #[pad_to(4)]
+        pub c: u16,
+    }
+
+    #[rustfmt::skip]
+    const PADDED_DERIVE: [u8; 28] = [
+        0x01, // a
+        0x00, 0x00, 0x00, // align(4)
+        0x02, 0x00, // b
+        0x03, // c
+        0x00, 0x00, 0x00, // pad_to(4)
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // align(8)
+        0x02, b'h', b'i', 0x00, // name
+        0x02, 0xaa, 0xbb, // data
+        0x00, 0x00, 0x00, 0x00, 0x00, // pad_to(8)
+    ];
+
+    #[test]
+    fn padded_derive_round_trip() {
+        let s = PaddedDerive::decode(&PADDED_DERIVE).unwrap();
+        assert_eq!(s.a, 1);
+        assert_eq!(s.b, 2);
+        assert_eq!(s.c, 3);
+        assert_eq!(s.name, "hi");
+        assert_eq!(s.data, &[0xaa, 0xbb][..]);
+        assert_eq!(s.encoded_len(), PADDED_DERIVE.len());
+        assert_eq!(s.encode().unwrap(), PADDED_DERIVE.to_vec());
+
+        assert!(PaddedDerive::decode(&PADDED_DERIVE[..26]).is_err());
+
+        let too_large = PaddedDerive {
+            data: Bytes8(&[0; 8]),
+            ..s
+        };
+        assert!(too_large.encode().is_err());
+
+        assert_eq!(FixedSizePaddedDerive::ENCODED_SIZE, 12);
+        let s = FixedSizePaddedDerive { a: 1, b: 2, c: 3 };
+        assert_eq!(s.encode().unwrap().len(), FixedSizePaddedDerive::ENCODED_SIZE);
+    }
+
+    #[derive(Encode, Decode, Debug)]
+    struct RangedDerive {
+        #[range(min = 1)]
+        pub id: u16,
+        #[range(min = "Self::MIN_SIZE", max = 64)]
+        pub size: u8,
+        pub count: core::num::NonZeroU8,
+    }
+
+    impl RangedDerive {
+        const MIN_SIZE: usize = 4;
+    }
+
+    #[test]
+    fn ranged_derive() {
+        let s = RangedDerive::decode(&[0x01, 0x00, 0x04, 0x02]).unwrap();
+        assert_eq!(s.id, 1);
+        assert_eq!(s.size, 4);
+        assert_eq!(s.count.get(), 2);
+        assert_eq!(s.encode().unwrap(), vec![0x01, 0x00, 0x04, 0x02]);
+        assert!(RangedDerive::decode(&[0x00, 0x01, 0x40, 0x02]).is_ok());
+
+        for bytes in &[
+            [0x00, 0x00, 0x04, 0x02],
+            [0x01, 0x00, 0x03, 0x02],
+            [0x01, 0x00, 0x41, 0x02],
+        ] {
+            let err = RangedDerive::decode(bytes).err().unwrap();
+            assert!(matches!(err.kind, ProtoErrorKind::OutOfRange(_)));
+        }
+
+        let err = RangedDerive::decode(&[0x01, 0x00, 0x04, 0x00]).err().unwrap();
+        assert!(matches!(
+            err.source.unwrap().kind,
+            ProtoErrorKind::OutOfRange("NonZeroU8")
+        ));
+    }
+
+    #[derive(Encode, Decode)]
+    #[fixed_size]
+    struct FixedSizeDerive {
+        pub a: u8,
+        pub b: u32,
+        pub token: [u32; 4],
+    }
+
+    #[test]
+    fn fixed_size_derive() {
+        use crate::message::{EdgeRect, NowSurfaceMap, SurfaceOrientation};
+
+        assert_eq!(FixedSizeDerive::ENCODED_SIZE, 21);
+        let s = FixedSizeDerive {
+            a: 1,
+            b: 2,
+            token: [3; 4],
+        };
+        assert_eq!(s.encoded_len(), FixedSizeDerive::ENCODED_SIZE);
+        assert_eq!(s.encode().unwrap().len(), FixedSizeDerive::ENCODED_SIZE);
+
+        assert_eq!(SurfaceOrientation::ENCODED_SIZE, 2);
+        assert_eq!(NowSurfaceMap::ENCODED_SIZE, 16);
+        let map = NowSurfaceMap::new(1, 0, EdgeRect::default());
+        assert_eq!(map.encode().unwrap().len(), NowSurfaceMap::ENCODED_SIZE);
+    }
+
+    __flags_struct! {
+        OptionalFieldFlags: u8 => {
+            extra = EXTRA = 0x01,
+        }
+    }
+
+    #[derive(Encode, Decode)]
+    struct ConditionalDerive {
+        pub flags: OptionalFieldFlags,
+        #[decode_if(flags, OptionalFieldFlags::EXTRA)]
+        pub extra: Option<u16>,
+        pub trailer: u8,
+    }
+
+    #[test]
+    fn conditional_field_derive() {
+        let s = ConditionalDerive::decode(&[0x01, 0x34, 0x12, 0xff]).unwrap();
+        assert!(s.flags.extra());
+        assert_eq!(s.extra, Some(0x1234));
+        assert_eq!(s.trailer, 0xff);
+
+        let s = ConditionalDerive::decode(&[0x00, 0xff]).unwrap();
+        assert_eq!(s.extra, None);
+        assert_eq!(s.trailer, 0xff);
+
+        let mut s = ConditionalDerive {
+            flags: OptionalFieldFlags::new_empty().set_extra(),
+            extra: Some(0x1234),
+            trailer: 0xff,
+        };
+        assert_eq!(s.encoded_len(), 4);
+        assert_eq!(s.encode().unwrap(), vec![0x01, 0x34, 0x12, 0xff]);
+
+        s.flags.unset_extra();
+        s.extra = None;
+        assert_eq!(s.encode().unwrap(), vec![0x00, 0xff]);
+    }
+
+    #[derive(Encode, Decode)]
+    struct SizeConditionalDerive {
+        #[size_field]
+        size: u8,
+        pub id: u8,
+        #[decode_if(size >= 4)]
+        pub extension: Option<u16>,
+    }
+
+    #[test]
+    fn size_conditional_field_derive() {
+        let s = SizeConditionalDerive::decode(&[0x04, 0x01, 0x34, 0x12]).unwrap();
+        assert_eq!(s.extension, Some(0x1234));
+        assert_eq!(s.encode().unwrap(), vec![0x04, 0x01, 0x34, 0x12]);
+
+        // too small for the extension: skipped along with the trailing bytes
+        let s = SizeConditionalDerive::decode(&[0x03, 0x01, 0xff]).unwrap();
+        assert_eq!(s.extension, None);
+        assert_eq!(s.size, 2);
+        assert_eq!(s.encode().unwrap(), vec![0x02, 0x01]);
+
+        assert!(SizeConditionalDerive::decode(&[0x04, 0x01, 0x34]).is_err());
+    }
+
+    #[derive(Encode, Decode, Debug, PartialEq)]
+    struct PingDerive {
+        subtype: u8,
+        pub value: u16,
+    }
+
+    #[derive(Encode, Decode, Debug, PartialEq)]
+    struct PongDerive {
+        subtype: u8,
+    }
+
+    #[repr(u8)]
+    enum PingPongType {
+        Ping = 0x01,
+    }
+
+    #[derive(Encode, Decode, Debug, PartialEq)]
+    #[message_enum(tag = u8, subtype = PingPongType)]
+    enum MessageEnumDerive {
+        Ping(PingDerive),
+        // explicit tags take precedence
+        #[tag = 0x02]
+        Pong(PongDerive),
+        #[decode_other]
+        Unknown {
+            subtype: u8,
+            payload: Vec<u8>,
+        },
+    }
+
+    #[test]
+    fn message_enum_derive() {
+        use crate::message::DecodeMode;
+
+        let msg = MessageEnumDerive::decode(&[0x01, 0x34, 0x12]).unwrap();
+        assert_eq!(
+            msg,
+            MessageEnumDerive::Ping(PingDerive {
+                subtype: 0x01,
+                value: 0x1234
+            })
+        );
+        assert_eq!(msg.encode().unwrap(), vec![0x01, 0x34, 0x12]);
+        assert_eq!(
+            MessageEnumDerive::decode(&[0x02]).unwrap(),
+            MessageEnumDerive::Pong(PongDerive { subtype: 0x02 })
+        );
+
+        let unknown = [0x2a, 0x01, 0x02];
+        assert!(MessageEnumDerive::decode(&unknown).is_err());
+        let msg = MessageEnumDerive::decode_with_mode(&mut Cursor::new(&unknown[..]), DecodeMode::Permissive).unwrap();
+        assert_eq!(
+            msg,
+            MessageEnumDerive::Unknown {
+                subtype: 0x2a,
+                payload: vec![0x01, 0x02]
+            }
+        );
+        assert_eq!(msg.encoded_len(), 3);
+        assert_eq!(msg.encode().unwrap(), unknown.to_vec());
+    }
+
+    #[test]
+    fn decode_ctx_is_threaded_through_fields() {
+        use crate::message::DecodeMode;
+
+        #[derive(Encode, Decode, Debug, PartialEq)]
+        struct Wrapper {
+            messages: Vec8<MessageEnumDerive>,
+        }
+
+        let bytes = [0x02, 0x02, 0x2a];
+        assert!(Wrapper::decode(&bytes).is_err());
+
+        let ctx = DecodeCtx::with_mode(DecodeMode::Permissive);
+        let wrapper = Wrapper::decode_ctx(&bytes, &ctx).unwrap();
+        assert_eq!(
+            wrapper.messages.0,
+            vec![
+                MessageEnumDerive::Pong(PongDerive { subtype: 0x02 }),
+                MessageEnumDerive::Unknown {
+                    subtype: 0x2a,
+                    payload: Vec::new()
+                },
+            ]
+        );
+        assert_eq!(wrapper.encode_ctx(&EncodeCtx::default()).unwrap(), bytes.to_vec());
+    }
+
+    #[test]
+    fn streaming_decoder_reassembles_chunks() {
+        let mut stream = NowPacket::from_message(NowSurfaceMsg::from(NowSurfaceSelectReqMsg::new(0, 1, 2)))
+            .encode()
+            .unwrap();
+        stream.extend(NowPacket::from_message(NowTerminateMsg::default()).encode().unwrap());
+        let ctx = VirtChannelsCtx::new();
+
+        let mut decoder = StreamingDecoder::new();
+        let mut decoded = Vec::new();
+        for byte in &stream {
+            decoder.feed(&[*byte]);
+            while let Some(packet) = decoder.next_packet(&ctx) {
+                match packet.unwrap().body {
+                    NowBody::Message(NowMessage::Surface(NowSurfaceMsg::SelectReq(req))) => {
+                        decoded.push(req.surface_id)
+                    }
+                    NowBody::Message(NowMessage::Terminate(_)) => decoded.push(0xffff),
+                    other => panic!("unexpected packet body {:?}", other),
+                }
+            }
+        }
+
+        assert_eq!(decoded, vec![2, 0xffff]);
+        assert_eq!(decoder.pending_len(), 0);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_adapters_round_trip() {
+        use crate::{
+            header::AbstractNowHeader,
+            message::{BodyType, MessageType},
+        };
+
+        let short = NowPacket::from_message(NowSurfaceMsg::from(NowSurfaceSelectReqMsg::new(0, 1, 2)));
+        assert!(short.header.is_short());
+        let terminate = NowTerminateMsg::default();
+        let long = NowPacket {
+            header: NowHeader::Long(NowLongHeader::new(
+                BodyType::Message(MessageType::Terminate),
+                terminate.encoded_len() as u32,
+            )),
+            body: NowBody::Message(NowMessage::Terminate(terminate)),
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let (mut client, mut server) = tokio::io::duplex(64);
+            let writer = async {
+                encode_into_async(&short, &mut client).await.unwrap();
+                encode_into_async(&long, &mut client).await.unwrap();
+            };
+
+            let reader = async {
+                let ctx = VirtChannelsCtx::new();
+                let mut buffer = Vec::new();
+                match decode_from_async(&mut server, &mut buffer, &ctx).await.unwrap().body {
+                    NowBody::Message(NowMessage::Surface(NowSurfaceMsg::SelectReq(req))) => {
+                        assert_eq!(req.surface_id, 2)
+                    }
+                    other => panic!("unexpected packet body {:?}", other),
+                }
+                match decode_from_async(&mut server, &mut buffer, &ctx).await.unwrap().body {
+                    NowBody::Message(NowMessage::Terminate(_)) => {}
+                    other => panic!("unexpected packet body {:?}", other),
+                }
+            };
+
+            tokio::join!(writer, reader);
+        });
+    }
+
+    #[cfg(feature = "fuzzing")]
+    fn arbitrary_round_trip<T>()
+    where
+        T: for<'a> arbitrary::Arbitrary<'a> + for<'a> Decode<'a> + Encode + core::fmt::Debug,
+    {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..1024 {
+            let data: Vec<u8> = (0..512)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    seed as u8
+                })
+                .collect();
+
+            let value = match T::arbitrary(&mut arbitrary::Unstructured::new(&data)) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            let encoded = value.encode().unwrap();
+            let decoded = T::decode(&encoded).unwrap_or_else(|e| panic!("couldn't decode {:?}: {}", value, e));
+            assert_eq!(decoded.encode().unwrap(), encoded, "{:?}", value);
+        }
+    }
+
+    #[cfg(feature = "fuzzing")]
+    #[test]
+    fn arbitrary_messages_round_trip() {
+        use crate::message::*;
+
+        arbitrary_round_trip::<NowHandshakeMsg>();
+        arbitrary_round_trip::<NowNegotiateMsg>();
+        arbitrary_round_trip::<NowAssociateMsg>();
+        arbitrary_round_trip::<NowChannelMsg>();
+        arbitrary_round_trip::<NowActivateMsg>();
+        arbitrary_round_trip::<NowTerminateMsg>();
+        arbitrary_round_trip::<NowSurfaceListReqMsg>();
+        arbitrary_round_trip::<NowSurfaceListRspMsg>();
+        arbitrary_round_trip::<NowSurfaceMapReqMsg>();
+        arbitrary_round_trip::<NowSurfaceMapRspMsg>();
+        arbitrary_round_trip::<NowSurfaceSelectReqMsg>();
+        arbitrary_round_trip::<NowSurfaceSelectRspMsg>();
+        arbitrary_round_trip::<NowSurfaceMultiSelectReqMsg>();
+        arbitrary_round_trip::<NowSystemMsg>();
+        arbitrary_round_trip::<NowSharingMsg>();
+        arbitrary_round_trip::<NowNetworkMsg>();
+        arbitrary_round_trip::<NowDesktopMsg>();
+        arbitrary_round_trip::<NowSessionMsg>();
+        arbitrary_round_trip::<NowChannelWindowMsg>();
+    }
+
+    #[test]
+    fn streaming_decoder_several_packets_in_one_chunk() {
+        let packet = NowPacket::from_message(NowTerminateMsg::default()).encode().unwrap();
+        let mut chunk = packet.repeat(3);
+        chunk.extend_from_slice(&packet[..2]);
+        let ctx = VirtChannelsCtx::new();
+
+        let mut decoder = StreamingDecoder::new();
+        decoder.feed(&chunk);
+        let mut count = 0;
+        while let Some(packet) = decoder.next_packet(&ctx) {
+            packet.unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 3);
+        assert_eq!(decoder.pending_len(), 2);
+
+        decoder.feed(&packet[2..]);
+        assert!(decoder.next_packet(&ctx).unwrap().is_ok());
+        assert!(decoder.next_packet(&ctx).is_none());
+    }
+
+    #[test]
+    fn streaming_decoder_fails_on_corrupt_header() {
+        let packet = NowPacket::from_message(NowTerminateMsg::default()).encode().unwrap();
+        let ctx = VirtChannelsCtx::new();
+
+        let mut decoder = StreamingDecoder::new();
+        // short header with an unknown message type
+        decoder.feed(&[0x00, 0x00, 0xff, 0x80]);
+        decoder.feed(&packet);
+        let mut errors = 0;
+        while let Some(failed) = decoder.next_packet(&ctx).map(|packet| packet.is_err()) {
+            assert!(failed);
+            errors += 1;
+            assert!(errors < 10, "decoder keeps returning errors");
+            decoder.feed(&packet);
+        }
+        assert_eq!(errors, 1);
+        assert!(decoder.is_failed());
+        assert_eq!(decoder.pending_len(), 0);
+
+        decoder.feed(&packet);
+        assert!(decoder.next_packet(&ctx).is_none());
+    }
+
+    #[test]
+    fn streaming_decoder_rejects_oversized_body() {
+        let ctx = VirtChannelsCtx::new();
+        let body_len = (ctx.decode_limits().max_message_size + 1) as u32;
+
+        let mut decoder = StreamingDecoder::new();
+        let mut header = body_len.to_le_bytes().to_vec();
+        // terminate message
+        header.extend_from_slice(&[0x00, 0x08]);
+        decoder.feed(&header);
+        let err = decoder.next_packet(&ctx).unwrap().err().unwrap();
+        assert!(matches!(err.kind, ProtoErrorKind::LimitExceeded(_)));
+        assert!(decoder.next_packet(&ctx).is_none());
+    }
 }
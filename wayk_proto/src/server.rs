@@ -0,0 +1,813 @@
+//! Async server hosting many concurrent sessions.
+//!
+//! This is the host side of the protocol: each accepted connection runs the server connection sequence
+//! (negotiation, authentication, capabilities and channels), is optionally advertised the host surfaces,
+//! then serves its virtual channels through the state machines of its [`NowSessionConfig`].
+//!
+//! State machines of this crate are not `Send`, each session is therefore driven by its own
+//! local task: the server must be used from within a [`tokio::task::LocalSet`].
+
+use crate::{
+    channels_manager::ChannelsManager,
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    header::{AbstractNowHeader, NowHeader},
+    message::{
+        AuthType, ChannelName, NowCapset, NowChannelDef, NowMessage, NowSurfaceDef, NowSurfaceListReqMsg,
+        NowSurfaceMsg, NowTerminateMsg, VirtChannelsCtx,
+    },
+    middleware::{MessageHook, MessageHooks},
+    packet::NowPacket,
+    serialization::{Decode, Encode},
+    sharee::{Sharee, ShareeCallbackTrait, ShareeResult, ShareeState},
+    sm::{
+        AccessControlRc, AutoResponder, ConnectionEvent, ConnectionSM, DummyConnectionSM, DummyConnectionSeqCallback,
+        HandoffRegistryRc, NowConnection, ServerConnectionSeqSM,
+    },
+    version::NowProtocolVersionRange,
+};
+use alloc::collections::BTreeMap;
+use core::{future::Future, task::Poll};
+use std::{
+    cell::RefCell,
+    io,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+};
+
+pub type SessionId = u32;
+
+/// Per-connection configuration.
+pub struct NowSessionConfig {
+    available_auth_types: Vec<AuthType>,
+    authenticate_sm: Box<dyn ConnectionSM>,
+    capabilities: Vec<NowCapset<'static>>,
+    available_channels: Vec<ChannelName>,
+    channels_manager: ChannelsManager,
+    surfaces: Option<NowSurfaceListReqMsg>,
+    access_control: Option<AccessControlRc>,
+    handoff_registry: Option<HandoffRegistryRc>,
+    versions: NowProtocolVersionRange,
+    hooks: MessageHooks,
+    auto_responder: AutoResponder,
+}
+
+impl Default for NowSessionConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NowSessionConfig {
+    pub fn new() -> Self {
+        Self {
+            available_auth_types: Vec::new(),
+            authenticate_sm: Box::new(DummyConnectionSM),
+            capabilities: Vec::new(),
+            available_channels: Vec::new(),
+            channels_manager: ChannelsManager::new(),
+            surfaces: None,
+            access_control: None,
+            handoff_registry: None,
+            versions: NowProtocolVersionRange::default(),
+            hooks: MessageHooks::new(),
+            auto_responder: AutoResponder::default(),
+        }
+    }
+
+    pub fn available_auth_process(self, available_auth_types: Vec<AuthType>) -> Self {
+        Self {
+            available_auth_types,
+            ..self
+        }
+    }
+
+    /// Authentication state machine verifying the client.
+    pub fn authenticate_sm<P: ConnectionSM + 'static>(self, sm: P) -> Self {
+        Self {
+            authenticate_sm: Box::new(sm),
+            ..self
+        }
+    }
+
+    pub fn capabilities(self, capabilities: Vec<NowCapset<'static>>) -> Self {
+        Self { capabilities, ..self }
+    }
+
+    pub fn available_channels(self, available_channels: Vec<ChannelName>) -> Self {
+        Self {
+            available_channels,
+            ..self
+        }
+    }
+
+    /// Handlers for the available virtual channels.
+    pub fn channels_manager(self, channels_manager: ChannelsManager) -> Self {
+        Self {
+            channels_manager,
+            ..self
+        }
+    }
+
+    /// Decides whether the authenticated client is granted remote control, typically by prompting the
+    /// local user. The prompt counts in the handshake timeout of the server.
+    pub fn access_control(self, access_control: AccessControlRc) -> Self {
+        Self {
+            access_control: Some(access_control),
+            ..self
+        }
+    }
+
+    /// Issues handoff tokens on request of the client and accepts clients taking over a session with them,
+    /// the original client being then disconnected. The registry is typically shared by all sessions.
+    pub fn handoff_registry(self, handoff_registry: HandoffRegistryRc) -> Self {
+        Self {
+            handoff_registry: Some(handoff_registry),
+            ..self
+        }
+    }
+
+    /// Protocol versions supported. The highest one also supported by the client is selected.
+    pub fn versions(self, versions: NowProtocolVersionRange) -> Self {
+        Self { versions, ..self }
+    }
+
+    /// Adds a hook observing or rewriting the messages of the session, including the packets sent through
+    /// the session handle. See `middleware`.
+    pub fn hook<H: MessageHook + 'static>(mut self, hook: H) -> Self {
+        self.hooks.add(hook);
+        self
+    }
+
+    /// Routine messages (keep-alives, probes and their acknowledgments) answered by the session
+    /// instead of being left to the application.
+    pub fn auto_responder(self, auto_responder: AutoResponder) -> Self {
+        Self { auto_responder, ..self }
+    }
+
+    /// Surfaces advertised to the client once the connection sequence completes,
+    /// before the session is handed out.
+    pub fn surfaces(self, desktop_width: u16, desktop_height: u16, surfaces: Vec<NowSurfaceDef>) -> Self {
+        Self {
+            surfaces: Some(NowSurfaceListReqMsg::new_with_surfaces(
+                0,
+                desktop_width,
+                desktop_height,
+                surfaces,
+            )),
+            ..self
+        }
+    }
+}
+
+/// Supplies the configuration of each accepted connection.
+pub trait NowSessionFactory {
+    fn new_session(&mut self, session_id: SessionId) -> NowSessionConfig;
+}
+
+sa::assert_obj_safe!(NowSessionFactory);
+
+/// A message received on an established session.
+#[derive(Debug, Clone)]
+pub struct NowSessionPacket {
+    bytes: Vec<u8>,
+    channels_ctx: VirtChannelsCtx,
+}
+
+impl NowSessionPacket {
+    pub(crate) fn new(bytes: Vec<u8>, channels_ctx: VirtChannelsCtx) -> Self {
+        Self { bytes, channels_ctx }
+    }
+
+    pub fn packet(&self) -> Result<NowPacket<'_>, ProtoError> {
+        let header = NowHeader::decode(&self.bytes)?;
+        let header_len = header.len();
+        NowPacket::decode_from(header, &self.bytes[header_len..], &self.channels_ctx)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Established session.
+///
+/// Dropping the handle (or calling [`cancel`](#method.cancel)) closes the session
+/// without affecting the others.
+pub struct NowServerSession {
+    id: SessionId,
+    channels_ctx: VirtChannelsCtx,
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    incoming: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl NowServerSession {
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    pub fn get_channels_ctx(&self) -> &VirtChannelsCtx {
+        &self.channels_ctx
+    }
+
+    pub fn send<'a, P: Into<NowPacket<'a>>>(&self, packet: P) -> Result<(), ProtoError> {
+        let bytes = packet.into().encode()?;
+        self.outgoing
+            .send(bytes)
+            .ok()
+            .chain(ProtoErrorKind::Server)
+            .or_else_desc(|| format!("session {} is closed", self.id))
+    }
+
+    /// Next message not processed by the session (that is, not a virtual channel message).
+    /// Returns `None` once the session is closed.
+    pub async fn recv(&mut self) -> Option<NowSessionPacket> {
+        let bytes = self.incoming.recv().await?;
+        Some(NowSessionPacket {
+            bytes,
+            channels_ctx: self.channels_ctx.clone(),
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.outgoing.is_closed()
+    }
+
+    /// Sends a terminate message and closes the session.
+    pub fn cancel(self) {
+        let _ = self.send(NowTerminateMsg::default());
+    }
+}
+
+/// Viewers of the sessions that can be handed off, indexed by the session they are attached to.
+type ViewersRc = Rc<RefCell<BTreeMap<SessionId, mpsc::UnboundedSender<NowTerminateMsg>>>>;
+
+pub struct NowAsyncServer<Factory> {
+    factory: Factory,
+    handshake_timeout: Duration,
+    sessions: Arc<Semaphore>,
+    viewers: ViewersRc,
+    next_session_id: SessionId,
+    established_tx: mpsc::UnboundedSender<NowServerSession>,
+    established_rx: mpsc::UnboundedReceiver<NowServerSession>,
+}
+
+impl<Factory> NowAsyncServer<Factory>
+where
+    Factory: NowSessionFactory + 'static,
+{
+    pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const DEFAULT_MAX_SESSIONS: usize = 64;
+
+    pub fn builder(factory: Factory) -> NowAsyncServerBuilder<Factory> {
+        NowAsyncServerBuilder {
+            factory,
+            handshake_timeout: Self::DEFAULT_HANDSHAKE_TIMEOUT,
+            max_sessions: Self::DEFAULT_MAX_SESSIONS,
+        }
+    }
+
+    pub fn new(factory: Factory, handshake_timeout: Duration, max_sessions: usize) -> Self {
+        let (established_tx, established_rx) = mpsc::unbounded_channel();
+        Self {
+            factory,
+            handshake_timeout,
+            sessions: Arc::new(Semaphore::new(max_sessions)),
+            viewers: Rc::new(RefCell::new(BTreeMap::new())),
+            next_session_id: 0,
+            established_tx,
+            established_rx,
+        }
+    }
+
+    /// Number of sessions that can still be accepted.
+    pub fn available_sessions(&self) -> usize {
+        self.sessions.available_permits()
+    }
+
+    /// Starts the connection sequence on the given stream.
+    ///
+    /// Fails right away when the maximum count of concurrent sessions is reached.
+    /// The session is handed out by [`next_session`](#method.next_session) once established.
+    pub fn accept<S>(&mut self, stream: S) -> Result<SessionId, ProtoError>
+    where
+        S: AsyncRead + AsyncWrite + 'static,
+    {
+        let permit = Arc::clone(&self.sessions)
+            .try_acquire_owned()
+            .ok()
+            .chain(ProtoErrorKind::Server)
+            .or_desc("maximum count of concurrent sessions reached")?;
+
+        let id = self.next_session_id;
+        self.next_session_id = self.next_session_id.wrapping_add(1);
+
+        let config = self.factory.new_session(id);
+        let task = SessionTask::new(id, config, self.handshake_timeout, Rc::clone(&self.viewers), permit);
+        let established_tx = self.established_tx.clone();
+        tokio::task::spawn_local(async move {
+            match task.run(stream, established_tx).await {
+                Ok(()) => log::info!("session {} closed", id),
+                Err(err) => {
+                    err.print_trace();
+                    log::error!("session {} failed: {}", id, err)
+                }
+            }
+        });
+
+        Ok(id)
+    }
+
+    /// Next session having completed the connection sequence.
+    pub async fn next_session(&mut self) -> Option<NowServerSession> {
+        self.established_rx.recv().await
+    }
+
+    /// Next session having completed the connection sequence, accepting meanwhile the connections
+    /// produced by `accept`, typically `TcpListener::accept`.
+    ///
+    /// Connections beyond the maximum count of concurrent sessions are closed right away.
+    /// `accept` must be cancel safe: its pending future is dropped once a session is established.
+    pub async fn next_session_from<S, A, Fut>(&mut self, mut accept: A) -> Result<NowServerSession, ProtoError>
+    where
+        S: AsyncRead + AsyncWrite + 'static,
+        A: FnMut() -> Fut,
+        Fut: Future<Output = io::Result<S>>,
+    {
+        loop {
+            tokio::select! {
+                session = self.established_rx.recv() => {
+                    // a sender is kept by the server itself
+                    return Ok(session.expect("established sessions channel closed"));
+                }
+                stream = accept() => {
+                    let stream = stream.map_err(ProtoError::from).or_desc("couldn't accept connection")?;
+                    if let Err(err) = self.accept(stream) {
+                        log::warn!("connection refused: {}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// builder
+
+pub struct NowAsyncServerBuilder<Factory> {
+    factory: Factory,
+    handshake_timeout: Duration,
+    max_sessions: usize,
+}
+
+impl<Factory> NowAsyncServerBuilder<Factory>
+where
+    Factory: NowSessionFactory + 'static,
+{
+    /// Maximum duration of the connection sequence.
+    pub fn handshake_timeout(self, handshake_timeout: Duration) -> Self {
+        Self {
+            handshake_timeout,
+            ..self
+        }
+    }
+
+    /// Maximum count of concurrent sessions, including those in the connection sequence.
+    pub fn max_sessions(self, max_sessions: usize) -> Self {
+        Self { max_sessions, ..self }
+    }
+
+    pub fn build(self) -> NowAsyncServer<Factory> {
+        NowAsyncServer::new(self.factory, self.handshake_timeout, self.max_sessions)
+    }
+}
+
+// pool
+
+/// Event of a [`SessionPool`](struct.SessionPool.html).
+#[derive(Debug)]
+pub enum SessionPoolEvent {
+    Packet(SessionId, NowSessionPacket),
+    /// The session was closed and removed from the pool.
+    Closed(SessionId),
+}
+
+/// Established sessions of a gateway or bot, indexed by id.
+///
+/// Each session is still driven by its own local task: the pool only multiplexes the messages
+/// received by all of them through [`next_event`](#method.next_event).
+pub struct SessionPool {
+    sessions: BTreeMap<SessionId, NowServerSession>,
+    max_sessions: usize,
+    /// Session polled last, so that a busy session doesn't starve the others.
+    last_polled: Option<SessionId>,
+}
+
+impl Default for SessionPool {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_SESSIONS)
+    }
+}
+
+impl SessionPool {
+    pub const DEFAULT_MAX_SESSIONS: usize = 64;
+
+    pub fn new(max_sessions: usize) -> Self {
+        Self {
+            sessions: BTreeMap::new(),
+            max_sessions,
+            last_polled: None,
+        }
+    }
+
+    pub fn max_sessions(&self) -> usize {
+        self.max_sessions
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.sessions.len() >= self.max_sessions
+    }
+
+    /// Adds an established session to the pool.
+    ///
+    /// When the pool is full or already has a session with the same id, the session is cancelled.
+    pub fn insert(&mut self, session: NowServerSession) -> Result<(), ProtoError> {
+        let id = session.id();
+        if self.is_full() {
+            session.cancel();
+            return ProtoError::new(ProtoErrorKind::Server)
+                .or_else_desc(|| format!("session pool is full ({} sessions)", self.max_sessions));
+        }
+        if self.sessions.contains_key(&id) {
+            session.cancel();
+            return ProtoError::new(ProtoErrorKind::Server).or_else_desc(|| format!("session {} already pooled", id));
+        }
+
+        self.sessions.insert(id, session);
+        Ok(())
+    }
+
+    pub fn contains(&self, id: SessionId) -> bool {
+        self.sessions.contains_key(&id)
+    }
+
+    pub fn get(&self, id: SessionId) -> Option<&NowServerSession> {
+        self.sessions.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: SessionId) -> Option<&mut NowServerSession> {
+        self.sessions.get_mut(&id)
+    }
+
+    /// Takes the session out of the pool without closing it.
+    pub fn remove(&mut self, id: SessionId) -> Option<NowServerSession> {
+        self.sessions.remove(&id)
+    }
+
+    /// Sends a terminate message and closes the session.
+    pub fn cancel(&mut self, id: SessionId) -> bool {
+        match self.sessions.remove(&id) {
+            Some(session) => {
+                session.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn cancel_all(&mut self) {
+        for (_, session) in core::mem::take(&mut self.sessions) {
+            session.cancel();
+        }
+    }
+
+    /// Sessions ordered by id.
+    pub fn iter(&self) -> impl Iterator<Item = &NowServerSession> {
+        self.sessions.values()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut NowServerSession> {
+        self.sessions.values_mut()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = SessionId> + '_ {
+        self.sessions.keys().copied()
+    }
+
+    pub fn send<'a, P: Into<NowPacket<'a>>>(&self, id: SessionId, packet: P) -> Result<(), ProtoError> {
+        self.sessions
+            .get(&id)
+            .chain(ProtoErrorKind::Server)
+            .or_else_desc(|| format!("no session {} in pool", id))?
+            .send(packet)
+    }
+
+    /// Sends the packet to every session. Returns the ids of the sessions already closed.
+    pub fn broadcast<'a, P: Into<NowPacket<'a>>>(&self, packet: P) -> Result<Vec<SessionId>, ProtoError> {
+        let bytes = packet.into().encode()?;
+        Ok(self
+            .sessions
+            .values()
+            .filter(|session| session.outgoing.send(bytes.clone()).is_err())
+            .map(NowServerSession::id)
+            .collect())
+    }
+
+    /// Next message received by any of the sessions, or closure of one of them.
+    ///
+    /// Closed sessions are removed from the pool. Returns `None` when the pool is empty.
+    pub async fn next_event(&mut self) -> Option<SessionPoolEvent> {
+        if self.sessions.is_empty() {
+            return None;
+        }
+
+        let (id, bytes) = core::future::poll_fn(|cx| {
+            // round robin, starting after the session polled last
+            let start = self.last_polled.map(|id| id.wrapping_add(1)).unwrap_or(0);
+            let (after, before): (Vec<_>, Vec<_>) = self.sessions.iter_mut().partition(|(id, _)| **id >= start);
+            for (id, session) in after.into_iter().chain(before) {
+                if let Poll::Ready(bytes) = session.incoming.poll_recv(cx) {
+                    return Poll::Ready((*id, bytes));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+
+        self.last_polled = Some(id);
+        match bytes {
+            Some(bytes) => {
+                let channels_ctx = self.sessions[&id].channels_ctx.clone();
+                Some(SessionPoolEvent::Packet(id, NowSessionPacket { bytes, channels_ctx }))
+            }
+            None => {
+                self.sessions.remove(&id);
+                Some(SessionPoolEvent::Closed(id))
+            }
+        }
+    }
+}
+
+// session task
+
+/// Forwards messages unprocessed by the sharee to the session handle.
+struct ForwardCallback {
+    incoming: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl ShareeCallbackTrait for ForwardCallback {
+    fn on_unprocessed_message<'msg: 'a, 'a>(&mut self, message: &'a NowMessage<'msg>) -> ShareeResult<'msg> {
+        let bytes = NowPacket::from_message(message.clone()).encode()?;
+        // handle may have been dropped already: the session is closing anyway
+        let _ = self.incoming.send(bytes);
+        Ok(None)
+    }
+}
+
+struct SessionTask {
+    id: SessionId,
+    handshake_timeout: Duration,
+    surfaces: Option<NowSurfaceListReqMsg>,
+    connection: NowConnection<ServerConnectionSeqSM<DummyConnectionSeqCallback>, ForwardCallback>,
+    incoming_rx: Option<mpsc::UnboundedReceiver<Vec<u8>>>,
+    handoff_registry: Option<HandoffRegistryRc>,
+    viewers: ViewersRc,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl SessionTask {
+    fn new(
+        id: SessionId,
+        config: NowSessionConfig,
+        handshake_timeout: Duration,
+        viewers: ViewersRc,
+        permit: OwnedSemaphorePermit,
+    ) -> Self {
+        let mut connection_seq = ServerConnectionSeqSM::new(
+            DummyConnectionSeqCallback,
+            config.available_auth_types,
+            config.authenticate_sm,
+            config.capabilities,
+            config.available_channels.into_iter().map(NowChannelDef::new).collect(),
+        );
+        connection_seq.set_access_control(config.access_control);
+        connection_seq.set_handoff_registry(config.handoff_registry.clone());
+        connection_seq.set_versions(config.versions);
+        if config.handoff_registry.is_some() {
+            // bound to the handoff tokens issued, unless the client takes over another session
+            let shared_data = connection_seq
+                .get_shared_data()
+                .expect("server connection sequence has shared data");
+            shared_data.borrow_mut().session_id = Some(id);
+        }
+
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let mut sharee = Sharee::new(
+            connection_seq,
+            config.channels_manager,
+            ForwardCallback { incoming: incoming_tx },
+        );
+        sharee.set_auto_responder(config.auto_responder);
+        sharee.set_hooks(config.hooks);
+        if let Some(handoff_registry) = &config.handoff_registry {
+            sharee.set_handoff_registry(Rc::clone(handoff_registry));
+        }
+
+        Self {
+            id,
+            handshake_timeout,
+            surfaces: config.surfaces,
+            connection: NowConnection::new(sharee),
+            incoming_rx: Some(incoming_rx),
+            handoff_registry: config.handoff_registry,
+            viewers,
+            _permit: permit,
+        }
+    }
+
+    async fn run<S>(
+        mut self,
+        stream: S,
+        established_tx: mpsc::UnboundedSender<NowServerSession>,
+    ) -> Result<(), ProtoError>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+
+        tokio::time::timeout(
+            self.handshake_timeout,
+            self.__connection_sequence(&mut reader, &mut writer),
+        )
+        .await
+        .ok()
+        .chain(ProtoErrorKind::Server)
+        .or_desc("connection sequence timed out")??;
+
+        log::info!("session {} established", self.id);
+
+        let (handed_off_tx, mut handed_off_rx) = mpsc::unbounded_channel();
+        self.__attach_viewer(handed_off_tx);
+
+        if let Some(surfaces) = self.surfaces.take() {
+            self.connection.send(NowSurfaceMsg::ListReq(surfaces), Instant::now())?;
+            self.__transmit(&mut writer).await?;
+        }
+
+        let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel();
+        let session = NowServerSession {
+            id: self.id,
+            channels_ctx: self.connection.get_sharee().get_channels_ctx().clone(),
+            outgoing: outgoing_tx,
+            incoming: self.incoming_rx.take().expect("session handed out twice"), // established only once
+        };
+        if established_tx.send(session).is_err() {
+            log::warn!("server dropped: closing session {}", self.id);
+            return Ok(());
+        }
+
+        let mut buf = [0; 4096];
+        loop {
+            tokio::select! {
+                read = reader.read(&mut buf) => {
+                    let n = read?;
+                    if n == 0 {
+                        log::debug!("session {}: connection closed by peer", self.id);
+                        return Ok(());
+                    }
+                    self.__feed(&buf[..n])?;
+                    if self.connection.is_terminated() {
+                        self.connection.send(NowTerminateMsg::default(), Instant::now())?;
+                        // the client may close the link without waiting for the acknowledgement
+                        if let Err(err) = self.__transmit(&mut writer).await {
+                            log::debug!("session {}: couldn't acknowledge termination: {}", self.id, err);
+                        }
+                        return Ok(());
+                    }
+                    self.__transmit(&mut writer).await?;
+                }
+                Some(terminate) = handed_off_rx.recv() => {
+                    log::info!("session {}: handed off to another viewer", self.id);
+                    self.connection.send(terminate, Instant::now())?;
+                    self.__transmit(&mut writer).await?;
+                    return Ok(());
+                }
+                outgoing = outgoing_rx.recv() => match outgoing {
+                    Some(bytes) => {
+                        self.connection.send_encoded(bytes, Instant::now())?;
+                        self.__transmit(&mut writer).await?;
+                    }
+                    None => {
+                        log::debug!("session {}: handle dropped", self.id);
+                        return Ok(());
+                    }
+                }
+            }
+
+            if self.connection.is_terminated() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn __connection_sequence<S>(
+        &mut self,
+        reader: &mut ReadHalf<S>,
+        writer: &mut WriteHalf<S>,
+    ) -> Result<(), ProtoError>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        self.connection.update(Instant::now())?;
+
+        let mut buf = [0; 4096];
+        loop {
+            self.__transmit(writer).await?;
+            if self.connection.get_state() != ShareeState::Connection {
+                break;
+            }
+
+            let n = match self.connection.next_deadline() {
+                Some(deadline) => tokio::select! {
+                    read = reader.read(&mut buf) => read?,
+                    _ = tokio::time::sleep_until(tokio::time::Instant::from_std(deadline)) => {
+                        self.connection.update(Instant::now())?;
+                        continue;
+                    }
+                },
+                None => reader.read(&mut buf).await?,
+            };
+            if n == 0 {
+                return ProtoError::new(ProtoErrorKind::Server).or_desc("connection closed during connection sequence");
+            }
+            self.__feed(&buf[..n])?;
+        }
+
+        if self.connection.is_terminated() {
+            ProtoError::new(ProtoErrorKind::Server).or_desc("connection sequence failed")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Disconnects the viewers of the sessions handed off, the last one typically taken over by this client,
+    /// then attaches this client as the viewer of its session.
+    fn __attach_viewer(&mut self, handed_off_tx: mpsc::UnboundedSender<NowTerminateMsg>) {
+        let registry = match &self.handoff_registry {
+            Some(registry) => registry,
+            None => return,
+        };
+
+        let mut viewers = self.viewers.borrow_mut();
+        while let Some((session_id, terminate)) = registry.borrow_mut().next_handed_off() {
+            match viewers.remove(&session_id) {
+                Some(viewer) => {
+                    // viewer may be closing already
+                    let _ = viewer.send(terminate);
+                }
+                None => log::debug!("session {} handed off without viewer to disconnect", session_id),
+            }
+        }
+
+        if let Some(session_id) = self.connection.get_sharee().get_session_id() {
+            viewers.insert(session_id, handed_off_tx);
+        }
+    }
+
+    fn __feed(&mut self, bytes: &[u8]) -> Result<(), ProtoError> {
+        for event in self.connection.feed_bytes(bytes, Instant::now())? {
+            if let ConnectionEvent::Processed(body_type) = event {
+                log::debug!("session {}: received {:?} packet", self.id, body_type);
+            }
+        }
+        Ok(())
+    }
+
+    async fn __transmit<S>(&mut self, writer: &mut WriteHalf<S>) -> Result<(), ProtoError>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        while let Some(frame) = self.connection.poll_transmit() {
+            writer.write_all(&frame).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SessionTask {
+    fn drop(&mut self) {
+        // receivers are dropped along with the tasks of their viewers
+        self.viewers.borrow_mut().retain(|_, viewer| !viewer.is_closed());
+    }
+}
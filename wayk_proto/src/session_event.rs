@@ -0,0 +1,238 @@
+//! Typed session events.
+//!
+//! Instead of matching raw messages in a [`ShareeCallbackTrait`](../sharee/trait.ShareeCallbackTrait.html)
+//! implementation, applications can give a [`SessionEventSubscriber`](trait.SessionEventSubscriber.html)
+//! to [`SessionEvents`](struct.SessionEvents.html) and receive [`SessionEvent`](enum.SessionEvent.html)s.
+//! Senders of `std` and tokio channels are subscribers, so that GUI applications can wire events
+//! directly into their event loop.
+//!
+//! `SessionEvents` is the sharee callback, its [`connection_seq_callback`](struct.SessionEvents.html#method.connection_seq_callback)
+//! is given to the connection sequence state machine to report authentication.
+
+use crate::{
+    error::ProtoError,
+    message::{
+        ChannelName, Codec, DisconnectStatusCode, NowMessage, NowStatus, NowUpdateGraphicsMsg, NowUpdateMsg, SizeRect,
+        UpdateGraphicsFlags,
+    },
+    sharee::{ShareeCallbackTrait, ShareeResult},
+    sm::{ConnectionSMSharedData, ConnectionSeqCallbackTrait, SurfaceEvent},
+};
+use std::{cell::RefCell, rc::Rc};
+
+/// Graphics update, owning its data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphicsUpdate {
+    pub codec_id: Codec,
+    pub surface_id: u16,
+    pub frame_id: u16,
+    pub update_flags: UpdateGraphicsFlags,
+    pub update_rect: SizeRect,
+    pub update_data: Vec<u8>,
+}
+
+impl From<&NowUpdateGraphicsMsg<'_>> for GraphicsUpdate {
+    fn from(msg: &NowUpdateGraphicsMsg<'_>) -> Self {
+        Self {
+            codec_id: msg.codec_id,
+            surface_id: msg.surface_id,
+            frame_id: msg.frame_id,
+            update_flags: msg.update_flags,
+            update_rect: msg.update_rect.clone(),
+            update_data: msg.update_data.0.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TerminateReason {
+    /// The peer terminated the session.
+    Peer(NowStatus<DisconnectStatusCode>),
+    /// The connection sequence failed.
+    ConnectionFailed(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    /// The connection sequence completed.
+    Connected {
+        session_id: Option<u32>,
+    },
+    Authenticated,
+    /// A virtual channel was opened by the connection sequence.
+    ChannelOpened(ChannelName),
+    /// The remote monitor topology changed.
+    SurfaceListChanged(SurfaceEvent),
+    UpdateReceived(GraphicsUpdate),
+    Terminated {
+        reason: TerminateReason,
+    },
+}
+
+pub trait SessionEventSubscriber {
+    fn on_event(&mut self, event: SessionEvent);
+}
+
+impl<F> SessionEventSubscriber for F
+where
+    F: FnMut(SessionEvent),
+{
+    fn on_event(&mut self, event: SessionEvent) {
+        self(event)
+    }
+}
+
+/// Events sent after the receiver is dropped are discarded.
+impl SessionEventSubscriber for std::sync::mpsc::Sender<SessionEvent> {
+    fn on_event(&mut self, event: SessionEvent) {
+        let _ = self.send(event);
+    }
+}
+
+/// Events sent after the receiver is dropped are discarded.
+#[cfg(feature = "async")]
+impl SessionEventSubscriber for tokio::sync::mpsc::UnboundedSender<SessionEvent> {
+    fn on_event(&mut self, event: SessionEvent) {
+        let _ = self.send(event);
+    }
+}
+
+/// Sharee callback reporting typed events to a subscriber.
+pub struct SessionEvents<S> {
+    subscriber: Rc<RefCell<S>>,
+}
+
+impl<S> SessionEvents<S>
+where
+    S: SessionEventSubscriber,
+{
+    pub fn new(subscriber: S) -> Self {
+        Self {
+            subscriber: Rc::new(RefCell::new(subscriber)),
+        }
+    }
+
+    /// Callback of the connection sequence state machine, reporting to the same subscriber.
+    pub fn connection_seq_callback(&self) -> SessionEventsSeqCallback<S> {
+        SessionEventsSeqCallback {
+            subscriber: Rc::clone(&self.subscriber),
+        }
+    }
+
+    fn __emit(&self, event: SessionEvent) {
+        self.subscriber.borrow_mut().on_event(event);
+    }
+}
+
+impl<S> ShareeCallbackTrait for SessionEvents<S>
+where
+    S: SessionEventSubscriber,
+{
+    fn on_enter_active_state(&mut self, shared_data: &ConnectionSMSharedData) {
+        self.__emit(SessionEvent::Connected {
+            session_id: shared_data.session_id,
+        });
+        for def in &shared_data.channels {
+            self.__emit(SessionEvent::ChannelOpened(def.name.clone()));
+        }
+    }
+
+    fn on_any_message<'msg: 'a, 'a>(&mut self, message: &'a NowMessage<'msg>) {
+        if let NowMessage::Terminate(msg) = message {
+            self.__emit(SessionEvent::Terminated {
+                reason: TerminateReason::Peer(msg.status.clone()),
+            });
+        }
+    }
+
+    fn on_unprocessed_message<'msg: 'a, 'a>(&mut self, message: &'a NowMessage<'msg>) -> ShareeResult<'msg> {
+        if let NowMessage::Update(NowUpdateMsg::UpdateGraphics(msg)) = message {
+            self.__emit(SessionEvent::UpdateReceived(GraphicsUpdate::from(msg)));
+        }
+        Ok(None)
+    }
+
+    fn on_surface_event(&mut self, event: &SurfaceEvent) {
+        self.__emit(SessionEvent::SurfaceListChanged(event.clone()));
+    }
+
+    fn on_connection_failed(&mut self, error: &ProtoError) {
+        self.__emit(SessionEvent::Terminated {
+            reason: TerminateReason::ConnectionFailed(error.to_string()),
+        });
+    }
+}
+
+/// Connection sequence callback of [`SessionEvents`](struct.SessionEvents.html).
+pub struct SessionEventsSeqCallback<S> {
+    subscriber: Rc<RefCell<S>>,
+}
+
+impl<S> ConnectionSeqCallbackTrait for SessionEventsSeqCallback<S>
+where
+    S: SessionEventSubscriber,
+{
+    fn on_authenticate_completed(&mut self, _: &ConnectionSMSharedData) {
+        self.subscriber.borrow_mut().on_event(SessionEvent::Authenticated);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channels_manager::ChannelsManager,
+        message::{AuthType, NowCapset, NowTerminateMsg, TransportCapset},
+        sharee::{DummyShareeCallback, Sharee},
+        sm::{ClientConnectionSeqSM, DummyConnectionSeqCallback, NowConnection, ServerConnectionSeqSM},
+    };
+    use std::time::Instant;
+
+    #[test]
+    fn typed_events_through_channel() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let events = SessionEvents::new(tx);
+
+        let client_seq = ClientConnectionSeqSM::builder(events.connection_seq_callback())
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .channels_to_open(vec![ChannelName::Chat])
+            .build();
+        let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![ChannelName::Chat])
+            .build();
+        let mut client = NowConnection::new(Sharee::new(client_seq, ChannelsManager::new(), events));
+        let mut server = NowConnection::new(Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback));
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        for _ in 0..32 {
+            while let Some(frame) = client.poll_transmit() {
+                server.feed_bytes(&frame, Instant::now()).unwrap();
+            }
+            while let Some(frame) = server.poll_transmit() {
+                client.feed_bytes(&frame, Instant::now()).unwrap();
+            }
+        }
+
+        server.send(NowTerminateMsg::default(), Instant::now()).unwrap();
+        client
+            .feed_bytes(&server.poll_transmit().unwrap(), Instant::now())
+            .unwrap();
+
+        let received: Vec<_> = rx.try_iter().collect();
+        assert_eq!(
+            received,
+            vec![
+                SessionEvent::Authenticated,
+                SessionEvent::Connected { session_id: None },
+                SessionEvent::ChannelOpened(ChannelName::Chat),
+                SessionEvent::Terminated {
+                    reason: TerminateReason::Peer(NowTerminateMsg::default().status),
+                },
+            ]
+        );
+    }
+}
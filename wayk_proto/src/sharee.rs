@@ -1,12 +1,26 @@
 /** SHAREE **/
-use crate::message::{NowBody, NowMessage, VirtChannelsCtx};
+use crate::message::{NowBody, NowMessage, NowSurfaceMsg, VirtChannelsCtx};
 use crate::{
-    channels_manager::{ChannelsManager, ChannelsManagerResult},
-    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
-    message::NowTerminateMsg,
+    channels_manager::{ChannelHandler, ChannelsManager, ChannelsManagerResult},
+    error::{ErrorClass, ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        ChannelDefFlags, ChannelMessageType, ChannelName, DecodeMode, NowBatchMsg, NowCapabilitiesMsg, NowCapset,
+        NowChannelDef, NowChannelMsg, NowChannelWindowMsg, NowSessionMsg, NowTerminateMsg,
+    },
+    middleware::{HookAction, MessageHook, MessageHooks},
     packet::NowPacket,
-    sm::{ConnectionSM, ConnectionSMResult, ConnectionSMSharedData, ConnectionSMSharedDataRc},
+    serialization::{known_capabilities, DecodeLimits, EncodeCtx},
+    sm::{
+        is_channel_failure, AutoResponder, ChannelCloseReason, ChannelLifecycle, ChannelLifecycleEvent,
+        ChannelLifecycleState, ConnectionSM, ConnectionSMResult, ConnectionSMSharedData, ConnectionSMSharedDataRc,
+        HandoffRegistryRc, LinkQuality, SessionGuard, SessionGuards, SurfaceEvent, SurfaceEventQueue, SurfaceManager,
+        VirtualChannelSM,
+    },
+    state_report::{ChannelReport, SessionStateReport},
+    version::{NowProtocolVersion, ProtocolFeature},
 };
+use alloc::collections::BTreeSet;
+use std::time::Instant;
 
 pub type ShareeResult<'a> = Result<Option<NowPacket<'a>>, ProtoError>;
 
@@ -25,6 +39,32 @@ pub trait ShareeCallbackTrait {
         #![allow(unused_variables)]
         Ok(None)
     }
+
+    /// called when a surface list update changes the remote monitor topology,
+    /// before the list message itself is delivered.
+    fn on_surface_event(&mut self, event: &SurfaceEvent) {
+        #![allow(unused_variables)]
+    }
+
+    /// called before the messages of a batch are delivered. Presentation should be deferred until the batch ends.
+    fn on_batch_begin(&mut self, messages_count: usize) {
+        #![allow(unused_variables)]
+    }
+
+    /// called once all messages of a batch are delivered.
+    fn on_batch_end(&mut self) {}
+
+    /// called when the peer updates its capabilities after the connection sequence,
+    /// once channel state machines are notified.
+    fn on_capabilities_updated(&mut self, peer_capabilities: &[NowCapset<'static>]) {
+        #![allow(unused_variables)]
+    }
+
+    /// called when the connection sequence fails, the sharee entering final or interrupted state
+    /// depending on the class of `error`.
+    fn on_connection_failed(&mut self, error: &ProtoError) {
+        #![allow(unused_variables)]
+    }
 }
 
 sa::assert_obj_safe!(ShareeCallbackTrait);
@@ -36,6 +76,8 @@ impl ShareeCallbackTrait for DummyShareeCallback {}
 pub enum ShareeState {
     Connection,
     Active,
+    /// The link failed: the session is to be resumed with `Sharee::reconnect` over a new link.
+    Interrupted,
     Final,
 }
 
@@ -46,6 +88,18 @@ pub struct Sharee<ConnectionSeq, UserCallback> {
     user_callback: UserCallback,
     shared_data: ConnectionSMSharedDataRc,
     channels_ctx: VirtChannelsCtx,
+    channels_lifecycle: ChannelLifecycle,
+    /// Channels to open again once their close request is acknowledged.
+    resetting: BTreeSet<ChannelName>,
+    /// Channels available on the peer, as last announced after the connection sequence.
+    peer_channels: Option<Vec<ChannelName>>,
+    surfaces: SurfaceManager<SurfaceEventQueue>,
+    last_error: Option<String>,
+    failure: Option<ErrorClass>,
+    hooks: MessageHooks,
+    auto_responder: AutoResponder,
+    session_guards: SessionGuards,
+    handoff_registry: Option<HandoffRegistryRc>,
 }
 
 impl<ConnectionSeq, UserCallback> Sharee<ConnectionSeq, UserCallback>
@@ -64,6 +118,16 @@ where
             user_callback,
             shared_data,
             channels_ctx: VirtChannelsCtx::new(),
+            channels_lifecycle: ChannelLifecycle::new(),
+            resetting: BTreeSet::new(),
+            peer_channels: None,
+            surfaces: SurfaceManager::new(SurfaceEventQueue::default()),
+            last_error: None,
+            failure: None,
+            hooks: MessageHooks::new(),
+            auto_responder: AutoResponder::default(),
+            session_guards: SessionGuards::new(),
+            handoff_registry: None,
         }
     }
 
@@ -75,23 +139,194 @@ where
         &self.connection_seq
     }
 
+    /// Session associated by the connection sequence, if the server provided one.
+    pub fn get_session_id(&self) -> Option<u32> {
+        self.shared_data.borrow().session_id
+    }
+
+    /// Runs a new connection sequence, typically re-attaching to the session over a new connection
+    /// once interrupted.
+    ///
+    /// Virtual channel state machines, surfaces, decoding settings and the user callback are kept as is,
+    /// channels are reopened by the new sequence.
+    pub fn reconnect(&mut self, connection_sm: ConnectionSeq) {
+        self.shared_data = connection_sm
+            .get_shared_data()
+            .expect("couldn't retrieve shared data from connection sequence state machine"); // should never panic
+        self.connection_seq = connection_sm;
+        self.channels_ctx.clear_channels();
+        // negotiated again by the new sequence
+        self.channels_ctx.set_version(NowProtocolVersion::CURRENT);
+        self.channels_lifecycle.on_session_terminated();
+        self.resetting.clear();
+        if let Some(liveness) = self.channels_manager.get_liveness_mut() {
+            liveness.on_channels_closed();
+        }
+        self.failure = None;
+        self.state = ShareeState::Connection;
+    }
+
+    pub fn get_channels_manager(&self) -> &ChannelsManager {
+        &self.channels_manager
+    }
+
+    /// Remote monitor topology as known from surface list updates.
+    pub fn get_surfaces(&self) -> &SurfaceManager<SurfaceEventQueue> {
+        &self.surfaces
+    }
+
     pub fn is_terminated(&self) -> bool {
         self.state == ShareeState::Final
     }
 
+    pub fn is_interrupted(&self) -> bool {
+        self.state == ShareeState::Interrupted
+    }
+
+    /// `true` while connecting or active.
     pub fn is_running(&self) -> bool {
-        !self.is_terminated()
+        matches!(self.state, ShareeState::Connection | ShareeState::Active)
+    }
+
+    /// Class of the failure that stopped the sharee, if any.
+    pub fn failure(&self) -> Option<ErrorClass> {
+        self.failure
+    }
+
+    /// Records a failure that happened outside the sharee, typically on the link, and transitions accordingly:
+    /// a transport failure interrupts the session, an authentication rejection or a termination
+    /// by the peer ends it. Other failures leave the sharee as is.
+    pub fn on_error(&mut self, error: &ProtoError) {
+        self.last_error = Some(error.to_string());
+        match error.class() {
+            class @ ErrorClass::Transport | class @ ErrorClass::AuthRejected | class @ ErrorClass::PeerTerminated => {
+                self.__fail(class)
+            }
+            ErrorClass::ProtocolViolation | ErrorClass::Other => {}
+        }
     }
 
-    pub fn waiting_for_packet(&self) -> bool {
+    /// Instant at which the sharee is to be updated again even if no packet is received, while connecting
+    /// or while waiting for answers over channels whose liveness is checked.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match self.state {
+            ShareeState::Connection => self.connection_seq.next_deadline(),
+            ShareeState::Active => self
+                .channels_manager
+                .get_liveness()
+                .and_then(|liveness| liveness.next_deadline()),
+            ShareeState::Interrupted | ShareeState::Final => None,
+        }
+    }
+
+    pub fn waiting_for_packet(&self, now: Instant) -> bool {
         match self.state {
             ShareeState::Connection => self.connection_seq.waiting_for_packet(),
-            ShareeState::Active => self.channels_manager.waiting_for_packet(),
-            ShareeState::Final => false,
+            ShareeState::Active => {
+                self.channels_manager.waiting_for_packet()
+                    && !self.channels_manager.get_flow_control().has_pending_grant()
+                    && self.next_deadline().is_none_or(|deadline| deadline > now)
+            }
+            ShareeState::Interrupted | ShareeState::Final => false,
+        }
+    }
+
+    /// Routine messages answered by the sharee instead of being given to `on_unprocessed_message`.
+    pub fn set_auto_responder(&mut self, auto_responder: AutoResponder) {
+        self.auto_responder = auto_responder;
+    }
+
+    /// Adds a hook observing or rewriting the received bodies and the packets sent, see `middleware`.
+    pub fn add_hook<H: MessageHook + 'static>(&mut self, hook: H) {
+        self.hooks.add(hook);
+    }
+
+    pub fn set_hooks(&mut self, hooks: MessageHooks) {
+        self.hooks = hooks;
+    }
+
+    pub fn has_hooks(&self) -> bool {
+        !self.hooks.is_empty()
+    }
+
+    /// Adds host state reverted on termination, on fatal failures and once the link is lost,
+    /// such as the curtain mode. Desktop requests handled by the guard are answered by the sharee.
+    pub fn add_session_guard<G: SessionGuard + 'static>(&mut self, guard: G) {
+        self.session_guards.add(guard);
+    }
+
+    /// Answers handoff token requests of the peer with tokens of `handoff_registry` bound to the session
+    /// of this sharee, the registry needing a token source. Draining the sessions handed off, so that their
+    /// original viewers are disconnected, is left to the caller (see `HandoffRegistry::next_handed_off`).
+    pub fn set_handoff_registry(&mut self, handoff_registry: HandoffRegistryRc) {
+        self.handoff_registry = Some(handoff_registry);
+    }
+
+    /// Reports the link quality, typically as tracked by `ConnectionQuality`, to the session guards.
+    pub fn on_quality_changed(&mut self, current: LinkQuality) {
+        self.session_guards.on_quality_changed(current);
+    }
+
+    /// Runs the outgoing hooks on a packet sent by the application at `now`. `None` if a hook dropped it.
+    pub fn intercept_outgoing<'a>(&mut self, packet: NowPacket<'a>, now: Instant) -> Option<NowPacket<'a>> {
+        let packet = self.hooks.on_outgoing(packet)?;
+        if let (NowBody::VirtualChannel(chan_msg), Some(liveness)) =
+            (&packet.body, self.channels_manager.get_liveness_mut())
+        {
+            liveness.on_sent(chan_msg.get_name(), now);
+        }
+        Some(packet)
+    }
+
+    /// Updates the sharee without a received packet, liveness deadlines being checked against `now`.
+    pub fn update_without_body<'msg>(&mut self, now: Instant) -> ShareeResult<'msg> {
+        let result = self.__update_without_body(now);
+        self.__record_error(&result);
+        self.__intercept_answer(result)
+    }
+
+    /// Updates the sharee with a packet received at `now`.
+    pub fn update_with_body<'msg: 'a, 'a>(&mut self, body: &'a NowBody<'msg>, now: Instant) -> ShareeResult<'msg> {
+        let result = match self.hooks.on_incoming(body) {
+            HookAction::Forward => self.__update_with_body(body, now),
+            HookAction::Drop => Ok(None),
+            HookAction::Replace(body) => self.__update_with_body(&body, now),
+        };
+        self.__record_error(&result);
+        self.__intercept_answer(result)
+    }
+
+    /// Snapshot of the session state, its `Display` implementation being a readable dump for bug reports.
+    pub fn state_report(&self) -> SessionStateReport {
+        let shared_data = self.shared_data.borrow();
+        SessionStateReport {
+            state: self.state,
+            connection_step: match self.state {
+                ShareeState::Connection => self.connection_seq.current_step(),
+                ShareeState::Active | ShareeState::Interrupted | ShareeState::Final => None,
+            },
+            peer_version: shared_data.peer_version,
+            negotiated_version: shared_data.negotiated_version,
+            session_id: shared_data.session_id,
+            channels: self
+                .channels_ctx
+                .channels()
+                .map(|(id, name)| ChannelReport {
+                    name: name.clone(),
+                    id,
+                    status: self.channels_manager.channel_status(name),
+                })
+                .collect(),
+            pending_sequence_ids: Vec::new(),
+            last_error: self.last_error.clone(),
         }
     }
 
-    pub fn update_without_body<'msg>(&mut self) -> ShareeResult<'msg> {
+    fn __intercept_answer<'msg>(&mut self, result: ShareeResult<'msg>) -> ShareeResult<'msg> {
+        Ok(result?.and_then(|answer| self.hooks.on_outgoing(answer)))
+    }
+
+    fn __update_without_body<'msg>(&mut self, now: Instant) -> ShareeResult<'msg> {
         match self.state {
             ShareeState::Connection => {
                 let answer = self.connection_seq.update_without_message();
@@ -102,14 +337,35 @@ where
                 answer.map(|o| o.map(NowPacket::from))
             }
             ShareeState::Active => {
-                let result = self.channels_manager.update_without_virt_msg();
+                if let Some(liveness) = self.channels_manager.get_liveness_mut() {
+                    let mut any_stalled = false;
+                    while let Some(stalled) = liveness.poll(now) {
+                        self.channels_lifecycle.on_stalled(stalled);
+                        any_stalled = true;
+                    }
+                    // updated for the stalls only
+                    if any_stalled
+                        && self.channels_manager.waiting_for_packet()
+                        && !self.channels_manager.get_flow_control().has_pending_grant()
+                    {
+                        return Ok(None);
+                    }
+                }
+
+                if let Some((name, credits)) = self.channels_manager.get_flow_control_mut().poll_grant() {
+                    return Ok(Some(NowPacket::from_message(NowChannelWindowMsg::new(name, credits))));
+                }
+
+                let result = self.channels_manager.update_without_virt_msg(now);
                 self.__map_channels_manager_result(result)
             }
+            ShareeState::Interrupted => ProtoError::new(ProtoErrorKind::Sharee(self.state))
+                .or_desc("session interrupted: to be resumed over a new link"),
             ShareeState::Final => Ok(NowPacket::from_message(NowTerminateMsg::default()).into()),
         }
     }
 
-    pub fn update_with_body<'msg: 'a, 'a>(&mut self, body: &'a NowBody<'msg>) -> ShareeResult<'msg> {
+    fn __update_with_body<'msg: 'a, 'a>(&mut self, body: &'a NowBody<'msg>, now: Instant) -> ShareeResult<'msg> {
         match body {
             NowBody::Message(msg) => match self.state {
                 ShareeState::Connection => {
@@ -124,52 +380,559 @@ where
                 ShareeState::Active => match msg {
                     NowMessage::Terminate(_) => {
                         self.state = ShareeState::Final;
+                        self.channels_lifecycle.on_session_terminated();
+                        self.channels_manager.on_channels_closed();
+                        self.session_guards.on_disconnect();
                         self.user_callback.on_any_message(msg);
                         Ok(None)
                     }
+                    NowMessage::Batch(batch) => self.__deliver_batch(batch),
+                    NowMessage::Channel(channel_msg) if __is_lifecycle_msg(channel_msg) => {
+                        let answer = self.__update_channels_lifecycle(channel_msg);
+                        self.user_callback.on_any_message(msg);
+                        answer
+                    }
+                    NowMessage::ChannelWindow(window_msg) => {
+                        self.channels_manager
+                            .get_flow_control_mut()
+                            .on_window_update(&window_msg.name, window_msg.credits);
+                        if let Some(liveness) = self.channels_manager.get_liveness_mut() {
+                            liveness.on_acknowledged(&window_msg.name);
+                        }
+                        self.user_callback.on_any_message(msg);
+                        Ok(None)
+                    }
+                    NowMessage::Capabilities(capabilities_msg) => {
+                        self.__reconcile_peer_capabilities(&capabilities_msg.capabilities);
+                        self.user_callback.on_any_message(msg);
+                        Ok(None)
+                    }
+                    NowMessage::Session(NowSessionMsg::HandoffTokenReq(_)) if self.handoff_registry.is_some() => {
+                        let answer = self.__issue_handoff_token(now);
+                        self.user_callback.on_any_message(msg);
+                        answer
+                    }
                     msg => {
+                        if let Some(answer) = self.__respond(msg) {
+                            self.user_callback.on_any_message(msg);
+                            return Ok(answer);
+                        }
+
+                        self.__track_surfaces(msg);
                         let answer = self.user_callback.on_unprocessed_message(msg);
                         self.user_callback.on_any_message(msg);
                         answer
                     }
                 },
-                ShareeState::Final => ProtoError::new(ProtoErrorKind::Sharee(self.state))
-                    .or_desc("unexpected call to `Sharee::update_with_body` in final state with a now message"),
+                ShareeState::Interrupted | ShareeState::Final => ProtoError::new(ProtoErrorKind::Sharee(self.state))
+                    .or_else_desc(|| {
+                        format!(
+                            "unexpected call to `Sharee::update_with_body` in {:?} state with a now message",
+                            self.state
+                        )
+                    }),
             },
             NowBody::VirtualChannel(chan_msg) => match self.state {
                 ShareeState::Connection => ProtoError::new(ProtoErrorKind::Sharee(self.state)).or_desc(
                     "unexpected call to `Sharee::update_with_body` in connection state with a virtual channel message",
                 ),
                 ShareeState::Active => {
-                    let result = self.channels_manager.update_with_virt_msg(chan_msg);
+                    let result = self.channels_manager.update_with_virt_msg(chan_msg, now);
                     self.__map_channels_manager_result(result)
                 }
-                ShareeState::Final => ProtoError::new(ProtoErrorKind::Sharee(self.state)).or_desc(
-                    "unexpected call to `Sharee::update_with_body` in final state with a virtual channel message",
-                ),
+                ShareeState::Interrupted | ShareeState::Final => ProtoError::new(ProtoErrorKind::Sharee(self.state))
+                    .or_else_desc(|| {
+                        format!(
+                            "unexpected call to `Sharee::update_with_body` in {:?} state with a virtual channel message",
+                            self.state
+                        )
+                    }),
             },
         }
     }
 
+    /// Channels currently opened.
     pub fn get_channels_ctx(&self) -> &VirtChannelsCtx {
         &self.channels_ctx
     }
 
+    /// How unknown message subtypes received from the peer are decoded.
+    pub fn set_decode_mode(&mut self, mode: DecodeMode) {
+        self.channels_ctx.set_decode_mode(mode);
+    }
+
+    /// Bounds on what received packets can make the decoder allocate.
+    pub fn set_decode_limits(&mut self, limits: DecodeLimits) {
+        self.channels_ctx.set_decode_limits(limits);
+    }
+
+    /// `None` if `name` was never opened.
+    pub fn channel_state(&self, name: &ChannelName) -> Option<ChannelLifecycleState> {
+        self.channels_lifecycle.state(name)
+    }
+
+    /// Oldest channel opened or closed not polled yet, channels opened by the connection sequence included.
+    pub fn poll_channel_event(&mut self) -> Option<ChannelLifecycleEvent> {
+        self.channels_lifecycle.poll_event()
+    }
+
+    /// Requests the peer to open `name` once connected. The returned packet is to be sent to the peer.
+    ///
+    /// The channel is opened once the peer acknowledges it, see `channel_state`. A state machine or
+    /// a handler must be registered for the channel.
+    pub fn open_channel<'msg>(&mut self, name: ChannelName) -> Result<NowPacket<'msg>, ProtoError> {
+        if self.state != ShareeState::Active {
+            return ProtoError::new(ProtoErrorKind::Sharee(self.state))
+                .or_desc("channels can only be opened in active state");
+        }
+
+        if !self.channels_ctx.version().supports_channel(&name) {
+            return ProtoError::new(ProtoErrorKind::VirtualChannel(name))
+                .or_else_desc(|| format!("channel not supported by version {}", self.channels_ctx.version()));
+        }
+
+        if !self.channels_manager.channel_names().contains(&name) {
+            return ProtoError::new(ProtoErrorKind::VirtualChannel(name))
+                .or_desc("channel has no state machine nor handler");
+        }
+
+        Ok(NowPacket::from_message(self.channels_lifecycle.open_request(name)?))
+    }
+
+    /// Registers `handler` for the channel `name`, so that it can be opened later on by either peer.
+    ///
+    /// Fails if the channel is opened: it is to be closed first.
+    pub fn register_channel<H>(&mut self, name: ChannelName, handler: H) -> Result<(), ProtoError>
+    where
+        H: ChannelHandler + 'static,
+    {
+        self.__check_not_opened(&name)?;
+        self.channels_manager.register(name, handler);
+        Ok(())
+    }
+
+    /// Same as `register_channel`, with a channel state machine.
+    pub fn add_channel_sm<VirtChanSM>(&mut self, state_machine: VirtChanSM) -> Result<(), ProtoError>
+    where
+        VirtChanSM: VirtualChannelSM + 'static,
+    {
+        self.__check_not_opened(&state_machine.get_channel_name())?;
+        self.channels_manager.add_channel_sm(state_machine);
+        Ok(())
+    }
+
+    /// Requests the channels available on the peer. The returned packet is to be sent to the peer.
+    ///
+    /// The list previously announced is forgotten: it is known again once the peer answers, see `peer_channels`.
+    pub fn request_channel_list<'msg>(&mut self) -> Result<NowPacket<'msg>, ProtoError> {
+        if self.state != ShareeState::Active {
+            return ProtoError::new(ProtoErrorKind::Sharee(self.state))
+                .or_desc("channel list can only be requested in active state");
+        }
+
+        self.peer_channels = None;
+        Ok(NowPacket::from_message(NowChannelMsg::new(
+            ChannelMessageType::ChannelListRequest,
+            Vec::new(),
+        )))
+    }
+
+    /// Announces the channels available on this side, such as after registering a new one.
+    /// The returned packet is to be sent to the peer.
+    pub fn announce_channels<'msg>(&mut self) -> Result<NowPacket<'msg>, ProtoError> {
+        if self.state != ShareeState::Active {
+            return ProtoError::new(ProtoErrorKind::Sharee(self.state))
+                .or_desc("channels can only be announced in active state");
+        }
+
+        Ok(NowPacket::from_message(self.__channel_list()))
+    }
+
+    /// Channels available on the peer, `None` until announced after the connection sequence.
+    pub fn peer_channels(&self) -> Option<&[ChannelName]> {
+        self.peer_channels.as_deref()
+    }
+
+    /// Requests the peer to close `name`. The returned packet is to be sent to the peer.
+    ///
+    /// Messages of the channel are processed until the peer acknowledges it.
+    pub fn close_channel<'msg>(&mut self, name: ChannelName) -> Result<NowPacket<'msg>, ProtoError> {
+        if self.state != ShareeState::Active {
+            return ProtoError::new(ProtoErrorKind::Sharee(self.state))
+                .or_desc("channels can only be closed in active state");
+        }
+
+        Ok(NowPacket::from_message(self.channels_lifecycle.close_request(name)?))
+    }
+
+    /// Closes then opens again `name`, such as when it stalled, the session going on meanwhile.
+    /// The returned packet is to be sent to the peer.
+    ///
+    /// The state machine or handler of the channel is closed and opened again: the channel is opened
+    /// once the peer acknowledges both requests, see `channel_state`.
+    pub fn reset_channel<'msg>(&mut self, name: ChannelName) -> Result<NowPacket<'msg>, ProtoError> {
+        let request = self.close_channel(name.clone())?;
+        log::debug!("resetting channel {:?}", name);
+        self.resetting.insert(name);
+        Ok(request)
+    }
+
+    /// Protocol version selected in the handshake, once completed.
+    pub fn negotiated_version(&self) -> Option<NowProtocolVersion> {
+        self.shared_data.borrow().negotiated_version
+    }
+
+    /// Encoding context matching the negotiated version and the capabilities of the peer,
+    /// for messages whose layout depends on them.
+    pub fn encode_ctx(&self) -> EncodeCtx {
+        let shared_data = self.shared_data.borrow();
+        let mut ctx = EncodeCtx::new(self.channels_ctx.version());
+        if self.state != ShareeState::Connection {
+            ctx.configure_from_capabilities(&shared_data.peer_capabilities);
+        }
+        ctx
+    }
+
+    /// Updates local capabilities once connected, typically to enable a codec later on.
+    ///
+    /// Capability sets replace the ones of the same kind, the others are added. The returned
+    /// message, carrying the updated sets only, is to be sent to the peer.
+    pub fn update_capabilities<'msg>(&mut self, capabilities: Vec<NowCapset<'static>>) -> ShareeResult<'msg> {
+        if self.state != ShareeState::Active {
+            return ProtoError::new(ProtoErrorKind::Sharee(self.state))
+                .or_desc("capabilities can only be updated in active state");
+        }
+
+        merge_capabilities(&mut self.shared_data.borrow_mut().capabilities, &capabilities);
+        Ok(Some(NowPacket::from_message(
+            NowCapabilitiesMsg::new_with_capabilities(capabilities),
+        )))
+    }
+
+    /// Delivers batched messages in order within a single batch scope, as top-level messages
+    /// not changing the session (see `NowBatchMsg`). Several answers are sent back as a batch as well.
+    fn __deliver_batch<'msg: 'a, 'a>(&mut self, batch: &'a NowBatchMsg<'msg>) -> ShareeResult<'msg> {
+        self.user_callback.on_batch_begin(batch.messages.len());
+
+        let mut answers = Vec::new();
+        let mut result = Ok(());
+        for msg in &batch.messages {
+            let answer = match self.__respond(msg) {
+                Some(answer) => Ok(answer),
+                None => {
+                    self.__track_surfaces(msg);
+                    self.user_callback.on_unprocessed_message(msg)
+                }
+            };
+            match answer {
+                Ok(Some(answer)) => answers.push(answer),
+                Ok(None) => {}
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+            self.user_callback.on_any_message(msg);
+        }
+
+        self.user_callback.on_batch_end();
+        result?;
+
+        if answers.len() <= 1 {
+            return Ok(answers.pop());
+        }
+
+        let messages = answers
+            .into_iter()
+            .map(|answer| match answer.body {
+                NowBody::Message(msg) => Ok(msg),
+                NowBody::VirtualChannel(_) => ProtoError::new(ProtoErrorKind::Sharee(self.state))
+                    .or_desc("virtual channel answer to a batched message can't be batched"),
+            })
+            .collect::<Result<Vec<_>, ProtoError>>()?;
+
+        Ok(Some(NowPacket::from_message(NowBatchMsg::new(messages))))
+    }
+
+    /// Opens or closes channels on open and close requests and responses, answering requests.
+    fn __update_channels_lifecycle<'msg>(&mut self, channel_msg: &NowChannelMsg) -> ShareeResult<'msg> {
+        match channel_msg.subtype {
+            ChannelMessageType::ChannelListRequest => {
+                return Ok(Some(NowPacket::from_message(self.__channel_list())));
+            }
+            ChannelMessageType::ChannelListResponse => {
+                let names: Vec<ChannelName> = channel_msg.channel_list.iter().map(|def| def.name.clone()).collect();
+                log::debug!("channels announced by peer: {:?}", names);
+                self.peer_channels = Some(names);
+                return Ok(None);
+            }
+            _ => {}
+        }
+
+        let mut answered = Vec::new();
+        let mut reopened = Vec::new();
+        for def in channel_msg.channel_list.iter() {
+            match channel_msg.subtype {
+                ChannelMessageType::ChannelOpenRequest => answered.push(self.__on_open_request(&def.name)),
+                ChannelMessageType::ChannelOpenResponse => self.__on_open_response(def),
+                ChannelMessageType::ChannelCloseRequest => answered.push(self.__on_close_request(&def.name)),
+                ChannelMessageType::ChannelCloseResponse => {
+                    if let Some(request) = self.__on_close_response(def) {
+                        reopened.extend(request.channel_list.0);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let subtype = match channel_msg.subtype {
+            ChannelMessageType::ChannelOpenRequest => ChannelMessageType::ChannelOpenResponse,
+            ChannelMessageType::ChannelCloseRequest => ChannelMessageType::ChannelCloseResponse,
+            ChannelMessageType::ChannelCloseResponse if !reopened.is_empty() => {
+                return Ok(Some(NowPacket::from_message(NowChannelMsg::new(
+                    ChannelMessageType::ChannelOpenRequest,
+                    reopened,
+                ))));
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(NowPacket::from_message(NowChannelMsg::new(subtype, answered))))
+    }
+
+    /// Channels having a state machine or a handler and supported by the negotiated version.
+    fn __available_channels(&self) -> Vec<ChannelName> {
+        self.channels_manager
+            .channel_names()
+            .into_iter()
+            .filter(|name| self.channels_ctx.version().supports_channel(name))
+            .collect()
+    }
+
+    fn __channel_list(&self) -> NowChannelMsg {
+        let defs = self
+            .__available_channels()
+            .into_iter()
+            .map(NowChannelDef::new)
+            .collect();
+        NowChannelMsg::new(ChannelMessageType::ChannelListResponse, defs)
+    }
+
+    fn __check_not_opened(&self, name: &ChannelName) -> Result<(), ProtoError> {
+        if self.channels_ctx.get_id_by_channel(name).is_some() {
+            return ProtoError::new(ProtoErrorKind::VirtualChannel(name.clone()))
+                .or_desc("channel is opened: it is to be closed before being registered again");
+        }
+        Ok(())
+    }
+
+    /// Accepts available channels not opened yet.
+    fn __on_open_request(&mut self, name: &ChannelName) -> NowChannelDef {
+        let available =
+            self.__available_channels().contains(name) && self.channels_ctx.get_id_by_channel(name).is_none();
+        let free_id = (0..=u8::MAX).find(|id| self.channels_ctx.get_channel_by_id(*id).is_none());
+        match free_id {
+            Some(id) if available => {
+                self.__open(name.clone(), id);
+                NowChannelDef::new_with_flags(name.clone(), ChannelDefFlags::from(u32::from(id)))
+            }
+            _ => {
+                log::warn!("open request for channel {:?} refused", name);
+                NowChannelDef::new_with_flags(name.clone(), ChannelDefFlags::from(ChannelDefFlags::STATUS_FAILURE))
+            }
+        }
+    }
+
+    fn __on_open_response(&mut self, def: &NowChannelDef) {
+        if self.channels_lifecycle.state(&def.name) != Some(ChannelLifecycleState::Opening) {
+            log::warn!("unexpected open response for channel {:?} ignored", def.name);
+        } else if is_channel_failure(def) {
+            self.channels_lifecycle
+                .on_closed(def.name.clone(), ChannelCloseReason::Refused);
+        } else {
+            self.__open(def.name.clone(), def.flags.value as u8);
+        }
+    }
+
+    fn __on_close_request(&mut self, name: &ChannelName) -> NowChannelDef {
+        match self.channels_ctx.get_id_by_channel(name) {
+            Some(id) => {
+                self.__close(name, id, ChannelCloseReason::Peer);
+                NowChannelDef::new_with_flags(name.clone(), ChannelDefFlags::from(u32::from(id)))
+            }
+            None => {
+                log::warn!("close request for channel {:?} not opened", name);
+                NowChannelDef::new_with_flags(name.clone(), ChannelDefFlags::from(ChannelDefFlags::STATUS_FAILURE))
+            }
+        }
+    }
+
+    /// Open request of a channel being reset, once closed.
+    fn __on_close_response(&mut self, def: &NowChannelDef) -> Option<NowChannelMsg> {
+        match self.channels_lifecycle.state(&def.name) {
+            Some(ChannelLifecycleState::Closing { id }) => self.__close(&def.name, id, ChannelCloseReason::Local),
+            _ => log::warn!("unexpected close response for channel {:?} ignored", def.name),
+        }
+
+        if !self.resetting.remove(&def.name) {
+            return None;
+        }
+        match self.channels_lifecycle.open_request(def.name.clone()) {
+            Ok(request) => Some(request),
+            Err(err) => {
+                log::warn!("channel {:?} not reopened: {}", def.name, err);
+                None
+            }
+        }
+    }
+
+    fn __open(&mut self, name: ChannelName, id: u8) {
+        self.channels_ctx.insert(id, name.clone());
+        self.channels_manager.on_channel_opened(&name);
+        self.channels_lifecycle.on_opened(name, id);
+    }
+
+    fn __close(&mut self, name: &ChannelName, id: u8, reason: ChannelCloseReason) {
+        if reason != ChannelCloseReason::Local {
+            self.resetting.remove(name);
+        }
+        self.channels_ctx.remove(id);
+        self.channels_manager.on_channel_closed(name);
+        self.channels_lifecycle.on_closed(name.clone(), reason);
+    }
+
+    /// Answers messages handled by the sharee itself: requests of session guards, then routine messages.
+    fn __respond<'msg>(&mut self, msg: &NowMessage<'_>) -> Option<Option<NowPacket<'msg>>> {
+        if let NowMessage::Desktop(desktop_msg) = msg {
+            if let Some(answer) = self.session_guards.update_with_desktop_msg(desktop_msg) {
+                return Some(Some(NowPacket::from_message(answer)));
+            }
+        }
+
+        self.auto_responder
+            .respond(msg)
+            .map(|answer| answer.map(NowPacket::from))
+    }
+
+    fn __issue_handoff_token<'msg>(&mut self, now: Instant) -> ShareeResult<'msg> {
+        let session_id = self
+            .get_session_id()
+            .chain(ProtoErrorKind::Sharee(self.state))
+            .or_desc("handoff token requested but no session is associated")?;
+        let rsp = match &self.handoff_registry {
+            Some(registry) => {
+                registry
+                    .borrow_mut()
+                    .issue_next(session_id, &self.shared_data.borrow().capabilities, now)
+            }
+            None => None,
+        }
+        .chain(ProtoErrorKind::Sharee(self.state))
+        .or_desc("handoff token requested but no token source is configured")?;
+
+        log::debug!("handoff token issued for session {}", session_id);
+        Ok(Some(NowPacket::from_message(NowSessionMsg::from(rsp))))
+    }
+
+    /// Applies surface list updates to the tracked topology and reports resulting events.
+    /// Answering the update is left to the user callback.
+    fn __track_surfaces(&mut self, msg: &NowMessage<'_>) {
+        if let NowMessage::Surface(surface_msg @ NowSurfaceMsg::ListReq(_)) = msg {
+            self.surfaces.update_with_surface_msg(surface_msg);
+            self.__deliver_surface_events();
+        }
+    }
+
+    fn __deliver_surface_events(&mut self) {
+        for event in self.surfaces.user_callback_mut().drain() {
+            self.user_callback.on_surface_event(&event);
+        }
+    }
+
+    /// Merges capabilities updated by the peer, then notifies channels and user callback of the whole set.
+    fn __reconcile_peer_capabilities(&mut self, updated: &[NowCapset<'_>]) {
+        let peer_capabilities = {
+            let mut shared_data = self.shared_data.borrow_mut();
+            merge_capabilities(&mut shared_data.peer_capabilities, &known_capabilities(updated));
+            shared_data.peer_capabilities.clone()
+        };
+        log::debug!(
+            "peer capabilities updated: {:?}",
+            updated.iter().map(NowCapset::name_as_str).collect::<Vec<_>>()
+        );
+
+        self.channels_manager.on_capabilities_updated(&peer_capabilities);
+        self.user_callback.on_capabilities_updated(&peer_capabilities);
+    }
+
+    fn __record_error(&mut self, result: &ShareeResult<'_>) {
+        if let Err(err) = result {
+            self.last_error = Some(err.to_string());
+        }
+    }
+
     fn __check_result(&mut self, result: &ConnectionSMResult<'_>) {
-        if result.is_err() {
-            log::trace!("an error occurred. Set sharee state to final state.");
+        if let Err(err) = result {
+            let class = err.class();
+            log::trace!("connection sequence failed: {:?}", class);
+            self.__fail(class);
+            self.user_callback.on_connection_failed(err);
+        }
+    }
+
+    /// Transport failures interrupt the session, the others end it.
+    fn __fail(&mut self, class: ErrorClass) {
+        if !self.is_running() {
+            return;
+        }
+
+        self.failure = Some(class);
+        if class.is_recoverable() {
+            self.state = ShareeState::Interrupted;
+            self.session_guards.on_quality_changed(LinkQuality::Lost);
+        } else {
             self.state = ShareeState::Final;
+            self.channels_lifecycle.on_session_terminated();
+            self.channels_manager.on_channels_closed();
+            self.session_guards.on_disconnect();
         }
     }
 
     fn __go_to_active_state(&mut self) {
         log::trace!("enter active state.");
         self.state = ShareeState::Active;
-        for def in &self.shared_data.borrow().channels {
-            self.channels_ctx.insert(def.flags.value as u8, def.name.clone());
+        if let Some(version) = self.shared_data.borrow().negotiated_version {
+            self.channels_ctx.set_version(version);
+        }
+        if !self
+            .channels_ctx
+            .version()
+            .supports(ProtocolFeature::ChannelFlowControl)
+        {
+            self.channels_manager.get_flow_control_mut().disable();
+        }
+        let channels = self.shared_data.borrow().channels.clone();
+        for def in channels {
+            self.__open(def.name, def.flags.value as u8);
         }
         log::debug!("virtual channels context: {:#?}", self.channels_ctx);
         self.user_callback.on_enter_active_state(&self.shared_data.borrow());
+
+        // initial topology is advertised by the surface capability of the peer
+        self.surfaces
+            .configure_from_capabilities(&self.shared_data.borrow().peer_capabilities);
+        let initial_list = self
+            .shared_data
+            .borrow()
+            .peer_capabilities
+            .iter()
+            .find_map(|capset| match capset {
+                NowCapset::Surface(capset) => Some(capset.list_req.clone()),
+                _ => None,
+            });
+        if let Some(list) = initial_list {
+            self.surfaces.apply_list(&list);
+            self.__deliver_surface_events();
+        }
     }
 
     fn __map_channels_manager_result<'msg>(&self, chan_result: ChannelsManagerResult<'msg>) -> ShareeResult<'msg> {
@@ -186,3 +949,535 @@ where
         }
     }
 }
+
+fn __is_lifecycle_msg(channel_msg: &NowChannelMsg) -> bool {
+    matches!(
+        channel_msg.subtype,
+        ChannelMessageType::ChannelListRequest
+            | ChannelMessageType::ChannelListResponse
+            | ChannelMessageType::ChannelOpenRequest
+            | ChannelMessageType::ChannelOpenResponse
+            | ChannelMessageType::ChannelCloseRequest
+            | ChannelMessageType::ChannelCloseResponse
+    )
+}
+
+pub(crate) fn merge_capabilities(capabilities: &mut Vec<NowCapset<'static>>, updated: &[NowCapset<'static>]) {
+    for capset in updated {
+        match capabilities
+            .iter_mut()
+            .find(|current| current.name_as_str() == capset.name_as_str())
+        {
+            Some(current) => *current = capset.clone(),
+            None => capabilities.push(capset.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{
+            ChannelName, Codec, CurtainFlags, DisplayPowerFlags, EdgeRect, NowDesktopCurtainReqMsg,
+            NowDesktopDisplayPowerReqMsg, NowDesktopMsg, NowSurfaceDef, NowSurfaceListReqMsg, NowSurfaceSelectReqMsg,
+            NowVirtualChannel, QualityMode, SurfaceCapset, SurfaceCapsetFlags, TransportCapset, UpdateCapset,
+        },
+        serialization::Encode,
+        sm::{
+            ConnectionSMResult, CurtainHost, CurtainHostCallbackTrait, DisplayPowerHost, DisplayPowerHostCallbackTrait,
+            VirtChannelSMResult, VirtualChannelSM,
+        },
+        version::NowProtocolVersionRange,
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    struct ConnectedSM(ConnectionSMSharedDataRc);
+
+    impl ConnectionSM for ConnectedSM {
+        fn set_shared_data(&mut self, shared_data: ConnectionSMSharedDataRc) {
+            self.0 = shared_data;
+        }
+
+        fn get_shared_data(&self) -> Option<ConnectionSMSharedDataRc> {
+            Some(Rc::clone(&self.0))
+        }
+
+        fn is_terminated(&self) -> bool {
+            true
+        }
+
+        fn waiting_for_packet(&self) -> bool {
+            false
+        }
+
+        fn update_without_message<'msg>(&mut self) -> ConnectionSMResult<'msg> {
+            Ok(None)
+        }
+
+        fn update_with_message<'msg: 'a, 'a>(&mut self, _: &'a NowMessage<'msg>) -> ConnectionSMResult<'msg> {
+            Ok(None)
+        }
+    }
+
+    /// Shared data of a completed connection sequence, with the capabilities of both sides.
+    fn connected_shared_data(
+        capabilities: Vec<NowCapset<'static>>,
+        peer_capabilities: Vec<NowCapset<'static>>,
+    ) -> ConnectionSMSharedDataRc {
+        Rc::new(RefCell::new(ConnectionSMSharedData {
+            available_auth_types: Vec::new(),
+            capabilities,
+            peer_capabilities,
+            channels: Vec::new(),
+            handoff: false,
+            tls: false,
+            peer_certificate: None,
+            session_id: None,
+            peer_version: None,
+            versions: NowProtocolVersionRange::default(),
+            negotiated_version: None,
+        }))
+    }
+
+    /// Presented state: surface ids and selected surface.
+    type Presentation = (Vec<u16>, Option<u16>);
+
+    /// Surfaces known to a renderer deferring presentation during batches.
+    #[derive(Default)]
+    struct SurfaceTable {
+        surfaces: Vec<u16>,
+        selected: Option<u16>,
+        in_batch: bool,
+        presented: Vec<Presentation>,
+        events: Vec<SurfaceEvent>,
+    }
+
+    impl SurfaceTable {
+        fn present(&mut self) {
+            if !self.in_batch {
+                self.presented.push((self.surfaces.clone(), self.selected));
+            }
+        }
+    }
+
+    struct Renderer(Rc<RefCell<SurfaceTable>>);
+
+    impl ShareeCallbackTrait for Renderer {
+        fn on_unprocessed_message<'msg: 'a, 'a>(&mut self, message: &'a NowMessage<'msg>) -> ShareeResult<'msg> {
+            let mut table = self.0.borrow_mut();
+            match message {
+                NowMessage::Surface(NowSurfaceMsg::ListReq(req)) => {
+                    table.surfaces = req.surfaces.iter().map(|def| def.surface_id).collect();
+                    if !table.selected.is_some_and(|id| table.surfaces.contains(&id)) {
+                        table.selected = None;
+                    }
+                }
+                NowMessage::Surface(NowSurfaceMsg::SelectReq(req)) => table.selected = Some(req.surface_id),
+                _ => {}
+            }
+            table.present();
+            Ok(None)
+        }
+
+        fn on_surface_event(&mut self, event: &SurfaceEvent) {
+            self.0.borrow_mut().events.push(event.clone());
+        }
+
+        fn on_batch_begin(&mut self, _: usize) {
+            self.0.borrow_mut().in_batch = true;
+        }
+
+        fn on_batch_end(&mut self) {
+            let mut table = self.0.borrow_mut();
+            table.in_batch = false;
+            table.present();
+        }
+    }
+
+    fn surface_list(ids: &[u16]) -> NowMessage<'static> {
+        let surfaces = ids
+            .iter()
+            .map(|id| NowSurfaceDef::new(*id, EdgeRect::default()))
+            .collect();
+        NowSurfaceMsg::ListReq(NowSurfaceListReqMsg::new_with_surfaces(0, 1920, 1080, surfaces)).into()
+    }
+
+    #[test]
+    fn surface_list_and_select_batch_applied_atomically() {
+        let shared_data = connected_shared_data(Vec::new(), Vec::new());
+        let table = Rc::new(RefCell::new(SurfaceTable::default()));
+        let mut sharee = Sharee::new(
+            ConnectedSM(shared_data),
+            ChannelsManager::new(),
+            Renderer(Rc::clone(&table)),
+        );
+        sharee.update_without_body(Instant::now()).unwrap();
+        assert_eq!(sharee.get_state(), ShareeState::Active);
+
+        let packet = NowPacket::from_message(surface_list(&[1]));
+        sharee.update_with_body(&packet.body, Instant::now()).unwrap();
+        let packet = NowPacket::from_message(NowSurfaceMsg::SelectReq(NowSurfaceSelectReqMsg::new(0, 1, 1)));
+        sharee.update_with_body(&packet.body, Instant::now()).unwrap();
+
+        // second monitor plugged in and selected: goes through the wire as a single batch
+        let batch = NowPacket::from_message(NowBatchMsg::new(vec![
+            surface_list(&[1, 2]),
+            NowSurfaceMsg::SelectReq(NowSurfaceSelectReqMsg::new(0, 2, 2)).into(),
+        ]));
+        let bytes = batch.encode().unwrap();
+        let mut buffer = Vec::new();
+        let packet = NowPacket::read_from(&mut bytes.as_slice(), &mut buffer, sharee.get_channels_ctx()).unwrap();
+        assert!(sharee.update_with_body(&packet.body, Instant::now()).unwrap().is_none());
+
+        assert_eq!(
+            table.borrow().presented,
+            vec![
+                (vec![1], None),
+                (vec![1], Some(1)),
+                // no intermediate state with the new list but the old selection
+                (vec![1, 2], Some(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn surface_hot_plug_events() {
+        let rect = |left, right| EdgeRect {
+            left,
+            top: 0,
+            right,
+            bottom: 1080,
+        };
+        let initial_list =
+            NowSurfaceListReqMsg::new_with_surfaces(0, 1920, 1080, vec![NowSurfaceDef::new(1, rect(0, 1920))]);
+        let shared_data = connected_shared_data(
+            Vec::new(),
+            vec![NowCapset::Surface(SurfaceCapset::new(
+                SurfaceCapsetFlags::new_empty().set_multi(),
+                initial_list,
+            ))],
+        );
+        let table = Rc::new(RefCell::new(SurfaceTable::default()));
+        let mut sharee = Sharee::new(
+            ConnectedSM(shared_data),
+            ChannelsManager::new(),
+            Renderer(Rc::clone(&table)),
+        );
+        sharee.update_without_body(Instant::now()).unwrap();
+        assert!(sharee.get_surfaces().multi_select());
+        assert!(matches!(&table.borrow().events[..], [SurfaceEvent::Added(surface)] if surface.surface_id == 1));
+        table.borrow_mut().events.clear();
+
+        let update = NowSurfaceListReqMsg::new_with_surfaces(
+            1,
+            3200,
+            1080,
+            vec![
+                NowSurfaceDef::new(1, rect(0, 1280)),
+                NowSurfaceDef::new(2, rect(1280, 3200)),
+            ],
+        );
+        let packet = NowPacket::from_message(NowSurfaceMsg::from(update));
+        sharee.update_with_body(&packet.body, Instant::now()).unwrap();
+
+        let table = table.borrow();
+        assert_eq!(table.events.len(), 2);
+        assert_eq!(
+            table.events[0],
+            SurfaceEvent::Resized {
+                surface_id: 1,
+                from: rect(0, 1920),
+                to: rect(0, 1280),
+            }
+        );
+        assert!(matches!(&table.events[1], SurfaceEvent::Added(surface) if surface.surface_id == 2));
+        // raw message is still handed to the user callback
+        assert_eq!(table.surfaces, vec![1, 2]);
+        assert_eq!(sharee.get_surfaces().surfaces().count(), 2);
+    }
+
+    /// Codec enabled on the channel, as advertised by the peer.
+    struct CodecChannel(Rc<RefCell<Option<Codec>>>);
+
+    impl VirtualChannelSM for CodecChannel {
+        fn get_channel_name(&self) -> ChannelName {
+            ChannelName::Chat
+        }
+
+        fn is_terminated(&self) -> bool {
+            false
+        }
+
+        fn waiting_for_packet(&self) -> bool {
+            true
+        }
+
+        fn update_without_chan_msg<'msg>(&mut self) -> VirtChannelSMResult<'msg> {
+            Ok(None)
+        }
+
+        fn update_with_chan_msg<'msg: 'a, 'a>(&mut self, _: &'a NowVirtualChannel<'msg>) -> VirtChannelSMResult<'msg> {
+            Ok(None)
+        }
+
+        fn on_capabilities_updated(&mut self, peer_capabilities: &[NowCapset<'static>]) {
+            *self.0.borrow_mut() = peer_capabilities.iter().find_map(|capset| match capset {
+                NowCapset::Update(capset) => Some(capset.codec_id),
+                _ => None,
+            });
+        }
+    }
+
+    #[test]
+    fn capabilities_renegotiated_once_connected() {
+        let shared_data = connected_shared_data(
+            vec![NowCapset::Transport(TransportCapset::default())],
+            vec![
+                NowCapset::Transport(TransportCapset::default()),
+                NowCapset::Update(UpdateCapset::new(QualityMode::High, Codec::JPEG)),
+            ],
+        );
+        let codec = Rc::new(RefCell::new(None));
+        let mut sharee = Sharee::new(
+            ConnectedSM(Rc::clone(&shared_data)),
+            ChannelsManager::new().with_sm(CodecChannel(Rc::clone(&codec))),
+            DummyShareeCallback,
+        );
+        assert!(sharee
+            .update_capabilities(vec![NowCapset::Update(UpdateCapset::new(
+                QualityMode::High,
+                Codec::GFWX
+            ))])
+            .is_err());
+        sharee.update_without_body(Instant::now()).unwrap();
+
+        // peer enables another codec
+        let packet = NowPacket::from_message(NowCapabilitiesMsg::new_with_capabilities(vec![NowCapset::Update(
+            UpdateCapset::new(QualityMode::Low, Codec::Thor),
+        )]));
+        assert!(sharee.update_with_body(&packet.body, Instant::now()).unwrap().is_none());
+        assert_eq!(*codec.borrow(), Some(Codec::Thor));
+        let peer_capabilities = shared_data.borrow().peer_capabilities.clone();
+        assert_eq!(peer_capabilities.len(), 2);
+        assert!(matches!(&peer_capabilities[1], NowCapset::Update(capset) if capset.quality_mode == QualityMode::Low));
+
+        let update = sharee
+            .update_capabilities(vec![NowCapset::Update(UpdateCapset::new(
+                QualityMode::High,
+                Codec::GFWX,
+            ))])
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            update.body,
+            NowBody::Message(NowMessage::Capabilities(ref msg)) if msg.capabilities.len() == 1
+        ));
+        assert_eq!(shared_data.borrow().capabilities.len(), 2);
+    }
+
+    #[test]
+    fn routine_messages_answered_without_application() {
+        use crate::{
+            message::{NowNetworkKeepAliveReqMsg, NowNetworkKeepAliveRspMsg, NowNetworkMsg},
+            sm::{AutoResponder, RoutineFamily},
+        };
+
+        let shared_data = connected_shared_data(Vec::new(), Vec::new());
+        let table = Rc::new(RefCell::new(SurfaceTable::default()));
+        let mut sharee = Sharee::new(
+            ConnectedSM(shared_data),
+            ChannelsManager::new(),
+            Renderer(Rc::clone(&table)),
+        );
+        sharee.set_auto_responder(AutoResponder::new().enable(RoutineFamily::KeepAlive));
+        sharee.update_without_body(Instant::now()).unwrap();
+
+        let keep_alive: NowMessage<'static> = NowNetworkMsg::from(NowNetworkKeepAliveReqMsg::new(9, 0)).into();
+        let packet = NowPacket::from_message(keep_alive.clone());
+        let answer = sharee.update_with_body(&packet.body, Instant::now()).unwrap().unwrap();
+        assert!(matches!(
+            answer.body,
+            NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveRsp(ref rsp))) if rsp.sequence_id == 9
+        ));
+
+        let batch = NowPacket::from_message(NowBatchMsg::new(vec![keep_alive, surface_list(&[1])]));
+        let answer = sharee.update_with_body(&batch.body, Instant::now()).unwrap();
+        assert!(answer.is_some());
+        assert_eq!(table.borrow().presented, vec![(vec![1], None)]);
+
+        // acknowledgments are not handled: left to the application
+        let rsp: NowMessage<'static> = NowNetworkMsg::from(NowNetworkKeepAliveRspMsg::new(0, 0)).into();
+        let packet = NowPacket::from_message(rsp);
+        assert!(sharee.update_with_body(&packet.body, Instant::now()).unwrap().is_none());
+        assert_eq!(table.borrow().presented.len(), 2);
+    }
+
+    #[test]
+    fn decoding_settings_kept_across_reconnection() {
+        let shared_data = connected_shared_data(Vec::new(), Vec::new());
+        shared_data.borrow_mut().channels = vec![NowChannelDef::new_with_flags(
+            ChannelName::Chat,
+            ChannelDefFlags::from(2),
+        )];
+        let mut sharee = Sharee::new(ConnectedSM(shared_data), ChannelsManager::new(), DummyShareeCallback);
+        let limits = DecodeLimits {
+            max_collection_len: 16,
+            max_message_size: 1024,
+        };
+        sharee.set_decode_mode(DecodeMode::Permissive);
+        sharee.set_decode_limits(limits);
+        sharee.update_without_body(Instant::now()).unwrap();
+        assert_eq!(sharee.get_channels_ctx().get_id_by_channel(&ChannelName::Chat), Some(2));
+
+        sharee.reconnect(ConnectedSM(connected_shared_data(Vec::new(), Vec::new())));
+        assert_eq!(sharee.get_channels_ctx().channels().count(), 0);
+        assert_eq!(sharee.get_channels_ctx().decode_mode(), DecodeMode::Permissive);
+        assert_eq!(sharee.get_channels_ctx().decode_limits(), limits);
+
+        sharee.update_without_body(Instant::now()).unwrap();
+        assert_eq!(sharee.get_state(), ShareeState::Active);
+        assert_eq!(sharee.get_channels_ctx().decode_mode(), DecodeMode::Permissive);
+    }
+
+    #[derive(Default)]
+    struct Curtain {
+        input_blocked: bool,
+    }
+
+    struct CurtainCallback(Rc<RefCell<Curtain>>);
+
+    impl CurtainHostCallbackTrait for CurtainCallback {
+        fn on_apply(&mut self, requested: CurtainFlags) -> CurtainFlags {
+            self.0.borrow_mut().input_blocked = requested.block_input();
+            requested
+        }
+
+        fn on_release(&mut self, _: CurtainFlags) {
+            self.0.borrow_mut().input_blocked = false;
+        }
+    }
+
+    fn connected_sharee() -> Sharee<ConnectedSM, DummyShareeCallback> {
+        let shared_data = connected_shared_data(Vec::new(), Vec::new());
+        let mut sharee = Sharee::new(ConnectedSM(shared_data), ChannelsManager::new(), DummyShareeCallback);
+        sharee.update_without_body(Instant::now()).unwrap();
+        sharee
+    }
+
+    fn curtained_sharee(curtain: &Rc<RefCell<Curtain>>) -> Sharee<ConnectedSM, DummyShareeCallback> {
+        let mut sharee = connected_sharee();
+        sharee.add_session_guard(CurtainHost::new(CurtainCallback(Rc::clone(curtain))));
+
+        let req = NowPacket::from_message(NowDesktopMsg::from(NowDesktopCurtainReqMsg::block_input()));
+        let answer = sharee.update_with_body(&req.body, Instant::now()).unwrap().unwrap();
+        assert!(matches!(
+            answer.body,
+            NowBody::Message(NowMessage::Desktop(NowDesktopMsg::CurtainRsp(ref rsp))) if rsp.applied.block_input()
+        ));
+        assert!(curtain.borrow().input_blocked);
+
+        sharee
+    }
+
+    #[test]
+    fn curtain_released_when_session_ends() {
+        let curtain = Rc::new(RefCell::new(Curtain::default()));
+        let mut sharee = curtained_sharee(&curtain);
+        let terminate = NowPacket::from_message(NowTerminateMsg::default());
+        sharee.update_with_body(&terminate.body, Instant::now()).unwrap();
+        assert!(sharee.is_terminated());
+        assert!(!curtain.borrow().input_blocked);
+
+        // link failure interrupting the session
+        let curtain = Rc::new(RefCell::new(Curtain::default()));
+        let mut sharee = curtained_sharee(&curtain);
+        sharee.on_error(&ProtoError::from(ProtoErrorKind::LocalConnection));
+        assert!(sharee.is_interrupted());
+        assert!(!curtain.borrow().input_blocked);
+
+        // link lost as tracked by the application
+        let curtain = Rc::new(RefCell::new(Curtain::default()));
+        let mut sharee = curtained_sharee(&curtain);
+        sharee.on_quality_changed(LinkQuality::Poor);
+        assert!(curtain.borrow().input_blocked);
+        sharee.on_quality_changed(LinkQuality::Lost);
+        assert!(!curtain.borrow().input_blocked);
+    }
+
+    struct SleepInhibitor(Rc<RefCell<bool>>);
+
+    impl DisplayPowerHostCallbackTrait for SleepInhibitor {
+        fn on_inhibit(&mut self, requested: DisplayPowerFlags) -> DisplayPowerFlags {
+            *self.0.borrow_mut() = true;
+            requested
+        }
+
+        fn on_allow(&mut self, _: DisplayPowerFlags) {
+            *self.0.borrow_mut() = false;
+        }
+    }
+
+    #[test]
+    fn display_power_allowed_when_session_ends() {
+        let sleep_inhibited = Rc::new(RefCell::new(false));
+        let curtain = Rc::new(RefCell::new(Curtain::default()));
+        let mut sharee = curtained_sharee(&curtain);
+        sharee.add_session_guard(DisplayPowerHost::new(SleepInhibitor(Rc::clone(&sleep_inhibited))));
+
+        // answered by the second guard
+        let req = NowPacket::from_message(NowDesktopMsg::from(NowDesktopDisplayPowerReqMsg::inhibit()));
+        let answer = sharee.update_with_body(&req.body, Instant::now()).unwrap().unwrap();
+        assert!(matches!(
+            answer.body,
+            NowBody::Message(NowMessage::Desktop(NowDesktopMsg::DisplayPowerRsp(_)))
+        ));
+        assert!(*sleep_inhibited.borrow());
+
+        sharee.on_quality_changed(LinkQuality::Lost);
+        assert!(!*sleep_inhibited.borrow());
+        assert!(!curtain.borrow().input_blocked);
+
+        // graceful disconnection
+        let mut sharee = connected_sharee();
+        sharee.add_session_guard(DisplayPowerHost::new(SleepInhibitor(Rc::clone(&sleep_inhibited))));
+        sharee.update_with_body(&req.body, Instant::now()).unwrap();
+        assert!(*sleep_inhibited.borrow());
+        let terminate = NowPacket::from_message(NowTerminateMsg::default());
+        sharee.update_with_body(&terminate.body, Instant::now()).unwrap();
+        assert!(!*sleep_inhibited.borrow());
+    }
+
+    #[test]
+    fn batched_desktop_requests_reach_session_guards() {
+        let curtain = Rc::new(RefCell::new(Curtain::default()));
+        let mut sharee = connected_sharee();
+        sharee.add_session_guard(CurtainHost::new(CurtainCallback(Rc::clone(&curtain))));
+
+        let batch = NowPacket::from_message(NowBatchMsg::new(vec![
+            NowDesktopMsg::from(NowDesktopCurtainReqMsg::block_input()).into(),
+            surface_list(&[1]),
+        ]));
+        let answer = sharee.update_with_body(&batch.body, Instant::now()).unwrap().unwrap();
+        assert!(matches!(
+            answer.body,
+            NowBody::Message(NowMessage::Desktop(NowDesktopMsg::CurtainRsp(_)))
+        ));
+        assert!(curtain.borrow().input_blocked);
+    }
+
+    #[test]
+    fn window_updates_grant_credits() {
+        let mut sharee = connected_sharee();
+        let window = NowPacket::from_message(NowChannelWindowMsg::new(ChannelName::Clipboard, 4096));
+        assert!(sharee.update_with_body(&window.body, Instant::now()).unwrap().is_none());
+        assert!(sharee.update_with_body(&window.body, Instant::now()).unwrap().is_none());
+        assert_eq!(
+            sharee
+                .get_channels_manager()
+                .get_flow_control()
+                .credits(&ChannelName::Clipboard),
+            Some(8192)
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! Access control of incoming connections.
+//!
+//! Once the client is authenticated, the host decides whether remote control is granted before
+//! associating the session, typically by asking the local user. The embedding application implements
+//! [`AccessControlCallbackTrait`](trait.AccessControlCallbackTrait.html) and gives it to the server
+//! connection sequence (see `ServerConnectionSeqBuilder::access_control`).
+//!
+//! While a prompt is shown, the sequence waits for the answer: drivers update the connection at
+//! its next deadline (see `Sharee::next_deadline`), which is when the answer is polled again or the
+//! prompt times out. A denied or timed out prompt is answered with `AssociateStatusCode::AccessDenied`.
+
+use crate::sm::ConnectionSMSharedData;
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+pub type AccessControlRc = Rc<RefCell<dyn AccessControlCallbackTrait>>;
+
+/// Interval at which the answer of a prompt is polled.
+pub const PROMPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessDecision {
+    Approve,
+    Deny,
+    /// Asks the local user, access being denied if no answer is given within `timeout`.
+    Prompt {
+        timeout: Duration,
+    },
+}
+
+pub trait AccessControlCallbackTrait {
+    /// called once the client is authenticated, before the session is associated.
+    fn on_access_requested(&mut self, shared_data: &ConnectionSMSharedData) -> AccessDecision;
+
+    /// Answer of the local user to the prompt, `None` while unanswered. Polled until the prompt times out.
+    fn poll_prompt_answer(&mut self) -> Option<bool> {
+        None
+    }
+
+    /// called when the prompt times out: access is denied.
+    fn on_prompt_timed_out(&mut self) {}
+}
+
+sa::assert_obj_safe!(AccessControlCallbackTrait);
+
+/// Grants access to every authenticated client.
+pub struct ApproveAll;
+impl AccessControlCallbackTrait for ApproveAll {
+    fn on_access_requested(&mut self, _: &ConnectionSMSharedData) -> AccessDecision {
+        AccessDecision::Approve
+    }
+}
+
+/// Denies access to every client, typically while the host isn't accepting remote control.
+pub struct DenyAll;
+impl AccessControlCallbackTrait for DenyAll {
+    fn on_access_requested(&mut self, _: &ConnectionSMSharedData) -> AccessDecision {
+        AccessDecision::Deny
+    }
+}
+
+/// Prompt shown to the local user, as tracked by the connection sequence.
+pub(crate) struct AccessPrompt {
+    deadline: Instant,
+    answer: Option<bool>,
+}
+
+impl AccessPrompt {
+    pub(crate) fn new(timeout: Duration, now: Instant) -> Self {
+        Self {
+            deadline: now + timeout,
+            answer: None,
+        }
+    }
+
+    /// Polls the answer if still unknown. `Some(false)` once timed out.
+    pub(crate) fn poll(&mut self, access_control: &AccessControlRc, now: Instant) -> Option<bool> {
+        if self.answer.is_none() {
+            self.answer = access_control.borrow_mut().poll_prompt_answer();
+        }
+        if self.answer.is_none() && now >= self.deadline {
+            log::debug!("access prompt timed out");
+            access_control.borrow_mut().on_prompt_timed_out();
+            self.answer = Some(false);
+        }
+        self.answer
+    }
+
+    pub(crate) fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        match self.answer {
+            Some(_) => Some(now),
+            None => Some(self.deadline.min(now + PROMPT_POLL_INTERVAL)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channels_manager::ChannelsManager,
+        message::{AuthType, ChannelName, NowCapset, TransportCapset},
+        sharee::{DummyShareeCallback, Sharee, ShareeState},
+        sm::{ClientConnectionSeqSM, DummyConnectionSeqCallback, NowConnection, ServerConnectionSeqSM},
+    };
+
+    struct LocalUser {
+        timeout: Duration,
+        answer: Option<bool>,
+        timed_out: bool,
+    }
+
+    impl AccessControlCallbackTrait for LocalUser {
+        fn on_access_requested(&mut self, _: &ConnectionSMSharedData) -> AccessDecision {
+            AccessDecision::Prompt { timeout: self.timeout }
+        }
+
+        fn poll_prompt_answer(&mut self) -> Option<bool> {
+            self.answer
+        }
+
+        fn on_prompt_timed_out(&mut self) {
+            self.timed_out = true;
+        }
+    }
+
+    type Connection<Seq> = NowConnection<Seq, DummyShareeCallback>;
+
+    fn connections(
+        access_control: AccessControlRc,
+    ) -> (
+        Connection<ClientConnectionSeqSM<DummyConnectionSeqCallback>>,
+        Connection<ServerConnectionSeqSM<DummyConnectionSeqCallback>>,
+    ) {
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .channels_to_open(vec![ChannelName::Chat])
+            .build();
+        let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![ChannelName::Chat])
+            .access_control(access_control)
+            .build();
+        (
+            NowConnection::new(Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback)),
+            NowConnection::new(Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback)),
+        )
+    }
+
+    /// Moves frames both ways until neither side has anything to send, or the client fails.
+    fn exchange<C, S>(
+        client: &mut NowConnection<C, DummyShareeCallback>,
+        server: &mut NowConnection<S, DummyShareeCallback>,
+    ) -> bool
+    where
+        C: crate::sm::ConnectionSM,
+        S: crate::sm::ConnectionSM,
+    {
+        while client.has_pending_transmit() || server.has_pending_transmit() {
+            while let Some(frame) = client.poll_transmit() {
+                // a denying server fails after queuing its response
+                let _ = server.feed_bytes(&frame, Instant::now());
+            }
+            while let Some(frame) = server.poll_transmit() {
+                if client.feed_bytes(&frame, Instant::now()).is_err() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn prompt_approved_by_local_user() {
+        let local_user = Rc::new(RefCell::new(LocalUser {
+            timeout: Duration::from_secs(60),
+            answer: None,
+            timed_out: false,
+        }));
+        let (mut client, mut server) = connections(local_user.clone());
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        assert!(exchange(&mut client, &mut server));
+        assert_eq!(client.get_state(), ShareeState::Connection);
+        let deadline = server.next_deadline().unwrap();
+        assert!(deadline <= Instant::now() + PROMPT_POLL_INTERVAL);
+
+        local_user.borrow_mut().answer = Some(true);
+        server.update(Instant::now()).unwrap();
+        assert_eq!(server.next_deadline(), None);
+        assert!(exchange(&mut client, &mut server));
+        assert_eq!(client.get_state(), ShareeState::Active);
+        assert_eq!(server.get_state(), ShareeState::Active);
+    }
+
+    #[test]
+    fn prompt_timed_out_denies_access() {
+        let local_user = Rc::new(RefCell::new(LocalUser {
+            timeout: Duration::from_millis(0),
+            answer: None,
+            timed_out: false,
+        }));
+        let (mut client, mut server) = connections(local_user.clone());
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        assert!(!exchange(&mut client, &mut server));
+        assert!(local_user.borrow().timed_out);
+        assert!(client.is_terminated());
+        assert!(server.is_terminated());
+    }
+
+    #[test]
+    fn denied_without_prompt() {
+        let (mut client, mut server) = connections(Rc::new(RefCell::new(DenyAll)));
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        assert!(!exchange(&mut client, &mut server));
+        assert!(client.is_terminated());
+    }
+}
@@ -0,0 +1,132 @@
+use crate::message::{NowMessage, NowNetworkKeepAliveRspMsg, NowNetworkMsg, NowNetworkProbeRspMsg};
+
+/// Families of routine messages a sharee can handle on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RoutineFamily {
+    /// Keep-alive requests, answered with a keep-alive response.
+    KeepAlive,
+    /// Network probe requests, answered with a probe response.
+    Probe,
+    /// Keep-alive and probe responses, consumed without answer.
+    Acknowledgment,
+}
+
+impl RoutineFamily {
+    pub fn of(msg: &NowMessage<'_>) -> Option<Self> {
+        match msg {
+            NowMessage::Network(NowNetworkMsg::KeepAliveReq(_)) => Some(RoutineFamily::KeepAlive),
+            NowMessage::Network(NowNetworkMsg::ProbeReq(_)) => Some(RoutineFamily::Probe),
+            NowMessage::Network(NowNetworkMsg::KeepAliveRsp(_)) | NowMessage::Network(NowNetworkMsg::ProbeRsp(_)) => {
+                Some(RoutineFamily::Acknowledgment)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Answers routine messages in place of the application, per message family.
+///
+/// Handled messages aren't given to `ShareeCallbackTrait::on_unprocessed_message`, so that the
+/// application only sees meaningful messages. No family is handled by default.
+/// Applications measuring the round trip time themselves (see `ConnectionQuality`) should leave
+/// acknowledgments to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AutoResponder {
+    keep_alive: bool,
+    probe: bool,
+    acknowledgment: bool,
+}
+
+impl AutoResponder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles every family.
+    pub fn all() -> Self {
+        Self {
+            keep_alive: true,
+            probe: true,
+            acknowledgment: true,
+        }
+    }
+
+    pub fn enable(self, family: RoutineFamily) -> Self {
+        self.__set(family, true)
+    }
+
+    pub fn disable(self, family: RoutineFamily) -> Self {
+        self.__set(family, false)
+    }
+
+    pub fn handles(&self, family: RoutineFamily) -> bool {
+        match family {
+            RoutineFamily::KeepAlive => self.keep_alive,
+            RoutineFamily::Probe => self.probe,
+            RoutineFamily::Acknowledgment => self.acknowledgment,
+        }
+    }
+
+    /// `None` if `msg` is to be given to the application, its answer if any otherwise.
+    pub fn respond(&self, msg: &NowMessage<'_>) -> Option<Option<NowMessage<'static>>> {
+        let family = RoutineFamily::of(msg)?;
+        if !self.handles(family) {
+            return None;
+        }
+
+        log::trace!("auto-responding to {:?} message", family);
+        match msg {
+            NowMessage::Network(NowNetworkMsg::KeepAliveReq(req)) => {
+                Some(Some(NowNetworkMsg::from(NowNetworkKeepAliveRspMsg::answer(req)).into()))
+            }
+            NowMessage::Network(NowNetworkMsg::ProbeReq(req)) => {
+                Some(Some(NowNetworkMsg::from(NowNetworkProbeRspMsg::answer(req)).into()))
+            }
+            _ => Some(None),
+        }
+    }
+
+    fn __set(self, family: RoutineFamily, enabled: bool) -> Self {
+        match family {
+            RoutineFamily::KeepAlive => Self {
+                keep_alive: enabled,
+                ..self
+            },
+            RoutineFamily::Probe => Self { probe: enabled, ..self },
+            RoutineFamily::Acknowledgment => Self {
+                acknowledgment: enabled,
+                ..self
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{NowNetworkKeepAliveReqMsg, NowNetworkProbeReqMsg, NowTerminateMsg};
+
+    #[test]
+    fn answers_enabled_families_only() {
+        let responder = AutoResponder::new()
+            .enable(RoutineFamily::KeepAlive)
+            .enable(RoutineFamily::Acknowledgment);
+
+        let req = NowMessage::from(NowNetworkMsg::from(NowNetworkKeepAliveReqMsg::new(4, 100)));
+        match responder.respond(&req) {
+            Some(Some(NowMessage::Network(NowNetworkMsg::KeepAliveRsp(rsp)))) => assert_eq!(rsp.sequence_id, 4),
+            other => panic!("unexpected answer: {:?}", other),
+        }
+
+        let rsp = NowMessage::from(NowNetworkMsg::from(NowNetworkKeepAliveRspMsg::new(4, 100)));
+        assert!(matches!(responder.respond(&rsp), Some(None)));
+
+        let probe = NowMessage::from(NowNetworkMsg::from(NowNetworkProbeReqMsg::new(1, 0)));
+        assert!(responder.respond(&probe).is_none());
+        assert!(AutoResponder::all().respond(&probe).is_some());
+
+        assert!(AutoResponder::all()
+            .respond(&NowMessage::from(NowTerminateMsg::default()))
+            .is_none());
+    }
+}
@@ -0,0 +1,229 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{ChannelDefFlags, ChannelMessageType, ChannelName, NowChannelDef, NowChannelMsg},
+    sm::ChannelStalled,
+};
+use alloc::collections::{BTreeMap, VecDeque};
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelCloseReason {
+    /// Closed on local request, acknowledged by the peer.
+    Local,
+    /// Closed on peer request.
+    Peer,
+    /// Opening refused by the peer.
+    Refused,
+    /// Session terminated while the channel was opened or opening.
+    SessionTerminated,
+}
+
+impl fmt::Display for ChannelCloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChannelCloseReason::Local => write!(f, "closed locally"),
+            ChannelCloseReason::Peer => write!(f, "closed by peer"),
+            ChannelCloseReason::Refused => write!(f, "refused by peer"),
+            ChannelCloseReason::SessionTerminated => write!(f, "session terminated"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelLifecycleState {
+    /// Open request sent, waiting for the peer to acknowledge it.
+    Opening,
+    Open {
+        id: u8,
+    },
+    /// Close request sent, messages of the channel are still processed until the peer acknowledges it.
+    Closing {
+        id: u8,
+    },
+    Closed(ChannelCloseReason),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelLifecycleEvent {
+    Opened {
+        name: ChannelName,
+        id: u8,
+    },
+    Closed {
+        name: ChannelName,
+        reason: ChannelCloseReason,
+    },
+    /// The peer stopped answering over an open channel, see `ChannelLiveness`.
+    /// The channel may be reset with `Sharee::reset_channel`.
+    Stalled(ChannelStalled),
+}
+
+/// Tracks the state of each channel opened or closed after the connection sequence, along with
+/// the channels opened by the sequence itself.
+///
+/// Open and close requests are answered by the peer with a response carrying the same channel
+/// definition: on success, the flags of the definition hold the channel id, `STATUS_FAILURE` otherwise.
+#[derive(Debug, Default)]
+pub struct ChannelLifecycle {
+    states: BTreeMap<ChannelName, ChannelLifecycleState>,
+    events: VecDeque<ChannelLifecycleEvent>,
+}
+
+impl ChannelLifecycle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `None` if `name` was never opened.
+    pub fn state(&self, name: &ChannelName) -> Option<ChannelLifecycleState> {
+        self.states.get(name).copied()
+    }
+
+    pub fn is_open(&self, name: &ChannelName) -> bool {
+        matches!(self.state(name), Some(ChannelLifecycleState::Open { .. }))
+    }
+
+    /// Oldest lifecycle event not polled yet.
+    pub fn poll_event(&mut self) -> Option<ChannelLifecycleEvent> {
+        self.events.pop_front()
+    }
+
+    /// Open request for `name`, to be sent to the peer.
+    pub fn open_request(&mut self, name: ChannelName) -> Result<NowChannelMsg, ProtoError> {
+        match self.state(&name) {
+            None | Some(ChannelLifecycleState::Closed(_)) => {}
+            Some(state) => {
+                return ProtoError::new(ProtoErrorKind::VirtualChannel(name))
+                    .or_else_desc(|| format!("channel can't be opened in state {:?}", state))
+            }
+        }
+
+        self.states.insert(name.clone(), ChannelLifecycleState::Opening);
+        Ok(NowChannelMsg::new(
+            ChannelMessageType::ChannelOpenRequest,
+            vec![NowChannelDef::new(name)],
+        ))
+    }
+
+    /// Close request for `name`, to be sent to the peer.
+    pub fn close_request(&mut self, name: ChannelName) -> Result<NowChannelMsg, ProtoError> {
+        let id = match self.state(&name) {
+            Some(ChannelLifecycleState::Open { id }) => id,
+            state => {
+                return ProtoError::new(ProtoErrorKind::VirtualChannel(name))
+                    .or_else_desc(|| format!("channel can't be closed in state {:?}", state))
+            }
+        };
+
+        self.states.insert(name.clone(), ChannelLifecycleState::Closing { id });
+        Ok(NowChannelMsg::new(
+            ChannelMessageType::ChannelCloseRequest,
+            vec![NowChannelDef::new_with_flags(
+                name,
+                ChannelDefFlags::from(u32::from(id)),
+            )],
+        ))
+    }
+
+    pub fn on_opened(&mut self, name: ChannelName, id: u8) {
+        log::debug!("channel {:?} opened with id {}", name, id);
+        self.states.insert(name.clone(), ChannelLifecycleState::Open { id });
+        self.events.push_back(ChannelLifecycleEvent::Opened { name, id });
+    }
+
+    pub fn on_closed(&mut self, name: ChannelName, reason: ChannelCloseReason) {
+        log::debug!("channel {:?} {}", name, reason);
+        self.states.insert(name.clone(), ChannelLifecycleState::Closed(reason));
+        self.events.push_back(ChannelLifecycleEvent::Closed { name, reason });
+    }
+
+    pub fn on_stalled(&mut self, stalled: ChannelStalled) {
+        self.events.push_back(ChannelLifecycleEvent::Stalled(stalled));
+    }
+
+    /// Closes every channel opened or opening, pending open requests included.
+    pub fn on_session_terminated(&mut self) {
+        let names: Vec<ChannelName> = self
+            .states
+            .iter()
+            .filter(|(_, state)| !matches!(state, ChannelLifecycleState::Closed(_)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in names {
+            self.on_closed(name, ChannelCloseReason::SessionTerminated);
+        }
+    }
+}
+
+/// `true` if `def`, taken from an open or close response, reports a failure.
+pub fn is_channel_failure(def: &NowChannelDef) -> bool {
+    def.flags == ChannelDefFlags::STATUS_FAILURE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_then_close() {
+        let mut lifecycle = ChannelLifecycle::new();
+        assert_eq!(lifecycle.state(&ChannelName::Chat), None);
+
+        let request = lifecycle.open_request(ChannelName::Chat).unwrap();
+        assert_eq!(request.subtype, ChannelMessageType::ChannelOpenRequest);
+        assert_eq!(
+            lifecycle.state(&ChannelName::Chat),
+            Some(ChannelLifecycleState::Opening)
+        );
+        assert!(lifecycle.open_request(ChannelName::Chat).is_err());
+        assert!(lifecycle.close_request(ChannelName::Chat).is_err());
+
+        lifecycle.on_opened(ChannelName::Chat, 2);
+        assert!(lifecycle.is_open(&ChannelName::Chat));
+        assert_eq!(
+            lifecycle.poll_event(),
+            Some(ChannelLifecycleEvent::Opened {
+                name: ChannelName::Chat,
+                id: 2
+            })
+        );
+
+        let request = lifecycle.close_request(ChannelName::Chat).unwrap();
+        assert_eq!(request.channel_list[0].flags, 2);
+        assert_eq!(
+            lifecycle.state(&ChannelName::Chat),
+            Some(ChannelLifecycleState::Closing { id: 2 })
+        );
+
+        lifecycle.on_closed(ChannelName::Chat, ChannelCloseReason::Local);
+        assert_eq!(
+            lifecycle.poll_event(),
+            Some(ChannelLifecycleEvent::Closed {
+                name: ChannelName::Chat,
+                reason: ChannelCloseReason::Local
+            })
+        );
+        assert!(lifecycle.open_request(ChannelName::Chat).is_ok());
+    }
+
+    #[test]
+    fn session_termination_closes_pending_channels() {
+        let mut lifecycle = ChannelLifecycle::new();
+        lifecycle.on_opened(ChannelName::Clipboard, 0);
+        lifecycle.on_closed(ChannelName::Clipboard, ChannelCloseReason::Peer);
+        lifecycle.open_request(ChannelName::Chat).unwrap();
+        while lifecycle.poll_event().is_some() {}
+
+        lifecycle.on_session_terminated();
+        assert_eq!(
+            lifecycle.state(&ChannelName::Chat),
+            Some(ChannelLifecycleState::Closed(ChannelCloseReason::SessionTerminated))
+        );
+        assert_eq!(
+            lifecycle.state(&ChannelName::Clipboard),
+            Some(ChannelLifecycleState::Closed(ChannelCloseReason::Peer))
+        );
+        assert!(lifecycle.poll_event().is_some());
+        assert!(lifecycle.poll_event().is_none());
+    }
+}
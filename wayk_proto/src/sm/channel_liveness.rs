@@ -0,0 +1,150 @@
+use crate::message::ChannelName;
+use alloc::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// An open channel whose peer didn't answer within the liveness timeout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelStalled {
+    pub name: ChannelName,
+    /// Time elapsed since the oldest message left unanswered was sent.
+    pub unanswered_for: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ChannelActivity {
+    /// Oldest message sent since the peer last answered over the channel.
+    unanswered_since: Option<Instant>,
+    /// Stall already reported, until the peer answers again.
+    stalled: bool,
+}
+
+/// Detects open channels whose peer stops answering.
+///
+/// Anything received over a channel, or credits granted for it, acknowledges the messages sent
+/// before. A channel is stalled once a message is left unacknowledged for longer than the timeout:
+/// this suits channels answering requests or under flow control, not channels only flowing one way.
+///
+/// Like `KeepAliveTracker`, this is a passive component fed with the current time.
+#[derive(Debug, Clone)]
+pub struct ChannelLiveness {
+    timeout: Duration,
+    channels: BTreeMap<ChannelName, ChannelActivity>,
+}
+
+impl ChannelLiveness {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            channels: BTreeMap::new(),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn on_channel_opened(&mut self, name: &ChannelName) {
+        self.channels.insert(name.clone(), ChannelActivity::default());
+    }
+
+    pub fn on_channel_closed(&mut self, name: &ChannelName) {
+        self.channels.remove(name);
+    }
+
+    pub fn on_channels_closed(&mut self) {
+        self.channels.clear();
+    }
+
+    /// Messages sent over channels not opened are not tracked.
+    pub fn on_sent(&mut self, name: &ChannelName, now: Instant) {
+        if let Some(activity) = self.channels.get_mut(name) {
+            activity.unanswered_since.get_or_insert(now);
+        }
+    }
+
+    /// Something was received over `name`, or credits were granted for it.
+    pub fn on_acknowledged(&mut self, name: &ChannelName) {
+        if let Some(activity) = self.channels.get_mut(name) {
+            if activity.stalled {
+                log::debug!("channel {:?} answering again", name);
+            }
+            *activity = ChannelActivity::default();
+        }
+    }
+
+    pub fn is_stalled(&self, name: &ChannelName) -> bool {
+        self.channels.get(name).is_some_and(|activity| activity.stalled)
+    }
+
+    /// Instant at which the next channel stalls if the peer doesn't answer, `None` if nothing is awaited.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.channels
+            .values()
+            .filter(|activity| !activity.stalled)
+            .filter_map(|activity| activity.unanswered_since)
+            .min()
+            .map(|since| since + self.timeout)
+    }
+
+    /// Next channel stalled, reported once until its peer answers again.
+    pub fn poll(&mut self, now: Instant) -> Option<ChannelStalled> {
+        let timeout = self.timeout;
+        let (name, activity) = self.channels.iter_mut().find(|(_, activity)| {
+            !activity.stalled
+                && activity
+                    .unanswered_since
+                    .is_some_and(|since| now.saturating_duration_since(since) >= timeout)
+        })?;
+
+        activity.stalled = true;
+        let unanswered_for = activity
+            .unanswered_since
+            .map_or(timeout, |since| now.saturating_duration_since(since));
+        log::warn!("channel {:?} unanswered for {:?}", name, unanswered_for);
+        Some(ChannelStalled {
+            name: name.clone(),
+            unanswered_for,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stall_reported_once_until_answered() {
+        let t0 = Instant::now();
+        let mut liveness = ChannelLiveness::new(Duration::from_secs(5));
+        liveness.on_sent(&ChannelName::Clipboard, t0);
+        assert_eq!(liveness.next_deadline(), None);
+
+        liveness.on_channel_opened(&ChannelName::Clipboard);
+        liveness.on_channel_opened(&ChannelName::Chat);
+        liveness.on_sent(&ChannelName::Clipboard, t0);
+        liveness.on_sent(&ChannelName::Clipboard, t0 + Duration::from_secs(2));
+        liveness.on_sent(&ChannelName::Chat, t0 + Duration::from_secs(1));
+        liveness.on_acknowledged(&ChannelName::Chat);
+        assert_eq!(liveness.next_deadline(), Some(t0 + Duration::from_secs(5)));
+        assert_eq!(liveness.poll(t0 + Duration::from_secs(4)), None);
+
+        let stalled = liveness.poll(t0 + Duration::from_secs(6)).unwrap();
+        assert_eq!(stalled.name, ChannelName::Clipboard);
+        assert_eq!(stalled.unanswered_for, Duration::from_secs(6));
+        assert!(liveness.is_stalled(&ChannelName::Clipboard));
+        assert_eq!(liveness.poll(t0 + Duration::from_secs(7)), None);
+        assert_eq!(liveness.next_deadline(), None);
+
+        liveness.on_acknowledged(&ChannelName::Clipboard);
+        assert!(!liveness.is_stalled(&ChannelName::Clipboard));
+        liveness.on_sent(&ChannelName::Clipboard, t0 + Duration::from_secs(8));
+        assert_eq!(liveness.next_deadline(), Some(t0 + Duration::from_secs(13)));
+
+        liveness.on_channel_closed(&ChannelName::Clipboard);
+        assert_eq!(liveness.next_deadline(), None);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        ChannelName, NowFileTransferAbortMsg, NowFileTransferDataMsg, NowFileTransferDownloadReqMsg,
+        NowFileTransferMsg, NowStatusCode, NowString65535, NowVirtualChannel,
+    },
+    sm::{FileTransferPolicy, SessionUsage, VirtChannelSMResult, VirtualChannelSM},
+};
+use alloc::collections::BTreeMap;
+use std::str::FromStr;
+
+pub trait FileTransferChannelCallbackTrait {
+    fn on_policy_changed(&mut self, policy: &FileTransferPolicy) {
+        #![allow(unused_variables)]
+    }
+
+    fn on_download_started(&mut self, request_id: u16, file_size: u64) {
+        #![allow(unused_variables)]
+    }
+
+    fn on_data<'msg>(&mut self, request_id: u16, data: &[u8], last: bool) -> VirtChannelSMResult<'msg> {
+        #![allow(unused_variables)]
+        Ok(None)
+    }
+
+    /// Download refused or interrupted by either side.
+    fn on_download_failed(&mut self, request_id: u16, status: NowStatusCode) {
+        #![allow(unused_variables)]
+    }
+}
+
+sa::assert_obj_safe!(FileTransferChannelCallbackTrait);
+
+pub struct DummyFileTransferChannelCallback;
+impl FileTransferChannelCallbackTrait for DummyFileTransferChannelCallback {}
+
+/// Download in progress. Limits are those of the policy in effect when it was requested.
+#[derive(Debug, Clone)]
+struct Download {
+    policy: FileTransferPolicy,
+    file_size: u64,
+    received: u64,
+}
+
+#[derive(PartialEq, Debug)]
+enum FileTransferState {
+    WaitPolicy,
+    Active,
+    Terminated,
+}
+
+/// Client side of the file transfer channel.
+///
+/// Downloads are only requested once the host policy is known, and requests
+/// outside of the policy are refused without any round trip.
+pub struct FileTransferChannelSM<UserCallback> {
+    state: FileTransferState,
+    policy: FileTransferPolicy,
+    used: u64,
+    next_request_id: u16,
+    downloads: BTreeMap<u16, Download>,
+    user_callback: UserCallback,
+}
+
+impl<UserCallback> FileTransferChannelSM<UserCallback>
+where
+    UserCallback: FileTransferChannelCallbackTrait,
+{
+    pub fn new(user_callback: UserCallback) -> Self {
+        Self {
+            state: FileTransferState::WaitPolicy,
+            policy: FileTransferPolicy::unrestricted(),
+            used: 0,
+            next_request_id: 0,
+            downloads: BTreeMap::new(),
+            user_callback,
+        }
+    }
+
+    /// Current host policy. Meaningless until the channel is active.
+    pub fn policy(&self) -> &FileTransferPolicy {
+        &self.policy
+    }
+
+    pub fn usage(&self) -> SessionUsage {
+        SessionUsage {
+            used: self.used,
+            limit: self.policy.max_session_bytes,
+        }
+    }
+
+    pub fn is_in_progress(&self, request_id: u16) -> bool {
+        self.downloads.contains_key(&request_id)
+    }
+
+    pub fn request_download<'msg>(
+        &mut self,
+        path: &str,
+        file_size: u64,
+    ) -> Result<NowVirtualChannel<'msg>, ProtoError> {
+        if self.state != FileTransferState::Active {
+            return ProtoError::new(ProtoErrorKind::VirtualChannel(self.get_channel_name()))
+                .or_desc("download requested before receiving the transfer policy");
+        }
+
+        if let Err(violation) = self.policy.check_request(path, file_size, self.used) {
+            return ProtoError::new(ProtoErrorKind::VirtualChannel(self.get_channel_name()))
+                .or_else_desc(|| format!("download of {:?} refused by policy: {}", path, violation));
+        }
+
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        self.downloads.insert(
+            request_id,
+            Download {
+                policy: self.policy.clone(),
+                file_size,
+                received: 0,
+            },
+        );
+
+        Ok(NowFileTransferDownloadReqMsg::new(request_id, file_size, NowString65535::from_str(path)?).into())
+    }
+
+    fn __fail<'msg>(&mut self, request_id: u16, status: NowStatusCode) -> VirtChannelSMResult<'msg> {
+        self.downloads.remove(&request_id);
+        self.user_callback.on_download_failed(request_id, status);
+        Ok(Some(NowFileTransferAbortMsg::new(request_id, status).into()))
+    }
+
+    fn __on_data<'msg>(&mut self, msg: &NowFileTransferDataMsg<'_>) -> VirtChannelSMResult<'msg> {
+        let download = match self.downloads.get_mut(&msg.request_id) {
+            Some(download) => download,
+            None => {
+                return ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::FileTransfer))
+                    .or_else_desc(|| format!("data received for unknown download {}", msg.request_id));
+            }
+        };
+
+        let len = msg.data.len() as u64;
+        if download.received + len > download.file_size || download.policy.check_size(len, self.used).is_err() {
+            log::warn!("download {} exceeds the transfer policy: aborting", msg.request_id);
+            return self.__fail(msg.request_id, NowStatusCode::PolicyDenied);
+        }
+
+        download.received += len;
+        self.used += len;
+
+        let last = msg.flags.last();
+        if last {
+            self.downloads.remove(&msg.request_id);
+        }
+
+        self.user_callback.on_data(msg.request_id, &msg.data, last)
+    }
+
+    fn __unexpected_with_call<'msg>(&self) -> VirtChannelSMResult<'msg> {
+        ProtoError::new(ProtoErrorKind::VirtualChannel(self.get_channel_name())).or_desc(format!(
+            "unexpected call to `update_with_chan_msg` in state {:?}",
+            self.state
+        ))
+    }
+
+    fn __unexpected_without_call<'msg>(&self) -> VirtChannelSMResult<'msg> {
+        ProtoError::new(ProtoErrorKind::VirtualChannel(self.get_channel_name())).or_desc(format!(
+            "unexpected call to `update_without_chan_msg` in state {:?}",
+            self.state
+        ))
+    }
+
+    fn __unexpected_message<'msg: 'a, 'a>(&self, unexpected: &'a NowVirtualChannel<'msg>) -> VirtChannelSMResult<'msg> {
+        ProtoError::new(ProtoErrorKind::VirtualChannel(self.get_channel_name())).or_desc(format!(
+            "received an unexpected message in state {:?}: {:?}",
+            self.state, unexpected
+        ))
+    }
+}
+
+impl<UserCallback> VirtualChannelSM for FileTransferChannelSM<UserCallback>
+where
+    UserCallback: FileTransferChannelCallbackTrait,
+{
+    fn get_channel_name(&self) -> ChannelName {
+        ChannelName::FileTransfer
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.state == FileTransferState::Terminated
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        self.state != FileTransferState::Terminated
+    }
+
+    fn update_without_chan_msg<'msg>(&mut self) -> VirtChannelSMResult<'msg> {
+        self.__unexpected_without_call()
+    }
+
+    fn update_with_chan_msg<'msg: 'a, 'a>(
+        &mut self,
+        chan_msg: &'a NowVirtualChannel<'msg>,
+    ) -> VirtChannelSMResult<'msg> {
+        match chan_msg {
+            NowVirtualChannel::FileTransfer(msg) => match self.state {
+                FileTransferState::WaitPolicy | FileTransferState::Active => match msg {
+                    NowFileTransferMsg::Policy(msg) => {
+                        // downloads in progress keep the policy they were requested with
+                        self.policy = FileTransferPolicy::from_msg(msg)?;
+                        log::trace!("transfer policy received: {:?}", self.policy);
+                        self.state = FileTransferState::Active;
+                        self.user_callback.on_policy_changed(&self.policy);
+                        Ok(None)
+                    }
+                    NowFileTransferMsg::DownloadRsp(msg) if self.downloads.contains_key(&msg.request_id) => {
+                        if !msg.status.is_success() {
+                            self.downloads.remove(&msg.request_id);
+                            self.user_callback.on_download_failed(msg.request_id, msg.status);
+                            return Ok(None);
+                        }
+
+                        let download = self.downloads.get_mut(&msg.request_id).unwrap(); // checked above
+                        if download.policy.check_size(msg.file_size, self.used).is_err() {
+                            log::warn!("host announced a file size exceeding the transfer policy");
+                            return self.__fail(msg.request_id, NowStatusCode::PolicyDenied);
+                        }
+
+                        download.file_size = msg.file_size;
+                        self.user_callback.on_download_started(msg.request_id, msg.file_size);
+                        Ok(None)
+                    }
+                    NowFileTransferMsg::Data(msg) => self.__on_data(msg),
+                    NowFileTransferMsg::Abort(msg) => {
+                        if self.downloads.remove(&msg.request_id).is_some() {
+                            self.user_callback.on_download_failed(msg.request_id, msg.status);
+                        }
+                        Ok(None)
+                    }
+                    _ => self.__unexpected_message(chan_msg),
+                },
+                FileTransferState::Terminated => self.__unexpected_with_call(),
+            },
+            _ => self.__unexpected_message(chan_msg),
+        }
+    }
+}
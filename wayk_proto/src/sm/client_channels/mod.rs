@@ -1,6 +1,8 @@
 pub mod chat;
 pub mod clipboard;
+pub mod file_transfer;
 
 // re-export
 pub use chat::*;
 pub use clipboard::*;
+pub use file_transfer::*;
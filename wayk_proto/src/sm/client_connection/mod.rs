@@ -2,13 +2,14 @@
 mod sub_sm;
 
 use crate::{
-    message::{AuthType, ChannelName, NowCapset, NowChannelDef, NowMessage},
+    message::{AuthType, ChannelName, HandoffToken, NowCapset, NowChannelDef, NowMessage},
     sm::{
         ConnectionSM, ConnectionSMResult, ConnectionSMSharedData, ConnectionSMSharedDataRc, ConnectionSeqCallbackTrait,
         ConnectionState, DummyConnectionSM,
     },
+    version::NowProtocolVersionRange,
 };
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, time::Instant};
 
 pub struct ClientConnectionSeqSM<UserCallback> {
     user_callback: UserCallback,
@@ -16,6 +17,7 @@ pub struct ClientConnectionSeqSM<UserCallback> {
     current_sm: Box<dyn ConnectionSM>,
     authenticate_sm: Box<dyn ConnectionSM>,
     shared_data: ConnectionSMSharedDataRc,
+    handoff: Option<(u32, HandoffToken)>,
 }
 
 impl<UserCallback> ClientConnectionSeqSM<UserCallback>
@@ -29,6 +31,10 @@ where
             authenticate_sm: Box::new(DummyConnectionSM),
             capabilities: Vec::new(),
             channels_to_open: Vec::new(),
+            versions: NowProtocolVersionRange::default(),
+            handoff: None,
+            reattach: None,
+            tls: false,
         }
     }
 
@@ -39,19 +45,34 @@ where
         capabilities: Vec<NowCapset<'static>>,
         channels_to_open: Vec<NowChannelDef>,
     ) -> Self {
+        let shared_data = Rc::new(RefCell::new(ConnectionSMSharedData {
+            available_auth_types,
+            capabilities,
+            peer_capabilities: Vec::new(),
+            channels: channels_to_open,
+            handoff: false,
+            tls: false,
+            peer_certificate: None,
+            session_id: None,
+            peer_version: None,
+            versions: NowProtocolVersionRange::default(),
+            negotiated_version: None,
+        }));
         Self {
             user_callback,
             state: ConnectionState::Handshake,
-            current_sm: Box::new(sub_sm::HandshakeSM::new()),
+            current_sm: Box::new(sub_sm::HandshakeSM::new(Rc::clone(&shared_data))),
             authenticate_sm,
-            shared_data: Rc::new(RefCell::new(ConnectionSMSharedData {
-                available_auth_types,
-                capabilities,
-                channels: channels_to_open,
-            })),
+            shared_data,
+            handoff: None,
         }
     }
 
+    /// See `ClientConnectionSeqBuilder::versions`.
+    pub fn set_versions(&mut self, versions: NowProtocolVersionRange) {
+        self.shared_data.borrow_mut().versions = versions;
+    }
+
     pub fn get_state(&self) -> ConnectionState {
         self.state
     }
@@ -66,10 +87,17 @@ where
     fn __go_to_next_state(&mut self) {
         match self.state {
             ConnectionState::Handshake => {
+                self.shared_data.borrow_mut().retain_supported_channels();
                 self.state = ConnectionState::Negotiate;
                 self.current_sm = Box::new(sub_sm::NegotiateSM::new(Rc::clone(&self.shared_data)));
                 self.user_callback.on_handshake_completed(&self.shared_data.borrow());
             }
+            ConnectionState::Negotiate if self.shared_data.borrow().handoff => {
+                // handoff token replaces authentication
+                self.state = ConnectionState::Associate;
+                self.current_sm = Box::new(sub_sm::AssociateSM::new(Rc::clone(&self.shared_data), self.handoff));
+                self.user_callback.on_negotiate_completed(&self.shared_data.borrow());
+            }
             ConnectionState::Negotiate => {
                 self.state = ConnectionState::Authenticate;
                 self.authenticate_sm.set_shared_data(Rc::clone(&self.shared_data));
@@ -83,7 +111,7 @@ where
             }
             ConnectionState::Authenticate => {
                 self.state = ConnectionState::Associate;
-                self.current_sm = Box::new(sub_sm::AssociateSM::new());
+                self.current_sm = Box::new(sub_sm::AssociateSM::new(Rc::clone(&self.shared_data), None));
                 self.user_callback.on_authenticate_completed(&self.shared_data.borrow());
             }
             ConnectionState::Associate => {
@@ -121,10 +149,18 @@ where
         self.state == ConnectionState::Final
     }
 
+    fn current_step(&self) -> Option<ConnectionState> {
+        Some(self.state)
+    }
+
     fn waiting_for_packet(&self) -> bool {
         self.current_sm.waiting_for_packet()
     }
 
+    fn next_deadline(&self) -> Option<Instant> {
+        self.current_sm.next_deadline()
+    }
+
     fn update_without_message<'msg>(&mut self) -> ConnectionSMResult<'msg> {
         let response = self.current_sm.update_without_message();
 
@@ -138,6 +174,10 @@ where
     }
 
     fn update_with_message<'msg: 'a, 'a>(&mut self, msg: &'a NowMessage<'msg>) -> ConnectionSMResult<'msg> {
+        if let (ConnectionState::Handshake, NowMessage::Handshake(handshake)) = (self.state, msg) {
+            self.shared_data.borrow_mut().peer_version = Some(handshake.version());
+        }
+
         let response = self.current_sm.update_with_message(msg);
 
         if self.current_sm.is_terminated() {
@@ -157,6 +197,10 @@ pub struct ClientConnectionSeqBuilder<UserCallback> {
     authenticate_sm: Box<dyn ConnectionSM>,
     capabilities: Vec<NowCapset<'static>>,
     channels_to_open: Vec<NowChannelDef>,
+    versions: NowProtocolVersionRange,
+    handoff: Option<(u32, HandoffToken)>,
+    reattach: Option<u32>,
+    tls: bool,
     user_callback: UserCallback,
 }
 
@@ -189,13 +233,46 @@ where
         }
     }
 
+    /// Protocol versions supported, the highest one being advertised in the handshake.
+    pub fn versions(self, versions: NowProtocolVersionRange) -> Self {
+        Self { versions, ..self }
+    }
+
+    /// Takes over `session_id` using a handoff token obtained by the current viewer.
+    pub fn handoff(self, session_id: u32, token: HandoffToken) -> Self {
+        Self {
+            handoff: Some((session_id, token)),
+            ..self
+        }
+    }
+
+    /// Re-attaches to `session_id`, typically after the connection to the server was lost.
+    pub fn reattach(self, session_id: u32) -> Self {
+        Self {
+            reattach: Some(session_id),
+            ..self
+        }
+    }
+
+    /// Requires the transport to be upgraded to TLS once negotiation completes, before authentication
+    /// (see `NowTransport::connect_tls`). The connection fails if the server doesn't accept it.
+    pub fn tls(self, tls: bool) -> Self {
+        Self { tls, ..self }
+    }
+
     pub fn build(self) -> ClientConnectionSeqSM<UserCallback> {
-        ClientConnectionSeqSM::new(
+        let mut connection_seq = ClientConnectionSeqSM::new(
             self.user_callback,
             self.available_auth_types,
             self.authenticate_sm,
             self.capabilities,
             self.channels_to_open,
-        )
+        );
+        connection_seq.set_versions(self.versions);
+        connection_seq.shared_data.borrow_mut().handoff = self.handoff.is_some();
+        connection_seq.shared_data.borrow_mut().session_id = self.reattach;
+        connection_seq.shared_data.borrow_mut().tls = self.tls;
+        connection_seq.handoff = self.handoff;
+        connection_seq
     }
 }
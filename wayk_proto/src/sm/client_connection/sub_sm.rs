@@ -1,7 +1,8 @@
 use super::{ConnectionSM, ConnectionSMResult};
 use crate::{
     error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
-    message::{NowActivateMsg, NowCapabilitiesMsg, NowMessage},
+    message::{HandoffToken, NowActivateMsg, NowCapabilitiesMsg, NowMessage},
+    serialization::known_capabilities,
     sm::{ConnectionSMSharedData, ConnectionSMSharedDataRc, ConnectionState},
 };
 use log::info;
@@ -39,24 +40,28 @@ enum BasicState {
 
 pub struct HandshakeSM {
     state: BasicState,
+    shared_data: Rc<RefCell<ConnectionSMSharedData>>,
 }
 
 impl HandshakeSM {
     const CONNECTION_STATE: ConnectionState = ConnectionState::Handshake;
     const NAME: &'static str = "HandshakeSM";
 
-    pub fn new() -> Self {
+    pub fn new(shared_data: Rc<RefCell<ConnectionSMSharedData>>) -> Self {
         Self {
             state: BasicState::Initial,
+            shared_data,
         }
     }
 }
 
 impl ConnectionSM for HandshakeSM {
-    fn set_shared_data(&mut self, _: ConnectionSMSharedDataRc) {}
+    fn set_shared_data(&mut self, shared_data: Rc<RefCell<ConnectionSMSharedData>>) {
+        self.shared_data = shared_data;
+    }
 
     fn get_shared_data(&self) -> Option<ConnectionSMSharedDataRc> {
-        None
+        Some(Rc::clone(&self.shared_data))
     }
 
     fn is_terminated(&self) -> bool {
@@ -73,7 +78,9 @@ impl ConnectionSM for HandshakeSM {
         match &self.state {
             BasicState::Initial => {
                 self.state = BasicState::Ready;
-                Ok(Some(NowHandshakeMsg::new_success().into()))
+                // server selects the version, up to the highest one supported
+                let version = self.shared_data.borrow().versions.max;
+                Ok(Some(NowHandshakeMsg::new_with_version(version).into()))
             }
             _ => unexpected_call!(Self, self, "update_without_message"),
         }
@@ -86,7 +93,18 @@ impl ConnectionSM for HandshakeSM {
             BasicState::Ready => match msg {
                 NowMessage::Handshake(msg) => match msg.status.code() {
                     HandshakeStatusCode::Success => {
-                        log::trace!("handshake succeeded");
+                        let mut shared_data = self.shared_data.borrow_mut();
+                        let selected = msg.version();
+                        if !shared_data.versions.contains(selected) {
+                            return ProtoError::new(ProtoErrorKind::ConnectionSequence(Self::CONNECTION_STATE))
+                                .or_desc(format!(
+                                    "server selected version {} out of supported versions ({})",
+                                    selected, shared_data.versions
+                                ));
+                        }
+
+                        log::trace!("handshake succeeded with version {}", selected);
+                        shared_data.negotiated_version = Some(selected);
                         self.state = BasicState::Terminated;
                         Ok(None)
                     }
@@ -149,12 +167,15 @@ impl ConnectionSM for NegotiateSM {
             BasicState::Initial => {
                 self.state = BasicState::Ready;
                 let shared_data = self.shared_data.borrow();
+                let mut flags = NegotiateFlags::new_empty().set_srp_extended();
+                if shared_data.handoff {
+                    flags = flags.set_handoff();
+                }
+                if shared_data.tls {
+                    flags = flags.set_tls();
+                }
                 Ok(Some(
-                    NowNegotiateMsg::new_with_auth_list(
-                        NegotiateFlags::new_empty().set_srp_extended(),
-                        shared_data.available_auth_types.clone(),
-                    )
-                    .into(),
+                    NowNegotiateMsg::new_with_auth_list(flags, shared_data.available_auth_types.clone()).into(),
                 ))
             }
             _ => unexpected_call!(Self, self, "update_without_message"),
@@ -168,6 +189,15 @@ impl ConnectionSM for NegotiateSM {
                     info!("Available authentication methods on server: {:?}", msg.auth_list.0);
 
                     let mut shared_data = self.shared_data.borrow_mut();
+                    if shared_data.handoff && !msg.flags.handoff() {
+                        return ProtoError::new(ProtoErrorKind::ConnectionSequence(Self::CONNECTION_STATE))
+                            .or_desc("server doesn't accept session handoff");
+                    }
+                    if shared_data.tls && !msg.flags.tls() {
+                        return ProtoError::new(ProtoErrorKind::ConnectionSequence(Self::CONNECTION_STATE))
+                            .or_desc("server doesn't accept TLS");
+                    }
+
                     let common_auth_types = msg
                         .auth_list
                         .iter()
@@ -197,15 +227,19 @@ enum AssociateState {
 
 pub struct AssociateSM {
     state: AssociateState,
+    shared_data: ConnectionSMSharedDataRc,
+    handoff: Option<(u32, HandoffToken)>,
 }
 
 impl AssociateSM {
     const CONNECTION_STATE: ConnectionState = ConnectionState::Associate;
     const NAME: &'static str = "AssociateSM";
 
-    pub fn new() -> Self {
+    pub fn new(shared_data: ConnectionSMSharedDataRc, handoff: Option<(u32, HandoffToken)>) -> Self {
         Self {
             state: AssociateState::WaitInfo,
+            shared_data,
+            handoff,
         }
     }
 }
@@ -230,13 +264,29 @@ impl ConnectionSM for AssociateSM {
     }
 
     fn update_with_message<'msg: 'a, 'a>(&mut self, msg: &'a NowMessage<'msg>) -> ConnectionSMResult<'msg> {
-        use wayk_proto::message::{status::AssociateStatusCode, NowAssociateMsg};
+        use wayk_proto::message::{
+            status::AssociateStatusCode, AssociateRequestFlags, NowAssociateMsg, NowAssociateRequestMsg,
+        };
 
         match &self.state {
             AssociateState::WaitInfo => match msg {
                 NowMessage::Associate(NowAssociateMsg::Info(msg)) => {
                     self.state = AssociateState::WaitResponse;
-                    if msg.flags.active() {
+                    let reattach = self.shared_data.borrow().session_id;
+                    if let Some((session_id, token)) = self.handoff {
+                        Ok(Some(
+                            NowAssociateMsg::from(NowAssociateRequestMsg::new_handoff(session_id, token)).into(),
+                        ))
+                    } else if let Some(session_id) = reattach {
+                        log::trace!("re-attaching to session {}", session_id);
+                        Ok(Some(
+                            NowAssociateMsg::from(NowAssociateRequestMsg::new_with_session_id(
+                                AssociateRequestFlags::new_empty(),
+                                session_id,
+                            ))
+                            .into(),
+                        ))
+                    } else if msg.flags.active() {
                         log::trace!("associate process session is already active");
                         Ok(None)
                     } else {
@@ -249,13 +299,22 @@ impl ConnectionSM for AssociateSM {
                 NowMessage::Associate(NowAssociateMsg::Response(msg)) => match msg.status.code() {
                     AssociateStatusCode::Success => {
                         self.state = AssociateState::Terminated;
+                        if msg.session_id != 0 {
+                            self.shared_data.borrow_mut().session_id = Some(msg.session_id);
+                        }
                         log::trace!("associate process succeeded");
                         Ok(None)
                     }
                     AssociateStatusCode::Failure => {
-                        ProtoError::new(ProtoErrorKind::ConnectionSequence(ConnectionState::Handshake))
+                        ProtoError::new(ProtoErrorKind::ConnectionSequence(Self::CONNECTION_STATE))
                             .or_desc(format!("Association failed {:?}", msg.status.status_type().to_string()))
                     }
+                    code @ AssociateStatusCode::HandoffTokenInvalid
+                    | code @ AssociateStatusCode::HandoffTokenExpired
+                    | code @ AssociateStatusCode::AccessDenied => {
+                        ProtoError::new(ProtoErrorKind::ConnectionSequence(Self::CONNECTION_STATE))
+                            .or_desc(code.to_string())
+                    }
                 },
                 unexpected => unexpected_msg!(Self, self, unexpected),
             },
@@ -318,6 +377,7 @@ impl ConnectionSM for CapabilitiesSM {
                     );
                     log::trace!("Server capabilities details: {:#?}", msg.capabilities.0);
 
+                    self.shared_data.borrow_mut().peer_capabilities = known_capabilities(&msg.capabilities.0);
                     self.terminated = true;
                     Ok(Some(
                         NowCapabilitiesMsg::new_with_capabilities(self.shared_data.borrow().capabilities.clone())
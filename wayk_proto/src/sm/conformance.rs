@@ -0,0 +1,801 @@
+//! Protocol conformance scenarios shared between client and server state machines.
+//!
+//! A scenario is data: the ordered list of full packets exchanged during a session,
+//! written as byte snapshots just like any other packet fixture of this crate.
+//! Each scenario runs in three modes:
+//! - client sharee against recorded server packets,
+//! - server sharee against recorded client packets,
+//! - client against server live (the transcript must match the recording).
+//!
+//! Packets are produced by the sharees, except the ones marked as sent by the application
+//! (a topology update, a termination, ...) which are sent as recorded.
+
+use crate::{
+    channels_manager::ChannelsManager,
+    message::{
+        AuthType, ChannelName, NowCapset, NowMessage, NowSurfaceListRspMsg, NowSurfaceMsg, SurfaceResponseFlags,
+        TransportCapset,
+    },
+    packet::NowPacket,
+    serialization::Encode,
+    sharee::{DummyShareeCallback, Sharee, ShareeCallbackTrait, ShareeResult, ShareeState},
+    sm::{
+        ClientConnectionSeqBuilder, ClientConnectionSeqSM, ConnectionSM, DummyConnectionSeqCallback,
+        ServerConnectionSeqBuilder, ServerConnectionSeqSM,
+    },
+};
+use alloc::collections::VecDeque;
+use std::{io::Cursor, time::Instant};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+use Direction::*;
+
+struct Step {
+    direction: Direction,
+    /// Sent by the application of the sending side rather than produced by its sharee.
+    application: bool,
+    packet: &'static [u8],
+}
+
+struct Scenario {
+    name: &'static str,
+    client: fn() -> Box<dyn Endpoint>,
+    server: fn() -> Box<dyn Endpoint>,
+    steps: &'static [Step],
+    /// State of both sharees once all steps are exchanged.
+    end_state: ShareeState,
+}
+
+/// Side of a scenario, packets being exchanged as bytes.
+trait Endpoint {
+    fn state(&self) -> ShareeState;
+    fn is_running(&self) -> bool;
+    fn waiting_for_packet(&self) -> bool;
+    fn update_without_packet(&mut self) -> Result<Option<Vec<u8>>, String>;
+    fn update_with_packet(&mut self, bytes: &[u8]) -> Result<Option<Vec<u8>>, String>;
+}
+
+fn encode_answer(result: ShareeResult<'_>) -> Result<Option<Vec<u8>>, String> {
+    match result.map_err(|e| e.to_string())? {
+        Some(packet) => packet.encode().map(Some).map_err(|e| e.to_string()),
+        None => Ok(None),
+    }
+}
+
+impl<ConnectionSeq, UserCallback> Endpoint for Sharee<ConnectionSeq, UserCallback>
+where
+    ConnectionSeq: ConnectionSM,
+    UserCallback: ShareeCallbackTrait,
+{
+    fn state(&self) -> ShareeState {
+        self.get_state()
+    }
+
+    fn is_running(&self) -> bool {
+        Sharee::is_running(self)
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        Sharee::waiting_for_packet(self, Instant::now())
+    }
+
+    fn update_without_packet(&mut self) -> Result<Option<Vec<u8>>, String> {
+        encode_answer(self.update_without_body(Instant::now()))
+            .map_err(|e| format!("update without packet failed: {}", e))
+    }
+
+    fn update_with_packet(&mut self, bytes: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let mut buffer = Vec::new();
+        let packet = NowPacket::read_from(&mut Cursor::new(bytes), &mut buffer, self.get_channels_ctx())
+            .map_err(|e| format!("couldn't decode packet {}: {}", hex(bytes), e))?;
+        encode_answer(self.update_with_body(&packet.body, Instant::now()))
+            .map_err(|e| format!("update with packet failed: {}", e))
+    }
+}
+
+fn endpoint<ConnectionSeq, UserCallback>(
+    connection_seq: ConnectionSeq,
+    user_callback: UserCallback,
+) -> Box<dyn Endpoint>
+where
+    ConnectionSeq: ConnectionSM + 'static,
+    UserCallback: ShareeCallbackTrait + 'static,
+{
+    Box::new(Sharee::new(connection_seq, ChannelsManager::new(), user_callback))
+}
+
+/// Viewer accepting every surface list update.
+struct SurfaceViewer;
+
+impl ShareeCallbackTrait for SurfaceViewer {
+    fn on_unprocessed_message<'msg: 'a, 'a>(&mut self, message: &'a NowMessage<'msg>) -> ShareeResult<'msg> {
+        match message {
+            NowMessage::Surface(NowSurfaceMsg::ListReq(req)) => {
+                Ok(Some(NowPacket::from_message(NowSurfaceMsg::ListRsp(
+                    NowSurfaceListRspMsg::new(SurfaceResponseFlags::new_empty(), req.sequence_id),
+                ))))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+// == SCENARIOS == //
+
+fn client_builder(auth_types: Vec<AuthType>) -> ClientConnectionSeqBuilder<DummyConnectionSeqCallback> {
+    ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(auth_types)
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .channels_to_open(vec![ChannelName::Clipboard, ChannelName::Chat])
+}
+
+fn happy_path_client() -> Box<dyn Endpoint> {
+    endpoint(client_builder(vec![AuthType::PFP]).build(), DummyShareeCallback)
+}
+
+fn reattach_client() -> Box<dyn Endpoint> {
+    endpoint(
+        client_builder(vec![AuthType::PFP]).reattach(42).build(),
+        DummyShareeCallback,
+    )
+}
+
+/// Prefers SRP, unavailable on the PFP-only server.
+fn srp_first_client() -> Box<dyn Endpoint> {
+    endpoint(
+        client_builder(vec![AuthType::SRP, AuthType::PFP]).build(),
+        DummyShareeCallback,
+    )
+}
+
+fn viewer_client() -> Box<dyn Endpoint> {
+    endpoint(client_builder(vec![AuthType::PFP]).build(), SurfaceViewer)
+}
+
+fn server_builder(auth_types: Vec<AuthType>) -> ServerConnectionSeqBuilder<DummyConnectionSeqCallback> {
+    ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(auth_types)
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .available_channels(vec![ChannelName::Chat])
+}
+
+fn happy_path_server() -> Box<dyn Endpoint> {
+    endpoint(
+        server_builder(vec![AuthType::SRP, AuthType::PFP]).build(),
+        DummyShareeCallback,
+    )
+}
+
+fn pfp_only_server() -> Box<dyn Endpoint> {
+    endpoint(server_builder(vec![AuthType::PFP]).build(), DummyShareeCallback)
+}
+
+#[rustfmt::skip]
+const HAPPY_PATH_STEPS: &[Step] = &[
+    // handshake
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x28, 0x00, 0x01, 0x80, 0x03, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // handshake
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x28, 0x00, 0x01, 0x80, 0x03, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // negotiate
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x06, 0x00, 0x02, 0x80, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01,
+    ] },
+    // negotiate
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x07, 0x00, 0x02, 0x80, 0x01, 0x00, 0x00, 0x00, 0x02, 0x02, 0x01,
+    ] },
+    // associate info
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x08, 0x00, 0x04, 0x80, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // associate request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x08, 0x00, 0x04, 0x80, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // associate response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x0c, 0x00, 0x04, 0x80, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // capabilities
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x19, 0x00, 0x05, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x14, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x54,
+        0x72, 0x61, 0x6e, 0x73, 0x70, 0x6f, 0x72, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // capabilities
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x19, 0x00, 0x05, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x14, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x54,
+        0x72, 0x61, 0x6e, 0x73, 0x70, 0x6f, 0x72, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // channel list request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x22, 0x00, 0x06, 0x80, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x43,
+        0x6c, 0x69, 0x70, 0x62, 0x6f, 0x61, 0x72, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f,
+        0x77, 0x43, 0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel list response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel open request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel open response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // activate
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x04, 0x00, 0x07, 0x80, 0x00, 0x00, 0x00, 0x00,
+    ] },
+];
+
+#[rustfmt::skip]
+const REATTACH_STEPS: &[Step] = &[
+    // handshake
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x28, 0x00, 0x01, 0x80, 0x03, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // handshake
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x28, 0x00, 0x01, 0x80, 0x03, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // negotiate
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x06, 0x00, 0x02, 0x80, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01,
+    ] },
+    // negotiate
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x07, 0x00, 0x02, 0x80, 0x01, 0x00, 0x00, 0x00, 0x02, 0x02, 0x01,
+    ] },
+    // associate info
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x08, 0x00, 0x04, 0x80, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // associate request (session 42)
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x08, 0x00, 0x04, 0x80, 0x02, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00,
+    ] },
+    // associate response (session 42)
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x0c, 0x00, 0x04, 0x80, 0x03, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // capabilities
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x19, 0x00, 0x05, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x14, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x54,
+        0x72, 0x61, 0x6e, 0x73, 0x70, 0x6f, 0x72, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // capabilities
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x19, 0x00, 0x05, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x14, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x54,
+        0x72, 0x61, 0x6e, 0x73, 0x70, 0x6f, 0x72, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // channel list request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x22, 0x00, 0x06, 0x80, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x43,
+        0x6c, 0x69, 0x70, 0x62, 0x6f, 0x61, 0x72, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f,
+        0x77, 0x43, 0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel list response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel open request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel open response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // activate
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x04, 0x00, 0x07, 0x80, 0x00, 0x00, 0x00, 0x00,
+    ] },
+];
+
+#[rustfmt::skip]
+const AUTH_FALLBACK_STEPS: &[Step] = &[
+    // handshake
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x28, 0x00, 0x01, 0x80, 0x03, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // handshake
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x28, 0x00, 0x01, 0x80, 0x03, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // negotiate (SRP preferred)
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x07, 0x00, 0x02, 0x80, 0x01, 0x00, 0x00, 0x00, 0x02, 0x02, 0x01,
+    ] },
+    // negotiate (PFP only)
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x06, 0x00, 0x02, 0x80, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01,
+    ] },
+    // associate info
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x08, 0x00, 0x04, 0x80, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // associate request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x08, 0x00, 0x04, 0x80, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // associate response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x0c, 0x00, 0x04, 0x80, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // capabilities
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x19, 0x00, 0x05, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x14, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x54,
+        0x72, 0x61, 0x6e, 0x73, 0x70, 0x6f, 0x72, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // capabilities
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x19, 0x00, 0x05, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x14, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x54,
+        0x72, 0x61, 0x6e, 0x73, 0x70, 0x6f, 0x72, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // channel list request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x22, 0x00, 0x06, 0x80, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x43,
+        0x6c, 0x69, 0x70, 0x62, 0x6f, 0x61, 0x72, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f,
+        0x77, 0x43, 0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel list response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel open request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel open response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // activate
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x04, 0x00, 0x07, 0x80, 0x00, 0x00, 0x00, 0x00,
+    ] },
+];
+
+#[rustfmt::skip]
+const SURFACE_RENEGOTIATION_STEPS: &[Step] = &[
+    // handshake
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x28, 0x00, 0x01, 0x80, 0x03, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // handshake
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x28, 0x00, 0x01, 0x80, 0x03, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // negotiate
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x06, 0x00, 0x02, 0x80, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01,
+    ] },
+    // negotiate
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x07, 0x00, 0x02, 0x80, 0x01, 0x00, 0x00, 0x00, 0x02, 0x02, 0x01,
+    ] },
+    // associate info
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x08, 0x00, 0x04, 0x80, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // associate request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x08, 0x00, 0x04, 0x80, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // associate response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x0c, 0x00, 0x04, 0x80, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // capabilities
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x19, 0x00, 0x05, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x14, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x54,
+        0x72, 0x61, 0x6e, 0x73, 0x70, 0x6f, 0x72, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // capabilities
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x19, 0x00, 0x05, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x14, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x54,
+        0x72, 0x61, 0x6e, 0x73, 0x70, 0x6f, 0x72, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // channel list request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x22, 0x00, 0x06, 0x80, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x43,
+        0x6c, 0x69, 0x70, 0x62, 0x6f, 0x61, 0x72, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f,
+        0x77, 0x43, 0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel list response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel open request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel open response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // activate
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x04, 0x00, 0x07, 0x80, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // surface list request (topology update)
+    Step { direction: ServerToClient, application: true, packet: &[
+        0x29, 0x00, 0x41, 0x80, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0f, 0x38, 0x04, 0x02, 0x10, 0x00, 0x09,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x07, 0x38, 0x04, 0x10, 0x00, 0x09,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x80, 0x07, 0x00, 0x00, 0x00, 0x0f, 0x38, 0x04,
+    ] },
+    // surface list response
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x04, 0x00, 0x41, 0x80, 0x02, 0x00, 0x01, 0x00,
+    ] },
+];
+
+#[rustfmt::skip]
+const GRACEFUL_DISCONNECT_STEPS: &[Step] = &[
+    // handshake
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x28, 0x00, 0x01, 0x80, 0x03, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // handshake
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x28, 0x00, 0x01, 0x80, 0x03, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // negotiate
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x06, 0x00, 0x02, 0x80, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01,
+    ] },
+    // negotiate
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x07, 0x00, 0x02, 0x80, 0x01, 0x00, 0x00, 0x00, 0x02, 0x02, 0x01,
+    ] },
+    // associate info
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x08, 0x00, 0x04, 0x80, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // associate request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x08, 0x00, 0x04, 0x80, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // associate response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x0c, 0x00, 0x04, 0x80, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // capabilities
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x19, 0x00, 0x05, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x14, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x54,
+        0x72, 0x61, 0x6e, 0x73, 0x70, 0x6f, 0x72, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // capabilities
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x19, 0x00, 0x05, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x14, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x54,
+        0x72, 0x61, 0x6e, 0x73, 0x70, 0x6f, 0x72, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // channel list request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x22, 0x00, 0x06, 0x80, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x4e, 0x6f, 0x77, 0x43,
+        0x6c, 0x69, 0x70, 0x62, 0x6f, 0x61, 0x72, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f,
+        0x77, 0x43, 0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel list response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel open request
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // channel open response
+    Step { direction: ServerToClient, application: false, packet: &[
+        0x10, 0x00, 0x06, 0x80, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x07, 0x4e, 0x6f, 0x77, 0x43,
+        0x68, 0x61, 0x74, 0x00,
+    ] },
+    // activate
+    Step { direction: ClientToServer, application: false, packet: &[
+        0x04, 0x00, 0x07, 0x80, 0x00, 0x00, 0x00, 0x00,
+    ] },
+    // terminate (by local user)
+    Step { direction: ClientToServer, application: true, packet: &[
+        0x08, 0x00, 0x08, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00,
+    ] },
+    // terminate (acknowledgement)
+    Step { direction: ServerToClient, application: true, packet: &[
+        0x08, 0x00, 0x08, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ] },
+];
+
+const SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "handshake happy path",
+        client: happy_path_client,
+        server: happy_path_server,
+        steps: HAPPY_PATH_STEPS,
+        end_state: ShareeState::Active,
+    },
+    Scenario {
+        name: "re-attach to session",
+        client: reattach_client,
+        server: happy_path_server,
+        steps: REATTACH_STEPS,
+        end_state: ShareeState::Active,
+    },
+    Scenario {
+        name: "auth fallback",
+        client: srp_first_client,
+        server: pfp_only_server,
+        steps: AUTH_FALLBACK_STEPS,
+        end_state: ShareeState::Active,
+    },
+    Scenario {
+        name: "surface renegotiation",
+        client: viewer_client,
+        server: happy_path_server,
+        steps: SURFACE_RENEGOTIATION_STEPS,
+        end_state: ShareeState::Active,
+    },
+    Scenario {
+        name: "graceful disconnect",
+        client: happy_path_client,
+        server: happy_path_server,
+        steps: GRACEFUL_DISCONNECT_STEPS,
+        end_state: ShareeState::Final,
+    },
+];
+
+// == RUNNERS == //
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+/// Drives the sharee until it waits for a packet, queuing produced packets.
+fn pump(
+    endpoint: &mut dyn Endpoint,
+    from: Direction,
+    queue: &mut VecDeque<(Direction, Vec<u8>)>,
+) -> Result<(), String> {
+    while endpoint.is_running() && !endpoint.waiting_for_packet() {
+        if let Some(bytes) = endpoint.update_without_packet()? {
+            queue.push_back((from, bytes));
+        }
+    }
+    Ok(())
+}
+
+/// Feeds a packet to the sharee, queuing produced packets.
+fn deliver(
+    endpoint: &mut dyn Endpoint,
+    from: Direction,
+    bytes: &[u8],
+    queue: &mut VecDeque<(Direction, Vec<u8>)>,
+) -> Result<(), String> {
+    if let Some(bytes) = endpoint.update_with_packet(bytes)? {
+        queue.push_back((from, bytes));
+    }
+
+    pump(endpoint, from, queue)
+}
+
+fn check_step(scenario: &Scenario, index: usize, direction: Direction, bytes: &[u8]) -> Result<(), String> {
+    let step = scenario.steps.get(index).ok_or_else(|| {
+        format!(
+            "[{}] unexpected extra packet {:?}: {}",
+            scenario.name,
+            direction,
+            hex(bytes)
+        )
+    })?;
+
+    if step.direction != direction || step.packet != bytes {
+        return Err(format!(
+            "[{}] step {} mismatch\n  expected {:?}: {}\n  actual   {:?}: {}",
+            scenario.name,
+            index,
+            step.direction,
+            hex(step.packet),
+            direction,
+            hex(bytes)
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_end_state(scenario: &Scenario, side: Direction, endpoint: &dyn Endpoint) -> Result<(), String> {
+    if endpoint.state() != scenario.end_state {
+        return Err(format!(
+            "[{}] {:?} side ended in {:?} state (expected {:?})",
+            scenario.name,
+            side,
+            endpoint.state(),
+            scenario.end_state
+        ));
+    }
+
+    Ok(())
+}
+
+/// Runs the local sharee against recorded packets of the remote side.
+fn run_against_recording(scenario: &Scenario, local: Direction) -> Result<(), String> {
+    let mut endpoint = match local {
+        ClientToServer => (scenario.client)(),
+        ServerToClient => (scenario.server)(),
+    };
+
+    let mut produced = VecDeque::new();
+    pump(endpoint.as_mut(), local, &mut produced)?;
+
+    for (index, step) in scenario.steps.iter().enumerate() {
+        if step.direction == local && !step.application {
+            let (direction, bytes) = produced
+                .pop_front()
+                .ok_or_else(|| format!("[{}] step {}: no packet produced", scenario.name, index))?;
+            check_step(scenario, index, direction, &bytes)?;
+        } else {
+            if let Some((direction, bytes)) = produced.front() {
+                return Err(format!(
+                    "[{}] step {}: unexpected packet {:?}: {}",
+                    scenario.name,
+                    index,
+                    direction,
+                    hex(bytes)
+                ));
+            }
+            // packets of the local application are sent as is
+            if step.direction != local {
+                deliver(endpoint.as_mut(), local, step.packet, &mut produced)
+                    .map_err(|e| format!("[{}] step {}: {}", scenario.name, index, e))?;
+            }
+        }
+    }
+
+    if let Some((direction, bytes)) = produced.front() {
+        return Err(format!(
+            "[{}] unexpected trailing packet {:?}: {}",
+            scenario.name,
+            direction,
+            hex(bytes)
+        ));
+    }
+
+    check_end_state(scenario, local, endpoint.as_ref())
+}
+
+/// Runs client against server and checks the transcript against the recording.
+///
+/// Packets of the applications are sent once both sides are idle, at their place in the recording.
+fn run_live(scenario: &Scenario) -> Result<(), String> {
+    let mut client = (scenario.client)();
+    let mut server = (scenario.server)();
+
+    let mut in_flight = VecDeque::new();
+    pump(client.as_mut(), ClientToServer, &mut in_flight)?;
+    pump(server.as_mut(), ServerToClient, &mut in_flight)?;
+
+    let mut index = 0;
+    loop {
+        if in_flight.is_empty() {
+            match scenario.steps.get(index) {
+                Some(step) if step.application => in_flight.push_back((step.direction, step.packet.to_vec())),
+                _ => break,
+            }
+        }
+        let (direction, bytes) = in_flight.pop_front().expect("packet in flight");
+
+        check_step(scenario, index, direction, &bytes)?;
+        match direction {
+            ClientToServer => deliver(server.as_mut(), ServerToClient, &bytes, &mut in_flight),
+            ServerToClient => deliver(client.as_mut(), ClientToServer, &bytes, &mut in_flight),
+        }
+        .map_err(|e| format!("[{}] step {}: {}", scenario.name, index, e))?;
+        index += 1;
+    }
+
+    if index != scenario.steps.len() {
+        return Err(format!(
+            "[{}] live exchange stopped after {} of {} steps",
+            scenario.name,
+            index,
+            scenario.steps.len()
+        ));
+    }
+
+    check_end_state(scenario, ClientToServer, client.as_ref())?;
+    check_end_state(scenario, ServerToClient, server.as_ref())
+}
+
+#[test]
+fn client_against_recorded_server() {
+    for scenario in SCENARIOS {
+        run_against_recording(scenario, ClientToServer).unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+#[test]
+fn server_against_recorded_client() {
+    for scenario in SCENARIOS {
+        run_against_recording(scenario, ServerToClient).unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+#[test]
+fn client_against_server_live() {
+    for scenario in SCENARIOS {
+        run_live(scenario).unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+#[test]
+fn encoding_change_breaks_snapshots() {
+    // flip a byte of the first client packet, as an encoding change would
+    let mut tampered = HAPPY_PATH_STEPS[0].packet.to_vec();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 0xFF;
+    let tampered: &'static [u8] = Box::leak(tampered.into_boxed_slice());
+
+    let mut steps: Vec<Step> = HAPPY_PATH_STEPS
+        .iter()
+        .map(|step| Step {
+            direction: step.direction,
+            application: step.application,
+            packet: step.packet,
+        })
+        .collect();
+    steps[0].packet = tampered;
+
+    let scenario = Scenario {
+        name: "tampered happy path",
+        client: happy_path_client,
+        server: happy_path_server,
+        steps: Box::leak(steps.into_boxed_slice()),
+        end_state: ShareeState::Active,
+    };
+
+    let err = run_against_recording(&scenario, ClientToServer).unwrap_err();
+    assert!(err.contains("step 0 mismatch"), "{}", err);
+    assert!(run_live(&scenario).is_err());
+}
@@ -0,0 +1,950 @@
+//! Sans-IO driver of a sharee.
+//!
+//! [`NowConnection`](struct.NowConnection.html) owns the sharee and the reception buffer: bytes read
+//! from the link are given to [`feed_bytes`](struct.NowConnection.html#method.feed_bytes), frames to
+//! write are taken from [`poll_transmit`](struct.NowConnection.html#method.poll_transmit). No I/O nor
+//! blocking call is made and the clock is not read, the current instant being given along, so the same
+//! sequencing runs over blocking sockets, any async runtime or wasm, the caller only moving bytes around.
+
+use crate::{
+    error::ProtoError,
+    header::{AbstractNowHeader, NowHeader},
+    message::BodyType,
+    packet::{NowPacket, NowPacketAccumulator},
+    serialization::{Decode, Encode},
+    sharee::{Sharee, ShareeCallbackTrait, ShareeState},
+    sm::ConnectionSM,
+};
+use alloc::collections::VecDeque;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// The connection sequence completed: the session is active.
+    Connected,
+    /// A packet was received and given to the sharee.
+    Processed(BodyType),
+    /// The link failed: the session is to be resumed over a new link (see `NowConnection::reconnect`).
+    Interrupted,
+    /// The session terminated. Frames still to transmit should be written before closing the link.
+    Terminated,
+}
+
+pub struct NowConnection<ConnectionSeq, UserCallback> {
+    sharee: Sharee<ConnectionSeq, UserCallback>,
+    acc: NowPacketAccumulator<'static>,
+    transmit: VecDeque<Vec<u8>>,
+    /// Sharee state as last reported by the events.
+    state: ShareeState,
+}
+
+impl<ConnectionSeq, UserCallback> NowConnection<ConnectionSeq, UserCallback>
+where
+    ConnectionSeq: ConnectionSM,
+    UserCallback: ShareeCallbackTrait,
+{
+    pub fn new(sharee: Sharee<ConnectionSeq, UserCallback>) -> Self {
+        let state = sharee.get_state();
+        Self {
+            sharee,
+            acc: NowPacketAccumulator::new(),
+            transmit: VecDeque::new(),
+            state,
+        }
+    }
+
+    pub fn get_sharee(&self) -> &Sharee<ConnectionSeq, UserCallback> {
+        &self.sharee
+    }
+
+    pub fn get_sharee_mut(&mut self) -> &mut Sharee<ConnectionSeq, UserCallback> {
+        &mut self.sharee
+    }
+
+    pub fn into_sharee(self) -> Sharee<ConnectionSeq, UserCallback> {
+        self.sharee
+    }
+
+    pub fn get_state(&self) -> ShareeState {
+        self.sharee.get_state()
+    }
+
+    pub fn is_terminated(&self) -> bool {
+        self.sharee.is_terminated()
+    }
+
+    /// Detaches the sharee from the current link and runs `connection_seq` for a new one,
+    /// typically re-attaching to the same session (see `Sharee::reconnect`).
+    ///
+    /// Bytes received and frames not transmitted yet are dropped with the previous link.
+    pub fn reconnect(
+        &mut self,
+        connection_seq: ConnectionSeq,
+        now: Instant,
+    ) -> Result<Vec<ConnectionEvent>, ProtoError> {
+        self.acc = NowPacketAccumulator::new();
+        self.transmit.clear();
+        self.sharee.reconnect(connection_seq);
+        self.state = self.sharee.get_state();
+        self.update(now)
+    }
+
+    /// Updates the sharee with bytes received from the link. Complete packets are processed right away,
+    /// the others are kept until the rest of their bytes is fed.
+    pub fn feed_bytes(&mut self, bytes: &[u8], now: Instant) -> Result<Vec<ConnectionEvent>, ProtoError> {
+        self.acc.accumulate(bytes);
+        self.update(now)
+    }
+
+    /// Records a failure of the link, such as a read error or a timeout, interrupting or ending the session
+    /// depending on its class (see `Sharee::on_error`).
+    pub fn on_error(&mut self, error: &ProtoError) -> Vec<ConnectionEvent> {
+        let mut events = Vec::new();
+        self.sharee.on_error(error);
+        self.__report_state(&mut events);
+        events
+    }
+
+    /// Runs the updates needing no packet, typically to start the connection sequence of a client.
+    pub fn update(&mut self, now: Instant) -> Result<Vec<ConnectionEvent>, ProtoError> {
+        let mut events = Vec::new();
+        let result = self.__pump(&mut events, now);
+        self.acc.purge_old_packets();
+        result.map(|()| events)
+    }
+
+    /// Queues `packet` to be transmitted after the frames already queued.
+    pub fn send<'a, P: Into<NowPacket<'a>>>(&mut self, packet: P, now: Instant) -> Result<(), ProtoError> {
+        if let Some(packet) = self.sharee.intercept_outgoing(packet.into(), now) {
+            self.transmit.push_back(packet.encode()?);
+        }
+        Ok(())
+    }
+
+    /// Queues an already encoded frame, decoded only for the outgoing hooks of the sharee if any.
+    pub fn send_encoded(&mut self, frame: Vec<u8>, now: Instant) -> Result<(), ProtoError> {
+        if !self.sharee.has_hooks() {
+            self.transmit.push_back(frame);
+            return Ok(());
+        }
+
+        let header = NowHeader::decode(&frame)?;
+        let header_len = header.len();
+        let packet = NowPacket::decode_from(header, &frame[header_len..], self.sharee.get_channels_ctx())?;
+        self.send(packet, now)
+    }
+
+    /// Next frame to write to the link.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.transmit.pop_front()
+    }
+
+    pub fn has_pending_transmit(&self) -> bool {
+        !self.transmit.is_empty()
+    }
+
+    /// Instant at which [`update`](#method.update) is to be called even if no byte is received,
+    /// typically while the local user is prompted for access.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.sharee.next_deadline()
+    }
+
+    /// Packets are processed one at a time: a state machine may need to be updated
+    /// without packet before it is ready for the next one.
+    fn __pump(&mut self, events: &mut Vec<ConnectionEvent>, now: Instant) -> Result<(), ProtoError> {
+        loop {
+            while self.sharee.is_running() && !self.sharee.waiting_for_packet(now) {
+                let answer = self.sharee.update_without_body(now)?;
+                if let Some(answer) = answer {
+                    self.transmit.push_back(answer.encode()?);
+                }
+                self.__report_state(events);
+            }
+
+            if !self.sharee.is_running() {
+                self.__report_state(events);
+                return Ok(());
+            }
+
+            let body_type = match self.acc.next_packet(self.sharee.get_channels_ctx()) {
+                Some(packet) => {
+                    let packet = packet?;
+                    // the answer may borrow the received packet: it is encoded right away
+                    if let Some(answer) = self.sharee.update_with_body(&packet.body, now)? {
+                        self.transmit.push_back(answer.encode()?);
+                    }
+                    packet.header.body_type()
+                }
+                None => return Ok(()),
+            };
+            events.push(ConnectionEvent::Processed(body_type));
+            self.__report_state(events);
+        }
+    }
+
+    fn __report_state(&mut self, events: &mut Vec<ConnectionEvent>) {
+        let state = self.sharee.get_state();
+        if state == self.state {
+            return;
+        }
+
+        match state {
+            ShareeState::Active => events.push(ConnectionEvent::Connected),
+            ShareeState::Interrupted => events.push(ConnectionEvent::Interrupted),
+            ShareeState::Final => events.push(ConnectionEvent::Terminated),
+            ShareeState::Connection => {}
+        }
+        self.state = state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channels_manager::{ChannelHandler, ChannelsManager},
+        error::{ErrorClass, ProtoErrorKind},
+        message::{
+            AuthType, ChannelName, MessageType, NowBody, NowCapset, NowChatMsg, NowChatPokeMsg, NowMessage,
+            TransportCapset,
+        },
+        middleware::{HookAction, MessageHook},
+        sharee::DummyShareeCallback,
+        sm::{
+            ChannelCloseReason, ChannelFlowControl, ChannelLifecycleEvent, ChannelLifecycleState, ChannelLiveness,
+            ClientConnectionSeqSM, CustomChannelSM, DummyConnectionSeqCallback, ServerConnectionSeqSM, TunnelChannel,
+            TunnelChannelSM,
+        },
+        version::{NowProtocolVersion, NowProtocolVersionRange},
+    };
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+        task::{Context, Poll, Waker},
+        time::Duration,
+    };
+
+    #[test]
+    fn sequence_driven_by_moving_bytes() {
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .channels_to_open(vec![ChannelName::Chat])
+            .build();
+        let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![ChannelName::Chat])
+            .build();
+        let mut client = NowConnection::new(Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback));
+        let mut server = NowConnection::new(Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback));
+
+        let mut client_events = client.update(Instant::now()).unwrap();
+        let mut server_events = server.update(Instant::now()).unwrap();
+        for _ in 0..32 {
+            while let Some(frame) = client.poll_transmit() {
+                // split frames to check partial packets are kept
+                let (first, second) = frame.split_at(frame.len() / 2);
+                server_events.extend(server.feed_bytes(first, Instant::now()).unwrap());
+                server_events.extend(server.feed_bytes(second, Instant::now()).unwrap());
+            }
+            while let Some(frame) = server.poll_transmit() {
+                client_events.extend(client.feed_bytes(&frame, Instant::now()).unwrap());
+            }
+        }
+
+        assert_eq!(client.get_state(), ShareeState::Active);
+        assert_eq!(server.get_state(), ShareeState::Active);
+        assert!(client_events.contains(&ConnectionEvent::Connected));
+        assert!(server_events.contains(&ConnectionEvent::Connected));
+        assert!(client
+            .get_sharee()
+            .get_channels_ctx()
+            .get_id_by_channel(&ChannelName::Chat)
+            .is_some());
+
+        client
+            .send(crate::message::NowTerminateMsg::default(), Instant::now())
+            .unwrap();
+        let frame = client.poll_transmit().unwrap();
+        assert_eq!(
+            server.feed_bytes(&frame, Instant::now()).unwrap().last(),
+            Some(&ConnectionEvent::Terminated)
+        );
+    }
+
+    fn connections(
+        client_versions: NowProtocolVersionRange,
+        server_versions: NowProtocolVersionRange,
+    ) -> (
+        NowConnection<ClientConnectionSeqSM<DummyConnectionSeqCallback>, DummyShareeCallback>,
+        NowConnection<ServerConnectionSeqSM<DummyConnectionSeqCallback>, DummyShareeCallback>,
+    ) {
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .channels_to_open(vec![ChannelName::Tunnel, ChannelName::Chat])
+            .versions(client_versions)
+            .build();
+        let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![ChannelName::Tunnel, ChannelName::Chat])
+            .versions(server_versions)
+            .build();
+        (
+            NowConnection::new(Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback)),
+            NowConnection::new(Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback)),
+        )
+    }
+
+    #[test]
+    fn features_follow_negotiated_version() {
+        let older = NowProtocolVersion::new(3, 1, 0);
+        let (mut client, mut server) = connections(
+            NowProtocolVersionRange::default(),
+            NowProtocolVersionRange::new(NowProtocolVersion::new(3, 0, 0), older),
+        );
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        while client.has_pending_transmit() || server.has_pending_transmit() {
+            while let Some(frame) = client.poll_transmit() {
+                server.feed_bytes(&frame, Instant::now()).unwrap();
+            }
+            while let Some(frame) = server.poll_transmit() {
+                client.feed_bytes(&frame, Instant::now()).unwrap();
+            }
+        }
+
+        assert_eq!(client.get_state(), ShareeState::Active);
+        assert_eq!(client.get_sharee().negotiated_version(), Some(older));
+        assert_eq!(server.get_sharee().negotiated_version(), Some(older));
+        assert_eq!(client.get_sharee().encode_ctx().version, older);
+        assert_eq!(client.get_sharee().get_channels_ctx().decode_ctx().version, older);
+
+        // tunnel channel requires a later version
+        let channels: Vec<&ChannelName> = client.get_sharee().get_channels_ctx().channels().map(|c| c.1).collect();
+        assert_eq!(channels, vec![&ChannelName::Chat]);
+    }
+
+    #[test]
+    fn incompatible_versions_rejected() {
+        let (mut client, mut server) = connections(
+            NowProtocolVersionRange::exactly(NowProtocolVersion::new(3, 0, 0)),
+            NowProtocolVersionRange::exactly(NowProtocolVersion::CURRENT),
+        );
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        let frame = client.poll_transmit().unwrap();
+        assert!(server.feed_bytes(&frame, Instant::now()).is_err());
+        assert!(server.is_terminated());
+
+        // the server answers before failing
+        let frame = server.poll_transmit().unwrap();
+        assert!(client.feed_bytes(&frame, Instant::now()).is_err());
+        assert!(client.is_terminated());
+        assert_eq!(client.get_sharee().negotiated_version(), None);
+    }
+
+    struct Recorder {
+        incoming: Rc<RefCell<Vec<MessageType>>>,
+    }
+
+    impl MessageHook for Recorder {
+        fn on_incoming(&mut self, body: &NowBody<'_>) -> HookAction {
+            if let NowBody::Message(msg) = body {
+                self.incoming.borrow_mut().push(msg.get_type());
+            }
+            HookAction::Forward
+        }
+
+        fn on_outgoing(&mut self, body: &NowBody<'_>) -> HookAction {
+            match body {
+                NowBody::Message(NowMessage::Terminate(_)) => HookAction::Drop,
+                _ => HookAction::Forward,
+            }
+        }
+    }
+
+    #[test]
+    fn hooks_on_both_paths() {
+        let (mut client, mut server) =
+            connections(NowProtocolVersionRange::default(), NowProtocolVersionRange::default());
+        let incoming = Rc::new(RefCell::new(Vec::new()));
+        server.get_sharee_mut().add_hook(Recorder {
+            incoming: Rc::clone(&incoming),
+        });
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        while client.has_pending_transmit() || server.has_pending_transmit() {
+            while let Some(frame) = client.poll_transmit() {
+                server.feed_bytes(&frame, Instant::now()).unwrap();
+            }
+            while let Some(frame) = server.poll_transmit() {
+                client.feed_bytes(&frame, Instant::now()).unwrap();
+            }
+        }
+        assert_eq!(server.get_state(), ShareeState::Active);
+        assert_eq!(incoming.borrow().first(), Some(&MessageType::Handshake));
+
+        // termination sent by the application is dropped
+        server
+            .send(crate::message::NowTerminateMsg::default(), Instant::now())
+            .unwrap();
+        assert!(!server.has_pending_transmit());
+        let frame = NowPacket::from_message(crate::message::NowTerminateMsg::default())
+            .encode()
+            .unwrap();
+        server.send_encoded(frame, Instant::now()).unwrap();
+        assert!(!server.has_pending_transmit());
+    }
+
+    struct ChatProbe {
+        received: Rc<Cell<usize>>,
+        closed: Rc<Cell<bool>>,
+    }
+
+    impl ChannelHandler for ChatProbe {
+        type Message<'a> = NowChatMsg;
+
+        fn on_message(&mut self, _: &NowChatMsg) -> Result<(), ProtoError> {
+            self.received.set(self.received.get() + 1);
+            Ok(())
+        }
+
+        fn on_close(&mut self) {
+            self.closed.set(true);
+        }
+
+        fn poll_outgoing(&mut self) -> Option<NowChatMsg> {
+            None
+        }
+    }
+
+    fn exchange<A, B, C, D>(client: &mut NowConnection<A, B>, server: &mut NowConnection<C, D>)
+    where
+        A: ConnectionSM,
+        B: ShareeCallbackTrait,
+        C: ConnectionSM,
+        D: ShareeCallbackTrait,
+    {
+        while client.has_pending_transmit() || server.has_pending_transmit() {
+            while let Some(frame) = client.poll_transmit() {
+                server.feed_bytes(&frame, Instant::now()).unwrap();
+            }
+            while let Some(frame) = server.poll_transmit() {
+                client.feed_bytes(&frame, Instant::now()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn channels_opened_and_closed_once_active() {
+        let received = Rc::new(Cell::new(0));
+        let server_closed = Rc::new(Cell::new(false));
+        let client_closed = Rc::new(Cell::new(false));
+
+        let mut client_channels = ChannelsManager::new();
+        client_channels.register(
+            ChannelName::Chat,
+            ChatProbe {
+                received: Rc::new(Cell::new(0)),
+                closed: Rc::clone(&client_closed),
+            },
+        );
+        let mut server_channels = ChannelsManager::new();
+        server_channels.register(
+            ChannelName::Chat,
+            ChatProbe {
+                received: Rc::clone(&received),
+                closed: Rc::clone(&server_closed),
+            },
+        );
+
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .build();
+        let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![ChannelName::Chat])
+            .build();
+        let mut client = NowConnection::new(Sharee::new(client_seq, client_channels, DummyShareeCallback));
+        let mut server = NowConnection::new(Sharee::new(server_seq, server_channels, DummyShareeCallback));
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(client.get_state(), ShareeState::Active);
+        assert_eq!(client.get_sharee().channel_state(&ChannelName::Chat), None);
+
+        let request = client.get_sharee_mut().open_channel(ChannelName::Chat).unwrap();
+        client.send(request, Instant::now()).unwrap();
+        assert_eq!(
+            client.get_sharee().channel_state(&ChannelName::Chat),
+            Some(ChannelLifecycleState::Opening)
+        );
+        exchange(&mut client, &mut server);
+
+        let id = match client.get_sharee().channel_state(&ChannelName::Chat) {
+            Some(ChannelLifecycleState::Open { id }) => id,
+            state => panic!("unexpected state: {:?}", state),
+        };
+        assert_eq!(
+            server
+                .get_sharee()
+                .get_channels_ctx()
+                .get_id_by_channel(&ChannelName::Chat),
+            Some(id)
+        );
+        assert_eq!(
+            client.get_sharee_mut().poll_channel_event(),
+            Some(ChannelLifecycleEvent::Opened {
+                name: ChannelName::Chat,
+                id
+            })
+        );
+
+        client
+            .send(NowPacket::from_virt_channel(NowChatPokeMsg::new(0), id), Instant::now())
+            .unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(received.get(), 1);
+
+        // closed by the server: handlers of both sides are notified
+        let request = server.get_sharee_mut().close_channel(ChannelName::Chat).unwrap();
+        server.send(request, Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert!(client_closed.get());
+        assert!(server_closed.get());
+        assert_eq!(
+            client.get_sharee().channel_state(&ChannelName::Chat),
+            Some(ChannelLifecycleState::Closed(ChannelCloseReason::Peer))
+        );
+        assert_eq!(
+            server.get_sharee().channel_state(&ChannelName::Chat),
+            Some(ChannelLifecycleState::Closed(ChannelCloseReason::Local))
+        );
+        assert!(client.get_sharee().get_channels_ctx().channels().next().is_none());
+
+        // pending open request is closed along with the session
+        let request = client.get_sharee_mut().open_channel(ChannelName::Chat).unwrap();
+        client.send(request, Instant::now()).unwrap();
+        client
+            .feed_bytes(
+                &NowPacket::from_message(crate::message::NowTerminateMsg::default())
+                    .encode()
+                    .unwrap(),
+                Instant::now(),
+            )
+            .unwrap();
+        assert_eq!(
+            client.get_sharee().channel_state(&ChannelName::Chat),
+            Some(ChannelLifecycleState::Closed(ChannelCloseReason::SessionTerminated))
+        );
+    }
+
+    #[test]
+    fn stalled_channel_reset_without_ending_session() {
+        let client_closed = Rc::new(Cell::new(false));
+        let mut client_channels = ChannelsManager::new().liveness(ChannelLiveness::new(Duration::from_secs(10)));
+        client_channels.register(
+            ChannelName::Chat,
+            ChatProbe {
+                received: Rc::new(Cell::new(0)),
+                closed: Rc::clone(&client_closed),
+            },
+        );
+        let mut server_channels = ChannelsManager::new();
+        server_channels.register(
+            ChannelName::Chat,
+            ChatProbe {
+                received: Rc::new(Cell::new(0)),
+                closed: Rc::new(Cell::new(false)),
+            },
+        );
+
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .build();
+        let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![ChannelName::Chat])
+            .build();
+        let mut client = NowConnection::new(Sharee::new(client_seq, client_channels, DummyShareeCallback));
+        let mut server = NowConnection::new(Sharee::new(server_seq, server_channels, DummyShareeCallback));
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        let request = client.get_sharee_mut().open_channel(ChannelName::Chat).unwrap();
+        client.send(request, Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        let id = match client.get_sharee_mut().poll_channel_event() {
+            Some(ChannelLifecycleEvent::Opened { id, .. }) => id,
+            event => panic!("unexpected event: {:?}", event),
+        };
+
+        // the poke is left unanswered by the server
+        let t0 = Instant::now();
+        client
+            .send(NowPacket::from_virt_channel(NowChatPokeMsg::new(0), id), t0)
+            .unwrap();
+        client.update(t0 + Duration::from_secs(9)).unwrap();
+        assert_eq!(client.get_sharee_mut().poll_channel_event(), None);
+        client.update(t0 + Duration::from_secs(10)).unwrap();
+        match client.get_sharee_mut().poll_channel_event() {
+            Some(ChannelLifecycleEvent::Stalled(stalled)) => assert_eq!(stalled.name, ChannelName::Chat),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(client
+            .get_sharee()
+            .get_channels_manager()
+            .get_liveness()
+            .unwrap()
+            .is_stalled(&ChannelName::Chat));
+        client.update(t0 + Duration::from_secs(20)).unwrap();
+        assert_eq!(client.get_sharee_mut().poll_channel_event(), None);
+
+        let request = client.get_sharee_mut().reset_channel(ChannelName::Chat).unwrap();
+        client.send(request, Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert!(client_closed.get());
+        assert_eq!(
+            client.get_sharee_mut().poll_channel_event(),
+            Some(ChannelLifecycleEvent::Closed {
+                name: ChannelName::Chat,
+                reason: ChannelCloseReason::Local
+            })
+        );
+        assert!(matches!(
+            client.get_sharee_mut().poll_channel_event(),
+            Some(ChannelLifecycleEvent::Opened {
+                name: ChannelName::Chat,
+                ..
+            })
+        ));
+        assert!(client
+            .get_sharee()
+            .get_channels_ctx()
+            .get_id_by_channel(&ChannelName::Chat)
+            .is_some());
+        assert!(server
+            .get_sharee()
+            .get_channels_ctx()
+            .get_id_by_channel(&ChannelName::Chat)
+            .is_some());
+        assert!(!client
+            .get_sharee()
+            .get_channels_manager()
+            .get_liveness()
+            .unwrap()
+            .is_stalled(&ChannelName::Chat));
+        assert_eq!(client.get_state(), ShareeState::Active);
+        assert_eq!(server.get_state(), ShareeState::Active);
+    }
+
+    #[test]
+    fn channel_refused_by_peer() {
+        let mut client_channels = ChannelsManager::new();
+        client_channels.register(
+            ChannelName::Chat,
+            ChatProbe {
+                received: Rc::new(Cell::new(0)),
+                closed: Rc::new(Cell::new(false)),
+            },
+        );
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .build();
+        let (_, mut server) = connections(NowProtocolVersionRange::default(), NowProtocolVersionRange::default());
+        let mut client = NowConnection::new(Sharee::new(client_seq, client_channels, DummyShareeCallback));
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+
+        let request = client.get_sharee_mut().open_channel(ChannelName::Chat).unwrap();
+        client.send(request, Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(
+            client.get_sharee().channel_state(&ChannelName::Chat),
+            Some(ChannelLifecycleState::Closed(ChannelCloseReason::Refused))
+        );
+        assert!(client.get_sharee().get_channels_ctx().channels().next().is_none());
+    }
+
+    #[test]
+    fn link_failure_interrupts_session() {
+        let (mut client, mut server) =
+            connections(NowProtocolVersionRange::default(), NowProtocolVersionRange::default());
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(client.get_state(), ShareeState::Active);
+
+        let reset = ProtoError::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+        assert_eq!(client.on_error(&reset), vec![ConnectionEvent::Interrupted]);
+        assert_eq!(client.get_sharee().failure(), Some(ErrorClass::Transport));
+        assert!(client.update(Instant::now()).unwrap().is_empty());
+
+        // session resumed over a new link
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .build();
+        let (_, mut server) = connections(NowProtocolVersionRange::default(), NowProtocolVersionRange::default());
+        client.reconnect(client_seq, Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(client.get_state(), ShareeState::Active);
+        assert_eq!(client.get_sharee().failure(), None);
+
+        let terminated = ProtoError::from(ProtoErrorKind::UnexpectedMessage(MessageType::Terminate));
+        assert_eq!(server.on_error(&terminated), vec![ConnectionEvent::Terminated]);
+        assert_eq!(server.get_sharee().failure(), Some(ErrorClass::PeerTerminated));
+    }
+
+    #[test]
+    fn custom_channel_payloads_exchanged() {
+        let name = ChannelName::custom("AcmeTelemetry");
+        let (client_sm, client_handle) = CustomChannelSM::new(name.clone());
+        let (server_sm, server_handle) = CustomChannelSM::new(name.clone());
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .channels_to_open(vec![name.clone()])
+            .build();
+        let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![name.clone()])
+            .build();
+        let mut client = NowConnection::new(Sharee::new(
+            client_seq,
+            ChannelsManager::new().with_sm(client_sm),
+            DummyShareeCallback,
+        ));
+        let mut server = NowConnection::new(Sharee::new(
+            server_seq,
+            ChannelsManager::new().with_sm(server_sm),
+            DummyShareeCallback,
+        ));
+
+        // queued until the channel is opened
+        client_handle.send(&b"early"[..]);
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert!(client_handle.is_open());
+        assert!(server_handle.is_open());
+        assert_eq!(server_handle.recv(), Some(b"early".to_vec()));
+
+        server_handle.send(vec![0, 1, 2]);
+        server.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(client_handle.recv(), Some(vec![0, 1, 2]));
+        assert_eq!(client_handle.recv(), None);
+        assert_eq!(server_handle.pending_outgoing(), 0);
+    }
+
+    #[test]
+    fn sender_limited_to_credits_granted() {
+        let name = ChannelName::custom("AcmeBulk");
+        let (client_sm, client_handle) = CustomChannelSM::new(name.clone());
+        let (server_sm, server_handle) = CustomChannelSM::new(name.clone());
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .channels_to_open(vec![name.clone()])
+            .build();
+        let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![name.clone()])
+            .build();
+        let mut client = NowConnection::new(Sharee::new(
+            client_seq,
+            ChannelsManager::new().with_sm(client_sm),
+            DummyShareeCallback,
+        ));
+        let mut server = NowConnection::new(Sharee::new(
+            server_seq,
+            ChannelsManager::new()
+                .with_sm(server_sm)
+                .flow_control(ChannelFlowControl::new().window(name.clone(), 64)),
+            DummyShareeCallback,
+        ));
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        let credits = |client: &NowConnection<_, _>| {
+            client
+                .get_sharee()
+                .get_channels_manager()
+                .get_flow_control()
+                .credits(&name)
+        };
+        assert_eq!(credits(&client), Some(64));
+
+        // two payloads fit in the window, the third one waits for the server to grant more
+        for _ in 0..3 {
+            client_handle.send(vec![0; 24]);
+        }
+        client.update(Instant::now()).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = client.poll_transmit() {
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), 2);
+        assert_eq!(client_handle.pending_outgoing(), 0);
+        assert_eq!(credits(&client), Some(16));
+
+        for frame in frames {
+            server.feed_bytes(&frame, Instant::now()).unwrap();
+        }
+        exchange(&mut client, &mut server);
+        let mut received = 0;
+        while server_handle.recv().is_some() {
+            received += 1;
+        }
+        assert_eq!(received, 3);
+        assert_eq!(credits(&client), Some(40));
+    }
+
+    #[test]
+    fn channels_registered_and_opened_when_needed() {
+        let name = ChannelName::custom("AcmeTelemetry");
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .build();
+        let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .build();
+        let mut client = NowConnection::new(Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback));
+        let mut server = NowConnection::new(Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback));
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(client.get_state(), ShareeState::Active);
+
+        // nothing available on the server yet
+        let request = client.get_sharee_mut().request_channel_list().unwrap();
+        client.send(request, Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(client.get_sharee().peer_channels(), Some(&[][..]));
+
+        let (server_sm, server_handle) = CustomChannelSM::new(name.clone());
+        server.get_sharee_mut().add_channel_sm(server_sm).unwrap();
+        let announcement = server.get_sharee_mut().announce_channels().unwrap();
+        server.send(announcement, Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(client.get_sharee().peer_channels(), Some(&[name.clone()][..]));
+
+        let (client_sm, client_handle) = CustomChannelSM::new(name.clone());
+        client.get_sharee_mut().add_channel_sm(client_sm).unwrap();
+        let request = client.get_sharee_mut().open_channel(name.clone()).unwrap();
+        client.send(request, Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert!(matches!(
+            client.get_sharee().channel_state(&name),
+            Some(ChannelLifecycleState::Open { .. })
+        ));
+
+        client_handle.send(vec![1, 2, 3]);
+        client.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(server_handle.recv(), Some(vec![1, 2, 3]));
+
+        let (replacement, _) = CustomChannelSM::new(name);
+        assert!(client.get_sharee_mut().add_channel_sm(replacement).is_err());
+    }
+
+    #[test]
+    fn tunnel_streams_carry_bytes_both_ways() {
+        let (client_sm, client_tunnel) = TunnelChannelSM::new();
+        let (server_sm, server_tunnel) = TunnelChannelSM::new();
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .channels_to_open(vec![ChannelName::Tunnel])
+            .build();
+        let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![ChannelName::Tunnel])
+            .build();
+        let mut client = NowConnection::new(Sharee::new(
+            client_seq,
+            ChannelsManager::new().with_sm(client_sm),
+            DummyShareeCallback,
+        ));
+        let mut server = NowConnection::new(Sharee::new(
+            server_seq,
+            ChannelsManager::new().with_sm(server_sm),
+            DummyShareeCallback,
+        ));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        // written before the channel is opened, larger than a message
+        let client_stream = client_tunnel.open_stream();
+        let payload: Vec<u8> = (0..TunnelChannel::MAX_CHUNK_SIZE + 100).map(|i| i as u8).collect();
+        let mut written = 0;
+        while written < payload.len() {
+            match client_stream.poll_write_bytes(&mut cx, &payload[written..]) {
+                Poll::Ready(len) => written += len.unwrap(),
+                Poll::Pending => panic!("write unexpectedly pending"),
+            }
+        }
+        assert!(server_tunnel.try_accept().is_none());
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert!(client_tunnel.is_open());
+        assert_eq!(client_stream.poll_flush_bytes(&mut cx), Poll::Ready(()));
+
+        let server_stream = server_tunnel.try_accept().unwrap();
+        assert!(!server_stream.is_opened_locally());
+        assert_eq!(server_stream.id(), client_stream.id());
+        assert_eq!(server_stream.available(), payload.len());
+        let mut received = vec![0; payload.len()];
+        assert_eq!(
+            server_stream.poll_read_bytes(&mut cx, &mut received),
+            Poll::Ready(payload.len())
+        );
+        assert_eq!(received, payload);
+        let mut buf = [0; 16];
+        assert_eq!(server_stream.poll_read_bytes(&mut cx, &mut buf), Poll::Pending);
+
+        // answer, then half-close of the server side
+        assert!(server_stream.poll_write_bytes(&mut cx, b"pong").is_ready());
+        server_stream.shutdown_write();
+        assert!(matches!(
+            server_stream.poll_write_bytes(&mut cx, b"late"),
+            Poll::Ready(Err(_))
+        ));
+        server.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(client_stream.poll_read_bytes(&mut cx, &mut buf), Poll::Ready(4));
+        assert_eq!(&buf[..4], b"pong");
+        assert_eq!(client_stream.poll_read_bytes(&mut cx, &mut buf), Poll::Ready(0));
+
+        // client side still writable until dropped
+        assert!(client_stream.poll_write_bytes(&mut cx, b"bye").is_ready());
+        drop(client_stream);
+        client.update(Instant::now()).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(server_stream.poll_read_bytes(&mut cx, &mut buf), Poll::Ready(3));
+        assert_eq!(server_stream.poll_read_bytes(&mut cx, &mut buf), Poll::Ready(0));
+    }
+}
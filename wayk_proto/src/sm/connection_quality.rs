@@ -0,0 +1,439 @@
+use crate::{
+    message::{NowCapset, NowNetworkKeepAliveRspMsg, NowNetworkMsg, NowNetworkProbeReqMsg, NowNetworkProbeRspMsg},
+    sm::{KeepAliveTracker, RttEstimator},
+};
+use alloc::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum LinkQuality {
+    Good,
+    Degraded,
+    Poor,
+    Lost,
+}
+
+pub trait ConnectionQualityCallbackTrait {
+    fn on_quality_changed(&mut self, previous: LinkQuality, current: LinkQuality) {
+        #![allow(unused_variables)]
+    }
+}
+
+sa::assert_obj_safe!(ConnectionQualityCallbackTrait);
+
+pub struct DummyConnectionQualityCallback;
+impl ConnectionQualityCallbackTrait for DummyConnectionQualityCallback {}
+
+/// Thresholds used to classify the link.
+///
+/// A level is entered as soon as the smoothed RTT reaches its threshold, but
+/// it is left only once the smoothed RTT went below that threshold minus `hysteresis`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityThresholds {
+    pub degraded_rtt: Duration,
+    pub poor_rtt: Duration,
+    pub hysteresis: Duration,
+    pub lost_after: Duration,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        Self {
+            degraded_rtt: Duration::from_millis(150),
+            poor_rtt: Duration::from_millis(400),
+            hysteresis: Duration::from_millis(30),
+            lost_after: Duration::from_secs(10),
+        }
+    }
+}
+
+impl QualityThresholds {
+    pub fn degraded_rtt(self, degraded_rtt: Duration) -> Self {
+        Self { degraded_rtt, ..self }
+    }
+
+    pub fn poor_rtt(self, poor_rtt: Duration) -> Self {
+        Self { poor_rtt, ..self }
+    }
+
+    pub fn hysteresis(self, hysteresis: Duration) -> Self {
+        Self { hysteresis, ..self }
+    }
+
+    pub fn lost_after(self, lost_after: Duration) -> Self {
+        Self { lost_after, ..self }
+    }
+
+    fn classify(&self, current: LinkQuality, rtt: Duration) -> LinkQuality {
+        let below = |threshold: Duration| rtt + self.hysteresis < threshold;
+
+        match current {
+            LinkQuality::Good | LinkQuality::Lost => {
+                if rtt >= self.poor_rtt {
+                    LinkQuality::Poor
+                } else if rtt >= self.degraded_rtt {
+                    LinkQuality::Degraded
+                } else {
+                    LinkQuality::Good
+                }
+            }
+            LinkQuality::Degraded => {
+                if rtt >= self.poor_rtt {
+                    LinkQuality::Poor
+                } else if below(self.degraded_rtt) {
+                    LinkQuality::Good
+                } else {
+                    LinkQuality::Degraded
+                }
+            }
+            LinkQuality::Poor => {
+                if below(self.degraded_rtt) {
+                    LinkQuality::Good
+                } else if below(self.poor_rtt) {
+                    LinkQuality::Degraded
+                } else {
+                    LinkQuality::Poor
+                }
+            }
+        }
+    }
+}
+
+/// Classifies the connection link from keep-alive and network probe round trips.
+///
+/// When the network probe capability isn't negotiated, the RTT is estimated
+/// from keep-alive responses only.
+pub struct ConnectionQuality<UserCallback> {
+    thresholds: QualityThresholds,
+    quality: LinkQuality,
+    keep_alive: KeepAliveTracker,
+    rtt: RttEstimator,
+    epoch: Instant,
+    probe_interval: Option<Duration>,
+    probe_last_sent: Option<Instant>,
+    probe_sequence_id: u16,
+    probe_pending: BTreeMap<u16, Instant>,
+    user_callback: UserCallback,
+}
+
+impl<UserCallback> ConnectionQuality<UserCallback>
+where
+    UserCallback: ConnectionQualityCallbackTrait,
+{
+    pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(2);
+    pub const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(
+        thresholds: QualityThresholds,
+        keep_alive_interval: Duration,
+        user_callback: UserCallback,
+        now: Instant,
+    ) -> Self {
+        Self {
+            thresholds,
+            quality: LinkQuality::Good,
+            keep_alive: KeepAliveTracker::new(keep_alive_interval, now),
+            rtt: RttEstimator::new(),
+            epoch: now,
+            probe_interval: None,
+            probe_last_sent: None,
+            probe_sequence_id: 0,
+            probe_pending: BTreeMap::new(),
+            user_callback,
+        }
+    }
+
+    /// Enables RTT measurement through network probes (requires the probe capability on both sides).
+    pub fn enable_probe(&mut self, probe_interval: Duration) {
+        self.probe_interval = Some(probe_interval);
+    }
+
+    pub fn disable_probe(&mut self) {
+        self.probe_interval = None;
+        self.probe_pending.clear();
+    }
+
+    /// Enables network probes if the peer advertised the probe capability.
+    pub fn configure_from_capabilities(&mut self, peer_capabilities: &[NowCapset<'_>]) {
+        let probe_supported = peer_capabilities.iter().any(|capset| match capset {
+            NowCapset::Network(capset) => capset.flags.probe(),
+            _ => false,
+        });
+
+        if probe_supported {
+            self.enable_probe(Self::DEFAULT_PROBE_INTERVAL);
+        } else {
+            self.disable_probe();
+        }
+    }
+
+    pub fn is_probe_enabled(&self) -> bool {
+        self.probe_interval.is_some()
+    }
+
+    pub fn quality(&self) -> LinkQuality {
+        self.quality
+    }
+
+    pub fn thresholds(&self) -> &QualityThresholds {
+        &self.thresholds
+    }
+
+    /// New thresholds are taken into account on next evaluation.
+    pub fn set_thresholds(&mut self, thresholds: QualityThresholds) {
+        self.thresholds = thresholds;
+    }
+
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    pub fn keep_alive(&self) -> &KeepAliveTracker {
+        &self.keep_alive
+    }
+
+    /// Instant at which `poll` should be called next.
+    pub fn next_deadline(&self) -> Instant {
+        let mut deadline = self.keep_alive.next_deadline();
+
+        if self.quality != LinkQuality::Lost {
+            deadline = deadline.min(self.keep_alive.last_received() + self.thresholds.lost_after);
+        }
+
+        if let Some(interval) = self.probe_interval {
+            if let Some(last_sent) = self.probe_last_sent {
+                deadline = deadline.min(last_sent + interval);
+            }
+        }
+
+        deadline
+    }
+
+    /// Records that a packet was received from the peer.
+    pub fn on_activity(&mut self, now: Instant) {
+        self.keep_alive.on_activity(now);
+        if self.quality == LinkQuality::Lost {
+            let recovered = match self.rtt.smoothed() {
+                Some(rtt) => self.thresholds.classify(LinkQuality::Lost, rtt),
+                None => LinkQuality::Good,
+            };
+            self.__set_quality(recovered);
+        }
+    }
+
+    /// Checks peer liveness and returns the next network message to send, if any.
+    pub fn poll(&mut self, now: Instant) -> Option<NowNetworkMsg> {
+        if self.keep_alive.silence(now) >= self.thresholds.lost_after {
+            self.__set_quality(LinkQuality::Lost);
+        }
+
+        if let Some(req) = self.keep_alive.poll(now) {
+            return Some(req.into());
+        }
+
+        if let Some(interval) = self.probe_interval {
+            let due = match self.probe_last_sent {
+                Some(last_sent) => now >= last_sent + interval,
+                None => true,
+            };
+
+            if due {
+                let sequence_id = self.probe_sequence_id;
+                self.probe_sequence_id = self.probe_sequence_id.wrapping_add(1);
+                self.probe_last_sent = Some(now);
+                self.probe_pending
+                    .retain(|_, sent| now.saturating_duration_since(*sent) < interval * 16);
+                self.probe_pending.insert(sequence_id, now);
+                let timestamp = now.saturating_duration_since(self.epoch).as_millis() as u32;
+                return Some(NowNetworkProbeReqMsg::new(sequence_id, timestamp).into());
+            }
+        }
+
+        None
+    }
+
+    /// Handles a network message from the peer. An answer may be returned.
+    pub fn update_with_network_msg(&mut self, msg: &NowNetworkMsg, now: Instant) -> Option<NowNetworkMsg> {
+        self.on_activity(now);
+
+        match msg {
+            NowNetworkMsg::KeepAliveReq(req) => Some(NowNetworkKeepAliveRspMsg::answer(req).into()),
+            NowNetworkMsg::KeepAliveRsp(rsp) => {
+                let sample = self.keep_alive.on_keep_alive_rsp(rsp, now);
+                if !self.is_probe_enabled() {
+                    if let Some(sample) = sample {
+                        self.__on_rtt_sample(sample);
+                    }
+                }
+                None
+            }
+            NowNetworkMsg::ProbeReq(req) => Some(NowNetworkProbeRspMsg::answer(req).into()),
+            NowNetworkMsg::ProbeRsp(rsp) => {
+                if let Some(sent) = self.probe_pending.remove(&rsp.sequence_id) {
+                    self.__on_rtt_sample(now.saturating_duration_since(sent));
+                }
+                None
+            }
+        }
+    }
+
+    fn __on_rtt_sample(&mut self, sample: Duration) {
+        self.rtt.update(sample);
+        if self.quality != LinkQuality::Lost {
+            let smoothed = self.rtt.smoothed().unwrap_or(sample);
+            let quality = self.thresholds.classify(self.quality, smoothed);
+            self.__set_quality(quality);
+        }
+    }
+
+    fn __set_quality(&mut self, quality: LinkQuality) {
+        if quality != self.quality {
+            let previous = self.quality;
+            self.quality = quality;
+            log::debug!("connection quality changed: {:?} -> {:?}", previous, quality);
+            self.user_callback.on_quality_changed(previous, quality);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{NetworkCapset, NetworkCapsetFlags};
+    use std::{cell::RefCell, rc::Rc};
+
+    type EventsRc = Rc<RefCell<Vec<(LinkQuality, LinkQuality)>>>;
+
+    struct RecordingCallback {
+        events: EventsRc,
+    }
+
+    impl ConnectionQualityCallbackTrait for RecordingCallback {
+        fn on_quality_changed(&mut self, previous: LinkQuality, current: LinkQuality) {
+            self.events.borrow_mut().push((previous, current));
+        }
+    }
+
+    fn build(now: Instant) -> (ConnectionQuality<RecordingCallback>, EventsRc) {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let quality = ConnectionQuality::new(
+            QualityThresholds::default()
+                .degraded_rtt(Duration::from_millis(150))
+                .poor_rtt(Duration::from_millis(400))
+                .hysteresis(Duration::from_millis(30))
+                .lost_after(Duration::from_secs(5)),
+            Duration::from_secs(1),
+            RecordingCallback {
+                events: Rc::clone(&events),
+            },
+            now,
+        );
+        (quality, events)
+    }
+
+    /// Runs one keep-alive exchange with the given round trip time, starting at `now`.
+    fn keep_alive_exchange(quality: &mut ConnectionQuality<RecordingCallback>, now: Instant, rtt_ms: u64) {
+        match quality.poll(now) {
+            Some(NowNetworkMsg::KeepAliveReq(req)) => {
+                let rsp = NowNetworkKeepAliveRspMsg::answer(&req).into();
+                let answer = quality.update_with_network_msg(&rsp, now + Duration::from_millis(rtt_ms));
+                assert!(answer.is_none());
+            }
+            other => panic!("expected a keep alive request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn latency_spike_without_flapping() {
+        let t0 = Instant::now();
+        let (mut quality, events) = build(t0);
+
+        let mut now = t0;
+        let mut exchange = |quality: &mut ConnectionQuality<RecordingCallback>, rtt_ms: u64| {
+            keep_alive_exchange(quality, now, rtt_ms);
+            now += Duration::from_secs(1);
+        };
+
+        for _ in 0..5 {
+            exchange(&mut quality, 50);
+        }
+        assert_eq!(quality.quality(), LinkQuality::Good);
+
+        // spike
+        for _ in 0..10 {
+            exchange(&mut quality, 300);
+        }
+        assert_eq!(quality.quality(), LinkQuality::Degraded);
+
+        // rtt oscillating around the degraded threshold, within the hysteresis band
+        for i in 0..20 {
+            exchange(&mut quality, if i % 2 == 0 { 160 } else { 135 });
+        }
+        assert_eq!(quality.quality(), LinkQuality::Degraded);
+
+        // recovery
+        for _ in 0..30 {
+            exchange(&mut quality, 40);
+        }
+        assert_eq!(quality.quality(), LinkQuality::Good);
+
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                (LinkQuality::Good, LinkQuality::Degraded),
+                (LinkQuality::Degraded, LinkQuality::Good)
+            ]
+        );
+    }
+
+    #[test]
+    fn silence_transitions_to_lost() {
+        let t0 = Instant::now();
+        let (mut quality, events) = build(t0);
+
+        keep_alive_exchange(&mut quality, t0, 20);
+        let last_heard = t0 + Duration::from_millis(20);
+
+        // keep-alive requests are still sent but never answered
+        let mut now = t0 + Duration::from_secs(1);
+        while now < last_heard + Duration::from_secs(5) {
+            quality.poll(now);
+            assert_eq!(quality.quality(), LinkQuality::Good);
+            now += Duration::from_millis(250);
+        }
+        assert_eq!(quality.next_deadline(), last_heard + Duration::from_secs(5));
+
+        quality.poll(last_heard + Duration::from_secs(5));
+        assert_eq!(quality.quality(), LinkQuality::Lost);
+
+        // no duplicated event while lost
+        quality.poll(last_heard + Duration::from_secs(8));
+        assert_eq!(*events.borrow(), vec![(LinkQuality::Good, LinkQuality::Lost)]);
+
+        quality.on_activity(last_heard + Duration::from_secs(9));
+        assert_eq!(quality.quality(), LinkQuality::Good);
+    }
+
+    #[test]
+    fn probe_used_when_negotiated() {
+        let t0 = Instant::now();
+        let (mut quality, _) = build(t0);
+        quality.configure_from_capabilities(&[NowCapset::Network(NetworkCapset::new(
+            NetworkCapsetFlags::new_empty().set_keep_alive().set_probe(),
+        ))]);
+        assert!(quality.is_probe_enabled());
+
+        // keep-alive round trips are ignored for RTT when probes are available
+        keep_alive_exchange(&mut quality, t0, 500);
+        assert!(quality.rtt().smoothed().is_none());
+
+        match quality.poll(t0) {
+            Some(NowNetworkMsg::ProbeReq(req)) => {
+                let rsp = NowNetworkProbeRspMsg::answer(&req).into();
+                quality.update_with_network_msg(&rsp, t0 + Duration::from_millis(30));
+            }
+            other => panic!("expected a probe request, got {:?}", other),
+        }
+        assert_eq!(quality.rtt().smoothed(), Some(Duration::from_millis(30)));
+    }
+}
@@ -0,0 +1,357 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        AccessControlCode, CurtainFlags, NowCapset, NowDesktopCurtainReqMsg, NowDesktopCurtainRspMsg, NowDesktopMsg,
+    },
+    sm::{LinkQuality, SessionGuard},
+};
+
+/// Technician side of the curtain mode (host input blocking and display blanking).
+///
+/// Requests are only emitted if the peer advertised the curtain capability and
+/// granted the interact access right.
+#[derive(Debug, Clone)]
+pub struct CurtainController {
+    negotiated: bool,
+    interact_allowed: bool,
+    requested: CurtainFlags,
+    applied: CurtainFlags,
+}
+
+impl Default for CurtainController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CurtainController {
+    pub fn new() -> Self {
+        Self {
+            negotiated: false,
+            interact_allowed: false,
+            requested: CurtainFlags::new_empty(),
+            applied: CurtainFlags::new_empty(),
+        }
+    }
+
+    pub fn configure_from_capabilities(&mut self, peer_capabilities: &[NowCapset<'_>]) {
+        self.negotiated = false;
+        self.interact_allowed = false;
+
+        for capset in peer_capabilities {
+            match capset {
+                NowCapset::Desktop(capset) => self.negotiated = capset.flags.curtain(),
+                NowCapset::Access(capset) => {
+                    self.interact_allowed = capset
+                        .access_controls
+                        .iter()
+                        .any(|def| def.code == AccessControlCode::Interact && def.flags.allowed());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.negotiated && self.interact_allowed
+    }
+
+    /// Items currently applied on the host as far as we know.
+    pub fn applied(&self) -> CurtainFlags {
+        self.applied
+    }
+
+    pub fn request(&mut self, flags: CurtainFlags) -> Result<NowDesktopMsg, ProtoError> {
+        if !self.negotiated {
+            return ProtoError::new(ProtoErrorKind::CapabilityNotNegotiated("NowDesktop"))
+                .or_desc("peer doesn't support curtain mode");
+        }
+
+        if !self.interact_allowed {
+            return ProtoError::new(ProtoErrorKind::AccessDenied(AccessControlCode::Interact))
+                .or_desc("curtain mode requires the interact access right");
+        }
+
+        self.requested = flags;
+        Ok(NowDesktopCurtainReqMsg::new(flags).into())
+    }
+
+    /// Returns a release request if anything is applied or pending.
+    pub fn release(&mut self) -> Option<NowDesktopMsg> {
+        if self.requested.is_empty() && self.applied.is_empty() {
+            None
+        } else {
+            self.requested = CurtainFlags::new_empty();
+            Some(NowDesktopCurtainReqMsg::release().into())
+        }
+    }
+
+    /// Handles the host answer and returns the refused items.
+    pub fn on_curtain_rsp(&mut self, rsp: &NowDesktopCurtainRspMsg) -> CurtainFlags {
+        self.applied = rsp.applied;
+        let refused = rsp.refused();
+        if !refused.is_empty() {
+            log::warn!("curtain mode items refused by host: {:?}", refused);
+        }
+        refused
+    }
+
+    /// Host releases everything by itself on disconnection.
+    pub fn on_disconnect(&mut self) {
+        self.requested = CurtainFlags::new_empty();
+        self.applied = CurtainFlags::new_empty();
+    }
+}
+
+pub trait CurtainHostCallbackTrait {
+    /// Applies requested items and returns the ones effectively applied.
+    fn on_apply(&mut self, requested: CurtainFlags) -> CurtainFlags {
+        #![allow(unused_variables)]
+        CurtainFlags::new_empty()
+    }
+
+    fn on_release(&mut self, released: CurtainFlags) {
+        #![allow(unused_variables)]
+    }
+}
+
+sa::assert_obj_safe!(CurtainHostCallbackTrait);
+
+pub struct DummyCurtainHostCallback;
+impl CurtainHostCallbackTrait for DummyCurtainHostCallback {}
+
+/// Host side of the curtain mode.
+///
+/// Everything applied is released as soon as the session is disconnected or the
+/// link is considered lost so that a dropped session never leaves the host locked.
+pub struct CurtainHost<UserCallback> {
+    applied: CurtainFlags,
+    user_callback: UserCallback,
+}
+
+impl<UserCallback> CurtainHost<UserCallback>
+where
+    UserCallback: CurtainHostCallbackTrait,
+{
+    pub fn new(user_callback: UserCallback) -> Self {
+        Self {
+            applied: CurtainFlags::new_empty(),
+            user_callback,
+        }
+    }
+
+    pub fn applied(&self) -> CurtainFlags {
+        self.applied
+    }
+
+    pub fn update_with_desktop_msg(&mut self, msg: &NowDesktopMsg) -> Option<NowDesktopMsg> {
+        match msg {
+            NowDesktopMsg::CurtainReq(req) => {
+                let dropped = self.applied.difference(req.flags);
+                if !dropped.is_empty() {
+                    self.__release(dropped);
+                }
+
+                let missing = req.flags.difference(self.applied);
+                if !missing.is_empty() {
+                    // the callback can't apply more than what was requested
+                    let newly_applied = self.user_callback.on_apply(missing).value & missing.value;
+                    self.applied = self.applied.union(CurtainFlags::from(newly_applied));
+                }
+
+                Some(NowDesktopCurtainRspMsg::new(req.flags, self.applied).into())
+            }
+            _ => None,
+        }
+    }
+
+    /// Graceful or abrupt session end.
+    pub fn on_disconnect(&mut self) {
+        self.release_all();
+    }
+
+    pub fn on_quality_changed(&mut self, current: LinkQuality) {
+        if current == LinkQuality::Lost {
+            log::warn!("link lost: releasing curtain mode");
+            self.release_all();
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        if !self.applied.is_empty() {
+            let applied = self.applied;
+            self.__release(applied);
+        }
+    }
+
+    fn __release(&mut self, flags: CurtainFlags) {
+        self.applied = self.applied.difference(flags);
+        self.user_callback.on_release(flags);
+    }
+}
+
+impl<UserCallback> SessionGuard for CurtainHost<UserCallback>
+where
+    UserCallback: CurtainHostCallbackTrait,
+{
+    fn update_with_desktop_msg(&mut self, msg: &NowDesktopMsg) -> Option<NowDesktopMsg> {
+        CurtainHost::update_with_desktop_msg(self, msg)
+    }
+
+    fn on_disconnect(&mut self) {
+        CurtainHost::on_disconnect(self)
+    }
+
+    fn on_quality_changed(&mut self, current: LinkQuality) {
+        CurtainHost::on_quality_changed(self, current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{AccessCapset, AccessControlDef, DesktopCapset, DesktopCapsetFlags},
+        sm::{ConnectionQuality, ConnectionQualityCallbackTrait, QualityThresholds},
+    };
+    use std::{
+        cell::RefCell,
+        rc::Rc,
+        time::{Duration, Instant},
+    };
+
+    /// Host machine only able to block input.
+    #[derive(Default)]
+    struct HostState {
+        input_blocked: bool,
+        released: Vec<CurtainFlags>,
+    }
+
+    struct HostCallback(Rc<RefCell<HostState>>);
+
+    impl CurtainHostCallbackTrait for HostCallback {
+        fn on_apply(&mut self, requested: CurtainFlags) -> CurtainFlags {
+            let mut applied = CurtainFlags::new_empty();
+            if requested.block_input() {
+                self.0.borrow_mut().input_blocked = true;
+                applied.set_block_input();
+            }
+            applied
+        }
+
+        fn on_release(&mut self, released: CurtainFlags) {
+            let mut state = self.0.borrow_mut();
+            if released.block_input() {
+                state.input_blocked = false;
+            }
+            state.released.push(released);
+        }
+    }
+
+    fn host_capabilities(interact: bool) -> Vec<NowCapset<'static>> {
+        let interact = if interact {
+            AccessControlDef::new_allowed(AccessControlCode::Interact)
+        } else {
+            AccessControlDef::new_disabled(AccessControlCode::Interact)
+        };
+
+        vec![
+            NowCapset::Desktop(DesktopCapset::new(DesktopCapsetFlags::new_empty().set_curtain())),
+            NowCapset::Access(AccessCapset::new_with_access_controls(vec![
+                AccessControlDef::new_allowed(AccessControlCode::Viewing),
+                interact,
+            ])),
+        ]
+    }
+
+    #[test]
+    fn request_not_emitted_without_negotiation() {
+        let mut controller = CurtainController::new();
+        assert!(controller.request(CurtainFlags::new_empty().set_block_input()).is_err());
+
+        controller.configure_from_capabilities(&host_capabilities(false));
+        assert!(controller.request(CurtainFlags::new_empty().set_block_input()).is_err());
+
+        controller.configure_from_capabilities(&host_capabilities(true));
+        assert!(controller.request(CurtainFlags::new_empty().set_block_input()).is_ok());
+    }
+
+    #[test]
+    fn partial_refusal_round_trip() {
+        let state = Rc::new(RefCell::new(HostState::default()));
+        let mut host = CurtainHost::new(HostCallback(Rc::clone(&state)));
+        let mut controller = CurtainController::new();
+        controller.configure_from_capabilities(&host_capabilities(true));
+
+        let req = controller
+            .request(CurtainFlags::new_empty().set_block_input().set_blank_display())
+            .unwrap();
+        let rsp = match host.update_with_desktop_msg(&req) {
+            Some(NowDesktopMsg::CurtainRsp(rsp)) => rsp,
+            other => panic!("expected a curtain rsp, got {:?}", other),
+        };
+
+        let refused = controller.on_curtain_rsp(&rsp);
+        assert_eq!(refused, CurtainFlags::BLANK_DISPLAY);
+        assert_eq!(controller.applied(), CurtainFlags::BLOCK_INPUT);
+        assert!(state.borrow().input_blocked);
+
+        let release = controller.release().unwrap();
+        let rsp = match host.update_with_desktop_msg(&release) {
+            Some(NowDesktopMsg::CurtainRsp(rsp)) => rsp,
+            other => panic!("expected a curtain rsp, got {:?}", other),
+        };
+        controller.on_curtain_rsp(&rsp);
+        assert!(controller.applied().is_empty());
+        assert!(!state.borrow().input_blocked);
+        assert!(controller.release().is_none());
+    }
+
+    struct LinkMonitor(Rc<RefCell<Vec<LinkQuality>>>);
+
+    impl ConnectionQualityCallbackTrait for LinkMonitor {
+        fn on_quality_changed(&mut self, _: LinkQuality, current: LinkQuality) {
+            self.0.borrow_mut().push(current);
+        }
+    }
+
+    #[test]
+    fn automatic_release_on_connection_loss() {
+        let state = Rc::new(RefCell::new(HostState::default()));
+        let mut host = CurtainHost::new(HostCallback(Rc::clone(&state)));
+
+        let t0 = Instant::now();
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let mut quality = ConnectionQuality::new(
+            QualityThresholds::default().lost_after(Duration::from_secs(5)),
+            Duration::from_secs(1),
+            LinkMonitor(Rc::clone(&changes)),
+            t0,
+        );
+
+        host.update_with_desktop_msg(&NowDesktopCurtainReqMsg::block_input().into());
+        assert!(state.borrow().input_blocked);
+
+        // technician vanishes without terminating the session
+        let mut now = t0;
+        while now <= t0 + Duration::from_secs(6) {
+            quality.poll(now);
+            for current in changes.borrow_mut().drain(..) {
+                host.on_quality_changed(current);
+            }
+            now += Duration::from_millis(500);
+        }
+
+        assert_eq!(quality.quality(), LinkQuality::Lost);
+        assert!(!state.borrow().input_blocked);
+        assert!(host.applied().is_empty());
+        assert_eq!(
+            state.borrow().released,
+            vec![CurtainFlags::from(CurtainFlags::BLOCK_INPUT)]
+        );
+
+        // nothing left to release on disconnection
+        host.on_disconnect();
+        assert_eq!(state.borrow().released.len(), 1);
+    }
+}
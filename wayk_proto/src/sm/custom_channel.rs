@@ -0,0 +1,124 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{ChannelName, CustomVirtualChannel, NowVirtualChannel},
+    sm::{VirtChannelSMResult, VirtualChannelSM},
+};
+use alloc::collections::VecDeque;
+use std::{cell::RefCell, rc::Rc};
+
+#[derive(Debug, Default)]
+struct CustomChannelQueues {
+    opened: bool,
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: VecDeque<Vec<u8>>,
+}
+
+/// State machine of a channel defined by the application, exchanging opaque payloads.
+///
+/// The channel is negotiated like the standard ones: its name is to be given to the channels to open
+/// (client) or to the available channels (server). Payloads are exchanged through the
+/// [`CustomChannelHandle`](struct.CustomChannelHandle.html) returned along with the state machine.
+pub struct CustomChannelSM {
+    name: ChannelName,
+    queues: Rc<RefCell<CustomChannelQueues>>,
+}
+
+impl CustomChannelSM {
+    pub fn new(name: ChannelName) -> (Self, CustomChannelHandle) {
+        let queues = Rc::new(RefCell::new(CustomChannelQueues::default()));
+        let handle = CustomChannelHandle {
+            name: name.clone(),
+            queues: Rc::clone(&queues),
+        };
+        (Self { name, queues }, handle)
+    }
+}
+
+impl VirtualChannelSM for CustomChannelSM {
+    fn get_channel_name(&self) -> ChannelName {
+        self.name.clone()
+    }
+
+    fn is_terminated(&self) -> bool {
+        false
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        let queues = self.queues.borrow();
+        !queues.opened || queues.outgoing.is_empty()
+    }
+
+    fn update_without_chan_msg<'msg>(&mut self) -> VirtChannelSMResult<'msg> {
+        let payload = self
+            .queues
+            .borrow_mut()
+            .outgoing
+            .pop_front()
+            .chain(ProtoErrorKind::VirtualChannel(self.name.clone()))
+            .or_desc("unexpected call to `update_without_chan_msg` without payload to send")?;
+        Ok(Some(CustomVirtualChannel::new(self.name.clone(), payload).into()))
+    }
+
+    fn update_with_chan_msg<'msg: 'a, 'a>(
+        &mut self,
+        chan_msg: &'a NowVirtualChannel<'msg>,
+    ) -> VirtChannelSMResult<'msg> {
+        match chan_msg {
+            NowVirtualChannel::Custom(msg) if msg.name == self.name => {
+                self.queues.borrow_mut().incoming.push_back(msg.payload.to_vec());
+                Ok(None)
+            }
+            _ => ProtoError::new(ProtoErrorKind::VirtualChannel(self.name.clone()))
+                .or_else_desc(|| format!("received an unexpected message: {:?}", chan_msg)),
+        }
+    }
+
+    fn on_open(&mut self) {
+        log::trace!("custom channel {:?} opened", self.name);
+        self.queues.borrow_mut().opened = true;
+    }
+
+    /// Payloads not sent yet are dropped, the received ones are kept until taken.
+    fn on_close(&mut self) {
+        log::trace!("custom channel {:?} closed", self.name);
+        let mut queues = self.queues.borrow_mut();
+        queues.opened = false;
+        queues.outgoing.clear();
+    }
+}
+
+/// Application side of a [`CustomChannelSM`](struct.CustomChannelSM.html).
+///
+/// Payloads sent are queued until the sharee is updated with the channel opened: the sans-IO
+/// connection and the async client do so before waiting for the next packet.
+#[derive(Clone)]
+pub struct CustomChannelHandle {
+    name: ChannelName,
+    queues: Rc<RefCell<CustomChannelQueues>>,
+}
+
+impl CustomChannelHandle {
+    pub fn name(&self) -> &ChannelName {
+        &self.name
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.queues.borrow().opened
+    }
+
+    /// Queues `payload` to be sent over the channel. Payloads queued before the channel is opened
+    /// are sent once it is.
+    pub fn send<P: Into<Vec<u8>>>(&self, payload: P) {
+        self.queues.borrow_mut().outgoing.push_back(payload.into());
+    }
+
+    /// Oldest payload received and not taken yet.
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        self.queues.borrow_mut().incoming.pop_front()
+    }
+
+    /// Number of payloads queued to be sent.
+    pub fn pending_outgoing(&self) -> usize {
+        self.queues.borrow().outgoing.len()
+    }
+}
@@ -0,0 +1,747 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        ChannelName, DeviceAccessFlags, DeviceIoFunction, DeviceType, NowDeviceAnnounceMsg, NowDeviceAnnounceRspMsg,
+        NowDeviceIoCompletionMsg, NowDeviceIoRequestMsg, NowDeviceMsg, NowDeviceRemoveMsg, NowStatusCode, NowString64,
+        NowVirtualChannel,
+    },
+    sm::{VirtChannelSMResult, VirtualChannelSM},
+};
+use alloc::collections::{BTreeMap, VecDeque};
+use std::{cell::RefCell, rc::Rc};
+
+/// Device made available to the peer by a concrete redirection, such as a smartcard reader or a USB HID device.
+///
+/// Requests of the peer are answered as soon as they are received, failures being reported with their status.
+/// Operations not supported by the device default to `NotSupported`.
+pub trait RedirectedDevice {
+    fn device_type(&self) -> DeviceType;
+
+    fn name(&self) -> NowString64;
+
+    /// Description announced along with the device, in the format of its type.
+    fn description(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Returns the handle of the device opened.
+    fn open(&mut self, access: DeviceAccessFlags) -> Result<u32, NowStatusCode>;
+
+    fn close(&mut self, handle: u32) -> Result<(), NowStatusCode>;
+
+    #[allow(unused_variables)]
+    fn read(&mut self, handle: u32, offset: u64, length: u32) -> Result<Vec<u8>, NowStatusCode> {
+        Err(NowStatusCode::NotSupported)
+    }
+
+    /// Returns the number of bytes written.
+    #[allow(unused_variables)]
+    fn write(&mut self, handle: u32, offset: u64, data: &[u8]) -> Result<u32, NowStatusCode> {
+        Err(NowStatusCode::NotSupported)
+    }
+
+    #[allow(unused_variables)]
+    fn ioctl(&mut self, handle: u32, code: u32, input: &[u8], max_output_len: u32) -> Result<Vec<u8>, NowStatusCode> {
+        Err(NowStatusCode::NotSupported)
+    }
+}
+
+/// Device announced by the peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteDevice {
+    pub id: u32,
+    pub device_type: DeviceType,
+    pub name: String,
+    pub description: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    /// The peer made a device available, to be accepted or refused.
+    Announced(RemoteDevice),
+    /// The peer removed a device it announced, its pending requests being dropped.
+    Removed { device_id: u32 },
+    /// The peer accepted or refused a device announced locally. Refused devices are removed.
+    AnnounceAnswered { device_id: u32, status: NowStatusCode },
+    /// Request to a remote device completed. `value` and `data` depend on `function`,
+    /// see [`NowDeviceIoRequestMsg`](../message/struct.NowDeviceIoRequestMsg.html).
+    Completed {
+        device_id: u32,
+        request_id: u32,
+        function: DeviceIoFunction,
+        status: NowStatusCode,
+        value: u32,
+        data: Vec<u8>,
+    },
+}
+
+struct LocalDevice {
+    device: Box<dyn RedirectedDevice>,
+    accepted: bool,
+}
+
+#[derive(Debug)]
+struct RemoteDeviceState {
+    device: RemoteDevice,
+    accepted: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingRequest {
+    device_id: u32,
+    function: DeviceIoFunction,
+}
+
+#[derive(Default)]
+struct DeviceState {
+    opened: bool,
+    next_device_id: u32,
+    next_request_id: u32,
+    local: BTreeMap<u32, LocalDevice>,
+    remote: BTreeMap<u32, RemoteDeviceState>,
+    pending: BTreeMap<u32, PendingRequest>,
+    outgoing: VecDeque<NowDeviceMsg>,
+    events: VecDeque<DeviceEvent>,
+}
+
+impl DeviceState {
+    fn __announce(&mut self, device_id: u32) {
+        if let Some(local) = self.local.get(&device_id) {
+            let device = &local.device;
+            self.outgoing.push_back(
+                NowDeviceAnnounceMsg::new(device_id, device.device_type(), device.name(), device.description()).into(),
+            );
+        }
+    }
+
+    fn __drop_pending(&mut self, device_id: u32) {
+        self.pending.retain(|_, pending| pending.device_id != device_id);
+    }
+}
+
+/// State machine of the device redirection channel, generic over the kind of device redirected.
+///
+/// The peer owning a device announces it with
+/// [`DeviceRedirection::announce`](struct.DeviceRedirection.html#method.announce) and answers the
+/// requests of the other through its [`RedirectedDevice`](trait.RedirectedDevice.html). The other peer
+/// accepts the device, then opens it, reads, writes or controls it, and closes it: each request is
+/// completed by a [`DeviceEvent::Completed`](enum.DeviceEvent.html#variant.Completed) event. Either peer
+/// may own devices.
+pub struct DeviceRedirectionSM {
+    state: Rc<RefCell<DeviceState>>,
+}
+
+impl DeviceRedirectionSM {
+    pub fn new() -> (Self, DeviceRedirection) {
+        let state = Rc::new(RefCell::new(DeviceState::default()));
+        let redirection = DeviceRedirection {
+            state: Rc::clone(&state),
+        };
+        (Self { state }, redirection)
+    }
+
+    fn __on_message(&mut self, msg: &NowDeviceMsg) {
+        match msg {
+            NowDeviceMsg::Announce(msg) => {
+                log::trace!("device {} announced by peer", msg.device_id);
+                let device = RemoteDevice {
+                    id: msg.device_id,
+                    device_type: msg.device_type,
+                    name: msg.name.as_str().to_owned(),
+                    description: msg.data.0.clone(),
+                };
+                let mut state = self.state.borrow_mut();
+                state.__drop_pending(msg.device_id);
+                state.remote.insert(
+                    msg.device_id,
+                    RemoteDeviceState {
+                        device: device.clone(),
+                        accepted: false,
+                    },
+                );
+                state.events.push_back(DeviceEvent::Announced(device));
+            }
+            NowDeviceMsg::AnnounceRsp(msg) => {
+                let mut state = self.state.borrow_mut();
+                let accepted = msg.status == NowStatusCode::Success;
+                match state.local.get_mut(&msg.device_id) {
+                    Some(local) if accepted => local.accepted = true,
+                    Some(_) => {
+                        log::debug!("device {} refused by peer: {:?}", msg.device_id, msg.status);
+                        state.local.remove(&msg.device_id);
+                    }
+                    None => {
+                        log::warn!("answer for device {} not announced ignored", msg.device_id);
+                        return;
+                    }
+                }
+                state.events.push_back(DeviceEvent::AnnounceAnswered {
+                    device_id: msg.device_id,
+                    status: msg.status,
+                });
+            }
+            NowDeviceMsg::Remove(msg) => {
+                let mut state = self.state.borrow_mut();
+                if state.remote.remove(&msg.device_id).is_some() {
+                    log::trace!("device {} removed by peer", msg.device_id);
+                    state.__drop_pending(msg.device_id);
+                    state.events.push_back(DeviceEvent::Removed {
+                        device_id: msg.device_id,
+                    });
+                } else {
+                    log::warn!("removal of device {} not announced ignored", msg.device_id);
+                }
+            }
+            NowDeviceMsg::IoRequest(msg) => {
+                let completion = self.__on_io_request(msg);
+                self.state.borrow_mut().outgoing.push_back(completion.into());
+            }
+            NowDeviceMsg::IoCompletion(msg) => {
+                let mut state = self.state.borrow_mut();
+                match state.pending.remove(&msg.request_id) {
+                    Some(pending) => state.events.push_back(DeviceEvent::Completed {
+                        device_id: pending.device_id,
+                        request_id: msg.request_id,
+                        function: pending.function,
+                        status: msg.status,
+                        value: msg.value,
+                        data: msg.data.0.clone(),
+                    }),
+                    None => log::warn!("completion of unknown device request {} ignored", msg.request_id),
+                }
+            }
+        }
+    }
+
+    /// The device is taken out of the state while answering, so that it may use the redirection handle.
+    fn __on_io_request(&mut self, msg: &NowDeviceIoRequestMsg) -> NowDeviceIoCompletionMsg {
+        let local = self.state.borrow_mut().local.remove(&msg.device_id);
+        let mut local = match local {
+            Some(local) if local.accepted => local,
+            local => {
+                if let Some(local) = local {
+                    self.state.borrow_mut().local.insert(msg.device_id, local);
+                }
+                log::warn!("request {} for device {} not available", msg.request_id, msg.device_id);
+                return NowDeviceIoCompletionMsg::failure(msg.device_id, msg.request_id, NowStatusCode::NotFound);
+            }
+        };
+
+        let device = &mut local.device;
+        let result = match msg.function {
+            DeviceIoFunction::Open => device.open(msg.access()).map(|handle| (handle, Vec::new())),
+            DeviceIoFunction::Close => device.close(msg.handle).map(|()| (0, Vec::new())),
+            DeviceIoFunction::Read => device
+                .read(msg.handle, msg.offset, msg.length)
+                .and_then(|data| __check_output_len(data, msg.length)),
+            DeviceIoFunction::Write => device
+                .write(msg.handle, msg.offset, &msg.data.0)
+                .map(|written| (written, Vec::new())),
+            DeviceIoFunction::Ioctl => device
+                .ioctl(msg.handle, msg.code, &msg.data.0, msg.length)
+                .and_then(|output| __check_output_len(output, msg.length)),
+        };
+        self.state.borrow_mut().local.entry(msg.device_id).or_insert(local);
+
+        match result {
+            Ok((value, data)) => {
+                NowDeviceIoCompletionMsg::new(msg.device_id, msg.request_id, NowStatusCode::Success, value, data)
+            }
+            Err(status) => {
+                log::debug!(
+                    "{:?} request {} for device {} failed: {:?}",
+                    msg.function,
+                    msg.request_id,
+                    msg.device_id,
+                    status
+                );
+                NowDeviceIoCompletionMsg::failure(msg.device_id, msg.request_id, status)
+            }
+        }
+    }
+}
+
+fn __check_output_len(data: Vec<u8>, max_len: u32) -> Result<(u32, Vec<u8>), NowStatusCode> {
+    if data.len() > max_len as usize {
+        Err(NowStatusCode::InvalidRequest)
+    } else {
+        Ok((data.len() as u32, data))
+    }
+}
+
+impl VirtualChannelSM for DeviceRedirectionSM {
+    fn get_channel_name(&self) -> ChannelName {
+        ChannelName::Device
+    }
+
+    fn is_terminated(&self) -> bool {
+        false
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        let state = self.state.borrow();
+        !state.opened || state.outgoing.is_empty()
+    }
+
+    fn update_without_chan_msg<'msg>(&mut self) -> VirtChannelSMResult<'msg> {
+        let msg = self
+            .state
+            .borrow_mut()
+            .outgoing
+            .pop_front()
+            .chain(ProtoErrorKind::VirtualChannel(ChannelName::Device))
+            .or_desc("unexpected call to `update_without_chan_msg` without message to send")?;
+        Ok(Some(msg.into()))
+    }
+
+    fn update_with_chan_msg<'msg: 'a, 'a>(
+        &mut self,
+        chan_msg: &'a NowVirtualChannel<'msg>,
+    ) -> VirtChannelSMResult<'msg> {
+        match chan_msg {
+            NowVirtualChannel::Device(msg) => {
+                self.__on_message(msg);
+                Ok(None)
+            }
+            _ => ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::Device))
+                .or_else_desc(|| format!("received an unexpected message: {:?}", chan_msg)),
+        }
+    }
+
+    /// Local devices are announced again.
+    fn on_open(&mut self) {
+        log::trace!("device redirection channel opened");
+        let mut state = self.state.borrow_mut();
+        state.opened = true;
+        let ids: Vec<u32> = state.local.keys().copied().collect();
+        for id in ids {
+            state.__announce(id);
+        }
+    }
+
+    /// Remote devices are removed, local ones are kept to be announced once the channel is opened again.
+    fn on_close(&mut self) {
+        log::trace!("device redirection channel closed");
+        let mut state = self.state.borrow_mut();
+        state.opened = false;
+        state.outgoing.clear();
+        state.pending.clear();
+        for local in state.local.values_mut() {
+            local.accepted = false;
+        }
+        let removed: Vec<u32> = state.remote.keys().copied().collect();
+        state.remote.clear();
+        for device_id in removed {
+            state.events.push_back(DeviceEvent::Removed { device_id });
+        }
+    }
+}
+
+/// Application side of a [`DeviceRedirectionSM`](struct.DeviceRedirectionSM.html).
+///
+/// Messages are queued until the sharee is updated with the channel opened.
+#[derive(Clone)]
+pub struct DeviceRedirection {
+    state: Rc<RefCell<DeviceState>>,
+}
+
+impl DeviceRedirection {
+    pub fn is_open(&self) -> bool {
+        self.state.borrow().opened
+    }
+
+    /// Oldest event not polled yet.
+    pub fn poll_event(&self) -> Option<DeviceEvent> {
+        self.state.borrow_mut().events.pop_front()
+    }
+
+    /// Makes `device` available to the peer, announced once the channel is opened. Returns its id.
+    pub fn announce<D: RedirectedDevice + 'static>(&self, device: D) -> u32 {
+        let mut state = self.state.borrow_mut();
+        let mut device_id = state.next_device_id;
+        while state.local.contains_key(&device_id) {
+            device_id = device_id.wrapping_add(1);
+        }
+        state.next_device_id = device_id.wrapping_add(1);
+        state.local.insert(
+            device_id,
+            LocalDevice {
+                device: Box::new(device),
+                accepted: false,
+            },
+        );
+        if state.opened {
+            state.__announce(device_id);
+        }
+        device_id
+    }
+
+    /// Removes a device announced locally. `false` if there is no such device.
+    pub fn remove(&self, device_id: u32) -> bool {
+        let mut state = self.state.borrow_mut();
+        if state.local.remove(&device_id).is_none() {
+            return false;
+        }
+        if state.opened {
+            state.outgoing.push_back(NowDeviceRemoveMsg::new(device_id).into());
+        }
+        true
+    }
+
+    /// Devices announced by the peer, accepted or not.
+    pub fn remote_devices(&self) -> Vec<RemoteDevice> {
+        self.state
+            .borrow()
+            .remote
+            .values()
+            .map(|remote| remote.device.clone())
+            .collect()
+    }
+
+    pub fn is_accepted(&self, device_id: u32) -> bool {
+        self.state
+            .borrow()
+            .remote
+            .get(&device_id)
+            .is_some_and(|remote| remote.accepted)
+    }
+
+    /// Number of requests to remote devices waiting for their completion.
+    pub fn pending_count(&self) -> usize {
+        self.state.borrow().pending.len()
+    }
+
+    pub fn accept_device(&self, device_id: u32) -> Result<(), ProtoError> {
+        let mut state = self.state.borrow_mut();
+        let remote = state
+            .remote
+            .get_mut(&device_id)
+            .chain(ProtoErrorKind::VirtualChannel(ChannelName::Device))
+            .or_else_desc(|| format!("device {} not announced by peer", device_id))?;
+        remote.accepted = true;
+        state
+            .outgoing
+            .push_back(NowDeviceAnnounceRspMsg::new(device_id, NowStatusCode::Success).into());
+        Ok(())
+    }
+
+    /// Refuses a device announced by the peer, which removes it.
+    pub fn refuse_device(&self, device_id: u32, status: NowStatusCode) -> Result<(), ProtoError> {
+        let mut state = self.state.borrow_mut();
+        state
+            .remote
+            .remove(&device_id)
+            .chain(ProtoErrorKind::VirtualChannel(ChannelName::Device))
+            .or_else_desc(|| format!("device {} not announced by peer", device_id))?;
+        state.__drop_pending(device_id);
+        state
+            .outgoing
+            .push_back(NowDeviceAnnounceRspMsg::new(device_id, status).into());
+        Ok(())
+    }
+
+    /// Opens a remote device. The handle is the value of the completion.
+    pub fn open(&self, device_id: u32, access: DeviceAccessFlags) -> Result<u32, ProtoError> {
+        self.__request(device_id, |request_id| {
+            NowDeviceIoRequestMsg::open(device_id, request_id, access)
+        })
+    }
+
+    pub fn close(&self, device_id: u32, handle: u32) -> Result<u32, ProtoError> {
+        self.__request(device_id, |request_id| {
+            NowDeviceIoRequestMsg::close(device_id, request_id, handle)
+        })
+    }
+
+    pub fn read(&self, device_id: u32, handle: u32, offset: u64, length: u32) -> Result<u32, ProtoError> {
+        self.__request(device_id, |request_id| {
+            NowDeviceIoRequestMsg::read(device_id, request_id, handle, offset, length)
+        })
+    }
+
+    pub fn write(&self, device_id: u32, handle: u32, offset: u64, data: Vec<u8>) -> Result<u32, ProtoError> {
+        self.__request(device_id, |request_id| {
+            NowDeviceIoRequestMsg::write(device_id, request_id, handle, offset, data)
+        })
+    }
+
+    pub fn ioctl(
+        &self,
+        device_id: u32,
+        handle: u32,
+        code: u32,
+        input: Vec<u8>,
+        max_output_len: u32,
+    ) -> Result<u32, ProtoError> {
+        self.__request(device_id, |request_id| {
+            NowDeviceIoRequestMsg::ioctl(device_id, request_id, handle, code, input, max_output_len)
+        })
+    }
+
+    /// Queues a request to an accepted remote device. Returns the id of the request.
+    fn __request<F>(&self, device_id: u32, request: F) -> Result<u32, ProtoError>
+    where
+        F: FnOnce(u32) -> NowDeviceIoRequestMsg,
+    {
+        let mut state = self.state.borrow_mut();
+        if !state.remote.get(&device_id).is_some_and(|remote| remote.accepted) {
+            return ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::Device))
+                .or_else_desc(|| format!("device {} not accepted", device_id));
+        }
+
+        let mut request_id = state.next_request_id;
+        while state.pending.contains_key(&request_id) {
+            request_id = request_id.wrapping_add(1);
+        }
+        state.next_request_id = request_id.wrapping_add(1);
+        let msg = request(request_id);
+        state.pending.insert(
+            request_id,
+            PendingRequest {
+                device_id,
+                function: msg.function,
+            },
+        );
+        state.outgoing.push_back(msg.into());
+        Ok(request_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm::test_support::transfer;
+    use std::str::FromStr;
+
+    /// Smartcard reader answering a single control code with the ATR of its card.
+    struct CardReader {
+        opened: Rc<RefCell<Vec<u32>>>,
+    }
+
+    impl RedirectedDevice for CardReader {
+        fn device_type(&self) -> DeviceType {
+            DeviceType::Smartcard
+        }
+
+        fn name(&self) -> NowString64 {
+            NowString64::from_str("Reader 0").unwrap()
+        }
+
+        fn open(&mut self, _: DeviceAccessFlags) -> Result<u32, NowStatusCode> {
+            let handle = self.opened.borrow().len() as u32 + 1;
+            self.opened.borrow_mut().push(handle);
+            Ok(handle)
+        }
+
+        fn close(&mut self, handle: u32) -> Result<(), NowStatusCode> {
+            let mut opened = self.opened.borrow_mut();
+            let len = opened.len();
+            opened.retain(|opened| *opened != handle);
+            if opened.len() == len {
+                Err(NowStatusCode::InvalidRequest)
+            } else {
+                Ok(())
+            }
+        }
+
+        fn ioctl(&mut self, _: u32, code: u32, _: &[u8], _: u32) -> Result<Vec<u8>, NowStatusCode> {
+            match code {
+                0x0009_0000 => Ok(vec![0x3b, 0x8f, 0x80]),
+                _ => Err(NowStatusCode::NotSupported),
+            }
+        }
+    }
+
+    #[test]
+    fn device_announced_opened_and_controlled() {
+        let (mut client_sm, client) = DeviceRedirectionSM::new();
+        let (mut server_sm, server) = DeviceRedirectionSM::new();
+        let opened = Rc::new(RefCell::new(Vec::new()));
+        let device_id = client.announce(CardReader {
+            opened: Rc::clone(&opened),
+        });
+        assert!(client_sm.waiting_for_packet());
+
+        client_sm.on_open();
+        server_sm.on_open();
+        transfer(&mut client_sm, &mut server_sm);
+        match server.poll_event() {
+            Some(DeviceEvent::Announced(device)) => {
+                assert_eq!(device.id, device_id);
+                assert_eq!(device.device_type, DeviceType::Smartcard);
+                assert_eq!(device.name, "Reader 0");
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(server.open(device_id, DeviceAccessFlags::new_empty()).is_err());
+
+        server.accept_device(device_id).unwrap();
+        let open_id = server.open(device_id, DeviceAccessFlags::new_empty()).unwrap();
+        transfer(&mut server_sm, &mut client_sm);
+        assert_eq!(
+            client.poll_event(),
+            Some(DeviceEvent::AnnounceAnswered {
+                device_id,
+                status: NowStatusCode::Success
+            })
+        );
+        transfer(&mut client_sm, &mut server_sm);
+        let handle = match server.poll_event() {
+            Some(DeviceEvent::Completed {
+                request_id,
+                function: DeviceIoFunction::Open,
+                status: NowStatusCode::Success,
+                value,
+                ..
+            }) if request_id == open_id => value,
+            event => panic!("unexpected event: {:?}", event),
+        };
+        assert_eq!(*opened.borrow(), vec![handle]);
+
+        server.ioctl(device_id, handle, 0x0009_0000, Vec::new(), 2).unwrap();
+        server.ioctl(device_id, handle, 0x0009_0000, Vec::new(), 32).unwrap();
+        server.read(device_id, handle, 0, 16).unwrap();
+        assert_eq!(server.pending_count(), 3);
+        transfer(&mut server_sm, &mut client_sm);
+        transfer(&mut client_sm, &mut server_sm);
+        let statuses: Vec<(NowStatusCode, Vec<u8>)> = core::iter::from_fn(|| server.poll_event())
+            .map(|event| match event {
+                DeviceEvent::Completed { status, data, .. } => (status, data),
+                event => panic!("unexpected event: {:?}", event),
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (NowStatusCode::InvalidRequest, Vec::new()),
+                (NowStatusCode::Success, vec![0x3b, 0x8f, 0x80]),
+                (NowStatusCode::NotSupported, Vec::new()),
+            ]
+        );
+
+        // requests pending on a removed device are dropped
+        server.close(device_id, handle).unwrap();
+        assert!(client.remove(device_id));
+        transfer(&mut server_sm, &mut client_sm);
+        transfer(&mut client_sm, &mut server_sm);
+        assert_eq!(server.poll_event(), Some(DeviceEvent::Removed { device_id }));
+        assert_eq!(server.poll_event(), None);
+        assert_eq!(server.pending_count(), 0);
+        assert!(server.remote_devices().is_empty());
+        assert_eq!(*opened.borrow(), vec![handle]);
+    }
+
+    /// Reader announced by the client and accepted by the server.
+    fn accepted_reader() -> (
+        DeviceRedirectionSM,
+        DeviceRedirection,
+        DeviceRedirectionSM,
+        DeviceRedirection,
+        u32,
+    ) {
+        let (mut client_sm, client) = DeviceRedirectionSM::new();
+        let (mut server_sm, server) = DeviceRedirectionSM::new();
+        let device_id = client.announce(CardReader {
+            opened: Rc::new(RefCell::new(Vec::new())),
+        });
+        client_sm.on_open();
+        server_sm.on_open();
+        transfer(&mut client_sm, &mut server_sm);
+        assert!(matches!(server.poll_event(), Some(DeviceEvent::Announced(_))));
+        server.accept_device(device_id).unwrap();
+        transfer(&mut server_sm, &mut client_sm);
+        assert!(matches!(
+            client.poll_event(),
+            Some(DeviceEvent::AnnounceAnswered { .. })
+        ));
+        (client_sm, client, server_sm, server, device_id)
+    }
+
+    /// Feeds `msg` to `sm` and returns the completion it answers with.
+    fn completion_of(sm: &mut DeviceRedirectionSM, msg: NowDeviceIoRequestMsg) -> NowDeviceIoCompletionMsg {
+        sm.update_with_chan_msg(&NowDeviceMsg::from(msg).into()).unwrap();
+        match sm.update_without_chan_msg().unwrap() {
+            Some(NowVirtualChannel::Device(NowDeviceMsg::IoCompletion(completion))) => completion,
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn request_to_unknown_device_not_found() {
+        let (mut client_sm, _client, _server_sm, server, device_id) = accepted_reader();
+        assert!(server.open(device_id + 1, DeviceAccessFlags::new_empty()).is_err());
+        assert_eq!(server.pending_count(), 0);
+
+        // not announced, or announced but not accepted
+        let completion = completion_of(
+            &mut client_sm,
+            NowDeviceIoRequestMsg::open(device_id + 1, 7, DeviceAccessFlags::new_empty()),
+        );
+        assert_eq!(completion.request_id, 7);
+        assert_eq!(completion.status, NowStatusCode::NotFound);
+
+        let (mut client_sm, client) = DeviceRedirectionSM::new();
+        let device_id = client.announce(CardReader {
+            opened: Rc::new(RefCell::new(Vec::new())),
+        });
+        client_sm.on_open();
+        client_sm.update_without_chan_msg().unwrap();
+        let completion = completion_of(
+            &mut client_sm,
+            NowDeviceIoRequestMsg::open(device_id, 8, DeviceAccessFlags::new_empty()),
+        );
+        assert_eq!(completion.status, NowStatusCode::NotFound);
+    }
+
+    #[test]
+    fn request_after_channel_closed() {
+        let (mut client_sm, client, mut server_sm, server, device_id) = accepted_reader();
+        server.open(device_id, DeviceAccessFlags::new_empty()).unwrap();
+        assert_eq!(server.pending_count(), 1);
+
+        // the request in flight is dropped along with the channel
+        client_sm.on_close();
+        server_sm.on_close();
+        assert_eq!(server.poll_event(), Some(DeviceEvent::Removed { device_id }));
+        assert_eq!(server.pending_count(), 0);
+        assert!(server.open(device_id, DeviceAccessFlags::new_empty()).is_err());
+
+        // announced again once reopened, to be accepted again
+        client_sm.on_open();
+        server_sm.on_open();
+        transfer(&mut client_sm, &mut server_sm);
+        assert!(matches!(server.poll_event(), Some(DeviceEvent::Announced(_))));
+        assert!(!server.is_accepted(device_id));
+        let completion = completion_of(
+            &mut client_sm,
+            NowDeviceIoRequestMsg::open(device_id, 0, DeviceAccessFlags::new_empty()),
+        );
+        assert_eq!(completion.status, NowStatusCode::NotFound);
+        assert_eq!(client.poll_event(), None);
+    }
+
+    #[test]
+    fn completion_of_unknown_request_ignored() {
+        let (_client_sm, _client, mut server_sm, server, device_id) = accepted_reader();
+        let request_id = server.open(device_id, DeviceAccessFlags::new_empty()).unwrap();
+
+        let unknown = NowDeviceIoCompletionMsg::new(device_id, request_id + 1, NowStatusCode::Success, 1, Vec::new());
+        server_sm
+            .update_with_chan_msg(&NowDeviceMsg::from(unknown).into())
+            .unwrap();
+        assert_eq!(server.poll_event(), None);
+        assert_eq!(server.pending_count(), 1);
+
+        // a request is completed once
+        let completion = NowDeviceIoCompletionMsg::new(device_id, request_id, NowStatusCode::Success, 1, Vec::new());
+        server_sm
+            .update_with_chan_msg(&NowDeviceMsg::from(completion.clone()).into())
+            .unwrap();
+        server_sm
+            .update_with_chan_msg(&NowDeviceMsg::from(completion).into())
+            .unwrap();
+        assert!(matches!(server.poll_event(), Some(DeviceEvent::Completed { .. })));
+        assert_eq!(server.poll_event(), None);
+        assert_eq!(server.pending_count(), 0);
+    }
+}
@@ -0,0 +1,380 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        AccessControlCode, DisplayPowerFlags, NowCapset, NowDesktopDisplayPowerReqMsg, NowDesktopDisplayPowerRspMsg,
+        NowDesktopMsg,
+    },
+    sm::{LinkQuality, SessionGuard},
+};
+
+/// Technician side of the host display power control (wake display, inhibit sleep and screensaver).
+///
+/// Requests are only emitted if the peer advertised the display power capability and
+/// granted the viewing access right.
+#[derive(Debug, Clone)]
+pub struct DisplayPowerController {
+    negotiated: bool,
+    viewing_allowed: bool,
+    inhibited: DisplayPowerFlags,
+}
+
+impl Default for DisplayPowerController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DisplayPowerController {
+    pub fn new() -> Self {
+        Self {
+            negotiated: false,
+            viewing_allowed: false,
+            inhibited: DisplayPowerFlags::new_empty(),
+        }
+    }
+
+    pub fn configure_from_capabilities(&mut self, peer_capabilities: &[NowCapset<'_>]) {
+        self.negotiated = false;
+        self.viewing_allowed = false;
+
+        for capset in peer_capabilities {
+            match capset {
+                NowCapset::Desktop(capset) => self.negotiated = capset.flags.display_power(),
+                NowCapset::Access(capset) => {
+                    self.viewing_allowed = capset
+                        .access_controls
+                        .iter()
+                        .any(|def| def.code == AccessControlCode::Viewing && def.flags.allowed());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.negotiated && self.viewing_allowed
+    }
+
+    /// Inhibitions currently in effect on the host as far as we know.
+    pub fn inhibited(&self) -> DisplayPowerFlags {
+        self.inhibited
+    }
+
+    pub fn request(&mut self, flags: DisplayPowerFlags) -> Result<NowDesktopMsg, ProtoError> {
+        if !self.negotiated {
+            return ProtoError::new(ProtoErrorKind::CapabilityNotNegotiated("NowDesktop"))
+                .or_desc("peer doesn't support display power control");
+        }
+
+        if !self.viewing_allowed {
+            return ProtoError::new(ProtoErrorKind::AccessDenied(AccessControlCode::Viewing))
+                .or_desc("display power control requires the viewing access right");
+        }
+
+        Ok(NowDesktopDisplayPowerReqMsg::new(flags).into())
+    }
+
+    /// Wakes the display while keeping current inhibitions.
+    pub fn wake_display(&mut self) -> Result<NowDesktopMsg, ProtoError> {
+        self.request(self.inhibited.union(DisplayPowerFlags::new_empty().set_wake_display()))
+    }
+
+    /// Returns an allow request if any inhibition is in effect.
+    pub fn allow(&mut self) -> Option<NowDesktopMsg> {
+        if self.inhibited.is_empty() {
+            None
+        } else {
+            Some(NowDesktopDisplayPowerReqMsg::allow().into())
+        }
+    }
+
+    /// Handles the host answer and returns the refused items.
+    pub fn on_display_power_rsp(&mut self, rsp: &NowDesktopDisplayPowerRspMsg) -> DisplayPowerFlags {
+        self.inhibited = rsp.applied.inhibitions();
+        let refused = rsp.refused();
+        if !refused.is_empty() {
+            log::warn!("display power items refused by host: {:?}", refused);
+        }
+        refused
+    }
+
+    /// Host allows sleep and screensaver again by itself on disconnection.
+    pub fn on_disconnect(&mut self) {
+        self.inhibited = DisplayPowerFlags::new_empty();
+    }
+}
+
+pub trait DisplayPowerHostCallbackTrait {
+    /// Wakes the physical display up. Returns `true` on success.
+    fn on_wake_display(&mut self) -> bool {
+        false
+    }
+
+    /// Inhibits requested items and returns the ones effectively inhibited.
+    fn on_inhibit(&mut self, requested: DisplayPowerFlags) -> DisplayPowerFlags {
+        #![allow(unused_variables)]
+        DisplayPowerFlags::new_empty()
+    }
+
+    fn on_allow(&mut self, released: DisplayPowerFlags) {
+        #![allow(unused_variables)]
+    }
+}
+
+sa::assert_obj_safe!(DisplayPowerHostCallbackTrait);
+
+pub struct DummyDisplayPowerHostCallback;
+impl DisplayPowerHostCallbackTrait for DummyDisplayPowerHostCallback {}
+
+/// Host side of the display power control.
+///
+/// Inhibitions are allowed again as soon as the session is disconnected or the
+/// link is considered lost so that a crashed technician never keeps the host awake.
+pub struct DisplayPowerHost<UserCallback> {
+    inhibited: DisplayPowerFlags,
+    user_callback: UserCallback,
+}
+
+impl<UserCallback> DisplayPowerHost<UserCallback>
+where
+    UserCallback: DisplayPowerHostCallbackTrait,
+{
+    pub fn new(user_callback: UserCallback) -> Self {
+        Self {
+            inhibited: DisplayPowerFlags::new_empty(),
+            user_callback,
+        }
+    }
+
+    pub fn inhibited(&self) -> DisplayPowerFlags {
+        self.inhibited
+    }
+
+    pub fn update_with_desktop_msg(&mut self, msg: &NowDesktopMsg) -> Option<NowDesktopMsg> {
+        match msg {
+            NowDesktopMsg::DisplayPowerReq(req) => {
+                let requested = req.flags.inhibitions();
+
+                let dropped = self.inhibited.difference(requested);
+                if !dropped.is_empty() {
+                    self.__allow(dropped);
+                }
+
+                let missing = requested.difference(self.inhibited);
+                if !missing.is_empty() {
+                    // the callback can't inhibit more than what was requested
+                    let newly_inhibited = self.user_callback.on_inhibit(missing).intersection(missing);
+                    self.inhibited = self.inhibited.union(newly_inhibited);
+                }
+
+                let mut applied = self.inhibited;
+                if req.flags.wake_display() && self.user_callback.on_wake_display() {
+                    applied.set_wake_display();
+                }
+
+                Some(NowDesktopDisplayPowerRspMsg::new(req.flags, applied).into())
+            }
+            _ => None,
+        }
+    }
+
+    /// Graceful or abrupt session end.
+    pub fn on_disconnect(&mut self) {
+        self.allow_all();
+    }
+
+    pub fn on_quality_changed(&mut self, current: LinkQuality) {
+        if current == LinkQuality::Lost {
+            log::warn!("link lost: allowing display sleep again");
+            self.allow_all();
+        }
+    }
+
+    pub fn allow_all(&mut self) {
+        if !self.inhibited.is_empty() {
+            let inhibited = self.inhibited;
+            self.__allow(inhibited);
+        }
+    }
+
+    fn __allow(&mut self, flags: DisplayPowerFlags) {
+        self.inhibited = self.inhibited.difference(flags);
+        self.user_callback.on_allow(flags);
+    }
+}
+
+impl<UserCallback> SessionGuard for DisplayPowerHost<UserCallback>
+where
+    UserCallback: DisplayPowerHostCallbackTrait,
+{
+    fn update_with_desktop_msg(&mut self, msg: &NowDesktopMsg) -> Option<NowDesktopMsg> {
+        DisplayPowerHost::update_with_desktop_msg(self, msg)
+    }
+
+    fn on_disconnect(&mut self) {
+        DisplayPowerHost::on_disconnect(self)
+    }
+
+    fn on_quality_changed(&mut self, current: LinkQuality) {
+        DisplayPowerHost::on_quality_changed(self, current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{AccessCapset, AccessControlDef, DesktopCapset, DesktopCapsetFlags},
+        sm::{ConnectionQuality, ConnectionQualityCallbackTrait, QualityThresholds},
+    };
+    use std::{
+        cell::RefCell,
+        rc::Rc,
+        time::{Duration, Instant},
+    };
+
+    /// Host machine able to inhibit sleep but not the screensaver.
+    #[derive(Default)]
+    struct HostState {
+        display_on: bool,
+        sleep_inhibited: bool,
+        allowed: Vec<DisplayPowerFlags>,
+    }
+
+    struct HostCallback(Rc<RefCell<HostState>>);
+
+    impl DisplayPowerHostCallbackTrait for HostCallback {
+        fn on_wake_display(&mut self) -> bool {
+            self.0.borrow_mut().display_on = true;
+            true
+        }
+
+        fn on_inhibit(&mut self, requested: DisplayPowerFlags) -> DisplayPowerFlags {
+            let mut inhibited = DisplayPowerFlags::new_empty();
+            if requested.inhibit_sleep() {
+                self.0.borrow_mut().sleep_inhibited = true;
+                inhibited.set_inhibit_sleep();
+            }
+            inhibited
+        }
+
+        fn on_allow(&mut self, released: DisplayPowerFlags) {
+            let mut state = self.0.borrow_mut();
+            if released.inhibit_sleep() {
+                state.sleep_inhibited = false;
+            }
+            state.allowed.push(released);
+        }
+    }
+
+    fn host_capabilities() -> Vec<NowCapset<'static>> {
+        vec![
+            NowCapset::Desktop(DesktopCapset::new(DesktopCapsetFlags::new_empty().set_display_power())),
+            NowCapset::Access(AccessCapset::new_with_access_controls(vec![
+                AccessControlDef::new_allowed(AccessControlCode::Viewing),
+            ])),
+        ]
+    }
+
+    fn exchange<C: DisplayPowerHostCallbackTrait>(
+        host: &mut DisplayPowerHost<C>,
+        controller: &mut DisplayPowerController,
+        req: NowDesktopMsg,
+    ) -> DisplayPowerFlags {
+        match host.update_with_desktop_msg(&req) {
+            Some(NowDesktopMsg::DisplayPowerRsp(rsp)) => controller.on_display_power_rsp(&rsp),
+            other => panic!("expected a display power rsp, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn request_not_emitted_without_negotiation() {
+        let mut controller = DisplayPowerController::new();
+        assert!(controller
+            .request(DisplayPowerFlags::new_empty().set_wake_display())
+            .is_err());
+
+        controller.configure_from_capabilities(&host_capabilities());
+        assert!(controller.is_available());
+        assert!(controller
+            .request(DisplayPowerFlags::new_empty().set_wake_display())
+            .is_ok());
+    }
+
+    #[test]
+    fn inhibit_allow_round_trip() {
+        let state = Rc::new(RefCell::new(HostState::default()));
+        let mut host = DisplayPowerHost::new(HostCallback(Rc::clone(&state)));
+        let mut controller = DisplayPowerController::new();
+        controller.configure_from_capabilities(&host_capabilities());
+
+        let req = controller
+            .request(DisplayPowerFlags::from(DisplayPowerFlags::INHIBIT_MASK))
+            .unwrap();
+        let refused = exchange(&mut host, &mut controller, req);
+        assert_eq!(refused, DisplayPowerFlags::INHIBIT_SCREENSAVER);
+        assert_eq!(controller.inhibited(), DisplayPowerFlags::INHIBIT_SLEEP);
+        assert!(state.borrow().sleep_inhibited);
+
+        // waking the display doesn't drop current inhibitions
+        let req = controller.wake_display().unwrap();
+        exchange(&mut host, &mut controller, req);
+        assert!(state.borrow().display_on);
+        assert!(state.borrow().sleep_inhibited);
+        assert_eq!(controller.inhibited(), DisplayPowerFlags::INHIBIT_SLEEP);
+
+        let req = controller.allow().unwrap();
+        exchange(&mut host, &mut controller, req);
+        assert!(controller.inhibited().is_empty());
+        assert!(!state.borrow().sleep_inhibited);
+        assert!(controller.allow().is_none());
+    }
+
+    struct LinkMonitor(Rc<RefCell<Vec<LinkQuality>>>);
+
+    impl ConnectionQualityCallbackTrait for LinkMonitor {
+        fn on_quality_changed(&mut self, _: LinkQuality, current: LinkQuality) {
+            self.0.borrow_mut().push(current);
+        }
+    }
+
+    #[test]
+    fn automatic_allow_on_connection_loss() {
+        let state = Rc::new(RefCell::new(HostState::default()));
+        let mut host = DisplayPowerHost::new(HostCallback(Rc::clone(&state)));
+
+        let t0 = Instant::now();
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let mut quality = ConnectionQuality::new(
+            QualityThresholds::default().lost_after(Duration::from_secs(5)),
+            Duration::from_secs(1),
+            LinkMonitor(Rc::clone(&changes)),
+            t0,
+        );
+
+        host.update_with_desktop_msg(&NowDesktopDisplayPowerReqMsg::inhibit().into());
+        assert!(state.borrow().sleep_inhibited);
+
+        // technician crashes without terminating the session
+        let mut now = t0;
+        while now <= t0 + Duration::from_secs(6) {
+            quality.poll(now);
+            for current in changes.borrow_mut().drain(..) {
+                host.on_quality_changed(current);
+            }
+            now += Duration::from_millis(500);
+        }
+
+        assert_eq!(quality.quality(), LinkQuality::Lost);
+        assert!(!state.borrow().sleep_inhibited);
+        assert!(host.inhibited().is_empty());
+        assert_eq!(
+            state.borrow().allowed,
+            vec![DisplayPowerFlags::from(DisplayPowerFlags::INHIBIT_SLEEP)]
+        );
+
+        // nothing left to allow on disconnection
+        host.on_disconnect();
+        assert_eq!(state.borrow().allowed.len(), 1);
+    }
+}
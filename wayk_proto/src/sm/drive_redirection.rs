@@ -0,0 +1,913 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        ChannelName, DriveFileAttributes, DriveFlags, DriveOpenFlags, DriveOperation, NowDriveAnnounceMsg,
+        NowDriveFileInfo, NowDriveMsg, NowDriveRemoveMsg, NowDriveRequestMsg, NowDriveResponseMsg, NowStatusCode,
+        NowString256, NowString64, NowVirtualChannel,
+    },
+    sm::{VirtChannelSMResult, VirtualChannelSM},
+};
+use alloc::collections::{BTreeMap, VecDeque};
+use core::convert::TryFrom;
+use std::{
+    cell::RefCell,
+    fs::{self, File, Metadata, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    rc::Rc,
+    str::FromStr,
+    time::UNIX_EPOCH,
+};
+
+/// Policy restricting what the peer can do with an exposed directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriveSandbox {
+    pub root: PathBuf,
+    pub read_only: bool,
+    pub max_open_files: usize,
+    /// Files whose name starts with a dot are neither listed nor accessible.
+    pub hide_dot_files: bool,
+}
+
+impl DriveSandbox {
+    pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            read_only: false,
+            max_open_files: Self::DEFAULT_MAX_OPEN_FILES,
+            hide_dot_files: false,
+        }
+    }
+
+    pub fn read_only(self, read_only: bool) -> Self {
+        Self { read_only, ..self }
+    }
+
+    pub fn max_open_files(self, max_open_files: usize) -> Self {
+        Self { max_open_files, ..self }
+    }
+
+    pub fn hide_dot_files(self, hide_dot_files: bool) -> Self {
+        Self { hide_dot_files, ..self }
+    }
+
+    /// Translates a path of the drive, relative to its root with components separated by `/`,
+    /// into a local path under the root.
+    ///
+    /// `..` components, drive or stream separators and links leading outside of the root are refused.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, NowStatusCode> {
+        let mut local = self.root.clone();
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => return Err(NowStatusCode::AccessDenied),
+                _ if component.contains(['\\', ':', '\0']) => return Err(NowStatusCode::AccessDenied),
+                _ if self.hide_dot_files && component.starts_with('.') => return Err(NowStatusCode::NotFound),
+                _ => local.push(component),
+            }
+        }
+
+        // links are followed by the filesystem: the closest existing ancestor must stay under the root
+        let root = fs::canonicalize(&self.root).map_err(|_| NowStatusCode::NotFound)?;
+        let mut existing = local.as_path();
+        loop {
+            match fs::canonicalize(existing) {
+                Ok(canonical) if canonical.starts_with(&root) => return Ok(local),
+                Ok(_) => return Err(NowStatusCode::AccessDenied),
+                // dangling link, which would be followed on creation
+                Err(_) if fs::symlink_metadata(existing).is_ok() => return Err(NowStatusCode::AccessDenied),
+                Err(_) => existing = existing.parent().ok_or(NowStatusCode::NotFound)?,
+            }
+        }
+    }
+
+    fn is_hidden(&self, name: &str) -> bool {
+        self.hide_dot_files && name.starts_with('.')
+    }
+}
+
+/// Drive exposed by the peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteDrive {
+    pub id: u32,
+    pub name: String,
+    pub read_only: bool,
+}
+
+/// File or directory of a drive exposed by the peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriveEntry {
+    pub name: String,
+    pub attributes: DriveFileAttributes,
+    pub size: u64,
+    /// Last modification, in seconds since the Unix epoch.
+    pub modified: u64,
+}
+
+impl From<&NowDriveFileInfo> for DriveEntry {
+    fn from(info: &NowDriveFileInfo) -> Self {
+        Self {
+            name: info.name.as_str().to_owned(),
+            attributes: info.attributes,
+            size: info.size,
+            modified: info.modified,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DriveEvent {
+    Announced(RemoteDrive),
+    /// The peer stopped exposing a drive, its pending requests being dropped.
+    Removed {
+        drive_id: u32,
+    },
+    /// Request to a remote drive completed. `value`, `data` and `entries` depend on `operation`,
+    /// see [`NowDriveRequestMsg`](../message/struct.NowDriveRequestMsg.html).
+    Completed {
+        drive_id: u32,
+        request_id: u32,
+        operation: DriveOperation,
+        status: NowStatusCode,
+        value: u32,
+        data: Vec<u8>,
+        entries: Vec<DriveEntry>,
+    },
+}
+
+struct ExposedDrive {
+    name: NowString64,
+    sandbox: DriveSandbox,
+}
+
+struct OpenFile {
+    drive_id: u32,
+    file: File,
+    writable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingRequest {
+    drive_id: u32,
+    operation: DriveOperation,
+}
+
+#[derive(Default)]
+struct DriveState {
+    opened: bool,
+    next_drive_id: u32,
+    next_handle: u32,
+    next_request_id: u32,
+    local: BTreeMap<u32, ExposedDrive>,
+    /// Files opened by the peer in local drives, by handle.
+    files: BTreeMap<u32, OpenFile>,
+    remote: BTreeMap<u32, RemoteDrive>,
+    pending: BTreeMap<u32, PendingRequest>,
+    outgoing: VecDeque<NowDriveMsg>,
+    events: VecDeque<DriveEvent>,
+}
+
+impl DriveState {
+    fn __announce(&mut self, drive_id: u32) {
+        if let Some(drive) = self.local.get(&drive_id) {
+            let mut flags = DriveFlags::new_empty();
+            if drive.sandbox.read_only {
+                flags.set_read_only();
+            }
+            self.outgoing
+                .push_back(NowDriveAnnounceMsg::new(drive_id, flags, drive.name.clone()).into());
+        }
+    }
+
+    fn __open_file(&mut self, drive_id: u32, handle: u32) -> Result<&mut OpenFile, NowStatusCode> {
+        self.files
+            .get_mut(&handle)
+            .filter(|open_file| open_file.drive_id == drive_id)
+            .ok_or(NowStatusCode::NotFound)
+    }
+}
+
+/// State machine of the drive redirection channel.
+///
+/// Directories exposed with [`DriveRedirection::expose`](struct.DriveRedirection.html#method.expose) are
+/// browsed, read and written by the peer within the limits of their [`DriveSandbox`](struct.DriveSandbox.html).
+/// Requests are served from the local filesystem as they are received. Drives exposed by the peer are
+/// accessed through the [`DriveRedirection`](struct.DriveRedirection.html) handle the other way.
+pub struct DriveRedirectionSM {
+    state: Rc<RefCell<DriveState>>,
+}
+
+impl DriveRedirectionSM {
+    /// Bytes read at most for a single request.
+    pub const MAX_READ_LEN: u32 = 64 * 1024;
+    /// Entries listed at most for a single request.
+    pub const MAX_ENTRIES: u32 = 256;
+
+    pub fn new() -> (Self, DriveRedirection) {
+        let state = Rc::new(RefCell::new(DriveState::default()));
+        let redirection = DriveRedirection {
+            state: Rc::clone(&state),
+        };
+        (Self { state }, redirection)
+    }
+
+    fn __on_message(&mut self, msg: &NowDriveMsg) {
+        let mut state = self.state.borrow_mut();
+        match msg {
+            NowDriveMsg::Announce(msg) => {
+                log::trace!("drive {} exposed by peer", msg.drive_id);
+                let drive = RemoteDrive {
+                    id: msg.drive_id,
+                    name: msg.name.as_str().to_owned(),
+                    read_only: msg.drive_flags.read_only(),
+                };
+                state.remote.insert(msg.drive_id, drive.clone());
+                state.events.push_back(DriveEvent::Announced(drive));
+            }
+            NowDriveMsg::Remove(msg) => {
+                if state.remote.remove(&msg.drive_id).is_none() {
+                    log::warn!("removal of drive {} not exposed ignored", msg.drive_id);
+                    return;
+                }
+                log::trace!("drive {} removed by peer", msg.drive_id);
+                state.pending.retain(|_, pending| pending.drive_id != msg.drive_id);
+                state.events.push_back(DriveEvent::Removed { drive_id: msg.drive_id });
+            }
+            NowDriveMsg::Request(msg) => {
+                let response = match Self::serve(&mut state, msg) {
+                    Ok(response) => response,
+                    Err(status) => {
+                        log::debug!(
+                            "{:?} request {} on drive {} failed: {:?}",
+                            msg.operation,
+                            msg.request_id,
+                            msg.drive_id,
+                            status
+                        );
+                        NowDriveResponseMsg::new(msg.request_id, status)
+                    }
+                };
+                state.outgoing.push_back(response.into());
+            }
+            NowDriveMsg::Response(msg) => match state.pending.remove(&msg.request_id) {
+                Some(pending) => state.events.push_back(DriveEvent::Completed {
+                    drive_id: pending.drive_id,
+                    request_id: msg.request_id,
+                    operation: pending.operation,
+                    status: msg.status,
+                    value: msg.value,
+                    data: msg.data.0.clone(),
+                    entries: msg.entries.iter().map(DriveEntry::from).collect(),
+                }),
+                None => log::warn!("response to unknown drive request {} ignored", msg.request_id),
+            },
+        }
+    }
+
+    fn serve(state: &mut DriveState, msg: &NowDriveRequestMsg) -> Result<NowDriveResponseMsg, NowStatusCode> {
+        let sandbox = state
+            .local
+            .get(&msg.drive_id)
+            .map(|drive| drive.sandbox.clone())
+            .ok_or(NowStatusCode::NotFound)?;
+        let response = NowDriveResponseMsg::new(msg.request_id, NowStatusCode::Success);
+
+        match msg.operation {
+            DriveOperation::Open => {
+                let flags = msg.open_flags;
+                let write = flags.write();
+                if (flags.create() || flags.truncate()) && !write {
+                    return Err(NowStatusCode::InvalidRequest);
+                }
+                if write && sandbox.read_only {
+                    return Err(NowStatusCode::AccessDenied);
+                }
+                let open_files = state
+                    .files
+                    .values()
+                    .filter(|open_file| open_file.drive_id == msg.drive_id)
+                    .count();
+                if open_files >= sandbox.max_open_files {
+                    return Err(NowStatusCode::Busy);
+                }
+
+                let path = sandbox.resolve(msg.path.as_str())?;
+                if path.is_dir() {
+                    return Err(NowStatusCode::InvalidRequest);
+                }
+                let file = OpenOptions::new()
+                    .read(flags.read() || !write)
+                    .write(write)
+                    .create(flags.create())
+                    .truncate(flags.truncate())
+                    .open(&path)
+                    .map_err(|e| io_status(&e))?;
+
+                let mut handle = state.next_handle;
+                while state.files.contains_key(&handle) {
+                    handle = handle.wrapping_add(1);
+                }
+                state.next_handle = handle.wrapping_add(1);
+                state.files.insert(
+                    handle,
+                    OpenFile {
+                        drive_id: msg.drive_id,
+                        file,
+                        writable: write,
+                    },
+                );
+                Ok(response.value(handle))
+            }
+            DriveOperation::Close => {
+                state.__open_file(msg.drive_id, msg.handle)?;
+                state.files.remove(&msg.handle);
+                Ok(response)
+            }
+            DriveOperation::Read => {
+                let open_file = state.__open_file(msg.drive_id, msg.handle)?;
+                let mut data = Vec::new();
+                open_file
+                    .file
+                    .seek(SeekFrom::Start(msg.offset))
+                    .and_then(|_| {
+                        (&mut open_file.file)
+                            .take(u64::from(msg.length.min(Self::MAX_READ_LEN)))
+                            .read_to_end(&mut data)
+                    })
+                    .map_err(|e| io_status(&e))?;
+                Ok(response.value(data.len() as u32).data(data))
+            }
+            DriveOperation::Write => {
+                let open_file = state.__open_file(msg.drive_id, msg.handle)?;
+                if !open_file.writable {
+                    return Err(NowStatusCode::AccessDenied);
+                }
+                open_file
+                    .file
+                    .seek(SeekFrom::Start(msg.offset))
+                    .and_then(|_| open_file.file.write_all(&msg.data))
+                    .map_err(|e| io_status(&e))?;
+                Ok(response.value(msg.data.len() as u32))
+            }
+            DriveOperation::Stat => {
+                let path = sandbox.resolve(msg.path.as_str())?;
+                let metadata = fs::metadata(&path).map_err(|e| io_status(&e))?;
+                let name = msg.path.as_str().trim_end_matches('/').rsplit('/').next().unwrap_or("");
+                let info = file_info(name, &metadata, &sandbox).ok_or(NowStatusCode::Failure)?;
+                Ok(response.entries(vec![info]))
+            }
+            DriveOperation::Enumerate => {
+                let path = sandbox.resolve(msg.path.as_str())?;
+                let mut entries: Vec<NowDriveFileInfo> = fs::read_dir(&path)
+                    .map_err(|e| io_status(&e))?
+                    .filter_map(Result::ok)
+                    .filter_map(|entry| {
+                        // names not representable on the channel are skipped, like hidden files
+                        let name = entry.file_name().into_string().ok()?;
+                        if sandbox.is_hidden(&name) {
+                            return None;
+                        }
+                        // links are listed as such, without revealing what they point to
+                        let metadata = entry.metadata().ok()?;
+                        file_info(&name, &metadata, &sandbox)
+                    })
+                    .collect();
+                entries.sort_by(|a, b| a.name.as_str().cmp(b.name.as_str()));
+
+                let total = entries.len() as u32;
+                let page = entries
+                    .into_iter()
+                    .skip(usize::try_from(msg.offset).unwrap_or(usize::MAX))
+                    .take(msg.length.min(Self::MAX_ENTRIES) as usize)
+                    .collect();
+                Ok(response.value(total).entries(page))
+            }
+        }
+    }
+}
+
+fn io_status(e: &io::Error) -> NowStatusCode {
+    match e.kind() {
+        io::ErrorKind::NotFound => NowStatusCode::NotFound,
+        io::ErrorKind::PermissionDenied => NowStatusCode::AccessDenied,
+        io::ErrorKind::AlreadyExists | io::ErrorKind::InvalidInput => NowStatusCode::InvalidRequest,
+        _ => NowStatusCode::Failure,
+    }
+}
+
+fn file_info(name: &str, metadata: &Metadata, sandbox: &DriveSandbox) -> Option<NowDriveFileInfo> {
+    let mut attributes = DriveFileAttributes::new_empty();
+    if metadata.is_dir() {
+        attributes.set_directory();
+    }
+    if sandbox.read_only || metadata.permissions().readonly() {
+        attributes.set_read_only();
+    }
+    Some(NowDriveFileInfo {
+        name: NowString256::from_str(name).ok()?,
+        attributes,
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+        modified: metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_secs()),
+    })
+}
+
+impl VirtualChannelSM for DriveRedirectionSM {
+    fn get_channel_name(&self) -> ChannelName {
+        ChannelName::Drive
+    }
+
+    fn is_terminated(&self) -> bool {
+        false
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        let state = self.state.borrow();
+        !state.opened || state.outgoing.is_empty()
+    }
+
+    fn update_without_chan_msg<'msg>(&mut self) -> VirtChannelSMResult<'msg> {
+        let msg = self
+            .state
+            .borrow_mut()
+            .outgoing
+            .pop_front()
+            .chain(ProtoErrorKind::VirtualChannel(ChannelName::Drive))
+            .or_desc("unexpected call to `update_without_chan_msg` without message to send")?;
+        Ok(Some(msg.into()))
+    }
+
+    fn update_with_chan_msg<'msg: 'a, 'a>(
+        &mut self,
+        chan_msg: &'a NowVirtualChannel<'msg>,
+    ) -> VirtChannelSMResult<'msg> {
+        match chan_msg {
+            NowVirtualChannel::Drive(msg) => {
+                self.__on_message(msg);
+                Ok(None)
+            }
+            _ => ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::Drive))
+                .or_else_desc(|| format!("received an unexpected message: {:?}", chan_msg)),
+        }
+    }
+
+    /// Local drives are announced again.
+    fn on_open(&mut self) {
+        log::trace!("drive redirection channel opened");
+        let mut state = self.state.borrow_mut();
+        state.opened = true;
+        let ids: Vec<u32> = state.local.keys().copied().collect();
+        for id in ids {
+            state.__announce(id);
+        }
+    }
+
+    /// Files opened by the peer are closed and the drives of the peer removed.
+    fn on_close(&mut self) {
+        log::trace!("drive redirection channel closed");
+        let mut state = self.state.borrow_mut();
+        state.opened = false;
+        state.outgoing.clear();
+        state.files.clear();
+        state.pending.clear();
+        let removed: Vec<u32> = state.remote.keys().copied().collect();
+        state.remote.clear();
+        for drive_id in removed {
+            state.events.push_back(DriveEvent::Removed { drive_id });
+        }
+    }
+}
+
+/// Application side of a [`DriveRedirectionSM`](struct.DriveRedirectionSM.html).
+///
+/// Messages are queued until the sharee is updated with the channel opened.
+#[derive(Clone)]
+pub struct DriveRedirection {
+    state: Rc<RefCell<DriveState>>,
+}
+
+impl DriveRedirection {
+    pub fn is_open(&self) -> bool {
+        self.state.borrow().opened
+    }
+
+    /// Oldest event not polled yet.
+    pub fn poll_event(&self) -> Option<DriveEvent> {
+        self.state.borrow_mut().events.pop_front()
+    }
+
+    /// Exposes the root of `sandbox` to the peer as `name`, announced once the channel is opened.
+    /// Returns the id of the drive.
+    pub fn expose(&self, name: NowString64, sandbox: DriveSandbox) -> u32 {
+        let mut state = self.state.borrow_mut();
+        let mut drive_id = state.next_drive_id;
+        while state.local.contains_key(&drive_id) {
+            drive_id = drive_id.wrapping_add(1);
+        }
+        state.next_drive_id = drive_id.wrapping_add(1);
+        state.local.insert(drive_id, ExposedDrive { name, sandbox });
+        if state.opened {
+            state.__announce(drive_id);
+        }
+        drive_id
+    }
+
+    /// Stops exposing a drive, closing the files opened by the peer. `false` if there is no such drive.
+    pub fn unexpose(&self, drive_id: u32) -> bool {
+        let mut state = self.state.borrow_mut();
+        if state.local.remove(&drive_id).is_none() {
+            return false;
+        }
+        state.files.retain(|_, open_file| open_file.drive_id != drive_id);
+        if state.opened {
+            state.outgoing.push_back(NowDriveRemoveMsg::new(drive_id).into());
+        }
+        true
+    }
+
+    /// Number of files opened by the peer in a local drive.
+    pub fn open_files(&self, drive_id: u32) -> usize {
+        self.state
+            .borrow()
+            .files
+            .values()
+            .filter(|open_file| open_file.drive_id == drive_id)
+            .count()
+    }
+
+    /// Drives exposed by the peer.
+    pub fn remote_drives(&self) -> Vec<RemoteDrive> {
+        self.state.borrow().remote.values().cloned().collect()
+    }
+
+    /// Opens a file of a remote drive. The handle is the value of the completion.
+    pub fn open(&self, drive_id: u32, path: NowString256, flags: DriveOpenFlags) -> Result<u32, ProtoError> {
+        self.__request(drive_id, |request_id| {
+            NowDriveRequestMsg::open(drive_id, request_id, path, flags)
+        })
+    }
+
+    pub fn close(&self, drive_id: u32, handle: u32) -> Result<u32, ProtoError> {
+        self.__request(drive_id, |request_id| {
+            NowDriveRequestMsg::close(drive_id, request_id, handle)
+        })
+    }
+
+    pub fn read(&self, drive_id: u32, handle: u32, offset: u64, length: u32) -> Result<u32, ProtoError> {
+        self.__request(drive_id, |request_id| {
+            NowDriveRequestMsg::read(drive_id, request_id, handle, offset, length)
+        })
+    }
+
+    pub fn write(&self, drive_id: u32, handle: u32, offset: u64, data: Vec<u8>) -> Result<u32, ProtoError> {
+        self.__request(drive_id, |request_id| {
+            NowDriveRequestMsg::write(drive_id, request_id, handle, offset, data)
+        })
+    }
+
+    pub fn stat(&self, drive_id: u32, path: NowString256) -> Result<u32, ProtoError> {
+        self.__request(drive_id, |request_id| {
+            NowDriveRequestMsg::stat(drive_id, request_id, path)
+        })
+    }
+
+    /// Lists a directory of a remote drive, by pages of up to `max_entries` entries.
+    pub fn enumerate(
+        &self,
+        drive_id: u32,
+        path: NowString256,
+        start: u32,
+        max_entries: u32,
+    ) -> Result<u32, ProtoError> {
+        self.__request(drive_id, |request_id| {
+            NowDriveRequestMsg::enumerate(drive_id, request_id, path, start, max_entries)
+        })
+    }
+
+    /// Queues a request to a remote drive. Returns the id of the request.
+    fn __request<F>(&self, drive_id: u32, request: F) -> Result<u32, ProtoError>
+    where
+        F: FnOnce(u32) -> NowDriveRequestMsg,
+    {
+        let mut state = self.state.borrow_mut();
+        if !state.remote.contains_key(&drive_id) {
+            return ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::Drive))
+                .or_else_desc(|| format!("drive {} not exposed by peer", drive_id));
+        }
+
+        let mut request_id = state.next_request_id;
+        while state.pending.contains_key(&request_id) {
+            request_id = request_id.wrapping_add(1);
+        }
+        state.next_request_id = request_id.wrapping_add(1);
+        let msg = request(request_id);
+        state.pending.insert(
+            request_id,
+            PendingRequest {
+                drive_id,
+                operation: msg.operation,
+            },
+        );
+        state.outgoing.push_back(msg.into());
+        Ok(request_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm::test_support::transfer;
+
+    type Completion = (NowStatusCode, u32, Vec<u8>, Vec<DriveEntry>);
+
+    /// Directory exposed by a server to a client, removed once dropped.
+    ///
+    /// `root` is exposed, and holds `docs/notes.txt` and `.secret`. `outside.txt` is next to it.
+    struct Exposed {
+        dir: PathBuf,
+        root: PathBuf,
+        client_sm: DriveRedirectionSM,
+        server_sm: DriveRedirectionSM,
+        client: DriveRedirection,
+        server: DriveRedirection,
+    }
+
+    impl Exposed {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("wayk-proto-drive-{}-{}", std::process::id(), test));
+            let _ = fs::remove_dir_all(&dir);
+            let root = dir.join("exposed");
+            fs::create_dir_all(root.join("docs")).unwrap();
+            fs::write(root.join("docs").join("notes.txt"), b"hello drive").unwrap();
+            fs::write(root.join(".secret"), b"hidden").unwrap();
+            fs::write(dir.join("outside.txt"), b"outside").unwrap();
+
+            let (mut client_sm, client) = DriveRedirectionSM::new();
+            let (mut server_sm, server) = DriveRedirectionSM::new();
+            client_sm.on_open();
+            server_sm.on_open();
+            Self {
+                dir,
+                root,
+                client_sm,
+                server_sm,
+                client,
+                server,
+            }
+        }
+
+        /// Exposes `sandbox` and waits for the client to know about it.
+        fn expose(&mut self, name: &str, sandbox: DriveSandbox) -> u32 {
+            let read_only = sandbox.read_only;
+            let drive_id = self.server.expose(NowString64::from_str(name).unwrap(), sandbox);
+            transfer(&mut self.server_sm, &mut self.client_sm);
+            assert_eq!(
+                self.client.poll_event(),
+                Some(DriveEvent::Announced(RemoteDrive {
+                    id: drive_id,
+                    name: name.to_owned(),
+                    read_only,
+                }))
+            );
+            drive_id
+        }
+
+        /// Sends the queued requests of the client and returns the completions.
+        fn round_trip(&mut self) -> Vec<Completion> {
+            transfer(&mut self.client_sm, &mut self.server_sm);
+            transfer(&mut self.server_sm, &mut self.client_sm);
+            core::iter::from_fn(|| self.client.poll_event())
+                .map(|event| match event {
+                    DriveEvent::Completed {
+                        status,
+                        value,
+                        data,
+                        entries,
+                        ..
+                    } => (status, value, data, entries),
+                    event => panic!("unexpected event: {:?}", event),
+                })
+                .collect()
+        }
+
+        fn statuses(&mut self) -> Vec<NowStatusCode> {
+            self.round_trip().into_iter().map(|completion| completion.0).collect()
+        }
+    }
+
+    impl Drop for Exposed {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn path(path: &str) -> NowString256 {
+        NowString256::from_str(path).unwrap()
+    }
+
+    fn names(entries: &[DriveEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    fn open_flags() -> DriveOpenFlags {
+        DriveOpenFlags::new_empty().set_read()
+    }
+
+    fn create_flags() -> DriveOpenFlags {
+        DriveOpenFlags::new_empty().set_write().set_create()
+    }
+
+    #[test]
+    fn drive_browsed_and_read() {
+        let mut exposed = Exposed::new("browsed");
+        let drive_id = exposed.expose("Exposed", DriveSandbox::new(&exposed.root));
+
+        exposed.client.enumerate(drive_id, path("/"), 0, 10).unwrap();
+        exposed.client.stat(drive_id, path("docs/notes.txt")).unwrap();
+        exposed
+            .client
+            .open(drive_id, path("docs/notes.txt"), open_flags())
+            .unwrap();
+        let completions = exposed.round_trip();
+        assert_eq!(names(&completions[0].3), [".secret", "docs"]);
+        assert!(completions[0].3[1].attributes.directory());
+        assert_eq!(completions[1].3[0].name, "notes.txt");
+        assert_eq!(completions[1].3[0].size, 11);
+        assert!(!completions[1].3[0].attributes.read_only());
+        assert_eq!(completions[2].0, NowStatusCode::Success);
+        let handle = completions[2].1;
+        assert_eq!(exposed.server.open_files(drive_id), 1);
+
+        exposed.client.read(drive_id, handle, 6, 64).unwrap();
+        exposed.client.close(drive_id, handle).unwrap();
+        let completions = exposed.round_trip();
+        assert_eq!(completions[0].2, b"drive");
+        assert_eq!(completions[1].0, NowStatusCode::Success);
+        assert_eq!(exposed.server.open_files(drive_id), 0);
+    }
+
+    #[test]
+    fn parent_components_refused() {
+        let mut exposed = Exposed::new("parent");
+        let drive_id = exposed.expose("Exposed", DriveSandbox::new(&exposed.root));
+
+        exposed.client.stat(drive_id, path("../outside.txt")).unwrap();
+        exposed.client.stat(drive_id, path("docs/../../outside.txt")).unwrap();
+        exposed
+            .client
+            .open(drive_id, path("../outside.txt"), open_flags())
+            .unwrap();
+        exposed.client.enumerate(drive_id, path("C:/"), 0, 10).unwrap();
+        assert_eq!(exposed.statuses(), [NowStatusCode::AccessDenied; 4]);
+    }
+
+    #[test]
+    fn hidden_files_neither_listed_nor_accessible() {
+        let mut exposed = Exposed::new("hidden");
+        let drive_id = exposed.expose("Exposed", DriveSandbox::new(&exposed.root).hide_dot_files(true));
+
+        exposed.client.enumerate(drive_id, path("/"), 0, 10).unwrap();
+        exposed.client.stat(drive_id, path(".secret")).unwrap();
+        exposed.client.open(drive_id, path(".secret"), open_flags()).unwrap();
+        let completions = exposed.round_trip();
+        assert_eq!(names(&completions[0].3), ["docs"]);
+        assert_eq!(completions[1].0, NowStatusCode::NotFound);
+        assert_eq!(completions[2].0, NowStatusCode::NotFound);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_escaping_root_refused() {
+        let mut exposed = Exposed::new("symlink");
+        std::os::unix::fs::symlink(exposed.dir.join("outside.txt"), exposed.root.join("escape")).unwrap();
+        let drive_id = exposed.expose("Exposed", DriveSandbox::new(&exposed.root));
+
+        exposed.client.enumerate(drive_id, path("/"), 0, 10).unwrap();
+        exposed.client.open(drive_id, path("escape"), open_flags()).unwrap();
+        exposed.client.stat(drive_id, path("escape")).unwrap();
+        let completions = exposed.round_trip();
+        // listed as such, without revealing what it points to
+        assert_eq!(names(&completions[0].3), [".secret", "docs", "escape"]);
+        assert_eq!(completions[1].0, NowStatusCode::AccessDenied);
+        assert_eq!(completions[2].0, NowStatusCode::AccessDenied);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dangling_link_not_followed_on_create() {
+        let mut exposed = Exposed::new("dangling");
+        let target = exposed.dir.join("created-outside.txt");
+        std::os::unix::fs::symlink(&target, exposed.root.join("dangling")).unwrap();
+        let drive_id = exposed.expose("Exposed", DriveSandbox::new(&exposed.root));
+
+        exposed.client.open(drive_id, path("dangling"), create_flags()).unwrap();
+        assert_eq!(exposed.statuses(), [NowStatusCode::AccessDenied]);
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn read_only_drive_refuses_writes() {
+        let mut exposed = Exposed::new("read-only");
+        let drive_id = exposed.expose("Exposed", DriveSandbox::new(&exposed.root).read_only(true));
+
+        exposed.client.stat(drive_id, path("docs/notes.txt")).unwrap();
+        exposed.client.open(drive_id, path("new.txt"), create_flags()).unwrap();
+        exposed
+            .client
+            .open(
+                drive_id,
+                path("docs/notes.txt"),
+                DriveOpenFlags::new_empty().set_write(),
+            )
+            .unwrap();
+        exposed
+            .client
+            .open(drive_id, path("docs/notes.txt"), open_flags())
+            .unwrap();
+        let completions = exposed.round_trip();
+        assert!(completions[0].3[0].attributes.read_only());
+        assert_eq!(completions[1].0, NowStatusCode::AccessDenied);
+        assert_eq!(completions[2].0, NowStatusCode::AccessDenied);
+        assert_eq!(completions[3].0, NowStatusCode::Success);
+        assert!(!exposed.root.join("new.txt").exists());
+
+        // opened for reading only
+        exposed
+            .client
+            .write(drive_id, completions[3].1, 0, b"bye".to_vec())
+            .unwrap();
+        assert_eq!(exposed.statuses(), [NowStatusCode::AccessDenied]);
+        assert_eq!(
+            fs::read(exposed.root.join("docs").join("notes.txt")).unwrap(),
+            b"hello drive"
+        );
+    }
+
+    #[test]
+    fn open_files_limited() {
+        let mut exposed = Exposed::new("max-open");
+        let drive_id = exposed.expose("Exposed", DriveSandbox::new(&exposed.root).max_open_files(1));
+
+        exposed
+            .client
+            .open(drive_id, path("docs/notes.txt"), open_flags())
+            .unwrap();
+        exposed.client.open(drive_id, path(".secret"), open_flags()).unwrap();
+        let completions = exposed.round_trip();
+        assert_eq!(completions[0].0, NowStatusCode::Success);
+        assert_eq!(completions[1].0, NowStatusCode::Busy);
+        assert_eq!(exposed.server.open_files(drive_id), 1);
+
+        // available again once closed
+        exposed.client.close(drive_id, completions[0].1).unwrap();
+        exposed.client.open(drive_id, path(".secret"), open_flags()).unwrap();
+        assert_eq!(exposed.statuses(), [NowStatusCode::Success; 2]);
+    }
+
+    #[test]
+    fn handles_bound_to_their_drive() {
+        let mut exposed = Exposed::new("wrong-drive");
+        let docs_id = exposed.expose("Docs", DriveSandbox::new(exposed.root.join("docs")));
+        let root_id = exposed.expose("Root", DriveSandbox::new(&exposed.root));
+
+        exposed.client.open(docs_id, path("notes.txt"), open_flags()).unwrap();
+        let handle = exposed.round_trip()[0].1;
+
+        exposed.client.read(root_id, handle, 0, 64).unwrap();
+        exposed.client.close(root_id, handle).unwrap();
+        exposed.client.read(docs_id, handle.wrapping_add(1), 0, 64).unwrap();
+        assert_eq!(exposed.statuses(), [NowStatusCode::NotFound; 3]);
+        assert_eq!(exposed.server.open_files(docs_id), 1);
+
+        // files of a drive no longer exposed are closed
+        assert!(exposed.server.unexpose(docs_id));
+        assert_eq!(exposed.server.open_files(docs_id), 0);
+    }
+
+    #[test]
+    fn directory_enumerated_by_pages() {
+        let mut exposed = Exposed::new("paging");
+        for i in 0..5 {
+            fs::write(exposed.root.join("docs").join(format!("page-{}.txt", i)), b"").unwrap();
+        }
+        let drive_id = exposed.expose("Exposed", DriveSandbox::new(exposed.root.join("docs")));
+
+        exposed.client.enumerate(drive_id, path("/"), 0, 4).unwrap();
+        exposed.client.enumerate(drive_id, path("/"), 4, 4).unwrap();
+        exposed.client.enumerate(drive_id, path("/"), 8, 4).unwrap();
+        let completions = exposed.round_trip();
+        // total count of entries along with each page
+        assert!(completions.iter().all(|completion| completion.1 == 6));
+        assert_eq!(
+            names(&completions[0].3),
+            ["notes.txt", "page-0.txt", "page-1.txt", "page-2.txt"]
+        );
+        assert_eq!(names(&completions[1].3), ["page-3.txt", "page-4.txt"]);
+        assert!(completions[2].3.is_empty());
+    }
+}
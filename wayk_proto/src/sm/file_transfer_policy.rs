@@ -0,0 +1,225 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{ChannelName, NowFileTransferPolicyMsg, NowString256},
+};
+use core::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PolicyViolation {
+    FileTooLarge { size: u64, max: u64 },
+    SessionQuotaExceeded { size: u64, remaining: u64 },
+    PathNotAllowed,
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileTooLarge { size, max } => write!(f, "file too large ({} bytes, max is {})", size, max),
+            Self::SessionQuotaExceeded { size, remaining } => write!(
+                f,
+                "session quota exceeded ({} bytes requested, {} remaining)",
+                size, remaining
+            ),
+            Self::PathNotAllowed => write!(f, "path outside of allowed roots"),
+        }
+    }
+}
+
+/// Bytes transferred during the session, for display purpose ("82 MB of 100 MB used").
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SessionUsage {
+    pub used: u64,
+    pub limit: Option<u64>,
+}
+
+impl SessionUsage {
+    pub fn remaining(&self) -> Option<u64> {
+        self.limit.map(|limit| limit.saturating_sub(self.used))
+    }
+}
+
+/// File transfer policy enforced on both sides of the file transfer channel.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FileTransferPolicy {
+    pub max_file_size: Option<u64>,
+    pub max_session_bytes: Option<u64>,
+    /// `None` means any path is allowed.
+    pub allowed_roots: Option<Vec<String>>,
+}
+
+impl FileTransferPolicy {
+    pub fn unrestricted() -> Self {
+        Self::default()
+    }
+
+    pub fn max_file_size(self, max_file_size: u64) -> Self {
+        Self {
+            max_file_size: Some(max_file_size),
+            ..self
+        }
+    }
+
+    pub fn max_session_bytes(self, max_session_bytes: u64) -> Self {
+        Self {
+            max_session_bytes: Some(max_session_bytes),
+            ..self
+        }
+    }
+
+    pub fn allowed_roots<S: Into<String>>(self, allowed_roots: Vec<S>) -> Self {
+        Self {
+            allowed_roots: Some(allowed_roots.into_iter().map(Into::into).collect()),
+            ..self
+        }
+    }
+
+    pub fn from_msg(msg: &NowFileTransferPolicyMsg) -> Result<Self, ProtoError> {
+        let allowed_roots = if msg.flags.allowed_roots() {
+            let mut roots = Vec::with_capacity(msg.allowed_roots.len());
+            for root in msg.allowed_roots.iter() {
+                validate_root(root.as_str())?;
+                roots.push(root.as_str().to_owned());
+            }
+            Some(roots)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            max_file_size: if msg.flags.max_file_size() {
+                Some(msg.max_file_size)
+            } else {
+                None
+            },
+            max_session_bytes: if msg.flags.max_session_bytes() {
+                Some(msg.max_session_bytes)
+            } else {
+                None
+            },
+            allowed_roots,
+        })
+    }
+
+    pub fn to_msg(&self) -> Result<NowFileTransferPolicyMsg, ProtoError> {
+        let mut msg = NowFileTransferPolicyMsg::new_unrestricted();
+
+        if let Some(max_file_size) = self.max_file_size {
+            msg = msg.max_file_size(max_file_size);
+        }
+
+        if let Some(max_session_bytes) = self.max_session_bytes {
+            msg = msg.max_session_bytes(max_session_bytes);
+        }
+
+        if let Some(allowed_roots) = &self.allowed_roots {
+            let mut roots = Vec::with_capacity(allowed_roots.len());
+            for root in allowed_roots {
+                validate_root(root)?;
+                roots.push(NowString256::from_str(root)?);
+            }
+            msg = msg.allowed_roots(roots);
+        }
+
+        Ok(msg)
+    }
+
+    pub fn check_path(&self, path: &str) -> Result<(), PolicyViolation> {
+        match &self.allowed_roots {
+            None => Ok(()),
+            Some(roots) => {
+                if has_parent_component(path) || !roots.iter().any(|root| is_within_root(path, root)) {
+                    Err(PolicyViolation::PathNotAllowed)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    pub fn check_size(&self, size: u64, session_used: u64) -> Result<(), PolicyViolation> {
+        if let Some(max) = self.max_file_size {
+            if size > max {
+                return Err(PolicyViolation::FileTooLarge { size, max });
+            }
+        }
+
+        if let Some(max_session_bytes) = self.max_session_bytes {
+            let remaining = max_session_bytes.saturating_sub(session_used);
+            if size > remaining {
+                return Err(PolicyViolation::SessionQuotaExceeded { size, remaining });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check_request(&self, path: &str, size: u64, session_used: u64) -> Result<(), PolicyViolation> {
+        self.check_path(path)?;
+        self.check_size(size, session_used)
+    }
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split(['/', '\\']).filter(|c| !c.is_empty())
+}
+
+fn has_parent_component(path: &str) -> bool {
+    components(path).any(|c| c == "..")
+}
+
+fn is_absolute(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    let is_drive = bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    path.starts_with('/') || path.starts_with('\\') || (is_drive && (bytes[2] == b'/' || bytes[2] == b'\\'))
+}
+
+fn is_within_root(path: &str, root: &str) -> bool {
+    if !is_absolute(path) {
+        return false;
+    }
+
+    let mut path_components = components(path);
+    components(root).all(|root_component| path_components.next() == Some(root_component))
+}
+
+fn validate_root(root: &str) -> Result<(), ProtoError> {
+    if !is_absolute(root) || components(root).any(|c| c == "." || c == "..") {
+        ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::FileTransfer))
+            .or_else_desc(|| format!("invalid allowed root {:?}: must be an absolute normalized path", root))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_checked_against_roots() {
+        let policy = FileTransferPolicy::unrestricted().allowed_roots(vec!["/srv/share", "C:\\Exports"]);
+
+        assert!(policy.check_path("/srv/share/report.pdf").is_ok());
+        assert!(policy.check_path("/srv/share").is_ok());
+        assert!(policy.check_path("C:\\Exports\\a\\b.txt").is_ok());
+        assert_eq!(
+            policy.check_path("/srv/shared/secret"),
+            Err(PolicyViolation::PathNotAllowed)
+        );
+        assert_eq!(
+            policy.check_path("/srv/share/../../etc/shadow"),
+            Err(PolicyViolation::PathNotAllowed)
+        );
+        assert_eq!(policy.check_path("srv/share/a"), Err(PolicyViolation::PathNotAllowed));
+    }
+
+    #[test]
+    fn invalid_roots_rejected() {
+        let policy = FileTransferPolicy::unrestricted().allowed_roots(vec!["/srv/../etc"]);
+        assert!(policy.to_msg().is_err());
+
+        let policy = FileTransferPolicy::unrestricted().allowed_roots(vec!["relative/root"]);
+        assert!(policy.to_msg().is_err());
+    }
+}
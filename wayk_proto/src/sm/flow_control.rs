@@ -0,0 +1,166 @@
+use crate::message::ChannelName;
+use alloc::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy)]
+struct ReceiveWindow {
+    /// Bytes the peer may still send.
+    outstanding: u32,
+    /// Bytes processed since the last grant.
+    consumed: u32,
+}
+
+/// Credit-based flow control of the virtual channels.
+///
+/// A receiver configured with a window for a channel grants it in full when the channel is opened,
+/// then grants again the bytes processed once half of the window is. A sender never sends more than
+/// it was granted; channels the peer never granted credits for are not limited.
+///
+/// Windows are to be larger than the largest message of their channel, which would never be sent otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelFlowControl {
+    windows: BTreeMap<ChannelName, u32>,
+    receive: BTreeMap<ChannelName, ReceiveWindow>,
+    send: BTreeMap<ChannelName, u32>,
+    pending_grants: BTreeMap<ChannelName, u32>,
+}
+
+impl ChannelFlowControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes the peer may send over `name` before being granted more.
+    pub fn window(mut self, name: ChannelName, bytes: u32) -> Self {
+        self.windows.insert(name, bytes);
+        self
+    }
+
+    /// Flow control is not supported by the peer: windows are dropped, so that no credit is granted.
+    pub fn disable(&mut self) {
+        self.windows.clear();
+        self.receive.clear();
+        self.pending_grants.clear();
+    }
+
+    pub fn on_channel_opened(&mut self, name: &ChannelName) {
+        self.send.remove(name);
+        if let Some(window) = self.windows.get(name).copied() {
+            self.receive.insert(
+                name.clone(),
+                ReceiveWindow {
+                    outstanding: window,
+                    consumed: 0,
+                },
+            );
+            self.pending_grants.insert(name.clone(), window);
+        }
+    }
+
+    pub fn on_channel_closed(&mut self, name: &ChannelName) {
+        self.send.remove(name);
+        self.receive.remove(name);
+        self.pending_grants.remove(name);
+    }
+
+    pub fn on_channels_closed(&mut self) {
+        self.send.clear();
+        self.receive.clear();
+        self.pending_grants.clear();
+    }
+
+    /// Adds the credits granted by the peer for `name`.
+    pub fn on_window_update(&mut self, name: &ChannelName, credits: u32) {
+        log::trace!("{} bytes granted for channel {:?}", credits, name);
+        let available = self.send.entry(name.clone()).or_insert(0);
+        *available = available.saturating_add(credits);
+    }
+
+    /// Bytes that can be sent over `name`, `None` while the peer doesn't limit the channel.
+    pub fn credits(&self, name: &ChannelName) -> Option<u32> {
+        self.send.get(name).copied()
+    }
+
+    pub fn can_send(&self, name: &ChannelName, len: usize) -> bool {
+        self.credits(name).is_none_or(|credits| len <= credits as usize)
+    }
+
+    pub fn on_sent(&mut self, name: &ChannelName, len: usize) {
+        if let Some(credits) = self.send.get_mut(name) {
+            *credits = credits.saturating_sub(len as u32);
+        }
+    }
+
+    /// A peer sending more than granted is logged, the message is processed anyway.
+    pub fn on_received(&mut self, name: &ChannelName, len: usize) {
+        if let Some(window) = self.receive.get_mut(name) {
+            if len > window.outstanding as usize {
+                log::warn!(
+                    "peer overran the window of channel {:?}: {} bytes received, {} granted",
+                    name,
+                    len,
+                    window.outstanding
+                );
+            }
+            window.outstanding = window.outstanding.saturating_sub(len as u32);
+        }
+    }
+
+    pub fn on_consumed(&mut self, name: &ChannelName, len: usize) {
+        let window_size = match self.windows.get(name) {
+            Some(window_size) => *window_size,
+            None => return,
+        };
+        if let Some(window) = self.receive.get_mut(name) {
+            window.consumed = window.consumed.saturating_add(len as u32);
+            if window.consumed >= window_size / 2 {
+                let credits = window.consumed;
+                window.outstanding = window.outstanding.saturating_add(credits);
+                window.consumed = 0;
+                let pending = self.pending_grants.entry(name.clone()).or_insert(0);
+                *pending = pending.saturating_add(credits);
+            }
+        }
+    }
+
+    pub fn has_pending_grant(&self) -> bool {
+        !self.pending_grants.is_empty()
+    }
+
+    /// Next credits to grant to the peer.
+    pub fn poll_grant(&mut self) -> Option<(ChannelName, u32)> {
+        let name = self.pending_grants.keys().next()?.clone();
+        self.pending_grants.remove_entry(&name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consumed_bytes_granted_again_past_half_window() {
+        let mut receiver = ChannelFlowControl::new().window(ChannelName::FileTransfer, 100);
+        let mut sender = ChannelFlowControl::new();
+        assert!(sender.can_send(&ChannelName::FileTransfer, 1000));
+
+        receiver.on_channel_opened(&ChannelName::FileTransfer);
+        sender.on_channel_opened(&ChannelName::FileTransfer);
+        let (name, credits) = receiver.poll_grant().unwrap();
+        sender.on_window_update(&name, credits);
+        assert!(!receiver.has_pending_grant());
+
+        sender.on_sent(&ChannelName::FileTransfer, 60);
+        assert!(!sender.can_send(&ChannelName::FileTransfer, 41));
+        receiver.on_received(&ChannelName::FileTransfer, 60);
+        receiver.on_consumed(&ChannelName::FileTransfer, 30);
+        assert!(!receiver.has_pending_grant());
+        receiver.on_consumed(&ChannelName::FileTransfer, 30);
+        assert_eq!(receiver.poll_grant(), Some((ChannelName::FileTransfer, 60)));
+
+        sender.on_window_update(&ChannelName::FileTransfer, 60);
+        assert_eq!(sender.credits(&ChannelName::FileTransfer), Some(100));
+
+        sender.on_channel_closed(&ChannelName::FileTransfer);
+        assert_eq!(sender.credits(&ChannelName::FileTransfer), None);
+    }
+}
@@ -0,0 +1,387 @@
+use crate::{
+    container::Bytes32,
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    serialization::{DecodeLimits, Encode},
+};
+use alloc::collections::BTreeMap;
+use core::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+/// Part of a channel payload too large to be sent in a single frame.
+///
+/// All fragments of a payload share the same `message_id`, allocated sequentially by the sender.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct NowFragment<'a> {
+    pub message_id: u32,
+    pub index: u16,
+    pub count: u16,
+    /// Size of the whole payload.
+    pub total_size: u32,
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub data: Bytes32<'a>,
+}
+
+impl NowFragment<'_> {
+    /// Encoded size of a fragment without its data.
+    pub const HEADER_SIZE: usize = 16;
+
+    pub fn is_last(&self) -> bool {
+        self.index + 1 == self.count
+    }
+}
+
+/// Splits channel payloads into fragments fitting in `mtu` bytes once encoded.
+#[derive(Debug, Clone)]
+pub struct Fragmenter {
+    mtu: usize,
+    next_message_id: u32,
+}
+
+impl Fragmenter {
+    pub const DEFAULT_MTU: usize = 16 * 1024;
+
+    pub fn new() -> Self {
+        Self {
+            mtu: Self::DEFAULT_MTU,
+            next_message_id: 0,
+        }
+    }
+
+    /// Maximum encoded size of a fragment. Values too small to carry any data are raised.
+    pub fn mtu(self, mtu: usize) -> Self {
+        Self {
+            mtu: mtu.max(NowFragment::HEADER_SIZE + 1),
+            ..self
+        }
+    }
+
+    pub fn get_mtu(&self) -> usize {
+        self.mtu
+    }
+
+    pub fn max_fragment_data(&self) -> usize {
+        self.mtu - NowFragment::HEADER_SIZE
+    }
+
+    /// Returns `true` if `payload_len` bytes can't be sent as a single fragment.
+    pub fn needs_fragmentation(&self, payload_len: usize) -> bool {
+        payload_len > self.max_fragment_data()
+    }
+
+    /// Splits `payload` into fragments of a new message. An empty payload still gives one fragment.
+    pub fn fragment<'a>(&mut self, payload: &'a [u8]) -> Result<Vec<NowFragment<'a>>, ProtoError> {
+        let total_size = u32::try_from(payload.len())
+            .map_err(ProtoError::from)
+            .chain(ProtoErrorKind::Encoding("NowFragment"))
+            .or_desc("payload is too large to be fragmented")?;
+
+        let chunk_size = self.max_fragment_data();
+        let count = u16::try_from(payload.len().div_ceil(chunk_size).max(1))
+            .map_err(ProtoError::from)
+            .chain(ProtoErrorKind::Encoding("NowFragment"))
+            .or_else_desc(|| {
+                format!(
+                    "payload of {} bytes needs too many fragments for mtu {}",
+                    payload.len(),
+                    self.mtu
+                )
+            })?;
+
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+
+        let fragments = if payload.is_empty() {
+            vec![NowFragment {
+                message_id,
+                index: 0,
+                count,
+                total_size,
+                data: Bytes32(payload),
+            }]
+        } else {
+            payload
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(index, data)| NowFragment {
+                    message_id,
+                    index: index as u16,
+                    count,
+                    total_size,
+                    data: Bytes32(data),
+                })
+                .collect()
+        };
+
+        debug_assert!(fragments.iter().all(|fragment| fragment.encoded_len() <= self.mtu));
+
+        Ok(fragments)
+    }
+}
+
+impl Default for Fragmenter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+struct PartialMessage {
+    count: u16,
+    total_size: u32,
+    received_size: usize,
+    fragments: BTreeMap<u16, Vec<u8>>,
+    last_update: Instant,
+}
+
+/// Rebuilds payloads from their fragments.
+///
+/// Fragments of a message may be received in any order and interleaved with fragments of other messages.
+/// Duplicated fragments are ignored. Messages not completed within the timeout are dropped by
+/// [`purge_expired`](#method.purge_expired).
+#[derive(Debug)]
+pub struct Reassembler {
+    limits: DecodeLimits,
+    timeout: Duration,
+    partial: BTreeMap<u32, PartialMessage>,
+}
+
+impl Reassembler {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub fn new() -> Self {
+        Self {
+            limits: DecodeLimits::default(),
+            timeout: Self::DEFAULT_TIMEOUT,
+            partial: BTreeMap::new(),
+        }
+    }
+
+    /// Bounds the size of a reassembled payload (`max_message_size`)
+    /// and the number of messages reassembled at the same time (`max_collection_len`).
+    pub fn limits(self, limits: DecodeLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Number of messages with missing fragments.
+    pub fn pending_count(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Adds a received fragment. Returns the whole payload once all its fragments are received.
+    pub fn push(&mut self, fragment: &NowFragment<'_>, now: Instant) -> Result<Option<Vec<u8>>, ProtoError> {
+        if fragment.index >= fragment.count {
+            return ProtoError::new(ProtoErrorKind::Decoding("NowFragment")).or_else_desc(|| {
+                format!(
+                    "fragment index ({}) out of fragment count ({})",
+                    fragment.index, fragment.count
+                )
+            });
+        }
+
+        let total_size = fragment.total_size as usize;
+        if total_size > self.limits.max_message_size {
+            return ProtoError::new(ProtoErrorKind::LimitExceeded("NowFragment")).or_else_desc(|| {
+                format!(
+                    "fragmented message size ({}) greater than message size limit ({})",
+                    total_size, self.limits.max_message_size
+                )
+            });
+        }
+
+        if fragment.count == 1 {
+            return Self::check_size(fragment.data.len(), total_size).map(|()| Some(fragment.data.to_vec()));
+        }
+
+        if !self.partial.contains_key(&fragment.message_id) {
+            self.limits
+                .check_collection_len(self.partial.len() + 1, "NowFragment")
+                .or_desc("too many fragmented messages pending")?;
+            self.partial.insert(
+                fragment.message_id,
+                PartialMessage {
+                    count: fragment.count,
+                    total_size: fragment.total_size,
+                    received_size: 0,
+                    fragments: BTreeMap::new(),
+                    last_update: now,
+                },
+            );
+        }
+
+        let message = self.partial.get_mut(&fragment.message_id).expect("inserted above");
+        if message.count != fragment.count || message.total_size != fragment.total_size {
+            let message_id = fragment.message_id;
+            self.partial.remove(&message_id);
+            return ProtoError::new(ProtoErrorKind::Decoding("NowFragment"))
+                .or_else_desc(|| format!("inconsistent fragments for message {}", message_id));
+        }
+
+        message.last_update = now;
+        if message.fragments.contains_key(&fragment.index) {
+            return Ok(None);
+        }
+
+        message.received_size += fragment.data.len();
+        if message.received_size > total_size {
+            let message_id = fragment.message_id;
+            self.partial.remove(&message_id);
+            return ProtoError::new(ProtoErrorKind::Decoding("NowFragment"))
+                .or_else_desc(|| format!("fragments of message {} exceed its size ({})", message_id, total_size));
+        }
+        message.fragments.insert(fragment.index, fragment.data.to_vec());
+
+        if message.fragments.len() < usize::from(message.count) {
+            return Ok(None);
+        }
+
+        let message = self.partial.remove(&fragment.message_id).expect("checked above");
+        Self::check_size(message.received_size, total_size)?;
+
+        let mut payload = Vec::with_capacity(total_size);
+        for data in message.fragments.values() {
+            payload.extend_from_slice(data);
+        }
+        Ok(Some(payload))
+    }
+
+    /// Drops messages without new fragment since the timeout. Returns the ids of dropped messages.
+    pub fn purge_expired(&mut self, now: Instant) -> Vec<u32> {
+        let timeout = self.timeout;
+        let expired: Vec<u32> = self
+            .partial
+            .iter()
+            .filter(|(_, message)| now.saturating_duration_since(message.last_update) >= timeout)
+            .map(|(message_id, _)| *message_id)
+            .collect();
+
+        for message_id in &expired {
+            self.partial.remove(message_id);
+        }
+
+        expired
+    }
+
+    fn check_size(received_size: usize, total_size: usize) -> Result<(), ProtoError> {
+        if received_size != total_size {
+            return ProtoError::new(ProtoErrorKind::Decoding("NowFragment")).or_else_desc(|| {
+                format!(
+                    "reassembled size ({}) doesn't match announced size ({})",
+                    received_size, total_size
+                )
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::Decode;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn fragments_fit_in_mtu() {
+        let payload = payload(1000);
+        let mut fragmenter = Fragmenter::new().mtu(116);
+        assert!(fragmenter.needs_fragmentation(payload.len()));
+
+        let fragments = fragmenter.fragment(&payload).unwrap();
+        assert_eq!(fragments.len(), 10);
+        assert!(fragments.iter().all(|fragment| fragment.encoded_len() <= 116));
+        assert!(fragments.iter().all(|fragment| fragment.message_id == 0));
+        assert!(fragments[9].is_last());
+
+        let encoded = fragments[3].encode().unwrap();
+        assert_eq!(NowFragment::decode(&encoded).unwrap(), fragments[3]);
+
+        assert_eq!(fragmenter.fragment(&[]).unwrap()[0].message_id, 1);
+    }
+
+    #[test]
+    fn reassembles_out_of_order_and_interleaved() {
+        let now = Instant::now();
+        let first = payload(1000);
+        let second = payload(250);
+        let mut fragmenter = Fragmenter::new().mtu(116);
+        let mut first_fragments = fragmenter.fragment(&first).unwrap();
+        let second_fragments = fragmenter.fragment(&second).unwrap();
+        first_fragments.reverse();
+
+        let mut reassembler = Reassembler::new();
+        for fragment in &first_fragments[..9] {
+            assert_eq!(reassembler.push(fragment, now).unwrap(), None);
+        }
+        // duplicate is ignored
+        assert_eq!(reassembler.push(&first_fragments[4], now).unwrap(), None);
+
+        for fragment in &second_fragments[..2] {
+            assert_eq!(reassembler.push(fragment, now).unwrap(), None);
+        }
+        assert_eq!(reassembler.pending_count(), 2);
+        assert_eq!(reassembler.push(&second_fragments[2], now).unwrap(), Some(second));
+        assert_eq!(reassembler.push(&first_fragments[9], now).unwrap(), Some(first));
+        assert_eq!(reassembler.pending_count(), 0);
+    }
+
+    #[test]
+    fn incomplete_messages_expire() {
+        let now = Instant::now();
+        let payload = payload(300);
+        let fragments = Fragmenter::new().mtu(116).fragment(&payload).unwrap();
+
+        let mut reassembler = Reassembler::new().timeout(Duration::from_secs(5));
+        reassembler.push(&fragments[0], now).unwrap();
+        assert!(reassembler.purge_expired(now + Duration::from_secs(4)).is_empty());
+        assert_eq!(reassembler.purge_expired(now + Duration::from_secs(5)), vec![0]);
+        assert_eq!(reassembler.pending_count(), 0);
+    }
+
+    #[test]
+    fn rejects_invalid_fragments() {
+        let now = Instant::now();
+        let payload = payload(300);
+        let fragments = Fragmenter::new().mtu(116).fragment(&payload).unwrap();
+
+        let limits = DecodeLimits {
+            max_message_size: 200,
+            ..DecodeLimits::default()
+        };
+        let err = Reassembler::new()
+            .limits(limits)
+            .push(&fragments[0], now)
+            .err()
+            .unwrap();
+        assert!(matches!(err.kind, ProtoErrorKind::LimitExceeded(_)));
+
+        let mut reassembler = Reassembler::new();
+        reassembler.push(&fragments[0], now).unwrap();
+        let inconsistent = NowFragment {
+            total_size: 400,
+            ..fragments[1].clone()
+        };
+        assert!(reassembler.push(&inconsistent, now).is_err());
+        assert_eq!(reassembler.pending_count(), 0);
+
+        let out_of_range = NowFragment {
+            index: 3,
+            ..fragments[0].clone()
+        };
+        assert!(reassembler.push(&out_of_range, now).is_err());
+    }
+}
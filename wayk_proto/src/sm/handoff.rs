@@ -0,0 +1,552 @@
+use crate::message::{
+    status::{AssociateStatusCode, DisconnectStatusCode, NowStatus, SeverityLevel, StatusType},
+    AccessCapset, AccessFlags, HandoffFlags, HandoffToken, NowCapset, NowSessionHandoffTokenRspMsg, NowTerminateMsg,
+};
+use alloc::collections::VecDeque;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+pub type HandoffRegistryRc = Rc<RefCell<HandoffRegistry>>;
+
+/// Generates the tokens issued on request of the viewers, typically from a secure random source.
+pub type HandoffTokenSource = Box<dyn FnMut() -> HandoffToken>;
+
+/// What the new viewer inherits from the session it takes over.
+#[derive(Debug, Clone)]
+pub struct HandoffGrant {
+    pub session_id: u32,
+    pub access: Option<AccessCapset>,
+}
+
+impl HandoffGrant {
+    /// Replaces access controls offered to the new viewer by the inherited ones.
+    pub fn apply_to(&self, capabilities: &mut Vec<NowCapset<'static>>) {
+        if let Some(access) = &self.access {
+            capabilities.retain(|capset| !matches!(capset, NowCapset::Access(_)));
+            capabilities.push(NowCapset::Access(access.clone()));
+        }
+    }
+}
+
+struct IssuedToken {
+    grant: HandoffGrant,
+    expires_at: Instant,
+}
+
+/// Handoff tokens issued by the host.
+///
+/// Tokens are generated by the caller (typically from a secure random source),
+/// bound to a session and valid only once.
+pub struct HandoffRegistry {
+    lifetime: Duration,
+    carry_over_access: bool,
+    token_source: Option<HandoffTokenSource>,
+    issued: HashMap<HandoffToken, IssuedToken>,
+    handed_off: VecDeque<u32>,
+}
+
+impl HandoffRegistry {
+    pub fn new(lifetime: Duration) -> Self {
+        Self {
+            lifetime,
+            carry_over_access: false,
+            token_source: None,
+            issued: HashMap::new(),
+            handed_off: VecDeque::new(),
+        }
+    }
+
+    pub fn into_rc(self) -> HandoffRegistryRc {
+        Rc::new(RefCell::new(self))
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Whether access rights granted to the current viewer carry over to the new viewer.
+    /// When they don't, allowed access controls are prompted again.
+    pub fn set_carry_over_access(&mut self, carry_over_access: bool) {
+        self.carry_over_access = carry_over_access;
+    }
+
+    pub fn carry_over_access(&self) -> bool {
+        self.carry_over_access
+    }
+
+    /// Tokens issued when a viewer requests one (see `Sharee::set_handoff_registry`).
+    pub fn set_token_source<F>(&mut self, token_source: F)
+    where
+        F: FnMut() -> HandoffToken + 'static,
+    {
+        self.token_source = Some(Box::new(token_source));
+    }
+
+    /// Issues a token from the token source, `None` if none is set.
+    pub fn issue_next(
+        &mut self,
+        session_id: u32,
+        capabilities: &[NowCapset<'_>],
+        now: Instant,
+    ) -> Option<NowSessionHandoffTokenRspMsg> {
+        let token = (self.token_source.as_mut()?)();
+        Some(self.issue(session_id, token, capabilities, now))
+    }
+
+    /// Issues a token for `session_id` negotiated with `capabilities`.
+    pub fn issue(
+        &mut self,
+        session_id: u32,
+        token: HandoffToken,
+        capabilities: &[NowCapset<'_>],
+        now: Instant,
+    ) -> NowSessionHandoffTokenRspMsg {
+        let access = capabilities.iter().find_map(|capset| match capset {
+            NowCapset::Access(access) => Some(access.clone()),
+            _ => None,
+        });
+
+        let access = if self.carry_over_access {
+            access
+        } else {
+            access.map(Self::__reprompt)
+        };
+
+        self.issued.insert(
+            token,
+            IssuedToken {
+                grant: HandoffGrant { session_id, access },
+                expires_at: now + self.lifetime,
+            },
+        );
+
+        let mut flags = HandoffFlags::new_empty();
+        if self.carry_over_access {
+            flags = flags.set_carry_over_access();
+        }
+
+        NowSessionHandoffTokenRspMsg::new(flags, session_id, self.lifetime.as_secs() as u32, token)
+    }
+
+    /// Redeems a token presented during an associate request.
+    /// A token is consumed by any redeem attempt, successful or not.
+    pub fn redeem(
+        &mut self,
+        session_id: u32,
+        token: HandoffToken,
+        now: Instant,
+    ) -> Result<HandoffGrant, AssociateStatusCode> {
+        let issued = self
+            .issued
+            .remove(&token)
+            .ok_or(AssociateStatusCode::HandoffTokenInvalid)?;
+
+        if issued.grant.session_id != session_id {
+            Err(AssociateStatusCode::HandoffTokenInvalid)
+        } else if now >= issued.expires_at {
+            Err(AssociateStatusCode::HandoffTokenExpired)
+        } else {
+            Ok(issued.grant)
+        }
+    }
+
+    /// Records `session_id` as taken over, once the new viewer is granted access.
+    pub(crate) fn hand_off(&mut self, session_id: u32) {
+        self.handed_off.push_back(session_id);
+    }
+
+    /// Removes expired tokens.
+    pub fn purge_expired(&mut self, now: Instant) {
+        self.issued.retain(|_, issued| now < issued.expires_at);
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.issued.len()
+    }
+
+    /// Next session taken over by a new viewer, with the message to send to the original viewer
+    /// before disconnecting it.
+    pub fn next_handed_off(&mut self) -> Option<(u32, NowTerminateMsg)> {
+        self.handed_off.pop_front().map(|session_id| {
+            let status = NowStatus::builder(DisconnectStatusCode::HandedOff)
+                .severity(SeverityLevel::Info)
+                .status_type(StatusType::Disconnect)
+                .build();
+            (session_id, NowTerminateMsg::new(status))
+        })
+    }
+
+    fn __reprompt(mut access: AccessCapset) -> AccessCapset {
+        for def in access.access_controls.iter_mut() {
+            if def.flags.allowed() {
+                def.flags = AccessFlags::new_empty().set_confirm();
+            }
+        }
+        access
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::ProtoError,
+        message::{
+            AccessControlCode, AccessControlDef, AuthType, ChannelName, NowBody, NowMessage, TransportCapset,
+            VirtChannelsCtx,
+        },
+        packet::NowPacket,
+        serialization::Encode,
+        sm::{
+            access_control::DenyAll, ClientConnectionSeqSM, ConnectionSM, ConnectionSMSharedData,
+            ConnectionSeqCallbackTrait, ServerConnectionSeqSM,
+        },
+    };
+    use std::io::Cursor;
+
+    const TOKEN: HandoffToken = [0xdead_beef, 0x0bad_cafe, 0x1234_5678, 0x9abc_def0];
+
+    #[derive(Clone, Default)]
+    struct Trace(Rc<RefCell<Vec<&'static str>>>);
+
+    impl ConnectionSeqCallbackTrait for Trace {
+        fn on_negotiate_completed(&mut self, _: &ConnectionSMSharedData) {
+            self.0.borrow_mut().push("negotiate");
+        }
+
+        fn on_authenticate_completed(&mut self, _: &ConnectionSMSharedData) {
+            self.0.borrow_mut().push("authenticate");
+        }
+
+        fn on_associate_completed(&mut self, _: &ConnectionSMSharedData) {
+            self.0.borrow_mut().push("associate");
+        }
+
+        fn on_connection_completed(&mut self, _: &ConnectionSMSharedData) {
+            self.0.borrow_mut().push("connected");
+        }
+    }
+
+    fn original_capabilities() -> Vec<NowCapset<'static>> {
+        vec![
+            NowCapset::Transport(TransportCapset::default()),
+            NowCapset::Access(AccessCapset::new_with_access_controls(vec![
+                AccessControlDef::new_allowed(AccessControlCode::Viewing),
+                AccessControlDef::new_allowed(AccessControlCode::Clipboard),
+                AccessControlDef::new_disabled(AccessControlCode::Exec),
+            ])),
+        ]
+    }
+
+    fn host_capabilities() -> Vec<NowCapset<'static>> {
+        vec![
+            NowCapset::Transport(TransportCapset::default()),
+            NowCapset::Access(AccessCapset::new_with_access_controls(vec![
+                AccessControlDef::new_confirm(AccessControlCode::Viewing),
+                AccessControlDef::new_confirm(AccessControlCode::Clipboard),
+                AccessControlDef::new_confirm(AccessControlCode::Exec),
+            ])),
+        ]
+    }
+
+    fn access_flags(shared_data: &ConnectionSMSharedData) -> Vec<(AccessControlCode, AccessFlags)> {
+        shared_data
+            .capabilities
+            .iter()
+            .find_map(|capset| match capset {
+                NowCapset::Access(access) => {
+                    Some(access.access_controls.iter().map(|def| (def.code, def.flags)).collect())
+                }
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    /// Feeds `incoming` then drives the state machine until it waits for a packet.
+    /// Packets produced before an error are kept in `out`.
+    fn drive(
+        sm: &mut dyn ConnectionSM,
+        incoming: Option<&[u8]>,
+        out: &mut VecDeque<Vec<u8>>,
+    ) -> Result<(), ProtoError> {
+        if let Some(bytes) = incoming {
+            let mut buffer = Vec::new();
+            let packet = NowPacket::read_from(&mut Cursor::new(bytes), &mut buffer, &VirtChannelsCtx::new())?;
+            if let NowBody::Message(msg) = &packet.body {
+                if let Some(response) = sm.update_with_message(msg)? {
+                    out.push_back(NowPacket::from_message(response).encode()?);
+                }
+            }
+        }
+
+        while !sm.is_terminated() && !sm.waiting_for_packet() {
+            if let Some(msg) = sm.update_without_message()? {
+                out.push_back(NowPacket::from_message(msg).encode()?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs client against server until no packet is in flight.
+    fn run(
+        client: &mut dyn ConnectionSM,
+        server: &mut dyn ConnectionSM,
+    ) -> (Result<(), ProtoError>, Result<(), ProtoError>) {
+        let mut to_server = VecDeque::new();
+        let mut to_client = VecDeque::new();
+
+        let mut client_result = drive(client, None, &mut to_server);
+        let mut server_result = drive(server, None, &mut to_client);
+
+        loop {
+            if server_result.is_ok() {
+                if let Some(bytes) = to_server.pop_front() {
+                    server_result = drive(server, Some(&bytes), &mut to_client);
+                    continue;
+                }
+            }
+
+            if client_result.is_ok() {
+                if let Some(bytes) = to_client.pop_front() {
+                    client_result = drive(client, Some(&bytes), &mut to_server);
+                    continue;
+                }
+            }
+
+            break;
+        }
+
+        (client_result, server_result)
+    }
+
+    fn handoff_client(session_id: u32, token: HandoffToken, trace: Trace) -> ClientConnectionSeqSM<Trace> {
+        ClientConnectionSeqSM::builder(trace)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .channels_to_open(vec![ChannelName::Chat])
+            .handoff(session_id, token)
+            .build()
+    }
+
+    fn handoff_server(registry: &HandoffRegistryRc, trace: Trace) -> ServerConnectionSeqSM<Trace> {
+        ServerConnectionSeqSM::builder(trace)
+            .available_auth_process(vec![AuthType::SRP, AuthType::PFP])
+            .capabilities(host_capabilities())
+            .available_channels(vec![ChannelName::Chat])
+            .handoff_registry(Rc::clone(registry))
+            .build()
+    }
+
+    #[test]
+    fn successful_handoff_carries_state_over() {
+        let registry = HandoffRegistry::new(Duration::from_secs(60)).into_rc();
+        registry.borrow_mut().set_carry_over_access(true);
+
+        let rsp = registry
+            .borrow_mut()
+            .issue(7, TOKEN, &original_capabilities(), Instant::now());
+        assert!(rsp.flags.carry_over_access());
+        assert_eq!(rsp.lifetime, 60);
+
+        let client_trace = Trace::default();
+        let server_trace = Trace::default();
+        let mut client = handoff_client(rsp.session_id, rsp.token, client_trace.clone());
+        let mut server = handoff_server(&registry, server_trace.clone());
+
+        let (client_result, server_result) = run(&mut client, &mut server);
+        client_result.unwrap();
+        server_result.unwrap();
+        assert!(client.is_terminated());
+        assert!(server.is_terminated());
+
+        // authentication is skipped on both sides
+        assert_eq!(*client_trace.0.borrow(), vec!["negotiate", "associate", "connected"]);
+        assert_eq!(*server_trace.0.borrow(), vec!["negotiate", "associate", "connected"]);
+
+        let shared_data = server.get_shared_data().unwrap();
+        assert_eq!(
+            access_flags(&shared_data.borrow()),
+            vec![
+                (AccessControlCode::Viewing, AccessFlags::new_empty().set_allowed()),
+                (AccessControlCode::Clipboard, AccessFlags::new_empty().set_allowed()),
+                (AccessControlCode::Exec, AccessFlags::new_empty().set_disabled()),
+            ]
+        );
+
+        // single-use
+        assert_eq!(registry.borrow().pending_count(), 0);
+        let mut replay = handoff_client(rsp.session_id, rsp.token, Trace::default());
+        let mut server = handoff_server(&registry, Trace::default());
+        let (client_result, _) = run(&mut replay, &mut server);
+        assert!(client_result.is_err());
+    }
+
+    #[test]
+    fn access_reprompted_without_carry_over() {
+        let registry = HandoffRegistry::new(Duration::from_secs(60)).into_rc();
+        registry
+            .borrow_mut()
+            .issue(7, TOKEN, &original_capabilities(), Instant::now());
+
+        let mut client = handoff_client(7, TOKEN, Trace::default());
+        let mut server = handoff_server(&registry, Trace::default());
+        let (client_result, server_result) = run(&mut client, &mut server);
+        client_result.unwrap();
+        server_result.unwrap();
+
+        let shared_data = server.get_shared_data().unwrap();
+        assert_eq!(
+            access_flags(&shared_data.borrow()),
+            vec![
+                (AccessControlCode::Viewing, AccessFlags::new_empty().set_confirm()),
+                (AccessControlCode::Clipboard, AccessFlags::new_empty().set_confirm()),
+                (AccessControlCode::Exec, AccessFlags::new_empty().set_disabled()),
+            ]
+        );
+    }
+
+    #[test]
+    fn expired_token_rejected() {
+        let lifetime = Duration::from_secs(60);
+        let mut registry = HandoffRegistry::new(lifetime);
+        let now = Instant::now();
+        registry.issue(7, TOKEN, &[], now);
+        assert_eq!(
+            registry.redeem(7, TOKEN, now + lifetime).unwrap_err(),
+            AssociateStatusCode::HandoffTokenExpired
+        );
+
+        // issued a lifetime ago
+        let registry = registry.into_rc();
+        let issued_at = Instant::now().checked_sub(lifetime).unwrap();
+        registry
+            .borrow_mut()
+            .issue(7, TOKEN, &original_capabilities(), issued_at);
+
+        let server_trace = Trace::default();
+        let mut client = handoff_client(7, TOKEN, Trace::default());
+        let mut server = handoff_server(&registry, server_trace.clone());
+        let (client_result, server_result) = run(&mut client, &mut server);
+
+        let err = client_result.unwrap_err();
+        assert!(err.to_string().contains("handoff token expired"), "{}", err);
+        assert!(server_result.is_err());
+        assert!(!server_trace.0.borrow().contains(&"associate"));
+        assert!(registry.borrow_mut().next_handed_off().is_none());
+    }
+
+    #[test]
+    fn handoff_denied_without_carry_over() {
+        let registry = HandoffRegistry::new(Duration::from_secs(60)).into_rc();
+        registry
+            .borrow_mut()
+            .issue(7, TOKEN, &original_capabilities(), Instant::now());
+
+        let server_trace = Trace::default();
+        let mut client = handoff_client(7, TOKEN, Trace::default());
+        let mut server = ServerConnectionSeqSM::builder(server_trace.clone())
+            .available_auth_process(vec![AuthType::SRP, AuthType::PFP])
+            .capabilities(host_capabilities())
+            .available_channels(vec![ChannelName::Chat])
+            .handoff_registry(Rc::clone(&registry))
+            .access_control(Rc::new(RefCell::new(DenyAll)))
+            .build();
+        let (client_result, server_result) = run(&mut client, &mut server);
+
+        let err = client_result.unwrap_err();
+        assert!(err.to_string().contains("access denied"), "{}", err);
+        assert!(server_result.is_err());
+        assert!(!server_trace.0.borrow().contains(&"associate"));
+        // the original viewer keeps the session
+        assert!(registry.borrow_mut().next_handed_off().is_none());
+    }
+
+    #[test]
+    fn access_carried_over_without_prompt() {
+        let registry = HandoffRegistry::new(Duration::from_secs(60)).into_rc();
+        registry.borrow_mut().set_carry_over_access(true);
+        registry
+            .borrow_mut()
+            .issue(7, TOKEN, &original_capabilities(), Instant::now());
+
+        let mut client = handoff_client(7, TOKEN, Trace::default());
+        let mut server = ServerConnectionSeqSM::builder(Trace::default())
+            .available_auth_process(vec![AuthType::SRP, AuthType::PFP])
+            .capabilities(host_capabilities())
+            .available_channels(vec![ChannelName::Chat])
+            .handoff_registry(Rc::clone(&registry))
+            .access_control(Rc::new(RefCell::new(DenyAll)))
+            .build();
+        let (client_result, server_result) = run(&mut client, &mut server);
+        client_result.unwrap();
+        server_result.unwrap();
+        assert_eq!(registry.borrow_mut().next_handed_off().unwrap().0, 7);
+    }
+
+    #[test]
+    fn tokens_issued_from_source() {
+        let mut registry = HandoffRegistry::new(Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(registry.issue_next(7, &[], now).is_none());
+
+        registry.set_token_source(|| TOKEN);
+        let rsp = registry.issue_next(7, &original_capabilities(), now).unwrap();
+        assert_eq!(rsp.session_id, 7);
+        assert_eq!(rsp.token, TOKEN);
+        assert_eq!(registry.redeem(7, TOKEN, now).unwrap().session_id, 7);
+    }
+
+    #[test]
+    fn token_bound_to_session() {
+        let mut registry = HandoffRegistry::new(Duration::from_secs(60));
+        let now = Instant::now();
+        registry.issue(7, TOKEN, &[], now);
+        assert_eq!(
+            registry.redeem(8, TOKEN, now).unwrap_err(),
+            AssociateStatusCode::HandoffTokenInvalid
+        );
+        assert_eq!(
+            registry.redeem(7, TOKEN, now).unwrap_err(),
+            AssociateStatusCode::HandoffTokenInvalid
+        );
+    }
+
+    #[test]
+    fn original_viewer_receives_handed_off_reason() {
+        let registry = HandoffRegistry::new(Duration::from_secs(60)).into_rc();
+        registry
+            .borrow_mut()
+            .issue(7, TOKEN, &original_capabilities(), Instant::now());
+        assert!(registry.borrow_mut().next_handed_off().is_none());
+
+        let mut client = handoff_client(7, TOKEN, Trace::default());
+        let mut server = handoff_server(&registry, Trace::default());
+        let (client_result, server_result) = run(&mut client, &mut server);
+        client_result.unwrap();
+        server_result.unwrap();
+
+        let (session_id, terminate) = registry.borrow_mut().next_handed_off().unwrap();
+        assert_eq!(session_id, 7);
+        assert!(registry.borrow_mut().next_handed_off().is_none());
+
+        let bytes = NowPacket::from_message(terminate).encode().unwrap();
+        let mut buffer = Vec::new();
+        let packet = NowPacket::read_from(&mut Cursor::new(&bytes), &mut buffer, &VirtChannelsCtx::new()).unwrap();
+        match packet.body {
+            NowBody::Message(NowMessage::Terminate(msg)) => {
+                assert_eq!(msg.status.status_type(), StatusType::Disconnect);
+                assert_eq!(msg.status.code(), DisconnectStatusCode::HandedOff);
+                assert_eq!(
+                    msg.status.code().to_string(),
+                    "disconnected because the session was handed off to another viewer."
+                );
+            }
+            other => panic!("expected a terminate message, got {:?}", other),
+        }
+    }
+}
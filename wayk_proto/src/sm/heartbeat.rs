@@ -0,0 +1,168 @@
+use crate::{
+    message::{NowBody, NowMessage, NowNetworkKeepAliveRspMsg, NowNetworkMsg},
+    sm::{KeepAliveTracker, LatencyEstimator, RttEstimator},
+};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub enum HeartbeatEvent {
+    /// Keep-alive message to send to the peer.
+    Send(NowNetworkMsg),
+    /// Nothing was received from the peer for the given duration, greater than the timeout.
+    /// Reported once until the peer is heard from again.
+    PeerSilent(Duration),
+}
+
+/// Sends keep-alive requests on an interval and monitors peer liveness.
+///
+/// Like `KeepAliveTracker`, this is driven by the caller: `poll` must be called until it returns
+/// `None` whenever `next_deadline` is reached, and every received packet must be passed to `on_body`.
+/// Transports do this on their own (see `NowTransport::recv_with_heartbeat`).
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    keep_alive: KeepAliveTracker,
+    latency: LatencyEstimator,
+    timeout: Duration,
+    silent: bool,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration, now: Instant) -> Self {
+        Self {
+            keep_alive: KeepAliveTracker::new(interval, now),
+            latency: LatencyEstimator::new(now),
+            timeout,
+            silent: false,
+        }
+    }
+
+    pub fn keep_alive(&self) -> &KeepAliveTracker {
+        &self.keep_alive
+    }
+
+    /// Round trip time measured by keep-alive requests.
+    pub fn rtt(&self) -> &RttEstimator {
+        self.latency.rtt()
+    }
+
+    /// Round trip time, jitter and clock of the peer estimated from keep-alive messages.
+    pub fn latency(&self) -> &LatencyEstimator {
+        &self.latency
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn is_peer_silent(&self) -> bool {
+        self.silent
+    }
+
+    /// Instant at which `poll` should be called next.
+    pub fn next_deadline(&self) -> Instant {
+        let deadline = self.keep_alive.next_deadline();
+        if self.silent {
+            deadline
+        } else {
+            deadline.min(self.keep_alive.last_received() + self.timeout)
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Option<HeartbeatEvent> {
+        let silence = self.keep_alive.silence(now);
+        if !self.silent && silence >= self.timeout {
+            log::debug!("peer silent for {:?}", silence);
+            self.silent = true;
+            return Some(HeartbeatEvent::PeerSilent(silence));
+        }
+
+        self.keep_alive
+            .poll(now)
+            .map(|req| HeartbeatEvent::Send(NowNetworkMsg::from(req)))
+    }
+
+    /// Records a packet received from the peer. Keep-alive requests are answered.
+    pub fn on_body(&mut self, body: &NowBody, now: Instant) -> Option<NowNetworkMsg> {
+        self.silent = false;
+        self.keep_alive.on_activity(now);
+        self.latency.on_body(body, now);
+
+        match body {
+            NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveReq(req))) => {
+                Some(NowNetworkKeepAliveRspMsg::answer(req).into())
+            }
+            NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveRsp(rsp))) => {
+                if let Some(rtt) = self.keep_alive.on_keep_alive_rsp(rsp, now) {
+                    self.latency.on_rtt_sample(rtt);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::NowNetworkKeepAliveReqMsg;
+
+    fn network_body(msg: NowNetworkMsg) -> NowBody<'static> {
+        NowBody::Message(NowMessage::Network(msg))
+    }
+
+    #[test]
+    fn keep_alive_sent_on_interval() {
+        let t0 = Instant::now();
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(1), Duration::from_secs(5), t0);
+
+        let req = match heartbeat.poll(t0) {
+            Some(HeartbeatEvent::Send(NowNetworkMsg::KeepAliveReq(req))) => req,
+            other => panic!("expected a keep-alive request and got {:?}", other),
+        };
+        assert!(heartbeat.poll(t0).is_none());
+        assert_eq!(heartbeat.next_deadline(), t0 + Duration::from_secs(1));
+
+        let rsp = NowNetworkKeepAliveRspMsg::answer(&req);
+        let answer = heartbeat.on_body(&network_body(rsp.into()), t0 + Duration::from_millis(50));
+        assert!(answer.is_none());
+        assert_eq!(heartbeat.keep_alive().pending_count(), 0);
+
+        let peer_req = NowNetworkKeepAliveReqMsg::new(7, 100);
+        match heartbeat.on_body(&network_body(peer_req.into()), t0 + Duration::from_millis(60)) {
+            Some(NowNetworkMsg::KeepAliveRsp(rsp)) => assert_eq!(rsp.sequence_id, 7),
+            other => panic!("expected a keep-alive response and got {:?}", other),
+        }
+        // peer request taken at 100ms on its clock, half the 50ms round trip before being received
+        assert_eq!(heartbeat.latency().clock_offset(), Some(-65));
+    }
+
+    #[test]
+    fn silent_peer_reported_once() {
+        let t0 = Instant::now();
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(1), Duration::from_secs(3), t0);
+        assert!(heartbeat.poll(t0).is_some());
+
+        let t3 = t0 + Duration::from_secs(3);
+        assert_eq!(heartbeat.next_deadline(), t0 + Duration::from_secs(1));
+        match heartbeat.poll(t3) {
+            Some(HeartbeatEvent::PeerSilent(silence)) => assert_eq!(silence, Duration::from_secs(3)),
+            other => panic!("expected a silent peer event and got {:?}", other),
+        }
+        assert!(heartbeat.is_peer_silent());
+        match heartbeat.poll(t3) {
+            Some(HeartbeatEvent::Send(_)) => {}
+            other => panic!("expected a keep-alive request and got {:?}", other),
+        }
+        assert!(heartbeat.poll(t3).is_none());
+
+        let req = NowNetworkKeepAliveReqMsg::new(0, 0);
+        heartbeat.on_body(&network_body(req.into()), t3);
+        assert!(!heartbeat.is_peer_silent());
+        assert_eq!(heartbeat.next_deadline(), t3 + Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{
+    message::{NowBody, NowMessage, NowNetworkMsg},
+    sm::RttEstimator,
+};
+use alloc::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Latency figures at a given time, for adaptive codecs and latency indicators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// Smoothed round trip time.
+    pub rtt: Option<Duration>,
+    /// Round trip time variation.
+    pub jitter: Duration,
+    /// Milliseconds to add to a peer timestamp to get the local one, see `LatencyEstimator::clock_offset`.
+    pub clock_offset: Option<i64>,
+    /// Parts per million the local clock gains on the peer clock, see `LatencyEstimator::clock_skew_ppm`.
+    pub clock_skew_ppm: Option<i64>,
+}
+
+/// Estimates round trip time, jitter and the clock of the peer relative to the local one.
+///
+/// Round trip times are measured by the caller (typically from keep-alive responses, see `Heartbeat`),
+/// while the clock of the peer is derived from the timestamps it sends: keep-alive and probe
+/// requests carry the milliseconds elapsed since an epoch of its own.
+///
+/// A timestamp is received one-way delay after it was taken: the lowest offset observed over
+/// the sampling window, less half the round trip time, is taken as the clock offset. Timestamps
+/// wrapping around (after about 49 days) aren't handled.
+#[derive(Debug, Clone)]
+pub struct LatencyEstimator {
+    epoch: Instant,
+    rtt: RttEstimator,
+    window: Duration,
+    offset_samples: VecDeque<(Instant, i64)>,
+    /// First lowest sample, from which the skew is measured.
+    reference_sample: Option<(Instant, i64)>,
+}
+
+impl LatencyEstimator {
+    pub const DEFAULT_WINDOW: Duration = Duration::from_secs(30);
+
+    /// Clock skew isn't estimated before offsets span this period.
+    pub const MIN_SKEW_PERIOD: Duration = Duration::from_secs(10);
+
+    const MAX_OFFSET_SAMPLES: usize = 64;
+
+    pub fn new(now: Instant) -> Self {
+        Self {
+            epoch: now,
+            rtt: RttEstimator::new(),
+            window: Self::DEFAULT_WINDOW,
+            offset_samples: VecDeque::new(),
+            reference_sample: None,
+        }
+    }
+
+    /// Period over which the lowest offset is taken. Default is 30 seconds.
+    pub fn window(self, window: Duration) -> Self {
+        Self { window, ..self }
+    }
+
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.rtt.variation()
+    }
+
+    pub fn on_rtt_sample(&mut self, rtt: Duration) {
+        self.rtt.update(rtt);
+    }
+
+    /// Records a timestamp of the peer, in milliseconds, received at `now`.
+    pub fn on_peer_timestamp(&mut self, timestamp: u32, now: Instant) {
+        let sample = self.__local_timestamp(now) - i64::from(timestamp);
+
+        while let Some((received, _)) = self.offset_samples.front() {
+            if now.saturating_duration_since(*received) <= self.window
+                && self.offset_samples.len() < Self::MAX_OFFSET_SAMPLES
+            {
+                break;
+            }
+            self.offset_samples.pop_front();
+        }
+        self.offset_samples.push_back((now, sample));
+
+        if self.reference_sample.is_none() {
+            self.reference_sample = Some((now, sample));
+        }
+    }
+
+    /// Records the timestamps carried by keep-alive and probe requests of the peer.
+    pub fn on_body(&mut self, body: &NowBody<'_>, now: Instant) {
+        match body {
+            NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveReq(req))) => {
+                self.on_peer_timestamp(req.timestamp, now)
+            }
+            NowBody::Message(NowMessage::Network(NowNetworkMsg::ProbeReq(req))) => {
+                self.on_peer_timestamp(req.timestamp, now)
+            }
+            _ => {}
+        }
+    }
+
+    /// Milliseconds to add to a peer timestamp to get the local one, relative to the epoch of the estimator.
+    pub fn clock_offset(&self) -> Option<i64> {
+        let lowest = self.__lowest_sample()?;
+        let one_way_delay = self.rtt.smoothed().map_or(0, |rtt| rtt.as_millis() as i64 / 2);
+        Some(lowest - one_way_delay)
+    }
+
+    /// Parts per million the local clock gains on the peer clock, negative if it loses.
+    pub fn clock_skew_ppm(&self) -> Option<i64> {
+        let (reference_time, reference_sample) = self.reference_sample?;
+        let (latest_time, _) = self.offset_samples.back()?;
+        let period = latest_time.saturating_duration_since(reference_time);
+        if period < Self::MIN_SKEW_PERIOD {
+            return None;
+        }
+
+        let drift = self.__lowest_sample()? - reference_sample;
+        Some(drift * 1_000_000 / period.as_millis() as i64)
+    }
+
+    /// Local instant at which the peer took `timestamp`, once the clock offset is known.
+    pub fn peer_instant(&self, timestamp: u32) -> Option<Instant> {
+        let local = i64::from(timestamp) + self.clock_offset()?;
+        if local >= 0 {
+            Some(self.epoch + Duration::from_millis(local as u64))
+        } else {
+            self.epoch.checked_sub(Duration::from_millis(local.unsigned_abs()))
+        }
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            rtt: self.rtt.smoothed(),
+            jitter: self.jitter(),
+            clock_offset: self.clock_offset(),
+            clock_skew_ppm: self.clock_skew_ppm(),
+        }
+    }
+
+    fn __lowest_sample(&self) -> Option<i64> {
+        self.offset_samples.iter().map(|(_, sample)| *sample).min()
+    }
+
+    fn __local_timestamp(&self, now: Instant) -> i64 {
+        now.saturating_duration_since(self.epoch).as_millis() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_and_skew_from_peer_timestamps() {
+        let t0 = Instant::now();
+        let mut latency = LatencyEstimator::new(t0).window(Duration::from_secs(1));
+        assert_eq!(latency.clock_offset(), None);
+
+        latency.on_rtt_sample(Duration::from_millis(40));
+        // peer clock is 5 seconds ahead, timestamps are received 20ms after they were taken
+        latency.on_peer_timestamp(5_000, t0 + Duration::from_millis(20));
+        // queued behind other packets
+        latency.on_peer_timestamp(5_100, t0 + Duration::from_millis(180));
+        assert_eq!(latency.clock_offset(), Some(-5_000));
+        assert_eq!(latency.peer_instant(5_500), Some(t0 + Duration::from_millis(500)));
+        assert_eq!(latency.clock_skew_ppm(), None);
+
+        // local clock gained 1ms on the peer one over 30 seconds
+        latency.on_peer_timestamp(34_999, t0 + Duration::from_millis(30_020));
+        assert_eq!(latency.clock_offset(), Some(-4_999));
+        assert_eq!(latency.clock_skew_ppm(), Some(33));
+
+        let snapshot = latency.snapshot();
+        assert_eq!(snapshot.rtt, Some(Duration::from_millis(40)));
+        assert_eq!(snapshot.jitter, Duration::from_millis(20));
+    }
+}
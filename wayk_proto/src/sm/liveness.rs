@@ -0,0 +1,129 @@
+use crate::message::{NowNetworkKeepAliveReqMsg, NowNetworkKeepAliveRspMsg};
+use alloc::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Keeps track of outgoing keep-alive requests and of the last time the peer was heard from.
+///
+/// This is a passive component: caller is responsible for feeding the current time
+/// and for sending the messages it produces.
+#[derive(Debug, Clone)]
+pub struct KeepAliveTracker {
+    interval: Duration,
+    epoch: Instant,
+    next_sequence_id: u16,
+    pending: BTreeMap<u16, Instant>,
+    last_sent: Option<Instant>,
+    last_received: Instant,
+}
+
+impl KeepAliveTracker {
+    /// Maximum number of unanswered keep-alive requests remembered.
+    const MAX_PENDING: usize = 16;
+
+    pub fn new(interval: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            epoch: now,
+            next_sequence_id: 0,
+            pending: BTreeMap::new(),
+            last_sent: None,
+            last_received: now,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Instant at which next keep-alive request is due.
+    pub fn next_deadline(&self) -> Instant {
+        match self.last_sent {
+            Some(last_sent) => last_sent + self.interval,
+            None => self.epoch,
+        }
+    }
+
+    /// Returns a keep-alive request to send if one is due.
+    pub fn poll(&mut self, now: Instant) -> Option<NowNetworkKeepAliveReqMsg> {
+        if now < self.next_deadline() {
+            return None;
+        }
+
+        let sequence_id = self.next_sequence_id;
+        self.next_sequence_id = self.next_sequence_id.wrapping_add(1);
+        self.last_sent = Some(now);
+
+        if self.pending.len() >= Self::MAX_PENDING {
+            let oldest = self.pending.iter().min_by_key(|(_, sent)| **sent).map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                self.pending.remove(&oldest);
+            }
+        }
+        self.pending.insert(sequence_id, now);
+
+        Some(NowNetworkKeepAliveReqMsg::new(sequence_id, self.timestamp(now)))
+    }
+
+    /// Records that something (anything) was received from the peer.
+    pub fn on_activity(&mut self, now: Instant) {
+        if now > self.last_received {
+            self.last_received = now;
+        }
+    }
+
+    /// Handles a keep-alive response and returns the measured round trip time if the
+    /// response matches an outstanding request.
+    pub fn on_keep_alive_rsp(&mut self, msg: &NowNetworkKeepAliveRspMsg, now: Instant) -> Option<Duration> {
+        self.on_activity(now);
+        self.pending
+            .remove(&msg.sequence_id)
+            .map(|sent| now.saturating_duration_since(sent))
+    }
+
+    pub fn last_received(&self) -> Instant {
+        self.last_received
+    }
+
+    /// Time elapsed since the peer was last heard from.
+    pub fn silence(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_received)
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn timestamp(&self, now: Instant) -> u32 {
+        now.saturating_duration_since(self.epoch).as_millis() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_alive_round_trip() {
+        let t0 = Instant::now();
+        let mut tracker = KeepAliveTracker::new(Duration::from_secs(1), t0);
+
+        let req = tracker.poll(t0).unwrap();
+        assert!(tracker.poll(t0 + Duration::from_millis(500)).is_none());
+
+        let rsp = NowNetworkKeepAliveRspMsg::answer(&req);
+        let rtt = tracker.on_keep_alive_rsp(&rsp, t0 + Duration::from_millis(80));
+        assert_eq!(rtt, Some(Duration::from_millis(80)));
+        assert_eq!(tracker.pending_count(), 0);
+
+        // duplicated responses are ignored
+        assert_eq!(tracker.on_keep_alive_rsp(&rsp, t0 + Duration::from_millis(90)), None);
+
+        let req = tracker.poll(t0 + Duration::from_secs(1)).unwrap();
+        assert_eq!(req.sequence_id, 1);
+        assert_eq!(req.timestamp, 1000);
+    }
+}
@@ -0,0 +1,282 @@
+use crate::{message::ChannelName, sm::RttEstimator};
+use alloc::collections::BTreeMap;
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficCounters {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+}
+
+impl TrafficCounters {
+    pub(crate) fn record_in(&mut self, len: usize) {
+        self.bytes_in += len as u64;
+        self.messages_in += 1;
+    }
+
+    pub(crate) fn record_out(&mut self, len: usize) {
+        self.bytes_out += len as u64;
+        self.messages_out += 1;
+    }
+}
+
+/// State of the session metrics at a given time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Traffic of the whole session, framing included.
+    pub total: TrafficCounters,
+    /// Traffic of each virtual channel. Traffic outside of virtual channels is only counted in `total`.
+    pub channels: BTreeMap<ChannelName, TrafficCounters>,
+    /// Packets received per second, measured over the last complete window.
+    pub messages_in_per_sec: f64,
+    /// Packets sent per second, measured over the last complete window.
+    pub messages_out_per_sec: f64,
+    /// Smoothed round trip time, once measured.
+    pub rtt: Option<Duration>,
+    pub reconnections: u32,
+}
+
+struct Subscriber {
+    interval: Duration,
+    last_notified: Option<Instant>,
+    callback: Box<dyn FnMut(&MetricsSnapshot)>,
+}
+
+impl Subscriber {
+    fn is_due(&self, now: Instant) -> bool {
+        self.last_notified
+            .map(|last| now.saturating_duration_since(last) >= self.interval)
+            .unwrap_or(true)
+    }
+}
+
+struct MetricsState {
+    total: TrafficCounters,
+    channels: BTreeMap<ChannelName, TrafficCounters>,
+    rate_window: Duration,
+    window_start: Instant,
+    window_messages_in: u64,
+    window_messages_out: u64,
+    messages_in_per_sec: f64,
+    messages_out_per_sec: f64,
+    rtt: RttEstimator,
+    reconnections: u32,
+    subscribers: Vec<Subscriber>,
+}
+
+impl MetricsState {
+    fn update_rates(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < self.rate_window {
+            return;
+        }
+
+        let secs = elapsed.as_secs_f64();
+        self.messages_in_per_sec = self.window_messages_in as f64 / secs;
+        self.messages_out_per_sec = self.window_messages_out as f64 / secs;
+        self.window_messages_in = 0;
+        self.window_messages_out = 0;
+        self.window_start = now;
+    }
+
+    fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            total: self.total,
+            channels: self.channels.clone(),
+            messages_in_per_sec: self.messages_in_per_sec,
+            messages_out_per_sec: self.messages_out_per_sec,
+            rtt: self.rtt.smoothed(),
+            reconnections: self.reconnections,
+        }
+    }
+}
+
+/// Shared handle on the metrics of a session.
+///
+/// Clones of the handle share the same counters: a handle given to a transport is updated as packets
+/// are sent and received, while another one is polled with [`snapshot`](#method.snapshot) or notifies
+/// the callbacks registered with [`subscribe`](#method.subscribe).
+#[derive(Clone)]
+pub struct SessionMetrics(Rc<RefCell<MetricsState>>);
+
+impl core::fmt::Debug for SessionMetrics {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("SessionMetrics")
+            .field(&self.0.borrow().snapshot())
+            .finish()
+    }
+}
+
+impl SessionMetrics {
+    pub const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+    pub fn new(now: Instant) -> Self {
+        Self(Rc::new(RefCell::new(MetricsState {
+            total: TrafficCounters::default(),
+            channels: BTreeMap::new(),
+            rate_window: Self::DEFAULT_RATE_WINDOW,
+            window_start: now,
+            window_messages_in: 0,
+            window_messages_out: 0,
+            messages_in_per_sec: 0.0,
+            messages_out_per_sec: 0.0,
+            rtt: RttEstimator::new(),
+            reconnections: 0,
+            subscribers: Vec::new(),
+        })))
+    }
+
+    /// Duration over which message rates are measured.
+    pub fn set_rate_window(&self, rate_window: Duration) {
+        self.0.borrow_mut().rate_window = rate_window.max(Duration::from_millis(1));
+    }
+
+    pub fn snapshot(&self, now: Instant) -> MetricsSnapshot {
+        let mut state = self.0.borrow_mut();
+        state.update_rates(now);
+        state.snapshot()
+    }
+
+    /// Calls `callback` with a snapshot whenever metrics are updated, at most once per `interval`.
+    pub fn subscribe<F>(&self, interval: Duration, callback: F)
+    where
+        F: FnMut(&MetricsSnapshot) + 'static,
+    {
+        self.0.borrow_mut().subscribers.push(Subscriber {
+            interval,
+            last_notified: None,
+            callback: Box::new(callback),
+        });
+    }
+
+    /// Records a packet of `len` bytes received on `channel` (`None` outside of virtual channels).
+    pub fn on_received(&self, channel: Option<&ChannelName>, len: usize, now: Instant) {
+        {
+            let mut state = self.0.borrow_mut();
+            state.update_rates(now);
+            state.total.record_in(len);
+            state.window_messages_in += 1;
+            if let Some(channel) = channel {
+                state.channels.entry(channel.clone()).or_default().record_in(len);
+            }
+        }
+        self.notify(now);
+    }
+
+    /// Records a packet of `len` bytes sent on `channel` (`None` outside of virtual channels).
+    pub fn on_sent(&self, channel: Option<&ChannelName>, len: usize, now: Instant) {
+        {
+            let mut state = self.0.borrow_mut();
+            state.update_rates(now);
+            state.total.record_out(len);
+            state.window_messages_out += 1;
+            if let Some(channel) = channel {
+                state.channels.entry(channel.clone()).or_default().record_out(len);
+            }
+        }
+        self.notify(now);
+    }
+
+    pub fn on_rtt_sample(&self, rtt: Duration, now: Instant) {
+        self.0.borrow_mut().rtt.update(rtt);
+        self.notify(now);
+    }
+
+    pub fn on_reconnection(&self, now: Instant) {
+        self.0.borrow_mut().reconnections += 1;
+        self.notify(now);
+    }
+
+    fn notify(&self, now: Instant) {
+        // callbacks are taken out so that they can use the handle
+        let (snapshot, mut subscribers) = {
+            let mut state = self.0.borrow_mut();
+            if !state.subscribers.iter().any(|subscriber| subscriber.is_due(now)) {
+                return;
+            }
+            (state.snapshot(), core::mem::take(&mut state.subscribers))
+        };
+
+        for subscriber in &mut subscribers {
+            if subscriber.is_due(now) {
+                subscriber.last_notified = Some(now);
+                (subscriber.callback)(&snapshot);
+            }
+        }
+
+        let mut state = self.0.borrow_mut();
+        subscribers.append(&mut state.subscribers);
+        state.subscribers = subscribers;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_traffic_per_channel() {
+        let now = Instant::now();
+        let metrics = SessionMetrics::new(now);
+        let handle = metrics.clone();
+
+        handle.on_sent(None, 100, now);
+        handle.on_sent(Some(&ChannelName::Clipboard), 1_000, now);
+        handle.on_received(Some(&ChannelName::Clipboard), 20, now);
+        handle.on_rtt_sample(Duration::from_millis(40), now);
+        handle.on_reconnection(now);
+
+        let snapshot = metrics.snapshot(now);
+        assert_eq!(
+            snapshot.total,
+            TrafficCounters {
+                bytes_in: 20,
+                bytes_out: 1_100,
+                messages_in: 1,
+                messages_out: 2,
+            }
+        );
+        assert_eq!(snapshot.channels.len(), 1);
+        assert_eq!(snapshot.channels[&ChannelName::Clipboard].bytes_out, 1_000);
+        assert_eq!(snapshot.rtt, Some(Duration::from_millis(40)));
+        assert_eq!(snapshot.reconnections, 1);
+    }
+
+    #[test]
+    fn message_rates_measured_over_window() {
+        let now = Instant::now();
+        let metrics = SessionMetrics::new(now);
+        metrics.set_rate_window(Duration::from_secs(2));
+
+        for _ in 0..10 {
+            metrics.on_sent(None, 10, now);
+        }
+        assert_eq!(metrics.snapshot(now + Duration::from_secs(1)).messages_out_per_sec, 0.0);
+
+        let snapshot = metrics.snapshot(now + Duration::from_secs(2));
+        assert_eq!(snapshot.messages_out_per_sec, 5.0);
+        assert_eq!(snapshot.messages_in_per_sec, 0.0);
+    }
+
+    #[test]
+    fn subscribers_notified_on_interval() {
+        let now = Instant::now();
+        let metrics = SessionMetrics::new(now);
+        let notified = Rc::new(RefCell::new(Vec::new()));
+        let notified_by_callback = Rc::clone(&notified);
+        metrics.subscribe(Duration::from_secs(1), move |snapshot| {
+            notified_by_callback.borrow_mut().push(snapshot.total.messages_out)
+        });
+
+        metrics.on_sent(None, 10, now);
+        metrics.on_sent(None, 10, now + Duration::from_millis(500));
+        metrics.on_sent(None, 10, now + Duration::from_secs(1));
+        assert_eq!(*notified.borrow(), vec![1, 3]);
+    }
+}
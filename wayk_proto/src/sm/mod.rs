@@ -1,16 +1,93 @@
+pub mod access_control;
+pub mod auto_responder;
+pub mod channel_lifecycle;
+pub mod channel_liveness;
 pub mod client_channels;
 /** STATE MACHINE **/
 pub mod client_connection;
+#[cfg(test)]
+mod conformance;
+pub mod connection;
+pub mod connection_quality;
+pub mod curtain;
+pub mod custom_channel;
+pub mod device_redirection;
+pub mod display_power;
+pub mod drive_redirection;
+pub mod file_transfer_policy;
+pub mod flow_control;
+pub mod fragmentation;
+pub mod handoff;
+pub mod heartbeat;
+pub mod latency;
+pub mod liveness;
+pub mod metrics;
+#[cfg(feature = "pcsc")]
+pub mod pcsc_backend;
+pub mod port_forwarding;
+pub mod printer_redirection;
+pub mod rate_limit;
+pub mod request_tracker;
+pub mod rtt;
+pub mod server_channels;
+pub mod server_connection;
+pub mod session_guard;
+pub mod smartcard_redirection;
+pub mod step_timeouts;
+pub mod surface_diff;
+pub mod surface_id_allocator;
+pub mod surface_manager;
+pub mod surface_transactions;
+#[cfg(test)]
+mod test_support;
+pub mod tunnel_channel;
 
 // re-export
+pub use access_control::*;
+pub use auto_responder::*;
+pub use channel_lifecycle::*;
+pub use channel_liveness::*;
 pub use client_channels::*;
 pub use client_connection::*;
+pub use connection::*;
+pub use connection_quality::*;
+pub use curtain::*;
+pub use custom_channel::*;
+pub use device_redirection::*;
+pub use display_power::*;
+pub use drive_redirection::*;
+pub use file_transfer_policy::*;
+pub use flow_control::*;
+pub use fragmentation::*;
+pub use handoff::*;
+pub use heartbeat::*;
+pub use latency::*;
+pub use liveness::*;
+pub use metrics::*;
+#[cfg(feature = "pcsc")]
+pub use pcsc_backend::*;
+pub use port_forwarding::*;
+pub use printer_redirection::*;
+pub use rate_limit::*;
+pub use request_tracker::*;
+pub use rtt::*;
+pub use server_channels::*;
+pub use server_connection::*;
+pub use session_guard::*;
+pub use smartcard_redirection::*;
+pub use step_timeouts::*;
+pub use surface_diff::*;
+pub use surface_id_allocator::*;
+pub use surface_manager::*;
+pub use surface_transactions::*;
+pub use tunnel_channel::*;
 
 use crate::{
     error::ProtoError,
     message::{AuthType, ChannelName, NowCapset, NowChannelDef, NowMessage, NowVirtualChannel},
+    version::{NowProtocolVersion, NowProtocolVersionRange},
 };
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, time::Instant};
 
 // === connection sequence ===
 
@@ -27,6 +104,17 @@ pub trait ConnectionSM {
     fn is_running(&self) -> bool {
         !self.is_terminated()
     }
+
+    /// Step of the connection sequence, for sequences made of steps.
+    fn current_step(&self) -> Option<ConnectionState> {
+        None
+    }
+
+    /// Instant at which the state machine is to be updated again even if no packet is received,
+    /// typically to poll the answer of the local user.
+    fn next_deadline(&self) -> Option<Instant> {
+        None
+    }
 }
 
 sa::assert_obj_safe!(ConnectionSM);
@@ -34,7 +122,42 @@ sa::assert_obj_safe!(ConnectionSM);
 pub struct ConnectionSMSharedData {
     pub available_auth_types: Vec<AuthType>,
     pub capabilities: Vec<NowCapset<'static>>,
+    /// Capabilities advertised by the peer, once received. Capsets unknown to this implementation are not kept.
+    pub peer_capabilities: Vec<NowCapset<'static>>,
     pub channels: Vec<NowChannelDef>,
+    /// Connection takes over an existing session: authentication is skipped.
+    pub handoff: bool,
+    /// Transport upgraded to TLS once negotiation completes: requested by the client, agreed by the server.
+    pub tls: bool,
+    /// DER certificate presented by the peer once the transport is upgraded to TLS,
+    /// for the authentication to verify its fingerprint.
+    pub peer_certificate: Option<Vec<u8>>,
+    /// Session the connection is associated with, once known.
+    /// Set before the connection sequence to re-attach to a previous session.
+    pub session_id: Option<u32>,
+    /// Protocol version of the peer, once its handshake is received.
+    pub peer_version: Option<NowProtocolVersion>,
+    /// Protocol versions supported by this side.
+    pub versions: NowProtocolVersionRange,
+    /// Version selected in the handshake, from which the features enabled for the session are derived.
+    pub negotiated_version: Option<NowProtocolVersion>,
+}
+
+impl ConnectionSMSharedData {
+    /// Drops the channels unavailable with the negotiated version.
+    pub(crate) fn retain_supported_channels(&mut self) {
+        let version = match self.negotiated_version {
+            Some(version) => version,
+            None => return,
+        };
+        self.channels.retain(|def| {
+            let supported = version.supports_channel(&def.name);
+            if !supported {
+                log::debug!("channel {} unavailable with version {}", def.name.as_str(), version);
+            }
+            supported
+        });
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -127,6 +250,19 @@ pub trait VirtualChannelSM {
     fn is_running(&self) -> bool {
         !self.is_terminated()
     }
+
+    /// called when the peer updates its capabilities after the connection sequence,
+    /// typically enabling a codec or a feature of the channel later.
+    fn on_capabilities_updated(&mut self, peer_capabilities: &[NowCapset<'static>]) {
+        #![allow(unused_variables)]
+    }
+
+    /// called when the channel is opened, by the connection sequence or later on, again after a reconnection.
+    fn on_open(&mut self) {}
+
+    /// called when the channel is closed by either peer or when the session terminates.
+    /// Requests still pending won't be answered and are to be dropped.
+    fn on_close(&mut self) {}
 }
 
 sa::assert_obj_safe!(VirtualChannelSM);
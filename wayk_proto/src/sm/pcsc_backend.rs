@@ -0,0 +1,242 @@
+use crate::{
+    message::{NowSmartcardStatus, NowStatusCode, SmartcardDisposition, SmartcardProtocol, SmartcardStateFlags},
+    sm::SmartcardBackend,
+};
+use alloc::collections::BTreeMap;
+use pcsc::{Card, Context, Disposition, Error, Protocol, Protocols, Scope, ShareMode, Status};
+use std::ffi::CString;
+
+/// Card connected by the backend, and whether the peer began a transaction on it.
+///
+/// The transaction of the pcsc crate borrows the card, while transactions are begun and ended by
+/// separate requests of the peer: each operation within a transaction of the peer runs in its own
+/// PC/SC transaction instead, and the disposition requested by the peer is applied when it ends.
+struct ConnectedCard {
+    card: Card,
+    transacted: bool,
+}
+
+impl ConnectedCard {
+    fn new(card: Card) -> Self {
+        Self {
+            card,
+            transacted: false,
+        }
+    }
+
+    /// Runs `operation` on the card, within a PC/SC transaction if the peer began one.
+    fn with_card<T>(&mut self, operation: impl FnOnce(&Card) -> Result<T, Error>) -> Result<T, Error> {
+        if !self.transacted {
+            return operation(&self.card);
+        }
+
+        let transaction = self.card.transaction()?;
+        let result = operation(&transaction);
+        transaction.end(Disposition::LeaveCard).map_err(|(_, e)| e)?;
+        result
+    }
+
+    fn begin_transaction(&mut self) -> Result<(), Error> {
+        if self.transacted {
+            return Err(Error::SharingViolation);
+        }
+        // fails right away if another application holds the card
+        self.card
+            .transaction()?
+            .end(Disposition::LeaveCard)
+            .map_err(|(_, e)| e)?;
+        self.transacted = true;
+        Ok(())
+    }
+
+    fn end_transaction(&mut self, disposition: Disposition) -> Result<(), Error> {
+        if !self.transacted {
+            return Err(Error::NotTransacted);
+        }
+        self.card.transaction()?.end(disposition).map_err(|(_, e)| e)?;
+        self.transacted = false;
+        Ok(())
+    }
+}
+
+/// [`SmartcardBackend`](trait.SmartcardBackend.html) of the local readers, through the pcsc crate.
+///
+/// Transactions begun by the peer make each of its operations exclusive, but other applications may
+/// access the card between two of them. Cards are reset when the backend is dropped while connected.
+pub struct PcscBackend {
+    context: Context,
+    cards: BTreeMap<u32, ConnectedCard>,
+    next_card: u32,
+}
+
+impl PcscBackend {
+    /// Establishes a PC/SC context in the user scope.
+    pub fn new() -> Result<Self, NowStatusCode> {
+        let context = Context::establish(Scope::User).map_err(status_code)?;
+        Ok(Self::with_context(context))
+    }
+
+    pub fn with_context(context: Context) -> Self {
+        Self {
+            context,
+            cards: BTreeMap::new(),
+            next_card: 1,
+        }
+    }
+
+    fn __card(&mut self, card: u32) -> Result<&mut ConnectedCard, NowStatusCode> {
+        self.cards.get_mut(&card).ok_or(NowStatusCode::InvalidRequest)
+    }
+}
+
+impl SmartcardBackend for PcscBackend {
+    fn list_readers(&mut self) -> Result<Vec<String>, NowStatusCode> {
+        match self.context.list_readers_owned() {
+            Ok(readers) => Ok(readers
+                .into_iter()
+                .map(|reader| reader.to_string_lossy().into_owned())
+                .collect()),
+            Err(Error::NoReadersAvailable) => Ok(Vec::new()),
+            Err(e) => Err(status_code(e)),
+        }
+    }
+
+    fn connect(&mut self, reader: &str, exclusive: bool) -> Result<u32, NowStatusCode> {
+        let reader = CString::new(reader).map_err(|_| NowStatusCode::InvalidRequest)?;
+        let share_mode = if exclusive {
+            ShareMode::Exclusive
+        } else {
+            ShareMode::Shared
+        };
+        let card = self
+            .context
+            .connect(&reader, share_mode, Protocols::ANY)
+            .map_err(status_code)?;
+
+        let mut handle = self.next_card;
+        while self.cards.contains_key(&handle) {
+            handle = handle.wrapping_add(1);
+        }
+        self.next_card = handle.wrapping_add(1);
+        self.cards.insert(handle, ConnectedCard::new(card));
+        Ok(handle)
+    }
+
+    fn disconnect(&mut self, card: u32, disposition: SmartcardDisposition) -> Result<(), NowStatusCode> {
+        let connected = self.cards.remove(&card).ok_or(NowStatusCode::InvalidRequest)?;
+        connected
+            .card
+            .disconnect(disposition_of(disposition))
+            .map_err(|(_, e)| status_code(e))
+    }
+
+    fn status(&mut self, card: u32) -> Result<NowSmartcardStatus, NowStatusCode> {
+        let status = self
+            .__card(card)?
+            .with_card(|card| card.status2_owned())
+            .map_err(status_code)?;
+
+        let mut state = SmartcardStateFlags::new_empty();
+        if status
+            .status()
+            .intersects(Status::PRESENT | Status::SWALLOWED | Status::POWERED)
+        {
+            state.set_present();
+        }
+        if status
+            .status()
+            .intersects(Status::POWERED | Status::NEGOTIABLE | Status::SPECIFIC)
+        {
+            state.set_powered();
+        }
+        let protocol = match status.protocol2() {
+            Some(Protocol::T0) => SmartcardProtocol::T0,
+            Some(Protocol::T1) => SmartcardProtocol::T1,
+            Some(Protocol::RAW) => SmartcardProtocol::Raw,
+            None => SmartcardProtocol::Undefined,
+        };
+        Ok(NowSmartcardStatus::new(state, protocol, status.atr().to_vec()))
+    }
+
+    fn transmit(&mut self, card: u32, apdu: &[u8], max_response_len: u32) -> Result<Vec<u8>, NowStatusCode> {
+        let mut response = vec![0; (max_response_len as usize).min(pcsc::MAX_BUFFER_SIZE_EXTENDED)];
+        let len = self
+            .__card(card)?
+            .with_card(|card| card.transmit(apdu, &mut response).map(|response| response.len()))
+            .map_err(status_code)?;
+        response.truncate(len);
+        Ok(response)
+    }
+
+    fn begin_transaction(&mut self, card: u32) -> Result<(), NowStatusCode> {
+        self.__card(card)?.begin_transaction().map_err(status_code)
+    }
+
+    fn end_transaction(&mut self, card: u32, disposition: SmartcardDisposition) -> Result<(), NowStatusCode> {
+        self.__card(card)?
+            .end_transaction(disposition_of(disposition))
+            .map_err(status_code)
+    }
+
+    fn control(&mut self, card: u32, code: u32, input: &[u8], max_output_len: u32) -> Result<Vec<u8>, NowStatusCode> {
+        let mut output = vec![0; (max_output_len as usize).min(pcsc::MAX_BUFFER_SIZE_EXTENDED)];
+        let len = self
+            .__card(card)?
+            .with_card(|card| {
+                card.control(pcsc::ffi::DWORD::from(code), input, &mut output)
+                    .map(|output| output.len())
+            })
+            .map_err(status_code)?;
+        output.truncate(len);
+        Ok(output)
+    }
+}
+
+fn disposition_of(disposition: SmartcardDisposition) -> Disposition {
+    match disposition {
+        SmartcardDisposition::Leave => Disposition::LeaveCard,
+        SmartcardDisposition::Reset => Disposition::ResetCard,
+        SmartcardDisposition::Unpower => Disposition::UnpowerCard,
+        SmartcardDisposition::Eject => Disposition::EjectCard,
+    }
+}
+
+/// Closest status of a PC/SC error, as documented by the [`SmartcardBackend`](trait.SmartcardBackend.html).
+fn status_code(error: Error) -> NowStatusCode {
+    log::debug!("PC/SC error: {}", error);
+    match error {
+        Error::UnknownReader
+        | Error::ReaderUnavailable
+        | Error::NoReadersAvailable
+        | Error::NoSmartcard
+        | Error::RemovedCard
+        | Error::UnknownCard => NowStatusCode::NotFound,
+        Error::SharingViolation | Error::ServerTooBusy | Error::Timeout => NowStatusCode::Busy,
+        Error::InvalidHandle
+        | Error::InvalidParameter
+        | Error::InvalidValue
+        | Error::InsufficientBuffer
+        | Error::NotTransacted
+        | Error::ProtoMismatch => NowStatusCode::InvalidRequest,
+        Error::UnsupportedFeature | Error::CardUnsupported | Error::ReaderUnsupported | Error::UnsupportedCard => {
+            NowStatusCode::NotSupported
+        }
+        Error::NoAccess | Error::SecurityViolation | Error::WrongChv | Error::ChvBlocked => NowStatusCode::AccessDenied,
+        _ => NowStatusCode::Failure,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcsc_errors_mapped_to_closest_status() {
+        assert_eq!(status_code(Error::NoSmartcard), NowStatusCode::NotFound);
+        assert_eq!(status_code(Error::SharingViolation), NowStatusCode::Busy);
+        assert_eq!(status_code(Error::NotTransacted), NowStatusCode::InvalidRequest);
+        assert_eq!(status_code(Error::UnsupportedFeature), NowStatusCode::NotSupported);
+        assert_eq!(status_code(Error::SecurityViolation), NowStatusCode::AccessDenied);
+        assert_eq!(status_code(Error::CommError), NowStatusCode::Failure);
+    }
+}
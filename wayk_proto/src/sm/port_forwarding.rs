@@ -0,0 +1,527 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{NowStatusCode, NowString256, NowTunnelListenMsg, NowTunnelListenRspMsg, NowTunnelMsg},
+    proxy::TargetAddr,
+    sm::{TunnelChannel, TunnelStream},
+};
+use alloc::collections::{BTreeMap, VecDeque};
+use core::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ForwardId(u16);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardKind {
+    /// Connections accepted locally on `listen_port` reach the target from the peer (`ssh -L`).
+    Local { listen_port: u16 },
+    /// The peer listens on `bind_port`, its connections reaching the target from here (`ssh -R`).
+    Remote { bind_port: u16 },
+    /// Remote forward requested by the peer: connections accepted locally on `bind_port` reach the
+    /// target from the peer.
+    Peer { bind_port: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardState {
+    /// Remote forward waiting for the answer of the peer.
+    Requested,
+    Active,
+    Refused(NowStatusCode),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardPolicy {
+    /// Connections forwarded at the same time, further ones being refused.
+    pub max_connections: Option<usize>,
+}
+
+impl ForwardPolicy {
+    pub fn max_connections(self, max_connections: usize) -> Self {
+        Self {
+            max_connections: Some(max_connections),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Forward {
+    kind: ForwardKind,
+    target: TargetAddr,
+    policy: ForwardPolicy,
+    state: ForwardState,
+    /// Streams of the connections forwarded, as (opened locally, stream id).
+    streams: Vec<(bool, u16)>,
+}
+
+impl Forward {
+    pub fn kind(&self) -> ForwardKind {
+        self.kind
+    }
+
+    pub fn target(&self) -> &TargetAddr {
+        &self.target
+    }
+
+    pub fn policy(&self) -> &ForwardPolicy {
+        &self.policy
+    }
+
+    pub fn state(&self) -> ForwardState {
+        self.state
+    }
+}
+
+pub enum ForwardEvent {
+    /// The peer asks to listen on `bind_port`: connections accepted on it are to be given to
+    /// [`PortForwarder::connect`](struct.PortForwarder.html#method.connect) with `forward`.
+    ListenRequested { forward: ForwardId, bind_port: u16 },
+    /// The peer answered a remote forward request.
+    RemoteForwardAnswered { forward: ForwardId, status: NowStatusCode },
+    /// A connection to `target` is to be made and bridged with `stream`. `forward` is `None` for
+    /// connections the peer forwards to a target permitted with
+    /// [`PortForwarder::permit_open`](struct.PortForwarder.html#method.permit_open).
+    Connect {
+        forward: Option<ForwardId>,
+        target: TargetAddr,
+        stream: TunnelStream,
+    },
+    /// Stream opened by the peer without target, not related to port forwarding.
+    Stream(TunnelStream),
+}
+
+/// Local and remote port forwarding over the [`TunnelChannel`](struct.TunnelChannel.html), each forwarded
+/// connection being carried by its own stream.
+///
+/// No socket is handled here: the application listens on the ports of its forwards and gives each
+/// connection accepted to [`connect`](#method.connect), then bridges the stream returned with the
+/// connection. Likewise, it connects to the target of each [`ForwardEvent::Connect`](enum.ForwardEvent.html)
+/// polled. With the `async` feature, [`bridge`](fn.bridge.html) copies data both ways.
+///
+/// The peer is only allowed to reach the targets permitted with [`permit_open`](#method.permit_open) and
+/// the targets of the remote forwards requested, and to have the ports permitted with
+/// [`permit_listen`](#method.permit_listen) listened on. The forwarder takes the streams opened by the peer:
+/// streams without target are given back with [`ForwardEvent::Stream`](enum.ForwardEvent.html).
+pub struct PortForwarder {
+    tunnel: TunnelChannel,
+    forwards: BTreeMap<ForwardId, Forward>,
+    next_id: u16,
+    permitted_targets: Vec<TargetAddr>,
+    permitted_ports: BTreeMap<u16, ForwardPolicy>,
+    events: VecDeque<ForwardEvent>,
+}
+
+impl PortForwarder {
+    pub fn new(tunnel: TunnelChannel) -> Self {
+        Self {
+            tunnel,
+            forwards: BTreeMap::new(),
+            next_id: 0,
+            permitted_targets: Vec::new(),
+            permitted_ports: BTreeMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// The peer may forward connections to `target`, reached from here.
+    pub fn permit_open(mut self, target: TargetAddr) -> Self {
+        self.permitted_targets.push(target);
+        self
+    }
+
+    /// The peer may request a remote forward listening on `port`, its connections following `policy`.
+    pub fn permit_listen(mut self, port: u16, policy: ForwardPolicy) -> Self {
+        self.permitted_ports.insert(port, policy);
+        self
+    }
+
+    /// Forwards the connections accepted locally on `listen_port` to `target`, reached from the peer.
+    /// The peer is to permit `target`.
+    pub fn add_local_forward(
+        &mut self,
+        listen_port: u16,
+        target: TargetAddr,
+        policy: ForwardPolicy,
+    ) -> Result<ForwardId, ProtoError> {
+        target_string(&target)?;
+        Ok(self.__insert(ForwardKind::Local { listen_port }, target, policy, ForwardState::Active))
+    }
+
+    /// Asks the peer to listen on `bind_port` and to forward its connections to `target`, reached from here.
+    /// The forward is active once the peer accepted it.
+    pub fn add_remote_forward(
+        &mut self,
+        bind_port: u16,
+        target: TargetAddr,
+        policy: ForwardPolicy,
+    ) -> Result<ForwardId, ProtoError> {
+        let target_string = target_string(&target)?;
+        let id = self.__insert(
+            ForwardKind::Remote { bind_port },
+            target,
+            policy,
+            ForwardState::Requested,
+        );
+        self.tunnel
+            .send(NowTunnelListenMsg::new(id.0, bind_port, target_string).into());
+        Ok(id)
+    }
+
+    /// Connections already forwarded are kept.
+    pub fn remove_forward(&mut self, id: ForwardId) -> Option<Forward> {
+        self.forwards.remove(&id)
+    }
+
+    pub fn get_forward(&self, id: ForwardId) -> Option<&Forward> {
+        self.forwards.get(&id)
+    }
+
+    pub fn forwards(&self) -> impl Iterator<Item = (ForwardId, &Forward)> {
+        self.forwards.iter().map(|(id, forward)| (*id, forward))
+    }
+
+    /// Number of connections of the forward not closed yet.
+    pub fn active_connections(&self, id: ForwardId) -> usize {
+        self.forwards.get(&id).map_or(0, |forward| {
+            forward
+                .streams
+                .iter()
+                .filter(|(opened_locally, stream_id)| self.tunnel.is_stream_alive(*opened_locally, *stream_id))
+                .count()
+        })
+    }
+
+    /// Opens the stream of a connection accepted on the port of a local forward, or of a remote forward
+    /// requested by the peer.
+    pub fn connect(&mut self, id: ForwardId) -> Result<TunnelStream, ProtoError> {
+        let forward = self
+            .forwards
+            .get(&id)
+            .chain(ProtoErrorKind::PortForwarding)
+            .or_else_desc(|| format!("unknown forward {:?}", id))?;
+
+        match (forward.kind, forward.state) {
+            (ForwardKind::Local { .. }, ForwardState::Active) | (ForwardKind::Peer { .. }, ForwardState::Active) => {}
+            _ => {
+                return ProtoError::new(ProtoErrorKind::PortForwarding)
+                    .or_else_desc(|| format!("forward {:?} doesn't accept local connections", id))
+            }
+        }
+
+        if !self.__has_capacity(id) {
+            return ProtoError::new(ProtoErrorKind::PortForwarding)
+                .or_else_desc(|| format!("too many connections forwarded by {:?}", id));
+        }
+
+        let target = target_string(&forward.target)?;
+        let stream = self.tunnel.open_stream_to(target);
+        self.__record(id, &stream);
+        Ok(stream)
+    }
+
+    pub fn poll_event(&mut self) -> Option<ForwardEvent> {
+        while let Some(msg) = self.tunnel.poll_listen_message() {
+            match msg {
+                NowTunnelMsg::Listen(msg) => self.__on_listen(msg),
+                NowTunnelMsg::ListenRsp(msg) => self.__on_listen_rsp(msg),
+                _ => {}
+            }
+        }
+
+        while let Some(stream) = self.tunnel.try_accept() {
+            self.__on_stream(stream);
+        }
+
+        self.events.pop_front()
+    }
+
+    fn __insert(
+        &mut self,
+        kind: ForwardKind,
+        target: TargetAddr,
+        policy: ForwardPolicy,
+        state: ForwardState,
+    ) -> ForwardId {
+        let mut id = ForwardId(self.next_id);
+        while self.forwards.contains_key(&id) {
+            id.0 = id.0.wrapping_add(1);
+        }
+        self.next_id = id.0.wrapping_add(1);
+        self.forwards.insert(
+            id,
+            Forward {
+                kind,
+                target,
+                policy,
+                state,
+                streams: Vec::new(),
+            },
+        );
+        id
+    }
+
+    fn __has_capacity(&self, id: ForwardId) -> bool {
+        let max_connections = self
+            .forwards
+            .get(&id)
+            .and_then(|forward| forward.policy.max_connections);
+        max_connections.is_none_or(|max| self.active_connections(id) < max)
+    }
+
+    fn __record(&mut self, id: ForwardId, stream: &TunnelStream) {
+        let tunnel = &self.tunnel;
+        if let Some(forward) = self.forwards.get_mut(&id) {
+            forward
+                .streams
+                .retain(|(opened_locally, stream_id)| tunnel.is_stream_alive(*opened_locally, *stream_id));
+            forward.streams.push((stream.is_opened_locally(), stream.id()));
+        }
+    }
+
+    fn __on_listen(&mut self, msg: NowTunnelListenMsg) {
+        let status = match (
+            self.permitted_ports.get(&msg.bind_port).cloned(),
+            TargetAddr::from_str(msg.target.as_str()),
+        ) {
+            (Some(_), _) if self.__is_listened(msg.bind_port) => NowStatusCode::Busy,
+            (Some(policy), Ok(target)) => {
+                let bind_port = msg.bind_port;
+                let forward = self.__insert(ForwardKind::Peer { bind_port }, target, policy, ForwardState::Active);
+                self.events
+                    .push_back(ForwardEvent::ListenRequested { forward, bind_port });
+                NowStatusCode::Success
+            }
+            (Some(_), Err(e)) => {
+                log::warn!("remote forward requested with an invalid target: {}", e);
+                NowStatusCode::InvalidRequest
+            }
+            (None, _) => {
+                log::warn!("remote forward on port {} not permitted", msg.bind_port);
+                NowStatusCode::PolicyDenied
+            }
+        };
+
+        self.tunnel
+            .send(NowTunnelListenRspMsg::new(msg.forward_id, status).into());
+    }
+
+    fn __on_listen_rsp(&mut self, msg: NowTunnelListenRspMsg) {
+        let forward_id = ForwardId(msg.forward_id);
+        match self.forwards.get_mut(&forward_id) {
+            Some(forward) if forward.state == ForwardState::Requested => {
+                forward.state = if msg.status == NowStatusCode::Success {
+                    ForwardState::Active
+                } else {
+                    ForwardState::Refused(msg.status)
+                };
+                self.events.push_back(ForwardEvent::RemoteForwardAnswered {
+                    forward: forward_id,
+                    status: msg.status,
+                });
+            }
+            _ => log::warn!("unexpected answer to remote forward {:?}", forward_id),
+        }
+    }
+
+    /// Streams opened by the peer are dropped, hence closed, when their target isn't allowed.
+    fn __on_stream(&mut self, stream: TunnelStream) {
+        let target = match stream.target() {
+            Some(target) => target,
+            None => {
+                self.events.push_back(ForwardEvent::Stream(stream));
+                return;
+            }
+        };
+
+        let target = match TargetAddr::from_str(&target) {
+            Ok(target) => target,
+            Err(e) => {
+                log::warn!("forwarded connection refused, invalid target: {}", e);
+                return;
+            }
+        };
+
+        let remote_forward = self
+            .forwards
+            .iter()
+            .find(|(_, forward)| {
+                matches!(forward.kind, ForwardKind::Remote { .. })
+                    && forward.state == ForwardState::Active
+                    && forward.target == target
+            })
+            .map(|(id, _)| *id);
+
+        match remote_forward {
+            Some(id) if !self.__has_capacity(id) => {
+                log::warn!(
+                    "forwarded connection refused, too many connections forwarded by {:?}",
+                    id
+                );
+            }
+            Some(id) => {
+                self.__record(id, &stream);
+                self.events.push_back(ForwardEvent::Connect {
+                    forward: Some(id),
+                    target,
+                    stream,
+                });
+            }
+            None if self.permitted_targets.contains(&target) => {
+                self.events.push_back(ForwardEvent::Connect {
+                    forward: None,
+                    target,
+                    stream,
+                });
+            }
+            None => log::warn!("forwarded connection to {} not permitted", target),
+        }
+    }
+
+    fn __is_listened(&self, port: u16) -> bool {
+        self.forwards
+            .values()
+            .any(|forward| forward.kind == ForwardKind::Peer { bind_port: port })
+    }
+}
+
+fn target_string(target: &TargetAddr) -> Result<NowString256, ProtoError> {
+    NowString256::from_str(&target.to_string())
+        .chain(ProtoErrorKind::PortForwarding)
+        .or_else_desc(|| format!("forward target too long: {}", target))
+}
+
+/// Copies data both ways between a forwarded stream and its connection, until both are shut down.
+/// Returns the number of bytes sent over the stream and the number of bytes received from it.
+#[cfg(feature = "async")]
+pub async fn bridge<S>(mut stream: TunnelStream, mut connection: S) -> std::io::Result<(u64, u64)>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (received, sent) = tokio::io::copy_bidirectional(&mut stream, &mut connection).await?;
+    Ok((sent, received))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm::{test_support::transfer, TunnelChannelSM, VirtualChannelSM};
+    use std::task::{Context, Poll, Waker};
+
+    fn peers() -> ((TunnelChannelSM, TunnelChannel), (TunnelChannelSM, TunnelChannel)) {
+        let (mut client_sm, client) = TunnelChannelSM::new();
+        let (mut server_sm, server) = TunnelChannelSM::new();
+        client_sm.on_open();
+        server_sm.on_open();
+        ((client_sm, client), (server_sm, server))
+    }
+
+    #[test]
+    fn local_forward_reaches_permitted_targets() {
+        let ((mut client_sm, client), (mut server_sm, server)) = peers();
+        let database = TargetAddr::from_str("db.internal:5432").unwrap();
+        let mut client = PortForwarder::new(client);
+        let mut server = PortForwarder::new(server).permit_open(database.clone());
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let forward = client
+            .add_local_forward(15432, database.clone(), ForwardPolicy::default())
+            .unwrap();
+        let stream = client.connect(forward).unwrap();
+        assert!(stream.poll_write_bytes(&mut cx, b"query").is_ready());
+        transfer(&mut client_sm, &mut server_sm);
+
+        match server.poll_event() {
+            Some(ForwardEvent::Connect {
+                forward: None,
+                target,
+                stream,
+            }) => {
+                assert_eq!(target, database);
+                let mut buf = [0; 8];
+                assert_eq!(stream.poll_read_bytes(&mut cx, &mut buf), Poll::Ready(5));
+            }
+            _ => panic!("connection not forwarded"),
+        }
+
+        // the client can't reach another target: the stream is closed right away
+        let other = client
+            .add_local_forward(
+                2222,
+                TargetAddr::from_str("10.0.0.1:22").unwrap(),
+                ForwardPolicy::default(),
+            )
+            .unwrap();
+        let refused = client.connect(other).unwrap();
+        transfer(&mut client_sm, &mut server_sm);
+        assert!(server.poll_event().is_none());
+        transfer(&mut server_sm, &mut client_sm);
+        let mut buf = [0; 8];
+        assert_eq!(refused.poll_read_bytes(&mut cx, &mut buf), Poll::Ready(0));
+    }
+
+    #[test]
+    fn remote_forward_listened_by_peer_with_its_policy() {
+        let ((mut client_sm, client), (mut server_sm, server)) = peers();
+        let ssh = TargetAddr::from_str("127.0.0.1:22").unwrap();
+        let mut client = PortForwarder::new(client);
+        let mut server = PortForwarder::new(server).permit_listen(2222, ForwardPolicy::default().max_connections(1));
+
+        let refused = client
+            .add_remote_forward(80, ssh.clone(), ForwardPolicy::default())
+            .unwrap();
+        let forward = client
+            .add_remote_forward(2222, ssh.clone(), ForwardPolicy::default())
+            .unwrap();
+        assert!(client.connect(forward).is_err());
+        transfer(&mut client_sm, &mut server_sm);
+
+        let peer_forward = match server.poll_event() {
+            Some(ForwardEvent::ListenRequested { forward, bind_port }) => {
+                assert_eq!(bind_port, 2222);
+                forward
+            }
+            _ => panic!("listen not requested"),
+        };
+        assert!(server.poll_event().is_none());
+        transfer(&mut server_sm, &mut client_sm);
+
+        assert!(matches!(
+            client.poll_event(),
+            Some(ForwardEvent::RemoteForwardAnswered {
+                status: NowStatusCode::PolicyDenied,
+                ..
+            })
+        ));
+        assert_eq!(
+            client.get_forward(refused).unwrap().state(),
+            ForwardState::Refused(NowStatusCode::PolicyDenied)
+        );
+        assert!(matches!(
+            client.poll_event(),
+            Some(ForwardEvent::RemoteForwardAnswered {
+                status: NowStatusCode::Success,
+                ..
+            })
+        ));
+
+        // connections accepted by the server on the port reach the target from the client
+        let stream = server.connect(peer_forward).unwrap();
+        assert!(server.connect(peer_forward).is_err());
+        assert_eq!(server.active_connections(peer_forward), 1);
+        transfer(&mut server_sm, &mut client_sm);
+        match client.poll_event() {
+            Some(ForwardEvent::Connect {
+                forward: Some(id),
+                target,
+                ..
+            }) => {
+                assert_eq!(id, forward);
+                assert_eq!(target, ssh);
+            }
+            _ => panic!("connection not forwarded"),
+        }
+
+        drop(stream);
+        assert_eq!(server.active_connections(peer_forward), 0);
+        assert!(server.connect(peer_forward).is_ok());
+    }
+}
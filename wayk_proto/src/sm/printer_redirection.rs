@@ -0,0 +1,760 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        ChannelName, NowPrintJobCancelMsg, NowPrintJobDataMsg, NowPrintJobEndMsg, NowPrintJobStartMsg,
+        NowPrintJobStatusMsg, NowPrinterAnnounceMsg, NowPrinterMsg, NowPrinterRemoveMsg, NowStatusCode, NowString256,
+        NowString64, NowVirtualChannel, PrintDataFormat, PrinterFlags,
+    },
+    sm::{VirtChannelSMResult, VirtualChannelSM},
+};
+use alloc::collections::{BTreeMap, VecDeque};
+use std::{cell::RefCell, rc::Rc};
+
+/// Receives the jobs sent by the peer to the local printers, to print them or archive them as PDF.
+///
+/// Calls for a job are `start`, any number of `write` with the spool data in order, then `finish`,
+/// unless the job is aborted first. A failure aborts the job and is reported to the peer with its status.
+/// The sink is called while the channel is updated and must not use the `PrinterRedirection` handle.
+pub trait PrintSink {
+    fn start(&mut self, job: &PrintJob) -> Result<(), NowStatusCode>;
+
+    fn write(&mut self, job_id: u32, data: &[u8]) -> Result<(), NowStatusCode>;
+
+    fn finish(&mut self, job_id: u32) -> Result<(), NowStatusCode>;
+
+    /// The job was cancelled by the peer, its printer removed or the channel closed.
+    #[allow(unused_variables)]
+    fn abort(&mut self, job_id: u32) {}
+}
+
+/// Job sent by the peer to a local printer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrintJob {
+    pub id: u32,
+    pub printer_id: u32,
+    pub printer_name: String,
+    pub format: PrintDataFormat,
+    pub copies: u16,
+    pub document: String,
+}
+
+/// Printer advertised to the peer.
+#[derive(Debug, Clone)]
+pub struct LocalPrinter {
+    pub name: NowString64,
+    pub driver: NowString64,
+    pub flags: PrinterFlags,
+    pub format: PrintDataFormat,
+}
+
+impl LocalPrinter {
+    pub fn new(name: NowString64, format: PrintDataFormat) -> Self {
+        Self {
+            name,
+            driver: NowString64::new_empty(),
+            flags: PrinterFlags::new_empty(),
+            format,
+        }
+    }
+
+    pub fn driver(self, driver: NowString64) -> Self {
+        Self { driver, ..self }
+    }
+
+    pub fn flags(self, flags: PrinterFlags) -> Self {
+        Self { flags, ..self }
+    }
+}
+
+/// Printer advertised by the peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemotePrinter {
+    pub id: u32,
+    pub name: String,
+    pub driver: String,
+    pub flags: PrinterFlags,
+    pub format: PrintDataFormat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrinterEvent {
+    Announced(RemotePrinter),
+    /// Jobs sent to the printer are finished with `NotFound` beforehand.
+    Removed {
+        printer_id: u32,
+    },
+    /// Final status of a job sent to the peer.
+    JobFinished {
+        job_id: u32,
+        status: NowStatusCode,
+    },
+}
+
+/// Job sent to a printer of the peer, until its status is received.
+#[derive(Debug, Clone, Copy)]
+struct OutgoingJob {
+    printer_id: u32,
+    ended: bool,
+}
+
+#[derive(Default)]
+struct PrinterState {
+    opened: bool,
+    next_printer_id: u32,
+    next_job_id: u32,
+    sink: Option<Box<dyn PrintSink>>,
+    local: BTreeMap<u32, LocalPrinter>,
+    remote: BTreeMap<u32, RemotePrinter>,
+    /// Jobs received by the sink, by id, with the printer they are sent to.
+    incoming_jobs: BTreeMap<u32, u32>,
+    outgoing_jobs: BTreeMap<u32, OutgoingJob>,
+    outgoing: VecDeque<NowPrinterMsg>,
+    events: VecDeque<PrinterEvent>,
+}
+
+impl PrinterState {
+    fn __announce(&mut self, printer_id: u32) {
+        if let Some(printer) = self.local.get(&printer_id) {
+            self.outgoing.push_back(
+                NowPrinterAnnounceMsg::new(
+                    printer_id,
+                    printer.flags,
+                    printer.format,
+                    printer.name.clone(),
+                    printer.driver.clone(),
+                )
+                .into(),
+            );
+        }
+    }
+
+    fn __abort_incoming(&mut self, job_id: u32) {
+        if self.incoming_jobs.remove(&job_id).is_some() {
+            if let Some(sink) = &mut self.sink {
+                sink.abort(job_id);
+            }
+        }
+    }
+
+    fn __finish_outgoing(&mut self, job_id: u32, status: NowStatusCode) {
+        if self.outgoing_jobs.remove(&job_id).is_some() {
+            self.events.push_back(PrinterEvent::JobFinished { job_id, status });
+        }
+    }
+}
+
+/// State machine of the printer redirection channel.
+///
+/// Printers added with [`PrinterRedirection::add_printer`](struct.PrinterRedirection.html#method.add_printer)
+/// are advertised to the peer, and the jobs it sends to them are handed to the
+/// [`PrintSink`](trait.PrintSink.html). Jobs are sent the other way to the printers advertised by the peer.
+pub struct PrinterRedirectionSM {
+    state: Rc<RefCell<PrinterState>>,
+}
+
+impl PrinterRedirectionSM {
+    pub fn new() -> (Self, PrinterRedirection) {
+        let state = Rc::new(RefCell::new(PrinterState::default()));
+        let redirection = PrinterRedirection {
+            state: Rc::clone(&state),
+        };
+        (Self { state }, redirection)
+    }
+
+    fn __on_message(&mut self, msg: &NowPrinterMsg) {
+        let mut state = self.state.borrow_mut();
+        match msg {
+            NowPrinterMsg::Announce(msg) => {
+                log::trace!("printer {} announced by peer", msg.printer_id);
+                let printer = RemotePrinter {
+                    id: msg.printer_id,
+                    name: msg.name.as_str().to_owned(),
+                    driver: msg.driver.as_str().to_owned(),
+                    flags: msg.printer_flags,
+                    format: msg.format,
+                };
+                state.remote.insert(msg.printer_id, printer.clone());
+                state.events.push_back(PrinterEvent::Announced(printer));
+            }
+            NowPrinterMsg::Remove(msg) => {
+                if state.remote.remove(&msg.printer_id).is_none() {
+                    log::warn!("removal of printer {} not announced ignored", msg.printer_id);
+                    return;
+                }
+                log::trace!("printer {} removed by peer", msg.printer_id);
+                let jobs: Vec<u32> = state
+                    .outgoing_jobs
+                    .iter()
+                    .filter(|(_, job)| job.printer_id == msg.printer_id)
+                    .map(|(job_id, _)| *job_id)
+                    .collect();
+                for job_id in jobs {
+                    state.__finish_outgoing(job_id, NowStatusCode::NotFound);
+                }
+                state.events.push_back(PrinterEvent::Removed {
+                    printer_id: msg.printer_id,
+                });
+            }
+            NowPrinterMsg::JobStart(msg) => {
+                if let Err(status) = Self::__start_job(&mut state, msg) {
+                    log::debug!("print job {} refused: {:?}", msg.job_id, status);
+                    state
+                        .outgoing
+                        .push_back(NowPrintJobStatusMsg::new(msg.job_id, status).into());
+                }
+            }
+            NowPrinterMsg::JobData(msg) => {
+                if !state.incoming_jobs.contains_key(&msg.job_id) {
+                    // data following a failure already reported
+                    log::trace!("data of inactive print job {} ignored", msg.job_id);
+                    return;
+                }
+                let result = state.sink.as_mut().map_or(Err(NowStatusCode::NotSupported), |sink| {
+                    sink.write(msg.job_id, &msg.data.0)
+                });
+                if let Err(status) = result {
+                    log::debug!("print job {} failed: {:?}", msg.job_id, status);
+                    state.__abort_incoming(msg.job_id);
+                    state
+                        .outgoing
+                        .push_back(NowPrintJobStatusMsg::new(msg.job_id, status).into());
+                }
+            }
+            NowPrinterMsg::JobEnd(msg) => {
+                if state.incoming_jobs.remove(&msg.job_id).is_none() {
+                    log::trace!("end of inactive print job {} ignored", msg.job_id);
+                    return;
+                }
+                let status = match state
+                    .sink
+                    .as_mut()
+                    .map_or(Err(NowStatusCode::NotSupported), |sink| sink.finish(msg.job_id))
+                {
+                    Ok(()) => NowStatusCode::Success,
+                    Err(status) => status,
+                };
+                log::trace!("print job {} ended: {:?}", msg.job_id, status);
+                state
+                    .outgoing
+                    .push_back(NowPrintJobStatusMsg::new(msg.job_id, status).into());
+            }
+            NowPrinterMsg::JobCancel(msg) => {
+                log::trace!("print job {} cancelled by peer", msg.job_id);
+                state.__abort_incoming(msg.job_id);
+            }
+            NowPrinterMsg::JobStatus(msg) => {
+                if state.outgoing_jobs.contains_key(&msg.job_id) {
+                    state.__finish_outgoing(msg.job_id, msg.status);
+                } else {
+                    log::warn!("status of unknown print job {} ignored", msg.job_id);
+                }
+            }
+        }
+    }
+
+    fn __start_job(state: &mut PrinterState, msg: &NowPrintJobStartMsg) -> Result<(), NowStatusCode> {
+        if state.incoming_jobs.contains_key(&msg.job_id) {
+            return Err(NowStatusCode::InvalidRequest);
+        }
+        let printer = state.local.get(&msg.printer_id).ok_or(NowStatusCode::NotFound)?;
+        let job = PrintJob {
+            id: msg.job_id,
+            printer_id: msg.printer_id,
+            printer_name: printer.name.as_str().to_owned(),
+            format: msg.format,
+            copies: msg.copies,
+            document: msg.document.as_str().to_owned(),
+        };
+        state.sink.as_mut().ok_or(NowStatusCode::NotSupported)?.start(&job)?;
+        state.incoming_jobs.insert(msg.job_id, msg.printer_id);
+        Ok(())
+    }
+}
+
+impl VirtualChannelSM for PrinterRedirectionSM {
+    fn get_channel_name(&self) -> ChannelName {
+        ChannelName::Printer
+    }
+
+    fn is_terminated(&self) -> bool {
+        false
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        let state = self.state.borrow();
+        !state.opened || state.outgoing.is_empty()
+    }
+
+    fn update_without_chan_msg<'msg>(&mut self) -> VirtChannelSMResult<'msg> {
+        let msg = self
+            .state
+            .borrow_mut()
+            .outgoing
+            .pop_front()
+            .chain(ProtoErrorKind::VirtualChannel(ChannelName::Printer))
+            .or_desc("unexpected call to `update_without_chan_msg` without message to send")?;
+        Ok(Some(msg.into()))
+    }
+
+    fn update_with_chan_msg<'msg: 'a, 'a>(
+        &mut self,
+        chan_msg: &'a NowVirtualChannel<'msg>,
+    ) -> VirtChannelSMResult<'msg> {
+        match chan_msg {
+            NowVirtualChannel::Printer(msg) => {
+                self.__on_message(msg);
+                Ok(None)
+            }
+            _ => ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::Printer))
+                .or_else_desc(|| format!("received an unexpected message: {:?}", chan_msg)),
+        }
+    }
+
+    /// Local printers are advertised again.
+    fn on_open(&mut self) {
+        log::trace!("printer redirection channel opened");
+        let mut state = self.state.borrow_mut();
+        state.opened = true;
+        let ids: Vec<u32> = state.local.keys().copied().collect();
+        for id in ids {
+            state.__announce(id);
+        }
+    }
+
+    /// Jobs in progress either way are aborted and the printers of the peer removed.
+    fn on_close(&mut self) {
+        log::trace!("printer redirection channel closed");
+        let mut state = self.state.borrow_mut();
+        state.opened = false;
+        state.outgoing.clear();
+
+        let incoming: Vec<u32> = state.incoming_jobs.keys().copied().collect();
+        for job_id in incoming {
+            state.__abort_incoming(job_id);
+        }
+        let outgoing: Vec<u32> = state.outgoing_jobs.keys().copied().collect();
+        for job_id in outgoing {
+            state.__finish_outgoing(job_id, NowStatusCode::Failure);
+        }
+        let removed: Vec<u32> = state.remote.keys().copied().collect();
+        state.remote.clear();
+        for printer_id in removed {
+            state.events.push_back(PrinterEvent::Removed { printer_id });
+        }
+    }
+}
+
+/// Application side of a [`PrinterRedirectionSM`](struct.PrinterRedirectionSM.html).
+///
+/// Messages are queued until the sharee is updated with the channel opened.
+#[derive(Clone)]
+pub struct PrinterRedirection {
+    state: Rc<RefCell<PrinterState>>,
+}
+
+impl PrinterRedirection {
+    /// Spool data is sent in messages of at most this size.
+    pub const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
+    pub fn is_open(&self) -> bool {
+        self.state.borrow().opened
+    }
+
+    /// Oldest event not polled yet.
+    pub fn poll_event(&self) -> Option<PrinterEvent> {
+        self.state.borrow_mut().events.pop_front()
+    }
+
+    /// Sink receiving the jobs sent to local printers. Jobs are refused with `NotSupported` without sink.
+    pub fn set_sink<S: PrintSink + 'static>(&self, sink: S) {
+        self.state.borrow_mut().sink = Some(Box::new(sink));
+    }
+
+    /// Advertises `printer` to the peer once the channel is opened. Returns its id.
+    pub fn add_printer(&self, printer: LocalPrinter) -> u32 {
+        let mut state = self.state.borrow_mut();
+        let mut printer_id = state.next_printer_id;
+        while state.local.contains_key(&printer_id) {
+            printer_id = printer_id.wrapping_add(1);
+        }
+        state.next_printer_id = printer_id.wrapping_add(1);
+        state.local.insert(printer_id, printer);
+        if state.opened {
+            state.__announce(printer_id);
+        }
+        printer_id
+    }
+
+    /// Removes a local printer, aborting the jobs it receives. `false` if there is no such printer.
+    pub fn remove_printer(&self, printer_id: u32) -> bool {
+        let mut state = self.state.borrow_mut();
+        if state.local.remove(&printer_id).is_none() {
+            return false;
+        }
+        let jobs: Vec<u32> = state
+            .incoming_jobs
+            .iter()
+            .filter(|(_, job_printer_id)| **job_printer_id == printer_id)
+            .map(|(job_id, _)| *job_id)
+            .collect();
+        for job_id in jobs {
+            state.__abort_incoming(job_id);
+        }
+        if state.opened {
+            state.outgoing.push_back(NowPrinterRemoveMsg::new(printer_id).into());
+        }
+        true
+    }
+
+    /// Printers advertised by the peer.
+    pub fn remote_printers(&self) -> Vec<RemotePrinter> {
+        self.state.borrow().remote.values().cloned().collect()
+    }
+
+    /// Starts a job on a printer of the peer. Returns the id of the job.
+    pub fn start_job(
+        &self,
+        printer_id: u32,
+        format: PrintDataFormat,
+        copies: u16,
+        document: NowString256,
+    ) -> Result<u32, ProtoError> {
+        let mut state = self.state.borrow_mut();
+        if !state.remote.contains_key(&printer_id) {
+            return ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::Printer))
+                .or_else_desc(|| format!("printer {} not announced by peer", printer_id));
+        }
+
+        let mut job_id = state.next_job_id;
+        while state.outgoing_jobs.contains_key(&job_id) {
+            job_id = job_id.wrapping_add(1);
+        }
+        state.next_job_id = job_id.wrapping_add(1);
+        state.outgoing_jobs.insert(
+            job_id,
+            OutgoingJob {
+                printer_id,
+                ended: false,
+            },
+        );
+        state
+            .outgoing
+            .push_back(NowPrintJobStartMsg::new(job_id, printer_id, format, copies, document).into());
+        Ok(job_id)
+    }
+
+    /// Queues spool data of a job, split in messages of at most `MAX_CHUNK_SIZE` bytes.
+    pub fn send_data(&self, job_id: u32, data: &[u8]) -> Result<(), ProtoError> {
+        let mut state = self.state.borrow_mut();
+        Self::__check_sending(&state, job_id)?;
+        for chunk in data.chunks(Self::MAX_CHUNK_SIZE) {
+            state
+                .outgoing
+                .push_back(NowPrintJobDataMsg::new(job_id, chunk.to_vec()).into());
+        }
+        Ok(())
+    }
+
+    /// Ends a job once all its data is sent. Its status follows as a `JobFinished` event.
+    pub fn end_job(&self, job_id: u32) -> Result<(), ProtoError> {
+        let mut state = self.state.borrow_mut();
+        Self::__check_sending(&state, job_id)?;
+        if let Some(job) = state.outgoing_jobs.get_mut(&job_id) {
+            job.ended = true;
+        }
+        state.outgoing.push_back(NowPrintJobEndMsg::new(job_id).into());
+        Ok(())
+    }
+
+    /// Aborts a job not finished yet. `false` if there is no such job.
+    pub fn cancel_job(&self, job_id: u32) -> bool {
+        let mut state = self.state.borrow_mut();
+        if state.outgoing_jobs.remove(&job_id).is_none() {
+            return false;
+        }
+        state.outgoing.push_back(NowPrintJobCancelMsg::new(job_id).into());
+        true
+    }
+
+    fn __check_sending(state: &PrinterState, job_id: u32) -> Result<(), ProtoError> {
+        match state.outgoing_jobs.get(&job_id) {
+            Some(job) if !job.ended => Ok(()),
+            Some(_) => ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::Printer))
+                .or_else_desc(|| format!("print job {} already ended", job_id)),
+            None => ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::Printer))
+                .or_else_desc(|| format!("print job {} not in progress", job_id)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm::test_support::transfer;
+    use std::str::FromStr;
+
+    #[derive(Default)]
+    struct Spool {
+        started: Vec<PrintJob>,
+        printed: BTreeMap<u32, Vec<u8>>,
+        aborted: Vec<u32>,
+    }
+
+    /// Archives the PDF jobs it receives, refusing the other formats.
+    struct PdfArchive(Rc<RefCell<Spool>>);
+
+    impl PrintSink for PdfArchive {
+        fn start(&mut self, job: &PrintJob) -> Result<(), NowStatusCode> {
+            if job.format != PrintDataFormat::Pdf {
+                return Err(NowStatusCode::NotSupported);
+            }
+            let mut spool = self.0.borrow_mut();
+            spool.started.push(job.clone());
+            spool.printed.insert(job.id, Vec::new());
+            Ok(())
+        }
+
+        fn write(&mut self, job_id: u32, data: &[u8]) -> Result<(), NowStatusCode> {
+            let mut spool = self.0.borrow_mut();
+            let printed = spool.printed.get_mut(&job_id).ok_or(NowStatusCode::NotFound)?;
+            printed.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn finish(&mut self, job_id: u32) -> Result<(), NowStatusCode> {
+            if self.0.borrow().printed[&job_id].starts_with(b"%PDF") {
+                Ok(())
+            } else {
+                Err(NowStatusCode::Failure)
+            }
+        }
+
+        fn abort(&mut self, job_id: u32) {
+            let mut spool = self.0.borrow_mut();
+            spool.printed.remove(&job_id);
+            spool.aborted.push(job_id);
+        }
+    }
+
+    /// Printer of the client, with the sink archiving its jobs, announced to the server.
+    fn announced_printer() -> (
+        PrinterRedirectionSM,
+        PrinterRedirectionSM,
+        PrinterRedirection,
+        Rc<RefCell<Spool>>,
+        u32,
+    ) {
+        let (mut client_sm, client) = PrinterRedirectionSM::new();
+        let (mut server_sm, server) = PrinterRedirectionSM::new();
+        let spool = Rc::new(RefCell::new(Spool::default()));
+        client.set_sink(PdfArchive(Rc::clone(&spool)));
+        let printer_id = client.add_printer(LocalPrinter::new(
+            NowString64::from_str("Office").unwrap(),
+            PrintDataFormat::Pdf,
+        ));
+        client_sm.on_open();
+        server_sm.on_open();
+        transfer(&mut client_sm, &mut server_sm);
+        assert!(matches!(server.poll_event(), Some(PrinterEvent::Announced(_))));
+        (client_sm, server_sm, server, spool, printer_id)
+    }
+
+    #[test]
+    fn print_jobs_handed_to_sink() {
+        let (mut client_sm, client) = PrinterRedirectionSM::new();
+        let (mut server_sm, server) = PrinterRedirectionSM::new();
+        let spool = Rc::new(RefCell::new(Spool::default()));
+        client.set_sink(PdfArchive(Rc::clone(&spool)));
+        let printer_id = client.add_printer(
+            LocalPrinter::new(NowString64::from_str("Office").unwrap(), PrintDataFormat::Pdf)
+                .flags(PrinterFlags::new_empty().set_default()),
+        );
+
+        client_sm.on_open();
+        server_sm.on_open();
+        transfer(&mut client_sm, &mut server_sm);
+        match server.poll_event() {
+            Some(PrinterEvent::Announced(printer)) => {
+                assert_eq!(printer.id, printer_id);
+                assert_eq!(printer.name, "Office");
+                assert!(printer.flags.default());
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(server
+            .start_job(printer_id + 1, PrintDataFormat::Pdf, 1, NowString256::new_empty())
+            .is_err());
+
+        let document = NowString256::from_str("report.pdf").unwrap();
+        let job_id = server.start_job(printer_id, PrintDataFormat::Pdf, 2, document).unwrap();
+        let data: Vec<u8> = b"%PDF-1.7".iter().copied().cycle().take(40 * 1024).collect();
+        server.send_data(job_id, &data).unwrap();
+        server.end_job(job_id).unwrap();
+        assert!(server.send_data(job_id, b"trailer").is_err());
+        assert!(server.end_job(job_id).is_err());
+
+        transfer(&mut server_sm, &mut client_sm);
+        transfer(&mut client_sm, &mut server_sm);
+        assert_eq!(
+            server.poll_event(),
+            Some(PrinterEvent::JobFinished {
+                job_id,
+                status: NowStatusCode::Success
+            })
+        );
+        assert_eq!(server.poll_event(), None);
+
+        let spool = spool.borrow();
+        assert_eq!(spool.started.len(), 1);
+        assert_eq!(spool.started[0].document, "report.pdf");
+        assert_eq!(spool.started[0].printer_name, "Office");
+        assert_eq!(spool.started[0].copies, 2);
+        assert_eq!(spool.printed[&job_id], data);
+        assert!(spool.aborted.is_empty());
+    }
+
+    #[test]
+    fn rejected_jobs_finished_with_status() {
+        let (mut client_sm, mut server_sm, server, spool, printer_id) = announced_printer();
+
+        // refused by the sink on start, its data is ignored
+        let refused_id = server
+            .start_job(printer_id, PrintDataFormat::Xps, 1, NowString256::new_empty())
+            .unwrap();
+        server.send_data(refused_id, b"PK").unwrap();
+        server.end_job(refused_id).unwrap();
+        // failing once ended
+        let failed_id = server
+            .start_job(printer_id, PrintDataFormat::Pdf, 1, NowString256::new_empty())
+            .unwrap();
+        server.send_data(failed_id, b"garbage").unwrap();
+        server.end_job(failed_id).unwrap();
+
+        transfer(&mut server_sm, &mut client_sm);
+        transfer(&mut client_sm, &mut server_sm);
+        assert_eq!(
+            server.poll_event(),
+            Some(PrinterEvent::JobFinished {
+                job_id: refused_id,
+                status: NowStatusCode::NotSupported
+            })
+        );
+        assert_eq!(
+            server.poll_event(),
+            Some(PrinterEvent::JobFinished {
+                job_id: failed_id,
+                status: NowStatusCode::Failure
+            })
+        );
+        assert_eq!(server.poll_event(), None);
+        assert!(server.send_data(refused_id, b"PK").is_err());
+
+        // printer removed since announced
+        client_sm
+            .update_with_chan_msg(
+                &NowPrinterMsg::from(NowPrintJobStartMsg::new(
+                    7,
+                    printer_id + 1,
+                    PrintDataFormat::Pdf,
+                    1,
+                    NowString256::new_empty(),
+                ))
+                .into(),
+            )
+            .unwrap();
+        match client_sm.update_without_chan_msg().unwrap() {
+            Some(NowVirtualChannel::Printer(NowPrinterMsg::JobStatus(msg))) => {
+                assert_eq!(msg.job_id, 7);
+                assert_eq!(msg.status, NowStatusCode::NotFound);
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+
+        let spool = spool.borrow();
+        assert_eq!(spool.started.len(), 1);
+        assert_eq!(spool.started[0].id, failed_id);
+        assert!(spool.aborted.is_empty());
+    }
+
+    #[test]
+    fn cancelled_jobs_aborted() {
+        let (mut client_sm, mut server_sm, server, spool, printer_id) = announced_printer();
+        assert!(!server.cancel_job(0));
+
+        let cancelled_id = server
+            .start_job(printer_id, PrintDataFormat::Pdf, 1, NowString256::new_empty())
+            .unwrap();
+        server.send_data(cancelled_id, b"%PDF").unwrap();
+        transfer(&mut server_sm, &mut client_sm);
+        assert!(spool.borrow().printed.contains_key(&cancelled_id));
+
+        // neither more data nor status once cancelled
+        assert!(server.cancel_job(cancelled_id));
+        assert!(!server.cancel_job(cancelled_id));
+        assert!(server.send_data(cancelled_id, b"-1.7").is_err());
+        assert!(server.end_job(cancelled_id).is_err());
+        transfer(&mut server_sm, &mut client_sm);
+        assert!(client_sm.waiting_for_packet());
+        assert_eq!(spool.borrow().aborted, vec![cancelled_id]);
+        assert!(!spool.borrow().printed.contains_key(&cancelled_id));
+
+        // cancelled while its status is in flight
+        let ended_id = server
+            .start_job(printer_id, PrintDataFormat::Pdf, 1, NowString256::new_empty())
+            .unwrap();
+        server.send_data(ended_id, b"%PDF").unwrap();
+        server.end_job(ended_id).unwrap();
+        transfer(&mut server_sm, &mut client_sm);
+        assert!(server.cancel_job(ended_id));
+        transfer(&mut client_sm, &mut server_sm);
+        assert_eq!(server.poll_event(), None);
+        assert_eq!(spool.borrow().aborted, vec![cancelled_id]);
+    }
+
+    #[test]
+    fn jobs_pending_on_close_aborted() {
+        let (mut client_sm, mut server_sm, server, spool, printer_id) = announced_printer();
+        let job_id = server
+            .start_job(printer_id, PrintDataFormat::Pdf, 1, NowString256::new_empty())
+            .unwrap();
+        server.send_data(job_id, b"%PDF").unwrap();
+        transfer(&mut server_sm, &mut client_sm);
+        let queued_id = server
+            .start_job(printer_id, PrintDataFormat::Pdf, 1, NowString256::new_empty())
+            .unwrap();
+
+        client_sm.on_close();
+        server_sm.on_close();
+        assert_eq!(
+            server.poll_event(),
+            Some(PrinterEvent::JobFinished {
+                job_id,
+                status: NowStatusCode::Failure
+            })
+        );
+        assert_eq!(
+            server.poll_event(),
+            Some(PrinterEvent::JobFinished {
+                job_id: queued_id,
+                status: NowStatusCode::Failure
+            })
+        );
+        assert_eq!(server.poll_event(), Some(PrinterEvent::Removed { printer_id }));
+        assert_eq!(server.poll_event(), None);
+        assert!(server.send_data(job_id, b"-1.7").is_err());
+        assert!(server.remote_printers().is_empty());
+        assert_eq!(spool.borrow().aborted, vec![job_id]);
+
+        // nothing left of the jobs once reopened
+        client_sm.on_open();
+        server_sm.on_open();
+        transfer(&mut client_sm, &mut server_sm);
+        assert!(matches!(server.poll_event(), Some(PrinterEvent::Announced(_))));
+        assert!(server_sm.waiting_for_packet());
+        client_sm
+            .update_with_chan_msg(&NowPrinterMsg::from(NowPrintJobEndMsg::new(job_id)).into())
+            .unwrap();
+        assert!(client_sm.waiting_for_packet());
+        assert_eq!(spool.borrow().aborted, vec![job_id]);
+    }
+}
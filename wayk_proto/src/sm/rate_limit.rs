@@ -0,0 +1,145 @@
+use crate::{
+    message::ChannelName,
+    send_queue::{RateLimit, TokenBucket},
+};
+use alloc::collections::BTreeMap;
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+pub type ChannelRateLimiterRc = Rc<RefCell<ChannelRateLimiter>>;
+
+/// Rate limits of a transport and of its virtual channels.
+///
+/// A frame sent on a virtual channel is limited by both the channel and the transport limits.
+/// Frames larger than the burst size are sent once the bucket is full, the following frames wait for it.
+/// Limits can be changed at any time, including while frames are waiting.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelRateLimiter {
+    transport: Option<TokenBucket>,
+    channels: BTreeMap<ChannelName, TokenBucket>,
+}
+
+impl ChannelRateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn new_rc() -> ChannelRateLimiterRc {
+        Rc::new(RefCell::new(Self::new()))
+    }
+
+    pub fn transport_limit(&self) -> Option<RateLimit> {
+        self.transport.as_ref().map(TokenBucket::limit)
+    }
+
+    /// Sets (or removes with `None`) the limit of the whole transport.
+    pub fn set_transport_limit(&mut self, limit: Option<RateLimit>, now: Instant) {
+        set_bucket_limit(&mut self.transport, limit, now);
+    }
+
+    pub fn channel_limit(&self, channel: &ChannelName) -> Option<RateLimit> {
+        self.channels.get(channel).map(TokenBucket::limit)
+    }
+
+    /// Sets (or removes with `None`) the limit of the given channel. Other channels and messages are not affected.
+    pub fn set_channel_limit(&mut self, channel: ChannelName, limit: Option<RateLimit>, now: Instant) {
+        let mut bucket = self.channels.remove(&channel);
+        set_bucket_limit(&mut bucket, limit, now);
+        if let Some(bucket) = bucket {
+            self.channels.insert(channel, bucket);
+        }
+    }
+
+    /// Time to wait before a frame of `len` bytes can be sent on `channel` (`None` for messages).
+    pub fn wait_time(&mut self, channel: Option<&ChannelName>, len: usize, now: Instant) -> Duration {
+        let transport = self.transport.as_mut().map(|bucket| {
+            bucket.refill(now);
+            bucket.wait_time(len)
+        });
+        let channel = channel
+            .and_then(|channel| self.channels.get_mut(channel))
+            .map(|bucket| {
+                bucket.refill(now);
+                bucket.wait_time(len)
+            });
+
+        transport.unwrap_or_default().max(channel.unwrap_or_default())
+    }
+
+    /// Accounts for a frame of `len` bytes sent on `channel` (`None` for messages).
+    pub fn consume(&mut self, channel: Option<&ChannelName>, len: usize) {
+        if let Some(bucket) = self.transport.as_mut() {
+            bucket.consume(len);
+        }
+        if let Some(bucket) = channel.and_then(|channel| self.channels.get_mut(channel)) {
+            bucket.consume(len);
+        }
+    }
+}
+
+fn set_bucket_limit(bucket: &mut Option<TokenBucket>, limit: Option<RateLimit>, now: Instant) {
+    match (bucket.as_mut(), limit) {
+        (Some(bucket), Some(limit)) => {
+            bucket.refill(now);
+            bucket.set_limit(limit);
+        }
+        (None, Some(limit)) => *bucket = Some(TokenBucket::new(limit, now)),
+        (_, None) => *bucket = None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_limits_leave_messages_unthrottled() {
+        let now = Instant::now();
+        let mut limiter = ChannelRateLimiter::new();
+        let file_transfer_limit = RateLimit::new(5_000_000, 5_000_000);
+        limiter.set_channel_limit(ChannelName::FileTransfer, Some(file_transfer_limit), now);
+        assert_eq!(
+            limiter.channel_limit(&ChannelName::FileTransfer),
+            Some(file_transfer_limit)
+        );
+
+        // larger than the burst: sent at once, then the next frames wait for the debt to be paid
+        assert_eq!(
+            limiter.wait_time(Some(&ChannelName::FileTransfer), 10_000_000, now),
+            Duration::from_secs(0)
+        );
+        limiter.consume(Some(&ChannelName::FileTransfer), 10_000_000);
+        assert_eq!(
+            limiter.wait_time(Some(&ChannelName::FileTransfer), 5_000_000, now),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            limiter.wait_time(
+                Some(&ChannelName::FileTransfer),
+                5_000_000,
+                now + Duration::from_secs(1)
+            ),
+            Duration::from_secs(1)
+        );
+        assert_eq!(limiter.wait_time(None, 10_000_000, now), Duration::from_secs(0));
+        assert_eq!(
+            limiter.wait_time(Some(&ChannelName::Clipboard), 1_000, now),
+            Duration::from_secs(0)
+        );
+
+        // transport limit applies to everything
+        limiter.set_transport_limit(Some(RateLimit::new(1_000, 1_000)), now);
+        limiter.consume(None, 1_000);
+        assert_eq!(limiter.wait_time(None, 1_000, now), Duration::from_secs(1));
+
+        limiter.set_transport_limit(None, now);
+        limiter.set_channel_limit(ChannelName::FileTransfer, None, now);
+        assert_eq!(
+            limiter.wait_time(Some(&ChannelName::FileTransfer), 1_000, now),
+            Duration::from_secs(0)
+        );
+    }
+}
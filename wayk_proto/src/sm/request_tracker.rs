@@ -0,0 +1,181 @@
+use crate::message::{NowStatusCode, NowSurfaceListRspMsg, NowSurfaceMapRspMsg, NowSurfaceMsg, NowSurfaceSelectRspMsg};
+use alloc::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Response message correlated to a request by sequence id.
+pub trait TrackedResponse {
+    fn sequence_id(&self) -> u16;
+    fn status(&self) -> NowStatusCode;
+}
+
+macro_rules! impl_tracked_response {
+    ($($rsp_ty:ident),+) => {
+        $(
+            impl TrackedResponse for $rsp_ty {
+                fn sequence_id(&self) -> u16 {
+                    self.sequence_id
+                }
+
+                fn status(&self) -> NowStatusCode {
+                    $rsp_ty::status(self)
+                }
+            }
+        )+
+    };
+}
+
+impl_tracked_response!(NowSurfaceListRspMsg, NowSurfaceMapRspMsg, NowSurfaceSelectRspMsg);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestCompletion<Request> {
+    pub sequence_id: u16,
+    pub request: Request,
+    pub status: NowStatusCode,
+    pub elapsed: Duration,
+}
+
+impl<Request> RequestCompletion<Request> {
+    pub fn is_success(&self) -> bool {
+        self.status.is_success()
+    }
+}
+
+/// Keeps track of in-flight requests and matches them with their response.
+#[derive(Debug, Clone)]
+pub struct RequestTracker<Request> {
+    next_sequence_id: u16,
+    in_flight: BTreeMap<u16, (Request, Instant)>,
+}
+
+impl<Request> Default for RequestTracker<Request> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Request> RequestTracker<Request> {
+    pub fn new() -> Self {
+        Self {
+            next_sequence_id: 0,
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    /// Registers a new request and returns the sequence id to use for it.
+    pub fn start(&mut self, request: Request, now: Instant) -> u16 {
+        let sequence_id = self.next_sequence_id;
+        self.next_sequence_id = self.next_sequence_id.wrapping_add(1);
+        self.in_flight.insert(sequence_id, (request, now));
+        sequence_id
+    }
+
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn in_flight_sequence_ids(&self) -> Vec<u16> {
+        self.in_flight.keys().copied().collect()
+    }
+
+    pub fn is_in_flight(&self, sequence_id: u16) -> bool {
+        self.in_flight.contains_key(&sequence_id)
+    }
+
+    /// Completes the request matching the response. Returns `None` for unsolicited responses.
+    pub fn complete<Response: TrackedResponse>(
+        &mut self,
+        response: &Response,
+        now: Instant,
+    ) -> Option<RequestCompletion<Request>> {
+        let sequence_id = response.sequence_id();
+        let (request, sent) = self.in_flight.remove(&sequence_id)?;
+        let status = response.status();
+
+        if !status.is_success() {
+            log::debug!("request with sequence id {} failed: {}", sequence_id, status);
+        }
+
+        Some(RequestCompletion {
+            sequence_id,
+            request,
+            status,
+            elapsed: now.saturating_duration_since(sent),
+        })
+    }
+
+    /// Removes and returns requests in flight for longer than `timeout`.
+    pub fn expire(&mut self, now: Instant, timeout: Duration) -> Vec<(u16, Request)> {
+        let expired_ids: Vec<u16> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, sent))| now.saturating_duration_since(*sent) >= timeout)
+            .map(|(id, _)| *id)
+            .collect();
+
+        expired_ids
+            .into_iter()
+            .filter_map(|id| self.in_flight.remove(&id).map(|(request, _)| (id, request)))
+            .collect()
+    }
+
+    /// Completes the surface request answered by given message, if it is a response.
+    pub fn complete_surface_msg(&mut self, msg: &NowSurfaceMsg, now: Instant) -> Option<RequestCompletion<Request>> {
+        match msg {
+            NowSurfaceMsg::ListRsp(rsp) => self.complete(rsp, now),
+            NowSurfaceMsg::MapRsp(rsp) => self.complete(rsp, now),
+            NowSurfaceMsg::SelectRsp(rsp) => self.complete(rsp, now),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{message::SurfaceResponseFlags, serialization::Decode};
+
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    enum SurfaceRequest {
+        Select(u16),
+        Map,
+    }
+
+    #[test]
+    fn status_surfaced_on_completion() {
+        let t0 = Instant::now();
+        let mut tracker = RequestTracker::new();
+
+        let select_id = tracker.start(SurfaceRequest::Select(2), t0);
+        let map_id = tracker.start(SurfaceRequest::Map, t0);
+        assert_eq!(tracker.in_flight_count(), 2);
+
+        let rsp = NowSurfaceMsg::decode(&[0x06, 0x81, select_id as u8, 0x00, 0x03, 0x00]).unwrap();
+        let completion = tracker
+            .complete_surface_msg(&rsp, t0 + Duration::from_millis(40))
+            .unwrap();
+        assert_eq!(completion.request, SurfaceRequest::Select(2));
+        assert_eq!(completion.status, NowStatusCode::AccessDenied);
+        assert_eq!(completion.elapsed, Duration::from_millis(40));
+        assert!(!completion.is_success());
+
+        let rsp = NowSurfaceMapRspMsg::new(SurfaceResponseFlags::new_empty(), map_id);
+        let completion = tracker.complete(&rsp, t0).unwrap();
+        assert_eq!(completion.request, SurfaceRequest::Map);
+        assert!(completion.is_success());
+
+        // unsolicited
+        assert!(tracker.complete(&rsp, t0).is_none());
+    }
+
+    #[test]
+    fn expiration() {
+        let t0 = Instant::now();
+        let mut tracker = RequestTracker::new();
+        tracker.start(SurfaceRequest::Map, t0);
+        let id = tracker.start(SurfaceRequest::Select(0), t0 + Duration::from_secs(2));
+
+        let expired = tracker.expire(t0 + Duration::from_secs(3), Duration::from_secs(3));
+        assert_eq!(expired, vec![(0, SurfaceRequest::Map)]);
+        assert!(tracker.is_in_flight(id));
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+/// Smoothed round trip time estimator (RFC 6298 style).
+#[derive(Debug, Clone, Default)]
+pub struct RttEstimator {
+    latest: Option<Duration>,
+    smoothed: Option<Duration>,
+    variation: Duration,
+    samples: u64,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, sample: Duration) {
+        self.latest = Some(sample);
+        self.samples += 1;
+
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(sample);
+                self.variation = sample / 2;
+            }
+            Some(smoothed) => {
+                let delta = smoothed.abs_diff(sample);
+                // rttvar = 3/4 * rttvar + 1/4 * |srtt - sample|
+                self.variation = (self.variation * 3 + delta) / 4;
+                // srtt = 7/8 * srtt + 1/8 * sample
+                self.smoothed = Some((smoothed * 7 + sample) / 8);
+            }
+        }
+    }
+
+    /// Latest raw sample.
+    pub fn latest(&self) -> Option<Duration> {
+        self.latest
+    }
+
+    /// Smoothed round trip time.
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+
+    /// Round trip time variation (jitter estimate).
+    pub fn variation(&self) -> Duration {
+        self.variation
+    }
+
+    pub fn samples_count(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smoothing() {
+        let mut rtt = RttEstimator::new();
+        assert!(rtt.smoothed().is_none());
+
+        rtt.update(Duration::from_millis(100));
+        assert_eq!(rtt.smoothed(), Some(Duration::from_millis(100)));
+        assert_eq!(rtt.variation(), Duration::from_millis(50));
+
+        rtt.update(Duration::from_millis(180));
+        assert_eq!(rtt.latest(), Some(Duration::from_millis(180)));
+        assert_eq!(rtt.smoothed(), Some(Duration::from_millis(110)));
+        assert_eq!(rtt.variation(), Duration::from_millis(57) + Duration::from_micros(500));
+    }
+}
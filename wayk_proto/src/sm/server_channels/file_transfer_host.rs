@@ -0,0 +1,384 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        ChannelName, NowFileTransferAbortMsg, NowFileTransferDataMsg, NowFileTransferDownloadReqMsg,
+        NowFileTransferDownloadRspMsg, NowFileTransferMsg, NowStatusCode, NowVirtualChannel,
+    },
+    sm::{FileTransferPolicy, SessionUsage, VirtChannelSMResult, VirtualChannelSM},
+};
+use alloc::collections::BTreeMap;
+
+pub trait FileTransferHostCallbackTrait {
+    /// Returns the actual size of the requested file or `None` if it can't be found.
+    fn on_download_req(&mut self, path: &str) -> Option<u64> {
+        #![allow(unused_variables)]
+        None
+    }
+
+    fn on_download_aborted(&mut self, request_id: u16, status: NowStatusCode) {
+        #![allow(unused_variables)]
+    }
+}
+
+sa::assert_obj_safe!(FileTransferHostCallbackTrait);
+
+pub struct DummyFileTransferHostCallback;
+impl FileTransferHostCallbackTrait for DummyFileTransferHostCallback {}
+
+/// Upload to the client in progress. Limits are those of the policy in effect when it was requested.
+#[derive(Debug, Clone)]
+struct Upload {
+    policy: FileTransferPolicy,
+    file_size: u64,
+    sent: u64,
+}
+
+#[derive(PartialEq, Debug)]
+enum FileTransferHostState {
+    Initial,
+    Active,
+    Terminated,
+}
+
+/// Host side of the file transfer channel.
+///
+/// The policy is sent as soon as the channel is opened and enforced again on
+/// every request and data chunk regardless of the checks done by the client.
+pub struct FileTransferHostChannelSM<UserCallback> {
+    state: FileTransferHostState,
+    policy: FileTransferPolicy,
+    used: u64,
+    uploads: BTreeMap<u16, Upload>,
+    user_callback: UserCallback,
+}
+
+impl<UserCallback> FileTransferHostChannelSM<UserCallback>
+where
+    UserCallback: FileTransferHostCallbackTrait,
+{
+    pub fn new(policy: FileTransferPolicy, user_callback: UserCallback) -> Self {
+        Self {
+            state: FileTransferHostState::Initial,
+            policy,
+            used: 0,
+            uploads: BTreeMap::new(),
+            user_callback,
+        }
+    }
+
+    pub fn policy(&self) -> &FileTransferPolicy {
+        &self.policy
+    }
+
+    /// Changes the policy for new requests. Returns the policy message to send to the client.
+    pub fn set_policy<'msg>(&mut self, policy: FileTransferPolicy) -> Result<NowVirtualChannel<'msg>, ProtoError> {
+        let msg = policy.to_msg()?;
+        self.policy = policy;
+        Ok(msg.into())
+    }
+
+    pub fn usage(&self) -> SessionUsage {
+        SessionUsage {
+            used: self.used,
+            limit: self.policy.max_session_bytes,
+        }
+    }
+
+    pub fn is_in_progress(&self, request_id: u16) -> bool {
+        self.uploads.contains_key(&request_id)
+    }
+
+    /// Returns the message to send for the given chunk: data, or an abort if
+    /// the chunk exceeds the policy the download was accepted with.
+    pub fn send_chunk<'a>(
+        &mut self,
+        request_id: u16,
+        data: &'a [u8],
+        last: bool,
+    ) -> Result<NowVirtualChannel<'a>, ProtoError> {
+        let upload = self
+            .uploads
+            .get_mut(&request_id)
+            .chain(ProtoErrorKind::VirtualChannel(ChannelName::FileTransfer))
+            .or_else_desc(|| format!("no download {} in progress", request_id))?;
+
+        let len = data.len() as u64;
+        if upload.sent + len > upload.file_size || upload.policy.check_size(len, self.used).is_err() {
+            log::warn!("download {} exceeds the transfer policy: aborting", request_id);
+            self.uploads.remove(&request_id);
+            return Ok(NowFileTransferAbortMsg::new(request_id, NowStatusCode::PolicyDenied).into());
+        }
+
+        upload.sent += len;
+        self.used += len;
+        if last {
+            self.uploads.remove(&request_id);
+        }
+
+        Ok(NowFileTransferDataMsg::new(request_id, data, last).into())
+    }
+
+    fn __on_download_req<'msg>(&mut self, msg: &NowFileTransferDownloadReqMsg) -> VirtChannelSMResult<'msg> {
+        let path = msg.path.as_str();
+
+        if let Err(violation) = self.policy.check_path(path) {
+            log::warn!("download of {:?} denied: {}", path, violation);
+            return Ok(Some(
+                NowFileTransferDownloadRspMsg::new_with_status(msg.request_id, NowStatusCode::PolicyDenied).into(),
+            ));
+        }
+
+        let file_size = match self.user_callback.on_download_req(path) {
+            Some(file_size) => file_size,
+            None => {
+                return Ok(Some(
+                    NowFileTransferDownloadRspMsg::new_with_status(msg.request_id, NowStatusCode::NotFound).into(),
+                ))
+            }
+        };
+
+        // actual size is checked, not the one announced by the client
+        if let Err(violation) = self.policy.check_size(file_size, self.used) {
+            log::warn!("download of {:?} denied: {}", path, violation);
+            return Ok(Some(
+                NowFileTransferDownloadRspMsg::new_with_status(msg.request_id, NowStatusCode::PolicyDenied).into(),
+            ));
+        }
+
+        self.uploads.insert(
+            msg.request_id,
+            Upload {
+                policy: self.policy.clone(),
+                file_size,
+                sent: 0,
+            },
+        );
+
+        Ok(Some(
+            NowFileTransferDownloadRspMsg::new(msg.request_id, file_size).into(),
+        ))
+    }
+
+    fn __unexpected_with_call<'msg>(&self) -> VirtChannelSMResult<'msg> {
+        ProtoError::new(ProtoErrorKind::VirtualChannel(self.get_channel_name())).or_desc(format!(
+            "unexpected call to `update_with_chan_msg` in state {:?}",
+            self.state
+        ))
+    }
+
+    fn __unexpected_without_call<'msg>(&self) -> VirtChannelSMResult<'msg> {
+        ProtoError::new(ProtoErrorKind::VirtualChannel(self.get_channel_name())).or_desc(format!(
+            "unexpected call to `update_without_chan_msg` in state {:?}",
+            self.state
+        ))
+    }
+
+    fn __unexpected_message<'msg: 'a, 'a>(&self, unexpected: &'a NowVirtualChannel<'msg>) -> VirtChannelSMResult<'msg> {
+        ProtoError::new(ProtoErrorKind::VirtualChannel(self.get_channel_name())).or_desc(format!(
+            "received an unexpected message in state {:?}: {:?}",
+            self.state, unexpected
+        ))
+    }
+}
+
+impl<UserCallback> VirtualChannelSM for FileTransferHostChannelSM<UserCallback>
+where
+    UserCallback: FileTransferHostCallbackTrait,
+{
+    fn get_channel_name(&self) -> ChannelName {
+        ChannelName::FileTransfer
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.state == FileTransferHostState::Terminated
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        self.state == FileTransferHostState::Active
+    }
+
+    fn update_without_chan_msg<'msg>(&mut self) -> VirtChannelSMResult<'msg> {
+        match self.state {
+            FileTransferHostState::Initial => {
+                log::trace!("send transfer policy");
+                self.state = FileTransferHostState::Active;
+                Ok(Some(self.policy.to_msg()?.into()))
+            }
+            _ => self.__unexpected_without_call(),
+        }
+    }
+
+    fn update_with_chan_msg<'msg: 'a, 'a>(
+        &mut self,
+        chan_msg: &'a NowVirtualChannel<'msg>,
+    ) -> VirtChannelSMResult<'msg> {
+        match chan_msg {
+            NowVirtualChannel::FileTransfer(msg) => match self.state {
+                FileTransferHostState::Active => match msg {
+                    NowFileTransferMsg::DownloadReq(msg) => self.__on_download_req(msg),
+                    NowFileTransferMsg::Abort(msg) => {
+                        if self.uploads.remove(&msg.request_id).is_some() {
+                            self.user_callback.on_download_aborted(msg.request_id, msg.status);
+                        }
+                        Ok(None)
+                    }
+                    _ => self.__unexpected_message(chan_msg),
+                },
+                _ => self.__unexpected_with_call(),
+            },
+            _ => self.__unexpected_message(chan_msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm::{FileTransferChannelCallbackTrait, FileTransferChannelSM};
+    use std::{cell::RefCell, rc::Rc};
+
+    const MB: u64 = 1024 * 1024;
+
+    /// Host file system: path and size.
+    struct HostFiles(Vec<(&'static str, u64)>);
+
+    impl FileTransferHostCallbackTrait for HostFiles {
+        fn on_download_req(&mut self, path: &str) -> Option<u64> {
+            self.0.iter().find(|(p, _)| *p == path).map(|(_, size)| *size)
+        }
+    }
+
+    #[derive(Default)]
+    struct ClientEvents {
+        started: Vec<u16>,
+        received: u64,
+        failed: Vec<(u16, NowStatusCode)>,
+    }
+
+    struct ClientCallback(Rc<RefCell<ClientEvents>>);
+
+    impl FileTransferChannelCallbackTrait for ClientCallback {
+        fn on_download_started(&mut self, request_id: u16, _: u64) {
+            self.0.borrow_mut().started.push(request_id);
+        }
+
+        fn on_data<'msg>(&mut self, _: u16, data: &[u8], _: bool) -> VirtChannelSMResult<'msg> {
+            self.0.borrow_mut().received += data.len() as u64;
+            Ok(None)
+        }
+
+        fn on_download_failed(&mut self, request_id: u16, status: NowStatusCode) {
+            self.0.borrow_mut().failed.push((request_id, status));
+        }
+    }
+
+    type Client = FileTransferChannelSM<ClientCallback>;
+    type Host = FileTransferHostChannelSM<HostFiles>;
+
+    fn setup(policy: FileTransferPolicy, files: Vec<(&'static str, u64)>) -> (Client, Host, Rc<RefCell<ClientEvents>>) {
+        let events = Rc::new(RefCell::new(ClientEvents::default()));
+        let mut client = FileTransferChannelSM::new(ClientCallback(Rc::clone(&events)));
+        let mut host = FileTransferHostChannelSM::new(policy, HostFiles(files));
+
+        let policy_msg = host.update_without_chan_msg().unwrap().unwrap();
+        assert!(client.update_with_chan_msg(&policy_msg).unwrap().is_none());
+
+        (client, host, events)
+    }
+
+    fn request(client: &mut Client, host: &mut Host, path: &str, size: u64) -> u16 {
+        let req = client.request_download(path, size).unwrap();
+        let request_id = match &req {
+            NowVirtualChannel::FileTransfer(NowFileTransferMsg::DownloadReq(req)) => req.request_id,
+            other => panic!("expected a download request, got {:?}", other),
+        };
+        let rsp = host.update_with_chan_msg(&req).unwrap().unwrap();
+        assert!(client.update_with_chan_msg(&rsp).unwrap().is_none());
+        request_id
+    }
+
+    #[test]
+    fn per_file_limit() {
+        let policy = FileTransferPolicy::unrestricted().max_file_size(10 * MB);
+        let (mut client, mut host, events) = setup(policy, vec![("/data/small.bin", MB), ("/data/big.bin", 20 * MB)]);
+
+        // refused client-side
+        assert!(client.request_download("/data/big.bin", 20 * MB).is_err());
+
+        // client announcing a wrong size is caught by the host
+        let id = request(&mut client, &mut host, "/data/big.bin", MB);
+        assert_eq!(events.borrow().failed, vec![(id, NowStatusCode::PolicyDenied)]);
+        assert!(!client.is_in_progress(id));
+        assert!(!host.is_in_progress(id));
+
+        let id = request(&mut client, &mut host, "/data/small.bin", MB);
+        assert_eq!(events.borrow().started, vec![id]);
+        assert!(host.is_in_progress(id));
+    }
+
+    #[test]
+    fn session_total_exhaustion_mid_transfer() {
+        let policy = FileTransferPolicy::unrestricted().max_session_bytes(100);
+        let (mut client, mut host, events) = setup(policy, vec![("/a", 60), ("/b", 60)]);
+
+        // both fit in the remaining quota when requested
+        let a = request(&mut client, &mut host, "/a", 60);
+        let b = request(&mut client, &mut host, "/b", 60);
+        assert_eq!(events.borrow().started, vec![a, b]);
+
+        let chunk = [0u8; 30];
+        for (id, last) in &[(a, false), (b, false), (a, true)] {
+            let data = host.send_chunk(*id, &chunk, *last).unwrap();
+            assert!(client.update_with_chan_msg(&data).unwrap().is_none());
+        }
+        assert_eq!(client.usage().used, 90);
+        assert_eq!(client.usage().remaining(), Some(10));
+
+        // quota exhausted in the middle of the second download
+        let abort = host.send_chunk(b, &chunk, true).unwrap();
+        match &abort {
+            NowVirtualChannel::FileTransfer(NowFileTransferMsg::Abort(msg)) => {
+                assert_eq!(msg.status, NowStatusCode::PolicyDenied)
+            }
+            other => panic!("expected an abort, got {:?}", other),
+        }
+        assert!(client.update_with_chan_msg(&abort).unwrap().is_none());
+        assert_eq!(events.borrow().failed, vec![(b, NowStatusCode::PolicyDenied)]);
+        assert_eq!(events.borrow().received, 90);
+        assert_eq!(host.usage(), client.usage());
+
+        // new requests refused client-side
+        assert!(client.request_download("/a", 60).is_err());
+    }
+
+    #[test]
+    fn path_outside_allowed_roots_rejected_client_side() {
+        let policy = FileTransferPolicy::unrestricted().allowed_roots(vec!["/srv/share"]);
+        let (mut client, _, _) = setup(policy, vec![("/etc/shadow", 1)]);
+
+        assert!(client.request_download("/etc/shadow", 1).is_err());
+        assert!(client.request_download("/srv/share/../../etc/shadow", 1).is_err());
+        assert!(!client.is_in_progress(0));
+        assert!(client.request_download("/srv/share/notes.txt", 1).is_ok());
+    }
+
+    #[test]
+    fn policy_change_applies_to_new_requests_only() {
+        let (mut client, mut host, _) = setup(FileTransferPolicy::unrestricted(), vec![("/a", 10 * MB)]);
+        let id = request(&mut client, &mut host, "/a", 10 * MB);
+
+        let policy_msg = host
+            .set_policy(FileTransferPolicy::unrestricted().max_file_size(MB))
+            .unwrap();
+        client.update_with_chan_msg(&policy_msg).unwrap();
+        assert_eq!(client.policy().max_file_size, Some(MB));
+
+        // download in progress is not affected
+        let chunk = vec![0u8; (2 * MB) as usize];
+        let data = host.send_chunk(id, &chunk, false).unwrap();
+        assert!(client.update_with_chan_msg(&data).unwrap().is_none());
+        assert!(client.is_in_progress(id));
+
+        assert!(client.request_download("/a", 10 * MB).is_err());
+    }
+}
@@ -0,0 +1,4 @@
+pub mod file_transfer_host;
+
+// re-export
+pub use file_transfer_host::*;
@@ -0,0 +1,305 @@
+/** server connection sequence **/
+mod sub_sm;
+
+use crate::{
+    message::{AuthType, ChannelName, NowCapset, NowChannelDef, NowMessage},
+    sm::{
+        AccessControlRc, ConnectionSM, ConnectionSMResult, ConnectionSMSharedData, ConnectionSMSharedDataRc,
+        ConnectionSeqCallbackTrait, ConnectionState, DummyConnectionSM, HandoffRegistryRc,
+    },
+    version::NowProtocolVersionRange,
+};
+use std::{cell::RefCell, rc::Rc, time::Instant};
+
+/// Server side of the connection sequence.
+///
+/// Shared data initially holds what the server offers (authentication types, capabilities
+/// and available channels) and is narrowed down to what is agreed with the client.
+pub struct ServerConnectionSeqSM<UserCallback> {
+    user_callback: UserCallback,
+    state: ConnectionState,
+    current_sm: Box<dyn ConnectionSM>,
+    authenticate_sm: Box<dyn ConnectionSM>,
+    shared_data: ConnectionSMSharedDataRc,
+    handoff_registry: Option<HandoffRegistryRc>,
+    access_control: Option<AccessControlRc>,
+}
+
+impl<UserCallback> ServerConnectionSeqSM<UserCallback>
+where
+    UserCallback: ConnectionSeqCallbackTrait,
+{
+    pub fn builder(user_callback: UserCallback) -> ServerConnectionSeqBuilder<UserCallback> {
+        ServerConnectionSeqBuilder {
+            user_callback,
+            available_auth_types: Vec::new(),
+            authenticate_sm: Box::new(DummyConnectionSM),
+            capabilities: Vec::new(),
+            available_channels: Vec::new(),
+            versions: NowProtocolVersionRange::default(),
+            handoff_registry: None,
+            access_control: None,
+            tls: false,
+        }
+    }
+
+    pub fn new(
+        user_callback: UserCallback,
+        available_auth_types: Vec<AuthType>,
+        authenticate_sm: Box<dyn ConnectionSM>,
+        capabilities: Vec<NowCapset<'static>>,
+        available_channels: Vec<NowChannelDef>,
+    ) -> Self {
+        let shared_data = Rc::new(RefCell::new(ConnectionSMSharedData {
+            available_auth_types,
+            capabilities,
+            peer_capabilities: Vec::new(),
+            channels: available_channels,
+            handoff: false,
+            tls: false,
+            peer_certificate: None,
+            session_id: None,
+            peer_version: None,
+            versions: NowProtocolVersionRange::default(),
+            negotiated_version: None,
+        }));
+        Self {
+            user_callback,
+            state: ConnectionState::Handshake,
+            current_sm: Box::new(sub_sm::ServerHandshakeSM::new(Rc::clone(&shared_data))),
+            authenticate_sm,
+            shared_data,
+            handoff_registry: None,
+            access_control: None,
+        }
+    }
+
+    pub fn get_state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// See `ServerConnectionSeqBuilder::versions`.
+    pub fn set_versions(&mut self, versions: NowProtocolVersionRange) {
+        self.shared_data.borrow_mut().versions = versions;
+    }
+
+    /// See `ServerConnectionSeqBuilder::handoff_registry`.
+    pub fn set_handoff_registry(&mut self, handoff_registry: Option<HandoffRegistryRc>) {
+        self.handoff_registry = handoff_registry;
+    }
+
+    /// See `ServerConnectionSeqBuilder::access_control`.
+    pub fn set_access_control(&mut self, access_control: Option<AccessControlRc>) {
+        self.access_control = access_control;
+    }
+
+    fn __check_result(&mut self, result: &ConnectionSMResult<'_>) {
+        if result.is_err() {
+            log::trace!("an error occurred. Set connection state machine to final state.");
+            self.state = ConnectionState::Final;
+        }
+    }
+
+    fn __go_to_next_state(&mut self) {
+        match self.state {
+            ConnectionState::Handshake => {
+                self.shared_data.borrow_mut().retain_supported_channels();
+                self.state = ConnectionState::Negotiate;
+                self.current_sm = Box::new(sub_sm::ServerNegotiateSM::new(
+                    Rc::clone(&self.shared_data),
+                    self.handoff_registry.is_some(),
+                ));
+                self.user_callback.on_handshake_completed(&self.shared_data.borrow());
+            }
+            ConnectionState::Negotiate if self.shared_data.borrow().handoff => {
+                // handoff token replaces authentication
+                self.state = ConnectionState::Associate;
+                self.current_sm = Box::new(sub_sm::ServerAssociateSM::new(
+                    Rc::clone(&self.shared_data),
+                    self.handoff_registry.clone(),
+                    self.access_control.clone(),
+                ));
+                self.user_callback.on_negotiate_completed(&self.shared_data.borrow());
+            }
+            ConnectionState::Negotiate => {
+                self.state = ConnectionState::Authenticate;
+                self.authenticate_sm.set_shared_data(Rc::clone(&self.shared_data));
+                std::mem::swap(&mut self.current_sm, &mut self.authenticate_sm);
+
+                // set invalid authenticate_sm field to dummy connection state machine
+                let mut dummy_sm: Box<dyn ConnectionSM> = Box::new(DummyConnectionSM);
+                std::mem::swap(&mut self.authenticate_sm, &mut dummy_sm);
+
+                self.user_callback.on_negotiate_completed(&self.shared_data.borrow());
+            }
+            ConnectionState::Authenticate => {
+                self.state = ConnectionState::Associate;
+                self.current_sm = Box::new(sub_sm::ServerAssociateSM::new(
+                    Rc::clone(&self.shared_data),
+                    None,
+                    self.access_control.clone(),
+                ));
+                self.user_callback.on_authenticate_completed(&self.shared_data.borrow());
+            }
+            ConnectionState::Associate => {
+                self.state = ConnectionState::Capabilities;
+                self.current_sm = Box::new(sub_sm::ServerCapabilitiesSM::new(Rc::clone(&self.shared_data)));
+                self.user_callback.on_associate_completed(&self.shared_data.borrow());
+            }
+            ConnectionState::Capabilities => {
+                self.state = ConnectionState::Channels;
+                self.current_sm = Box::new(sub_sm::ServerChannelsSM::new(Rc::clone(&self.shared_data)));
+                self.user_callback.on_capabilities_completed(&self.shared_data.borrow());
+            }
+            ConnectionState::Channels => {
+                self.state = ConnectionState::Final;
+                self.user_callback.on_connection_completed(&self.shared_data.borrow());
+            }
+            ConnectionState::Final => log::warn!("Attempted to go to the next state from the final state."),
+        }
+    }
+}
+
+impl<UserCallback> ConnectionSM for ServerConnectionSeqSM<UserCallback>
+where
+    UserCallback: ConnectionSeqCallbackTrait,
+{
+    fn set_shared_data(&mut self, shared_data: ConnectionSMSharedDataRc) {
+        self.shared_data = shared_data
+    }
+
+    fn get_shared_data(&self) -> Option<ConnectionSMSharedDataRc> {
+        Some(Rc::clone(&self.shared_data))
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.state == ConnectionState::Final
+    }
+
+    fn current_step(&self) -> Option<ConnectionState> {
+        Some(self.state)
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        self.current_sm.waiting_for_packet()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.current_sm.next_deadline()
+    }
+
+    fn update_without_message<'msg>(&mut self) -> ConnectionSMResult<'msg> {
+        let response = self.current_sm.update_without_message();
+
+        if self.current_sm.is_terminated() {
+            self.__go_to_next_state();
+        } else {
+            self.__check_result(&response);
+        }
+
+        response
+    }
+
+    fn update_with_message<'msg: 'a, 'a>(&mut self, msg: &'a NowMessage<'msg>) -> ConnectionSMResult<'msg> {
+        if let (ConnectionState::Handshake, NowMessage::Handshake(handshake)) = (self.state, msg) {
+            self.shared_data.borrow_mut().peer_version = Some(handshake.version());
+        }
+
+        let response = self.current_sm.update_with_message(msg);
+
+        if self.current_sm.is_terminated() {
+            self.__go_to_next_state();
+        } else {
+            self.__check_result(&response);
+        }
+
+        response
+    }
+}
+
+// builder
+
+pub struct ServerConnectionSeqBuilder<UserCallback> {
+    available_auth_types: Vec<AuthType>,
+    authenticate_sm: Box<dyn ConnectionSM>,
+    capabilities: Vec<NowCapset<'static>>,
+    available_channels: Vec<NowChannelDef>,
+    versions: NowProtocolVersionRange,
+    handoff_registry: Option<HandoffRegistryRc>,
+    access_control: Option<AccessControlRc>,
+    tls: bool,
+    user_callback: UserCallback,
+}
+
+impl<UserCallback> ServerConnectionSeqBuilder<UserCallback>
+where
+    UserCallback: ConnectionSeqCallbackTrait,
+{
+    pub fn available_auth_process(self, available_auth_types: Vec<AuthType>) -> Self {
+        Self {
+            available_auth_types,
+            ..self
+        }
+    }
+
+    pub fn authenticate_sm<P: ConnectionSM + 'static>(self, sm: P) -> Self {
+        Self {
+            authenticate_sm: Box::new(sm),
+            ..self
+        }
+    }
+
+    pub fn capabilities(self, capabilities: Vec<NowCapset<'static>>) -> Self {
+        Self { capabilities, ..self }
+    }
+
+    pub fn available_channels(self, available_channels: Vec<ChannelName>) -> Self {
+        Self {
+            available_channels: available_channels.into_iter().map(NowChannelDef::new).collect(),
+            ..self
+        }
+    }
+
+    /// Protocol versions supported. The highest one also supported by the client is selected.
+    pub fn versions(self, versions: NowProtocolVersionRange) -> Self {
+        Self { versions, ..self }
+    }
+
+    /// Accepts session handoffs using tokens issued by `handoff_registry`.
+    pub fn handoff_registry(self, handoff_registry: HandoffRegistryRc) -> Self {
+        Self {
+            handoff_registry: Some(handoff_registry),
+            ..self
+        }
+    }
+
+    /// Decides whether authenticated clients are granted remote control, typically by prompting the local user.
+    /// Access is granted to every authenticated client otherwise.
+    pub fn access_control(self, access_control: AccessControlRc) -> Self {
+        Self {
+            access_control: Some(access_control),
+            ..self
+        }
+    }
+
+    /// Accepts upgrading the transport to TLS once negotiation completes, if requested by the client
+    /// (see `NowTransport::connect_tls`).
+    pub fn tls(self, tls: bool) -> Self {
+        Self { tls, ..self }
+    }
+
+    pub fn build(self) -> ServerConnectionSeqSM<UserCallback> {
+        let mut connection_seq = ServerConnectionSeqSM::new(
+            self.user_callback,
+            self.available_auth_types,
+            self.authenticate_sm,
+            self.capabilities,
+            self.available_channels,
+        );
+        connection_seq.set_versions(self.versions);
+        connection_seq.set_handoff_registry(self.handoff_registry);
+        connection_seq.set_access_control(self.access_control);
+        connection_seq.shared_data.borrow_mut().tls = self.tls;
+        connection_seq
+    }
+}
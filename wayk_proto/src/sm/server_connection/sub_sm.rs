@@ -0,0 +1,610 @@
+use super::{ConnectionSM, ConnectionSMResult};
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{NowAssociateRequestMsg, NowCapabilitiesMsg, NowMessage},
+    serialization::known_capabilities,
+    sm::{
+        AccessControlRc, AccessDecision, AccessPrompt, ConnectionSMSharedData, ConnectionSMSharedDataRc,
+        ConnectionState, HandoffRegistryRc,
+    },
+};
+use std::{cell::RefCell, rc::Rc, time::Instant};
+
+macro_rules! unexpected_call {
+    ($sm_struct:ident, $self:ident, $method_name:literal) => {
+        ProtoError::new(ProtoErrorKind::ConnectionSequence($sm_struct::CONNECTION_STATE)).or_desc(format!(
+            concat!("unexpected call to `{}::", $method_name, "` in state {:?}"),
+            $sm_struct::NAME,
+            $self.state
+        ))
+    };
+}
+
+macro_rules! unexpected_msg {
+    ($sm_struct:ident, $self:ident, $unexpected_msg:ident) => {
+        ProtoError::new(ProtoErrorKind::UnexpectedMessage($unexpected_msg.get_type())).or_desc(format!(
+            "`{}` received an unexpected message in state {:?}: {:?}",
+            $sm_struct::NAME,
+            $self.state,
+            $unexpected_msg
+        ))
+    };
+}
+
+#[derive(PartialEq, Debug)]
+enum WaitState {
+    Initial,
+    Waiting,
+    Rejected,
+    /// Waiting for the local user to approve remote control.
+    Prompting,
+    Denied,
+    Terminated,
+}
+
+// handshake
+
+pub struct ServerHandshakeSM {
+    state: WaitState,
+    shared_data: Rc<RefCell<ConnectionSMSharedData>>,
+}
+
+impl ServerHandshakeSM {
+    const CONNECTION_STATE: ConnectionState = ConnectionState::Handshake;
+    const NAME: &'static str = "ServerHandshakeSM";
+
+    pub fn new(shared_data: Rc<RefCell<ConnectionSMSharedData>>) -> Self {
+        Self {
+            state: WaitState::Waiting,
+            shared_data,
+        }
+    }
+}
+
+impl ConnectionSM for ServerHandshakeSM {
+    fn set_shared_data(&mut self, shared_data: Rc<RefCell<ConnectionSMSharedData>>) {
+        self.shared_data = shared_data;
+    }
+
+    fn get_shared_data(&self) -> Option<ConnectionSMSharedDataRc> {
+        Some(Rc::clone(&self.shared_data))
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.state == WaitState::Terminated
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        self.state == WaitState::Waiting
+    }
+
+    fn update_without_message<'msg>(&mut self) -> ConnectionSMResult<'msg> {
+        match &self.state {
+            WaitState::Rejected => ProtoError::new(ProtoErrorKind::ConnectionSequence(Self::CONNECTION_STATE))
+                .or_desc("client version is incompatible"),
+            _ => unexpected_call!(Self, self, "update_without_message"),
+        }
+    }
+
+    fn update_with_message<'msg: 'a, 'a>(&mut self, msg: &'a NowMessage<'msg>) -> ConnectionSMResult<'msg> {
+        use wayk_proto::message::{
+            status::{HandshakeStatusCode, NowStatus, SeverityLevel, StatusType},
+            NowHandshakeMsg,
+        };
+
+        match &self.state {
+            WaitState::Waiting => match msg {
+                NowMessage::Handshake(msg) => {
+                    let mut shared_data = self.shared_data.borrow_mut();
+                    match shared_data.versions.select(msg.version()) {
+                        Some(selected) => {
+                            log::trace!("handshake succeeded with version {}", selected);
+                            shared_data.negotiated_version = Some(selected);
+                            self.state = WaitState::Terminated;
+                            Ok(Some(NowHandshakeMsg::new_with_version(selected).into()))
+                        }
+                        None => {
+                            log::warn!(
+                                "client version {} is incompatible (supported versions: {})",
+                                msg.version(),
+                                shared_data.versions
+                            );
+                            self.state = WaitState::Rejected;
+                            let mut rsp = NowHandshakeMsg::new_with_version(shared_data.versions.max);
+                            rsp.configure_failure(
+                                NowStatus::builder(HandshakeStatusCode::Incompatible)
+                                    .severity(SeverityLevel::Error)
+                                    .status_type(StatusType::Handshake)
+                                    .build(),
+                            );
+                            Ok(Some(rsp.into()))
+                        }
+                    }
+                }
+                unexpected => unexpected_msg!(Self, self, unexpected),
+            },
+            _ => unexpected_call!(Self, self, "update_with_message"),
+        }
+    }
+}
+
+// negotiate
+
+pub struct ServerNegotiateSM {
+    state: WaitState,
+    shared_data: Rc<RefCell<ConnectionSMSharedData>>,
+    accept_handoff: bool,
+}
+
+impl ServerNegotiateSM {
+    const CONNECTION_STATE: ConnectionState = ConnectionState::Negotiate;
+    const NAME: &'static str = "ServerNegotiateSM";
+
+    pub fn new(shared_data: Rc<RefCell<ConnectionSMSharedData>>, accept_handoff: bool) -> Self {
+        Self {
+            state: WaitState::Waiting,
+            shared_data,
+            accept_handoff,
+        }
+    }
+}
+
+impl ConnectionSM for ServerNegotiateSM {
+    fn set_shared_data(&mut self, shared_data: Rc<RefCell<ConnectionSMSharedData>>) {
+        self.shared_data = shared_data;
+    }
+
+    fn get_shared_data(&self) -> Option<ConnectionSMSharedDataRc> {
+        Some(Rc::clone(&self.shared_data))
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.state == WaitState::Terminated
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        self.state == WaitState::Waiting
+    }
+
+    fn update_without_message<'msg>(&mut self) -> ConnectionSMResult<'msg> {
+        unexpected_call!(Self, self, "update_without_message")
+    }
+
+    fn update_with_message<'msg: 'a, 'a>(&mut self, msg: &'a NowMessage<'msg>) -> ConnectionSMResult<'msg> {
+        use wayk_proto::message::NowNegotiateMsg;
+
+        match &self.state {
+            WaitState::Waiting => match msg {
+                NowMessage::Negotiate(msg) => {
+                    log::info!("Available authentication methods on client: {:?}", msg.auth_list.0);
+
+                    if msg.flags.handoff() && !self.accept_handoff {
+                        return ProtoError::new(ProtoErrorKind::ConnectionSequence(Self::CONNECTION_STATE))
+                            .or_desc("client requested a session handoff but no handoff registry is configured");
+                    }
+
+                    let mut shared_data = self.shared_data.borrow_mut();
+                    shared_data.handoff = msg.flags.handoff();
+                    // TLS offered by the server, used if requested by the client
+                    shared_data.tls &= msg.flags.tls();
+                    let mut flags = msg.flags;
+                    if !shared_data.tls {
+                        flags.unset_tls();
+                    }
+                    let server_auth_types = shared_data.available_auth_types.clone();
+                    shared_data
+                        .available_auth_types
+                        .retain(|auth_type| msg.auth_list.contains(auth_type));
+
+                    self.state = WaitState::Terminated;
+                    Ok(Some(
+                        NowNegotiateMsg::new_with_auth_list(flags, server_auth_types).into(),
+                    ))
+                }
+                unexpected => unexpected_msg!(Self, self, unexpected),
+            },
+            _ => unexpected_call!(Self, self, "update_with_message"),
+        }
+    }
+}
+
+// associate
+
+pub struct ServerAssociateSM {
+    state: WaitState,
+    shared_data: ConnectionSMSharedDataRc,
+    handoff_registry: Option<HandoffRegistryRc>,
+    access_control: Option<AccessControlRc>,
+    /// Prompt shown to the local user, along with the session requested by the client.
+    prompt: Option<(RefCell<AccessPrompt>, u32)>,
+}
+
+impl ServerAssociateSM {
+    const CONNECTION_STATE: ConnectionState = ConnectionState::Associate;
+    const NAME: &'static str = "ServerAssociateSM";
+
+    pub fn new(
+        shared_data: ConnectionSMSharedDataRc,
+        handoff_registry: Option<HandoffRegistryRc>,
+        access_control: Option<AccessControlRc>,
+    ) -> Self {
+        Self {
+            state: WaitState::Initial,
+            shared_data,
+            handoff_registry,
+            access_control,
+            prompt: None,
+        }
+    }
+
+    fn __request_access<'msg>(&mut self, session_id: u32) -> ConnectionSMResult<'msg> {
+        let access_control = match &self.access_control {
+            Some(access_control) => Rc::clone(access_control),
+            None => return self.__accept(session_id),
+        };
+
+        let decision = access_control
+            .borrow_mut()
+            .on_access_requested(&self.shared_data.borrow());
+        match decision {
+            AccessDecision::Approve => self.__accept(session_id),
+            AccessDecision::Deny => self.__deny(session_id),
+            AccessDecision::Prompt { timeout } => {
+                log::trace!("prompting the local user for access");
+                self.state = WaitState::Prompting;
+                self.prompt = Some((RefCell::new(AccessPrompt::new(timeout, Instant::now())), session_id));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Answer of the local user, `None` while the prompt is shown.
+    fn __prompt_answer(&self) -> Option<bool> {
+        let (prompt, _) = self.prompt.as_ref()?;
+        let access_control = self.access_control.as_ref()?;
+        prompt.borrow_mut().poll(access_control, Instant::now())
+    }
+
+    fn __accept<'msg>(&mut self, session_id: u32) -> ConnectionSMResult<'msg> {
+        use wayk_proto::message::{
+            status::NowStatus, AssociateResponseFlags, NowAssociateMsg, NowAssociateResponseMsg,
+        };
+
+        log::trace!("associate process succeeded");
+        self.state = WaitState::Terminated;
+        let handoff = self.shared_data.borrow().handoff;
+        if handoff {
+            if let Some(registry) = &self.handoff_registry {
+                registry.borrow_mut().hand_off(session_id);
+            }
+        }
+        // handed off sessions keep their id, even 0
+        if session_id == 0 && !handoff {
+            return Ok(Some(NowAssociateMsg::new_response().into()));
+        }
+
+        // client re-attaching to a previous session
+        self.shared_data.borrow_mut().session_id = Some(session_id);
+        Ok(Some(
+            NowAssociateMsg::from(NowAssociateResponseMsg::new_with_session_id(
+                AssociateResponseFlags::new_empty(),
+                NowStatus::default(),
+                session_id,
+            ))
+            .into(),
+        ))
+    }
+
+    fn __deny<'msg>(&mut self, session_id: u32) -> ConnectionSMResult<'msg> {
+        use wayk_proto::message::{
+            status::{AssociateStatusCode, NowStatus, SeverityLevel, StatusType},
+            AssociateResponseFlags, NowAssociateMsg, NowAssociateResponseMsg,
+        };
+
+        log::info!("remote control access denied");
+        self.state = WaitState::Denied;
+        let status = NowStatus::builder(AssociateStatusCode::AccessDenied)
+            .severity(SeverityLevel::Error)
+            .status_type(StatusType::Associate)
+            .build();
+        Ok(Some(
+            NowAssociateMsg::from(NowAssociateResponseMsg::new_with_session_id(
+                AssociateResponseFlags::new_empty().set_failure(),
+                status,
+                session_id,
+            ))
+            .into(),
+        ))
+    }
+
+    fn __handoff<'msg>(&mut self, msg: &NowAssociateRequestMsg) -> ConnectionSMResult<'msg> {
+        use wayk_proto::message::{
+            status::{AssociateStatusCode, NowStatus, SeverityLevel, StatusType},
+            AssociateResponseFlags, NowAssociateMsg, NowAssociateResponseMsg,
+        };
+
+        let registry = self
+            .handoff_registry
+            .as_ref()
+            .chain(ProtoErrorKind::ConnectionSequence(Self::CONNECTION_STATE))
+            .or_desc("no handoff registry configured")?;
+        let redeemed = match msg.handoff_token {
+            Some(token) => registry.borrow_mut().redeem(msg.session_id, token, Instant::now()),
+            None => Err(AssociateStatusCode::HandoffTokenInvalid),
+        };
+        let carry_over_access = registry.borrow().carry_over_access();
+
+        match redeemed {
+            Ok(grant) => {
+                log::trace!("session {} handed off", grant.session_id);
+                grant.apply_to(&mut self.shared_data.borrow_mut().capabilities);
+                if carry_over_access {
+                    self.__accept(grant.session_id)
+                } else {
+                    self.__request_access(grant.session_id)
+                }
+            }
+            Err(code) => {
+                log::warn!("session handoff rejected: {}", code);
+                self.state = WaitState::Rejected;
+                let status = NowStatus::builder(code)
+                    .severity(SeverityLevel::Error)
+                    .status_type(StatusType::Associate)
+                    .build();
+                Ok(Some(
+                    NowAssociateMsg::from(NowAssociateResponseMsg::new_with_session_id(
+                        AssociateResponseFlags::new_empty().set_failure(),
+                        status,
+                        msg.session_id,
+                    ))
+                    .into(),
+                ))
+            }
+        }
+    }
+}
+
+impl ConnectionSM for ServerAssociateSM {
+    fn set_shared_data(&mut self, shared_data: ConnectionSMSharedDataRc) {
+        self.shared_data = shared_data;
+    }
+
+    fn get_shared_data(&self) -> Option<ConnectionSMSharedDataRc> {
+        Some(Rc::clone(&self.shared_data))
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.state == WaitState::Terminated
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        match self.state {
+            WaitState::Waiting => true,
+            WaitState::Prompting => self.__prompt_answer().is_none(),
+            _ => false,
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        match (&self.state, &self.prompt) {
+            (WaitState::Prompting, Some((prompt, _))) => prompt.borrow().next_deadline(Instant::now()),
+            _ => None,
+        }
+    }
+
+    fn update_without_message<'msg>(&mut self) -> ConnectionSMResult<'msg> {
+        use wayk_proto::message::NowAssociateMsg;
+
+        match &self.state {
+            WaitState::Initial => {
+                self.state = WaitState::Waiting;
+                Ok(Some(NowAssociateMsg::new_info().into()))
+            }
+            WaitState::Prompting => {
+                let session_id = self.prompt.as_ref().map_or(0, |(_, session_id)| *session_id);
+                match self.__prompt_answer() {
+                    Some(true) => self.__accept(session_id),
+                    Some(false) => self.__deny(session_id),
+                    None => unexpected_call!(Self, self, "update_without_message"),
+                }
+            }
+            WaitState::Rejected => ProtoError::new(ProtoErrorKind::ConnectionSequence(Self::CONNECTION_STATE))
+                .or_desc("session handoff rejected"),
+            WaitState::Denied => ProtoError::new(ProtoErrorKind::ConnectionSequence(Self::CONNECTION_STATE))
+                .or_desc("remote control access denied"),
+            _ => unexpected_call!(Self, self, "update_without_message"),
+        }
+    }
+
+    fn update_with_message<'msg: 'a, 'a>(&mut self, msg: &'a NowMessage<'msg>) -> ConnectionSMResult<'msg> {
+        use wayk_proto::message::NowAssociateMsg;
+
+        match &self.state {
+            WaitState::Waiting => match msg {
+                NowMessage::Associate(NowAssociateMsg::Request(msg)) if self.shared_data.borrow().handoff => {
+                    self.__handoff(msg)
+                }
+                NowMessage::Associate(NowAssociateMsg::Request(msg)) => self.__request_access(msg.session_id),
+                unexpected => unexpected_msg!(Self, self, unexpected),
+            },
+            _ => unexpected_call!(Self, self, "update_with_message"),
+        }
+    }
+}
+
+// capabilities
+
+pub struct ServerCapabilitiesSM {
+    state: WaitState,
+    shared_data: ConnectionSMSharedDataRc,
+}
+
+impl ServerCapabilitiesSM {
+    const CONNECTION_STATE: ConnectionState = ConnectionState::Capabilities;
+    const NAME: &'static str = "ServerCapabilitiesSM";
+
+    pub fn new(shared_data: ConnectionSMSharedDataRc) -> Self {
+        Self {
+            state: WaitState::Initial,
+            shared_data,
+        }
+    }
+}
+
+impl ConnectionSM for ServerCapabilitiesSM {
+    fn set_shared_data(&mut self, shared_data: ConnectionSMSharedDataRc) {
+        self.shared_data = shared_data;
+    }
+
+    fn get_shared_data(&self) -> Option<ConnectionSMSharedDataRc> {
+        Some(Rc::clone(&self.shared_data))
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.state == WaitState::Terminated
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        self.state == WaitState::Waiting
+    }
+
+    fn update_without_message<'msg>(&mut self) -> ConnectionSMResult<'msg> {
+        match &self.state {
+            WaitState::Initial => {
+                self.state = WaitState::Waiting;
+                Ok(Some(
+                    NowCapabilitiesMsg::new_with_capabilities(self.shared_data.borrow().capabilities.clone()).into(),
+                ))
+            }
+            _ => unexpected_call!(Self, self, "update_without_message"),
+        }
+    }
+
+    fn update_with_message<'msg: 'a, 'a>(&mut self, msg: &'a NowMessage<'msg>) -> ConnectionSMResult<'msg> {
+        match &self.state {
+            WaitState::Waiting => match msg {
+                NowMessage::Capabilities(msg) => {
+                    log::info!(
+                        "Client capabilities (short): {:?}",
+                        msg.capabilities
+                            .iter()
+                            .map(|caps| caps.name_as_str())
+                            .collect::<Vec<&str>>()
+                    );
+                    log::trace!("Client capabilities details: {:#?}", msg.capabilities.0);
+
+                    self.shared_data.borrow_mut().peer_capabilities = known_capabilities(&msg.capabilities.0);
+                    self.state = WaitState::Terminated;
+                    Ok(None)
+                }
+                unexpected => unexpected_msg!(Self, self, unexpected),
+            },
+            _ => unexpected_call!(Self, self, "update_with_message"),
+        }
+    }
+}
+
+// channels
+
+#[derive(PartialEq, Debug)]
+enum ChannelPairingState {
+    WaitListRequest,
+    WaitOpenRequest,
+    WaitActivate,
+    Terminated,
+}
+
+pub struct ServerChannelsSM {
+    state: ChannelPairingState,
+    shared_data: ConnectionSMSharedDataRc,
+}
+
+impl ServerChannelsSM {
+    const CONNECTION_STATE: ConnectionState = ConnectionState::Channels;
+    const NAME: &'static str = "ServerChannelsSM";
+
+    pub fn new(shared_data: ConnectionSMSharedDataRc) -> Self {
+        Self {
+            state: ChannelPairingState::WaitListRequest,
+            shared_data,
+        }
+    }
+}
+
+impl ConnectionSM for ServerChannelsSM {
+    fn set_shared_data(&mut self, shared_data: ConnectionSMSharedDataRc) {
+        self.shared_data = shared_data;
+    }
+
+    fn get_shared_data(&self) -> Option<ConnectionSMSharedDataRc> {
+        Some(Rc::clone(&self.shared_data))
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.state == ChannelPairingState::Terminated
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        self.state != ChannelPairingState::Terminated
+    }
+
+    fn update_without_message<'msg>(&mut self) -> ConnectionSMResult<'msg> {
+        unexpected_call!(Self, self, "update_without_message")
+    }
+
+    fn update_with_message<'msg: 'a, 'a>(&mut self, msg: &'a NowMessage<'msg>) -> ConnectionSMResult<'msg> {
+        use crate::message::{ChannelDefFlags, ChannelMessageType, NowChannelDef, NowChannelMsg};
+
+        match self.state {
+            ChannelPairingState::WaitListRequest => match msg {
+                NowMessage::Channel(msg) if msg.subtype == ChannelMessageType::ChannelListRequest => {
+                    self.state = ChannelPairingState::WaitOpenRequest;
+                    Ok(Some(
+                        NowChannelMsg::new(
+                            ChannelMessageType::ChannelListResponse,
+                            self.shared_data.borrow().channels.clone(),
+                        )
+                        .into(),
+                    ))
+                }
+                unexpected => unexpected_msg!(Self, self, unexpected),
+            },
+            ChannelPairingState::WaitOpenRequest => match msg {
+                NowMessage::Channel(msg) if msg.subtype == ChannelMessageType::ChannelOpenRequest => {
+                    let mut shared_data = self.shared_data.borrow_mut();
+
+                    // channel id is carried by the flags of the open response
+                    let opened: Vec<NowChannelDef> = msg
+                        .channel_list
+                        .iter()
+                        .filter(|def| shared_data.channels.iter().any(|d| d.name == def.name))
+                        .enumerate()
+                        .map(|(id, def)| {
+                            NowChannelDef::new_with_flags(def.name.clone(), ChannelDefFlags::from(id as u32))
+                        })
+                        .collect();
+
+                    log::info!(
+                        "Opened channel(s): {:?}",
+                        opened.iter().map(|def| &def.name).collect::<Vec<_>>()
+                    );
+
+                    shared_data.channels = opened.clone();
+                    self.state = ChannelPairingState::WaitActivate;
+                    Ok(Some(
+                        NowChannelMsg::new(ChannelMessageType::ChannelOpenResponse, opened).into(),
+                    ))
+                }
+                unexpected => unexpected_msg!(Self, self, unexpected),
+            },
+            ChannelPairingState::WaitActivate => match msg {
+                NowMessage::Activate(_) => {
+                    self.state = ChannelPairingState::Terminated;
+                    Ok(None)
+                }
+                unexpected => unexpected_msg!(Self, self, unexpected),
+            },
+            ChannelPairingState::Terminated => unexpected_call!(Self, self, "update_with_message"),
+        }
+    }
+}
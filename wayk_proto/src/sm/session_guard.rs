@@ -0,0 +1,54 @@
+use crate::{message::NowDesktopMsg, sm::LinkQuality};
+
+/// Host state to be reverted by the sharee itself once the session ends or its link is lost,
+/// whatever the technician did before (see `Sharee::add_session_guard`).
+pub trait SessionGuard {
+    /// Answers desktop requests handled by the guard, `None` if not handled.
+    fn update_with_desktop_msg(&mut self, msg: &NowDesktopMsg) -> Option<NowDesktopMsg>;
+
+    /// Graceful or abrupt session end.
+    fn on_disconnect(&mut self);
+
+    fn on_quality_changed(&mut self, current: LinkQuality);
+}
+
+sa::assert_obj_safe!(SessionGuard);
+
+/// Guards registered on a sharee, notified in registration order.
+#[derive(Default)]
+pub struct SessionGuards {
+    guards: Vec<Box<dyn SessionGuard>>,
+}
+
+impl SessionGuards {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add<G: SessionGuard + 'static>(&mut self, guard: G) {
+        self.guards.push(Box::new(guard));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.guards.is_empty()
+    }
+
+    /// Answer of the first guard handling `msg`.
+    pub fn update_with_desktop_msg(&mut self, msg: &NowDesktopMsg) -> Option<NowDesktopMsg> {
+        self.guards
+            .iter_mut()
+            .find_map(|guard| guard.update_with_desktop_msg(msg))
+    }
+
+    pub fn on_disconnect(&mut self) {
+        for guard in &mut self.guards {
+            guard.on_disconnect();
+        }
+    }
+
+    pub fn on_quality_changed(&mut self, current: LinkQuality) {
+        for guard in &mut self.guards {
+            guard.on_quality_changed(current);
+        }
+    }
+}
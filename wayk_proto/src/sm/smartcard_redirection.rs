@@ -0,0 +1,355 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        ChannelName, DeviceAccessFlags, DeviceType, NowSmartcardControl, NowSmartcardStatus, NowStatusCode,
+        NowString64, SmartcardDisposition, SmartcardIoctl,
+    },
+    serialization::{Decode, Encode},
+    sm::{DeviceRedirection, RedirectedDevice, RemoteDevice},
+};
+use std::{cell::RefCell, rc::Rc};
+
+/// Local PC/SC readers, such as the ones of the pcsc crate (`PcscBackend`, with the `pcsc` feature).
+///
+/// `card` is the handle returned by `connect`. PC/SC errors are reported with the closest status:
+/// unknown reader or no card as `NotFound`, sharing violations as `Busy`, and so on.
+pub trait SmartcardBackend {
+    fn list_readers(&mut self) -> Result<Vec<String>, NowStatusCode>;
+
+    /// Connects to the card of `reader`, exclusively or shared with other applications.
+    fn connect(&mut self, reader: &str, exclusive: bool) -> Result<u32, NowStatusCode>;
+
+    fn disconnect(&mut self, card: u32, disposition: SmartcardDisposition) -> Result<(), NowStatusCode>;
+
+    fn status(&mut self, card: u32) -> Result<NowSmartcardStatus, NowStatusCode>;
+
+    fn transmit(&mut self, card: u32, apdu: &[u8], max_response_len: u32) -> Result<Vec<u8>, NowStatusCode>;
+
+    fn begin_transaction(&mut self, card: u32) -> Result<(), NowStatusCode>;
+
+    fn end_transaction(&mut self, card: u32, disposition: SmartcardDisposition) -> Result<(), NowStatusCode>;
+
+    #[allow(unused_variables)]
+    fn control(&mut self, card: u32, code: u32, input: &[u8], max_output_len: u32) -> Result<Vec<u8>, NowStatusCode> {
+        Err(NowStatusCode::NotSupported)
+    }
+}
+
+/// Reader of a [`SmartcardBackend`](trait.SmartcardBackend.html) redirected as a device.
+///
+/// Closing the device disconnects the card, leaving it as is.
+pub struct SmartcardReader<B> {
+    backend: Rc<RefCell<B>>,
+    name: NowString64,
+}
+
+impl<B: SmartcardBackend> SmartcardReader<B> {
+    pub fn new(backend: Rc<RefCell<B>>, name: NowString64) -> Self {
+        Self { backend, name }
+    }
+
+    /// Announces every reader of `backend` on `devices`. Returns the ids of the devices.
+    ///
+    /// Readers whose name is too long to be announced are skipped.
+    pub fn announce_all(devices: &DeviceRedirection, backend: &Rc<RefCell<B>>) -> Result<Vec<u32>, NowStatusCode>
+    where
+        B: 'static,
+    {
+        let readers = backend.borrow_mut().list_readers()?;
+        Ok(readers
+            .into_iter()
+            .filter_map(|reader| match NowString64::from_string(reader) {
+                Ok(name) => Some(devices.announce(Self::new(Rc::clone(backend), name))),
+                Err(e) => {
+                    log::warn!("smartcard reader not announced: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+impl<B: SmartcardBackend> RedirectedDevice for SmartcardReader<B> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Smartcard
+    }
+
+    fn name(&self) -> NowString64 {
+        self.name.clone()
+    }
+
+    fn open(&mut self, access: DeviceAccessFlags) -> Result<u32, NowStatusCode> {
+        self.backend
+            .borrow_mut()
+            .connect(self.name.as_str(), access.exclusive())
+    }
+
+    fn close(&mut self, handle: u32) -> Result<(), NowStatusCode> {
+        self.backend
+            .borrow_mut()
+            .disconnect(handle, SmartcardDisposition::Leave)
+    }
+
+    fn ioctl(&mut self, handle: u32, code: u32, input: &[u8], max_output_len: u32) -> Result<Vec<u8>, NowStatusCode> {
+        let mut backend = self.backend.borrow_mut();
+        match num::FromPrimitive::from_u32(code) {
+            Some(SmartcardIoctl::Transmit) => backend.transmit(handle, input, max_output_len),
+            Some(SmartcardIoctl::Status) => backend.status(handle)?.encode().map_err(|_| NowStatusCode::Failure),
+            Some(SmartcardIoctl::BeginTransaction) => backend.begin_transaction(handle).map(|()| Vec::new()),
+            Some(SmartcardIoctl::EndTransaction) => {
+                let disposition = SmartcardDisposition::decode(input).map_err(|_| NowStatusCode::InvalidRequest)?;
+                backend.end_transaction(handle, disposition).map(|()| Vec::new())
+            }
+            Some(SmartcardIoctl::Control) => {
+                let control = NowSmartcardControl::decode(input).map_err(|_| NowStatusCode::InvalidRequest)?;
+                backend.control(handle, control.code, &control.input, max_output_len)
+            }
+            None => Err(NowStatusCode::NotSupported),
+        }
+    }
+}
+
+/// Forwards PC/SC operations to the smartcard readers redirected by the peer.
+///
+/// Operations return the id of the device request, completed by a
+/// [`DeviceEvent::Completed`](enum.DeviceEvent.html#variant.Completed) event. Connecting completes
+/// with the card handle as value, `transmit`, `status` and `control` with their output as data.
+#[derive(Clone)]
+pub struct SmartcardClient {
+    devices: DeviceRedirection,
+}
+
+impl SmartcardClient {
+    /// Largest response APDU, extended length included.
+    pub const MAX_RESPONSE_LEN: u32 = 65538;
+
+    pub fn new(devices: DeviceRedirection) -> Self {
+        Self { devices }
+    }
+
+    pub fn devices(&self) -> &DeviceRedirection {
+        &self.devices
+    }
+
+    /// Readers announced by the peer, accepted or not.
+    pub fn readers(&self) -> Vec<RemoteDevice> {
+        self.devices
+            .remote_devices()
+            .into_iter()
+            .filter(|device| device.device_type == DeviceType::Smartcard)
+            .collect()
+    }
+
+    pub fn connect(&self, reader_id: u32, exclusive: bool) -> Result<u32, ProtoError> {
+        let mut access = DeviceAccessFlags::new_empty().set_read().set_write();
+        if exclusive {
+            access.set_exclusive();
+        }
+        self.devices.open(reader_id, access)
+    }
+
+    pub fn disconnect(&self, reader_id: u32, card: u32) -> Result<u32, ProtoError> {
+        self.devices.close(reader_id, card)
+    }
+
+    pub fn transmit(&self, reader_id: u32, card: u32, apdu: Vec<u8>) -> Result<u32, ProtoError> {
+        self.__ioctl(reader_id, card, SmartcardIoctl::Transmit, apdu, Self::MAX_RESPONSE_LEN)
+    }
+
+    /// Output is decoded with [`decode_status`](#method.decode_status).
+    pub fn status(&self, reader_id: u32, card: u32) -> Result<u32, ProtoError> {
+        self.__ioctl(
+            reader_id,
+            card,
+            SmartcardIoctl::Status,
+            Vec::new(),
+            NowSmartcardStatus::MAX_SIZE,
+        )
+    }
+
+    pub fn begin_transaction(&self, reader_id: u32, card: u32) -> Result<u32, ProtoError> {
+        self.__ioctl(reader_id, card, SmartcardIoctl::BeginTransaction, Vec::new(), 0)
+    }
+
+    pub fn end_transaction(
+        &self,
+        reader_id: u32,
+        card: u32,
+        disposition: SmartcardDisposition,
+    ) -> Result<u32, ProtoError> {
+        let input = disposition.encode()?;
+        self.__ioctl(reader_id, card, SmartcardIoctl::EndTransaction, input, 0)
+    }
+
+    pub fn control(
+        &self,
+        reader_id: u32,
+        card: u32,
+        code: u32,
+        input: Vec<u8>,
+        max_output_len: u32,
+    ) -> Result<u32, ProtoError> {
+        let input = NowSmartcardControl::new(code, input).encode()?;
+        self.__ioctl(reader_id, card, SmartcardIoctl::Control, input, max_output_len)
+    }
+
+    pub fn decode_status(data: &[u8]) -> Result<NowSmartcardStatus, ProtoError> {
+        NowSmartcardStatus::decode(data)
+            .chain(ProtoErrorKind::VirtualChannel(ChannelName::Device))
+            .or_desc("invalid smartcard status")
+    }
+
+    fn __ioctl(
+        &self,
+        reader_id: u32,
+        card: u32,
+        ioctl: SmartcardIoctl,
+        input: Vec<u8>,
+        max_output_len: u32,
+    ) -> Result<u32, ProtoError> {
+        self.devices.ioctl(reader_id, card, ioctl as u32, input, max_output_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{DeviceIoFunction, SmartcardProtocol, SmartcardStateFlags},
+        sm::{test_support::transfer, DeviceEvent, DeviceRedirectionSM, VirtualChannelSM},
+    };
+
+    /// Single reader holding a card that answers every APDU with `90 00`.
+    #[derive(Default)]
+    struct FakeReaders {
+        connected: Vec<(u32, bool)>,
+        in_transaction: bool,
+        ended_with: Option<SmartcardDisposition>,
+    }
+
+    impl SmartcardBackend for FakeReaders {
+        fn list_readers(&mut self) -> Result<Vec<String>, NowStatusCode> {
+            Ok(vec!["Token Reader 0".to_owned()])
+        }
+
+        fn connect(&mut self, reader: &str, exclusive: bool) -> Result<u32, NowStatusCode> {
+            if reader != "Token Reader 0" {
+                return Err(NowStatusCode::NotFound);
+            }
+            let card = self.connected.len() as u32 + 1;
+            self.connected.push((card, exclusive));
+            Ok(card)
+        }
+
+        fn disconnect(&mut self, card: u32, _: SmartcardDisposition) -> Result<(), NowStatusCode> {
+            self.connected.retain(|(connected, _)| *connected != card);
+            Ok(())
+        }
+
+        fn status(&mut self, _: u32) -> Result<NowSmartcardStatus, NowStatusCode> {
+            Ok(NowSmartcardStatus::new(
+                SmartcardStateFlags::new_empty().set_present().set_powered(),
+                SmartcardProtocol::T1,
+                vec![0x3b, 0x8f, 0x80],
+            ))
+        }
+
+        fn transmit(&mut self, _: u32, apdu: &[u8], _: u32) -> Result<Vec<u8>, NowStatusCode> {
+            if apdu.len() < 4 {
+                return Err(NowStatusCode::InvalidRequest);
+            }
+            Ok(vec![0x90, 0x00])
+        }
+
+        fn begin_transaction(&mut self, _: u32) -> Result<(), NowStatusCode> {
+            if self.in_transaction {
+                return Err(NowStatusCode::Busy);
+            }
+            self.in_transaction = true;
+            Ok(())
+        }
+
+        fn end_transaction(&mut self, _: u32, disposition: SmartcardDisposition) -> Result<(), NowStatusCode> {
+            self.in_transaction = false;
+            self.ended_with = Some(disposition);
+            Ok(())
+        }
+    }
+
+    fn completions(devices: &DeviceRedirection) -> Vec<(DeviceIoFunction, NowStatusCode, u32, Vec<u8>)> {
+        core::iter::from_fn(|| devices.poll_event())
+            .map(|event| match event {
+                DeviceEvent::Completed {
+                    function,
+                    status,
+                    value,
+                    data,
+                    ..
+                } => (function, status, value, data),
+                event => panic!("unexpected event: {:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pcsc_operations_forwarded_to_local_reader() {
+        let (mut local_sm, local_devices) = DeviceRedirectionSM::new();
+        let (mut host_sm, host_devices) = DeviceRedirectionSM::new();
+        let backend = Rc::new(RefCell::new(FakeReaders::default()));
+        let ids = SmartcardReader::announce_all(&local_devices, &backend).unwrap();
+        assert_eq!(ids.len(), 1);
+
+        local_sm.on_open();
+        host_sm.on_open();
+        transfer(&mut local_sm, &mut host_sm);
+        let client = SmartcardClient::new(host_devices.clone());
+        let readers = client.readers();
+        assert_eq!(readers.len(), 1);
+        assert_eq!(readers[0].name, "Token Reader 0");
+        let reader_id = readers[0].id;
+        assert!(matches!(host_devices.poll_event(), Some(DeviceEvent::Announced(_))));
+
+        host_devices.accept_device(reader_id).unwrap();
+        client.connect(reader_id, true).unwrap();
+        transfer(&mut host_sm, &mut local_sm);
+        transfer(&mut local_sm, &mut host_sm);
+        let card = completions(&host_devices)[0].2;
+        assert_eq!(backend.borrow().connected, vec![(card, true)]);
+
+        client.begin_transaction(reader_id, card).unwrap();
+        client.begin_transaction(reader_id, card).unwrap();
+        client.transmit(reader_id, card, vec![0x00, 0xa4, 0x04, 0x00]).unwrap();
+        client.status(reader_id, card).unwrap();
+        client
+            .end_transaction(reader_id, card, SmartcardDisposition::Reset)
+            .unwrap();
+        client.control(reader_id, card, 0x0031_3520, Vec::new(), 16).unwrap();
+        client.disconnect(reader_id, card).unwrap();
+        transfer(&mut host_sm, &mut local_sm);
+        transfer(&mut local_sm, &mut host_sm);
+
+        let completions = completions(&host_devices);
+        let statuses: Vec<NowStatusCode> = completions.iter().map(|completion| completion.1).collect();
+        assert_eq!(
+            statuses,
+            [
+                NowStatusCode::Success,
+                NowStatusCode::Busy,
+                NowStatusCode::Success,
+                NowStatusCode::Success,
+                NowStatusCode::Success,
+                NowStatusCode::NotSupported,
+                NowStatusCode::Success,
+            ]
+        );
+        assert_eq!(completions[2].3, [0x90, 0x00]);
+        let status = SmartcardClient::decode_status(&completions[3].3).unwrap();
+        assert_eq!(status.protocol, SmartcardProtocol::T1);
+        assert_eq!(status.atr.0, [0x3b, 0x8f, 0x80]);
+        assert_eq!(completions[6].0, DeviceIoFunction::Close);
+
+        let backend = backend.borrow();
+        assert_eq!(backend.ended_with, Some(SmartcardDisposition::Reset));
+        assert!(backend.connected.is_empty());
+    }
+}
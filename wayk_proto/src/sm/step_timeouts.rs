@@ -0,0 +1,204 @@
+use crate::sm::ConnectionState;
+use std::time::{Duration, Instant};
+
+/// Maximum duration of the connection sequence steps. All are disabled by default.
+///
+/// A stalled step may be retried, the last packet sent being sent again, each retry waiting
+/// `backoff_factor` times longer than the previous attempt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepTimeouts {
+    pub negotiate: Option<Duration>,
+    pub authenticate: Option<Duration>,
+    pub associate: Option<Duration>,
+    pub capabilities: Option<Duration>,
+    pub max_retries: u32,
+    pub backoff_factor: u32,
+}
+
+impl Default for StepTimeouts {
+    fn default() -> Self {
+        Self {
+            negotiate: None,
+            authenticate: None,
+            associate: None,
+            capabilities: None,
+            max_retries: 0,
+            backoff_factor: 2,
+        }
+    }
+}
+
+impl StepTimeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn negotiate(self, timeout: Duration) -> Self {
+        Self {
+            negotiate: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Authentication may involve the user: this one is typically longer than the others.
+    pub fn authenticate(self, timeout: Duration) -> Self {
+        Self {
+            authenticate: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn associate(self, timeout: Duration) -> Self {
+        Self {
+            associate: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn capabilities(self, timeout: Duration) -> Self {
+        Self {
+            capabilities: Some(timeout),
+            ..self
+        }
+    }
+
+    pub fn retries(self, max_retries: u32, backoff_factor: u32) -> Self {
+        Self {
+            max_retries,
+            backoff_factor: backoff_factor.max(1),
+            ..self
+        }
+    }
+
+    pub fn timeout_of(&self, step: ConnectionState) -> Option<Duration> {
+        match step {
+            ConnectionState::Negotiate => self.negotiate,
+            ConnectionState::Authenticate => self.authenticate,
+            ConnectionState::Associate => self.associate,
+            ConnectionState::Capabilities => self.capabilities,
+            ConnectionState::Handshake | ConnectionState::Channels | ConnectionState::Final => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepWatchdogEvent {
+    /// The step timed out: the last packet sent is to be sent again.
+    Retry { step: ConnectionState, attempt: u32 },
+    /// The step timed out and retries are exhausted. Reported once.
+    Stalled(ConnectionState),
+}
+
+/// Watches the connection sequence for stalled steps.
+///
+/// Driven by the caller: `on_step` must be given the current step whenever the sequence may
+/// have moved on, and `poll` called once `next_deadline` is reached.
+/// Transports do this on their own (see `TransportTimeouts::steps`).
+#[derive(Debug, Clone)]
+pub struct StepWatchdog {
+    timeouts: StepTimeouts,
+    step: Option<ConnectionState>,
+    attempt: u32,
+    deadline: Option<Instant>,
+}
+
+impl StepWatchdog {
+    pub fn new(timeouts: StepTimeouts) -> Self {
+        Self {
+            timeouts,
+            step: None,
+            attempt: 0,
+            deadline: None,
+        }
+    }
+
+    pub fn step(&self) -> Option<ConnectionState> {
+        self.step
+    }
+
+    /// Starts timing `step` if the sequence moved on to it.
+    pub fn on_step(&mut self, step: ConnectionState, now: Instant) {
+        if self.step == Some(step) {
+            return;
+        }
+
+        self.step = Some(step);
+        self.attempt = 0;
+        self.deadline = self.timeouts.timeout_of(step).map(|timeout| now + timeout);
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Option<StepWatchdogEvent> {
+        let step = self.step?;
+        if self.deadline? > now {
+            return None;
+        }
+
+        if self.attempt < self.timeouts.max_retries {
+            self.attempt += 1;
+            let factor = self.timeouts.backoff_factor.saturating_pow(self.attempt);
+            self.deadline = self
+                .timeouts
+                .timeout_of(step)
+                .map(|timeout| now + timeout.saturating_mul(factor));
+            Some(StepWatchdogEvent::Retry {
+                step,
+                attempt: self.attempt,
+            })
+        } else {
+            self.deadline = None;
+            Some(StepWatchdogEvent::Stalled(step))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stalled_step_retried_with_backoff() {
+        let start = Instant::now();
+        let timeouts = StepTimeouts::new()
+            .authenticate(Duration::from_secs(10))
+            .capabilities(Duration::from_secs(1))
+            .retries(2, 2);
+        let mut watchdog = StepWatchdog::new(timeouts);
+
+        watchdog.on_step(ConnectionState::Handshake, start);
+        assert_eq!(watchdog.next_deadline(), None);
+
+        watchdog.on_step(ConnectionState::Capabilities, start);
+        assert_eq!(watchdog.poll(start), None);
+        let now = start + Duration::from_secs(1);
+        assert_eq!(
+            watchdog.poll(now),
+            Some(StepWatchdogEvent::Retry {
+                step: ConnectionState::Capabilities,
+                attempt: 1
+            })
+        );
+        assert_eq!(watchdog.next_deadline(), Some(now + Duration::from_secs(2)));
+
+        // same step reported again: timing goes on
+        watchdog.on_step(ConnectionState::Capabilities, now);
+        let now = now + Duration::from_secs(2);
+        assert!(matches!(
+            watchdog.poll(now),
+            Some(StepWatchdogEvent::Retry { attempt: 2, .. })
+        ));
+        let now = now + Duration::from_secs(4);
+        assert_eq!(
+            watchdog.poll(now),
+            Some(StepWatchdogEvent::Stalled(ConnectionState::Capabilities))
+        );
+        assert_eq!(watchdog.poll(now + Duration::from_secs(60)), None);
+
+        // next step timed on its own
+        watchdog.on_step(ConnectionState::Authenticate, now);
+        assert_eq!(watchdog.next_deadline(), Some(now + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt, Result},
+    message::{NowSurfaceListReqMsg, NowSurfaceMap, NowSurfaceMapReqMsg, SurfaceMapReqFlags},
+};
+
+/// Surface mappings changed between two topologies.
+///
+/// Only surfaces whose mapping is new or different are kept so that a layout change
+/// mid-session results in a minimal map request.
+#[derive(Debug, Clone, Default)]
+pub struct SurfaceDiff {
+    pub maps: Vec<NowSurfaceMap>,
+}
+
+impl SurfaceDiff {
+    pub fn new(previous: &[NowSurfaceMap], next: &[NowSurfaceMap]) -> Self {
+        let maps = next.iter().filter(|map| !previous.contains(map)).cloned().collect();
+
+        Self { maps }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.maps.is_empty()
+    }
+
+    /// Checks mappings against the last list request: surfaces must be listed, mapped
+    /// only once and to a non-empty output rectangle.
+    pub fn validate(&self, list: &NowSurfaceListReqMsg) -> Result<()> {
+        for (i, map) in self.maps.iter().enumerate() {
+            if !list.surfaces.iter().any(|def| def.surface_id == map.surface_id) {
+                return ProtoError::new(ProtoErrorKind::SurfaceLayout).or_else_desc(|| {
+                    format!(
+                        "surface {} isn't part of list request {}",
+                        map.surface_id, list.sequence_id
+                    )
+                });
+            }
+
+            if self.maps[..i].iter().any(|other| other.surface_id == map.surface_id) {
+                return ProtoError::new(ProtoErrorKind::SurfaceLayout)
+                    .or_else_desc(|| format!("surface {} is mapped more than once", map.surface_id));
+            }
+
+            let rect = &map.output_rect;
+            if rect.is_empty() {
+                return ProtoError::new(ProtoErrorKind::SurfaceLayout).or_else_desc(|| {
+                    format!(
+                        "surface {} is mapped to an empty output rect {:?}",
+                        map.surface_id, rect
+                    )
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds the partial map request for the desktop described by `list`.
+    pub fn into_map_req(self, sequence_id: u16, list: &NowSurfaceListReqMsg) -> Result<NowSurfaceMapReqMsg> {
+        self.validate(list)?;
+        Ok(
+            NowSurfaceMapReqMsg::new_with_mappings(sequence_id, list.desktop_width, list.desktop_height, self.maps)
+                .flags(SurfaceMapReqFlags::new_empty().set_partial()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{EdgeRect, NowSurfaceDef};
+
+    fn rect(left: i16, top: i16, right: i16, bottom: i16) -> EdgeRect {
+        EdgeRect {
+            left,
+            top,
+            right,
+            bottom,
+        }
+    }
+
+    fn triple_monitor_list() -> NowSurfaceListReqMsg {
+        NowSurfaceListReqMsg::new_with_surfaces(
+            4,
+            5760,
+            1080,
+            vec![
+                NowSurfaceDef::new(1, rect(0, 0, 1920, 1080)),
+                NowSurfaceDef::new(2, rect(1920, 0, 3840, 1080)),
+                NowSurfaceDef::new(3, rect(3840, 0, 5760, 1080)),
+            ],
+        )
+    }
+
+    fn previous_maps() -> Vec<NowSurfaceMap> {
+        vec![
+            NowSurfaceMap::new(1, 0, rect(0, 0, 1920, 1080)),
+            NowSurfaceMap::new(2, 1, rect(0, 0, 1920, 1080)),
+        ]
+    }
+
+    #[test]
+    fn only_changed_surfaces_are_mapped() {
+        let next = vec![
+            NowSurfaceMap::new(1, 0, rect(0, 0, 1920, 1080)),
+            NowSurfaceMap::new(2, 1, rect(0, 0, 1280, 720)),
+            NowSurfaceMap::new(3, 2, rect(0, 0, 1920, 1080)),
+        ];
+
+        let diff = SurfaceDiff::new(&previous_maps(), &next);
+        assert_eq!(diff.maps, next[1..].to_vec());
+
+        let req = diff.into_map_req(5, &triple_monitor_list()).unwrap();
+        assert!(req.flags.partial());
+        assert_eq!(req.sequence_id, 5);
+        assert_eq!(req.desktop_width, 5760);
+        assert_eq!(req.desktop_height, 1080);
+        assert_eq!(req.maps.len(), 2);
+        assert_eq!(req.maps.0, next[1..].to_vec());
+    }
+
+    #[test]
+    fn unchanged_topology_is_empty() {
+        assert!(SurfaceDiff::new(&previous_maps(), &previous_maps()).is_empty());
+    }
+
+    #[test]
+    fn unknown_surface_rejected() {
+        let next = vec![NowSurfaceMap::new(9, 0, rect(0, 0, 1920, 1080))];
+        let diff = SurfaceDiff::new(&previous_maps(), &next);
+        assert!(diff.validate(&triple_monitor_list()).is_err());
+    }
+
+    #[test]
+    fn duplicate_and_empty_mappings_rejected() {
+        let duplicate = SurfaceDiff {
+            maps: vec![
+                NowSurfaceMap::new(3, 0, rect(0, 0, 1920, 1080)),
+                NowSurfaceMap::new(3, 1, rect(0, 0, 1920, 1080)),
+            ],
+        };
+        assert!(duplicate.validate(&triple_monitor_list()).is_err());
+
+        let empty = SurfaceDiff {
+            maps: vec![NowSurfaceMap::new(3, 0, rect(0, 0, 0, 1080))],
+        };
+        assert!(empty.validate(&triple_monitor_list()).is_err());
+    }
+}
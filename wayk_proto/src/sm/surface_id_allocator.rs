@@ -0,0 +1,121 @@
+use crate::error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Hands out surface ids to host monitors (server side).
+///
+/// A monitor keeps its id as long as it is registered, ids of removed monitors are
+/// recycled (lowest first), and ids reserved as output ids are never given to a surface.
+#[derive(Debug, Clone)]
+pub struct SurfaceIdAllocator<Key> {
+    surfaces: BTreeMap<Key, u16>,
+    in_use: BTreeSet<u16>,
+    output_ids: BTreeSet<u16>,
+}
+
+impl<Key: Ord + Clone> Default for SurfaceIdAllocator<Key> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Key: Ord + Clone> SurfaceIdAllocator<Key> {
+    pub fn new() -> Self {
+        Self {
+            surfaces: BTreeMap::new(),
+            in_use: BTreeSet::new(),
+            output_ids: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the id of the monitor, allocating one if it isn't registered yet.
+    pub fn allocate(&mut self, key: Key) -> Result<u16> {
+        if let Some(id) = self.surfaces.get(&key) {
+            return Ok(*id);
+        }
+
+        let id = (0..=u16::MAX)
+            .find(|id| !self.in_use.contains(id) && !self.output_ids.contains(id))
+            .chain(ProtoErrorKind::SurfaceLayout)
+            .or_desc("no surface id left")?;
+        self.in_use.insert(id);
+        self.surfaces.insert(key, id);
+        Ok(id)
+    }
+
+    /// Unregisters the monitor. Its id may be handed out again.
+    pub fn release(&mut self, key: &Key) -> Option<u16> {
+        let id = self.surfaces.remove(key)?;
+        self.in_use.remove(&id);
+        Some(id)
+    }
+
+    /// Reserves an output id. Fails if a surface already uses it.
+    pub fn reserve_output_id(&mut self, output_id: u16) -> Result<()> {
+        if self.in_use.contains(&output_id) {
+            return ProtoError::new(ProtoErrorKind::SurfaceLayout)
+                .or_else_desc(|| format!("id {} is already used by a surface", output_id));
+        }
+
+        self.output_ids.insert(output_id);
+        Ok(())
+    }
+
+    pub fn release_output_id(&mut self, output_id: u16) -> bool {
+        self.output_ids.remove(&output_id)
+    }
+
+    pub fn get(&self, key: &Key) -> Option<u16> {
+        self.surfaces.get(key).copied()
+    }
+
+    pub fn key_of(&self, surface_id: u16) -> Option<&Key> {
+        self.surfaces
+            .iter()
+            .find(|(_, id)| **id == surface_id)
+            .map(|(key, _)| key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, u16)> {
+        self.surfaces.iter().map(|(key, id)| (key, *id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.surfaces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.surfaces.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_and_recycled_ids() {
+        let mut allocator = SurfaceIdAllocator::new();
+        assert_eq!(allocator.allocate("DP-1").unwrap(), 0);
+        assert_eq!(allocator.allocate("HDMI-1").unwrap(), 1);
+        assert_eq!(allocator.allocate("DP-1").unwrap(), 0);
+
+        assert_eq!(allocator.release(&"DP-1"), Some(0));
+        assert_eq!(allocator.release(&"DP-1"), None);
+        assert_eq!(allocator.allocate("DP-2").unwrap(), 0);
+        assert_eq!(allocator.key_of(0), Some(&"DP-2"));
+        assert_eq!(allocator.len(), 2);
+    }
+
+    #[test]
+    fn output_ids_never_collide() {
+        let mut allocator = SurfaceIdAllocator::new();
+        allocator.reserve_output_id(0).unwrap();
+        allocator.reserve_output_id(2).unwrap();
+        assert_eq!(allocator.allocate("DP-1").unwrap(), 1);
+        assert_eq!(allocator.allocate("DP-2").unwrap(), 3);
+        assert!(allocator.reserve_output_id(3).is_err());
+
+        assert!(allocator.release_output_id(0));
+        assert_eq!(allocator.allocate("DP-3").unwrap(), 0);
+    }
+}
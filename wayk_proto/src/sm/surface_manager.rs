@@ -0,0 +1,796 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt, Result},
+    message::{
+        EdgeRect, NowCapset, NowSurfaceDef, NowSurfaceListReqMsg, NowSurfaceListRspMsg, NowSurfaceMap,
+        NowSurfaceMapReqMsg, NowSurfaceMapRspMsg, NowSurfaceMsg, NowSurfaceMultiSelectReqMsg, NowSurfaceSelectReqMsg,
+        NowSurfaceSelectRspMsg, SurfaceMapFlags, SurfaceOrientation, SurfacePropertiesFlags, SurfaceResponseFlags,
+        SurfaceScaling,
+    },
+    sm::SurfaceDiff,
+};
+use std::collections::BTreeMap;
+
+/// Output a surface is mapped to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SurfaceMapping {
+    pub flags: SurfaceMapFlags,
+    pub output_id: u16,
+    pub output_rect: EdgeRect,
+}
+
+/// Remote monitor as currently known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Surface {
+    pub surface_id: u16,
+    pub flags: SurfacePropertiesFlags,
+    pub orientation: SurfaceOrientation,
+    pub rect: EdgeRect,
+    pub scaling: Option<SurfaceScaling>,
+    pub mapping: Option<SurfaceMapping>,
+}
+
+impl From<&NowSurfaceDef> for Surface {
+    fn from(def: &NowSurfaceDef) -> Self {
+        Self {
+            surface_id: def.surface_id,
+            flags: def.flags,
+            orientation: def.orientation,
+            rect: def.rect.clone(),
+            scaling: def.scaling().cloned(),
+            mapping: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SurfaceChange {
+    DesktopResized {
+        width: u16,
+        height: u16,
+    },
+    Added(Surface),
+    Removed(Surface),
+    Moved {
+        surface_id: u16,
+        from: EdgeRect,
+        to: EdgeRect,
+    },
+    Reoriented {
+        surface_id: u16,
+        from: SurfaceOrientation,
+        to: SurfaceOrientation,
+    },
+    FlagsChanged {
+        surface_id: u16,
+        from: SurfacePropertiesFlags,
+        to: SurfacePropertiesFlags,
+    },
+    ScalingChanged {
+        surface_id: u16,
+        scaling: Option<SurfaceScaling>,
+    },
+    Mapped {
+        surface_id: u16,
+        mapping: SurfaceMapping,
+    },
+    Unmapped {
+        surface_id: u16,
+    },
+}
+
+/// Hot-plug event, as presented to GUI clients.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SurfaceEvent {
+    Added(Surface),
+    Removed(Surface),
+    Resized {
+        surface_id: u16,
+        from: EdgeRect,
+        to: EdgeRect,
+    },
+    Reoriented {
+        surface_id: u16,
+        from: SurfaceOrientation,
+        to: SurfaceOrientation,
+    },
+}
+
+impl SurfaceEvent {
+    /// Returns `None` for changes that aren't hot-plug events (mapping, selection, scaling...).
+    pub fn from_change(change: &SurfaceChange) -> Option<Self> {
+        match change {
+            SurfaceChange::Added(surface) => Some(Self::Added(surface.clone())),
+            SurfaceChange::Removed(surface) => Some(Self::Removed(surface.clone())),
+            SurfaceChange::Moved { surface_id, from, to } => Some(Self::Resized {
+                surface_id: *surface_id,
+                from: from.clone(),
+                to: to.clone(),
+            }),
+            SurfaceChange::Reoriented { surface_id, from, to } => Some(Self::Reoriented {
+                surface_id: *surface_id,
+                from: *from,
+                to: *to,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub trait SurfaceManagerCallbackTrait {
+    fn on_surface_change(&mut self, change: &SurfaceChange) {
+        #![allow(unused_variables)]
+    }
+}
+
+sa::assert_obj_safe!(SurfaceManagerCallbackTrait);
+
+pub struct DummySurfaceManagerCallback;
+impl SurfaceManagerCallbackTrait for DummySurfaceManagerCallback {}
+
+/// Buffers surface events until they are drained.
+#[derive(Debug, Default)]
+pub struct SurfaceEventQueue {
+    events: Vec<SurfaceEvent>,
+}
+
+impl SurfaceEventQueue {
+    pub fn drain(&mut self) -> std::vec::Drain<'_, SurfaceEvent> {
+        self.events.drain(..)
+    }
+}
+
+impl SurfaceManagerCallbackTrait for SurfaceEventQueue {
+    fn on_surface_change(&mut self, change: &SurfaceChange) {
+        self.events.extend(SurfaceEvent::from_change(change));
+    }
+}
+
+/// Tracks the remote monitor topology from surface messages.
+///
+/// Every list request replaces the topology; changes relative to the previous one
+/// are reported to the user callback in order (desktop, removals, updates, additions).
+pub struct SurfaceManager<UserCallback> {
+    desktop_width: u16,
+    desktop_height: u16,
+    surfaces: BTreeMap<u16, Surface>,
+    last_list: Option<NowSurfaceListReqMsg>,
+    strict_layout: bool,
+    multi_select: bool,
+    user_callback: UserCallback,
+}
+
+impl<UserCallback> SurfaceManager<UserCallback>
+where
+    UserCallback: SurfaceManagerCallbackTrait,
+{
+    pub fn new(user_callback: UserCallback) -> Self {
+        Self {
+            desktop_width: 0,
+            desktop_height: 0,
+            surfaces: BTreeMap::new(),
+            last_list: None,
+            strict_layout: false,
+            multi_select: false,
+            user_callback,
+        }
+    }
+
+    pub fn configure_from_capabilities(&mut self, peer_capabilities: &[NowCapset<'_>]) {
+        self.multi_select = peer_capabilities.iter().any(|capset| match capset {
+            NowCapset::Surface(capset) => capset.flags.multi(),
+            _ => false,
+        });
+    }
+
+    pub fn user_callback(&self) -> &UserCallback {
+        &self.user_callback
+    }
+
+    pub fn user_callback_mut(&mut self) -> &mut UserCallback {
+        &mut self.user_callback
+    }
+
+    /// Whether several surfaces can be selected at once.
+    pub fn multi_select(&self) -> bool {
+        self.multi_select
+    }
+
+    /// When set, list requests with an invalid layout are refused and the topology is left untouched.
+    pub fn set_strict_layout(&mut self, strict_layout: bool) {
+        self.strict_layout = strict_layout;
+    }
+
+    pub fn desktop_size(&self) -> (u16, u16) {
+        (self.desktop_width, self.desktop_height)
+    }
+
+    /// Sequence id of the last list request applied.
+    pub fn last_list_sequence_id(&self) -> Option<u16> {
+        self.last_list.as_ref().map(|list| list.sequence_id)
+    }
+
+    pub fn surfaces(&self) -> impl Iterator<Item = &Surface> {
+        self.surfaces.values()
+    }
+
+    pub fn surface(&self, surface_id: u16) -> Option<&Surface> {
+        self.surfaces.get(&surface_id)
+    }
+
+    pub fn primary(&self) -> Option<&Surface> {
+        self.surfaces.values().find(|surface| surface.flags.primary())
+    }
+
+    pub fn selected(&self) -> impl Iterator<Item = &Surface> {
+        self.surfaces.values().filter(|surface| surface.flags.selected())
+    }
+
+    /// Applies a surface message and returns the response to send back, if any.
+    pub fn update_with_surface_msg(&mut self, msg: &NowSurfaceMsg) -> Option<NowSurfaceMsg> {
+        match msg {
+            NowSurfaceMsg::ListReq(req) => {
+                if self.strict_layout {
+                    if let Err(e) = req.validate() {
+                        log::warn!("surface list request {} refused: {}", req.sequence_id, e);
+                        return Some(
+                            NowSurfaceListRspMsg::new(SurfaceResponseFlags::new_empty().set_failure(), req.sequence_id)
+                                .into(),
+                        );
+                    }
+                }
+
+                self.apply_list(req);
+                Some(NowSurfaceListRspMsg::new(SurfaceResponseFlags::new_empty(), req.sequence_id).into())
+            }
+            NowSurfaceMsg::MapReq(req) => {
+                let flags = if self.apply_map(req) {
+                    SurfaceResponseFlags::new_empty()
+                } else {
+                    SurfaceResponseFlags::new_empty().set_failure()
+                };
+                Some(NowSurfaceMapRspMsg::new(flags, req.sequence_id).into())
+            }
+            NowSurfaceMsg::SelectReq(req) => {
+                let flags = if self.apply_select(req) {
+                    SurfaceResponseFlags::new_empty()
+                } else {
+                    SurfaceResponseFlags::new_empty().set_failure()
+                };
+                Some(NowSurfaceSelectRspMsg::new(flags, req.sequence_id).into())
+            }
+            NowSurfaceMsg::MultiSelectReq(req) => {
+                let flags = if self.apply_multi_select(req) {
+                    SurfaceResponseFlags::new_empty()
+                } else {
+                    SurfaceResponseFlags::new_empty().set_failure()
+                };
+                Some(NowSurfaceSelectRspMsg::new(flags, req.sequence_id).into())
+            }
+            _ => None,
+        }
+    }
+
+    /// Replaces the topology with the listed surfaces. Mappings of surfaces still present are kept.
+    pub fn apply_list(&mut self, req: &NowSurfaceListReqMsg) {
+        let mut changes = Vec::new();
+
+        if (req.desktop_width, req.desktop_height) != (self.desktop_width, self.desktop_height) {
+            self.desktop_width = req.desktop_width;
+            self.desktop_height = req.desktop_height;
+            changes.push(SurfaceChange::DesktopResized {
+                width: req.desktop_width,
+                height: req.desktop_height,
+            });
+        }
+
+        let mut previous = std::mem::take(&mut self.surfaces);
+        let mut updates = Vec::new();
+        let mut additions = Vec::new();
+
+        for def in req.surfaces.iter() {
+            let mut surface = Surface::from(def);
+            match previous.remove(&def.surface_id) {
+                Some(old) => {
+                    __diff_surface(&old, &surface, &mut updates);
+                    surface.mapping = old.mapping;
+                }
+                None => additions.push(SurfaceChange::Added(surface.clone())),
+            }
+            self.surfaces.insert(surface.surface_id, surface);
+        }
+
+        changes.extend(previous.into_values().map(SurfaceChange::Removed));
+        changes.append(&mut updates);
+        changes.append(&mut additions);
+
+        self.last_list = Some(req.clone());
+        self.__notify(&changes);
+    }
+
+    /// Current output mappings.
+    pub fn mappings(&self) -> Vec<NowSurfaceMap> {
+        self.surfaces
+            .values()
+            .filter_map(|surface| {
+                surface.mapping.as_ref().map(|mapping| {
+                    NowSurfaceMap::new(surface.surface_id, mapping.output_id, mapping.output_rect.clone())
+                        .flags(mapping.flags)
+                })
+            })
+            .collect()
+    }
+
+    /// Builds a map request containing only mappings that differ from the current ones.
+    /// Returns `None` if nothing changed.
+    pub fn map_req(&self, sequence_id: u16, next: &[NowSurfaceMap]) -> Result<Option<NowSurfaceMapReqMsg>> {
+        let list = self
+            .last_list
+            .as_ref()
+            .chain(ProtoErrorKind::SurfaceLayout)
+            .or_desc("no surface list received yet")?;
+
+        let diff = SurfaceDiff::new(&self.mappings(), next);
+        if diff.is_empty() {
+            Ok(None)
+        } else {
+            diff.into_map_req(sequence_id, list).map(Some)
+        }
+    }
+
+    /// Applies output mappings. Fails without applying anything if a surface is unknown.
+    /// Unless the request is partial, surfaces it doesn't mention are unmapped.
+    pub fn apply_map(&mut self, req: &NowSurfaceMapReqMsg) -> bool {
+        if let Some(map) = req.maps.iter().find(|map| !self.surfaces.contains_key(&map.surface_id)) {
+            log::warn!("map request references unknown surface {}", map.surface_id);
+            return false;
+        }
+
+        let mut changes = Vec::new();
+
+        if !req.flags.partial() {
+            for surface in self.surfaces.values_mut() {
+                if surface.mapping.is_some() && !req.maps.iter().any(|map| map.surface_id == surface.surface_id) {
+                    surface.mapping = None;
+                    changes.push(SurfaceChange::Unmapped {
+                        surface_id: surface.surface_id,
+                    });
+                }
+            }
+        }
+
+        for map in req.maps.iter() {
+            let mapping = SurfaceMapping {
+                flags: map.flags,
+                output_id: map.output_id,
+                output_rect: map.output_rect.clone(),
+            };
+            let surface = self.surfaces.get_mut(&map.surface_id).expect("checked above");
+            if surface.mapping.as_ref() != Some(&mapping) {
+                surface.mapping = Some(mapping.clone());
+                changes.push(SurfaceChange::Mapped {
+                    surface_id: map.surface_id,
+                    mapping,
+                });
+            }
+        }
+
+        self.__notify(&changes);
+        true
+    }
+
+    /// Builds a request selecting the given surfaces.
+    ///
+    /// A single surface results in a regular select request; several surfaces require
+    /// the multi surface capability to be negotiated.
+    pub fn select_req(&self, sequence_id: u16, surface_ids: &[u16]) -> Result<NowSurfaceMsg> {
+        if let Some(surface_id) = surface_ids.iter().find(|id| !self.surfaces.contains_key(id)) {
+            return ProtoError::new(ProtoErrorKind::SurfaceLayout)
+                .or_else_desc(|| format!("surface {} isn't part of the current topology", surface_id));
+        }
+
+        match surface_ids {
+            [] => ProtoError::new(ProtoErrorKind::SurfaceLayout).or_desc("no surface to select"),
+            [surface_id] => Ok(NowSurfaceSelectReqMsg::new(0, sequence_id, *surface_id).into()),
+            _ if !self.multi_select => ProtoError::new(ProtoErrorKind::CapabilityNotNegotiated("NowSurface"))
+                .or_desc("peer doesn't support selecting multiple surfaces"),
+            _ => Ok(NowSurfaceMultiSelectReqMsg::new(0, sequence_id, surface_ids.to_vec()).into()),
+        }
+    }
+
+    /// Moves the selected flag to the requested surface.
+    pub fn apply_select(&mut self, req: &NowSurfaceSelectReqMsg) -> bool {
+        self.__select(&[req.surface_id])
+    }
+
+    /// Sets the selected flag on all requested surfaces and clears it elsewhere.
+    pub fn apply_multi_select(&mut self, req: &NowSurfaceMultiSelectReqMsg) -> bool {
+        self.__select(&req.surface_ids)
+    }
+
+    fn __select(&mut self, surface_ids: &[u16]) -> bool {
+        if let Some(surface_id) = surface_ids.iter().find(|id| !self.surfaces.contains_key(id)) {
+            log::warn!("select request references unknown surface {}", surface_id);
+            return false;
+        }
+
+        let mut changes = Vec::new();
+        for surface in self.surfaces.values_mut() {
+            let from = surface.flags;
+            let mut to = from;
+            if surface_ids.contains(&surface.surface_id) {
+                to.set_selected();
+            } else {
+                to.unset_selected();
+            }
+
+            if from != to {
+                surface.flags = to;
+                changes.push(SurfaceChange::FlagsChanged {
+                    surface_id: surface.surface_id,
+                    from,
+                    to,
+                });
+            }
+        }
+
+        self.__notify(&changes);
+        true
+    }
+
+    fn __notify(&mut self, changes: &[SurfaceChange]) {
+        for change in changes {
+            self.user_callback.on_surface_change(change);
+        }
+    }
+}
+
+fn __diff_surface(old: &Surface, new: &Surface, changes: &mut Vec<SurfaceChange>) {
+    let surface_id = new.surface_id;
+
+    if old.rect != new.rect {
+        changes.push(SurfaceChange::Moved {
+            surface_id,
+            from: old.rect.clone(),
+            to: new.rect.clone(),
+        });
+    }
+
+    if old.orientation != new.orientation {
+        changes.push(SurfaceChange::Reoriented {
+            surface_id,
+            from: old.orientation,
+            to: new.orientation,
+        });
+    }
+
+    if old.flags != new.flags {
+        changes.push(SurfaceChange::FlagsChanged {
+            surface_id,
+            from: old.flags,
+            to: new.flags,
+        });
+    }
+
+    if old.scaling != new.scaling {
+        changes.push(SurfaceChange::ScalingChanged {
+            surface_id,
+            scaling: new.scaling.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{NowSurfaceMap, SurfaceCapset, SurfaceCapsetFlags};
+    use std::{cell::RefCell, rc::Rc};
+
+    struct Recorder(Rc<RefCell<Vec<SurfaceChange>>>);
+
+    impl SurfaceManagerCallbackTrait for Recorder {
+        fn on_surface_change(&mut self, change: &SurfaceChange) {
+            self.0.borrow_mut().push(change.clone());
+        }
+    }
+
+    fn rect(left: i16, top: i16, right: i16, bottom: i16) -> EdgeRect {
+        EdgeRect {
+            left,
+            top,
+            right,
+            bottom,
+        }
+    }
+
+    fn manager() -> (SurfaceManager<Recorder>, Rc<RefCell<Vec<SurfaceChange>>>) {
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        (SurfaceManager::new(Recorder(Rc::clone(&changes))), changes)
+    }
+
+    fn dual_monitor_list(sequence_id: u16) -> NowSurfaceMsg {
+        NowSurfaceListReqMsg::new_with_surfaces(
+            sequence_id,
+            3840,
+            1080,
+            vec![
+                NowSurfaceDef::new(1, rect(0, 0, 1920, 1080)),
+                NowSurfaceDef::new(2, rect(1920, 0, 3840, 1080)).flags(SurfacePropertiesFlags::new_empty()),
+            ],
+        )
+        .into()
+    }
+
+    #[test]
+    fn initial_list_adds_surfaces() {
+        let (mut manager, changes) = manager();
+        let rsp = manager.update_with_surface_msg(&dual_monitor_list(3));
+
+        match rsp {
+            Some(NowSurfaceMsg::ListRsp(rsp)) => {
+                assert_eq!(rsp.sequence_id, 3);
+                assert!(rsp.status().is_success());
+            }
+            other => panic!("expected a list response, got {:?}", other),
+        }
+
+        assert_eq!(manager.desktop_size(), (3840, 1080));
+        assert_eq!(manager.surfaces().count(), 2);
+        assert_eq!(manager.primary().unwrap().surface_id, 1);
+        assert_eq!(manager.selected().map(|s| s.surface_id).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(manager.last_list_sequence_id(), Some(3));
+
+        let changes = changes.borrow();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(
+            changes[0],
+            SurfaceChange::DesktopResized {
+                width: 3840,
+                height: 1080
+            }
+        );
+        assert!(matches!(&changes[1], SurfaceChange::Added(surface) if surface.surface_id == 1));
+        assert!(matches!(&changes[2], SurfaceChange::Added(surface) if surface.surface_id == 2));
+    }
+
+    #[test]
+    fn list_update_reports_differences_only() {
+        let (mut manager, changes) = manager();
+        manager.update_with_surface_msg(&dual_monitor_list(0));
+        changes.borrow_mut().clear();
+
+        let update = NowSurfaceListReqMsg::new_with_surfaces(
+            1,
+            3000,
+            1920,
+            vec![
+                NowSurfaceDef::new(1, rect(0, 0, 1920, 1080)),
+                NowSurfaceDef::new(3, rect(1920, 0, 3000, 1920))
+                    .flags(SurfacePropertiesFlags::new_empty())
+                    .orientation(SurfaceOrientation::Portrait),
+            ],
+        );
+        manager.update_with_surface_msg(&update.into());
+
+        let changes = changes.borrow();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(
+            changes[0],
+            SurfaceChange::DesktopResized {
+                width: 3000,
+                height: 1920
+            }
+        );
+        assert!(matches!(&changes[1], SurfaceChange::Removed(surface) if surface.surface_id == 2));
+        assert!(matches!(&changes[2], SurfaceChange::Added(surface) if surface.surface_id == 3));
+    }
+
+    #[test]
+    fn moved_and_reoriented_surface() {
+        let (mut manager, changes) = manager();
+        manager.update_with_surface_msg(&dual_monitor_list(0));
+        changes.borrow_mut().clear();
+
+        let update = NowSurfaceListReqMsg::new_with_surfaces(
+            1,
+            3840,
+            1080,
+            vec![
+                NowSurfaceDef::new(1, rect(0, 0, 1920, 1080)),
+                NowSurfaceDef::new(2, rect(1920, 0, 3000, 1920))
+                    .flags(SurfacePropertiesFlags::new_empty())
+                    .orientation(SurfaceOrientation::Portrait),
+            ],
+        );
+        manager.update_with_surface_msg(&update.into());
+
+        assert_eq!(
+            *changes.borrow(),
+            vec![
+                SurfaceChange::Moved {
+                    surface_id: 2,
+                    from: rect(1920, 0, 3840, 1080),
+                    to: rect(1920, 0, 3000, 1920),
+                },
+                SurfaceChange::Reoriented {
+                    surface_id: 2,
+                    from: SurfaceOrientation::Landscape,
+                    to: SurfaceOrientation::Portrait,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mapping_kept_across_list_updates() {
+        let (mut manager, changes) = manager();
+        manager.update_with_surface_msg(&dual_monitor_list(0));
+
+        let map = NowSurfaceMapReqMsg::new_with_mappings(
+            1,
+            3840,
+            1080,
+            vec![NowSurfaceMap::new(2, 0, rect(0, 0, 1920, 1080))],
+        );
+        let rsp = manager.update_with_surface_msg(&map.into());
+        assert!(matches!(rsp, Some(NowSurfaceMsg::MapRsp(rsp)) if rsp.status().is_success()));
+        assert!(matches!(
+            changes.borrow().last(),
+            Some(SurfaceChange::Mapped { surface_id: 2, .. })
+        ));
+
+        changes.borrow_mut().clear();
+        manager.update_with_surface_msg(&dual_monitor_list(2));
+        assert!(changes.borrow().is_empty());
+        assert_eq!(manager.surface(2).unwrap().mapping.as_ref().unwrap().output_id, 0);
+    }
+
+    #[test]
+    fn map_req_after_layout_change() {
+        let (mut manager, _) = manager();
+        assert!(manager.map_req(0, &[]).is_err());
+
+        manager.update_with_surface_msg(&dual_monitor_list(0));
+        let initial = vec![
+            NowSurfaceMap::new(1, 0, rect(0, 0, 1920, 1080)),
+            NowSurfaceMap::new(2, 1, rect(0, 0, 1920, 1080)),
+        ];
+        let req = manager.map_req(1, &initial).unwrap().unwrap();
+        assert_eq!(req.maps.len(), 2);
+        manager.update_with_surface_msg(&req.into());
+        assert!(manager.map_req(2, &initial).unwrap().is_none());
+
+        // second monitor switched to portrait
+        let update = NowSurfaceListReqMsg::new_with_surfaces(
+            3,
+            3000,
+            1920,
+            vec![
+                NowSurfaceDef::new(1, rect(0, 0, 1920, 1080)),
+                NowSurfaceDef::new(2, rect(1920, 0, 3000, 1920)).orientation(SurfaceOrientation::Portrait),
+            ],
+        );
+        manager.update_with_surface_msg(&update.into());
+
+        let next = vec![
+            NowSurfaceMap::new(1, 0, rect(0, 0, 1920, 1080)),
+            NowSurfaceMap::new(2, 1, rect(0, 0, 1080, 1920)),
+        ];
+        let req = manager.map_req(4, &next).unwrap().unwrap();
+        assert_eq!(req.desktop_width, 3000);
+        assert_eq!(req.maps.0, next[1..].to_vec());
+    }
+
+    #[test]
+    fn strict_layout_refuses_invalid_list() {
+        let (mut manager, changes) = manager();
+        manager.set_strict_layout(true);
+        manager.update_with_surface_msg(&dual_monitor_list(0));
+        changes.borrow_mut().clear();
+
+        let overlapping = NowSurfaceListReqMsg::new_with_surfaces(
+            1,
+            3840,
+            1080,
+            vec![
+                NowSurfaceDef::new(1, rect(0, 0, 1920, 1080)),
+                NowSurfaceDef::new(2, rect(1000, 0, 2920, 1080)),
+            ],
+        );
+        let rsp = manager.update_with_surface_msg(&overlapping.into());
+        assert!(matches!(rsp, Some(NowSurfaceMsg::ListRsp(rsp)) if rsp.flags.failure()));
+        assert!(changes.borrow().is_empty());
+        assert_eq!(manager.last_list_sequence_id(), Some(0));
+        assert_eq!(manager.surface(2).unwrap().rect, rect(1920, 0, 3840, 1080));
+    }
+
+    #[test]
+    fn full_map_req_unmaps_missing_surfaces() {
+        let (mut manager, changes) = manager();
+        manager.update_with_surface_msg(&dual_monitor_list(0));
+
+        let both = vec![
+            NowSurfaceMap::new(1, 0, rect(0, 0, 1920, 1080)),
+            NowSurfaceMap::new(2, 1, rect(0, 0, 1280, 720)).flags(SurfaceMapFlags::new_empty().set_scaled()),
+        ];
+        manager.update_with_surface_msg(&NowSurfaceMapReqMsg::new_with_mappings(1, 3840, 1080, both.clone()).into());
+        assert_eq!(manager.mappings(), both);
+        changes.borrow_mut().clear();
+
+        let only_first = both[..1].to_vec();
+        manager.update_with_surface_msg(&NowSurfaceMapReqMsg::new_with_mappings(2, 3840, 1080, only_first).into());
+        assert_eq!(*changes.borrow(), vec![SurfaceChange::Unmapped { surface_id: 2 }]);
+        assert!(manager.surface(2).unwrap().mapping.is_none());
+    }
+
+    #[test]
+    fn map_with_unknown_surface_fails() {
+        let (mut manager, _) = manager();
+        manager.update_with_surface_msg(&dual_monitor_list(0));
+
+        let map = NowSurfaceMapReqMsg::new_with_mappings(
+            1,
+            3840,
+            1080,
+            vec![
+                NowSurfaceMap::new(1, 0, rect(0, 0, 1920, 1080)),
+                NowSurfaceMap::new(9, 1, rect(0, 0, 1920, 1080)),
+            ],
+        );
+        let rsp = manager.update_with_surface_msg(&map.into());
+        assert!(matches!(rsp, Some(NowSurfaceMsg::MapRsp(rsp)) if rsp.flags.failure()));
+        assert!(manager.surface(1).unwrap().mapping.is_none());
+    }
+
+    #[test]
+    fn select_moves_selected_flag() {
+        let (mut manager, changes) = manager();
+        manager.update_with_surface_msg(&dual_monitor_list(0));
+        changes.borrow_mut().clear();
+
+        manager.update_with_surface_msg(&NowSurfaceSelectReqMsg::new(0, 1, 2).into());
+        assert_eq!(manager.selected().map(|s| s.surface_id).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(changes.borrow().len(), 2);
+
+        let rsp = manager.update_with_surface_msg(&NowSurfaceSelectReqMsg::new(0, 2, 7).into());
+        assert!(matches!(rsp, Some(NowSurfaceMsg::SelectRsp(rsp)) if rsp.flags.failure()));
+    }
+
+    #[test]
+    fn multi_select_selects_all_requested_surfaces() {
+        let (mut manager, changes) = manager();
+        manager.update_with_surface_msg(&dual_monitor_list(0));
+        changes.borrow_mut().clear();
+
+        let rsp = manager.update_with_surface_msg(&NowSurfaceMultiSelectReqMsg::new(0, 1, vec![1, 2]).into());
+        assert!(matches!(rsp, Some(NowSurfaceMsg::SelectRsp(rsp)) if rsp.status().is_success()));
+        assert_eq!(manager.selected().map(|s| s.surface_id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(changes.borrow().len(), 1);
+
+        let rsp = manager.update_with_surface_msg(&NowSurfaceMultiSelectReqMsg::new(0, 2, vec![2, 7]).into());
+        assert!(matches!(rsp, Some(NowSurfaceMsg::SelectRsp(rsp)) if rsp.flags.failure()));
+        assert_eq!(manager.selected().count(), 2);
+    }
+
+    #[test]
+    fn multi_select_req_requires_capability() {
+        let (mut manager, _) = manager();
+        manager.update_with_surface_msg(&dual_monitor_list(0));
+
+        assert!(matches!(
+            manager.select_req(1, &[2]).unwrap(),
+            NowSurfaceMsg::SelectReq(req) if req.surface_id == 2
+        ));
+        assert!(manager.select_req(1, &[1, 2]).is_err());
+        assert!(manager.select_req(1, &[3]).is_err());
+
+        let capset = SurfaceCapset::new(
+            SurfaceCapsetFlags::new_empty().set_multi(),
+            NowSurfaceListReqMsg::new_with_surfaces(0, 0, 0, Vec::new()),
+        );
+        manager.configure_from_capabilities(&[NowCapset::Surface(capset)]);
+        assert!(matches!(
+            manager.select_req(1, &[1, 2]).unwrap(),
+            NowSurfaceMsg::MultiSelectReq(req) if req.surface_ids.0 == vec![1, 2]
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt, Result},
+    message::{MessageType, NowSurfaceMsg},
+    sm::{RequestCompletion, RequestTracker},
+};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SurfaceRequestKind {
+    List,
+    Map,
+    Select,
+}
+
+impl SurfaceRequestKind {
+    /// Returns `None` for response messages.
+    pub fn of(msg: &NowSurfaceMsg) -> Option<Self> {
+        match msg {
+            NowSurfaceMsg::ListReq(_) => Some(Self::List),
+            NowSurfaceMsg::MapReq(_) => Some(Self::Map),
+            NowSurfaceMsg::SelectReq(_) | NowSurfaceMsg::MultiSelectReq(_) => Some(Self::Select),
+            _ => None,
+        }
+    }
+}
+
+/// Surface requests awaiting their response.
+///
+/// Sequence ids are allocated when requests are sent. Failed and timed out requests are
+/// reported as `SurfaceRequestFailed` and `SurfaceRequestTimedOut` errors.
+#[derive(Debug, Clone)]
+pub struct SurfaceTransactions {
+    tracker: RequestTracker<SurfaceRequestKind>,
+    timeout: Duration,
+}
+
+impl SurfaceTransactions {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            tracker: RequestTracker::new(),
+            timeout,
+        }
+    }
+
+    pub fn in_flight_count(&self) -> usize {
+        self.tracker.in_flight_count()
+    }
+
+    pub fn in_flight_sequence_ids(&self) -> Vec<u16> {
+        self.tracker.in_flight_sequence_ids()
+    }
+
+    /// Assigns a fresh sequence id to the request and starts tracking it.
+    pub fn send(&mut self, mut msg: NowSurfaceMsg, now: Instant) -> Result<NowSurfaceMsg> {
+        let kind = SurfaceRequestKind::of(&msg)
+            .chain(ProtoErrorKind::UnexpectedMessage(MessageType::Surface))
+            .or_desc("only surface requests can be tracked")?;
+        let sequence_id = self.tracker.start(kind, now);
+
+        match &mut msg {
+            NowSurfaceMsg::ListReq(req) => req.sequence_id = sequence_id,
+            NowSurfaceMsg::MapReq(req) => req.sequence_id = sequence_id,
+            NowSurfaceMsg::SelectReq(req) => req.sequence_id = sequence_id,
+            NowSurfaceMsg::MultiSelectReq(req) => req.sequence_id = sequence_id,
+            _ => unreachable!("checked above"),
+        }
+
+        Ok(msg)
+    }
+
+    /// Completes the request answered by given message.
+    ///
+    /// Returns `None` if the message isn't a response to a tracked request.
+    pub fn complete(
+        &mut self,
+        msg: &NowSurfaceMsg,
+        now: Instant,
+    ) -> Option<Result<RequestCompletion<SurfaceRequestKind>>> {
+        let completion = self.tracker.complete_surface_msg(msg, now)?;
+        if completion.is_success() {
+            Some(Ok(completion))
+        } else {
+            Some(
+                ProtoError::new(ProtoErrorKind::SurfaceRequestFailed(
+                    completion.request,
+                    completion.status,
+                ))
+                .or_else_desc(|| format!("sequence id {}", completion.sequence_id)),
+            )
+        }
+    }
+
+    /// Stops tracking requests without response for longer than the timeout.
+    pub fn expire(&mut self, now: Instant) -> Vec<ProtoError> {
+        self.tracker
+            .expire(now, self.timeout)
+            .into_iter()
+            .map(|(sequence_id, kind)| ProtoError {
+                description: Some(format!("sequence id {}", sequence_id).into()),
+                ..ProtoError::from(ProtoErrorKind::SurfaceRequestTimedOut(kind))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        NowSurfaceListReqMsg, NowSurfaceMapRspMsg, NowSurfaceSelectReqMsg, NowSurfaceSelectRspMsg, SurfaceResponseFlags,
+    };
+
+    #[test]
+    fn responses_correlated_by_sequence_id() {
+        let t0 = Instant::now();
+        let mut transactions = SurfaceTransactions::new(Duration::from_secs(5));
+
+        let list = transactions
+            .send(NowSurfaceListReqMsg::new(0, 1920, 1080).into(), t0)
+            .unwrap();
+        let select = transactions
+            .send(NowSurfaceSelectReqMsg::new(0, 0, 1).into(), t0)
+            .unwrap();
+        let select_id = match select {
+            NowSurfaceMsg::SelectReq(req) => req.sequence_id,
+            other => panic!("expected a select request, got {:?}", other),
+        };
+        assert!(matches!(list, NowSurfaceMsg::ListReq(req) if req.sequence_id != select_id));
+        assert_eq!(transactions.in_flight_count(), 2);
+
+        let rsp = NowSurfaceSelectRspMsg::new(SurfaceResponseFlags::new_empty().set_failure(), select_id);
+        let err = transactions.complete(&rsp.into(), t0).unwrap().unwrap_err();
+        assert!(matches!(
+            err.kind,
+            ProtoErrorKind::SurfaceRequestFailed(SurfaceRequestKind::Select, status) if !status.is_success()
+        ));
+
+        // unsolicited
+        let rsp = NowSurfaceMapRspMsg::new(SurfaceResponseFlags::new_empty(), 42);
+        assert!(transactions.complete(&rsp.into(), t0).is_none());
+
+        let expired = transactions.expire(t0 + Duration::from_secs(5));
+        assert_eq!(expired.len(), 1);
+        assert!(matches!(
+            expired[0].kind,
+            ProtoErrorKind::SurfaceRequestTimedOut(SurfaceRequestKind::List)
+        ));
+        assert_eq!(transactions.in_flight_count(), 0);
+    }
+
+    #[test]
+    fn responses_cannot_be_sent() {
+        let mut transactions = SurfaceTransactions::new(Duration::from_secs(5));
+        let rsp = NowSurfaceMapRspMsg::new(SurfaceResponseFlags::new_empty(), 0);
+        assert!(transactions.send(rsp.into(), Instant::now()).is_err());
+    }
+}
@@ -0,0 +1,11 @@
+//! Helpers shared by the tests of the virtual channel state machines.
+
+use crate::sm::VirtualChannelSM;
+
+/// Moves the queued messages of `from` to `to`.
+pub(crate) fn transfer<S: VirtualChannelSM>(from: &mut S, to: &mut S) {
+    while !from.waiting_for_packet() {
+        let msg = from.update_without_chan_msg().unwrap().unwrap();
+        to.update_with_chan_msg(&msg).unwrap();
+    }
+}
@@ -0,0 +1,666 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    message::{
+        ChannelName, NowString256, NowTunnelCloseMsg, NowTunnelDataMsg, NowTunnelMsg, NowTunnelOpenMsg,
+        NowTunnelWindowMsg, NowVirtualChannel,
+    },
+    sm::{VirtChannelSMResult, VirtualChannelSM},
+};
+use alloc::collections::{BTreeMap, VecDeque};
+use core::{
+    future::poll_fn,
+    task::{Context, Poll, Waker},
+};
+use std::{cell::RefCell, rc::Rc};
+
+/// Streams are identified by their id along with the peer that opened them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct StreamKey {
+    opened_locally: bool,
+    id: u16,
+}
+
+#[derive(Debug, Default)]
+struct StreamState {
+    /// `host:port` the peer asked to connect the stream to.
+    target: Option<String>,
+    /// Bytes received and not read yet.
+    incoming: VecDeque<u8>,
+    peer_closed: bool,
+    closed_locally: bool,
+    /// Bytes written and not taken by the session yet.
+    pending_write: usize,
+    /// Bytes the peer may still receive over the stream.
+    send_credits: u32,
+    /// Bytes read since credits were last granted to the peer.
+    unacknowledged: u32,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new(target: Option<String>) -> Self {
+        Self {
+            target,
+            send_credits: TunnelChannel::RECEIVE_WINDOW,
+            ..Self::default()
+        }
+    }
+
+    fn __wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TunnelState {
+    opened: bool,
+    next_stream_id: u16,
+    streams: BTreeMap<StreamKey, StreamState>,
+    outgoing: VecDeque<NowTunnelMsg>,
+    /// Streams opened by the peer and not accepted yet.
+    accept_queue: VecDeque<u16>,
+    accept_waker: Option<Waker>,
+    /// Remote forwarding messages, handled by the port forwarder.
+    listen_messages: VecDeque<NowTunnelMsg>,
+}
+
+impl TunnelState {
+    fn __stream_closed(&mut self, key: StreamKey) {
+        let finished = self
+            .streams
+            .get(&key)
+            .is_some_and(|stream| stream.peer_closed && stream.closed_locally && stream.incoming.is_empty());
+        if finished {
+            self.streams.remove(&key);
+        }
+    }
+}
+
+/// State machine of the tunnel channel, carrying byte streams between peers.
+///
+/// Either peer opens streams with [`TunnelChannel::open_stream`](struct.TunnelChannel.html#method.open_stream)
+/// and accepts the ones opened by the other with
+/// [`TunnelChannel::accept`](struct.TunnelChannel.html#method.accept). Streams are closed separately in
+/// each direction, like TCP half-close: reads reach the end of the stream once the peer shut its side down.
+///
+/// Each direction of a stream is under flow control: a peer never sends more than
+/// [`TunnelChannel::RECEIVE_WINDOW`](struct.TunnelChannel.html#associatedconstant.RECEIVE_WINDOW) bytes
+/// not read yet by the other, which grants credits back as the data is read. A peer exceeding the window
+/// gets its stream reset, and streams opened beyond
+/// [`TunnelChannel::MAX_PENDING_ACCEPTS`](struct.TunnelChannel.html#associatedconstant.MAX_PENDING_ACCEPTS)
+/// are refused by closing them right away.
+pub struct TunnelChannelSM {
+    state: Rc<RefCell<TunnelState>>,
+}
+
+impl TunnelChannelSM {
+    pub fn new() -> (Self, TunnelChannel) {
+        let state = Rc::new(RefCell::new(TunnelState::default()));
+        let channel = TunnelChannel {
+            state: Rc::clone(&state),
+        };
+        (Self { state }, channel)
+    }
+
+    fn __on_message(&mut self, msg: &NowTunnelMsg) {
+        let mut state = self.state.borrow_mut();
+        let (flags, stream_id) = match msg {
+            NowTunnelMsg::Open(msg) => (msg.flags, msg.stream_id),
+            NowTunnelMsg::Data(msg) => (msg.flags, msg.stream_id),
+            NowTunnelMsg::Close(msg) => (msg.flags, msg.stream_id),
+            NowTunnelMsg::Window(msg) => (msg.flags, msg.stream_id),
+            NowTunnelMsg::Listen(_) | NowTunnelMsg::ListenRsp(_) => {
+                state.listen_messages.push_back(msg.clone());
+                return;
+            }
+        };
+        let key = StreamKey {
+            opened_locally: !flags.initiator(),
+            id: stream_id,
+        };
+
+        match msg {
+            NowTunnelMsg::Open(_)
+                if !key.opened_locally && state.accept_queue.len() >= TunnelChannel::MAX_PENDING_ACCEPTS =>
+            {
+                log::warn!("too many tunnel streams pending, stream {} refused", key.id);
+                state.outgoing.push_back(NowTunnelCloseMsg::new(key.id, false).into());
+            }
+            NowTunnelMsg::Open(msg) if !key.opened_locally && !state.streams.contains_key(&key) => {
+                log::trace!("tunnel stream {} opened by peer", key.id);
+                let stream = StreamState::new(msg.get_target().map(str::to_owned));
+                state.streams.insert(key, stream);
+                state.accept_queue.push_back(key.id);
+                if let Some(waker) = state.accept_waker.take() {
+                    waker.wake();
+                }
+            }
+            NowTunnelMsg::Open(_) => log::warn!("unexpected opening of tunnel stream {:?}", key),
+            NowTunnelMsg::Data(msg) => match state.streams.get_mut(&key) {
+                Some(stream)
+                    if !stream.peer_closed
+                        && stream.incoming.len() + msg.data.0.len() > TunnelChannel::RECEIVE_WINDOW as usize =>
+                {
+                    log::warn!(
+                        "peer exceeded the receive window of tunnel stream {:?}, stream reset",
+                        key
+                    );
+                    stream.incoming.clear();
+                    stream.peer_closed = true;
+                    stream.pending_write = 0;
+                    stream.__wake();
+                    if !stream.closed_locally {
+                        stream.closed_locally = true;
+                        state
+                            .outgoing
+                            .push_back(NowTunnelCloseMsg::new(key.id, key.opened_locally).into());
+                    }
+                    state.__stream_closed(key);
+                }
+                Some(stream) if !stream.peer_closed => {
+                    stream.incoming.extend(msg.data.0.iter());
+                    if let Some(waker) = stream.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+                _ => log::warn!("data received for tunnel stream {:?} not opened, dropped", key),
+            },
+            NowTunnelMsg::Window(msg) => match state.streams.get_mut(&key) {
+                Some(stream) => {
+                    stream.send_credits = stream.send_credits.saturating_add(msg.credits);
+                    if let Some(waker) = stream.write_waker.take() {
+                        waker.wake();
+                    }
+                }
+                None => log::trace!("credits received for tunnel stream {:?} no longer open", key),
+            },
+            NowTunnelMsg::Close(_) => match state.streams.get_mut(&key) {
+                Some(stream) => {
+                    log::trace!("tunnel stream {:?} closed by peer", key);
+                    stream.peer_closed = true;
+                    stream.__wake();
+                    state.__stream_closed(key);
+                }
+                None => log::warn!("close received for tunnel stream {:?} not opened", key),
+            },
+            NowTunnelMsg::Listen(_) | NowTunnelMsg::ListenRsp(_) => {}
+        }
+    }
+}
+
+impl VirtualChannelSM for TunnelChannelSM {
+    fn get_channel_name(&self) -> ChannelName {
+        ChannelName::Tunnel
+    }
+
+    fn is_terminated(&self) -> bool {
+        false
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        let state = self.state.borrow();
+        !state.opened || state.outgoing.is_empty()
+    }
+
+    fn update_without_chan_msg<'msg>(&mut self) -> VirtChannelSMResult<'msg> {
+        let mut state = self.state.borrow_mut();
+        let msg = state
+            .outgoing
+            .pop_front()
+            .chain(ProtoErrorKind::VirtualChannel(ChannelName::Tunnel))
+            .or_desc("unexpected call to `update_without_chan_msg` without message to send")?;
+
+        if let NowTunnelMsg::Data(data) = &msg {
+            let key = StreamKey {
+                opened_locally: data.flags.initiator(),
+                id: data.stream_id,
+            };
+            if let Some(stream) = state.streams.get_mut(&key) {
+                stream.pending_write = stream.pending_write.saturating_sub(data.data.0.len());
+                if let Some(waker) = stream.write_waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+
+        Ok(Some(msg.into()))
+    }
+
+    fn update_with_chan_msg<'msg: 'a, 'a>(
+        &mut self,
+        chan_msg: &'a NowVirtualChannel<'msg>,
+    ) -> VirtChannelSMResult<'msg> {
+        match chan_msg {
+            NowVirtualChannel::Tunnel(msg) => {
+                self.__on_message(msg);
+                Ok(None)
+            }
+            _ => ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::Tunnel))
+                .or_else_desc(|| format!("received an unexpected message: {:?}", chan_msg)),
+        }
+    }
+
+    fn on_open(&mut self) {
+        log::trace!("tunnel channel opened");
+        self.state.borrow_mut().opened = true;
+    }
+
+    /// Streams don't outlive the channel: they all reach their end, data not read yet being kept.
+    fn on_close(&mut self) {
+        log::trace!("tunnel channel closed");
+        let mut state = self.state.borrow_mut();
+        state.opened = false;
+        state.outgoing.clear();
+        state.accept_queue.clear();
+        state.listen_messages.clear();
+        let keys: Vec<StreamKey> = state.streams.keys().copied().collect();
+        for key in keys {
+            if let Some(stream) = state.streams.get_mut(&key) {
+                stream.peer_closed = true;
+                stream.closed_locally = true;
+                stream.pending_write = 0;
+                stream.__wake();
+            }
+            state.__stream_closed(key);
+        }
+    }
+}
+
+/// Application side of a [`TunnelChannelSM`](struct.TunnelChannelSM.html).
+///
+/// Messages of the streams are queued until the sharee is updated with the channel opened: the sans-IO
+/// connection and the async client do so before waiting for the next packet.
+#[derive(Clone)]
+pub struct TunnelChannel {
+    state: Rc<RefCell<TunnelState>>,
+}
+
+impl TunnelChannel {
+    /// Largest data carried by a message: larger writes are split.
+    pub const MAX_CHUNK_SIZE: usize = 16 * 1024;
+    /// Bytes written to a stream and not taken by the session yet, beyond which writes wait.
+    pub const MAX_PENDING_WRITE: usize = 64 * 1024;
+    /// Bytes received over a stream and not read yet the peer may send: credits are granted back once
+    /// half of it was read.
+    pub const RECEIVE_WINDOW: u32 = 256 * 1024;
+    /// Streams opened by the peer and not accepted yet, beyond which the peer's streams are refused.
+    pub const MAX_PENDING_ACCEPTS: usize = 32;
+
+    pub fn is_open(&self) -> bool {
+        self.state.borrow().opened
+    }
+
+    /// Opens a stream to the peer. Data can be written right away, it is sent once the channel is opened.
+    pub fn open_stream(&self) -> TunnelStream {
+        self.__open(None)
+    }
+
+    /// Opens a stream the peer is to connect to `target` (`host:port`).
+    pub fn open_stream_to(&self, target: NowString256) -> TunnelStream {
+        self.__open(Some(target))
+    }
+
+    pub(crate) fn send(&self, msg: NowTunnelMsg) {
+        self.state.borrow_mut().outgoing.push_back(msg);
+    }
+
+    pub(crate) fn poll_listen_message(&self) -> Option<NowTunnelMsg> {
+        self.state.borrow_mut().listen_messages.pop_front()
+    }
+
+    /// `false` once the stream was dropped or closed by both peers.
+    pub(crate) fn is_stream_alive(&self, opened_locally: bool, id: u16) -> bool {
+        self.state
+            .borrow()
+            .streams
+            .contains_key(&StreamKey { opened_locally, id })
+    }
+
+    fn __open(&self, target: Option<NowString256>) -> TunnelStream {
+        let mut state = self.state.borrow_mut();
+        let mut key = StreamKey {
+            opened_locally: true,
+            id: state.next_stream_id,
+        };
+        while state.streams.contains_key(&key) {
+            key.id = key.id.wrapping_add(1);
+        }
+        state.next_stream_id = key.id.wrapping_add(1);
+        let open = match &target {
+            Some(target) => NowTunnelOpenMsg::new_with_target(key.id, target.clone()),
+            None => NowTunnelOpenMsg::new(key.id),
+        };
+        let stream = StreamState::new(target.map(|target| target.as_str().to_owned()));
+        state.streams.insert(key, stream);
+        state.outgoing.push_back(open.into());
+        log::trace!("tunnel stream {} opened", key.id);
+
+        TunnelStream {
+            key,
+            state: Rc::clone(&self.state),
+        }
+    }
+
+    /// Oldest stream opened by the peer and not accepted yet.
+    pub fn try_accept(&self) -> Option<TunnelStream> {
+        let id = self.state.borrow_mut().accept_queue.pop_front()?;
+        Some(TunnelStream {
+            key: StreamKey {
+                opened_locally: false,
+                id,
+            },
+            state: Rc::clone(&self.state),
+        })
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<TunnelStream> {
+        match self.try_accept() {
+            Some(stream) => Poll::Ready(stream),
+            None => {
+                self.state.borrow_mut().accept_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Waits for the peer to open a stream.
+    pub async fn accept(&self) -> TunnelStream {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+}
+
+/// Byte stream tunneled over the [`TunnelChannel`](struct.TunnelChannel.html).
+///
+/// With the `async` feature, streams implement tokio's `AsyncRead` and `AsyncWrite`.
+/// Dropping a stream closes it: data received and not read yet is discarded.
+pub struct TunnelStream {
+    key: StreamKey,
+    state: Rc<RefCell<TunnelState>>,
+}
+
+impl TunnelStream {
+    pub fn id(&self) -> u16 {
+        self.key.id
+    }
+
+    pub fn is_opened_locally(&self) -> bool {
+        self.key.opened_locally
+    }
+
+    /// `host:port` the stream is to be connected to, if opened with one.
+    pub fn target(&self) -> Option<String> {
+        self.state
+            .borrow()
+            .streams
+            .get(&self.key)
+            .and_then(|stream| stream.target.clone())
+    }
+
+    /// Number of bytes received and not read yet.
+    pub fn available(&self) -> usize {
+        self.state
+            .borrow()
+            .streams
+            .get(&self.key)
+            .map_or(0, |stream| stream.incoming.len())
+    }
+
+    /// Reads the bytes received so far into `buf`. Returns `Poll::Ready(0)` at the end of the stream.
+    pub fn poll_read_bytes(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<usize> {
+        let mut state = self.state.borrow_mut();
+        let stream = match state.streams.get_mut(&self.key) {
+            Some(stream) => stream,
+            None => return Poll::Ready(0),
+        };
+
+        if stream.incoming.is_empty() {
+            if stream.peer_closed || buf.is_empty() {
+                return Poll::Ready(0);
+            }
+            stream.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = buf.len().min(stream.incoming.len());
+        for (dst, src) in buf.iter_mut().zip(stream.incoming.drain(..len)) {
+            *dst = src;
+        }
+        let mut grant = None;
+        if !stream.peer_closed {
+            stream.unacknowledged += len as u32;
+            if stream.unacknowledged >= TunnelChannel::RECEIVE_WINDOW / 2 {
+                grant = Some(core::mem::take(&mut stream.unacknowledged));
+            }
+        }
+        if let Some(credits) = grant {
+            state
+                .outgoing
+                .push_back(NowTunnelWindowMsg::new(self.key.id, self.key.opened_locally, credits).into());
+        }
+        state.__stream_closed(self.key);
+        Poll::Ready(len)
+    }
+
+    /// Queues up to `MAX_CHUNK_SIZE` bytes of `buf` to be sent, waiting while too many bytes are pending
+    /// or while the peer didn't grant credits.
+    pub fn poll_write_bytes(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, ProtoError>> {
+        let mut state = self.state.borrow_mut();
+        let stream = match state.streams.get_mut(&self.key) {
+            Some(stream) if !stream.closed_locally => stream,
+            _ => {
+                return Poll::Ready(
+                    ProtoError::new(ProtoErrorKind::VirtualChannel(ChannelName::Tunnel))
+                        .or_else_desc(|| format!("tunnel stream {} is closed", self.key.id)),
+                )
+            }
+        };
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        if stream.pending_write >= TunnelChannel::MAX_PENDING_WRITE || stream.send_credits == 0 {
+            stream.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = buf
+            .len()
+            .min(TunnelChannel::MAX_CHUNK_SIZE)
+            .min(TunnelChannel::MAX_PENDING_WRITE - stream.pending_write)
+            .min(stream.send_credits as usize);
+        stream.pending_write += len;
+        stream.send_credits -= len as u32;
+        state
+            .outgoing
+            .push_back(NowTunnelDataMsg::new(self.key.id, self.key.opened_locally, buf[..len].to_vec()).into());
+        Poll::Ready(Ok(len))
+    }
+
+    /// Ready once the bytes written were all taken by the session.
+    pub fn poll_flush_bytes(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.borrow_mut();
+        match state.streams.get_mut(&self.key) {
+            Some(stream) if stream.pending_write > 0 => {
+                stream.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            _ => Poll::Ready(()),
+        }
+    }
+
+    /// Shuts the sending side down: the peer reaches the end of the stream once the data written is read.
+    pub fn shutdown_write(&self) {
+        let mut state = self.state.borrow_mut();
+        let closed = match state.streams.get_mut(&self.key) {
+            Some(stream) if !stream.closed_locally => {
+                stream.closed_locally = true;
+                true
+            }
+            _ => false,
+        };
+        if closed {
+            state
+                .outgoing
+                .push_back(NowTunnelCloseMsg::new(self.key.id, self.key.opened_locally).into());
+            state.__stream_closed(self.key);
+        }
+    }
+}
+
+impl Drop for TunnelStream {
+    fn drop(&mut self) {
+        self.shutdown_write();
+        self.state.borrow_mut().streams.remove(&self.key);
+    }
+}
+
+#[cfg(feature = "async")]
+mod io {
+    use super::TunnelStream;
+    use core::{
+        pin::Pin,
+        task::{ready, Context, Poll},
+    };
+    use std::io;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    impl AsyncRead for TunnelStream {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let len = ready!(self.poll_read_bytes(cx, buf.initialize_unfilled()));
+            buf.advance(len);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for TunnelStream {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.poll_write_bytes(cx, buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.poll_flush_bytes(cx).map(Ok)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.shutdown_write();
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sm::test_support::transfer;
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn streams_read_and_written_asynchronously() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut client_sm, client) = TunnelChannelSM::new();
+        let (mut server_sm, server) = TunnelChannelSM::new();
+        client_sm.on_open();
+        server_sm.on_open();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let mut client_stream = client.open_stream();
+            client_stream.write_all(b"hello tunnel").await.unwrap();
+            client_stream.shutdown().await.unwrap();
+            transfer(&mut client_sm, &mut server_sm);
+
+            let mut server_stream = server.accept().await;
+            let mut received = Vec::new();
+            server_stream.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"hello tunnel");
+
+            server_sm.on_close();
+            assert!(server_stream.write_all(b"closed").await.is_err());
+        });
+    }
+
+    #[test]
+    fn writes_wait_for_credits_granted_by_reads() {
+        let (mut client_sm, client) = TunnelChannelSM::new();
+        let (mut server_sm, server) = TunnelChannelSM::new();
+        client_sm.on_open();
+        server_sm.on_open();
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let client_stream = client.open_stream();
+        let chunk = vec![0xab; TunnelChannel::MAX_CHUNK_SIZE];
+        let mut written = 0;
+        while let Poll::Ready(len) = client_stream.poll_write_bytes(&mut cx, &chunk) {
+            written += len.unwrap();
+            transfer(&mut client_sm, &mut server_sm);
+        }
+        assert_eq!(written, TunnelChannel::RECEIVE_WINDOW as usize);
+
+        let server_stream = server.try_accept().unwrap();
+        assert_eq!(server_stream.available(), written);
+        let mut buf = vec![0; TunnelChannel::RECEIVE_WINDOW as usize / 2];
+        assert_eq!(server_stream.poll_read_bytes(&mut cx, &mut buf), Poll::Ready(buf.len()));
+        transfer(&mut server_sm, &mut client_sm);
+
+        assert!(matches!(
+            client_stream.poll_write_bytes(&mut cx, &chunk),
+            Poll::Ready(Ok(TunnelChannel::MAX_CHUNK_SIZE))
+        ));
+    }
+
+    #[test]
+    fn stream_exceeding_the_window_is_reset() {
+        let (mut sm, channel) = TunnelChannelSM::new();
+        sm.on_open();
+
+        sm.update_with_chan_msg(&NowTunnelOpenMsg::new(1).into()).unwrap();
+        let stream = channel.try_accept().unwrap();
+        let data = vec![0; TunnelChannel::MAX_CHUNK_SIZE];
+        for _ in 0..TunnelChannel::RECEIVE_WINDOW as usize / data.len() + 1 {
+            sm.update_with_chan_msg(&NowTunnelDataMsg::new(1, true, data.clone()).into())
+                .unwrap();
+        }
+
+        assert_eq!(stream.available(), 0);
+        match sm.update_without_chan_msg().unwrap() {
+            Some(NowVirtualChannel::Tunnel(NowTunnelMsg::Close(msg))) => assert_eq!(msg.stream_id, 1),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(matches!(
+            stream.poll_write_bytes(&mut cx, b"reset"),
+            Poll::Ready(Err(_))
+        ));
+    }
+
+    #[test]
+    fn streams_refused_beyond_pending_accepts() {
+        let (mut sm, channel) = TunnelChannelSM::new();
+        sm.on_open();
+
+        for id in 0..=TunnelChannel::MAX_PENDING_ACCEPTS as u16 {
+            sm.update_with_chan_msg(&NowTunnelOpenMsg::new(id).into()).unwrap();
+        }
+
+        let refused = TunnelChannel::MAX_PENDING_ACCEPTS as u16;
+        match sm.update_without_chan_msg().unwrap() {
+            Some(NowVirtualChannel::Tunnel(NowTunnelMsg::Close(msg))) => assert_eq!(msg.stream_id, refused),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        assert!(!channel.is_stream_alive(false, refused));
+        let mut accepted = 0;
+        while channel.try_accept().is_some() {
+            accepted += 1;
+        }
+        assert_eq!(accepted, TunnelChannel::MAX_PENDING_ACCEPTS);
+    }
+}
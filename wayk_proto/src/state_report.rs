@@ -0,0 +1,178 @@
+//! Snapshot of a session state, for diagnostics.
+//!
+//! [`Sharee::state_report`](../sharee/struct.Sharee.html#method.state_report) returns a
+//! [`SessionStateReport`](struct.SessionStateReport.html) whose `Display` implementation is a readable
+//! dump, meant to be attached to bug reports.
+
+use crate::{message::ChannelName, sharee::ShareeState, sm::ConnectionState, version::NowProtocolVersion};
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelStatus {
+    /// No state machine nor handler is registered for the channel.
+    Unhandled,
+    /// State machine waiting for a packet.
+    Waiting,
+    /// State machine ready to be updated without packet.
+    Ready,
+    Terminated,
+    Handler {
+        opened: bool,
+        queued: usize,
+    },
+}
+
+impl fmt::Display for ChannelStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelStatus::Unhandled => write!(f, "unhandled"),
+            ChannelStatus::Waiting => write!(f, "waiting for packet"),
+            ChannelStatus::Ready => write!(f, "ready"),
+            ChannelStatus::Terminated => write!(f, "terminated"),
+            ChannelStatus::Handler { opened: true, queued } => write!(f, "handler, {} queued", queued),
+            ChannelStatus::Handler { opened: false, .. } => write!(f, "handler, closed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelReport {
+    pub name: ChannelName,
+    pub id: u8,
+    pub status: ChannelStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionStateReport {
+    pub state: ShareeState,
+    /// Step of the connection sequence, while connecting.
+    pub connection_step: Option<ConnectionState>,
+    /// Protocol version of the peer, once its handshake is received.
+    pub peer_version: Option<NowProtocolVersion>,
+    /// Protocol version selected in the handshake.
+    pub negotiated_version: Option<NowProtocolVersion>,
+    pub session_id: Option<u32>,
+    /// Channels opened by the connection sequence.
+    pub channels: Vec<ChannelReport>,
+    /// Requests waiting for their response. Sharees don't track requests: these are the ones
+    /// added by the caller, typically from `SurfaceTransactions::in_flight_sequence_ids`.
+    pub pending_sequence_ids: Vec<u16>,
+    pub last_error: Option<String>,
+}
+
+impl SessionStateReport {
+    pub fn pending_sequence_ids(self, pending_sequence_ids: Vec<u16>) -> Self {
+        Self {
+            pending_sequence_ids,
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for SessionStateReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "session state: {:?}", self.state)?;
+        if let Some(step) = self.connection_step {
+            writeln!(f, "connection step: {:?}", step)?;
+        }
+        match self.peer_version {
+            Some(version) => writeln!(f, "peer version: {}", version)?,
+            None => writeln!(f, "peer version: unknown")?,
+        }
+        if let Some(version) = self.negotiated_version {
+            writeln!(f, "negotiated version: {}", version)?;
+        }
+        match self.session_id {
+            Some(session_id) => writeln!(f, "session id: {}", session_id)?,
+            None => writeln!(f, "session id: none")?,
+        }
+
+        writeln!(f, "channels: {}", self.channels.len())?;
+        for channel in &self.channels {
+            writeln!(f, "  [{}] {}: {}", channel.id, channel.name.as_str(), channel.status)?;
+        }
+
+        if self.pending_sequence_ids.is_empty() {
+            writeln!(f, "pending requests: none")?;
+        } else {
+            let ids: Vec<String> = self.pending_sequence_ids.iter().map(u16::to_string).collect();
+            writeln!(f, "pending requests: {}", ids.join(", "))?;
+        }
+
+        match &self.last_error {
+            Some(error) => write!(f, "last error: {}", error),
+            None => write!(f, "last error: none"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channels_manager::ChannelsManager,
+        message::{AuthType, NowCapset, TransportCapset},
+        sharee::{DummyShareeCallback, Sharee},
+        sm::{ClientConnectionSeqSM, DummyConnectionSeqCallback, NowConnection, ServerConnectionSeqSM},
+    };
+    use std::time::Instant;
+
+    #[test]
+    fn report_of_connected_session() {
+        let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .channels_to_open(vec![ChannelName::Chat])
+            .build();
+        let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![ChannelName::Chat])
+            .build();
+        let mut client = NowConnection::new(Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback));
+        let mut server = NowConnection::new(Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback));
+
+        let report = client.get_sharee().state_report();
+        assert_eq!(report.connection_step, Some(ConnectionState::Handshake));
+        assert_eq!(report.peer_version, None);
+
+        client.update(Instant::now()).unwrap();
+        server.update(Instant::now()).unwrap();
+        for _ in 0..32 {
+            while let Some(frame) = client.poll_transmit() {
+                server.feed_bytes(&frame, Instant::now()).unwrap();
+            }
+            while let Some(frame) = server.poll_transmit() {
+                client.feed_bytes(&frame, Instant::now()).unwrap();
+            }
+        }
+
+        let report = client.get_sharee().state_report();
+        assert_eq!(report.state, ShareeState::Active);
+        assert_eq!(report.connection_step, None);
+        assert_eq!(report.peer_version, Some(NowProtocolVersion::CURRENT));
+        assert_eq!(report.negotiated_version, Some(NowProtocolVersion::CURRENT));
+        assert_eq!(report.channels.len(), 1);
+        assert_eq!(report.channels[0].name, ChannelName::Chat);
+        assert_eq!(report.channels[0].status, ChannelStatus::Unhandled);
+
+        // chat messages are unexpected without channel state machine
+        let chat = crate::message::NowChatPokeMsg::new(0);
+        let id = report.channels[0].id;
+        server
+            .send(crate::packet::NowPacket::from_virt_channel(chat, id), Instant::now())
+            .unwrap();
+        assert!(client
+            .feed_bytes(&server.poll_transmit().unwrap(), Instant::now())
+            .is_err());
+
+        let report = client.get_sharee().state_report().pending_sequence_ids(vec![3, 4]);
+        assert!(report.last_error.is_some());
+        let dump = report.to_string();
+        assert!(dump.contains("session state: Active"));
+        assert!(dump.contains(&format!("peer version: {}", NowProtocolVersion::CURRENT)));
+        assert!(dump.contains(&format!("  [{}] NowChat: unhandled", id)));
+        assert!(dump.contains("pending requests: 3, 4"));
+        assert!(dump.contains("last error: "));
+    }
+}
@@ -0,0 +1,209 @@
+//! Tuning of TCP connections.
+//!
+//! Remote control is latency sensitive: small input and surface packets shouldn't wait for
+//! Nagle's algorithm, hence `TCP_NODELAY` being enabled by default. Other options are left to
+//! the OS defaults unless configured.
+//!
+//! Keep-alive, buffer sizes and the local address aren't supported on WebAssembly, which fails
+//! with `io::ErrorKind::Unsupported` when they are configured.
+
+use crate::error::ProtoError;
+use std::{
+    io,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TcpConfig {
+    /// Disables Nagle's algorithm (`TCP_NODELAY`).
+    pub nodelay: bool,
+    /// Enables TCP keep-alive probes, sent after the given idle time.
+    pub keepalive: Option<Duration>,
+    /// Size of the kernel send buffer (`SO_SNDBUF`).
+    pub send_buffer_size: Option<usize>,
+    /// Size of the kernel receive buffer (`SO_RCVBUF`).
+    pub recv_buffer_size: Option<usize>,
+    /// Local address the socket is bound to before connecting. Port 0 lets the OS pick one.
+    pub bind_addr: Option<SocketAddr>,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            bind_addr: None,
+        }
+    }
+}
+
+impl TcpConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn nodelay(self, nodelay: bool) -> Self {
+        Self { nodelay, ..self }
+    }
+
+    pub fn keepalive(self, idle: Duration) -> Self {
+        Self {
+            keepalive: Some(idle),
+            ..self
+        }
+    }
+
+    pub fn send_buffer_size(self, size: usize) -> Self {
+        Self {
+            send_buffer_size: Some(size),
+            ..self
+        }
+    }
+
+    pub fn recv_buffer_size(self, size: usize) -> Self {
+        Self {
+            recv_buffer_size: Some(size),
+            ..self
+        }
+    }
+
+    pub fn bind_addr(self, addr: SocketAddr) -> Self {
+        Self {
+            bind_addr: Some(addr),
+            ..self
+        }
+    }
+
+    /// Connects to the first address of `addr` accepting the connection and applies the options.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> Result<TcpStream, ProtoError> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match self.__connect_addr(&addr) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        let err = last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"));
+        Err(ProtoError::from(err))
+    }
+
+    /// Applies the options, except the local address, to an already connected stream.
+    pub fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(idle) = self.keepalive {
+            sys::set_keepalive(stream, idle)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            sys::set_send_buffer_size(stream, size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            sys::set_recv_buffer_size(stream, size)?;
+        }
+        Ok(())
+    }
+
+    fn __connect_addr(&self, addr: &SocketAddr) -> io::Result<TcpStream> {
+        let stream = match self.bind_addr {
+            Some(local) => sys::connect_from(&local, addr)?,
+            None => TcpStream::connect(addr)?,
+        };
+        self.configure(&stream)?;
+        Ok(stream)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod sys {
+    use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+    use std::{
+        io,
+        net::{SocketAddr, TcpStream},
+        time::Duration,
+    };
+
+    pub(super) fn set_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+        let keepalive = TcpKeepalive::new().with_time(idle.max(Duration::from_secs(1)));
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)
+    }
+
+    pub(super) fn set_send_buffer_size(stream: &TcpStream, size: usize) -> io::Result<()> {
+        SockRef::from(stream).set_send_buffer_size(size)
+    }
+
+    pub(super) fn set_recv_buffer_size(stream: &TcpStream, size: usize) -> io::Result<()> {
+        SockRef::from(stream).set_recv_buffer_size(size)
+    }
+
+    /// Connects to `remote` from a socket bound to `local`.
+    pub(super) fn connect_from(local: &SocketAddr, remote: &SocketAddr) -> io::Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(*remote), Type::STREAM, None)?;
+        socket.bind(&(*local).into())?;
+        socket.connect(&(*remote).into())?;
+        Ok(socket.into())
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod sys {
+    use std::{
+        io,
+        net::{SocketAddr, TcpStream},
+        time::Duration,
+    };
+
+    fn unsupported(option: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} is not supported on this platform", option),
+        )
+    }
+
+    pub(super) fn set_keepalive(_: &TcpStream, _: Duration) -> io::Result<()> {
+        Err(unsupported("TCP keep-alive"))
+    }
+
+    pub(super) fn set_send_buffer_size(_: &TcpStream, _: usize) -> io::Result<()> {
+        Err(unsupported("send buffer size"))
+    }
+
+    pub(super) fn set_recv_buffer_size(_: &TcpStream, _: usize) -> io::Result<()> {
+        Err(unsupported("receive buffer size"))
+    }
+
+    pub(super) fn connect_from(_: &SocketAddr, _: &SocketAddr) -> io::Result<TcpStream> {
+        Err(unsupported("binding to a local address"))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn connect_with_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = TcpConfig::new()
+            .keepalive(Duration::from_secs(60))
+            .send_buffer_size(64 * 1024)
+            .recv_buffer_size(64 * 1024)
+            .bind_addr("127.0.0.1:0".parse().unwrap());
+
+        let stream = config.connect(listener.local_addr().unwrap()).unwrap();
+        let (_, peer_addr) = listener.accept().unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(stream.local_addr().unwrap(), peer_addr);
+    }
+
+    #[test]
+    fn bind_to_unavailable_address_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // documentation range, not assigned to any interface
+        let config = TcpConfig::new().bind_addr("192.0.2.1:0".parse().unwrap());
+        assert!(config.connect(listener.local_addr().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! In-process link simulating network conditions.
+//!
+//! Like [`MemoryTransport`](../struct.MemoryTransport.html), both ends live in the same process,
+//! but frames are delivered after a configurable latency and may be reordered, so that client and
+//! server state machines can be tested end to end against a realistic link without sockets.
+//!
+//! Random decisions come from a seeded generator: a given configuration always gives the same delays.
+
+use super::Transport;
+use crate::{
+    error::{ProtoError, ProtoErrorResultExt},
+    serialization::DecodeLimits,
+};
+use alloc::collections::BinaryHeap;
+use core::cmp::Reverse;
+use std::{io, time::Duration};
+use tokio::{sync::mpsc, time::Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoopbackConfig {
+    /// Delay of every frame.
+    pub latency: Duration,
+    /// Maximum random delay added to the latency. Frames may be reordered when it exceeds the time between them.
+    pub jitter: Duration,
+    /// Probability (between 0 and 1) of a frame being held back by `reorder_delay`, letting the next ones overtake it.
+    pub reorder_probability: f64,
+    pub reorder_delay: Duration,
+    pub seed: u64,
+}
+
+impl Default for LoopbackConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+            reorder_probability: 0.0,
+            reorder_delay: Duration::from_millis(10),
+            seed: 0x5eed,
+        }
+    }
+}
+
+impl LoopbackConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn latency(self, latency: Duration) -> Self {
+        Self { latency, ..self }
+    }
+
+    pub fn jitter(self, jitter: Duration) -> Self {
+        Self { jitter, ..self }
+    }
+
+    pub fn reordering(self, probability: f64, delay: Duration) -> Self {
+        Self {
+            reorder_probability: probability.clamp(0.0, 1.0),
+            reorder_delay: delay,
+            ..self
+        }
+    }
+
+    pub fn seed(self, seed: u64) -> Self {
+        Self { seed, ..self }
+    }
+}
+
+/// Both ends of a link without delay nor reordering.
+pub fn pair() -> (LoopbackTransport, LoopbackTransport) {
+    pair_with(LoopbackConfig::default())
+}
+
+/// Both ends of a link with the same conditions in each direction.
+pub fn pair_with(config: LoopbackConfig) -> (LoopbackTransport, LoopbackTransport) {
+    let (a_tx, a_rx) = mpsc::unbounded_channel();
+    let (b_tx, b_rx) = mpsc::unbounded_channel();
+    let reverse_config = LoopbackConfig {
+        seed: config.seed.rotate_left(32) ^ 0x9e37_79b9_7f4a_7c15,
+        ..config.clone()
+    };
+    (
+        LoopbackTransport::new(config, a_tx, b_rx),
+        LoopbackTransport::new(reverse_config, b_tx, a_rx),
+    )
+}
+
+type DelayedFrame = Reverse<(Instant, u64, Vec<u8>)>;
+
+pub struct LoopbackTransport {
+    config: LoopbackConfig,
+    rng_state: u64,
+    next_sequence: u64,
+    outgoing: Option<mpsc::UnboundedSender<DelayedFrame>>,
+    incoming: mpsc::UnboundedReceiver<DelayedFrame>,
+    incoming_closed: bool,
+    /// Frames received, ordered by delivery time.
+    pending: BinaryHeap<DelayedFrame>,
+    frame: Vec<u8>,
+}
+
+impl LoopbackTransport {
+    fn new(
+        config: LoopbackConfig,
+        outgoing: mpsc::UnboundedSender<DelayedFrame>,
+        incoming: mpsc::UnboundedReceiver<DelayedFrame>,
+    ) -> Self {
+        Self {
+            rng_state: config.seed.max(1),
+            config,
+            next_sequence: 0,
+            outgoing: Some(outgoing),
+            incoming,
+            incoming_closed: false,
+            pending: BinaryHeap::new(),
+            frame: Vec::new(),
+        }
+    }
+
+    fn delay(&mut self) -> Duration {
+        let mut delay = self.config.latency;
+        if self.config.jitter > Duration::from_secs(0) {
+            delay += self.config.jitter.mul_f64(self.next_random());
+        }
+        if self.config.reorder_probability > 0.0 && self.next_random() < self.config.reorder_probability {
+            delay += self.config.reorder_delay;
+        }
+        delay
+    }
+
+    /// Uniform in `[0, 1)` (xorshift64*).
+    fn next_random(&mut self) -> f64 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        let value = self.rng_state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (value >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Transport for LoopbackTransport {
+    /// Frames are received whole: message size is only checked when decoding the packet.
+    async fn read_frame(&mut self, _: DecodeLimits) -> Result<bool, ProtoError> {
+        loop {
+            while let Ok(frame) = self.incoming.try_recv() {
+                self.pending.push(frame);
+            }
+
+            let next_delivery = self.pending.peek().map(|Reverse((deliver_at, _, _))| *deliver_at);
+            match next_delivery {
+                Some(deliver_at) if deliver_at <= Instant::now() => {
+                    let Reverse((_, _, frame)) = self.pending.pop().expect("peeked above");
+                    self.frame = frame;
+                    return Ok(true);
+                }
+                Some(deliver_at) if self.incoming_closed => tokio::time::sleep_until(deliver_at).await,
+                Some(deliver_at) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deliver_at) => {}
+                        frame = self.incoming.recv() => match frame {
+                            Some(frame) => self.pending.push(frame),
+                            None => self.incoming_closed = true,
+                        },
+                    }
+                }
+                None if self.incoming_closed => return Ok(false),
+                None => match self.incoming.recv().await {
+                    Some(frame) => self.pending.push(frame),
+                    None => self.incoming_closed = true,
+                },
+            }
+        }
+    }
+
+    fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), ProtoError> {
+        let deliver_at = Instant::now() + self.delay();
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let sent = match &self.outgoing {
+            Some(outgoing) => outgoing.send(Reverse((deliver_at, sequence, frame.to_vec()))).is_ok(),
+            None => false,
+        };
+
+        if sent {
+            Ok(())
+        } else {
+            Err(ProtoError::from(io::Error::from(io::ErrorKind::BrokenPipe))).or_desc("loopback transport is closed")
+        }
+    }
+
+    /// Frames already sent are still delivered to the peer.
+    async fn close(&mut self) -> Result<(), ProtoError> {
+        self.outgoing = None;
+        Ok(())
+    }
+}
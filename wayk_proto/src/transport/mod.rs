@@ -0,0 +1,719 @@
+//! Async transport carrying packets over a link.
+//!
+//! [`NowTransport`] drives sharees over any [`Transport`]: a stream framed by packet headers
+//! ([`StreamTransport`], typically over a `tokio::net::TcpStream` connected by the caller),
+//! an in-process channel ([`MemoryTransport`], or [`loopback`] to simulate latency and reordering)
+//! or a custom link implementing the trait.
+//!
+//! With the `tls` feature, stream transports are upgraded to TLS by the connection sequence (see [`tls`]).
+//!
+//! With the `compression` feature, compressed packets are decompressed on reception and outgoing
+//! packets are compressed once compression is negotiated by the connection sequence.
+
+pub mod loopback;
+mod memory;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod reconnect;
+mod sender;
+mod stream;
+mod timeout;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+pub use memory::MemoryTransport;
+pub use sender::FramedSender;
+pub use stream::{FrameBatching, StreamTransport};
+pub use timeout::{CancellationToken, TransportTimeouts};
+
+#[cfg(feature = "compression")]
+use crate::compression::{ChannelCompression, FrameCompression};
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    header::{AbstractNowHeader, NowHeader},
+    message::{
+        BodyType, ChannelName, DisconnectStatusCode, NowBody, NowMessage, NowStatus, NowTerminateMsg, VirtChannelsCtx,
+    },
+    packet::NowPacket,
+    serialization::{Decode, DecodeLimits, Encode},
+    sharee::{Sharee, ShareeCallbackTrait, ShareeState},
+    sm::{
+        ChannelRateLimiterRc, ConnectionSM, Heartbeat, HeartbeatEvent, SessionMetrics, StepWatchdog, StepWatchdogEvent,
+    },
+};
+#[cfg(feature = "compression")]
+use alloc::borrow::Cow;
+use core::future::Future;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Link carrying frames, each frame being a whole packet (header included).
+pub trait Transport {
+    /// Waits for the next frame, then returned by [`frame`](#tymethod.frame).
+    /// Returns `false` once the link is closed between two frames.
+    ///
+    /// Must be cancel safe: a frame partially received when the future is dropped is completed by the next call.
+    /// Frames whose body exceeds `limits` may be rejected before being received entirely.
+    fn read_frame(&mut self, limits: DecodeLimits) -> impl Future<Output = Result<bool, ProtoError>>;
+
+    /// Last frame read.
+    fn frame(&self) -> &[u8];
+
+    /// Sends a frame. It is flushed to the link before returning, unless the transport batches
+    /// frames: it is then written by [`flush`](#method.flush) at the latest.
+    fn write_frame(&mut self, frame: &[u8]) -> impl Future<Output = Result<(), ProtoError>>;
+
+    /// Writes the frames held back by a batching transport.
+    fn flush(&mut self) -> impl Future<Output = Result<(), ProtoError>> {
+        async { Ok(()) }
+    }
+
+    /// Time by which frames held back must be flushed, if any.
+    fn flush_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    fn close(&mut self) -> impl Future<Output = Result<(), ProtoError>>;
+}
+
+#[derive(Debug)]
+pub enum TransportEvent<'a> {
+    Packet(NowPacket<'a>),
+    /// Nothing was received from the peer for the given duration (see `Heartbeat`).
+    PeerSilent(Duration),
+    /// The link was closed between two packets.
+    Closed,
+}
+
+/// How a [`shutdown`](struct.NowTransport.html#method.shutdown) ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShutdownOutcome {
+    /// The peer acknowledged the termination with its own terminate message.
+    Acknowledged(NowStatus<DisconnectStatusCode>),
+    /// The peer closed the link without acknowledging.
+    Closed,
+    /// Nothing acknowledged the termination in time.
+    TimedOut,
+}
+
+pub struct NowTransport<T> {
+    transport: T,
+    channels_ctx: VirtChannelsCtx,
+    rate_limiter: Option<ChannelRateLimiterRc>,
+    metrics: Option<SessionMetrics>,
+    timeouts: TransportTimeouts,
+    cancellation_token: Option<CancellationToken>,
+    #[cfg(feature = "compression")]
+    compression: FrameCompression,
+    #[cfg(feature = "compression")]
+    compressing: bool,
+    #[cfg(feature = "compression")]
+    channel_compression: ChannelCompression,
+    /// Last frame read, if it was decompressed.
+    #[cfg(feature = "compression")]
+    decompressed: Option<Vec<u8>>,
+}
+
+impl<S> NowTransport<StreamTransport<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S) -> Self {
+        Self::with_transport(StreamTransport::new(stream))
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.transport.get_ref()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.transport.into_inner()
+    }
+
+    /// Replaces the stream by a wrapper of it, typically a TLS stream from the caller's TLS library
+    /// once security is negotiated.
+    ///
+    /// Packets are read one at a time, so no received byte is lost by the upgrade.
+    /// Verifying the peer certificate is up to `upgrade`.
+    pub async fn upgrade<U, F, Fut>(self, upgrade: F) -> Result<NowTransport<StreamTransport<U>>, ProtoError>
+    where
+        U: AsyncRead + AsyncWrite + Unpin,
+        F: FnOnce(S) -> Fut,
+        Fut: Future<Output = std::io::Result<U>>,
+    {
+        Ok(NowTransport {
+            transport: self.transport.upgrade(upgrade).await?,
+            channels_ctx: self.channels_ctx,
+            rate_limiter: self.rate_limiter,
+            metrics: self.metrics,
+            timeouts: self.timeouts,
+            cancellation_token: self.cancellation_token,
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+            #[cfg(feature = "compression")]
+            compressing: self.compressing,
+            #[cfg(feature = "compression")]
+            channel_compression: self.channel_compression,
+            #[cfg(feature = "compression")]
+            decompressed: self.decompressed,
+        })
+    }
+}
+
+impl<T> NowTransport<T>
+where
+    T: Transport,
+{
+    pub fn with_transport(transport: T) -> Self {
+        Self {
+            transport,
+            channels_ctx: VirtChannelsCtx::new(),
+            rate_limiter: None,
+            metrics: None,
+            timeouts: TransportTimeouts::default(),
+            cancellation_token: None,
+            #[cfg(feature = "compression")]
+            compression: FrameCompression::new(),
+            #[cfg(feature = "compression")]
+            compressing: false,
+            #[cfg(feature = "compression")]
+            channel_compression: ChannelCompression::new(),
+            #[cfg(feature = "compression")]
+            decompressed: None,
+        }
+    }
+
+    /// Throttles outgoing frames. The limiter is shared so that limits can be adjusted while the transport runs.
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<ChannelRateLimiterRc>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    /// Records the traffic of the transport into `metrics`.
+    pub fn set_metrics(&mut self, metrics: Option<SessionMetrics>) {
+        self.metrics = metrics;
+    }
+
+    pub fn get_metrics(&self) -> Option<&SessionMetrics> {
+        self.metrics.as_ref()
+    }
+
+    pub fn get_timeouts(&self) -> TransportTimeouts {
+        self.timeouts
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: TransportTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Aborts connection, reads and writes once `cancellation_token` is cancelled.
+    pub fn set_cancellation_token(&mut self, cancellation_token: Option<CancellationToken>) {
+        self.cancellation_token = cancellation_token;
+    }
+
+    #[cfg(feature = "compression")]
+    /// Compression applied to outgoing packets once negotiated.
+    pub fn set_compression(&mut self, compression: FrameCompression) {
+        self.compression = compression;
+    }
+
+    #[cfg(feature = "compression")]
+    /// Whether outgoing packets are compressed. Set by [`connect`](#method.connect) from the negotiated capabilities.
+    pub fn is_compressing(&self) -> bool {
+        self.compressing
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn set_compressing(&mut self, compressing: bool) {
+        self.compressing = compressing;
+    }
+
+    #[cfg(feature = "compression")]
+    /// Compression of individual channels. Negotiated by [`connect`](#method.connect), its capset is to be
+    /// part of the capabilities of the connection sequence.
+    pub fn get_channel_compression(&self) -> &ChannelCompression {
+        &self.channel_compression
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn set_channel_compression(&mut self, channel_compression: ChannelCompression) {
+        self.channel_compression = channel_compression;
+    }
+
+    /// Channels used to decode virtual channel packets. Set by [`connect`](#method.connect).
+    pub fn get_channels_ctx(&self) -> &VirtChannelsCtx {
+        &self.channels_ctx
+    }
+
+    pub fn set_channels_ctx(&mut self, channels_ctx: VirtChannelsCtx) {
+        self.channels_ctx = channels_ctx;
+    }
+
+    pub fn get_transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    pub async fn send<'a, P: Into<NowPacket<'a>>>(&mut self, packet: P) -> Result<(), ProtoError> {
+        self.write_frame(&packet.into().encode()?).await
+    }
+
+    /// Next packet received. Returns `None` once the link is closed between two packets.
+    ///
+    /// Fails once the idle timeout is reached.
+    pub async fn recv(&mut self) -> Result<Option<NowPacket<'_>>, ProtoError> {
+        let limits = self.channels_ctx.decode_limits();
+        if self.read_frame(limits, self.timeouts.idle).await? {
+            self.on_frame_read(limits)?;
+            decode_frame(self.frame(), &self.channels_ctx).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Next packet received, sending keep-alive requests of `heartbeat` while waiting for it.
+    ///
+    /// Keep-alive requests from the peer are answered before the packet is returned.
+    /// A silent peer is reported once: the connection is left to the caller to close or keep waiting on.
+    pub async fn recv_with_heartbeat(&mut self, heartbeat: &mut Heartbeat) -> Result<TransportEvent<'_>, ProtoError> {
+        loop {
+            while let Some(event) = heartbeat.poll(Instant::now()) {
+                match event {
+                    HeartbeatEvent::Send(msg) => self.send(NowMessage::Network(msg)).await?,
+                    HeartbeatEvent::PeerSilent(silence) => return Ok(TransportEvent::PeerSilent(silence)),
+                }
+            }
+
+            let deadline = tokio::time::Instant::from_std(heartbeat.next_deadline());
+            let limits = self.channels_ctx.decode_limits();
+            let cancellation_token = self.cancellation_token.clone();
+            tokio::select! {
+                received = timeout::guard(
+                    self.__read_frame(limits),
+                    None,
+                    cancellation_token.as_ref(),
+                    "couldn't receive packet",
+                ) => {
+                    if !received? {
+                        return Ok(TransportEvent::Closed);
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline) => continue,
+            }
+            self.on_frame_read(limits)?;
+
+            // the packet borrows the frame: it is decoded again once the answer is sent
+            let rtt_samples = heartbeat.rtt().samples_count();
+            let answer = {
+                let packet = decode_frame(self.frame(), &self.channels_ctx)?;
+                heartbeat.on_body(&packet.body, Instant::now())
+            };
+            if let (Some(metrics), Some(rtt)) = (&self.metrics, heartbeat.rtt().latest()) {
+                if heartbeat.rtt().samples_count() > rtt_samples {
+                    metrics.on_rtt_sample(rtt, Instant::now());
+                }
+            }
+            if let Some(answer) = answer {
+                self.send(NowMessage::Network(answer)).await?;
+            }
+            return decode_frame(self.frame(), &self.channels_ctx).map(TransportEvent::Packet);
+        }
+    }
+
+    /// Writes the frames held back by a batching transport.
+    ///
+    /// Batched frames are otherwise written when the batch is full, by the next send past the maximum
+    /// delay, or while waiting for a packet once the maximum delay is reached.
+    pub async fn flush(&mut self) -> Result<(), ProtoError> {
+        timeout::guard(
+            self.transport.flush(),
+            None,
+            self.cancellation_token.as_ref(),
+            "couldn't send packet",
+        )
+        .await
+    }
+
+    /// Closes the link.
+    pub async fn close(&mut self) -> Result<(), ProtoError> {
+        self.transport.close().await
+    }
+
+    /// Terminates the session gracefully, then closes the link.
+    ///
+    /// Pending frames are flushed before the terminate message carrying `reason`. Packets still
+    /// in flight are dropped while waiting up to `ack_timeout` for the peer to answer with its own
+    /// terminate message, as done by [`run`](#method.run).
+    pub async fn shutdown(
+        &mut self,
+        reason: DisconnectStatusCode,
+        ack_timeout: Duration,
+    ) -> Result<ShutdownOutcome, ProtoError> {
+        self.flush().await?;
+        self.send(NowTerminateMsg::with_reason(reason)).await?;
+        self.flush().await?;
+
+        let deadline = tokio::time::Instant::now() + ack_timeout;
+        let cancellation_token = self.cancellation_token.clone();
+        let outcome = loop {
+            let limits = self.channels_ctx.decode_limits();
+            let read = timeout::guard(
+                self.__read_frame(limits),
+                None,
+                cancellation_token.as_ref(),
+                "couldn't receive terminate acknowledgement",
+            );
+            match tokio::time::timeout_at(deadline, read).await {
+                Ok(received) => {
+                    if !received? {
+                        break ShutdownOutcome::Closed;
+                    }
+                }
+                Err(_) => break ShutdownOutcome::TimedOut,
+            }
+            self.on_frame_read(limits)?;
+
+            if let NowBody::Message(NowMessage::Terminate(msg)) = decode_frame(self.frame(), &self.channels_ctx)?.body {
+                break ShutdownOutcome::Acknowledged(msg.status);
+            }
+        };
+
+        log::debug!("shutdown: {:?}", outcome);
+        self.transport.close().await?;
+        Ok(outcome)
+    }
+
+    /// Runs the connection sequence of `sharee`, within the connect timeout.
+    ///
+    /// The channels opened during the sequence are used to decode the following packets.
+    pub async fn connect<ConnectionSeq, UserCallback>(
+        &mut self,
+        sharee: &mut Sharee<ConnectionSeq, UserCallback>,
+    ) -> Result<(), ProtoError>
+    where
+        ConnectionSeq: ConnectionSM,
+        UserCallback: ShareeCallbackTrait,
+    {
+        let TransportTimeouts {
+            connect, read, steps, ..
+        } = self.timeouts;
+        let sequence = self.drive(sharee, read, steps.map(StepWatchdog::new), |sharee| {
+            sharee.get_state() == ShareeState::Connection
+        });
+        let result = timeout::guard(sequence, connect, None, "connection sequence timed out").await;
+        if let Err(err) = &result {
+            sharee.on_error(err);
+        }
+        result?;
+        self.channels_ctx = sharee.get_channels_ctx().clone();
+
+        #[cfg(feature = "compression")]
+        {
+            if let Some(shared_data) = sharee.get_connection_seq().get_shared_data() {
+                let shared_data = shared_data.borrow();
+                self.compressing =
+                    crate::compression::is_negotiated(&shared_data.capabilities, &shared_data.peer_capabilities);
+                self.channel_compression
+                    .negotiate(&shared_data.capabilities, &shared_data.peer_capabilities);
+            }
+        }
+
+        match sharee.get_state() {
+            ShareeState::Active => Ok(()),
+            ShareeState::Connection => Err(ProtoError::from(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
+            )))
+            .or_desc("link closed during connection sequence"),
+            ShareeState::Interrupted => {
+                ProtoError::new(ProtoErrorKind::Sharee(sharee.get_state())).or_desc("connection sequence interrupted")
+            }
+            ShareeState::Final => {
+                ProtoError::new(ProtoErrorKind::Sharee(sharee.get_state())).or_desc("connection sequence failed")
+            }
+        }
+    }
+
+    /// Updates `sharee` with the received packets until it terminates, the link is closed
+    /// or the idle timeout is reached.
+    ///
+    /// A termination by the peer is acknowledged before returning.
+    pub async fn run<ConnectionSeq, UserCallback>(
+        &mut self,
+        sharee: &mut Sharee<ConnectionSeq, UserCallback>,
+    ) -> Result<(), ProtoError>
+    where
+        ConnectionSeq: ConnectionSM,
+        UserCallback: ShareeCallbackTrait,
+    {
+        let idle = self.timeouts.idle;
+        if let Err(err) = self.drive(sharee, idle, None, Sharee::is_running).await {
+            sharee.on_error(&err);
+            return Err(err);
+        }
+
+        if sharee.is_terminated() {
+            // terminate message in final state
+            if let Some(ack) = sharee.update_without_body(Instant::now())? {
+                // the peer may close the link without waiting for the acknowledgement
+                let acknowledged = match self.send(ack).await {
+                    Ok(()) => self.flush().await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = acknowledged {
+                    log::debug!("couldn't acknowledge termination: {}", err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates `sharee` while `running`. Steps of its connection sequence stalling past the
+    /// timeouts of `watchdog` are retried by sending the last frame again.
+    async fn drive<ConnectionSeq, UserCallback, F>(
+        &mut self,
+        sharee: &mut Sharee<ConnectionSeq, UserCallback>,
+        read_timeout: Option<Duration>,
+        mut watchdog: Option<StepWatchdog>,
+        running: F,
+    ) -> Result<(), ProtoError>
+    where
+        ConnectionSeq: ConnectionSM,
+        UserCallback: ShareeCallbackTrait,
+        F: Fn(&Sharee<ConnectionSeq, UserCallback>) -> bool,
+    {
+        let mut last_sent = None;
+        while running(sharee) {
+            let now = Instant::now();
+            if !sharee.waiting_for_packet(now) {
+                if let Some(answer) = sharee.update_without_body(now)? {
+                    let frame = answer.encode()?;
+                    self.write_frame(&frame).await?;
+                    if watchdog.is_some() {
+                        last_sent = Some(frame);
+                    }
+                }
+                continue;
+            }
+
+            let watchdog_deadline = match &mut watchdog {
+                Some(watchdog) => {
+                    if let Some(step) = sharee.get_connection_seq().current_step() {
+                        watchdog.on_step(step, Instant::now());
+                    }
+                    watchdog.next_deadline()
+                }
+                None => None,
+            };
+            let deadline = match (watchdog_deadline, sharee.next_deadline()) {
+                (Some(watchdog_deadline), Some(sharee_deadline)) => Some(watchdog_deadline.min(sharee_deadline)),
+                (watchdog_deadline, sharee_deadline) => watchdog_deadline.or(sharee_deadline),
+            };
+
+            let limits = sharee.get_channels_ctx().decode_limits();
+            let received = match deadline {
+                Some(deadline) => tokio::select! {
+                    received = self.read_frame(limits, read_timeout) => Some(received?),
+                    _ = tokio::time::sleep_until(tokio::time::Instant::from_std(deadline)) => None,
+                },
+                None => Some(self.read_frame(limits, read_timeout).await?),
+            };
+            match received {
+                Some(true) => {}
+                Some(false) => return Ok(()),
+                None => {
+                    match watchdog.as_mut().and_then(|watchdog| watchdog.poll(Instant::now())) {
+                        Some(StepWatchdogEvent::Retry { step, attempt }) => {
+                            log::warn!("{:?} step stalled, retrying (attempt {})", step, attempt);
+                            if let Some(frame) = last_sent.take() {
+                                self.write_frame(&frame).await?;
+                                last_sent = Some(frame);
+                            }
+                        }
+                        Some(StepWatchdogEvent::Stalled(step)) => {
+                            return Err(ProtoError::from(std::io::Error::from(std::io::ErrorKind::TimedOut)))
+                                .chain(ProtoErrorKind::ConnectionSequence(step))
+                                .or_desc("step stalled: no answer from the peer");
+                        }
+                        None => {}
+                    }
+                    continue;
+                }
+            }
+            self.on_frame_read(limits)?;
+
+            // the answer may borrow the received packet: it is encoded before writing
+            let packet = decode_frame(self.frame(), sharee.get_channels_ctx())?;
+            let channels_updated = matches!(packet.body, NowBody::Message(NowMessage::Channel(_)));
+            let answer = match sharee.update_with_body(&packet.body, Instant::now())? {
+                Some(answer) => Some(answer.encode()?),
+                None => None,
+            };
+
+            if channels_updated {
+                // messages of channels opened since are compressed and counted as well
+                self.channels_ctx = sharee.get_channels_ctx().clone();
+            }
+
+            let answer = match answer {
+                Some(answer) => answer,
+                None => continue,
+            };
+            self.write_frame(&answer).await?;
+            if watchdog.is_some() {
+                last_sent = Some(answer);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads next frame. The link is closed if nothing is received within `timeout`.
+    async fn read_frame(&mut self, limits: DecodeLimits, timeout: Option<Duration>) -> Result<bool, ProtoError> {
+        let cancellation_token = self.cancellation_token.clone();
+        let result = timeout::guard(
+            self.__read_frame(limits),
+            timeout,
+            cancellation_token.as_ref(),
+            "couldn't receive packet",
+        )
+        .await;
+
+        if let Err(err) = &result {
+            if matches!(&err.kind, ProtoErrorKind::Io(err) if err.kind() == std::io::ErrorKind::TimedOut) {
+                log::warn!("nothing received for {:?}, closing link", timeout.unwrap_or_default());
+                let _ = self.transport.close().await;
+            }
+        }
+
+        result
+    }
+
+    /// Reads next frame, flushing batched frames meanwhile once their deadline is reached.
+    async fn __read_frame(&mut self, limits: DecodeLimits) -> Result<bool, ProtoError> {
+        loop {
+            let deadline = match self.transport.flush_deadline() {
+                Some(deadline) => tokio::time::Instant::from_std(deadline),
+                None => return self.transport.read_frame(limits).await,
+            };
+
+            let received = tokio::select! {
+                received = self.transport.read_frame(limits) => Some(received),
+                _ = tokio::time::sleep_until(deadline) => None,
+            };
+            match received {
+                Some(received) => return received,
+                None => self.transport.flush().await?,
+            }
+        }
+    }
+
+    /// Last frame read, decompressed.
+    fn frame(&self) -> &[u8] {
+        #[cfg(feature = "compression")]
+        {
+            if let Some(frame) = &self.decompressed {
+                return frame;
+            }
+        }
+
+        self.transport.frame()
+    }
+
+    #[allow(unused_variables)]
+    fn on_frame_read(&mut self, limits: DecodeLimits) -> Result<(), ProtoError> {
+        #[cfg(feature = "compression")]
+        {
+            self.decompressed = match FrameCompression::decompress_frame(self.transport.frame(), limits)? {
+                Cow::Owned(frame) => Some(frame),
+                Cow::Borrowed(_) => None,
+            };
+            let frame = self.decompressed.as_deref().unwrap_or_else(|| self.transport.frame());
+            if let Cow::Owned(frame) = self
+                .channel_compression
+                .decompress_frame(frame, &self.channels_ctx, limits)?
+            {
+                self.decompressed = Some(frame);
+            }
+        }
+
+        if let Some(metrics) = &self.metrics {
+            let channel = self.frame_channel(self.frame())?;
+            metrics.on_received(channel.as_ref(), self.transport.frame().len(), Instant::now());
+        }
+
+        Ok(())
+    }
+
+    /// Virtual channel carrying `frame`, if any.
+    fn frame_channel(&self, frame: &[u8]) -> Result<Option<ChannelName>, ProtoError> {
+        Ok(match NowHeader::decode(frame)?.body_type() {
+            BodyType::VirtualChannel(id) => self.channels_ctx.get_channel_by_id(id).cloned(),
+            BodyType::Message(_) => None,
+        })
+    }
+
+    pub(crate) async fn write_frame(&mut self, frame: &[u8]) -> Result<(), ProtoError> {
+        #[cfg(feature = "compression")]
+        {
+            let frame = self.channel_compression.compress_frame(frame, &self.channels_ctx)?;
+            if self.compressing {
+                let frame = self.compression.compress_frame(&frame)?;
+                return self.__write_frame(&frame).await;
+            }
+            if let Cow::Owned(frame) = frame {
+                return self.__write_frame(&frame).await;
+            }
+        }
+
+        self.__write_frame(frame).await
+    }
+
+    async fn __write_frame(&mut self, frame: &[u8]) -> Result<(), ProtoError> {
+        let channel = if self.rate_limiter.is_some() || self.metrics.is_some() {
+            self.frame_channel(frame)?
+        } else {
+            None
+        };
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            // limits may change while waiting
+            loop {
+                let wait_time = rate_limiter
+                    .borrow_mut()
+                    .wait_time(channel.as_ref(), frame.len(), Instant::now());
+                if wait_time == Duration::from_secs(0) {
+                    break;
+                }
+                tokio::time::sleep(wait_time).await;
+            }
+            rate_limiter.borrow_mut().consume(channel.as_ref(), frame.len());
+        }
+
+        timeout::guard(
+            self.transport.write_frame(frame),
+            None,
+            self.cancellation_token.as_ref(),
+            "couldn't send packet",
+        )
+        .await?;
+
+        if let Some(metrics) = &self.metrics {
+            metrics.on_sent(channel.as_ref(), frame.len(), Instant::now());
+        }
+
+        Ok(())
+    }
+}
+
+fn decode_frame<'a>(frame: &'a [u8], channels_ctx: &VirtChannelsCtx) -> Result<NowPacket<'a>, ProtoError> {
+    let header = NowHeader::decode(frame)?;
+    let body = frame
+        .get(header.len()..header.len() + header.body_len())
+        .chain(ProtoErrorKind::Decoding(stringify!(NowPacket)))
+        .or_else_desc(|| format!("frame shorter ({}) than its packet", frame.len()))?;
+    NowPacket::decode_from(header, body, channels_ctx)
+}
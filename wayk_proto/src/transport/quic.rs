@@ -0,0 +1,253 @@
+//! Experimental transport over QUIC streams, built on quinn.
+//!
+//! Packets are spread over several streams of the same QUIC connection according to their
+//! [`TrafficClass`](../../send_queue/enum.TrafficClass.html): the control stream carries the
+//! connection sequence and session messages, while input and virtual channels may be given
+//! their own streams. Retransmissions on a bulk stream then don't delay input or surface updates.
+//!
+//! The QUIC connection is established by the caller with a quinn `Endpoint`. The client then opens
+//! the streams with [`QuicTransport::open`](struct.QuicTransport.html#method.open) and the server
+//! accepts them with [`QuicTransport::accept`](struct.QuicTransport.html#method.accept), each stream
+//! starting with the class it carries. Packets of a class without a dedicated stream go over the
+//! control stream.
+//!
+//! Ordering is only kept between packets of the same stream.
+
+use super::{StreamTransport, Transport};
+use crate::{
+    error::{ProtoError, ProtoErrorResultExt},
+    header::{AbstractNowHeader, NowHeader},
+    send_queue::TrafficClass,
+    serialization::{Decode, DecodeLimits},
+};
+use alloc::collections::BTreeMap;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Byte starting each stream opened by [`QuicTransport::open`](struct.QuicTransport.html#method.open).
+fn class_id(class: TrafficClass) -> u8 {
+    match class {
+        TrafficClass::Realtime => 0,
+        TrafficClass::Interactive => 1,
+        TrafficClass::Bulk => 2,
+    }
+}
+
+/// Receiving and sending halves of a bidirectional stream.
+pub struct BiStream<R, W> {
+    pub recv: R,
+    pub send: W,
+}
+
+impl<R, W> BiStream<R, W> {
+    pub fn new(recv: R, send: W) -> Self {
+        Self { recv, send }
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for BiStream<R, W> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for BiStream<R, W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+struct Lane<R, W> {
+    transport: StreamTransport<BiStream<R, W>>,
+    /// The peer finished the stream.
+    finished: bool,
+}
+
+/// Packets over several QUIC streams, one per traffic class.
+pub struct QuicTransport<R, W> {
+    lanes: BTreeMap<TrafficClass, Lane<R, W>>,
+    last_read: TrafficClass,
+}
+
+impl<R, W> QuicTransport<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Control stream, carrying the packets of classes without a dedicated stream.
+    pub const CONTROL: TrafficClass = TrafficClass::Interactive;
+
+    /// Transport over the control stream, typically the first bidirectional stream opened.
+    pub fn new(recv: R, send: W) -> Self {
+        Self {
+            lanes: BTreeMap::new(),
+            last_read: Self::CONTROL,
+        }
+        .lane(Self::CONTROL, recv, send)
+    }
+
+    /// Dedicated stream for packets of `class`. Both peers must use the same mapping.
+    pub fn lane(mut self, class: TrafficClass, recv: R, send: W) -> Self {
+        self.lanes.insert(
+            class,
+            Lane {
+                transport: StreamTransport::new(BiStream::new(recv, send)),
+                finished: false,
+            },
+        );
+        self
+    }
+
+    pub fn has_lane(&self, class: TrafficClass) -> bool {
+        self.lanes.contains_key(&class)
+    }
+
+    fn lane_of(&self, frame: &[u8]) -> Result<TrafficClass, ProtoError> {
+        let class = TrafficClass::of_body_type(NowHeader::decode(frame)?.body_type());
+        if self.lanes.contains_key(&class) {
+            Ok(class)
+        } else {
+            Ok(Self::CONTROL)
+        }
+    }
+}
+
+impl QuicTransport<quinn::RecvStream, quinn::SendStream> {
+    /// Opens the control stream, then a dedicated stream for each of `lanes`, over a connection
+    /// established by the client.
+    pub async fn open(connection: &quinn::Connection, lanes: &[TrafficClass]) -> Result<Self, ProtoError> {
+        let (send, recv) = Self::__open_stream(connection, Self::CONTROL).await?;
+        let mut transport = Self::new(recv, send);
+        for class in lanes.iter().filter(|class| **class != Self::CONTROL) {
+            let (send, recv) = Self::__open_stream(connection, *class).await?;
+            transport = transport.lane(*class, recv, send);
+        }
+        Ok(transport)
+    }
+
+    /// Accepts the streams opened by the peer with [`open`](#method.open), `lanes` being the same on both sides.
+    ///
+    /// Completes once the peer sent something on each stream, the control stream being written first
+    /// by the connection sequence.
+    pub async fn accept(connection: &quinn::Connection, lanes: &[TrafficClass]) -> Result<Self, ProtoError> {
+        let mut expected: Vec<_> = lanes.iter().copied().filter(|class| *class != Self::CONTROL).collect();
+        expected.push(Self::CONTROL);
+
+        let mut streams = BTreeMap::new();
+        while streams.len() < expected.len() {
+            let (send, mut recv) = connection.accept_bi().await.map_err(io::Error::from)?;
+            let mut id = [0];
+            recv.read_exact(&mut id).await.map_err(io::Error::other)?;
+            match expected.iter().find(|class| class_id(**class) == id[0]) {
+                Some(class) if !streams.contains_key(class) => {
+                    streams.insert(*class, (recv, send));
+                }
+                _ => {
+                    return Err(ProtoError::from(io::Error::from(io::ErrorKind::InvalidData)))
+                        .or_else_desc(|| format!("unexpected QUIC stream for class {}", id[0]));
+                }
+            }
+        }
+
+        let (recv, send) = streams.remove(&Self::CONTROL).expect("control stream accepted");
+        Ok(streams
+            .into_iter()
+            .fold(Self::new(recv, send), |transport, (class, (recv, send))| {
+                transport.lane(class, recv, send)
+            }))
+    }
+
+    async fn __open_stream(
+        connection: &quinn::Connection,
+        class: TrafficClass,
+    ) -> Result<(quinn::SendStream, quinn::RecvStream), ProtoError> {
+        let (mut send, recv) = connection.open_bi().await.map_err(io::Error::from)?;
+        // the peer is notified of the stream once something is sent over it
+        send.write_all(&[class_id(class)]).await.map_err(io::Error::from)?;
+        Ok((send, recv))
+    }
+}
+
+impl<R, W> Transport for QuicTransport<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Waits for a frame on any stream, higher priority classes first.
+    ///
+    /// Returns `false` once the control stream is finished.
+    async fn read_frame(&mut self, limits: DecodeLimits) -> Result<bool, ProtoError> {
+        loop {
+            let (class, received) = {
+                // reads of the other streams are cancel safe, and resumed by the next call
+                let mut reads: Vec<_> = self
+                    .lanes
+                    .iter_mut()
+                    .filter(|(_, lane)| !lane.finished)
+                    .map(|(class, lane)| {
+                        let class = *class;
+                        Box::pin(async move { (class, lane.transport.read_frame(limits).await) })
+                    })
+                    .collect();
+
+                core::future::poll_fn(|cx| {
+                    for read in reads.iter_mut() {
+                        if let Poll::Ready(result) = read.as_mut().poll(cx) {
+                            return Poll::Ready(result);
+                        }
+                    }
+                    Poll::Pending
+                })
+                .await
+            };
+
+            if received? {
+                self.last_read = class;
+                return Ok(true);
+            }
+
+            if class == Self::CONTROL {
+                return Ok(false);
+            }
+            if let Some(lane) = self.lanes.get_mut(&class) {
+                lane.finished = true;
+            }
+        }
+    }
+
+    fn frame(&self) -> &[u8] {
+        self.lanes
+            .get(&self.last_read)
+            .map(|lane| lane.transport.frame())
+            .unwrap_or_default()
+    }
+
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), ProtoError> {
+        let class = self.lane_of(frame)?;
+        match self.lanes.get_mut(&class) {
+            Some(lane) => lane.transport.write_frame(frame).await,
+            None => Err(ProtoError::from(io::Error::from(io::ErrorKind::NotConnected))),
+        }
+    }
+
+    /// Finishes all streams.
+    async fn close(&mut self) -> Result<(), ProtoError> {
+        for lane in self.lanes.values_mut() {
+            lane.transport.close().await?;
+        }
+        Ok(())
+    }
+}
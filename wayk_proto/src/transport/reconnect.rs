@@ -0,0 +1,276 @@
+//! Transport reconnecting to the server when the connection is lost.
+//!
+//! On reconnection, the sharee runs a new connection sequence re-attaching to the session it was
+//! associated with. Virtual channels and user callbacks of the sharee are kept, so the session
+//! resumes without the caller rebuilding its state.
+
+use super::{timeout, CancellationToken, NowTransport, Transport, TransportTimeouts};
+use crate::{
+    error::{ProtoError, ProtoErrorKind},
+    sharee::{Sharee, ShareeCallbackTrait},
+    sm::{ConnectionSM, SessionMetrics},
+};
+use core::future::Future;
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+/// Exponential backoff between reconnection attempts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), Duration::from_secs(30))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            initial_delay,
+            max_delay,
+            max_attempts: None,
+        }
+    }
+
+    /// Gives up after `max_attempts` failed attempts in a row. Attempts are unlimited by default.
+    pub fn max_attempts(self, max_attempts: u32) -> Self {
+        Self {
+            max_attempts: Some(max_attempts),
+            ..self
+        }
+    }
+
+    /// Delay before the attempt following `failures` failed attempts in a row,
+    /// or `None` once attempts are exhausted.
+    pub fn delay(&self, failures: u32) -> Option<Duration> {
+        if let Some(max_attempts) = self.max_attempts {
+            if failures >= max_attempts {
+                return None;
+            }
+        }
+
+        if failures == 0 {
+            return Some(Duration::from_secs(0));
+        }
+
+        let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+        let delay = self.initial_delay.checked_mul(factor).unwrap_or(self.max_delay);
+        Some(delay.min(self.max_delay))
+    }
+}
+
+/// Wraps a [`NowTransport`](../struct.NowTransport.html) over the links returned by `connect`.
+///
+/// Lost connections (I/O errors or links closed before the sharee terminates) are reopened
+/// with an exponential backoff.
+pub struct ReconnectingTransport<T, Connect> {
+    transport: Option<NowTransport<T>>,
+    connect: Connect,
+    backoff: Backoff,
+    session_id: Option<u32>,
+    reconnections: u32,
+    metrics: Option<SessionMetrics>,
+    timeouts: TransportTimeouts,
+    cancellation_token: Option<CancellationToken>,
+}
+
+impl<T, Connect, ConnectFut> ReconnectingTransport<T, Connect>
+where
+    T: Transport,
+    Connect: FnMut() -> ConnectFut,
+    ConnectFut: Future<Output = io::Result<T>>,
+{
+    pub fn new(connect: Connect) -> Self {
+        Self {
+            transport: None,
+            connect,
+            backoff: Backoff::default(),
+            session_id: None,
+            reconnections: 0,
+            metrics: None,
+            timeouts: TransportTimeouts::default(),
+            cancellation_token: None,
+        }
+    }
+
+    pub fn backoff(self, backoff: Backoff) -> Self {
+        Self { backoff, ..self }
+    }
+
+    /// Metrics given to each connection, counting reconnections too.
+    pub fn metrics(self, metrics: SessionMetrics) -> Self {
+        Self {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    /// Timeouts of each connection. The connect timeout also bounds each call to `connect`.
+    ///
+    /// Connections timing out are reopened like lost connections.
+    pub fn timeouts(self, timeouts: TransportTimeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    /// Aborts connection attempts, waits between them and the current connection once cancelled.
+    pub fn cancellation_token(self, cancellation_token: CancellationToken) -> Self {
+        Self {
+            cancellation_token: Some(cancellation_token),
+            ..self
+        }
+    }
+
+    /// Current transport, if connected.
+    pub fn get_transport(&mut self) -> Option<&mut NowTransport<T>> {
+        self.transport.as_mut()
+    }
+
+    /// Session the sharee re-attaches to on reconnection.
+    pub fn session_id(&self) -> Option<u32> {
+        self.session_id
+    }
+
+    /// Number of times the connection was reopened after being lost.
+    pub fn reconnections(&self) -> u32 {
+        self.reconnections
+    }
+
+    /// Connects and updates `sharee` until it terminates, reconnecting whenever the connection is lost.
+    ///
+    /// `new_connection_seq` builds the connection sequence of each connection from the session
+    /// to re-attach to (`None` until a session is associated), typically with
+    /// `ClientConnectionSeqBuilder::reattach`. It replaces the sequence `sharee` was built with.
+    pub async fn run<ConnectionSeq, UserCallback, NewConnectionSeq>(
+        &mut self,
+        sharee: &mut Sharee<ConnectionSeq, UserCallback>,
+        mut new_connection_seq: NewConnectionSeq,
+    ) -> Result<(), ProtoError>
+    where
+        ConnectionSeq: ConnectionSM,
+        UserCallback: ShareeCallbackTrait,
+        NewConnectionSeq: FnMut(Option<u32>) -> ConnectionSeq,
+    {
+        loop {
+            let transport = match self.transport.as_mut() {
+                Some(transport) => transport,
+                None => self.__connect(sharee, &mut new_connection_seq).await?,
+            };
+
+            match transport.run(sharee).await {
+                Ok(()) if sharee.is_terminated() => return Ok(()),
+                Ok(()) => log::warn!("connection closed by server"),
+                Err(err) if is_connection_lost(&err) => log::warn!("connection lost: {}", err),
+                Err(err) => return Err(err),
+            }
+
+            self.transport = None;
+            self.reconnections += 1;
+            if let Some(metrics) = &self.metrics {
+                metrics.on_reconnection(Instant::now());
+            }
+        }
+    }
+
+    async fn __connect<ConnectionSeq, UserCallback, NewConnectionSeq>(
+        &mut self,
+        sharee: &mut Sharee<ConnectionSeq, UserCallback>,
+        new_connection_seq: &mut NewConnectionSeq,
+    ) -> Result<&mut NowTransport<T>, ProtoError>
+    where
+        ConnectionSeq: ConnectionSM,
+        UserCallback: ShareeCallbackTrait,
+        NewConnectionSeq: FnMut(Option<u32>) -> ConnectionSeq,
+    {
+        let mut failures = 0;
+        loop {
+            sharee.reconnect(new_connection_seq(self.session_id));
+
+            let connecting = (self.connect)();
+            let link = timeout::guard(
+                async { connecting.await.map_err(ProtoError::from) },
+                self.timeouts.connect,
+                self.cancellation_token.as_ref(),
+                "couldn't open connection",
+            );
+            let connected = match link.await {
+                Ok(transport) => {
+                    let mut transport = NowTransport::with_transport(transport);
+                    transport.set_metrics(self.metrics.clone());
+                    transport.set_timeouts(self.timeouts);
+                    transport.set_cancellation_token(self.cancellation_token.clone());
+                    transport.connect(sharee).await.map(|()| transport)
+                }
+                Err(err) => Err(err),
+            };
+
+            match connected {
+                Ok(transport) => {
+                    if let Some(session_id) = sharee.get_session_id() {
+                        self.session_id = Some(session_id);
+                    }
+                    return Ok(self.transport.get_or_insert(transport));
+                }
+                Err(err) if is_connection_lost(&err) => {
+                    failures += 1;
+                    match self.backoff.delay(failures) {
+                        Some(delay) => {
+                            log::warn!(
+                                "connection attempt {} failed: {} (retrying in {:?})",
+                                failures,
+                                err,
+                                delay
+                            );
+                            let sleep = async {
+                                tokio::time::sleep(delay).await;
+                                Ok(())
+                            };
+                            timeout::guard(sleep, None, self.cancellation_token.as_ref(), "reconnection cancelled")
+                                .await?;
+                        }
+                        None => return Err(err),
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Whether the error is caused by the connection itself (and not by the session).
+fn is_connection_lost(err: &ProtoError) -> bool {
+    match &err.kind {
+        ProtoErrorKind::Io(_) => true,
+        _ => err.source.as_deref().map(is_connection_lost).unwrap_or(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delays() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).max_attempts(6);
+        let delays: Vec<_> = (1..=6).map(|failures| backoff.delay(failures)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_millis(100)),
+                Some(Duration::from_millis(200)),
+                Some(Duration::from_millis(400)),
+                Some(Duration::from_millis(800)),
+                Some(Duration::from_secs(1)),
+                None,
+            ]
+        );
+
+        assert_eq!(Backoff::default().delay(64), Some(Duration::from_secs(30)));
+    }
+}
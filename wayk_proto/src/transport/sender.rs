@@ -0,0 +1,132 @@
+use crate::{error::ProtoError, packet::NowPacket, serialization::Encode};
+use alloc::collections::VecDeque;
+use std::io::{self, IoSlice};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Sender queuing encoded packets up to a bounded amount of bytes.
+///
+/// Queued frames are written together with vectored writes. Once the queue is full,
+/// [`send`](#method.send) waits for frames to be written and [`try_send`](#method.try_send) rejects
+/// the packet, so that a producer faster than the network can drop or coalesce its updates instead
+/// of growing memory.
+pub struct FramedSender<W> {
+    writer: W,
+    queue: VecDeque<Vec<u8>>,
+    /// Bytes of the front frame already written.
+    written: usize,
+    queued_bytes: usize,
+    capacity: usize,
+}
+
+impl<W> FramedSender<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub const DEFAULT_CAPACITY: usize = 1024 * 1024;
+
+    /// Maximum number of frames written by a single vectored write.
+    const MAX_SLICES: usize = 64;
+
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            queue: VecDeque::new(),
+            written: 0,
+            queued_bytes: 0,
+            capacity: Self::DEFAULT_CAPACITY,
+        }
+    }
+
+    /// Maximum amount of bytes queued. A single frame larger than the capacity is still accepted
+    /// once the queue is empty.
+    pub fn capacity(self, capacity: usize) -> Self {
+        Self { capacity, ..self }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Bytes queued and not yet written.
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Whether a frame of `len` bytes can be queued without waiting.
+    pub fn has_room_for(&self, len: usize) -> bool {
+        self.queue.is_empty() || self.queued_bytes + len <= self.capacity
+    }
+
+    /// Queues `packet` if there is room for it. Returns `false` if the queue is full.
+    pub fn try_send<'a, P: Into<NowPacket<'a>>>(&mut self, packet: P) -> Result<bool, ProtoError> {
+        let packet = packet.into();
+        if !self.has_room_for(packet.encoded_len()) {
+            return Ok(false);
+        }
+
+        self.push(packet.encode()?);
+        Ok(true)
+    }
+
+    /// Queues `packet`, writing queued frames first until there is room for it.
+    pub async fn send<'a, P: Into<NowPacket<'a>>>(&mut self, packet: P) -> Result<(), ProtoError> {
+        let frame = packet.into().encode()?;
+        while !self.has_room_for(frame.len()) {
+            self.write_some().await?;
+        }
+
+        self.push(frame);
+        Ok(())
+    }
+
+    /// Writes every queued frame and flushes the writer.
+    pub async fn flush(&mut self) -> Result<(), ProtoError> {
+        while !self.queue.is_empty() {
+            self.write_some().await?;
+        }
+
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    fn push(&mut self, frame: Vec<u8>) {
+        self.queued_bytes += frame.len();
+        self.queue.push_back(frame);
+    }
+
+    async fn write_some(&mut self) -> Result<(), ProtoError> {
+        let mut slices = Vec::with_capacity(self.queue.len().min(Self::MAX_SLICES));
+        for (i, frame) in self.queue.iter().take(Self::MAX_SLICES).enumerate() {
+            let start = if i == 0 { self.written } else { 0 };
+            slices.push(IoSlice::new(&frame[start..]));
+        }
+
+        let mut n = self.writer.write_vectored(&slices).await?;
+        if n == 0 {
+            return Err(ProtoError::from(io::Error::from(io::ErrorKind::WriteZero)));
+        }
+        self.queued_bytes -= n;
+
+        while n > 0 {
+            let remaining = self.queue[0].len() - self.written;
+            if n < remaining {
+                self.written += n;
+                break;
+            }
+
+            n -= remaining;
+            self.written = 0;
+            self.queue.pop_front();
+        }
+
+        Ok(())
+    }
+}
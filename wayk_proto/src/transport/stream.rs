@@ -0,0 +1,234 @@
+use super::Transport;
+use crate::{
+    error::{ProtoError, ProtoErrorResultExt},
+    header::{AbstractNowHeader, NowHeader, NowLongHeader, NowShortHeader},
+    packet::check_body_len,
+    serialization::{Decode, DecodeLimits},
+};
+use core::future::Future;
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Coalescing of small outgoing frames into a single write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBatching {
+    /// Maximum time a frame is held back before being written.
+    pub max_delay: Duration,
+    /// Batches are written as soon as they reach this size.
+    pub max_bytes: usize,
+}
+
+impl Default for FrameBatching {
+    fn default() -> Self {
+        Self {
+            max_delay: Duration::from_millis(5),
+            max_bytes: 16 * 1024,
+        }
+    }
+}
+
+impl FrameBatching {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_delay(self, max_delay: Duration) -> Self {
+        Self { max_delay, ..self }
+    }
+
+    pub fn max_bytes(self, max_bytes: usize) -> Self {
+        Self { max_bytes, ..self }
+    }
+}
+
+/// Packets over a byte stream, framed by their header.
+///
+/// Exactly one packet is read at a time: nothing past the frame is read from the stream.
+///
+/// With [`batching`](#method.batching), written frames are held back and written together once the
+/// batch is full or its oldest frame reached the maximum delay, saving a write and a flush per frame
+/// on chatty sessions.
+pub struct StreamTransport<S> {
+    stream: S,
+    buffer: Vec<u8>,
+    filled: usize,
+    complete: bool,
+    batching: Option<FrameBatching>,
+    batch: Vec<u8>,
+    batch_started: Option<Instant>,
+}
+
+impl<S> StreamTransport<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            filled: 0,
+            complete: false,
+            batching: None,
+            batch: Vec::new(),
+            batch_started: None,
+        }
+    }
+
+    pub fn batching(self, batching: FrameBatching) -> Self {
+        Self {
+            batching: Some(batching),
+            ..self
+        }
+    }
+
+    /// Bytes of the frames held back.
+    pub fn batched_bytes(&self) -> usize {
+        self.batch.len()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Replaces the stream by a wrapper of it. A partially received frame is kept,
+    /// while frames held back are written to the former stream first.
+    pub async fn upgrade<T, F, Fut>(mut self, upgrade: F) -> Result<StreamTransport<T>, ProtoError>
+    where
+        S: AsyncWrite + Unpin,
+        F: FnOnce(S) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        self.__flush_batch().await?;
+
+        let stream = upgrade(self.stream)
+            .await
+            .map_err(ProtoError::from)
+            .or_desc("couldn't upgrade transport stream")?;
+
+        Ok(StreamTransport {
+            stream,
+            buffer: self.buffer,
+            filled: self.filled,
+            complete: self.complete,
+            batching: self.batching,
+            batch: self.batch,
+            batch_started: None,
+        })
+    }
+
+    /// Length of the frame being received, or of the part of its header needed to know it.
+    fn frame_len(&self, limits: DecodeLimits) -> Result<usize, ProtoError> {
+        if self.filled < NowShortHeader::SIZE {
+            return Ok(NowShortHeader::SIZE);
+        }
+
+        let header_len = if self.buffer[3] > 7 {
+            NowShortHeader::SIZE
+        } else {
+            NowLongHeader::SIZE
+        };
+        if self.filled < header_len {
+            return Ok(header_len);
+        }
+
+        let header = NowHeader::decode(&self.buffer[..header_len])?;
+        check_body_len(header.body_len(), limits)?;
+        Ok(header_len + header.body_len())
+    }
+
+    async fn __flush_batch(&mut self) -> Result<(), ProtoError>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        if !self.batch.is_empty() {
+            self.stream.write_all(&self.batch).await?;
+            self.stream.flush().await?;
+            self.batch.clear();
+        }
+        self.batch_started = None;
+        Ok(())
+    }
+}
+
+impl<S> Transport for StreamTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn read_frame(&mut self, limits: DecodeLimits) -> Result<bool, ProtoError> {
+        if self.complete {
+            self.filled = 0;
+            self.complete = false;
+        }
+
+        loop {
+            let frame_len = self.frame_len(limits)?;
+            if self.filled == frame_len {
+                self.complete = true;
+                return Ok(true);
+            }
+
+            if self.buffer.len() < frame_len {
+                self.buffer.resize(frame_len, 0);
+            }
+
+            match self.stream.read(&mut self.buffer[self.filled..frame_len]).await? {
+                0 if self.filled == 0 => return Ok(false),
+                0 => {
+                    return Err(ProtoError::from(io::Error::from(io::ErrorKind::UnexpectedEof)))
+                        .or_else_desc(|| format!("stream closed after {} bytes of a packet", self.filled))
+                }
+                n => self.filled += n,
+            }
+        }
+    }
+
+    fn frame(&self) -> &[u8] {
+        if self.complete {
+            &self.buffer[..self.filled]
+        } else {
+            &[]
+        }
+    }
+
+    /// Only queues the frame while batching, unless the batch is then full or overdue.
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), ProtoError> {
+        let batching = match self.batching {
+            Some(batching) => batching,
+            None => {
+                self.stream.write_all(frame).await?;
+                self.stream.flush().await?;
+                return Ok(());
+            }
+        };
+
+        if self.batch.is_empty() {
+            self.batch_started = Some(Instant::now());
+        }
+        self.batch.extend_from_slice(frame);
+
+        let overdue = matches!(self.flush_deadline(), Some(deadline) if deadline <= Instant::now());
+        if self.batch.len() >= batching.max_bytes || overdue {
+            self.__flush_batch().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), ProtoError> {
+        self.__flush_batch().await
+    }
+
+    fn flush_deadline(&self) -> Option<Instant> {
+        let batching = self.batching?;
+        self.batch_started.map(|started| started + batching.max_delay)
+    }
+
+    async fn close(&mut self) -> Result<(), ProtoError> {
+        self.__flush_batch().await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    sm::StepTimeouts,
+};
+use core::future::Future;
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// Timeouts of a [`NowTransport`](struct.NowTransport.html). All are disabled by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TransportTimeouts {
+    /// Maximum duration of the whole connection sequence.
+    pub connect: Option<Duration>,
+    /// Maximum time waiting for an answer of the peer during the connection sequence.
+    pub read: Option<Duration>,
+    /// Maximum time without receiving anything once connected. The transport is closed when reached.
+    pub idle: Option<Duration>,
+    /// Maximum durations of the connection sequence steps, and retries of stalled steps.
+    pub steps: Option<StepTimeouts>,
+}
+
+impl TransportTimeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connect(self, connect: Duration) -> Self {
+        Self {
+            connect: Some(connect),
+            ..self
+        }
+    }
+
+    pub fn read(self, read: Duration) -> Self {
+        Self {
+            read: Some(read),
+            ..self
+        }
+    }
+
+    pub fn idle(self, idle: Duration) -> Self {
+        Self {
+            idle: Some(idle),
+            ..self
+        }
+    }
+
+    pub fn steps(self, steps: StepTimeouts) -> Self {
+        Self {
+            steps: Some(steps),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// Handle aborting the operations of the transports it is given to.
+///
+/// Clones share the same state: cancelling any of them aborts pending connections, reads and writes
+/// with a [`Cancelled`](../error/enum.ProtoErrorKind.html#variant.Cancelled) error.
+/// A token can't be reset once cancelled.
+#[derive(Debug, Default, Clone)]
+pub struct CancellationToken(Arc<CancellationState>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once the token is cancelled.
+    pub async fn cancelled(&self) {
+        loop {
+            // registered before checking the flag so that a concurrent cancel isn't missed
+            let notified = self.0.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Runs `fut`, failing if it lasts more than `timeout` or if `token` is cancelled first.
+pub(crate) async fn guard<T, F>(
+    fut: F,
+    timeout: Option<Duration>,
+    token: Option<&CancellationToken>,
+    desc: &'static str,
+) -> Result<T, ProtoError>
+where
+    F: Future<Output = Result<T, ProtoError>>,
+{
+    let cancelled = async {
+        match token {
+            Some(token) => token.cancelled().await,
+            None => core::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        biased;
+        _ = cancelled => ProtoError::new(ProtoErrorKind::Cancelled).or_desc(desc),
+        result = fut => result,
+        _ = tokio::time::sleep(timeout.unwrap_or_default()), if timeout.is_some() => {
+            Err(ProtoError::from(io::Error::from(io::ErrorKind::TimedOut))).or_desc(desc)
+        }
+    }
+}
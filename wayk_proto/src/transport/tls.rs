@@ -0,0 +1,152 @@
+//! TLS upgrade of stream transports, performed by the connection sequence once negotiated.
+//!
+//! Both sides enable TLS in their connection sequence builder (`tls(true)`), then connect with
+//! [`NowTransport::connect_tls`]: handshake and negotiation are exchanged in clear, the stream is
+//! upgraded with rustls, and authentication onwards is carried over TLS. The certificate of the peer
+//! is made available to the authentication through the shared data of the connection sequence.
+
+use super::{timeout, NowTransport, StreamTransport, TransportTimeouts};
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    sharee::{Sharee, ShareeCallbackTrait, ShareeState},
+    sm::{ConnectionSM, ConnectionState, StepWatchdog},
+};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, ServerConfig},
+    TlsAcceptor, TlsConnector,
+};
+
+pub use tokio_rustls::{rustls, TlsStream};
+
+/// Side of the TLS handshake, with its rustls configuration.
+///
+/// Certificates are verified by rustls according to the configuration. Servers presenting
+/// self-signed certificates are usually accepted by a custom verifier, their fingerprint
+/// being checked against the peer certificate by the authentication.
+#[derive(Clone)]
+pub enum TlsConfig {
+    Client {
+        config: Arc<ClientConfig>,
+        server_name: ServerName<'static>,
+    },
+    Server(Arc<ServerConfig>),
+}
+
+impl TlsConfig {
+    pub fn client(config: Arc<ClientConfig>, server_name: ServerName<'static>) -> Self {
+        TlsConfig::Client { config, server_name }
+    }
+
+    pub fn server(config: Arc<ServerConfig>) -> Self {
+        TlsConfig::Server(config)
+    }
+}
+
+impl<S> NowTransport<StreamTransport<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Runs the connection sequence of `sharee`, upgrading the stream to TLS once negotiated.
+    ///
+    /// The connection sequence must have TLS enabled: the connection fails if the peer didn't agree to it,
+    /// rather than going on in clear. The DER certificate of the peer, if any, is stored in the shared
+    /// data of the connection sequence before authentication starts.
+    ///
+    /// The connect timeout applies to the negotiation and TLS handshake, then to the rest of the sequence.
+    pub async fn connect_tls<ConnectionSeq, UserCallback>(
+        mut self,
+        sharee: &mut Sharee<ConnectionSeq, UserCallback>,
+        config: TlsConfig,
+    ) -> Result<NowTransport<StreamTransport<TlsStream<S>>>, ProtoError>
+    where
+        ConnectionSeq: ConnectionSM,
+        UserCallback: ShareeCallbackTrait,
+    {
+        let TransportTimeouts {
+            connect, read, steps, ..
+        } = self.timeouts;
+        let cancellation_token = self.cancellation_token.clone();
+        let negotiation = async {
+            self.drive(sharee, read, steps.map(StepWatchdog::new), |sharee| {
+                sharee.get_state() == ShareeState::Connection
+                    && matches!(
+                        sharee.get_connection_seq().current_step(),
+                        Some(ConnectionState::Handshake | ConnectionState::Negotiate)
+                    )
+            })
+            .await?;
+            self.__check_tls_negotiated(sharee)?;
+            self.upgrade(|stream| async move {
+                match config {
+                    TlsConfig::Client { config, server_name } => TlsConnector::from(config)
+                        .connect(server_name, stream)
+                        .await
+                        .map(TlsStream::from),
+                    TlsConfig::Server(config) => TlsAcceptor::from(config).accept(stream).await.map(TlsStream::from),
+                }
+            })
+            .await
+        };
+        let result = timeout::guard(
+            negotiation,
+            connect,
+            cancellation_token.as_ref(),
+            "TLS negotiation timed out",
+        )
+        .await;
+        let mut transport = match result {
+            Ok(transport) => transport,
+            Err(err) => {
+                sharee.on_error(&err);
+                return Err(err);
+            }
+        };
+
+        if let Some(shared_data) = sharee.get_connection_seq().get_shared_data() {
+            shared_data.borrow_mut().peer_certificate = transport.peer_certificate().map(<[u8]>::to_vec);
+        }
+        transport.connect(sharee).await?;
+        Ok(transport)
+    }
+
+    fn __check_tls_negotiated<ConnectionSeq, UserCallback>(
+        &self,
+        sharee: &Sharee<ConnectionSeq, UserCallback>,
+    ) -> Result<(), ProtoError>
+    where
+        ConnectionSeq: ConnectionSM,
+        UserCallback: ShareeCallbackTrait,
+    {
+        if sharee.get_state() != ShareeState::Connection {
+            return ProtoError::new(ProtoErrorKind::Sharee(sharee.get_state()))
+                .or_desc("connection sequence ended before TLS upgrade");
+        }
+
+        let negotiated = sharee
+            .get_connection_seq()
+            .get_shared_data()
+            .is_some_and(|shared_data| shared_data.borrow().tls);
+        if negotiated {
+            Ok(())
+        } else {
+            ProtoError::new(ProtoErrorKind::ConnectionSequence(ConnectionState::Negotiate))
+                .or_desc("TLS not negotiated with the peer")
+        }
+    }
+}
+
+impl<S> NowTransport<StreamTransport<TlsStream<S>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// DER certificate presented by the peer, typically absent for clients.
+    pub fn peer_certificate(&self) -> Option<&[u8]> {
+        let (_, connection) = self.get_ref().get_ref();
+        connection
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .map(|certificate| certificate.as_ref())
+    }
+}
@@ -0,0 +1,237 @@
+//! WebSocket framing, for connections going through HTTP-only infrastructure (eg: a gateway).
+//!
+//! [`WebSocketStream`] performs the opening handshake over a stream connected by the caller (see
+//! [`connect`](struct.WebSocketStream.html#method.connect) and [`accept`](struct.WebSocketStream.html#method.accept)),
+//! or wraps a stream on which it is already completed. Written bytes are sent in binary messages and the payload
+//! of received binary messages is read back as a byte stream: it is used as the stream of a
+//! [`NowTransport`](../struct.NowTransport.html). Framing, masking and control frames are handled by tungstenite.
+//!
+//! On wasm, the browser performs the handshake and framing: [`BrowserWebSocket`] wraps its WebSocket API.
+
+#[cfg(target_arch = "wasm32")]
+mod browser;
+
+#[cfg(target_arch = "wasm32")]
+pub use self::browser::BrowserWebSocket;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::native::*;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use core::{
+        pin::Pin,
+        task::{ready, Context, Poll},
+    };
+    use futures_util::{Sink, Stream};
+    use std::io;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio_tungstenite::tungstenite::{
+        error::ProtocolError,
+        protocol::{Role, WebSocketConfig},
+        Error, Message,
+    };
+
+    pub use tokio_tungstenite::tungstenite;
+
+    /// Side of the connection. Frames sent by clients are masked, frames sent by servers are not.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum WebSocketRole {
+        Client,
+        Server,
+    }
+
+    pub struct WebSocketStream<S> {
+        inner: tokio_tungstenite::WebSocketStream<S>,
+        /// Payload of the last received binary message not yet read.
+        payload: Vec<u8>,
+        payload_pos: usize,
+    }
+
+    impl<S> WebSocketStream<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        /// Received frames and messages with a larger payload are rejected.
+        pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+        /// Performs the opening handshake as a client, requesting `url` (eg: `ws://gateway/jet/<association>`).
+        pub async fn connect(stream: S, url: &str) -> io::Result<Self> {
+            let (inner, _) = tokio_tungstenite::client_async_with_config(url, stream, Some(Self::config()))
+                .await
+                .map_err(io_error)?;
+            Ok(Self::wrap(inner))
+        }
+
+        /// Performs the opening handshake as a server, accepting the upgrade request of the client.
+        pub async fn accept(stream: S) -> io::Result<Self> {
+            let inner = tokio_tungstenite::accept_async_with_config(stream, Some(Self::config()))
+                .await
+                .map_err(io_error)?;
+            Ok(Self::wrap(inner))
+        }
+
+        /// Wraps a stream on which the opening handshake is already completed (eg: by an HTTP server).
+        pub async fn from_upgraded(stream: S, role: WebSocketRole) -> Self {
+            let role = match role {
+                WebSocketRole::Client => Role::Client,
+                WebSocketRole::Server => Role::Server,
+            };
+            let inner = tokio_tungstenite::WebSocketStream::from_raw_socket(stream, role, Some(Self::config())).await;
+            Self::wrap(inner)
+        }
+
+        pub fn get_ref(&self) -> &S {
+            self.inner.get_ref()
+        }
+
+        pub fn get_mut(&mut self) -> &mut S {
+            self.inner.get_mut()
+        }
+
+        fn wrap(inner: tokio_tungstenite::WebSocketStream<S>) -> Self {
+            Self {
+                inner,
+                payload: Vec::new(),
+                payload_pos: 0,
+            }
+        }
+
+        fn config() -> WebSocketConfig {
+            WebSocketConfig {
+                max_message_size: Some(Self::DEFAULT_MAX_FRAME_SIZE),
+                max_frame_size: Some(Self::DEFAULT_MAX_FRAME_SIZE),
+                ..WebSocketConfig::default()
+            }
+        }
+    }
+
+    impl<S> AsyncRead for WebSocketStream<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            loop {
+                if this.payload_pos < this.payload.len() {
+                    let available = &this.payload[this.payload_pos..];
+                    let len = available.len().min(buf.remaining());
+                    buf.put_slice(&available[..len]);
+                    this.payload_pos += len;
+                    return Poll::Ready(Ok(()));
+                }
+
+                match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                    Some(Ok(Message::Binary(payload))) => {
+                        this.payload = payload;
+                        this.payload_pos = 0;
+                    }
+                    // pongs and close answers are queued by tungstenite and written on the way
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Close(_))) => {}
+                    Some(Ok(Message::Text(_) | Message::Frame(_))) => {
+                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected text frame")))
+                    }
+                    // the peer closing the underlying stream without closing handshake is a regular end of stream
+                    None
+                    | Some(Err(
+                        Error::ConnectionClosed
+                        | Error::AlreadyClosed
+                        | Error::Protocol(ProtocolError::ResetWithoutClosingHandshake),
+                    )) => return Poll::Ready(Ok(())),
+                    Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+                }
+            }
+        }
+    }
+
+    impl<S> AsyncWrite for WebSocketStream<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(io_error)?;
+            if !buf.is_empty() {
+                Pin::new(&mut this.inner)
+                    .start_send(Message::Binary(buf.to_vec()))
+                    .map_err(io_error)?;
+            }
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_flush(cx).map_err(io_error)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_close(cx).map_err(io_error)
+        }
+    }
+
+    fn io_error(error: Error) -> io::Error {
+        match error {
+            Error::Io(e) => e,
+            Error::ConnectionClosed | Error::AlreadyClosed => io::ErrorKind::BrokenPipe.into(),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        const OPCODE_CONTINUATION: u8 = 0x0;
+        const OPCODE_BINARY: u8 = 0x2;
+        const OPCODE_PING: u8 = 0x9;
+
+        /// Unmasked frame with a short payload, as sent by a server.
+        fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+            let fin = if fin { 0x80 } else { 0x00 };
+            let mut out = vec![fin | opcode, payload.len() as u8];
+            out.extend_from_slice(payload);
+            out
+        }
+
+        async fn read_all(frames: &[Vec<u8>]) -> io::Result<Vec<u8>> {
+            let (client, mut server) = tokio::io::duplex(1024);
+            server.write_all(&frames.concat()).await?;
+            // pongs are still to be written
+            server.shutdown().await?;
+
+            let mut stream = WebSocketStream::from_upgraded(client, WebSocketRole::Client).await;
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await?;
+            Ok(received)
+        }
+
+        #[tokio::test]
+        async fn fragmented_message_with_interleaved_ping() {
+            let received = read_all(&[
+                frame(false, OPCODE_BINARY, b"now"),
+                frame(true, OPCODE_PING, b"?"),
+                frame(false, OPCODE_CONTINUATION, b"-"),
+                frame(true, OPCODE_CONTINUATION, b"proto"),
+                frame(true, OPCODE_BINARY, b"!"),
+            ])
+            .await
+            .unwrap();
+            assert_eq!(received, b"now-proto!");
+        }
+
+        #[tokio::test]
+        async fn invalid_frames_rejected() {
+            let orphan_continuation = read_all(&[frame(true, OPCODE_CONTINUATION, b"proto")]).await;
+            assert_eq!(orphan_continuation.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+            let interrupted = read_all(&[frame(false, OPCODE_BINARY, b"now"), frame(true, OPCODE_BINARY, b"!")]).await;
+            assert_eq!(interrupted.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+            let fragmented_ping = read_all(&[frame(false, OPCODE_PING, b"?")]).await;
+            assert_eq!(fragmented_ping.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+            let text = read_all(&[frame(true, 0x1, b"now")]).await;
+            assert_eq!(text.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
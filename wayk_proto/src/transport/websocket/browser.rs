@@ -0,0 +1,175 @@
+use core::{
+    cell::RefCell,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use std::{io, rc::Rc};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, WebSocket};
+
+#[derive(Default)]
+struct SocketState {
+    open: bool,
+    closed: bool,
+    error: Option<String>,
+    /// Payload of received binary messages not yet read.
+    received: Vec<u8>,
+    received_pos: usize,
+    waker: Option<Waker>,
+}
+
+impl SocketState {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// WebSocket of the browser, opened by the page on behalf of the client.
+///
+/// Messages are sent and received as binary: received text messages are an error.
+pub struct BrowserWebSocket {
+    socket: WebSocket,
+    state: Rc<RefCell<SocketState>>,
+    _on_open: Closure<dyn FnMut(Event)>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_error: Closure<dyn FnMut(Event)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl BrowserWebSocket {
+    /// Opens a WebSocket to `url` (eg: `wss://gateway/jet/<association>`) and waits for the handshake.
+    pub async fn connect(url: &str) -> io::Result<Self> {
+        let socket = WebSocket::new(url).map_err(js_error)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+        let state = Rc::new(RefCell::new(SocketState::default()));
+
+        let on_open = {
+            let state = Rc::clone(&state);
+            Closure::<dyn FnMut(Event)>::new(move |_: Event| {
+                let mut state = state.borrow_mut();
+                state.open = true;
+                state.wake();
+            })
+        };
+        let on_message = {
+            let state = Rc::clone(&state);
+            Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                let mut state = state.borrow_mut();
+                match event.data().dyn_into::<js_sys::ArrayBuffer>() {
+                    Ok(buffer) => {
+                        let SocketState {
+                            received, received_pos, ..
+                        } = &mut *state;
+                        received.drain(..*received_pos);
+                        *received_pos = 0;
+                        received.extend(js_sys::Uint8Array::new(&buffer).to_vec());
+                    }
+                    Err(_) => state.error = Some("unexpected text message".into()),
+                }
+                state.wake();
+            })
+        };
+        let on_error = {
+            let state = Rc::clone(&state);
+            Closure::<dyn FnMut(Event)>::new(move |_: Event| {
+                let mut state = state.borrow_mut();
+                state.error.get_or_insert_with(|| "WebSocket error".into());
+                state.wake();
+            })
+        };
+        let on_close = {
+            let state = Rc::clone(&state);
+            Closure::<dyn FnMut(CloseEvent)>::new(move |_: CloseEvent| {
+                let mut state = state.borrow_mut();
+                state.closed = true;
+                state.wake();
+            })
+        };
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        let socket = Self {
+            socket,
+            state,
+            _on_open: on_open,
+            _on_message: on_message,
+            _on_error: on_error,
+            _on_close: on_close,
+        };
+        core::future::poll_fn(|cx| {
+            let mut state = socket.state.borrow_mut();
+            if let Some(error) = &state.error {
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionRefused, error.clone())))
+            } else if state.closed {
+                Poll::Ready(Err(io::ErrorKind::ConnectionRefused.into()))
+            } else if state.open {
+                Poll::Ready(Ok(()))
+            } else {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await?;
+        Ok(socket)
+    }
+}
+
+impl Drop for BrowserWebSocket {
+    fn drop(&mut self) {
+        self.socket.set_onopen(None);
+        self.socket.set_onmessage(None);
+        self.socket.set_onerror(None);
+        self.socket.set_onclose(None);
+        let _ = self.socket.close();
+    }
+}
+
+impl AsyncRead for BrowserWebSocket {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.borrow_mut();
+        if state.received_pos < state.received.len() {
+            let available = &state.received[state.received_pos..];
+            let len = available.len().min(buf.remaining());
+            buf.put_slice(&available[..len]);
+            state.received_pos += len;
+            Poll::Ready(Ok(()))
+        } else if let Some(error) = &state.error {
+            Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, error.clone())))
+        } else if state.closed {
+            Poll::Ready(Ok(()))
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl AsyncWrite for BrowserWebSocket {
+    /// Messages are buffered by the browser: writes complete immediately.
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.state.borrow().closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if !buf.is_empty() {
+            self.socket.send_with_u8_array(buf).map_err(js_error)?;
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.socket.close().map_err(js_error))
+    }
+}
+
+fn js_error(error: JsValue) -> io::Error {
+    io::Error::other(format!("{:?}", error))
+}
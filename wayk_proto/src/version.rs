@@ -1,3 +1,5 @@
+use crate::message::ChannelName;
+use core::fmt;
 use lazy_static::lazy_static;
 
 pub const WAYK_NOW_VERSION_MAJOR: u8 = 3;
@@ -16,3 +18,159 @@ lazy_static! {
         u16::from(WAYK_NOW_VERSION_PATCH)
     ];
 }
+
+/// NOW protocol version, as exchanged in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NowProtocolVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl NowProtocolVersion {
+    /// Version implemented by this crate.
+    pub const CURRENT: Self = Self::new(WAYK_NOW_VERSION_MAJOR, WAYK_NOW_VERSION_MINOR, WAYK_NOW_VERSION_PATCH);
+
+    pub const fn new(major: u8, minor: u8, patch: u8) -> Self {
+        Self { major, minor, patch }
+    }
+
+    pub fn supports(self, feature: ProtocolFeature) -> bool {
+        self >= feature.since()
+    }
+
+    /// Channels without a version requirement are available with every version.
+    pub fn supports_channel(self, name: &ChannelName) -> bool {
+        match ProtocolFeature::for_channel(name) {
+            Some(feature) => self.supports(feature),
+            None => true,
+        }
+    }
+}
+
+impl Default for NowProtocolVersion {
+    fn default() -> Self {
+        Self::CURRENT
+    }
+}
+
+impl fmt::Display for NowProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Range of protocol versions a peer supports.
+///
+/// The client advertises the upper bound in its handshake. The server answers with the highest
+/// version both support, which the client checks is within its own range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NowProtocolVersionRange {
+    pub min: NowProtocolVersion,
+    pub max: NowProtocolVersion,
+}
+
+impl NowProtocolVersionRange {
+    pub const fn new(min: NowProtocolVersion, max: NowProtocolVersion) -> Self {
+        Self { min, max }
+    }
+
+    /// Only supports `version`.
+    pub const fn exactly(version: NowProtocolVersion) -> Self {
+        Self::new(version, version)
+    }
+
+    pub fn contains(&self, version: NowProtocolVersion) -> bool {
+        self.min <= version && version <= self.max
+    }
+
+    /// Highest version supported by both sides, given the highest version supported by the peer.
+    pub fn select(&self, peer_max: NowProtocolVersion) -> Option<NowProtocolVersion> {
+        let selected = self.max.min(peer_max);
+        if selected >= self.min {
+            Some(selected)
+        } else {
+            None
+        }
+    }
+}
+
+/// Every version of the current major, up to the current one.
+impl Default for NowProtocolVersionRange {
+    fn default() -> Self {
+        Self::new(
+            NowProtocolVersion::new(WAYK_NOW_VERSION_MAJOR, 0, 0),
+            NowProtocolVersion::CURRENT,
+        )
+    }
+}
+
+impl fmt::Display for NowProtocolVersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} to {}", self.min, self.max)
+    }
+}
+
+/// Protocol features depending on the version negotiated in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProtocolFeature {
+    ExecChannel,
+    TunnelChannel,
+    /// Status code following the sequence id of surface responses.
+    ExtendedSurfaceStatus,
+    /// Credits granted by the receiver of a virtual channel.
+    ChannelFlowControl,
+    /// Clipboard format data split over several responses flagged with a continuation.
+    ClipboardContinuation,
+}
+
+impl ProtocolFeature {
+    /// First version supporting the feature.
+    pub fn since(self) -> NowProtocolVersion {
+        match self {
+            ProtocolFeature::ExecChannel => NowProtocolVersion::new(3, 1, 0),
+            ProtocolFeature::TunnelChannel => NowProtocolVersion::new(3, 2, 0),
+            ProtocolFeature::ExtendedSurfaceStatus => NowProtocolVersion::new(3, 3, 0),
+            ProtocolFeature::ChannelFlowControl => NowProtocolVersion::new(3, 3, 1),
+            ProtocolFeature::ClipboardContinuation => NowProtocolVersion::new(3, 3, 1),
+        }
+    }
+
+    pub fn for_channel(name: &ChannelName) -> Option<Self> {
+        match name {
+            ChannelName::Exec => Some(ProtocolFeature::ExecChannel),
+            ChannelName::Tunnel => Some(ProtocolFeature::TunnelChannel),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V3_0: NowProtocolVersion = NowProtocolVersion::new(3, 0, 0);
+    const V3_2: NowProtocolVersion = NowProtocolVersion::new(3, 2, 0);
+
+    #[test]
+    fn highest_common_version_selected() {
+        let range = NowProtocolVersionRange::new(V3_0, NowProtocolVersion::CURRENT);
+        assert_eq!(range.select(V3_2), Some(V3_2));
+        assert_eq!(
+            range.select(NowProtocolVersion::new(4, 0, 0)),
+            Some(NowProtocolVersion::CURRENT)
+        );
+        assert_eq!(NowProtocolVersionRange::exactly(V3_2).select(V3_0), None);
+    }
+
+    #[test]
+    fn features_follow_version() {
+        assert!(!V3_0.supports_channel(&ChannelName::Tunnel));
+        assert!(V3_2.supports_channel(&ChannelName::Tunnel));
+        assert!(V3_0.supports_channel(&ChannelName::Chat));
+        assert!(!V3_2.supports(ProtocolFeature::ExtendedSurfaceStatus));
+        assert!(NowProtocolVersion::CURRENT.supports(ProtocolFeature::ExtendedSurfaceStatus));
+    }
+}
@@ -0,0 +1,489 @@
+//! Wake-on-LAN helpers to reach hosts that are asleep.
+//!
+//! [`ConnectWithWake`](struct.ConnectWithWake.html) sends magic packets and retries the
+//! connection until the host answers or the deadline is reached.
+
+use crate::{
+    error::{ProtoError, ProtoErrorKind, ProtoErrorResultExt},
+    serialization::Encode,
+};
+use core::{fmt, str::FromStr};
+use std::{
+    io::Write,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+/// Parses six hexadecimal bytes separated by `:` or `-`.
+fn parse_six_bytes(s: &str) -> Option<[u8; 6]> {
+    let mut bytes = [0; 6];
+    let mut parts = s.trim().split([':', '-']);
+    for byte in bytes.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+
+    if parts.next().is_some() {
+        None
+    } else {
+        Some(bytes)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl FromStr for MacAddress {
+    type Err = ProtoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_six_bytes(s)
+            .map(Self)
+            .chain(ProtoErrorKind::Wake)
+            .or_else_desc(|| format!("invalid MAC address: {}", s))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    }
+}
+
+/// SecureOn password, written like a MAC address.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SecureOnPassword(pub [u8; 6]);
+
+impl FromStr for SecureOnPassword {
+    type Err = ProtoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_six_bytes(s)
+            .map(Self)
+            .chain(ProtoErrorKind::Wake)
+            .or_desc("invalid SecureOn password")
+    }
+}
+
+/// Six `0xFF` bytes followed by sixteen repetitions of the MAC address,
+/// and the SecureOn password if any.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MagicPacket {
+    pub mac: MacAddress,
+    pub password: Option<SecureOnPassword>,
+}
+
+impl MagicPacket {
+    const SYNC_STREAM: [u8; 6] = [0xFF; 6];
+    const MAC_REPETITIONS: usize = 16;
+
+    pub fn new(mac: MacAddress) -> Self {
+        Self { mac, password: None }
+    }
+
+    pub fn new_with_password(mac: MacAddress, password: SecureOnPassword) -> Self {
+        Self {
+            mac,
+            password: Some(password),
+        }
+    }
+}
+
+impl Encode for MagicPacket {
+    fn encoded_len(&self) -> usize {
+        Self::SYNC_STREAM.len() + Self::MAC_REPETITIONS * self.mac.0.len() + self.password.map_or(0, |p| p.0.len())
+    }
+
+    fn encode_into<W: Write>(&self, writer: &mut W) -> Result<(), ProtoError> {
+        writer.write_all(&Self::SYNC_STREAM)?;
+        for _ in 0..Self::MAC_REPETITIONS {
+            writer.write_all(&self.mac.0)?;
+        }
+        if let Some(password) = &self.password {
+            writer.write_all(&password.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where and how magic packets are sent.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct WakeTarget {
+    pub mac: MacAddress,
+    /// Limited broadcast on the discard port by default. Use a directed broadcast
+    /// address (such as `192.168.1.255:9`) to reach another subnet.
+    pub broadcast_addr: SocketAddr,
+    pub password: Option<SecureOnPassword>,
+}
+
+impl WakeTarget {
+    pub const DEFAULT_PORT: u16 = 9;
+
+    pub fn new(mac: MacAddress) -> Self {
+        Self {
+            mac,
+            broadcast_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, Self::DEFAULT_PORT)),
+            password: None,
+        }
+    }
+
+    pub fn broadcast_addr(self, broadcast_addr: SocketAddr) -> Self {
+        Self { broadcast_addr, ..self }
+    }
+
+    pub fn password(self, password: SecureOnPassword) -> Self {
+        Self {
+            password: Some(password),
+            ..self
+        }
+    }
+
+    pub fn magic_packet(&self) -> MagicPacket {
+        MagicPacket {
+            mac: self.mac,
+            password: self.password,
+        }
+    }
+
+    pub fn send(&self) -> Result<(), ProtoError> {
+        let bind_addr: SocketAddr = if self.broadcast_addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.set_broadcast(true)?;
+        socket.send_to(&self.magic_packet().encode()?, self.broadcast_addr)?;
+
+        log::trace!("magic packet for {} sent to {}", self.mac, self.broadcast_addr);
+        Ok(())
+    }
+}
+
+pub trait WakeCallbackTrait {
+    fn on_wake_sent(&mut self, attempt: u32) {
+        #![allow(unused_variables)]
+    }
+
+    fn on_attempt_failed(&mut self, attempt: u32, error: &dyn fmt::Display, retry_in: Option<Duration>) {
+        #![allow(unused_variables)]
+    }
+
+    fn on_connected(&mut self, attempt: u32, elapsed: Duration) {
+        #![allow(unused_variables)]
+    }
+}
+
+sa::assert_obj_safe!(WakeCallbackTrait);
+
+pub struct DummyWakeCallback;
+impl WakeCallbackTrait for DummyWakeCallback {}
+
+/// Sends magic packets and retries a connector until the host answers.
+///
+/// A magic packet is sent before each attempt since they are not acknowledged and may be lost.
+/// The interval between attempts doubles up to `max_interval`.
+pub struct ConnectWithWake<UserCallback> {
+    target: WakeTarget,
+    attempts: u32,
+    interval: Duration,
+    max_interval: Duration,
+    deadline: Duration,
+    user_callback: UserCallback,
+}
+
+impl ConnectWithWake<DummyWakeCallback> {
+    pub fn new(target: WakeTarget) -> Self {
+        Self::new_with_callback(target, DummyWakeCallback)
+    }
+}
+
+impl<UserCallback> ConnectWithWake<UserCallback>
+where
+    UserCallback: WakeCallbackTrait,
+{
+    pub const DEFAULT_ATTEMPTS: u32 = 10;
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(2);
+    pub const DEFAULT_MAX_INTERVAL: Duration = Duration::from_secs(15);
+    pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(120);
+
+    pub fn new_with_callback(target: WakeTarget, user_callback: UserCallback) -> Self {
+        Self {
+            target,
+            attempts: Self::DEFAULT_ATTEMPTS,
+            interval: Self::DEFAULT_INTERVAL,
+            max_interval: Self::DEFAULT_MAX_INTERVAL,
+            deadline: Self::DEFAULT_DEADLINE,
+            user_callback,
+        }
+    }
+
+    pub fn attempts(self, attempts: u32) -> Self {
+        Self { attempts, ..self }
+    }
+
+    pub fn interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    pub fn max_interval(self, max_interval: Duration) -> Self {
+        Self { max_interval, ..self }
+    }
+
+    pub fn deadline(self, deadline: Duration) -> Self {
+        Self { deadline, ..self }
+    }
+
+    /// Calls `connector` until it succeeds. The connector is expected to perform
+    /// the TCP connection and, optionally, the NOW handshake.
+    pub fn connect<T, E, Connector>(mut self, mut connector: Connector) -> Result<T, ProtoError>
+    where
+        E: fmt::Display,
+        Connector: FnMut() -> Result<T, E>,
+    {
+        let start = Instant::now();
+        let mut interval = self.interval;
+        for attempt in 1..=self.attempts {
+            self.__send_wake(attempt);
+
+            let error = match connector() {
+                Ok(connection) => {
+                    self.user_callback.on_connected(attempt, start.elapsed());
+                    return Ok(connection);
+                }
+                Err(e) => e,
+            };
+
+            match self.__next_delay(attempt, start.elapsed(), &mut interval, &error) {
+                Some(delay) => std::thread::sleep(delay),
+                None => return self.__gave_up(attempt, &error),
+            }
+        }
+
+        self.__gave_up(self.attempts, &"no attempt allowed")
+    }
+
+    #[cfg(feature = "async")]
+    /// Same as [`connect`](#method.connect) with an async connector.
+    pub async fn connect_async<T, E, Connector, Fut>(mut self, mut connector: Connector) -> Result<T, ProtoError>
+    where
+        E: fmt::Display,
+        Connector: FnMut() -> Fut,
+        Fut: core::future::Future<Output = Result<T, E>>,
+    {
+        let start = Instant::now();
+        let mut interval = self.interval;
+        for attempt in 1..=self.attempts {
+            self.__send_wake(attempt);
+
+            let error = match connector().await {
+                Ok(connection) => {
+                    self.user_callback.on_connected(attempt, start.elapsed());
+                    return Ok(connection);
+                }
+                Err(e) => e,
+            };
+
+            match self.__next_delay(attempt, start.elapsed(), &mut interval, &error) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return self.__gave_up(attempt, &error),
+            }
+        }
+
+        self.__gave_up(self.attempts, &"no attempt allowed")
+    }
+
+    fn __send_wake(&mut self, attempt: u32) {
+        // host may be reachable even if the magic packet can't be sent (no broadcast route, etc.)
+        match self.target.send() {
+            Ok(()) => self.user_callback.on_wake_sent(attempt),
+            Err(e) => log::warn!("couldn't send magic packet: {}", e),
+        }
+    }
+
+    /// Delay before the next attempt, `None` if there is no attempt left or the deadline would be exceeded.
+    fn __next_delay(
+        &mut self,
+        attempt: u32,
+        elapsed: Duration,
+        interval: &mut Duration,
+        error: &dyn fmt::Display,
+    ) -> Option<Duration> {
+        let delay = *interval;
+        let retry_in = if attempt < self.attempts && elapsed.saturating_add(delay) < self.deadline {
+            Some(delay)
+        } else {
+            None
+        };
+
+        self.user_callback.on_attempt_failed(attempt, error, retry_in);
+        *interval = core::cmp::min(delay.saturating_mul(2), self.max_interval);
+
+        retry_in
+    }
+
+    fn __gave_up<T>(&self, attempts: u32, last_error: &dyn fmt::Display) -> Result<T, ProtoError> {
+        ProtoError::new(ProtoErrorKind::Wake).or_else_desc(|| {
+            format!(
+                "host {} didn't answer after {} attempt(s): {}",
+                self.target.mac, attempts, last_error
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    const MAC: MacAddress = MacAddress([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+
+    #[test]
+    fn magic_packet_layout() {
+        let bytes = MagicPacket::new(MAC).encode().unwrap();
+        assert_eq!(bytes.len(), 102);
+        assert_eq!(bytes[..6], [0xFF; 6]);
+        for repetition in bytes[6..].chunks(6) {
+            assert_eq!(repetition, MAC.0);
+        }
+    }
+
+    #[test]
+    fn magic_packet_layout_with_password() {
+        let password = SecureOnPassword::from_str("de-ad-be-ef-00-01").unwrap();
+        let bytes = MagicPacket::new_with_password(MAC, password).encode().unwrap();
+        assert_eq!(bytes.len(), 108);
+        assert_eq!(bytes[..102], MagicPacket::new(MAC).encode().unwrap()[..]);
+        assert_eq!(bytes[102..], [0xDE, 0xAD, 0xBE, 0xEF, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn mac_address_parsing() {
+        assert_eq!(MacAddress::from_str("00:11:22:33:44:55").unwrap(), MAC);
+        assert_eq!(MacAddress::from_str("00-11-22-33-44-55").unwrap(), MAC);
+        assert!(MacAddress::from_str("00:11:22:33:44").is_err());
+        assert!(MacAddress::from_str("00:11:22:33:44:55:66").is_err());
+        assert!(MacAddress::from_str("0:11:22:33:44:555").is_err());
+    }
+
+    #[derive(Default)]
+    struct Progress {
+        wake_sent: u32,
+        failed: u32,
+        connected: Option<u32>,
+    }
+
+    impl WakeCallbackTrait for &mut Progress {
+        fn on_wake_sent(&mut self, _: u32) {
+            self.wake_sent += 1;
+        }
+
+        fn on_attempt_failed(&mut self, _: u32, _: &dyn fmt::Display, _: Option<Duration>) {
+            self.failed += 1;
+        }
+
+        fn on_connected(&mut self, attempt: u32, _: Duration) {
+            self.connected = Some(attempt);
+        }
+    }
+
+    #[test]
+    fn retries_until_listener_accepts() {
+        // magic packets are received on a local socket instead of being broadcasted
+        let wake_receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = WakeTarget::new(MAC).broadcast_addr(wake_receiver.local_addr().unwrap());
+
+        // host starts listening after a while
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let host = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(150));
+            let listener = TcpListener::bind(addr).unwrap();
+            listener.accept().unwrap();
+        });
+
+        let mut progress = Progress::default();
+        let stream = ConnectWithWake::new_with_callback(target, &mut progress)
+            .attempts(20)
+            .interval(Duration::from_millis(20))
+            .max_interval(Duration::from_millis(40))
+            .deadline(Duration::from_secs(5))
+            .connect(|| TcpStream::connect(addr));
+        assert!(stream.is_ok());
+        host.join().unwrap();
+
+        assert!(progress.failed > 0);
+        assert_eq!(progress.connected, Some(progress.failed + 1));
+        assert_eq!(progress.wake_sent, progress.failed + 1);
+
+        let mut packet = [0; 128];
+        let (len, _) = wake_receiver.recv_from(&mut packet).unwrap();
+        assert_eq!(packet[..len], MagicPacket::new(MAC).encode().unwrap()[..]);
+    }
+
+    #[test]
+    fn gives_up_at_deadline() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let target = WakeTarget::new(MAC).broadcast_addr("127.0.0.1:9".parse().unwrap());
+
+        let result = ConnectWithWake::new(target)
+            .attempts(100)
+            .interval(Duration::from_millis(20))
+            .deadline(Duration::from_millis(100))
+            .connect(|| TcpStream::connect(addr));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn large_interval_saturates() {
+        let target = WakeTarget::new(MAC).broadcast_addr("127.0.0.1:9".parse().unwrap());
+        let mut connector = ConnectWithWake::new(target)
+            .interval(Duration::MAX)
+            .max_interval(Duration::MAX);
+
+        let mut interval = Duration::MAX;
+        let retry_in = connector.__next_delay(1, Duration::from_secs(1), &mut interval, &"host is asleep");
+        assert_eq!(retry_in, None);
+        assert_eq!(interval, Duration::MAX);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_connector() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let target = WakeTarget::new(MAC).broadcast_addr("127.0.0.1:9".parse().unwrap());
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let mut attempts = 0;
+        let result = runtime.block_on(
+            ConnectWithWake::new(target)
+                .interval(Duration::from_millis(10))
+                .connect_async(|| {
+                    attempts += 1;
+                    let attempt = attempts;
+                    async move {
+                        if attempt < 3 {
+                            Err("host is asleep")
+                        } else {
+                            TcpStream::connect(addr).map_err(|_| "connection failed")
+                        }
+                    }
+                }),
+        );
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+    }
+}
@@ -0,0 +1,748 @@
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    task::{JoinHandle, LocalSet},
+};
+use wayk_proto::{
+    automation::{AutomationStep, FrameDecoder, Framebuffer, ImagePattern, NowAutomation},
+    channels_manager::{ChannelHandler, ChannelsManager},
+    client::{NowClient, NowClientConfig},
+    error::ProtoError,
+    message::{
+        AuthType, ChannelName, Codec, DisconnectStatusCode, EdgeRect, EventMouseFlags, HandoffToken, NowBody,
+        NowCapset, NowChatMsg, NowCodecDef, NowMessage, NowSessionHandoffTokenReqMsg, NowSessionHandoffTokenRspMsg,
+        NowSessionMsg, NowSurfaceDef, NowSurfaceListReqMsg, NowSurfaceListRspMsg, NowSurfaceMsg, NowUpdateGraphicsMsg,
+        NowUpdateMsg, SizeRect, SurfaceResponseFlags, TransportCapset, UpdateGraphicsFlags,
+    },
+    packet::{NowPacket, NowPacketAccumulator},
+    serialization::Encode,
+    server::{
+        NowAsyncServer, NowServerSession, NowSessionConfig, NowSessionFactory, SessionId, SessionPool, SessionPoolEvent,
+    },
+    sharee::{Sharee, ShareeCallbackTrait, ShareeResult, ShareeState},
+    sm::{ClientConnectionSeqSM, DummyConnectionSeqCallback, HandoffRegistry, HandoffRegistryRc},
+    transport::StreamTransport,
+};
+
+struct Factory;
+
+impl NowSessionFactory for Factory {
+    fn new_session(&mut self, _: SessionId) -> NowSessionConfig {
+        NowSessionConfig::new()
+            .available_auth_process(vec![AuthType::SRP, AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![ChannelName::Chat])
+    }
+}
+
+/// Answers surface lists and records the received surface ids.
+struct ClientCallback(Rc<RefCell<Vec<u16>>>);
+
+impl ShareeCallbackTrait for ClientCallback {
+    fn on_unprocessed_message<'msg: 'a, 'a>(&mut self, message: &'a NowMessage<'msg>) -> ShareeResult<'msg> {
+        match message {
+            NowMessage::Surface(NowSurfaceMsg::ListReq(req)) => {
+                *self.0.borrow_mut() = req.surfaces.iter().map(|def| def.surface_id).collect();
+                Ok(Some(NowPacket::from_message(NowSurfaceMsg::ListRsp(
+                    NowSurfaceListRspMsg::new(SurfaceResponseFlags::new_empty(), req.sequence_id),
+                ))))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+async fn run_client(mut stream: DuplexStream, surfaces: Rc<RefCell<Vec<u16>>>) {
+    let connection_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .channels_to_open(vec![ChannelName::Chat])
+        .build();
+    let mut sharee = Sharee::new(connection_seq, ChannelsManager::new(), ClientCallback(surfaces));
+
+    let mut acc = NowPacketAccumulator::new();
+    let mut buf = [0; 512];
+    while sharee.is_running() {
+        while sharee.is_running() && !sharee.waiting_for_packet(Instant::now()) {
+            if let Some(packet) = sharee.update_without_body(Instant::now()).unwrap() {
+                stream.write_all(&packet.encode().unwrap()).await.unwrap();
+            }
+        }
+
+        let answer = match acc.next_packet(sharee.get_channels_ctx()) {
+            Some(packet) => sharee
+                .update_with_body(&packet.unwrap().body, Instant::now())
+                .unwrap()
+                .map(|answer| answer.encode().unwrap()),
+            None => {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                acc.accumulate(&buf[..n]);
+                None
+            }
+        };
+        acc.purge_old_packets();
+
+        if let Some(answer) = answer {
+            stream.write_all(&answer).await.unwrap();
+        }
+    }
+}
+
+async fn exchange_surface_list(session: &mut NowServerSession) {
+    let list = NowSurfaceListReqMsg::new_with_surfaces(
+        session.id() as u16,
+        1920,
+        1080,
+        vec![NowSurfaceDef::new(session.id() as u16 + 1, EdgeRect::default())],
+    );
+    session.send(NowSurfaceMsg::ListReq(list)).unwrap();
+
+    let rsp = session.recv().await.unwrap();
+    match rsp.packet().unwrap().body {
+        NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListRsp(rsp))) => {
+            assert_eq!(rsp.sequence_id, session.id() as u16)
+        }
+        other => panic!("expected a surface list response, got {:?}", other),
+    }
+}
+
+async fn wait_client(client: JoinHandle<()>) {
+    tokio::time::timeout(Duration::from_secs(5), client)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[test]
+fn three_concurrent_sessions_exchange_surface_lists() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    LocalSet::new().block_on(&runtime, async {
+        let mut server = NowAsyncServer::builder(Factory)
+            .handshake_timeout(Duration::from_secs(5))
+            .max_sessions(3)
+            .build();
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let (client_stream, server_stream) = tokio::io::duplex(4096);
+            server.accept(server_stream).unwrap();
+
+            let surfaces = Rc::new(RefCell::new(Vec::new()));
+            let client = tokio::task::spawn_local(run_client(client_stream, Rc::clone(&surfaces)));
+            clients.push((client, surfaces));
+        }
+
+        // maximum count of concurrent sessions reached
+        let (_, extra_stream) = tokio::io::duplex(4096);
+        assert!(server.accept(extra_stream).is_err());
+
+        let mut sessions = Vec::new();
+        for _ in 0..3 {
+            let session = tokio::time::timeout(Duration::from_secs(5), server.next_session())
+                .await
+                .unwrap()
+                .unwrap();
+            sessions.push(session);
+        }
+
+        for session in &mut sessions {
+            exchange_surface_list(session).await;
+        }
+
+        // sessions are cancelled independently
+        let mut clients = clients.into_iter();
+        let mut sessions = sessions.into_iter();
+        sessions.next().unwrap().cancel();
+        let (cancelled_client, surfaces) = clients.next().unwrap();
+        wait_client(cancelled_client).await;
+        assert_eq!(*surfaces.borrow(), vec![1]);
+
+        for mut session in sessions {
+            assert!(!session.is_closed());
+            exchange_surface_list(&mut session).await;
+        }
+
+        for (client, surfaces) in clients {
+            wait_client(client).await;
+            assert_eq!(surfaces.borrow().len(), 1);
+        }
+    });
+}
+
+#[test]
+fn session_pool_multiplexes_sessions() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    LocalSet::new().block_on(&runtime, async {
+        let mut server = NowAsyncServer::builder(Factory)
+            .handshake_timeout(Duration::from_secs(5))
+            .build();
+        let mut pool = SessionPool::new(2);
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let (client_stream, server_stream) = tokio::io::duplex(4096);
+            server.accept(server_stream).unwrap();
+            let surfaces = Rc::new(RefCell::new(Vec::new()));
+            clients.push(tokio::task::spawn_local(run_client(client_stream, surfaces)));
+        }
+
+        for _ in 0..3 {
+            let session = tokio::time::timeout(Duration::from_secs(5), server.next_session())
+                .await
+                .unwrap()
+                .unwrap();
+            let _ = pool.insert(session);
+        }
+
+        // third session refused and cancelled
+        assert_eq!(pool.len(), 2);
+        assert!(pool.is_full());
+        let refused = (0..3).find(|id| !pool.contains(*id)).unwrap();
+        wait_client(clients.remove(refused as usize)).await;
+
+        for id in pool.ids().collect::<Vec<_>>() {
+            let list = NowSurfaceListReqMsg::new_with_surfaces(id as u16, 1920, 1080, Vec::new());
+            pool.send(id, NowSurfaceMsg::ListReq(list)).unwrap();
+        }
+
+        let mut answered = Vec::new();
+        for _ in 0..2 {
+            match tokio::time::timeout(Duration::from_secs(5), pool.next_event())
+                .await
+                .unwrap()
+            {
+                Some(SessionPoolEvent::Packet(id, packet)) => match packet.packet().unwrap().body {
+                    NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListRsp(rsp))) => {
+                        assert_eq!(rsp.sequence_id, id as u16);
+                        answered.push(id);
+                    }
+                    other => panic!("expected a surface list response, got {:?}", other),
+                },
+                other => panic!("expected a packet, got {:?}", other),
+            }
+        }
+        answered.sort_unstable();
+        assert_eq!(answered, pool.ids().collect::<Vec<_>>());
+
+        // closed sessions are removed from the pool
+        let first = pool.ids().next().unwrap();
+        clients.remove(0).abort();
+        match tokio::time::timeout(Duration::from_secs(5), pool.next_event())
+            .await
+            .unwrap()
+        {
+            Some(SessionPoolEvent::Closed(id)) => assert_eq!(id, first),
+            other => panic!("expected a closed session, got {:?}", other),
+        }
+        assert_eq!(pool.len(), 1);
+
+        pool.cancel_all();
+        assert!(pool.next_event().await.is_none());
+        wait_client(clients.remove(0)).await;
+    });
+}
+
+#[test]
+fn client_session_connects_in_one_call() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    LocalSet::new().block_on(&runtime, async {
+        let mut server = NowAsyncServer::builder(Factory)
+            .handshake_timeout(Duration::from_secs(5))
+            .build();
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        server.accept(server_stream).unwrap();
+
+        let config = NowClientConfig::new()
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .channels_to_open(vec![ChannelName::Chat]);
+        let (client, session) = tokio::join!(NowClient::connect(client_stream, config), server.next_session());
+        let mut client = client.unwrap();
+        let mut session = session.unwrap();
+        assert!(client.chat().is_some());
+        assert!(client.clipboard().is_none());
+
+        let surfaces = vec![NowSurfaceDef::new(1, EdgeRect::default())];
+        let list = NowSurfaceListReqMsg::new_with_surfaces(7, 1920, 1080, surfaces);
+        session.send(NowSurfaceMsg::ListReq(list)).unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), client.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        match received.packet().unwrap().body {
+            NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListReq(req))) => assert_eq!(req.sequence_id, 7),
+            other => panic!("expected a surface list request, got {:?}", other),
+        }
+        assert_eq!(client.surfaces().surfaces().count(), 1);
+
+        client.input().mouse(EventMouseFlags::ButtonLeft, 10, 20).await.unwrap();
+        client.input().key(0x01, 0x1e).await.unwrap();
+        for _ in 0..2 {
+            let received = tokio::time::timeout(Duration::from_secs(5), session.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match received.packet().unwrap().body {
+                NowBody::Message(NowMessage::Input(input)) => assert_eq!(input.events().len(), 1),
+                other => panic!("expected an input message, got {:?}", other),
+            }
+        }
+
+        session.cancel();
+        let closed = tokio::time::timeout(Duration::from_secs(5), client.recv())
+            .await
+            .unwrap();
+        assert!(closed.unwrap().is_none());
+        assert!(client.is_terminated());
+    });
+}
+
+/// Update data holding little endian pixels.
+struct RawDecoder;
+
+impl FrameDecoder for RawDecoder {
+    fn decode(&mut self, update: &NowUpdateGraphicsMsg<'_>) -> Result<Option<Framebuffer>, ProtoError> {
+        let pixels = update
+            .update_data
+            .chunks(4)
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+        Framebuffer::from_pixels(update.update_rect.width, update.update_rect.height, pixels).map(Some)
+    }
+}
+
+#[test]
+fn automation_waits_for_image_and_clicks() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    LocalSet::new().block_on(&runtime, async {
+        let mut server = NowAsyncServer::builder(Factory)
+            .handshake_timeout(Duration::from_secs(5))
+            .build();
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        server.accept(server_stream).unwrap();
+
+        let config = NowClientConfig::new()
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())]);
+        let (automation, session) = tokio::join!(
+            NowAutomation::connect(client_stream, config, RawDecoder),
+            server.next_session()
+        );
+        let mut automation = automation.unwrap();
+        let mut session = session.unwrap();
+
+        let button: Vec<u8> = [0x00ff_0000u32; 4]
+            .iter()
+            .flat_map(|pixel| pixel.to_le_bytes())
+            .collect();
+        let rect = SizeRect {
+            x: 4,
+            y: 2,
+            width: 2,
+            height: 2,
+        };
+        let update = NowUpdateGraphicsMsg::new(
+            Codec::Unspecified,
+            1,
+            0,
+            UpdateGraphicsFlags::new_empty(),
+            rect,
+            &button,
+        );
+        session
+            .send(NowMessage::from(NowUpdateMsg::UpdateGraphics(update)))
+            .unwrap();
+
+        let pattern = ImagePattern::new(Framebuffer::from_pixels(2, 2, vec![0x00ff_0000; 4]).unwrap());
+        let position = automation
+            .wait_for_image(&pattern, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(position, (4, 2));
+
+        let screenshots = automation
+            .run_script(&[
+                AutomationStep::Click {
+                    button: EventMouseFlags::ButtonLeft,
+                    x: 5,
+                    y: 3,
+                },
+                AutomationStep::Screenshot,
+            ])
+            .await
+            .unwrap();
+        assert_eq!(screenshots.len(), 1);
+        assert_eq!(screenshots[0].pixel(5, 3), Some(0x00ff_0000));
+
+        for _ in 0..2 {
+            let received = tokio::time::timeout(Duration::from_secs(5), session.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match received.packet().unwrap().body {
+                NowBody::Message(NowMessage::Input(input)) => assert_eq!(input.events().len(), 1),
+                other => panic!("expected an input message, got {:?}", other),
+            }
+        }
+
+        let missing = ImagePattern::new(Framebuffer::from_pixels(1, 1, vec![0x0000_00ff]).unwrap());
+        assert!(automation
+            .wait_for_image(&missing, Duration::from_millis(50))
+            .await
+            .is_err());
+    });
+}
+
+struct SurfacesFactory;
+
+impl NowSessionFactory for SurfacesFactory {
+    fn new_session(&mut self, _: SessionId) -> NowSessionConfig {
+        Factory.new_session(0).surfaces(
+            3840,
+            1080,
+            vec![
+                NowSurfaceDef::new(1, EdgeRect::default()),
+                NowSurfaceDef::new(2, EdgeRect::default()),
+            ],
+        )
+    }
+}
+
+#[test]
+fn host_accepts_and_advertises_surfaces() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    LocalSet::new().block_on(&runtime, async {
+        let mut server = NowAsyncServer::builder(SurfacesFactory)
+            .handshake_timeout(Duration::from_secs(5))
+            .build();
+
+        // stands for a listener, accepting through a shared reference
+        let (incoming_tx, incoming_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        incoming_tx.send(server_stream).unwrap();
+        let listener = &tokio::sync::Mutex::new(incoming_rx);
+        let accept = || async move {
+            listener
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotConnected))
+        };
+
+        let config = NowClientConfig::new()
+            .available_auth_process(vec![AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())]);
+        let (client, session) = tokio::join!(
+            NowClient::connect(client_stream, config),
+            server.next_session_from(accept)
+        );
+        let mut client = client.unwrap();
+        assert_eq!(session.unwrap().id(), 0);
+
+        let received = tokio::time::timeout(Duration::from_secs(5), client.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        match received.packet().unwrap().body {
+            NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListReq(req))) => {
+                assert_eq!(req.desktop_width, 3840);
+                assert_eq!(req.surfaces.len(), 2);
+            }
+            other => panic!("expected a surface list request, got {:?}", other),
+        }
+        assert_eq!(client.surfaces().surfaces().count(), 2);
+    });
+}
+
+#[test]
+fn client_reattaches_over_new_transport() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    LocalSet::new().block_on(&runtime, async {
+        let mut server = NowAsyncServer::builder(Factory)
+            .handshake_timeout(Duration::from_secs(5))
+            .build();
+        let config = || {
+            NowClientConfig::new()
+                .available_auth_process(vec![AuthType::PFP])
+                .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+                .channels_to_open(vec![ChannelName::Chat])
+        };
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        server.accept(server_stream).unwrap();
+        let (client, session) = tokio::join!(NowClient::connect(client_stream, config()), server.next_session());
+        let mut client = client.unwrap();
+        let session = session.unwrap();
+
+        let list =
+            NowSurfaceListReqMsg::new_with_surfaces(1, 1920, 1080, vec![NowSurfaceDef::new(3, EdgeRect::default())]);
+        session.send(NowSurfaceMsg::ListReq(list)).unwrap();
+        assert!(client.recv().await.unwrap().is_some());
+
+        // network blip: the link dies under the client
+        drop(session);
+        let lost = tokio::time::timeout(Duration::from_secs(5), client.recv())
+            .await
+            .unwrap();
+        assert!(!matches!(lost, Ok(Some(_))));
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        server.accept(server_stream).unwrap();
+        let (reattached, session) = tokio::join!(
+            client.reattach(StreamTransport::new(client_stream), config()),
+            server.next_session()
+        );
+        reattached.unwrap();
+        let mut session = session.unwrap();
+        assert!(client.chat().is_some());
+        // state of the previous connection survived
+        assert_eq!(client.surfaces().surfaces().count(), 1);
+
+        client.input().key(0x01, 0x1e).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), session.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            received.packet().unwrap().body,
+            NowBody::Message(NowMessage::Input(_))
+        ));
+    });
+}
+
+struct HandoffFactory(HandoffRegistryRc);
+
+impl NowSessionFactory for HandoffFactory {
+    fn new_session(&mut self, id: SessionId) -> NowSessionConfig {
+        Factory.new_session(id).handoff_registry(Rc::clone(&self.0))
+    }
+}
+
+/// Hands the handoff token out and records why the session was terminated.
+struct Viewer {
+    token: Option<tokio::sync::oneshot::Sender<NowSessionHandoffTokenRspMsg>>,
+    terminated: Rc<RefCell<Option<DisconnectStatusCode>>>,
+}
+
+impl ShareeCallbackTrait for Viewer {
+    fn on_any_message<'msg: 'a, 'a>(&mut self, message: &'a NowMessage<'msg>) {
+        if let NowMessage::Terminate(msg) = message {
+            *self.terminated.borrow_mut() = Some(msg.status.code());
+        }
+    }
+
+    fn on_unprocessed_message<'msg: 'a, 'a>(&mut self, message: &'a NowMessage<'msg>) -> ShareeResult<'msg> {
+        if let (NowMessage::Session(NowSessionMsg::HandoffTokenRsp(rsp)), Some(token)) = (message, self.token.take()) {
+            let _ = token.send(rsp.clone());
+        }
+        Ok(None)
+    }
+}
+
+/// Connects a viewer, taking over `handoff` if any. A handoff token is requested once connected
+/// if the viewer expects one.
+async fn run_viewer(mut stream: DuplexStream, handoff: Option<(u32, HandoffToken)>, viewer: Viewer) {
+    let mut builder = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .channels_to_open(vec![ChannelName::Chat]);
+    if let Some((session_id, token)) = handoff {
+        builder = builder.handoff(session_id, token);
+    }
+    let mut request_token = viewer.token.is_some();
+    let mut sharee = Sharee::new(builder.build(), ChannelsManager::new(), viewer);
+
+    let mut acc = NowPacketAccumulator::new();
+    let mut buf = [0; 512];
+    while sharee.is_running() {
+        while sharee.is_running() && !sharee.waiting_for_packet(Instant::now()) {
+            if let Some(packet) = sharee.update_without_body(Instant::now()).unwrap() {
+                stream.write_all(&packet.encode().unwrap()).await.unwrap();
+            }
+        }
+
+        if request_token && sharee.get_state() == ShareeState::Active {
+            request_token = false;
+            let request = NowPacket::from_message(NowSessionMsg::from(NowSessionHandoffTokenReqMsg::new()));
+            stream.write_all(&request.encode().unwrap()).await.unwrap();
+        }
+
+        let answer = match acc.next_packet(sharee.get_channels_ctx()) {
+            Some(packet) => sharee
+                .update_with_body(&packet.unwrap().body, Instant::now())
+                .unwrap()
+                .map(|answer| answer.encode().unwrap()),
+            None => {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                acc.accumulate(&buf[..n]);
+                None
+            }
+        };
+        acc.purge_old_packets();
+
+        if let Some(answer) = answer {
+            stream.write_all(&answer).await.unwrap();
+        }
+    }
+}
+
+#[test]
+fn handed_off_viewer_is_disconnected() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    LocalSet::new().block_on(&runtime, async {
+        let registry = HandoffRegistry::new(Duration::from_secs(60)).into_rc();
+        let mut next_token = 0;
+        registry.borrow_mut().set_token_source(move || {
+            next_token += 1;
+            [next_token, 0, 0, 0]
+        });
+        let mut server = NowAsyncServer::builder(HandoffFactory(Rc::clone(&registry)))
+            .handshake_timeout(Duration::from_secs(5))
+            .build();
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        server.accept(server_stream).unwrap();
+        let (token_tx, token_rx) = tokio::sync::oneshot::channel();
+        let original_terminated = Rc::new(RefCell::new(None));
+        let original = tokio::task::spawn_local(run_viewer(
+            client_stream,
+            None,
+            Viewer {
+                token: Some(token_tx),
+                terminated: Rc::clone(&original_terminated),
+            },
+        ));
+        let original_session = server.next_session().await.unwrap();
+
+        let rsp = tokio::time::timeout(Duration::from_secs(5), token_rx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rsp.session_id, original_session.id());
+        assert_eq!(rsp.token, [1, 0, 0, 0]);
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        server.accept(server_stream).unwrap();
+        let new_terminated = Rc::new(RefCell::new(None));
+        let new_viewer = tokio::task::spawn_local(run_viewer(
+            client_stream,
+            Some((rsp.session_id, rsp.token)),
+            Viewer {
+                token: None,
+                terminated: Rc::clone(&new_terminated),
+            },
+        ));
+        let new_session = tokio::time::timeout(Duration::from_secs(5), server.next_session())
+            .await
+            .unwrap()
+            .unwrap();
+
+        wait_client(original).await;
+        assert_eq!(*original_terminated.borrow(), Some(DisconnectStatusCode::HandedOff));
+        assert!(original_session.is_closed());
+        assert!(!new_session.is_closed());
+        assert_eq!(registry.borrow().pending_count(), 0);
+
+        new_session.cancel();
+        wait_client(new_viewer).await;
+        assert_eq!(*new_terminated.borrow(), Some(DisconnectStatusCode::Success));
+    });
+}
+
+struct IgnoreChat;
+
+impl ChannelHandler for IgnoreChat {
+    type Message<'a> = NowChatMsg;
+
+    fn on_message(&mut self, _: &NowChatMsg) -> Result<(), ProtoError> {
+        Ok(())
+    }
+
+    fn poll_outgoing(&mut self) -> Option<NowChatMsg> {
+        None
+    }
+}
+
+#[test]
+fn client_config_derives_advertised_sets() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    LocalSet::new().block_on(&runtime, async {
+        let mut server = NowAsyncServer::builder(Factory)
+            .handshake_timeout(Duration::from_secs(5))
+            .build();
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        server.accept(server_stream).unwrap();
+
+        let mut channels_manager = ChannelsManager::new();
+        channels_manager.register(ChannelName::Chat, IgnoreChat);
+        let config = NowClientConfig::new()
+            .available_auth_process(vec![AuthType::PFP])
+            .capability(NowCapset::Transport(TransportCapset::default()))
+            .codecs(vec![NowCodecDef::new(Codec::Thor)])
+            .codecs(vec![NowCodecDef::new(Codec::JPEG), NowCodecDef::new(Codec::GFWX)])
+            .channels_manager(channels_manager)
+            .connect_timeout(Duration::from_secs(5));
+
+        assert_eq!(config.get_channels_to_open(), vec![ChannelName::Chat]);
+        assert_eq!(config.get_timeouts().connect, Some(Duration::from_secs(5)));
+        let capsets: Vec<&str> = config.get_capabilities().iter().map(NowCapset::name_as_str).collect();
+        assert_eq!(capsets, vec!["NowTransport", "NowUpdate"]);
+        match &config.get_capabilities()[1] {
+            NowCapset::Update(capset) => {
+                let codecs: Vec<Codec> = capset.codecs.iter().map(|codec| codec.id).collect();
+                assert_eq!(codecs, vec![Codec::JPEG, Codec::GFWX]);
+            }
+            other => panic!("unexpected capability set: {:?}", other),
+        }
+
+        let (client, session) = tokio::join!(NowClient::connect(client_stream, config), server.next_session());
+        let mut client = client.unwrap();
+        session.unwrap();
+        assert!(client.chat().is_some());
+    });
+}
@@ -0,0 +1,151 @@
+use std::{cell::RefCell, convert::TryFrom, rc::Rc, sync::Arc};
+use wayk_proto::{
+    channels_manager::ChannelsManager,
+    message::{AuthType, ChannelName, NowBody, NowCapset, NowMessage, NowTerminateMsg, TransportCapset},
+    sharee::{DummyShareeCallback, Sharee, ShareeState},
+    sm::{
+        ClientConnectionSeqSM, ConnectionSM, ConnectionSMResult, ConnectionSMSharedDataRc, DummyConnectionSeqCallback,
+        ServerConnectionSeqSM,
+    },
+    transport::{
+        tls::{
+            rustls::{
+                pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+                ClientConfig, RootCertStore, ServerConfig,
+            },
+            TlsConfig,
+        },
+        NowTransport,
+    },
+};
+
+/// Authentication recording the certificate of the server, as seen when it starts.
+struct FingerprintAuth {
+    shared_data: Option<ConnectionSMSharedDataRc>,
+    peer_certificate: Rc<RefCell<Option<Vec<u8>>>>,
+    done: bool,
+}
+
+impl ConnectionSM for FingerprintAuth {
+    fn set_shared_data(&mut self, shared_data: ConnectionSMSharedDataRc) {
+        self.shared_data = Some(shared_data);
+    }
+
+    fn get_shared_data(&self) -> Option<ConnectionSMSharedDataRc> {
+        self.shared_data.clone()
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+
+    fn waiting_for_packet(&self) -> bool {
+        false
+    }
+
+    fn update_without_message<'msg>(&mut self) -> ConnectionSMResult<'msg> {
+        let shared_data = self.shared_data.as_ref().unwrap().borrow();
+        *self.peer_certificate.borrow_mut() = shared_data.peer_certificate.clone();
+        self.done = true;
+        Ok(None)
+    }
+
+    fn update_with_message<'msg: 'a, 'a>(&mut self, _: &'a NowMessage<'msg>) -> ConnectionSMResult<'msg> {
+        unreachable!()
+    }
+}
+
+fn tls_configs() -> (TlsConfig, TlsConfig, Vec<u8>) {
+    let _ = wayk_proto::transport::tls::rustls::crypto::ring::default_provider().install_default();
+
+    let certified = rcgen::generate_simple_self_signed(vec!["wayk.local".to_owned()]).unwrap();
+    let certificate = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certificate.clone()).unwrap();
+    let client_config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate.clone()], key)
+        .unwrap();
+
+    let server_name = ServerName::try_from("wayk.local").unwrap();
+    (
+        TlsConfig::client(Arc::new(client_config), server_name),
+        TlsConfig::server(Arc::new(server_config)),
+        certificate.to_vec(),
+    )
+}
+
+#[tokio::test]
+async fn connection_upgraded_to_tls_after_negotiation() {
+    let (client_tls, server_tls, certificate) = tls_configs();
+    let (client_stream, server_stream) = tokio::io::duplex(1024);
+
+    let peer_certificate = Rc::new(RefCell::new(None));
+    let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .authenticate_sm(FingerprintAuth {
+            shared_data: None,
+            peer_certificate: Rc::clone(&peer_certificate),
+            done: false,
+        })
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .channels_to_open(vec![ChannelName::Chat])
+        .tls(true)
+        .build();
+    let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .available_channels(vec![ChannelName::Chat])
+        .tls(true)
+        .build();
+    let mut client_sharee = Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback);
+    let mut server_sharee = Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback);
+
+    let (client, server) = tokio::join!(
+        NowTransport::new(client_stream).connect_tls(&mut client_sharee, client_tls),
+        NowTransport::new(server_stream).connect_tls(&mut server_sharee, server_tls),
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    assert_eq!(client_sharee.get_state(), ShareeState::Active);
+    assert_eq!(server_sharee.get_state(), ShareeState::Active);
+    assert_eq!(client.peer_certificate(), Some(certificate.as_slice()));
+    assert_eq!(server.peer_certificate(), None);
+    assert_eq!(peer_certificate.borrow().as_deref(), Some(certificate.as_slice()));
+
+    server.send(NowTerminateMsg::default()).await.unwrap();
+    match client.recv().await.unwrap().unwrap().body {
+        NowBody::Message(NowMessage::Terminate(_)) => {}
+        other => panic!("expected a terminate message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn connection_fails_when_peer_refuses_tls() {
+    let (client_tls, _, _) = tls_configs();
+    let (client_stream, server_stream) = tokio::io::duplex(1024);
+
+    let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .tls(true)
+        .build();
+    let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .build();
+    let mut client_sharee = Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback);
+    let mut server_sharee = Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback);
+
+    let mut server = NowTransport::new(server_stream);
+    let client = NowTransport::new(client_stream);
+    let (client, _) = tokio::join!(client.connect_tls(&mut client_sharee, client_tls), async {
+        let _ = server.connect(&mut server_sharee).await;
+        drop(server);
+    });
+    assert!(client.is_err());
+    assert_ne!(client_sharee.get_state(), ShareeState::Active);
+}
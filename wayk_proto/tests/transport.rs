@@ -0,0 +1,911 @@
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc, task::LocalSet};
+use wayk_proto::{
+    channels_manager::ChannelsManager,
+    error::ProtoErrorKind,
+    message::{
+        AuthType, ChannelName, DisconnectStatusCode, EdgeRect, NowBody, NowCapset, NowHandshakeMsg, NowMessage,
+        NowNetworkKeepAliveReqMsg, NowNetworkKeepAliveRspMsg, NowNetworkMsg, NowSurfaceDef, NowSurfaceListReqMsg,
+        NowSurfaceListRspMsg, NowSurfaceMsg, SurfaceResponseFlags, TransportCapset,
+    },
+    packet::NowPacket,
+    send_queue::RateLimit,
+    serialization::Encode,
+    server::{NowAsyncServer, NowSessionConfig, NowSessionFactory, SessionId},
+    sharee::{DummyShareeCallback, Sharee, ShareeCallbackTrait, ShareeResult, ShareeState},
+    sm::{
+        ChannelRateLimiter, ClientConnectionSeqSM, ConnectionState, DummyConnectionSeqCallback, Heartbeat,
+        ServerConnectionSeqSM, SessionMetrics, StepTimeouts,
+    },
+    transport::{
+        loopback::{self, LoopbackConfig},
+        reconnect::{Backoff, ReconnectingTransport},
+        CancellationToken, FrameBatching, FramedSender, MemoryTransport, NowTransport, ShutdownOutcome,
+        StreamTransport, TransportEvent, TransportTimeouts,
+    },
+};
+
+struct Factory;
+
+impl NowSessionFactory for Factory {
+    fn new_session(&mut self, _: SessionId) -> NowSessionConfig {
+        NowSessionConfig::new()
+            .available_auth_process(vec![AuthType::SRP, AuthType::PFP])
+            .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+            .available_channels(vec![ChannelName::Chat])
+    }
+}
+
+/// Answers surface lists and records the received surface ids.
+struct ClientCallback(Rc<RefCell<Vec<u16>>>);
+
+impl ShareeCallbackTrait for ClientCallback {
+    fn on_unprocessed_message<'msg: 'a, 'a>(&mut self, message: &'a NowMessage<'msg>) -> ShareeResult<'msg> {
+        match message {
+            NowMessage::Surface(NowSurfaceMsg::ListReq(req)) => {
+                *self.0.borrow_mut() = req.surfaces.iter().map(|def| def.surface_id).collect();
+                Ok(Some(NowPacket::from_message(NowSurfaceMsg::ListRsp(
+                    NowSurfaceListRspMsg::new(SurfaceResponseFlags::new_empty(), req.sequence_id),
+                ))))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[tokio::test]
+async fn packets_round_trip() {
+    let (client_stream, server_stream) = tokio::io::duplex(64);
+    let mut client = NowTransport::new(client_stream);
+    let mut server = NowTransport::new(server_stream);
+
+    let surfaces = (1..=8).map(|id| NowSurfaceDef::new(id, EdgeRect::default())).collect();
+    let list = NowSurfaceListReqMsg::new_with_surfaces(1, 1920, 1080, surfaces);
+    // larger than the duplex buffer: written and read in several chunks
+    let (sent, received) = tokio::join!(client.send(NowSurfaceMsg::ListReq(list)), server.recv());
+    sent.unwrap();
+    match received.unwrap().unwrap().body {
+        NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListReq(req))) => assert_eq!(req.surfaces.len(), 8),
+        other => panic!("expected a surface list request, got {:?}", other),
+    }
+
+    drop(client);
+    assert!(server.recv().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn sharees_connect_over_memory_transport() {
+    let (client_link, server_link) = MemoryTransport::pair();
+    let mut client = NowTransport::with_transport(client_link);
+    let mut server = NowTransport::with_transport(server_link);
+
+    let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .channels_to_open(vec![ChannelName::Chat])
+        .build();
+    let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::SRP, AuthType::PFP])
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .available_channels(vec![ChannelName::Chat])
+        .build();
+    let surfaces = Rc::new(RefCell::new(Vec::new()));
+    let mut client_sharee = Sharee::new(client_seq, ChannelsManager::new(), ClientCallback(Rc::clone(&surfaces)));
+    let mut server_sharee = Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback);
+
+    let (client_connected, server_connected) =
+        tokio::join!(client.connect(&mut client_sharee), server.connect(&mut server_sharee));
+    client_connected.unwrap();
+    server_connected.unwrap();
+    assert!(server
+        .get_channels_ctx()
+        .get_id_by_channel(&ChannelName::Chat)
+        .is_some());
+
+    let list = NowSurfaceListReqMsg::new_with_surfaces(4, 1920, 1080, vec![NowSurfaceDef::new(9, EdgeRect::default())]);
+    server.send(NowSurfaceMsg::ListReq(list)).await.unwrap();
+    server.close().await.unwrap();
+    client.run(&mut client_sharee).await.unwrap();
+    assert_eq!(*surfaces.borrow(), vec![9]);
+
+    match server.recv().await.unwrap().unwrap().body {
+        NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListRsp(rsp))) => assert_eq!(rsp.sequence_id, 4),
+        other => panic!("expected a surface list response, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn sharees_connect_over_delayed_loopback() {
+    let (client_link, server_link) = loopback::pair_with(LoopbackConfig::new().latency(Duration::from_millis(5)));
+    let mut client = NowTransport::with_transport(client_link);
+    let mut server = NowTransport::with_transport(server_link);
+
+    let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .channels_to_open(vec![ChannelName::Chat])
+        .build();
+    let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .available_channels(vec![ChannelName::Chat])
+        .build();
+    let mut client_sharee = Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback);
+    let mut server_sharee = Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback);
+
+    let start = Instant::now();
+    let (client_connected, server_connected) =
+        tokio::join!(client.connect(&mut client_sharee), server.connect(&mut server_sharee));
+    client_connected.unwrap();
+    server_connected.unwrap();
+    assert_eq!(client_sharee.get_state(), ShareeState::Active);
+    // several round trips
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[tokio::test]
+async fn loopback_reorders_frames() {
+    let config = LoopbackConfig::new().reordering(0.5, Duration::from_millis(10)).seed(7);
+    let (client_link, server_link) = loopback::pair_with(config);
+    let mut client = NowTransport::with_transport(client_link);
+    let mut server = NowTransport::with_transport(server_link);
+
+    for sequence_id in 0..16 {
+        client
+            .send(NowNetworkMsg::from(NowNetworkKeepAliveReqMsg::new(sequence_id, 0)))
+            .await
+            .unwrap();
+    }
+    client.close().await.unwrap();
+
+    let mut received = Vec::new();
+    while let Some(packet) = server.recv().await.unwrap() {
+        match packet.body {
+            NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveReq(req))) => received.push(req.sequence_id),
+            other => panic!("expected a keep-alive request, got {:?}", other),
+        }
+    }
+
+    // everything is delivered, but not in order
+    assert_ne!(received, (0..16).collect::<Vec<_>>());
+    received.sort_unstable();
+    assert_eq!(received, (0..16).collect::<Vec<_>>());
+}
+
+#[cfg(feature = "compression")]
+#[tokio::test]
+async fn compression_negotiated_by_capabilities() {
+    use wayk_proto::message::TransportCapsetFlags;
+
+    let (client_link, server_link) = MemoryTransport::pair();
+    let mut client = NowTransport::with_transport(client_link);
+    let mut server = NowTransport::with_transport(server_link);
+
+    let capabilities = || {
+        vec![NowCapset::Transport(TransportCapset::new(
+            TransportCapsetFlags::new_empty().set_compression(),
+        ))]
+    };
+    let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(capabilities())
+        .build();
+    let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(capabilities())
+        .build();
+    let mut client_sharee = Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback);
+    let mut server_sharee = Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback);
+
+    let (client_connected, server_connected) =
+        tokio::join!(client.connect(&mut client_sharee), server.connect(&mut server_sharee));
+    client_connected.unwrap();
+    server_connected.unwrap();
+    assert!(client.is_compressing());
+    assert!(server.is_compressing());
+
+    let surfaces = (1..=64).map(|id| NowSurfaceDef::new(id, EdgeRect::default())).collect();
+    let list = NowSurfaceListReqMsg::new_with_surfaces(1, 1920, 1080, surfaces);
+    server.send(NowSurfaceMsg::ListReq(list)).await.unwrap();
+    match client.recv().await.unwrap().unwrap().body {
+        NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListReq(req))) => assert_eq!(req.surfaces.len(), 64),
+        other => panic!("expected a surface list request, got {:?}", other),
+    }
+}
+
+#[cfg(feature = "compression")]
+#[tokio::test]
+async fn channel_opened_after_connecting_compressed() {
+    use core::str::FromStr;
+    use wayk_proto::{
+        channels_manager::ChannelHandler,
+        compression::ChannelCompression,
+        error::ProtoError,
+        message::{NowChatMsg, NowChatTextMsg, NowString65535},
+        sm::ChannelLifecycleState,
+    };
+
+    /// Records the received chat texts.
+    struct ChatRecorder(Rc<RefCell<Vec<String>>>);
+
+    impl ChannelHandler for ChatRecorder {
+        type Message<'a> = NowChatMsg;
+
+        fn on_message(&mut self, message: &NowChatMsg) -> Result<(), ProtoError> {
+            if let NowChatMsg::Text(msg) = message {
+                self.0.borrow_mut().push(msg.text.as_str().to_owned());
+            }
+            Ok(())
+        }
+
+        fn poll_outgoing(&mut self) -> Option<NowChatMsg> {
+            None
+        }
+    }
+
+    let (client_link, server_link) = MemoryTransport::pair();
+    let mut client = NowTransport::with_transport(client_link);
+    let mut server = NowTransport::with_transport(server_link);
+    let channel_compression = ChannelCompression::new().channel(ChannelName::Chat).threshold(0);
+    client.set_channel_compression(channel_compression.clone());
+    server.set_channel_compression(channel_compression.clone());
+
+    let capabilities = || {
+        vec![
+            NowCapset::Transport(TransportCapset::default()),
+            channel_compression.capset(),
+        ]
+    };
+    let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(capabilities())
+        .build();
+    let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(capabilities())
+        .available_channels(vec![ChannelName::Chat])
+        .build();
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let mut client_channels = ChannelsManager::new();
+    client_channels.register(ChannelName::Chat, ChatRecorder(Rc::new(RefCell::new(Vec::new()))));
+    let mut server_channels = ChannelsManager::new();
+    server_channels.register(ChannelName::Chat, ChatRecorder(Rc::clone(&received)));
+    let mut client_sharee = Sharee::new(client_seq, client_channels, DummyShareeCallback);
+    let mut server_sharee = Sharee::new(server_seq, server_channels, DummyShareeCallback);
+
+    let (client_connected, server_connected) =
+        tokio::join!(client.connect(&mut client_sharee), server.connect(&mut server_sharee));
+    client_connected.unwrap();
+    server_connected.unwrap();
+    assert!(client.get_channel_compression().is_negotiated(&ChannelName::Chat));
+    assert_eq!(client_sharee.channel_state(&ChannelName::Chat), None);
+
+    // the server opens the channel while running: its messages are decompressed from then on
+    let text = "compressed once opened ".repeat(64);
+    let chat = async {
+        let request = client_sharee.open_channel(ChannelName::Chat).unwrap();
+        client.send(request).await.unwrap();
+        let id = loop {
+            if let Some(ChannelLifecycleState::Open { id }) = client_sharee.channel_state(&ChannelName::Chat) {
+                break id;
+            }
+            let packet = client.recv().await.unwrap().unwrap();
+            client_sharee.update_with_body(&packet.body, Instant::now()).unwrap();
+        };
+        client.set_channels_ctx(client_sharee.get_channels_ctx().clone());
+
+        let msg = NowChatTextMsg::new(0, 1, NowString65535::from_str(&text).unwrap());
+        client.send(NowPacket::from_virt_channel(msg, id)).await.unwrap();
+        client
+            .shutdown(DisconnectStatusCode::ByLocalUser, Duration::from_secs(1))
+            .await
+            .unwrap();
+    };
+    let (_, served) = tokio::join!(chat, server.run(&mut server_sharee));
+    served.unwrap();
+    assert_eq!(*received.borrow(), vec![text.clone()]);
+}
+
+#[tokio::test]
+async fn framed_sender_applies_backpressure() {
+    let (client_stream, server_stream) = tokio::io::duplex(64);
+    let mut sender = FramedSender::new(client_stream).capacity(256);
+    let mut server = NowTransport::new(server_stream);
+
+    let list = |sequence_id| {
+        let surfaces = (1..=4).map(|id| NowSurfaceDef::new(id, EdgeRect::default())).collect();
+        NowSurfaceMsg::ListReq(NowSurfaceListReqMsg::new_with_surfaces(
+            sequence_id,
+            1920,
+            1080,
+            surfaces,
+        ))
+    };
+
+    // nothing is written until the queue is full
+    let mut queued = 0;
+    while sender.try_send(list(queued)).unwrap() {
+        queued += 1;
+    }
+    assert!(queued > 1);
+    assert!(sender.queued_bytes() <= 256);
+
+    let receiver = async {
+        for expected in 0..=queued {
+            match server.recv().await.unwrap().unwrap().body {
+                NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListReq(req))) => {
+                    assert_eq!(req.sequence_id, expected)
+                }
+                other => panic!("expected a surface list request, got {:?}", other),
+            }
+        }
+    };
+    let producer = async {
+        // waits for the reader to make room
+        sender.send(list(queued)).await.unwrap();
+        sender.flush().await.unwrap();
+    };
+    tokio::join!(receiver, producer);
+    assert!(sender.is_empty());
+}
+
+#[tokio::test]
+async fn batched_frames_flushed_after_max_delay() {
+    let (client_stream, server_stream) = tokio::io::duplex(4096);
+    let batching = FrameBatching::new().max_delay(Duration::from_millis(50));
+    let mut client = NowTransport::with_transport(StreamTransport::new(client_stream).batching(batching));
+    let mut server = NowTransport::new(server_stream);
+
+    for sequence_id in 0..10 {
+        client
+            .send(NowNetworkMsg::from(NowNetworkKeepAliveReqMsg::new(sequence_id, 0)))
+            .await
+            .unwrap();
+    }
+    assert!(client.get_transport().batched_bytes() > 0);
+    assert!(tokio::time::timeout(Duration::from_millis(10), server.recv())
+        .await
+        .is_err());
+
+    // held back frames are written while the client waits for packets
+    let receiver = async {
+        for expected in 0..10 {
+            let packet = tokio::time::timeout(Duration::from_secs(1), server.recv())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            match packet.body {
+                NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveReq(req))) => {
+                    assert_eq!(req.sequence_id, expected)
+                }
+                other => panic!("expected a keep-alive request, got {:?}", other),
+            }
+        }
+    };
+    let waiting = tokio::time::timeout(Duration::from_millis(200), client.recv());
+    let (_, timed_out) = tokio::join!(receiver, waiting);
+    assert!(timed_out.is_err());
+    assert_eq!(client.get_transport().batched_bytes(), 0);
+}
+
+#[tokio::test]
+async fn rate_limiter_throttles_outgoing_frames() {
+    let (client_link, _server_link) = MemoryTransport::pair();
+    let mut client = NowTransport::with_transport(client_link);
+    let rate_limiter = ChannelRateLimiter::new_rc();
+    rate_limiter
+        .borrow_mut()
+        .set_transport_limit(Some(RateLimit::new(4_000, 4_000)), Instant::now());
+    client.set_rate_limiter(Some(Rc::clone(&rate_limiter)));
+
+    let list = || {
+        let surfaces = (1..=64).map(|id| NowSurfaceDef::new(id, EdgeRect::default())).collect();
+        NowSurfaceMsg::ListReq(NowSurfaceListReqMsg::new_with_surfaces(0, 1920, 1080, surfaces))
+    };
+    let frame_len = NowPacket::from_message(list()).encode().unwrap().len();
+
+    assert!(frame_len >= 400);
+
+    // the first second worth of bytes is sent at once, the next frames wait for tokens
+    let start = Instant::now();
+    for _ in 0..4_000 / frame_len {
+        client.send(list()).await.unwrap();
+    }
+    assert!(start.elapsed() < Duration::from_millis(100));
+    client.send(list()).await.unwrap();
+    client.send(list()).await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    // limits are adjustable while the transport is in use
+    rate_limiter.borrow_mut().set_transport_limit(None, Instant::now());
+    let start = Instant::now();
+    for _ in 0..10 {
+        client.send(list()).await.unwrap();
+    }
+    assert!(start.elapsed() < Duration::from_millis(100));
+}
+
+#[cfg(feature = "quic")]
+#[tokio::test]
+async fn quic_lanes_avoid_head_of_line_blocking() {
+    use wayk_proto::{send_queue::TrafficClass, transport::quic::QuicTransport};
+
+    let (client_control, server_control) = tokio::io::duplex(4096);
+    let (client_realtime, server_realtime) = tokio::io::duplex(4096);
+    let quic_transport = |control, realtime| {
+        let (control_recv, control_send) = tokio::io::split(control);
+        let (realtime_recv, realtime_send) = tokio::io::split(realtime);
+        QuicTransport::new(control_recv, control_send).lane(TrafficClass::Realtime, realtime_recv, realtime_send)
+    };
+    let mut client = NowTransport::with_transport(quic_transport(client_control, client_realtime));
+    let mut server = NowTransport::with_transport(quic_transport(server_control, server_realtime));
+
+    let list = NowSurfaceListReqMsg::new_with_surfaces(8, 1920, 1080, Vec::new());
+    client.send(NowSurfaceMsg::ListReq(list)).await.unwrap();
+    client
+        .send(NowNetworkMsg::from(NowNetworkKeepAliveReqMsg::new(2, 0)))
+        .await
+        .unwrap();
+
+    // the realtime stream is read first
+    match server.recv().await.unwrap().unwrap().body {
+        NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveReq(req))) => assert_eq!(req.sequence_id, 2),
+        other => panic!("expected a keep-alive request, got {:?}", other),
+    }
+    match server.recv().await.unwrap().unwrap().body {
+        NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListReq(req))) => assert_eq!(req.sequence_id, 8),
+        other => panic!("expected a surface list request, got {:?}", other),
+    }
+
+    client.close().await.unwrap();
+    assert!(server.recv().await.unwrap().is_none());
+}
+
+#[cfg(feature = "quic")]
+#[tokio::test]
+async fn connection_sequence_over_quinn() {
+    use quinn::rustls::{
+        pki_types::{CertificateDer, PrivatePkcs8KeyDer},
+        RootCertStore,
+    };
+    use std::sync::Arc;
+    use wayk_proto::{send_queue::TrafficClass, transport::quic::QuicTransport};
+
+    let _ = quinn::rustls::crypto::ring::default_provider().install_default();
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let certificate = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+    let server_config = quinn::ServerConfig::with_single_cert(vec![certificate.clone()], key.into()).unwrap();
+    let server_endpoint = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(certificate).unwrap();
+    let mut client_endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    client_endpoint.set_default_client_config(quinn::ClientConfig::with_root_certificates(Arc::new(roots)).unwrap());
+
+    let (client_connection, server_connection) = tokio::join!(
+        client_endpoint
+            .connect(server_endpoint.local_addr().unwrap(), "localhost")
+            .unwrap(),
+        async { server_endpoint.accept().await.unwrap().await },
+    );
+    let (client_connection, server_connection) = (client_connection.unwrap(), server_connection.unwrap());
+
+    let lanes = [TrafficClass::Realtime, TrafficClass::Bulk];
+    let (client, server) = tokio::join!(
+        QuicTransport::open(&client_connection, &lanes),
+        QuicTransport::accept(&server_connection, &lanes),
+    );
+    let (client, server) = (client.unwrap(), server.unwrap());
+    assert!(server.has_lane(TrafficClass::Realtime) && server.has_lane(TrafficClass::Bulk));
+    let mut client = NowTransport::with_transport(client);
+    let mut server = NowTransport::with_transport(server);
+
+    let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .channels_to_open(vec![ChannelName::Chat])
+        .build();
+    let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .available_channels(vec![ChannelName::Chat])
+        .build();
+    let mut client_sharee = Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback);
+    let mut server_sharee = Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback);
+
+    let (client_connected, server_connected) =
+        tokio::join!(client.connect(&mut client_sharee), server.connect(&mut server_sharee));
+    client_connected.unwrap();
+    server_connected.unwrap();
+
+    client
+        .send(NowNetworkMsg::from(NowNetworkKeepAliveReqMsg::new(3, 0)))
+        .await
+        .unwrap();
+    match server.recv().await.unwrap().unwrap().body {
+        NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveReq(req))) => assert_eq!(req.sequence_id, 3),
+        other => panic!("expected a keep-alive request, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn upgraded_transport_keeps_framing() {
+    let (client_stream, server_stream) = tokio::io::duplex(4096);
+    let client = NowTransport::new(client_stream);
+    let mut server = NowTransport::new(server_stream);
+
+    let mut client = client
+        .upgrade(|stream| async { Ok(tokio::io::BufStream::new(stream)) })
+        .await
+        .unwrap();
+
+    let list = NowSurfaceListReqMsg::new_with_surfaces(2, 1920, 1080, Vec::new());
+    client.send(NowSurfaceMsg::ListReq(list)).await.unwrap();
+    match server.recv().await.unwrap().unwrap().body {
+        NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListReq(req))) => assert_eq!(req.sequence_id, 2),
+        other => panic!("expected a surface list request, got {:?}", other),
+    }
+
+    let failed = NowTransport::new(server.into_inner())
+        .upgrade(|_| async { Err::<tokio::io::DuplexStream, _>(std::io::ErrorKind::InvalidData.into()) })
+        .await;
+    assert!(failed.is_err());
+}
+
+#[tokio::test]
+async fn heartbeat_keeps_alive_and_reports_silence() {
+    let (client_stream, server_stream) = tokio::io::duplex(4096);
+    let mut client = NowTransport::new(client_stream);
+    let mut server = NowTransport::new(server_stream);
+    let mut heartbeat = Heartbeat::new(Duration::from_secs(1), Duration::from_millis(50), Instant::now());
+
+    let list = NowSurfaceListReqMsg::new_with_surfaces(3, 1920, 1080, Vec::new());
+    server.send(NowSurfaceMsg::ListReq(list)).await.unwrap();
+    server
+        .send(NowNetworkMsg::from(NowNetworkKeepAliveReqMsg::new(7, 0)))
+        .await
+        .unwrap();
+
+    match client.recv_with_heartbeat(&mut heartbeat).await.unwrap() {
+        TransportEvent::Packet(packet) => match packet.body {
+            NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListReq(req))) => assert_eq!(req.sequence_id, 3),
+            other => panic!("expected a surface list request, got {:?}", other),
+        },
+        other => panic!("expected a packet, got {:?}", other),
+    }
+    match client.recv_with_heartbeat(&mut heartbeat).await.unwrap() {
+        TransportEvent::Packet(packet) => match packet.body {
+            NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveReq(_))) => {}
+            other => panic!("expected a keep-alive request, got {:?}", other),
+        },
+        other => panic!("expected a packet, got {:?}", other),
+    }
+
+    match server.recv().await.unwrap().unwrap().body {
+        NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveReq(req))) => assert_eq!(req.sequence_id, 0),
+        other => panic!("expected a keep-alive request, got {:?}", other),
+    }
+    match server.recv().await.unwrap().unwrap().body {
+        NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveRsp(rsp))) => assert_eq!(rsp.sequence_id, 7),
+        other => panic!("expected a keep-alive response, got {:?}", other),
+    }
+
+    match client.recv_with_heartbeat(&mut heartbeat).await.unwrap() {
+        TransportEvent::PeerSilent(silence) => assert!(silence >= Duration::from_millis(50)),
+        other => panic!("expected a silent peer, got {:?}", other),
+    }
+
+    drop(server);
+    match client.recv_with_heartbeat(&mut heartbeat).await.unwrap() {
+        TransportEvent::Closed => {}
+        other => panic!("expected a closed stream, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn metrics_count_traffic_and_rtt() {
+    let (client_link, server_link) = MemoryTransport::pair();
+    let mut client = NowTransport::with_transport(client_link);
+    let mut server = NowTransport::with_transport(server_link);
+    let metrics = SessionMetrics::new(Instant::now());
+    client.set_metrics(Some(metrics.clone()));
+    let mut heartbeat = Heartbeat::new(Duration::from_secs(1), Duration::from_secs(5), Instant::now());
+
+    let (received, answered) = tokio::join!(client.recv_with_heartbeat(&mut heartbeat), async {
+        let req = match server.recv().await.unwrap().unwrap().body {
+            NowBody::Message(NowMessage::Network(NowNetworkMsg::KeepAliveReq(req))) => req,
+            other => panic!("expected a keep-alive request, got {:?}", other),
+        };
+        server
+            .send(NowNetworkMsg::from(NowNetworkKeepAliveRspMsg::answer(&req)))
+            .await
+    });
+    answered.unwrap();
+    assert!(matches!(received.unwrap(), TransportEvent::Packet(_)));
+
+    let snapshot = metrics.snapshot(Instant::now());
+    assert_eq!(snapshot.total.messages_out, 1);
+    assert_eq!(snapshot.total.messages_in, 1);
+    assert!(snapshot.total.bytes_in > 0 && snapshot.total.bytes_out > 0);
+    assert!(snapshot.channels.is_empty());
+    assert!(snapshot.rtt.is_some());
+}
+
+#[tokio::test]
+async fn idle_transport_closed_after_timeout() {
+    let (client_link, server_link) = MemoryTransport::pair();
+    let mut client = NowTransport::with_transport(client_link);
+    let mut server = NowTransport::with_transport(server_link);
+    client.set_timeouts(TransportTimeouts::new().idle(Duration::from_millis(20)));
+
+    let list = NowSurfaceListReqMsg::new_with_surfaces(5, 1920, 1080, Vec::new());
+    server.send(NowSurfaceMsg::ListReq(list)).await.unwrap();
+    assert!(client.recv().await.unwrap().is_some());
+
+    let start = Instant::now();
+    let err = client.recv().await.err().unwrap();
+    assert!(matches!(&err.kind, ProtoErrorKind::Io(err) if err.kind() == std::io::ErrorKind::TimedOut));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert!(server.recv().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn graceful_shutdown_acknowledged_by_peer() {
+    let (client_link, server_link) = MemoryTransport::pair();
+    let mut client = NowTransport::with_transport(client_link);
+    let mut server = NowTransport::with_transport(server_link);
+
+    let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .build();
+    let server_seq = ServerConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+        .build();
+    let mut client_sharee = Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback);
+    let mut server_sharee = Sharee::new(server_seq, ChannelsManager::new(), DummyShareeCallback);
+    let (client_connected, server_connected) =
+        tokio::join!(client.connect(&mut client_sharee), server.connect(&mut server_sharee));
+    client_connected.unwrap();
+    server_connected.unwrap();
+
+    let (outcome, served) = tokio::join!(
+        client.shutdown(DisconnectStatusCode::ByLocalUser, Duration::from_secs(1)),
+        server.run(&mut server_sharee),
+    );
+    served.unwrap();
+    assert!(matches!(outcome.unwrap(), ShutdownOutcome::Acknowledged(_)));
+    assert!(server_sharee.is_terminated());
+
+    // silent peer
+    let (link, _peer_link) = MemoryTransport::pair();
+    let mut transport = NowTransport::with_transport(link);
+    let outcome = transport
+        .shutdown(DisconnectStatusCode::ByLocalUser, Duration::from_millis(20))
+        .await
+        .unwrap();
+    assert_eq!(outcome, ShutdownOutcome::TimedOut);
+}
+
+#[tokio::test]
+async fn stalled_step_retried_then_reported() {
+    let (client_link, server_link) = MemoryTransport::pair();
+    let mut client = NowTransport::with_transport(client_link);
+    let mut server = NowTransport::with_transport(server_link);
+    client.set_timeouts(
+        TransportTimeouts::new().steps(StepTimeouts::new().negotiate(Duration::from_millis(20)).retries(1, 2)),
+    );
+
+    let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .build();
+    let mut client_sharee = Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback);
+
+    // answers the handshake only
+    let misbehaving_server = async {
+        assert!(server.recv().await.unwrap().is_some());
+        server.send(NowHandshakeMsg::new_success()).await.unwrap();
+
+        let mut received = 0;
+        while server.recv().await.unwrap().is_some() {
+            received += 1;
+        }
+        received
+    };
+    let connect = async {
+        let result = client.connect(&mut client_sharee).await;
+        client.close().await.unwrap();
+        result
+    };
+
+    let start = Instant::now();
+    let (result, received) = tokio::join!(connect, misbehaving_server);
+    let err = result.err().unwrap();
+    assert!(matches!(
+        err.kind,
+        ProtoErrorKind::ConnectionSequence(ConnectionState::Negotiate)
+    ));
+    // initial attempt (20ms) then a retry waiting twice as long
+    assert!(start.elapsed() >= Duration::from_millis(60));
+    // negotiation sent again by the retry
+    assert_eq!(received, 2);
+}
+
+#[tokio::test]
+async fn cancellation_aborts_hung_connection() {
+    let (client_link, _server_link) = MemoryTransport::pair();
+    let mut client = NowTransport::with_transport(client_link);
+    let token = CancellationToken::new();
+    client.set_cancellation_token(Some(token.clone()));
+
+    let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .build();
+    let mut client_sharee = Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback);
+
+    // the server never answers
+    let (connected, ()) = tokio::join!(client.connect(&mut client_sharee), async {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        token.cancel();
+    });
+    assert!(matches!(connected.err().unwrap().kind, ProtoErrorKind::Cancelled));
+
+    // cancelled tokens abort immediately
+    let list = NowSurfaceListReqMsg::new_with_surfaces(6, 1920, 1080, Vec::new());
+    assert!(client.send(NowSurfaceMsg::ListReq(list)).await.is_err());
+
+    // connect timeout
+    client.set_cancellation_token(None);
+    client.set_timeouts(TransportTimeouts::new().connect(Duration::from_millis(20)));
+    let client_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+        .available_auth_process(vec![AuthType::PFP])
+        .build();
+    let mut client_sharee = Sharee::new(client_seq, ChannelsManager::new(), DummyShareeCallback);
+    let err = client.connect(&mut client_sharee).await.err().unwrap();
+    assert!(matches!(&err.kind, ProtoErrorKind::Io(err) if err.kind() == std::io::ErrorKind::TimedOut));
+}
+
+#[test]
+fn client_connects_to_async_server() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    LocalSet::new().block_on(&runtime, async {
+        let mut server = NowAsyncServer::builder(Factory)
+            .handshake_timeout(Duration::from_secs(5))
+            .build();
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        server.accept(server_stream).unwrap();
+
+        let surfaces = Rc::new(RefCell::new(Vec::new()));
+        let client_surfaces = Rc::clone(&surfaces);
+        let client = tokio::task::spawn_local(async move {
+            let connection_seq = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+                .available_auth_process(vec![AuthType::PFP])
+                .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+                .channels_to_open(vec![ChannelName::Chat])
+                .build();
+            let mut sharee = Sharee::new(connection_seq, ChannelsManager::new(), ClientCallback(client_surfaces));
+
+            let mut transport = NowTransport::new(client_stream);
+            transport.connect(&mut sharee).await.unwrap();
+            assert_eq!(sharee.get_state(), ShareeState::Active);
+            assert!(transport
+                .get_channels_ctx()
+                .get_id_by_channel(&ChannelName::Chat)
+                .is_some());
+
+            transport.run(&mut sharee).await.unwrap();
+            assert!(sharee.is_terminated());
+        });
+
+        let mut session = tokio::time::timeout(Duration::from_secs(5), server.next_session())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let list =
+            NowSurfaceListReqMsg::new_with_surfaces(7, 1920, 1080, vec![NowSurfaceDef::new(3, EdgeRect::default())]);
+        session.send(NowSurfaceMsg::ListReq(list)).unwrap();
+        match session.recv().await.unwrap().packet().unwrap().body {
+            NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListRsp(rsp))) => assert_eq!(rsp.sequence_id, 7),
+            other => panic!("expected a surface list response, got {:?}", other),
+        }
+
+        session.cancel();
+        tokio::time::timeout(Duration::from_secs(5), client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*surfaces.borrow(), vec![3]);
+    });
+}
+
+#[test]
+fn client_reconnects_after_connection_loss() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    LocalSet::new().block_on(&runtime, async {
+        let mut server = NowAsyncServer::builder(Factory)
+            .handshake_timeout(Duration::from_secs(5))
+            .build();
+
+        let (streams_tx, mut streams_rx) = mpsc::unbounded_channel();
+        let surfaces = Rc::new(RefCell::new(Vec::new()));
+        let client_surfaces = Rc::clone(&surfaces);
+        let client = tokio::task::spawn_local(async move {
+            let new_connection_seq = |session_id: Option<u32>| {
+                let builder = ClientConnectionSeqSM::builder(DummyConnectionSeqCallback)
+                    .available_auth_process(vec![AuthType::PFP])
+                    .capabilities(vec![NowCapset::Transport(TransportCapset::default())])
+                    .channels_to_open(vec![ChannelName::Chat]);
+                match session_id {
+                    Some(session_id) => builder.reattach(session_id).build(),
+                    None => builder.build(),
+                }
+            };
+            let mut sharee = Sharee::new(
+                new_connection_seq(None),
+                ChannelsManager::new(),
+                ClientCallback(client_surfaces),
+            );
+
+            let mut transport = ReconnectingTransport::new(move || {
+                let (client_stream, server_stream) = tokio::io::duplex(4096);
+                let sent = streams_tx.send(server_stream);
+                async move {
+                    sent.map(|()| StreamTransport::new(client_stream))
+                        .map_err(|_| std::io::ErrorKind::ConnectionRefused.into())
+                }
+            })
+            .backoff(Backoff::new(Duration::from_millis(10), Duration::from_millis(100)).max_attempts(3));
+
+            transport.run(&mut sharee, new_connection_seq).await.unwrap();
+            assert!(sharee.is_terminated());
+            transport.reconnections()
+        });
+
+        // first connection is lost once established
+        server.accept(streams_rx.recv().await.unwrap()).unwrap();
+        let session = tokio::time::timeout(Duration::from_secs(5), server.next_session())
+            .await
+            .unwrap()
+            .unwrap();
+        drop(session);
+
+        server.accept(streams_rx.recv().await.unwrap()).unwrap();
+        let mut session = tokio::time::timeout(Duration::from_secs(5), server.next_session())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let list =
+            NowSurfaceListReqMsg::new_with_surfaces(8, 1920, 1080, vec![NowSurfaceDef::new(5, EdgeRect::default())]);
+        session.send(NowSurfaceMsg::ListReq(list)).unwrap();
+        match session.recv().await.unwrap().packet().unwrap().body {
+            NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListRsp(rsp))) => assert_eq!(rsp.sequence_id, 8),
+            other => panic!("expected a surface list response, got {:?}", other),
+        }
+
+        session.cancel();
+        let reconnections = tokio::time::timeout(Duration::from_secs(5), client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reconnections, 1);
+        assert_eq!(*surfaces.borrow(), vec![5]);
+    });
+}
@@ -0,0 +1,101 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use wayk_proto::{
+    message::{NowBody, NowMessage, NowSurfaceListRspMsg, NowSurfaceMsg, SurfaceResponseFlags},
+    packet::NowPacket,
+    serialization::Encode,
+    transport::{
+        websocket::{WebSocketRole, WebSocketStream},
+        NowTransport,
+    },
+};
+
+fn list_rsp(sequence_id: u16) -> NowSurfaceMsg {
+    NowSurfaceMsg::ListRsp(NowSurfaceListRspMsg::new(
+        SurfaceResponseFlags::new_empty(),
+        sequence_id,
+    ))
+}
+
+fn expect_list_rsp(packet: NowPacket<'_>, sequence_id: u16) {
+    match packet.body {
+        NowBody::Message(NowMessage::Surface(NowSurfaceMsg::ListRsp(rsp))) => assert_eq!(rsp.sequence_id, sequence_id),
+        other => panic!("expected a surface list response, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn packets_over_websocket() {
+    let (client_stream, server_stream) = tokio::io::duplex(4096);
+    let mut client = NowTransport::new(WebSocketStream::from_upgraded(client_stream, WebSocketRole::Client).await);
+    let mut server = NowTransport::new(WebSocketStream::from_upgraded(server_stream, WebSocketRole::Server).await);
+
+    client.send(list_rsp(1)).await.unwrap();
+    client.send(list_rsp(2)).await.unwrap();
+    expect_list_rsp(server.recv().await.unwrap().unwrap(), 1);
+    expect_list_rsp(server.recv().await.unwrap().unwrap(), 2);
+
+    server.send(list_rsp(3)).await.unwrap();
+    expect_list_rsp(client.recv().await.unwrap().unwrap(), 3);
+
+    let mut client_stream = client.into_inner();
+    client_stream.shutdown().await.unwrap();
+    assert!(server.recv().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn packets_after_opening_handshake() {
+    let (client_stream, server_stream) = tokio::io::duplex(4096);
+    let (client, server) = tokio::join!(
+        WebSocketStream::connect(client_stream, "ws://gateway.example/jet/now"),
+        WebSocketStream::accept(server_stream),
+    );
+    let mut client = NowTransport::new(client.unwrap());
+    let mut server = NowTransport::new(server.unwrap());
+
+    client.send(list_rsp(5)).await.unwrap();
+    expect_list_rsp(server.recv().await.unwrap().unwrap(), 5);
+    server.send(list_rsp(6)).await.unwrap();
+    expect_list_rsp(client.recv().await.unwrap().unwrap(), 6);
+}
+
+#[tokio::test]
+async fn plain_http_request_rejected() {
+    let (mut raw, server_stream) = tokio::io::duplex(4096);
+    raw.write_all(b"GET /jet/now HTTP/1.1\r\nHost: gateway.example\r\n\r\n")
+        .await
+        .unwrap();
+    assert!(WebSocketStream::accept(server_stream).await.is_err());
+}
+
+#[tokio::test]
+async fn frames_from_peer() {
+    let packet = NowPacket::from_message(list_rsp(4)).encode().unwrap();
+    let (mut raw, server_stream) = tokio::io::duplex(4096);
+    let mut server = NowTransport::new(WebSocketStream::from_upgraded(server_stream, WebSocketRole::Server).await);
+
+    // ping, then the packet split in two masked frames (binary and continuation)
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let masked = |payload: &[u8]| -> Vec<u8> { payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect() };
+    let mut frames = vec![0x89, 0x82];
+    frames.extend_from_slice(&mask);
+    frames.extend(masked(b"hi"));
+    let (first, second) = packet.split_at(3);
+    frames.extend_from_slice(&[0x02, 0x80 | first.len() as u8]);
+    frames.extend_from_slice(&mask);
+    frames.extend(masked(first));
+    frames.extend_from_slice(&[0x80, 0x80 | second.len() as u8]);
+    frames.extend_from_slice(&mask);
+    frames.extend(masked(second));
+    raw.write_all(&frames).await.unwrap();
+
+    expect_list_rsp(server.recv().await.unwrap().unwrap(), 4);
+
+    // pong from the server is not masked
+    let mut pong = [0; 4];
+    raw.read_exact(&mut pong).await.unwrap();
+    assert_eq!(pong, [0x8A, 0x02, b'h', b'i']);
+
+    // clients must mask their frames
+    raw.write_all(&[0x82, 0x01, 0x00]).await.unwrap();
+    assert!(server.recv().await.is_err());
+}
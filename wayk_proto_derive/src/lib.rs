@@ -7,37 +7,76 @@ extern crate proc_macro2;
 use alloc::vec::Vec;
 use proc_macro::TokenStream;
 use proc_macro2::{Span, TokenStream as TokenStream2};
-use quote::quote;
+use quote::{format_ident, quote};
 use syn::{
-    punctuated::Punctuated, token::Add, Attribute, Data, Fields, Generics, Ident, Lifetime, LifetimeDef, Lit, Meta,
-    Type,
+    parse::ParseStream,
+    punctuated::Punctuated,
+    token::{Add, Comma},
+    Attribute, Data, Fields, Generics, Ident, Lifetime, LifetimeDef, Lit, Meta, Path, Token, Type,
 };
 
 mod parsed {
     use alloc::vec::Vec;
+    use proc_macro2::TokenStream as TokenStream2;
 
     pub enum Type<'a> {
         Struct(Struct<'a>),
         FieldlessEnum(FieldlessEnum<'a>),
         MetaEnum(MetaEnum<'a>),
+        MessageEnum(MessageEnum<'a>),
     }
 
     pub struct Struct<'a> {
         pub name: &'a syn::Ident,
         pub generics: &'a syn::Generics,
+        pub fixed_size: bool,
         pub fields: Vec<Field<'a>>,
     }
 
     pub struct Field<'a> {
         pub decode_ignore: bool,
         pub encode_ignore: bool,
+        pub condition: Option<Condition>,
+        pub size_field: bool,
+        /// Zero padding before the field so that it starts at a multiple of this offset in the struct.
+        pub align: Option<usize>,
+        /// Zero padding after the field up to this size.
+        pub pad_to: Option<usize>,
+        pub range: Option<Range>,
         pub name: &'a syn::Ident,
         pub ty: &'a syn::Type,
     }
 
+    impl Field<'_> {
+        pub fn has_padding(&self) -> bool {
+            self.align.is_some() || self.pad_to.is_some()
+        }
+    }
+
+    /// Inclusive bounds an integer field is checked against on decode.
+    pub struct Range {
+        pub min: Option<syn::Expr>,
+        pub max: Option<syn::Expr>,
+    }
+
+    /// Optional field only present on the wire under a condition on a previous field.
+    pub enum Condition {
+        /// `flag` is set in `flags_field` (eg: `decode_if(flags, Flags::FLAG)`).
+        Flag { flags_field: syn::Path, flag: syn::Path },
+        /// The size declared by the size field is at least `min_size` (eg: `decode_if(size >= 32)`).
+        MinSize {
+            size_field: syn::Ident,
+            min_size: syn::Expr,
+        },
+    }
+
     pub struct FieldlessEnum<'a> {
         pub name: &'a syn::Ident,
         pub underlying_repr: syn::Ident,
+        /// Explicit discriminants are required when there is a variant for other values.
+        pub variants: Vec<(&'a syn::Ident, Option<&'a syn::Expr>)>,
+        /// Variant holding values without a matching variant, if any.
+        pub other_variant: Option<&'a syn::Ident>,
     }
 
     pub struct MetaEnum<'a> {
@@ -53,12 +92,62 @@ mod parsed {
         pub name: &'a syn::Ident,
         pub field_type: &'a syn::Type,
     }
+
+    /// Enum dispatched on a leading tag value peeked from the wire.
+    pub struct MessageEnum<'a> {
+        pub name: &'a syn::Ident,
+        pub generics: &'a syn::Generics,
+        pub tag_ty: syn::Ident,
+        pub variants: Vec<MessageVariant<'a>>,
+        pub other_variant: Option<OtherMessageVariant<'a>>,
+    }
+
+    pub struct MessageVariant<'a> {
+        pub name: &'a syn::Ident,
+        pub field_type: &'a syn::Type,
+        /// Literal, or variant of the same name in the subtype enum.
+        pub tag: TokenStream2,
+    }
+
+    /// Variant holding the tag and the raw payload of messages with an unknown tag.
+    pub struct OtherMessageVariant<'a> {
+        pub name: &'a syn::Ident,
+        pub tag_field: &'a syn::Ident,
+        pub payload_field: &'a syn::Ident,
+    }
 }
 
-#[proc_macro_derive(Encode, attributes(meta_enum, encode_ignore))]
+#[proc_macro_derive(
+    Encode,
+    attributes(
+        meta_enum,
+        message_enum,
+        tag,
+        encode_ignore,
+        decode_if,
+        size_field,
+        decode_other,
+        fixed_size,
+        manual_schema,
+        align,
+        pad_to,
+        range
+    )
+)]
 pub fn encode_macro_derive(input: TokenStream) -> TokenStream {
-    let ast = syn::parse(input).expect("failed to parse input");
-    impl_trait(&ast, impl_encode)
+    let ast: syn::DeriveInput = syn::parse(input).expect("failed to parse input");
+    // `manual_schema` types describe their wire layout with a hand-written `WireSchema` impl
+    let manual_schema = find_attr(&ast.attrs, "manual_schema").is_some();
+    impl_trait(&ast, |ty| {
+        let wire_schema = if manual_schema {
+            TokenStream2::new()
+        } else {
+            impl_wire_schema(&ty)
+        };
+        let mut expanded = impl_encode(ty);
+        expanded.extend(TokenStream::from(wire_schema));
+        expanded
+    })
 }
 
 fn impl_encode(ty: parsed::Type<'_>) -> TokenStream {
@@ -70,23 +159,179 @@ fn impl_encode(ty: parsed::Type<'_>) -> TokenStream {
                 .fields
                 .iter()
                 .filter(|field| !field.encode_ignore)
-                .map(|field| field.name)
-                .collect::<Vec<&Ident>>();
+                .collect::<Vec<&parsed::Field<'_>>>();
+
+            if fields
+                .iter()
+                .any(|field| field.has_padding() && field.condition.is_some())
+            {
+                panic!("`decode_if` fields can't be padded");
+            }
+            let padded = fields.iter().any(|field| field.has_padding());
+
+            // padding depends on the offset of each field in the struct
+            let fields_offset = |field_len: &dyn Fn(&parsed::Field<'_>) -> TokenStream2| {
+                let offsets = fields.iter().map(|field| {
+                    let align = field.align.map(|align| {
+                        quote! { __offset += (#align - __offset % #align) % #align; }
+                    });
+                    let len = match field.pad_to {
+                        Some(pad_to) => quote! { #pad_to },
+                        None => field_len(field),
+                    };
+                    quote! {
+                        #align
+                        __offset += #len;
+                    }
+                });
+                quote! {
+                    {
+                        let mut __offset = 0usize;
+                        #( #offsets )*
+                        __offset
+                    }
+                }
+            };
+
+            let field_len = |field: &parsed::Field<'_>| {
+                let name = field.name;
+                if field.condition.is_some() {
+                    quote! { self.#name.as_ref().map_or(0, |value| value.encoded_len_ctx(ctx)) }
+                } else {
+                    quote! { self.#name.encoded_len_ctx(ctx) }
+                }
+            };
+            let fields_len = if padded {
+                fields_offset(&field_len)
+            } else {
+                let fields_len = fields.iter().map(|field| field_len(field));
+                quote! { #( #fields_len )+* }
+            };
+
+            let fields_encode = fields.iter().map(|field| {
+                let name = field.name;
+                let encode = quote! {
+                    .encode_into_ctx(writer, ctx)
+                        .chain(ProtoErrorKind::Encoding(stringify!(#ty)))
+                        .or_else_desc(|| format!("couldn't encode {}::{}", stringify!(#ty), stringify!(#name)))?;
+                };
+
+                if field.condition.is_some() {
+                    return quote! {
+                        if let Some(value) = &self.#name {
+                            value #encode
+                        }
+                    };
+                }
+
+                if !padded {
+                    return quote! { self.#name #encode };
+                }
+
+                let align = field.align.map(|align| {
+                    quote! {
+                        let __padding = (#align - __offset % #align) % #align;
+                        ::wayk_proto::serialization::__write_padding(writer, __padding)?;
+                        __offset += __padding;
+                    }
+                });
+                let pad_to = match field.pad_to {
+                    Some(pad_to) => quote! {
+                        let __len = self.#name.encoded_len_ctx(ctx);
+                        if __len > #pad_to {
+                            return ::wayk_proto::error::ProtoError::new(ProtoErrorKind::Encoding(stringify!(#ty)))
+                                .or_else_desc(|| format!(
+                                    "{}::{} is larger ({}) than its padded size {}",
+                                    stringify!(#ty),
+                                    stringify!(#name),
+                                    __len,
+                                    #pad_to
+                                ));
+                        }
+                        ::wayk_proto::serialization::__write_padding(writer, #pad_to - __len)?;
+                        __offset += #pad_to;
+                    },
+                    None => quote! {
+                        __offset += self.#name.encoded_len_ctx(ctx);
+                    },
+                };
+                quote! {
+                    #align
+                    self.#name #encode
+                    #pad_to
+                }
+            });
+            let offset_start = if padded {
+                quote! { let mut __offset = 0usize; }
+            } else {
+                quote! {}
+            };
+
+            // size of fixed-size structs is computed at compile time from the fields types
+            let (fixed_size_impl, encoded_len) = if data.fixed_size {
+                if fields.iter().any(|field| field.condition.is_some()) {
+                    panic!("`fixed_size` structs can't have `decode_if` fields");
+                }
+
+                let encoded_size = if padded {
+                    fields_offset(&|field: &parsed::Field<'_>| {
+                        let field_ty = field.ty;
+                        quote! { <#field_ty as ::wayk_proto::serialization::FixedSize>::ENCODED_SIZE }
+                    })
+                } else {
+                    let fields_ty = fields.iter().map(|field| field.ty);
+                    quote! {
+                        0 #(
+                            + <#fields_ty as ::wayk_proto::serialization::FixedSize>::ENCODED_SIZE
+                        )*
+                    }
+                };
+                (
+                    quote! {
+                        impl #impl_generics ::wayk_proto::serialization::FixedSize for #ty #ty_generics #where_clause {
+                            const ENCODED_SIZE: usize = #encoded_size;
+                        }
+                    },
+                    quote! {
+                        fn encoded_len(&self) -> usize {
+                            <Self as ::wayk_proto::serialization::FixedSize>::ENCODED_SIZE
+                        }
+                    },
+                )
+            } else {
+                (
+                    quote! {},
+                    quote! {
+                        fn encoded_len(&self) -> usize {
+                            self.encoded_len_ctx(&::core::default::Default::default())
+                        }
+
+                        fn encoded_len_ctx(&self, ctx: &::wayk_proto::serialization::EncodeCtx) -> usize {
+                            #fields_len
+                        }
+                    },
+                )
+            };
 
             let expanded = quote! {
+                #fixed_size_impl
+
                 impl #impl_generics ::wayk_proto::serialization::Encode for #ty #ty_generics #where_clause {
-                    fn encoded_len(&self) -> usize {
-                        #(
-                            self.#fields.encoded_len()
-                        )+*
-                    }
+                    #encoded_len
 
                     fn encode_into<W: ::std::io::Write>(&self, writer: &mut W) -> ::core::result::Result<(), ::wayk_proto::error::ProtoError> {
+                        self.encode_into_ctx(writer, &::core::default::Default::default())
+                    }
+
+                    fn encode_into_ctx<W: ::std::io::Write>(
+                        &self,
+                        writer: &mut W,
+                        ctx: &::wayk_proto::serialization::EncodeCtx,
+                    ) -> ::core::result::Result<(), ::wayk_proto::error::ProtoError> {
                         use ::wayk_proto::error::{ProtoErrorKind, ProtoErrorResultExt};
+                        #offset_start
                         #(
-                            self.#fields.encode_into(writer)
-                                .chain(ProtoErrorKind::Encoding(stringify!(#ty)))
-                                .or_else_desc(|| format!("couldn't encode {}::{}", stringify!(#ty), stringify!(#fields)))?;
+                            #fields_encode
                         )*
                         Ok(())
                     }
@@ -109,22 +354,101 @@ fn impl_encode(ty: parsed::Type<'_>) -> TokenStream {
             let expanded = quote! {
                 impl #impl_generics ::wayk_proto::serialization::Encode for #ty #ty_generics #where_clause {
                     fn encoded_len(&self) -> usize {
+                        self.encoded_len_ctx(&::core::default::Default::default())
+                    }
+
+                    fn encoded_len_ctx(&self, ctx: &::wayk_proto::serialization::EncodeCtx) -> usize {
                         match self {
                             #(
-                                Self::#variants(msg) => msg.encoded_len(),
+                                Self::#variants(msg) => msg.encoded_len_ctx(ctx),
                             )*
                         }
                     }
 
                     fn encode_into<W: ::std::io::Write>(&self, writer: &mut W) -> ::core::result::Result<(), ::wayk_proto::error::ProtoError> {
+                        self.encode_into_ctx(writer, &::core::default::Default::default())
+                    }
+
+                    fn encode_into_ctx<W: ::std::io::Write>(
+                        &self,
+                        writer: &mut W,
+                        ctx: &::wayk_proto::serialization::EncodeCtx,
+                    ) -> ::core::result::Result<(), ::wayk_proto::error::ProtoError> {
+                        use ::wayk_proto::error::{ProtoErrorKind, ProtoErrorResultExt};
+                        match self {
+                            #(
+                                Self::#variants(msg) => msg
+                                    .encode_into_ctx(writer, ctx)
+                                    .chain(ProtoErrorKind::Encoding(stringify!(#ty)))
+                                    .or_desc(concat!("couldn't encode ", stringify!(#variants)," message")),
+                            )*
+                        }
+                    }
+                }
+            };
+
+            expanded.into()
+        }
+        parsed::Type::MessageEnum(data) => {
+            let ty = data.name;
+            let tag_ty = &data.tag_ty;
+            let (impl_generics, ty_generics, where_clause) = data.generics.split_for_impl();
+
+            let variants: Vec<&Ident> = data.variants.iter().map(|variant| variant.name).collect();
+
+            let (other_len, other_encode) = match &data.other_variant {
+                Some(parsed::OtherMessageVariant {
+                    name,
+                    tag_field,
+                    payload_field,
+                }) => (
+                    quote! {
+                        Self::#name { #payload_field, .. } => ::core::mem::size_of::<#tag_ty>() + #payload_field.len(),
+                    },
+                    quote! {
+                        Self::#name { #tag_field, #payload_field } => {
+                            #tag_field.encode_into(writer)?;
+                            writer.write_all(#payload_field)?;
+                            Ok(())
+                        }
+                    },
+                ),
+                None => (quote! {}, quote! {}),
+            };
+
+            let expanded = quote! {
+                impl #impl_generics ::wayk_proto::serialization::Encode for #ty #ty_generics #where_clause {
+                    fn encoded_len(&self) -> usize {
+                        self.encoded_len_ctx(&::core::default::Default::default())
+                    }
+
+                    fn encoded_len_ctx(&self, ctx: &::wayk_proto::serialization::EncodeCtx) -> usize {
+                        match self {
+                            #(
+                                Self::#variants(msg) => msg.encoded_len_ctx(ctx),
+                            )*
+                            #other_len
+                        }
+                    }
+
+                    fn encode_into<W: ::std::io::Write>(&self, writer: &mut W) -> ::core::result::Result<(), ::wayk_proto::error::ProtoError> {
+                        self.encode_into_ctx(writer, &::core::default::Default::default())
+                    }
+
+                    fn encode_into_ctx<W: ::std::io::Write>(
+                        &self,
+                        writer: &mut W,
+                        ctx: &::wayk_proto::serialization::EncodeCtx,
+                    ) -> ::core::result::Result<(), ::wayk_proto::error::ProtoError> {
                         use ::wayk_proto::error::{ProtoErrorKind, ProtoErrorResultExt};
                         match self {
                             #(
                                 Self::#variants(msg) => msg
-                                    .encode_into(writer)
+                                    .encode_into_ctx(writer, ctx)
                                     .chain(ProtoErrorKind::Encoding(stringify!(#ty)))
                                     .or_desc(concat!("couldn't encode ", stringify!(#variants)," message")),
                             )*
+                            #other_encode
                         }
                     }
                 }
@@ -136,6 +460,43 @@ fn impl_encode(ty: parsed::Type<'_>) -> TokenStream {
             let ty = data.name;
             let underlying_repr = data.underlying_repr;
 
+            if let Some(other_variant) = data.other_variant {
+                let variants = data.variants.iter().map(|(name, _)| name);
+                let discriminants = data.variants.iter().map(|(_, discriminant)| discriminant.unwrap());
+
+                let expanded = quote! {
+                    impl ::wayk_proto::serialization::Encode for #ty {
+                        fn encoded_len(&self) -> usize {
+                            ::core::mem::size_of::<#underlying_repr>()
+                        }
+
+                        fn encode_into<W: ::std::io::Write>(
+                            &self,
+                            writer: &mut W,
+                        ) -> ::core::result::Result<(), ::wayk_proto::error::ProtoError> {
+                            <#underlying_repr>::encode_into(&self.to_primitive(), writer)
+                        }
+                    }
+
+                    impl ::wayk_proto::serialization::FixedSize for #ty {
+                        const ENCODED_SIZE: usize = ::core::mem::size_of::<#underlying_repr>();
+                    }
+
+                    impl #ty {
+                        fn to_primitive(&self) -> #underlying_repr {
+                            match self {
+                                #(
+                                    Self::#variants => #discriminants,
+                                )*
+                                Self::#other_variant(value) => *value,
+                            }
+                        }
+                    }
+                };
+
+                return expanded.into();
+            }
+
             let expanded = quote! {
                 impl ::wayk_proto::serialization::Encode for #ty {
                     fn encoded_len(&self) -> usize {
@@ -150,6 +511,10 @@ fn impl_encode(ty: parsed::Type<'_>) -> TokenStream {
                     }
                 }
 
+                impl ::wayk_proto::serialization::FixedSize for #ty {
+                    const ENCODED_SIZE: usize = ::core::mem::size_of::<#underlying_repr>();
+                }
+
                 impl #ty {
                     fn to_primitive(&self) -> #underlying_repr {
                         *self as #underlying_repr
@@ -162,7 +527,23 @@ fn impl_encode(ty: parsed::Type<'_>) -> TokenStream {
     }
 }
 
-#[proc_macro_derive(Decode, attributes(meta_enum, decode_ignore))]
+#[proc_macro_derive(
+    Decode,
+    attributes(
+        meta_enum,
+        message_enum,
+        tag,
+        decode_ignore,
+        decode_if,
+        size_field,
+        decode_other,
+        fixed_size,
+        manual_schema,
+        align,
+        pad_to,
+        range
+    )
+)]
 pub fn decode_macro_derive(input: TokenStream) -> TokenStream {
     let ast = syn::parse(input).expect("failed to parse input");
     impl_trait(&ast, impl_decode)
@@ -199,18 +580,117 @@ fn impl_decode(enc_dec_ty: parsed::Type<'_>) -> TokenStream {
             let impl_generics = build_decode_impl_generics(data.generics);
             let (_, ty_generics, where_clause) = data.generics.split_for_impl();
 
-            let fields_ty = data
+            let decoded_fields = data
                 .fields
                 .iter()
                 .filter(|field| !field.decode_ignore)
-                .map(|field| field.ty)
-                .collect::<Vec<&Type>>();
-            let fields = data
-                .fields
-                .iter()
-                .filter(|field| !field.decode_ignore)
-                .map(|field| field.name)
-                .collect::<Vec<&Ident>>();
+                .collect::<Vec<&parsed::Field<'_>>>();
+            let fields = decoded_fields.iter().map(|field| field.name).collect::<Vec<&Ident>>();
+            let fields_decode = decoded_fields.iter().map(|field| {
+                let name = field.name;
+                let field_ty = field.ty;
+                let decode = quote! {
+                    .chain(ProtoErrorKind::Decoding(stringify!(#ty)))
+                    .or_desc(concat!(
+                        "couldn't decode ",
+                        stringify!(#field_ty),
+                        " into ",
+                        stringify!(#ty), "::", stringify!(#name)
+                    ))
+                    .map_err(|e| e.in_field(
+                        concat!(stringify!(#ty), "::", stringify!(#name)),
+                        cursor.get_ref(),
+                        __field_start,
+                    ))?
+                };
+
+                // fields are decoded in order so that a condition can refer to a previous flags or size field
+                if let Some(condition) = &field.condition {
+                    if field.range.is_some() {
+                        panic!("`decode_if` fields can't be ranged");
+                    }
+
+                    let condition = match condition {
+                        parsed::Condition::Flag { flags_field, flag } => quote! { #flags_field.value & #flag != 0 },
+                        parsed::Condition::MinSize { size_field, min_size } => {
+                            if !decoded_fields
+                                .iter()
+                                .any(|field| field.size_field && field.name == size_field)
+                            {
+                                panic!("`decode_if` size conditions must refer to the `size_field` of the struct");
+                            }
+                            // the declared size, before trailing bytes are skipped
+                            quote! { usize::from(#size_field) >= #min_size }
+                        }
+                    };
+                    quote! {
+                        let __field_start = cursor.position();
+                        let #name: #field_ty = if #condition {
+                            Some(::wayk_proto::serialization::Decode::decode_from_ctx(cursor, ctx) #decode)
+                        } else {
+                            None
+                        };
+                    }
+                } else {
+                    let align = field.align.map(|align| {
+                        let align = align as u64;
+                        quote! {
+                            let __offset = cursor.position() - __struct_start;
+                            ::wayk_proto::serialization::__skip_padding(
+                                cursor,
+                                __struct_start + __offset + (#align - __offset % #align) % #align,
+                                stringify!(#ty),
+                            )?;
+                        }
+                    });
+                    let pad_to = field.pad_to.map(|pad_to| {
+                        let pad_to = pad_to as u64;
+                        quote! {
+                            if cursor.position() - __field_start > #pad_to {
+                                return ::wayk_proto::error::ProtoError::new(ProtoErrorKind::Decoding(stringify!(#ty)))
+                                    .or_else_desc(|| format!(
+                                        "{}::{} is larger than its padded size {}",
+                                        stringify!(#ty),
+                                        stringify!(#name),
+                                        #pad_to
+                                    ));
+                            }
+                            ::wayk_proto::serialization::__skip_padding(cursor, __field_start + #pad_to, stringify!(#ty))?;
+                        }
+                    });
+                    // bounds are compared as i128 so that they can be of any integer type
+                    let range = field.range.as_ref().map(|parsed::Range { min, max }| {
+                        let min = min.as_ref().map(|min| quote! { (#name as i128) < (#min as i128) });
+                        let max = max.as_ref().map(|max| quote! { (#name as i128) > (#max as i128) });
+                        let out_of_range = min.into_iter().chain(max);
+                        quote! {
+                            if #(#out_of_range)||* {
+                                return ::wayk_proto::error::ProtoError::new(ProtoErrorKind::OutOfRange(
+                                    concat!(stringify!(#ty), "::", stringify!(#name))
+                                ))
+                                .or_else_desc(|| format!("unexpected value {}", #name))
+                                .map_err(|e| e.in_field(
+                                    concat!(stringify!(#ty), "::", stringify!(#name)),
+                                    cursor.get_ref(),
+                                    __field_start,
+                                ));
+                            }
+                        }
+                    });
+                    quote! {
+                        #align
+                        let __field_start = cursor.position();
+                        let #name = <#field_ty as ::wayk_proto::serialization::Decode>::decode_from_ctx(cursor, ctx) #decode;
+                        #range
+                        #pad_to
+                    }
+                }
+            });
+            let struct_start = if decoded_fields.iter().any(|field| field.align.is_some()) {
+                quote! { let __struct_start = cursor.position(); }
+            } else {
+                quote! {}
+            };
             let ignored_fields = data
                 .fields
                 .iter()
@@ -218,20 +698,64 @@ fn impl_decode(enc_dec_ty: parsed::Type<'_>) -> TokenStream {
                 .map(|field| field.name)
                 .collect::<Vec<&Ident>>();
 
+            // The size field declares the full struct size (including itself): trailing bytes
+            // added by a newer peer are skipped and the field is set to the size actually decoded.
+            let (size_field_start, size_field_check) = match decoded_fields.iter().find(|field| field.size_field) {
+                Some(field) => {
+                    let name = field.name;
+                    let field_ty = field.ty;
+                    (
+                        quote! {
+                            let __size_field_start = cursor.position();
+                        },
+                        quote! {
+                            let #name = {
+                                let declared_size = usize::from(#name);
+                                let decoded_size = (cursor.position() - __size_field_start) as usize;
+                                if declared_size < decoded_size {
+                                    return ::wayk_proto::error::ProtoError::new(ProtoErrorKind::Decoding(stringify!(#ty)))
+                                        .or_else_desc(|| format!(
+                                            "declared size {} is smaller than the required size {}",
+                                            declared_size,
+                                            decoded_size
+                                        ));
+                                }
+
+                                let end = __size_field_start + declared_size as u64;
+                                if end > cursor.get_ref().len() as u64 {
+                                    return ::wayk_proto::error::ProtoError::new(ProtoErrorKind::Decoding(stringify!(#ty)))
+                                        .or_else_desc(|| format!("not enough bytes for declared size {}", declared_size));
+                                }
+                                cursor.set_position(end);
+
+                                decoded_size as #field_ty
+                            };
+                        },
+                    )
+                }
+                None => (quote! {}, quote! {}),
+            };
+
             let expanded = quote! {
                 impl #impl_generics ::wayk_proto::serialization::Decode<'dec> for #ty #ty_generics #where_clause {
                     fn decode_from(cursor: &mut ::std::io::Cursor<&'dec [u8]>) -> ::core::result::Result<Self, ::wayk_proto::error::ProtoError> {
+                        Self::decode_from_ctx(cursor, &::core::default::Default::default())
+                    }
+
+                    fn decode_from_ctx(
+                        cursor: &mut ::std::io::Cursor<&'dec [u8]>,
+                        ctx: &::wayk_proto::serialization::DecodeCtx,
+                    ) -> ::core::result::Result<Self, ::wayk_proto::error::ProtoError> {
                         use ::wayk_proto::error::{ProtoErrorResultExt, ProtoErrorKind};
+                        #struct_start
+                        #size_field_start
+                        #(
+                            #fields_decode
+                        )*
+                        #size_field_check
                         Ok(Self {
                             #(
-                                #fields: <#fields_ty as ::wayk_proto::serialization::Decode>::decode_from(cursor)
-                                    .chain(ProtoErrorKind::Decoding(stringify!(#ty)))
-                                    .or_desc(concat!(
-                                        "couldn't decode ",
-                                        stringify!(#fields_ty),
-                                        " into ",
-                                        stringify!(#ty), "::", stringify!(#fields)
-                                    ))?,
+                                #fields,
                             )*
                             #(
                                 #ignored_fields: ::core::default::Default::default(),
@@ -267,19 +791,24 @@ fn impl_decode(enc_dec_ty: parsed::Type<'_>) -> TokenStream {
             let expanded = quote! {
                 impl #impl_generics ::wayk_proto::serialization::Decode<'dec> for #ty #ty_generics #where_clause {
                     fn decode_from(cursor: &mut ::std::io::Cursor<&'dec [u8]>) -> ::core::result::Result<Self, ::wayk_proto::error::ProtoError> {
+                        Self::decode_from_ctx(cursor, &::core::default::Default::default())
+                    }
+
+                    fn decode_from_ctx(
+                        cursor: &mut ::std::io::Cursor<&'dec [u8]>,
+                        ctx: &::wayk_proto::serialization::DecodeCtx,
+                    ) -> ::core::result::Result<Self, ::wayk_proto::error::ProtoError> {
                         use ::wayk_proto::error::{ProtoErrorResultExt, ProtoErrorKind};
-                        use ::wayk_proto::serialization::Encode;
-                        use ::std::io::{Seek, SeekFrom};
 
-                        let subtype = <#subtype_enum_ty as ::wayk_proto::serialization::Decode>::decode_from(cursor)
+                        // the subtype is peeked: each variant decodes it again
+                        let subtype = ::wayk_proto::serialization::Peek::peek::<#subtype_enum_ty>(&*cursor)
                             .chain(ProtoErrorKind::Decoding(stringify!(#ty)))
                             .or_desc("couldn't decode subtype")?;
-                        cursor.seek(SeekFrom::Current(-(subtype.encoded_len() as i64)))
-                            .expect("seek back after subtype decoding failed"); // cannot fail
+                        let __variant_start = cursor.position();
 
                         match subtype {
                             #(
-                                #subtype_enum_ty::#variants => <#variants_field_ty as ::wayk_proto::serialization::Decode>::decode_from(cursor)
+                                #subtype_enum_ty::#variants => <#variants_field_ty as ::wayk_proto::serialization::Decode>::decode_from_ctx(cursor, ctx)
                                     .map(Self::#variants)
                                     .chain(ProtoErrorKind::Decoding(stringify!(#ty)))
                                     .or_desc(concat!(
@@ -287,6 +816,11 @@ fn impl_decode(enc_dec_ty: parsed::Type<'_>) -> TokenStream {
                                         stringify!(#ty),
                                         " for subtype ",
                                         stringify!(#variants)
+                                    ))
+                                    .map_err(|e| e.in_field(
+                                        concat!(stringify!(#ty), "::", stringify!(#variants)),
+                                        cursor.get_ref(),
+                                        __variant_start,
                                     )),
                             )*
                         }
@@ -296,10 +830,151 @@ fn impl_decode(enc_dec_ty: parsed::Type<'_>) -> TokenStream {
 
             expanded.into()
         }
+        parsed::Type::MessageEnum(data) => {
+            let ty = data.name;
+            let generics = data.generics;
+            let tag_ty = &data.tag_ty;
+
+            let variants: Vec<&Ident> = data.variants.iter().map(|variant| variant.name).collect();
+            let variants_field_ty: Vec<&Type> = data.variants.iter().map(|variant| variant.field_type).collect();
+            let tags: Vec<&TokenStream2> = data.variants.iter().map(|variant| &variant.tag).collect();
+            // subtype enum variants can't be matched on as tag values
+            let tag_consts: Vec<Ident> = (0..variants.len()).map(|i| format_ident!("__TAG_{}", i)).collect();
+
+            let impl_generics = build_decode_impl_generics(generics);
+            let (_, ty_generics, where_clause) = generics.split_for_impl();
+
+            // the tag is peeked: each message decodes it again as its subtype
+            let decode_tag = quote! {
+                #(
+                    const #tag_consts: #tag_ty = #tags as #tag_ty;
+                )*
+                ctx.limits.check_message_size(cursor, stringify!(#ty))?;
+                let __variant_start = cursor.position();
+                let tag = ::wayk_proto::serialization::Peek::peek::<#tag_ty>(&*cursor)
+                    .chain(ProtoErrorKind::Decoding(stringify!(#ty)))
+                    .or_desc("couldn't decode tag")?;
+            };
+            let decode_variants = quote! {
+                #(
+                    #tag_consts => {
+                        <#variants_field_ty as ::wayk_proto::serialization::Decode>::decode_from_ctx(cursor, ctx)
+                            .map(Self::#variants)
+                            .chain(ProtoErrorKind::Decoding(stringify!(#ty)))
+                            .or_desc(concat!(
+                                "couldn't decode ",
+                                stringify!(#ty),
+                                " for tag ",
+                                stringify!(#variants)
+                            ))
+                            .map_err(|e| e.in_field(
+                                concat!(stringify!(#ty), "::", stringify!(#variants)),
+                                cursor.get_ref(),
+                                __variant_start,
+                            ))
+                    }
+                )*
+            };
+            let invalid_tag = quote! {
+                _ => ::wayk_proto::error::ProtoError::new(ProtoErrorKind::Decoding(stringify!(#ty)))
+                    .or_else_desc(|| format!("invalid tag {:#04x}", tag)),
+            };
+
+            let expanded = match &data.other_variant {
+                // unknown messages are only kept in permissive mode
+                Some(parsed::OtherMessageVariant {
+                    name,
+                    tag_field,
+                    payload_field,
+                }) => quote! {
+                    impl #impl_generics #ty #ty_generics #where_clause {
+                        /// In permissive mode, an unknown tag is kept along with the remaining bytes.
+                        pub fn decode_with_mode(
+                            cursor: &mut ::std::io::Cursor<&'dec [u8]>,
+                            mode: ::wayk_proto::message::DecodeMode,
+                        ) -> ::core::result::Result<Self, ::wayk_proto::error::ProtoError> {
+                            <Self as ::wayk_proto::serialization::Decode>::decode_from_ctx(
+                                cursor,
+                                &::wayk_proto::serialization::DecodeCtx::with_mode(mode),
+                            )
+                        }
+                    }
+
+                    impl #impl_generics ::wayk_proto::serialization::Decode<'dec> for #ty #ty_generics #where_clause {
+                        fn decode_from(cursor: &mut ::std::io::Cursor<&'dec [u8]>) -> ::core::result::Result<Self, ::wayk_proto::error::ProtoError> {
+                            Self::decode_from_ctx(cursor, &::core::default::Default::default())
+                        }
+
+                        fn decode_from_ctx(
+                            cursor: &mut ::std::io::Cursor<&'dec [u8]>,
+                            ctx: &::wayk_proto::serialization::DecodeCtx,
+                        ) -> ::core::result::Result<Self, ::wayk_proto::error::ProtoError> {
+                            use ::wayk_proto::error::{ProtoErrorResultExt, ProtoErrorKind};
+                            #decode_tag
+                            match tag {
+                                #decode_variants
+                                _ if ctx.mode == ::wayk_proto::message::DecodeMode::Permissive => {
+                                    <#tag_ty as ::wayk_proto::serialization::Decode>::decode_from(cursor)?;
+                                    let #payload_field = cursor.get_ref()[cursor.position() as usize..].to_vec();
+                                    cursor.set_position(cursor.get_ref().len() as u64);
+                                    Ok(Self::#name { #tag_field: tag, #payload_field })
+                                }
+                                #invalid_tag
+                            }
+                        }
+                    }
+                },
+                None => quote! {
+                    impl #impl_generics ::wayk_proto::serialization::Decode<'dec> for #ty #ty_generics #where_clause {
+                        fn decode_from(cursor: &mut ::std::io::Cursor<&'dec [u8]>) -> ::core::result::Result<Self, ::wayk_proto::error::ProtoError> {
+                            Self::decode_from_ctx(cursor, &::core::default::Default::default())
+                        }
+
+                        fn decode_from_ctx(
+                            cursor: &mut ::std::io::Cursor<&'dec [u8]>,
+                            ctx: &::wayk_proto::serialization::DecodeCtx,
+                        ) -> ::core::result::Result<Self, ::wayk_proto::error::ProtoError> {
+                            use ::wayk_proto::error::{ProtoErrorResultExt, ProtoErrorKind};
+                            #decode_tag
+                            match tag {
+                                #decode_variants
+                                #invalid_tag
+                            }
+                        }
+                    }
+                },
+            };
+
+            expanded.into()
+        }
         parsed::Type::FieldlessEnum(data) => {
             let ty = data.name;
             let underlying_repr = data.underlying_repr;
 
+            if let Some(other_variant) = data.other_variant {
+                let variants = data.variants.iter().map(|(name, _)| name);
+                let discriminants = data.variants.iter().map(|(_, discriminant)| discriminant.unwrap());
+
+                // unknown values are preserved so that they can be encoded back unchanged
+                let expanded = quote! {
+                    impl ::wayk_proto::serialization::Decode<'_> for #ty {
+                        fn decode_from(
+                            cursor: &mut ::std::io::Cursor<&[u8]>,
+                        ) -> ::core::result::Result<Self, ::wayk_proto::error::ProtoError> {
+                            let v = #underlying_repr::decode_from(cursor)?;
+                            Ok(match v {
+                                #(
+                                    v if v == #discriminants => Self::#variants,
+                                )*
+                                v => Self::#other_variant(v),
+                            })
+                        }
+                    }
+                };
+
+                return expanded.into();
+            }
+
             let from_primitive = Ident::new(&alloc::format!("from_{}", underlying_repr), Span::call_site());
 
             let expanded = quote! {
@@ -323,12 +998,301 @@ fn impl_decode(enc_dec_ty: parsed::Type<'_>) -> TokenStream {
     }
 }
 
+/// Wire layout registration, see `wayk_proto::schema`.
+#[cfg(feature = "schema")]
+fn impl_wire_schema(ty: &parsed::Type<'_>) -> TokenStream2 {
+    let (name, generics, describe) = match ty {
+        parsed::Type::Struct(data) => {
+            let size = if data.fixed_size {
+                quote! { Some(<Self as ::wayk_proto::serialization::FixedSize>::ENCODED_SIZE) }
+            } else {
+                quote! { None }
+            };
+
+            let fields = data.fields.iter().filter(|field| !field.encode_ignore).map(|field| {
+                let name = field.name;
+                let field_ty = field.ty;
+                let size_field = field.size_field;
+                let align = option_tokens(field.align);
+                let pad_to = option_tokens(field.pad_to);
+                let present_if = match &field.condition {
+                    Some(parsed::Condition::Flag { flags_field, flag }) => {
+                        let condition = alloc::format!("{} & {}", path_to_string(flags_field), path_to_string(flag));
+                        quote! { Some(#condition) }
+                    }
+                    Some(parsed::Condition::MinSize { size_field, min_size }) => {
+                        let min_size = alloc::string::ToString::to_string(&quote! { #min_size }).replace(" :: ", "::");
+                        let condition = alloc::format!("{} >= {}", size_field, min_size);
+                        quote! { Some(#condition) }
+                    }
+                    None => quote! { None },
+                };
+
+                quote! {
+                    ::wayk_proto::schema::FieldSchema {
+                        name: stringify!(#name),
+                        ty: schema.register::<#field_ty>(),
+                        present_if: #present_if,
+                        size_field: #size_field,
+                        align: #align,
+                        pad_to: #pad_to,
+                    }
+                }
+            });
+
+            let describe = quote! {
+                ::wayk_proto::schema::TypeSchema::Struct {
+                    size: #size,
+                    fields: vec![#( #fields ),*],
+                }
+            };
+            (data.name, Some(data.generics), describe)
+        }
+        parsed::Type::FieldlessEnum(data) => {
+            let underlying_repr = &data.underlying_repr;
+            let open = data.other_variant.is_some();
+            let variants = data.variants.iter().map(|(name, discriminant)| {
+                let value = match discriminant {
+                    Some(discriminant) if open => quote! { (#discriminant) as u64 },
+                    _ => quote! { Self::#name as u64 },
+                };
+                quote! {
+                    ::wayk_proto::schema::NamedValue {
+                        name: stringify!(#name),
+                        value: #value,
+                    }
+                }
+            });
+
+            let describe = quote! {
+                ::wayk_proto::schema::TypeSchema::Enum {
+                    repr: stringify!(#underlying_repr),
+                    size: ::core::mem::size_of::<#underlying_repr>(),
+                    variants: vec![#( #variants ),*],
+                    open: #open,
+                }
+            };
+            (data.name, None, describe)
+        }
+        parsed::Type::MetaEnum(data) => {
+            let subtype_enum_ty = &data.subtype_enum_ty;
+            // message type enums are dispatched on the header message type
+            let has_tag = subtype_enum_ty != "None";
+            let tag = if has_tag {
+                quote! { Some(Box::new(schema.register::<#subtype_enum_ty>())) }
+            } else {
+                quote! { None }
+            };
+
+            let variants = data
+                .meta_variants
+                .iter()
+                .filter(|variant| !variant.encode_ignore)
+                .map(|variant| {
+                    let name = variant.name;
+                    let field_ty = variant.field_type;
+                    let tag = if has_tag && !variant.decode_ignore {
+                        quote! { Some(#subtype_enum_ty::#name as u64) }
+                    } else {
+                        quote! { None }
+                    };
+                    quote! {
+                        ::wayk_proto::schema::MessageVariantSchema {
+                            name: stringify!(#name),
+                            tag: #tag,
+                            ty: schema.register::<#field_ty>(),
+                        }
+                    }
+                });
+
+            let describe = quote! {
+                ::wayk_proto::schema::TypeSchema::Message {
+                    tag: #tag,
+                    variants: vec![#( #variants ),*],
+                }
+            };
+            (data.name, Some(data.generics), describe)
+        }
+        parsed::Type::MessageEnum(data) => {
+            let tag_ty = &data.tag_ty;
+            let mut variants: Vec<TokenStream2> = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let name = variant.name;
+                    let field_ty = variant.field_type;
+                    let tag = &variant.tag;
+                    quote! {
+                        ::wayk_proto::schema::MessageVariantSchema {
+                            name: stringify!(#name),
+                            tag: Some(#tag as u64),
+                            ty: schema.register::<#field_ty>(),
+                        }
+                    }
+                })
+                .collect();
+            if let Some(other_variant) = &data.other_variant {
+                let name = other_variant.name;
+                variants.push(quote! {
+                    ::wayk_proto::schema::MessageVariantSchema {
+                        name: stringify!(#name),
+                        tag: None,
+                        ty: ::wayk_proto::schema::TypeSchema::Opaque {
+                            description: "unknown tag followed by the remaining bytes",
+                        },
+                    }
+                });
+            }
+
+            let describe = quote! {
+                ::wayk_proto::schema::TypeSchema::Message {
+                    tag: Some(Box::new(schema.register::<#tag_ty>())),
+                    variants: vec![#( #variants ),*],
+                }
+            };
+            (data.name, Some(data.generics), describe)
+        }
+    };
+
+    let (impl_generics, ty_generics, where_clause) = match generics {
+        Some(generics) => {
+            let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+            (
+                quote! { #impl_generics },
+                quote! { #ty_generics },
+                quote! { #where_clause },
+            )
+        }
+        None => (quote! {}, quote! {}, quote! {}),
+    };
+
+    quote! {
+        impl #impl_generics ::wayk_proto::schema::WireSchema for #name #ty_generics #where_clause {
+            fn describe(schema: &mut ::wayk_proto::schema::Schema) -> ::wayk_proto::schema::TypeSchema {
+                schema.define(stringify!(#name), |schema| #describe)
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "schema"))]
+fn impl_wire_schema(_: &parsed::Type<'_>) -> TokenStream2 {
+    quote! {}
+}
+
+#[cfg(feature = "schema")]
+fn option_tokens(value: Option<usize>) -> TokenStream2 {
+    match value {
+        Some(value) => quote! { Some(#value) },
+        None => quote! { None },
+    }
+}
+
+#[cfg(feature = "schema")]
+fn path_to_string(path: &Path) -> alloc::string::String {
+    let segments: Vec<alloc::string::String> = path
+        .segments
+        .iter()
+        .map(|segment| alloc::string::ToString::to_string(&segment.ident))
+        .collect();
+    segments.join("::")
+}
+
 fn find_attr<'a>(attrs: &'a [Attribute], name: &str) -> Option<&'a Attribute> {
     attrs
         .iter()
         .find(|attr| attr.path.segments.iter().any(|seg| seg.ident == name))
 }
 
+fn parse_condition(attr: &Attribute) -> parsed::Condition {
+    if let Ok(syn::Expr::Binary(syn::ExprBinary {
+        left,
+        op: syn::BinOp::Ge(_),
+        right,
+        ..
+    })) = attr.parse_args::<syn::Expr>()
+    {
+        if let syn::Expr::Path(syn::ExprPath { path, .. }) = *left {
+            if let Some(size_field) = path.get_ident() {
+                return parsed::Condition::MinSize {
+                    size_field: size_field.clone(),
+                    min_size: *right,
+                };
+            }
+        }
+    }
+
+    let args = attr
+        .parse_args_with(Punctuated::<Path, Comma>::parse_terminated)
+        .expect("failed to parse `decode_if` arguments");
+    let mut args = args.into_iter();
+    match (args.next(), args.next(), args.next()) {
+        (Some(flags_field), Some(flag), None) => parsed::Condition::Flag { flags_field, flag },
+        _ => panic!(
+            "wrong arguments for `decode_if`. Expected a flags field and a flag (eg: decode_if(flags, Flags::FLAG)) \
+             or a minimum declared size (eg: decode_if(size >= 32))."
+        ),
+    }
+}
+
+fn parse_padding(attr: &Attribute) -> usize {
+    let padding = attr
+        .parse_args::<syn::LitInt>()
+        .and_then(|lit| lit.base10_parse::<usize>())
+        .expect("wrong argument for `align` or `pad_to`. Expected a size in bytes (eg: pad_to(4)).");
+    if padding == 0 {
+        panic!("`align` and `pad_to` sizes can't be zero");
+    }
+    padding
+}
+
+/// Parses the bounds of a ranged field (eg: `range(min = 1, max = Self::MAX_ID)`).
+fn parse_range(attr: &Attribute) -> parsed::Range {
+    let args = attr
+        .parse_args_with(Punctuated::<syn::MetaNameValue, Comma>::parse_terminated)
+        .expect("failed to parse `range` arguments");
+    let mut range = parsed::Range { min: None, max: None };
+    for arg in args {
+        let bound = if arg.path.is_ident("min") {
+            &mut range.min
+        } else if arg.path.is_ident("max") {
+            &mut range.max
+        } else {
+            panic!("wrong argument for `range`. Expected `min` or `max` (eg: range(min = 1)).");
+        };
+        *bound = Some(match arg.lit {
+            Lit::Str(expr) => expr.parse().expect("failed to parse `range` bound"),
+            lit => syn::Expr::Lit(syn::ExprLit { attrs: Vec::new(), lit }),
+        });
+    }
+    if range.min.is_none() && range.max.is_none() {
+        panic!("`range` expects at least one bound (eg: range(min = 1)).");
+    }
+    range
+}
+
+/// Parses the tag type of a message enum and the optional subtype enum tags are taken from
+/// (eg: `message_enum(tag = u8, subtype = SurfaceMessageType)`).
+fn parse_message_enum_args(input: ParseStream<'_>) -> syn::Result<(Ident, Option<Ident>)> {
+    let key: Ident = input.parse()?;
+    if key != "tag" {
+        return Err(syn::Error::new(key.span(), "expected `tag`"));
+    }
+    input.parse::<Token![=]>()?;
+    let tag_ty = input.parse()?;
+
+    if input.is_empty() {
+        return Ok((tag_ty, None));
+    }
+    input.parse::<Comma>()?;
+    let key: Ident = input.parse()?;
+    if key != "subtype" {
+        return Err(syn::Error::new(key.span(), "expected `subtype`"));
+    }
+    input.parse::<Token![=]>()?;
+    Ok((tag_ty, Some(input.parse()?)))
+}
+
 fn impl_trait<F>(ast: &syn::DeriveInput, implementor: F) -> TokenStream
 where
     F: FnOnce(parsed::Type<'_>) -> TokenStream,
@@ -344,6 +1308,11 @@ where
                     .map(|field| parsed::Field {
                         decode_ignore: find_attr(&field.attrs, "decode_ignore").is_some(),
                         encode_ignore: find_attr(&field.attrs, "encode_ignore").is_some(),
+                        condition: find_attr(&field.attrs, "decode_if").map(parse_condition),
+                        size_field: find_attr(&field.attrs, "size_field").is_some(),
+                        align: find_attr(&field.attrs, "align").map(parse_padding),
+                        pad_to: find_attr(&field.attrs, "pad_to").map(parse_padding),
+                        range: find_attr(&field.attrs, "range").map(parse_range),
                         name: field.ident.as_ref().unwrap(),
                         ty: &field.ty,
                     })
@@ -352,6 +1321,7 @@ where
                 parsed::Type::Struct(parsed::Struct {
                     name: ty,
                     generics,
+                    fixed_size: find_attr(&ast.attrs, "fixed_size").is_some(),
                     fields,
                 })
             } else {
@@ -360,8 +1330,62 @@ where
         }
         Data::Enum(data) => {
             let meta_enum_attr = find_attr(&ast.attrs, "meta_enum");
+            let message_enum_attr = find_attr(&ast.attrs, "message_enum");
             let repr_attr = find_attr(&ast.attrs, "repr");
-            if let Some(meta_enum_attr) = meta_enum_attr {
+            if let Some(message_enum_attr) = message_enum_attr {
+                let (tag_ty, subtype_enum_ty) = message_enum_attr
+                    .parse_args_with(parse_message_enum_args)
+                    .expect("failed to parse `message_enum` arguments");
+
+                let mut variants = Vec::new();
+                let mut other_variant = None;
+                for variant in &data.variants {
+                    if find_attr(&variant.attrs, "decode_other").is_some() {
+                        let mut fields = match &variant.fields {
+                            Fields::Named(fields) if fields.named.len() == 2 => {
+                                fields.named.iter().map(|field| field.ident.as_ref().unwrap())
+                            }
+                            _ => panic!("`decode_other` variant expects two named fields: the tag and the payload"),
+                        };
+                        other_variant = Some(parsed::OtherMessageVariant {
+                            name: &variant.ident,
+                            tag_field: fields.next().unwrap(),
+                            payload_field: fields.next().unwrap(),
+                        });
+                        continue;
+                    }
+
+                    let name = &variant.ident;
+                    let tag = match (
+                        find_attr(&variant.attrs, "tag").map(Attribute::parse_meta),
+                        &subtype_enum_ty,
+                    ) {
+                        (Some(Ok(Meta::NameValue(name))), _) => {
+                            let lit = name.lit;
+                            quote! { #lit }
+                        }
+                        (None, Some(subtype_enum_ty)) => quote! { #subtype_enum_ty::#name },
+                        _ => panic!(
+                            r#"missing or wrong `tag` attribute. Expected a name value (eg: tag = 0x01) or a subtype enum (eg: message_enum(tag = u8, subtype = ...))."#
+                        ),
+                    };
+                    let field_type = match &variant.fields {
+                        Fields::Unnamed(field) => &field.unnamed.first().unwrap().ty,
+                        Fields::Named(_) => panic!("named fields unsupported"),
+                        Fields::Unit => panic!("unexpected unit field"),
+                    };
+
+                    variants.push(parsed::MessageVariant { name, field_type, tag });
+                }
+
+                parsed::Type::MessageEnum(parsed::MessageEnum {
+                    name: ty,
+                    generics,
+                    tag_ty,
+                    variants,
+                    other_variant,
+                })
+            } else if let Some(meta_enum_attr) = meta_enum_attr {
                 let meta = meta_enum_attr
                     .parse_meta()
                     .expect("failed to parse `meta_enum` argument");
@@ -398,12 +1422,32 @@ where
                     meta_variants,
                 })
             } else if let Some(repr_attr) = repr_attr {
+                let other_variant = data
+                    .variants
+                    .iter()
+                    .find(|variant| find_attr(&variant.attrs, "decode_other").is_some())
+                    .map(|variant| &variant.ident);
+                let variants = data
+                    .variants
+                    .iter()
+                    .filter(|variant| find_attr(&variant.attrs, "decode_other").is_none())
+                    .map(|variant| {
+                        let discriminant = variant.discriminant.as_ref().map(|(_, discriminant)| discriminant);
+                        if other_variant.is_some() && discriminant.is_none() {
+                            panic!("variants of an enum with a `decode_other` variant need an explicit discriminant");
+                        }
+                        (&variant.ident, discriminant)
+                    })
+                    .collect();
+
                 parsed::Type::FieldlessEnum(parsed::FieldlessEnum {
                     name: ty,
                     underlying_repr: repr_attr.parse_args().expect("couldn't parse repr type"),
+                    variants,
+                    other_variant,
                 })
             } else {
-                panic!("meta_enum or repr attribute missing")
+                panic!("meta_enum, message_enum or repr attribute missing")
             }
         }
         Data::Union(_) => unimplemented!("union"),